@@ -0,0 +1,61 @@
+//! How long a generated multi-row INSERT takes to tokenize, parse and run
+//! in each engine, and to run as a stream of tuples, at a few sizes; each
+//! time should grow linearly with the number of rows. Run with
+//! `cargo bench --bench insert`.
+
+use rust_sql_parser::executor::Database;
+use rust_sql_parser::parser::{parse_insert_stream, parse_sql};
+use rust_sql_parser::persistent_executor::PersistentDatabase;
+use rust_sql_parser::tokenizer::tokenize;
+use std::time::{Duration, Instant};
+
+const SIZES: [usize; 3] = [10_000, 20_000, 40_000];
+
+fn insert_sql(rows: usize) -> String {
+    let tuples: Vec<String> = (0..rows)
+        .map(|i| format!("({}, 'user{}', {}.5, TRUE)", i, i, i % 100))
+        .collect();
+    format!("INSERT INTO users (id, name, score, active) VALUES {};", tuples.join(", "))
+}
+
+fn time<T>(run: impl FnOnce() -> T) -> (T, Duration) {
+    let started = Instant::now();
+    let result = run();
+    (result, started.elapsed())
+}
+
+// How long `insert` takes on a new database with the table `create` makes
+fn time_stored(create: &str, insert: impl FnOnce(&PersistentDatabase)) -> Duration {
+    let dir = std::env::temp_dir().join(format!("rust_sql_parser_bench_insert_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let db = PersistentDatabase::new(dir.to_str().unwrap()).unwrap();
+    db.execute(parse_sql(tokenize(create).unwrap()).unwrap()).unwrap();
+    let (_, elapsed) = time(|| insert(&db));
+    drop(db);
+    let _ = std::fs::remove_dir_all(&dir);
+    elapsed
+}
+
+fn main() {
+    let create = "CREATE TABLE users (id INT PRIMARY KEY, name TEXT, score FLOAT, active BOOLEAN);";
+    println!("{:>8} {:>12} {:>12} {:>12} {:>12} {:>12}", "rows", "tokenize", "parse", "memory", "storage", "streamed");
+    for rows in SIZES {
+        let sql = insert_sql(rows);
+        let (tokens, tokenized) = time(|| tokenize(&sql).unwrap());
+        let (stmt, parsed) = time(|| parse_sql(tokens).unwrap());
+
+        let mut memory = Database::new();
+        memory.execute(parse_sql(tokenize(create).unwrap()).unwrap()).unwrap();
+        let (_, in_memory) = time(|| memory.execute(stmt.clone()).unwrap());
+
+        let stored = time_stored(create, |db| {
+            db.execute(stmt).unwrap();
+        });
+        let streamed = time_stored(create, |db| {
+            db.execute_insert_stream(parse_insert_stream(&sql).unwrap()).unwrap();
+        });
+
+        println!("{:>8} {:>12.2?} {:>12.2?} {:>12.2?} {:>12.2?} {:>12.2?}",
+                 rows, tokenized, parsed, in_memory, stored, streamed);
+    }
+}
@@ -0,0 +1,103 @@
+//! `#[derive(SqlTable)]`, which implements `rust_sql_parser::table::SqlTable`
+//! for a struct with named fields. Use it through the `derive` feature of
+//! rust_sql_parser rather than depending on this crate directly.
+//!
+//! Attributes:
+//! - `#[sql(table = "name")]` on the struct names its table; by default it is
+//!   the struct's name in lower case.
+//! - `#[sql(primary_key)]` on one field makes its column the primary key.
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, LitStr};
+
+#[proc_macro_derive(SqlTable, attributes(sql))]
+pub fn derive_sql_table(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input).unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+fn expand(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let name = &input.ident;
+    let mut table = name.to_string().to_lowercase();
+    for attr in input.attrs.iter().filter(|a| a.path().is_ident("sql")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("table") {
+                table = meta.value()?.parse::<LitStr>()?.value();
+                Ok(())
+            } else {
+                Err(meta.error("expected `table = \"...\"`"))
+            }
+        })?;
+    }
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => return Err(syn::Error::new_spanned(name, "SqlTable needs a struct with named fields")),
+        },
+        _ => return Err(syn::Error::new_spanned(name, "SqlTable can only be derived for structs")),
+    };
+
+    let mut primary_key = None;
+    for field in fields {
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("sql")) {
+            attr.parse_nested_meta(|meta| {
+                if !meta.path.is_ident("primary_key") {
+                    return Err(meta.error("expected `primary_key`"));
+                }
+                if primary_key.is_some() {
+                    return Err(meta.error("only one field can be the primary key"));
+                }
+                primary_key = field.ident.as_ref().map(|ident| ident.to_string());
+                Ok(())
+            })?;
+        }
+    }
+
+    let idents: Vec<_> = fields.iter().filter_map(|f| f.ident.as_ref()).collect();
+    let columns: Vec<String> = idents.iter().map(|ident| ident.to_string()).collect();
+    let types: Vec<_> = fields.iter().map(|f| &f.ty).collect();
+    let primary_key = match primary_key {
+        Some(column) => quote!(::std::option::Option::Some(#column.to_string())),
+        None => quote!(::std::option::Option::None),
+    };
+    let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::rust_sql_parser::table::SqlTable for #name #type_generics #where_clause {
+            const TABLE: &'static str = #table;
+            const COLUMNS: &'static [&'static str] = &[#(#columns),*];
+
+            fn create_table() -> ::rust_sql_parser::ast::CreateTableStatement {
+                ::rust_sql_parser::ast::CreateTableStatement {
+                    table: #table.to_string(),
+                    columns: ::std::vec![#((
+                        #columns.to_string(),
+                        <#types as ::rust_sql_parser::table::SqlValue>::SQL_TYPE.to_string(),
+                    )),*],
+                    primary_key: #primary_key,
+                    options: ::std::vec::Vec::new(),
+                    partition_by: ::std::option::Option::None,
+                }
+            }
+
+            fn insert(&self) -> ::rust_sql_parser::ast::InsertStatement {
+                ::rust_sql_parser::ast::InsertStatement {
+                    table: #table.to_string(),
+                    columns: ::std::vec![#(#columns.to_string()),*],
+                    values: ::std::vec![::std::vec![#(
+                        ::rust_sql_parser::table::SqlValue::to_sql(&self.#idents)
+                    ),*]],
+                    on_conflict: ::std::option::Option::None,
+                }
+            }
+
+            fn from_row(row: ::rust_sql_parser::row::Row<'_>) -> ::std::result::Result<Self, ::std::string::String> {
+                ::std::result::Result::Ok(Self {
+                    #(#idents: row.get_sql(#columns)?,)*
+                })
+            }
+        }
+    })
+}
@@ -1,4 +1,14 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// How a NULL is held wherever values are strings, in statements and rows
+/// alike. No string literal can spell it, since they can't hold a NUL
+/// character, so it's never taken for the text 'NULL'.
+pub const NULL: &str = "\0NULL";
+
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum SQLStatement {
     Select(SelectStatement),
     Insert(InsertStatement),
@@ -7,9 +17,19 @@ pub enum SQLStatement {
     CreateTable(CreateTableStatement),
     AlterTable(AlterTableStatement), 
     DropTable(DropTableStatement),
+    Transaction(TransactionStatement),
+    Show(ShowStatement),
+    Explain(ExplainStatement),
+    // VACUUM [table]
+    Vacuum(Option<String>),
+    // ANALYZE [table]
+    Analyze(Option<String>),
+    // SET name = value: a variable of the session it runs in
+    SetVariable(String, String),
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct SelectStatement {
     pub columns: Vec<ColumnExpr>, // NOT Option<>
     pub table: String,
@@ -18,49 +38,185 @@ pub struct SelectStatement {
     pub group_by: Option<Vec<String>>,
     pub having: Option<HavingClause>,
     pub join: Option<JoinClause>,
+    // LIMIT n / FETCH FIRST n ROWS ONLY, and OFFSET m
+    pub limit: Option<usize>,
+    pub offset: Option<usize>,
+    // From a /*+ ... */ comment after SELECT
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub hints: Vec<Hint>,
+    // AS OF <milliseconds since the Unix epoch>: read the tables as they
+    // were then
+    pub as_of: Option<u64>,
+    /// Set when FROM calls a table function, e.g. `FROM stats('users')`,
+    /// whose rows are read instead of a table's; `table` is then its name.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub table_function: Option<TableFunction>,
+}
+
+/// A function a SELECT can read its rows from in place of a table.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum TableFunction {
+    /// `stats('table')`: a row per column of the table, with the row count
+    /// and column statistics ANALYZE last gathered.
+    Stats(String),
+}
+
+impl TableFunction {
+    /// The function called `name` with `args`, in any case.
+    pub fn from_call(name: &str, args: &[String]) -> Result<Self, String> {
+        match (name.to_lowercase().as_str(), args) {
+            ("stats", [table]) => Ok(TableFunction::Stats(table.clone())),
+            ("stats", _) => Err("stats() takes one argument, the table name".to_string()),
+            _ => Err(format!("Unknown table function '{}'", name)),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            TableFunction::Stats(_) => "stats",
+        }
+    }
+
+    pub fn args(&self) -> Vec<&str> {
+        match self {
+            TableFunction::Stats(table) => vec![table],
+        }
+    }
+
+    /// The columns of the rows it returns, in order.
+    pub fn columns(&self) -> &'static [&'static str] {
+        match self {
+            TableFunction::Stats(_) => &["column_name", "row_count", "null_count", "distinct_count", "min_value", "max_value"],
+        }
+    }
+}
+
+/// Overrides a choice the planner would make for a SELECT.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Hint {
+    /// NO_INDEX(table): scan the table rather than reading rows by primary key.
+    NoIndex(String),
+    /// JOIN_ORDER(a, b): the table to loop over first in an inner or cross
+    /// join, then the other.
+    JoinOrder(Vec<String>),
 }
 
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct InsertStatement {
     pub table: String,
     pub columns: Vec<String>,
     pub values: Vec<Vec<String>>,
+    /// `INSERT OR IGNORE` or `INSERT OR REPLACE`.
+    pub on_conflict: Option<ConflictAction>,
+}
+
+/// What an INSERT does with a row whose primary key is already taken,
+/// instead of failing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ConflictAction {
+    /// Skips the row.
+    Ignore,
+    /// Overwrites the row that has the key.
+    Replace,
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct UpdateStatement {
     pub table: String,
-    pub assignments: Vec<(String, String)>,
+    /// A table whose rows the updated rows must join to, MySQL's `UPDATE a
+    /// JOIN b ON ... SET ...`.
+    pub join: Option<JoinClause>,
+    pub assignments: Vec<(String, SetValue)>,
     pub where_clause: Option<WhereClause>,
+    /// `RETURNING ...`: the columns to give back of each updated row, as the
+    /// UPDATE left it unless qualified `old.`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub returning: Option<Vec<String>>,
 }
 
+/// What an UPDATE sets a column to.
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum SetValue {
+    Value(String),
+    /// A math function of a column of the row, as it was before the UPDATE.
+    Math(FunctionCall),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct DeleteStatement {
     pub table: String,
+    /// As for UPDATE, written `DELETE a FROM a JOIN b ON ...`.
+    pub join: Option<JoinClause>,
     pub where_clause: Option<WhereClause>,
+    /// `RETURNING ...`: the columns to give back of each deleted row.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub returning: Option<Vec<String>>,
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct WhereClause {
     pub column: String,
     pub operator: String,
     pub value: String,
+    pub upper_bound: Option<String>, // only set for BETWEEN
+    pub in_list: Option<InList>, // only set for IN
+    /// A math function of `column` whose value is tested instead of the
+    /// column's, e.g. `ABS(balance) > '100'`.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub function: Option<FunctionCall>,
 }
+
+/// What a WHERE column is tested to be IN.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum InList {
+    Values(Vec<String>),
+    /// A SELECT of one column, which the executors run once and replace with
+    /// the values it returns.
+    Subquery(Box<SelectStatement>),
+}
+
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct CreateTableStatement {
     pub table: String,
     pub columns: Vec<(String, String)>,
+    pub primary_key: Option<String>,
+    pub options: Vec<(String, String)>, // WITH (name = value, ...)
+    pub partition_by: Option<PartitionBy>,
 }
 
+// PARTITION BY HASH(column) PARTITIONS n, or PARTITION BY RANGE(column)
+// SPLIT AT (value, ...), whose partitions begin at the values after the first
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PartitionBy {
+    pub column: String,
+    pub partitions: usize,
+    // Empty for HASH; otherwise one fewer than `partitions`, ascending
+    pub split_at: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum AlterAction {
-    AddColumn(String),
+    AddColumn(String, Option<String>), // column, data type
     DropColumn(String),
     ModifyColumn(String, String),
+    SetOptions(Vec<(String, String)>),
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct AlterTableStatement {
     pub table: String,
     pub action: AlterAction,
@@ -68,17 +224,62 @@ pub struct AlterTableStatement {
 
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct DropTableStatement {
     pub table: String,
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum TransactionStatement {
+    Begin,
+    /// BEGIN OPTIMISTIC: validated at COMMIT rather than locking tables.
+    BeginOptimistic,
+    Commit,
+    Rollback,
+    Savepoint(String),
+    RollbackToSavepoint(String),
+    ReleaseSavepoint(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ShowStatement {
+    StorageStats,
+    TableStatus,
+    /// SHOW name: a session variable's value.
+    Variable(String),
+}
+
+// EXPLAIN [(FORMAT TEXT | JSON)] SELECT ...
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ExplainStatement {
+    pub format: ExplainFormat,
+    pub select: SelectStatement,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum ExplainFormat {
+    /// An indented tree, one plan node per line.
+    #[default]
+    Text,
+    /// The plan tree as a JSON object.
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct OrderByClause {
-    pub column: String,
+    /// A column, an aggregate such as `COUNT(*)`, or the alias of one in
+    /// the select list.
+    pub expr: ColumnExpr,
     pub descending: bool,
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum ColumnExpr {
     Column(String),
     Count(String),
@@ -86,11 +287,109 @@ pub enum ColumnExpr {
     Avg(String),
     Min(String),
     Max(String),
+    // Bytes of a blob, characters of anything else
+    Length(String),
+    Math(FunctionCall),
     CountAll,
     All,
+    /// `expr AS alias`, naming the result column `alias`.
+    Aliased(Box<ColumnExpr>, String),
+}
+
+impl ColumnExpr {
+    /// The expression an alias is given to, or this one if it has none.
+    pub fn unaliased(&self) -> &ColumnExpr {
+        match self {
+            ColumnExpr::Aliased(expr, _) => expr.unaliased(),
+            expr => expr,
+        }
+    }
+
+    pub fn is_aggregate(&self) -> bool {
+        matches!(self.unaliased(), ColumnExpr::Count(_) | ColumnExpr::Sum(_) | ColumnExpr::Avg(_) |
+                                   ColumnExpr::Min(_) | ColumnExpr::Max(_) | ColumnExpr::CountAll)
+    }
+
+    /// The name of the result column it makes: its alias, or the expression
+    /// as written, e.g. `COUNT(*)`.
+    pub fn name(&self) -> String {
+        match self {
+            ColumnExpr::Column(c) => c.clone(),
+            ColumnExpr::Count(c) => format!("COUNT({})", c),
+            ColumnExpr::Sum(c) => format!("SUM({})", c),
+            ColumnExpr::Avg(c) => format!("AVG({})", c),
+            ColumnExpr::Min(c) => format!("MIN({})", c),
+            ColumnExpr::Max(c) => format!("MAX({})", c),
+            ColumnExpr::Length(c) => format!("LENGTH({})", c),
+            ColumnExpr::Math(call) => call.name(),
+            ColumnExpr::CountAll => "COUNT(*)".to_string(),
+            ColumnExpr::All => "*".to_string(),
+            ColumnExpr::Aliased(_, alias) => alias.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum MathFunction {
+    Abs,
+    Round,
+    Floor,
+    Ceil,
+    Mod,
+    Power,
 }
 
+impl MathFunction {
+    /// The function called `name`, in any case; CEILING is CEIL and POW is
+    /// POWER.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_uppercase().as_str() {
+            "ABS" => Some(MathFunction::Abs),
+            "ROUND" => Some(MathFunction::Round),
+            "FLOOR" => Some(MathFunction::Floor),
+            "CEIL" | "CEILING" => Some(MathFunction::Ceil),
+            "MOD" => Some(MathFunction::Mod),
+            "POWER" | "POW" => Some(MathFunction::Power),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MathFunction::Abs => "ABS",
+            MathFunction::Round => "ROUND",
+            MathFunction::Floor => "FLOOR",
+            MathFunction::Ceil => "CEIL",
+            MathFunction::Mod => "MOD",
+            MathFunction::Power => "POWER",
+        }
+    }
+}
+
+/// A math function of a column, e.g. `ROUND(price, 2)`. `arg` is the second
+/// argument: the digits ROUND keeps, if given, the divisor of MOD or the
+/// exponent of POWER.
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FunctionCall {
+    pub function: MathFunction,
+    pub column: String,
+    pub arg: Option<String>,
+}
+
+impl FunctionCall {
+    /// The call as written, e.g. `ROUND(price, 2)`.
+    pub fn name(&self) -> String {
+        match &self.arg {
+            Some(arg) => format!("{}({}, {})", self.function.name(), self.column, arg),
+            None => format!("{}({})", self.function.name(), self.column),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct HavingClause {
     pub column_expr: ColumnExpr,
     pub operator: String,
@@ -98,6 +397,7 @@ pub struct HavingClause {
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum JoinType {
     Inner,
     Left,
@@ -106,11 +406,38 @@ pub enum JoinType {
     Cross,
 }
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct JoinClause {
     pub join_type: JoinType,
+    /// The joined table, or the first table of a parenthesized group.
     pub table: String,
-    pub on_left: String,
-    pub on_right: String,
+    /// The comparisons of ON, all of which a pair of rows must meet; empty
+    /// for a CROSS JOIN.
+    pub on: Vec<JoinCondition>,
+    /// The join inside the parentheses when the joined side is a group,
+    /// `a JOIN (b JOIN c ON ...) ON ...`, which joins `table` to further
+    /// tables before their rows are joined to the FROM table's.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub nested: Option<Box<JoinClause>>,
+}
+
+impl JoinClause {
+    /// The tables of the joined side, `table` first, then those of a group.
+    pub fn tables(&self) -> Vec<&str> {
+        let mut tables = vec![self.table.as_str()];
+        tables.extend(self.nested.iter().flat_map(|nested| nested.tables()));
+        tables
+    }
+}
+
+/// One comparison in a JOIN's ON condition, between a column of each table,
+/// e.g. `events.ts < shifts.ends`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct JoinCondition {
+    pub left: String,
+    pub operator: String,
+    pub right: String,
 }
 
 
@@ -0,0 +1,65 @@
+//! Binary values for BLOB columns. A blob is written `X'DEADBEEF'`, and rows
+//! hold it in that form: hex digits survive the JSON rows are stored as,
+//! which raw bytes in a string wouldn't.
+
+#[cfg(any(feature = "executor", feature = "storage"))]
+use crate::ast::NULL;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Blob(pub Vec<u8>);
+
+impl FromStr for Blob {
+    type Err = String;
+
+    /// Parses an `X'...'` literal, in either case.
+    fn from_str(s: &str) -> Result<Self, String> {
+        s.strip_prefix(['X', 'x'])
+            .and_then(|rest| rest.strip_prefix('\'')?.strip_suffix('\''))
+            .and_then(decode_hex)
+            .map(Blob)
+            .ok_or_else(|| format!("'{}' is not a blob literal", s))
+    }
+}
+
+impl fmt::Display for Blob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "X'")?;
+        for byte in &self.0 {
+            write!(f, "{:02X}", byte)?;
+        }
+        write!(f, "'")
+    }
+}
+
+/// The bytes an even number of hex digits spell.
+pub(crate) fn decode_hex(digits: &str) -> Option<Vec<u8>> {
+    if !digits.len().is_multiple_of(2) || !digits.is_ascii() {
+        return None;
+    }
+    (0..digits.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// LENGTH of a value: bytes for a blob, characters for anything else.
+#[cfg(feature = "executor")]
+pub(crate) fn length(value: &str) -> String {
+    match value.parse::<Blob>() {
+        _ if value == NULL => value.to_string(),
+        Ok(blob) => blob.0.len().to_string(),
+        Err(_) => value.chars().count().to_string(),
+    }
+}
+
+/// A value as a BLOB column stores it: a blob literal as written, and text as
+/// its UTF-8 bytes. NULL is kept as it is.
+#[cfg(feature = "storage")]
+pub(crate) fn to_blob(value: &str) -> String {
+    match value.parse::<Blob>() {
+        _ if value == NULL => value.to_string(),
+        Ok(blob) => blob.to_string(),
+        Err(_) => Blob(value.as_bytes().to_vec()).to_string(),
+    }
+}
@@ -0,0 +1,447 @@
+//! Builders that construct statements directly, so applications can compose
+//! queries without formatting SQL strings, e.g.
+//!
+//! ```
+//! use rust_sql_parser::ast::SQLStatement;
+//! use rust_sql_parser::builder::{Condition, Select};
+//!
+//! let query = Select::from("users")
+//!     .columns(["name", "age"])
+//!     .filter(Condition::gt("age", "30"))
+//!     .order_by_desc("age")
+//!     .limit(10);
+//! assert_eq!(SQLStatement::from(query).to_string(),
+//!            "SELECT name, age FROM users WHERE age > '30' ORDER BY age DESC LIMIT 10");
+//! ```
+//!
+//! Each builder converts into an `SQLStatement` for `execute`, and `Select`
+//! builds the `SelectStatement` `query` takes.
+
+use crate::ast::{
+    ColumnExpr, ConflictAction, CreateTableStatement, DeleteStatement, HavingClause, Hint, InList, InsertStatement, JoinClause,
+    JoinCondition, JoinType, OrderByClause, PartitionBy, SQLStatement, SelectStatement, SetValue, UpdateStatement, WhereClause,
+};
+
+/// The conditions a WHERE clause can test.
+pub struct Condition;
+
+impl Condition {
+    pub fn eq(column: impl Into<String>, value: impl Into<String>) -> WhereClause {
+        Self::compare(column, "=", value)
+    }
+
+    pub fn lt(column: impl Into<String>, value: impl Into<String>) -> WhereClause {
+        Self::compare(column, "<", value)
+    }
+
+    pub fn gt(column: impl Into<String>, value: impl Into<String>) -> WhereClause {
+        Self::compare(column, ">", value)
+    }
+
+    /// `column BETWEEN low AND high`, both ends included.
+    pub fn between(column: impl Into<String>, low: impl Into<String>, high: impl Into<String>) -> WhereClause {
+        WhereClause {
+            column: column.into(),
+            operator: "BETWEEN".to_string(),
+            value: low.into(),
+            upper_bound: Some(high.into()),
+            in_list: None,
+            function: None,
+        }
+    }
+
+    /// `column IN (value, ...)`.
+    pub fn in_values<I, S>(column: impl Into<String>, values: I) -> WhereClause
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        Self::is_in(column, InList::Values(values.into_iter().map(Into::into).collect()))
+    }
+
+    /// `column IN (SELECT ...)`, for a query of one column.
+    pub fn in_select(column: impl Into<String>, query: Select) -> WhereClause {
+        Self::is_in(column, InList::Subquery(Box::new(query.build())))
+    }
+
+    fn is_in(column: impl Into<String>, list: InList) -> WhereClause {
+        WhereClause {
+            column: column.into(),
+            operator: "IN".to_string(),
+            value: String::new(),
+            upper_bound: None,
+            in_list: Some(list),
+            function: None,
+        }
+    }
+
+    fn compare(column: impl Into<String>, operator: &str, value: impl Into<String>) -> WhereClause {
+        WhereClause { column: column.into(), operator: operator.to_string(), value: value.into(), upper_bound: None, in_list: None, function: None }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Select {
+    statement: SelectStatement,
+}
+
+impl Select {
+    /// `SELECT * FROM table`, until columns are chosen.
+    pub fn from(table: impl Into<String>) -> Self {
+        Self {
+            statement: SelectStatement {
+                columns: Vec::new(),
+                table: table.into(),
+                where_clause: None,
+                order_by: None,
+                group_by: None,
+                having: None,
+                join: None,
+                limit: None,
+                offset: None,
+                hints: Vec::new(),
+                as_of: None,
+                table_function: None,
+            },
+        }
+    }
+
+    pub fn columns<I, S>(mut self, columns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.statement.columns.extend(columns.into_iter().map(|c| ColumnExpr::Column(c.into())));
+        self
+    }
+
+    /// Adds an aggregate such as `ColumnExpr::Count(column)`, or `*`.
+    pub fn expr(mut self, expr: ColumnExpr) -> Self {
+        self.statement.columns.push(expr);
+        self
+    }
+
+    pub fn filter(mut self, condition: WhereClause) -> Self {
+        self.statement.where_clause = Some(condition);
+        self
+    }
+
+    /// `join_type JOIN table ON left = right`; a `JoinType::Cross` ignores
+    /// the columns.
+    pub fn join(mut self, join_type: JoinType, table: impl Into<String>,
+                left: impl Into<String>, right: impl Into<String>) -> Self {
+        let on = match join_type {
+            JoinType::Cross => Vec::new(),
+            _ => vec![JoinCondition { left: left.into(), operator: "=".to_string(), right: right.into() }],
+        };
+        self.statement.join = Some(JoinClause { join_type, table: table.into(), on, nested: None });
+        self
+    }
+
+    /// ANDs `left = right` onto the ON condition of the join, for rows
+    /// paired by more than one column.
+    pub fn and_on(mut self, left: impl Into<String>, right: impl Into<String>) -> Self {
+        if let Some(join) = &mut self.statement.join {
+            join.on.push(JoinCondition { left: left.into(), operator: "=".to_string(), right: right.into() });
+        }
+        self
+    }
+
+    pub fn group_by<I, S>(mut self, columns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.statement.group_by = Some(columns.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// `HAVING expr operator value`, where `operator` is `=`, `<` or `>`.
+    pub fn having(mut self, expr: ColumnExpr, operator: &str, value: impl Into<String>) -> Self {
+        self.statement.having = Some(HavingClause { column_expr: expr, operator: operator.to_string(), value: value.into() });
+        self
+    }
+
+    pub fn order_by(mut self, column: impl Into<String>) -> Self {
+        self.statement.order_by = Some(OrderByClause { expr: ColumnExpr::Column(column.into()), descending: false });
+        self
+    }
+
+    pub fn order_by_desc(mut self, column: impl Into<String>) -> Self {
+        self.statement.order_by = Some(OrderByClause { expr: ColumnExpr::Column(column.into()), descending: true });
+        self
+    }
+
+    /// Orders by an aggregate such as `ColumnExpr::CountAll`, or by a column
+    /// or alias as `ColumnExpr::Column`.
+    pub fn order_by_expr(mut self, expr: ColumnExpr, descending: bool) -> Self {
+        self.statement.order_by = Some(OrderByClause { expr, descending });
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.statement.limit = Some(limit);
+        self
+    }
+
+    pub fn offset(mut self, offset: usize) -> Self {
+        self.statement.offset = Some(offset);
+        self
+    }
+
+    pub fn hint(mut self, hint: Hint) -> Self {
+        self.statement.hints.push(hint);
+        self
+    }
+
+    pub fn as_of(mut self, timestamp: u64) -> Self {
+        self.statement.as_of = Some(timestamp);
+        self
+    }
+
+    pub fn build(mut self) -> SelectStatement {
+        if self.statement.columns.is_empty() {
+            self.statement.columns.push(ColumnExpr::All);
+        }
+        self.statement
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Insert {
+    statement: InsertStatement,
+}
+
+impl Insert {
+    pub fn into(table: impl Into<String>) -> Self {
+        Self { statement: InsertStatement { table: table.into(), columns: Vec::new(), values: Vec::new(), on_conflict: None } }
+    }
+
+    /// `INSERT OR IGNORE` or `INSERT OR REPLACE`.
+    pub fn on_conflict(mut self, action: ConflictAction) -> Self {
+        self.statement.on_conflict = Some(action);
+        self
+    }
+
+    /// The columns each row gives values for, in order; without them rows
+    /// give every column of the table.
+    pub fn columns<I, S>(mut self, columns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.statement.columns = columns.into_iter().map(Into::into).collect();
+        self
+    }
+
+    pub fn row<I, S>(mut self, values: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.statement.values.push(values.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn build(self) -> InsertStatement {
+        self.statement
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Update {
+    statement: UpdateStatement,
+}
+
+impl Update {
+    pub fn table(table: impl Into<String>) -> Self {
+        Self { statement: UpdateStatement { table: table.into(), join: None, assignments: Vec::new(), where_clause: None, returning: None } }
+    }
+
+    /// Updates only the rows with a row of `table` where `left = right`,
+    /// which the filter can test as well.
+    pub fn join(mut self, table: impl Into<String>, left: impl Into<String>, right: impl Into<String>) -> Self {
+        self.statement.join = Some(inner_join(table.into(), left.into(), right.into()));
+        self
+    }
+
+    pub fn set(mut self, column: impl Into<String>, value: impl Into<String>) -> Self {
+        self.statement.assignments.push((column.into(), SetValue::Value(value.into())));
+        self
+    }
+
+    pub fn filter(mut self, condition: WhereClause) -> Self {
+        self.statement.where_clause = Some(condition);
+        self
+    }
+
+    /// Gives back `columns` of each updated row, e.g. `["*"]` or
+    /// `["old.status"]`.
+    pub fn returning<I, S>(mut self, columns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.statement.returning = Some(columns.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn build(self) -> UpdateStatement {
+        self.statement
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Delete {
+    statement: DeleteStatement,
+}
+
+impl Delete {
+    /// Deletes every row, unless filtered.
+    pub fn from(table: impl Into<String>) -> Self {
+        Self { statement: DeleteStatement { table: table.into(), join: None, where_clause: None, returning: None } }
+    }
+
+    /// Deletes only the rows with a row of `table` where `left = right`,
+    /// which the filter can test as well.
+    pub fn join(mut self, table: impl Into<String>, left: impl Into<String>, right: impl Into<String>) -> Self {
+        self.statement.join = Some(inner_join(table.into(), left.into(), right.into()));
+        self
+    }
+
+    pub fn filter(mut self, condition: WhereClause) -> Self {
+        self.statement.where_clause = Some(condition);
+        self
+    }
+
+    /// Gives back `columns` of each deleted row.
+    pub fn returning<I, S>(mut self, columns: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.statement.returning = Some(columns.into_iter().map(Into::into).collect());
+        self
+    }
+
+    pub fn build(self) -> DeleteStatement {
+        self.statement
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CreateTable {
+    statement: CreateTableStatement,
+}
+
+impl CreateTable {
+    pub fn new(table: impl Into<String>) -> Self {
+        Self {
+            statement: CreateTableStatement {
+                table: table.into(),
+                columns: Vec::new(),
+                primary_key: None,
+                options: Vec::new(),
+                partition_by: None,
+            },
+        }
+    }
+
+    pub fn column(mut self, name: impl Into<String>, data_type: impl Into<String>) -> Self {
+        self.statement.columns.push((name.into(), data_type.into()));
+        self
+    }
+
+    pub fn primary_key(mut self, column: impl Into<String>) -> Self {
+        self.statement.primary_key = Some(column.into());
+        self
+    }
+
+    /// Spreads rows over `partitions` by a hash of `column`.
+    pub fn partition_by_hash(mut self, column: impl Into<String>, partitions: usize) -> Self {
+        self.statement.partition_by = Some(PartitionBy { column: column.into(), partitions, split_at: Vec::new() });
+        self
+    }
+
+    /// Spreads rows over partitions by ranges of `column`, each after the
+    /// first beginning at one of `split_at`, in ascending order.
+    pub fn partition_by_range<V: Into<String>>(mut self, column: impl Into<String>, split_at: impl IntoIterator<Item = V>) -> Self {
+        let split_at: Vec<String> = split_at.into_iter().map(Into::into).collect();
+        self.statement.partition_by = Some(PartitionBy { column: column.into(), partitions: split_at.len() + 1, split_at });
+        self
+    }
+
+    /// A storage option, as in `WITH (name = value)`.
+    pub fn option(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.statement.options.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn build(self) -> CreateTableStatement {
+        self.statement
+    }
+}
+
+fn inner_join(table: String, left: String, right: String) -> JoinClause {
+    JoinClause { join_type: JoinType::Inner, table, on: vec![JoinCondition { left, operator: "=".to_string(), right }], nested: None }
+}
+
+impl From<Select> for SQLStatement {
+    fn from(select: Select) -> Self {
+        SQLStatement::Select(select.build())
+    }
+}
+
+impl From<Insert> for SQLStatement {
+    fn from(insert: Insert) -> Self {
+        SQLStatement::Insert(insert.build())
+    }
+}
+
+impl From<Update> for SQLStatement {
+    fn from(update: Update) -> Self {
+        SQLStatement::Update(update.build())
+    }
+}
+
+impl From<Delete> for SQLStatement {
+    fn from(delete: Delete) -> Self {
+        SQLStatement::Delete(delete.build())
+    }
+}
+
+impl From<CreateTable> for SQLStatement {
+    fn from(create: CreateTable) -> Self {
+        SQLStatement::CreateTable(create.build())
+    }
+}
+
+impl From<SelectStatement> for SQLStatement {
+    fn from(statement: SelectStatement) -> Self {
+        SQLStatement::Select(statement)
+    }
+}
+
+impl From<InsertStatement> for SQLStatement {
+    fn from(statement: InsertStatement) -> Self {
+        SQLStatement::Insert(statement)
+    }
+}
+
+impl From<UpdateStatement> for SQLStatement {
+    fn from(statement: UpdateStatement) -> Self {
+        SQLStatement::Update(statement)
+    }
+}
+
+impl From<DeleteStatement> for SQLStatement {
+    fn from(statement: DeleteStatement) -> Self {
+        SQLStatement::Delete(statement)
+    }
+}
+
+impl From<CreateTableStatement> for SQLStatement {
+    fn from(statement: CreateTableStatement) -> Self {
+        SQLStatement::CreateTable(statement)
+    }
+}
@@ -0,0 +1,301 @@
+//! System catalog for `PersistentDatabase`.
+//!
+//! Every schema object lives as one record in a single LSM table, keyed by
+//! `<kind>:<name>` (`table:`, or `stats:` for the statistics ANALYZE gathers
+//! from a table's rows), so creating or dropping an object is a single
+//! atomic WAL write.
+//!
+//! A `table:` record holds the table's columns and their types, its primary
+//! key, partitioning and storage options. The primary key is the only
+//! constraint the engine knows, and it has no secondary indexes or views, so
+//! there are no record kinds for those.
+
+use crate::format::quote_name;
+use crate::storage::{LSMStorage, StorageConfig};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+pub const CATALOG_DIR: &str = "_catalog";
+const TABLE_PREFIX: &str = "table:";
+const STATS_PREFIX: &str = "stats:";
+// Layout used before the catalog existed: one `<table>_schema` LSM per table
+const LEGACY_SCHEMA_SUFFIX: &str = "_schema";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ColumnDef {
+    pub name: String,
+    pub data_type: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TableDef {
+    pub name: String,
+    pub columns: Vec<ColumnDef>,
+    pub primary_key: Option<String>,
+    /// Set by CREATE TABLE ... WITH or ALTER TABLE ... SET; otherwise the
+    /// table uses the database's default.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub storage: Option<StorageConfig>,
+    /// Number of partitions of the primary key; see `partition`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub partitions: Option<usize>,
+    /// The primary key values the range partitions after the first begin
+    /// at, in ascending order; empty if the partitions are by hash.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub split_at: Vec<String>,
+}
+
+impl TableDef {
+    pub fn column(&self, name: &str) -> Option<&ColumnDef> {
+        self.columns.iter().find(|c| c.name == name)
+    }
+
+    pub fn column_names(&self) -> Vec<String> {
+        self.columns.iter().map(|c| c.name.clone()).collect()
+    }
+
+    /// Checks that no two columns share a name.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut names = HashSet::new();
+        match self.columns.iter().find(|c| !names.insert(c.name.as_str())) {
+            Some(column) => Err(format!("Column '{}' is defined more than once in table '{}'", column.name, self.name)),
+            None => Ok(()),
+        }
+    }
+
+    /// A CREATE TABLE statement that recreates this table, as written by
+    /// `PersistentDatabase::dump`.
+    pub fn create_sql(&self) -> String {
+        let columns: Vec<String> = self.columns.iter()
+            .map(|c| match &self.primary_key {
+                Some(primary_key) if *primary_key == c.name => format!("{} {} PRIMARY KEY", quote_name(&c.name, '"'), c.data_type),
+                _ => format!("{} {}", quote_name(&c.name, '"'), c.data_type),
+            })
+            .collect();
+        let mut sql = format!("CREATE TABLE {} ({})", quote_name(&self.name, '"'), columns.join(", "));
+        match (self.partitions, &self.primary_key) {
+            (Some(_), Some(primary_key)) if !self.split_at.is_empty() => {
+                let values: Vec<String> = self.split_at.iter().map(|value| format!("'{}'", value.replace('\'', "''"))).collect();
+                sql.push_str(&format!(" PARTITION BY RANGE({}) SPLIT AT ({})", quote_name(primary_key, '"'), values.join(", ")));
+            }
+            (Some(partitions), Some(primary_key)) => {
+                sql.push_str(&format!(" PARTITION BY HASH({}) PARTITIONS {}", quote_name(primary_key, '"'), partitions));
+            }
+            _ => {}
+        }
+        if let Some(storage) = &self.storage {
+            let mut options = vec![
+                format!("memtable_size = {}", storage.memtable_size),
+                format!("sstable_size = {}", storage.sstable_size),
+            ];
+            if let Some(ttl) = storage.ttl {
+                options.push(format!("ttl = {}", ttl.as_secs()));
+            }
+            sql.push_str(&format!(" WITH ({})", options.join(", ")));
+        }
+        sql.push(';');
+        sql
+    }
+}
+
+/// What ANALYZE found in a table's rows when it last ran.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TableStats {
+    pub rows: u64,
+    pub columns: Vec<ColumnStats>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ColumnStats {
+    pub name: String,
+    pub nulls: u64,
+    pub distinct: u64,
+    /// The smallest and largest values other than NULL; None if every value
+    /// is NULL.
+    pub min: Option<String>,
+    pub max: Option<String>,
+}
+
+impl TableStats {
+    pub fn column(&self, name: &str) -> Option<&ColumnStats> {
+        self.columns.iter().find(|c| c.name == name)
+    }
+}
+
+#[derive(Deserialize)]
+struct LegacyPrimaryKey {
+    column: String,
+    data_type: String,
+}
+
+#[derive(Debug)]
+pub struct Catalog {
+    storage: LSMStorage,
+    tables: BTreeMap<String, TableDef>,
+    stats: BTreeMap<String, TableStats>,
+}
+
+impl Catalog {
+    /// Opens the catalog in `data_dir`.
+    pub fn open(data_dir: &Path) -> Result<Self, String> {
+        let storage = LSMStorage::new(data_dir, CATALOG_DIR)
+            .map_err(|e| format!("Failed to open catalog: {}", e))?;
+        Self::read(storage)
+    }
+
+    /// Opens the catalog without writing to it; see
+    /// `LSMStorage::open_read_only`.
+    pub fn open_read_only(data_dir: &Path) -> Result<Self, String> {
+        let storage = LSMStorage::open_read_only(data_dir, CATALOG_DIR)
+            .map_err(|e| format!("Failed to open catalog: {}", e))?;
+        Self::read(storage)
+    }
+
+    fn read(storage: LSMStorage) -> Result<Self, String> {
+        let mut tables = BTreeMap::new();
+        let mut stats = BTreeMap::new();
+        for (key, value) in storage.get_all().map_err(|e| format!("Failed to read catalog: {}", e))? {
+            if let Some(name) = key.strip_prefix(TABLE_PREFIX) {
+                let table: TableDef = serde_json::from_str(&value)
+                    .map_err(|e| format!("Failed to deserialize table '{}': {}", name, e))?;
+                tables.insert(name.to_string(), table);
+            } else if let Some(name) = key.strip_prefix(STATS_PREFIX) {
+                let table_stats: TableStats = serde_json::from_str(&value)
+                    .map_err(|e| format!("Failed to deserialize statistics of '{}': {}", name, e))?;
+                stats.insert(name.to_string(), table_stats);
+            }
+        }
+        Ok(Self { storage, tables, stats })
+    }
+
+    pub fn table(&self, name: &str) -> Option<&TableDef> {
+        self.tables.get(name)
+    }
+
+    pub fn tables(&self) -> impl Iterator<Item = &TableDef> {
+        self.tables.values()
+    }
+
+    /// Adds a table definition, failing if the table already exists.
+    pub fn create_table(&mut self, table: TableDef) -> Result<(), String> {
+        if self.tables.contains_key(&table.name) {
+            return Err(format!("Table '{}' already exists", table.name));
+        }
+        self.put_table(table)
+    }
+
+    /// Creates or replaces a table definition.
+    pub fn put_table(&mut self, table: TableDef) -> Result<(), String> {
+        table.validate()?;
+        let table_json = serde_json::to_string(&table)
+            .map_err(|e| format!("Failed to serialize table '{}': {}", table.name, e))?;
+        self.storage.insert(format!("{}{}", TABLE_PREFIX, table.name), table_json)
+            .map_err(|e| format!("Failed to update catalog: {}", e))?;
+        self.tables.insert(table.name.clone(), table);
+        Ok(())
+    }
+
+    pub fn remove_table(&mut self, name: &str) -> Result<Option<TableDef>, String> {
+        if !self.tables.contains_key(name) {
+            return Ok(None);
+        }
+        if self.stats.remove(name).is_some() {
+            self.storage.delete(format!("{}{}", STATS_PREFIX, name))
+                .map_err(|e| format!("Failed to update catalog: {}", e))?;
+        }
+        self.storage.delete(format!("{}{}", TABLE_PREFIX, name))
+            .map_err(|e| format!("Failed to update catalog: {}", e))?;
+        Ok(self.tables.remove(name))
+    }
+
+    /// The statistics ANALYZE last gathered for a table, if it has run on it.
+    pub fn stats(&self, table: &str) -> Option<&TableStats> {
+        self.stats.get(table)
+    }
+
+    /// Replaces a table's statistics.
+    pub fn put_stats(&mut self, table: &str, stats: TableStats) -> Result<(), String> {
+        let stats_json = serde_json::to_string(&stats)
+            .map_err(|e| format!("Failed to serialize statistics of '{}': {}", table, e))?;
+        self.storage.insert(format!("{}{}", STATS_PREFIX, table), stats_json)
+            .map_err(|e| format!("Failed to update catalog: {}", e))?;
+        self.stats.insert(table.to_string(), stats);
+        Ok(())
+    }
+
+    pub fn set_wal_archiving(&mut self, archive: bool) -> Result<(), String> {
+        self.storage.set_wal_archiving(archive)
+            .map_err(|e| format!("Failed to create catalog WAL archive: {}", e))
+    }
+
+    pub fn set_wal_retention(&mut self, retention: Option<Duration>) {
+        self.storage.set_wal_retention(retention);
+    }
+
+    pub fn close(&mut self) -> Result<(), String> {
+        self.storage.close().map_err(|e| format!("Failed to close catalog: {}", e))
+    }
+
+    /// Moves any tables still in the legacy `<table>_schema` layout into the
+    /// catalog; see `migrations`.
+    pub(crate) fn migrate_legacy_schemas(&mut self, data_dir: &Path) -> Result<(), String> {
+        for entry in fs::read_dir(data_dir)
+            .map_err(|e| format!("Failed to read data directory: {}", e))? {
+            let entry = entry.map_err(|e| format!("Failed to read directory entry: {}", e))?;
+            let dir_name = entry.file_name();
+            let Some(schema_dir) = dir_name.to_str().filter(|_| entry.path().is_dir()) else {
+                continue;
+            };
+            let Some(table_name) = schema_dir.strip_suffix(LEGACY_SCHEMA_SUFFIX) else {
+                continue;
+            };
+            // A user table can have a name ending in `_schema` too
+            if self.tables.contains_key(schema_dir) {
+                continue;
+            }
+
+            let schema_storage = LSMStorage::new(data_dir, schema_dir)
+                .map_err(|e| format!("Failed to open schema storage: {}", e))?;
+            let Ok(Some(schema_json)) = schema_storage.get("schema") else {
+                continue;
+            };
+            let columns: Vec<String> = serde_json::from_str(&schema_json)
+                .map_err(|e| format!("Failed to deserialize schema: {}", e))?;
+            // The old layout had no column types, except for the primary key
+            let primary_key = match schema_storage.get("primary_key") {
+                Ok(Some(primary_key_json)) => Some(serde_json::from_str(&primary_key_json)
+                    .unwrap_or(LegacyPrimaryKey { column: primary_key_json, data_type: "TEXT".to_string() })),
+                _ => None,
+            };
+            let columns = columns.into_iter()
+                .map(|name| {
+                    let data_type = match &primary_key {
+                        Some(pk) if pk.column == name => pk.data_type.clone(),
+                        _ => "TEXT".to_string(),
+                    };
+                    ColumnDef { name, data_type }
+                })
+                .collect();
+            drop(schema_storage);
+
+            // Recorded before the old directory goes away, so a crash in
+            // between just migrates the same table again
+            if !self.tables.contains_key(table_name) {
+                self.put_table(TableDef {
+                    name: table_name.to_string(),
+                    columns,
+                    primary_key: primary_key.map(|pk| pk.column),
+                    storage: None,
+                    partitions: None,
+                    split_at: Vec::new(),
+                })?;
+            }
+            fs::remove_dir_all(entry.path())
+                .map_err(|e| format!("Failed to remove legacy schema directory: {}", e))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,138 @@
+//! Callbacks on the rows a committed INSERT, UPDATE or DELETE changed, for
+//! `PersistentDatabase::on_change`, and the stream of row writes a table's
+//! WAL logs, for `PersistentDatabase::subscribe_changes`.
+
+use crate::ast::NULL;
+use crate::output::ResultSet;
+use crate::persistent_executor::decode_row_json;
+use crate::storage::{StorageEntry, StorageValue};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Insert,
+    Update,
+    Delete,
+}
+
+/// The rows one statement changed in a table.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeEvent {
+    pub table: String,
+    pub kind: ChangeKind,
+    /// Every column of each row: as inserted, as updated, or as it was
+    /// before being deleted.
+    pub rows: ResultSet,
+}
+
+pub(crate) type ChangeCallback = Arc<dyn Fn(&ChangeEvent) + Send + Sync>;
+
+#[derive(Default)]
+pub(crate) struct ChangeHooks {
+    by_table: HashMap<String, Vec<ChangeCallback>>,
+}
+
+impl ChangeHooks {
+    pub(crate) fn add(&mut self, table: &str, hook: ChangeCallback) {
+        self.by_table.entry(table.to_string()).or_default().push(hook);
+    }
+
+    pub(crate) fn remove(&mut self, table: &str) {
+        self.by_table.remove(table);
+    }
+
+    pub(crate) fn watches(&self, table: &str) -> bool {
+        self.by_table.contains_key(table)
+    }
+
+    /// The hooks on `table`, cloned so they can be called without holding
+    /// the database's lock on them.
+    pub(crate) fn hooks(&self, table: &str) -> Vec<ChangeCallback> {
+        self.by_table.get(table).cloned().unwrap_or_default()
+    }
+}
+
+impl fmt::Debug for ChangeHooks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.by_table.keys()).finish()
+    }
+}
+
+/// An event for `rows` of `table`, with `columns` in the table's order and
+/// NULL for any a row lacks.
+pub(crate) fn change_event(table: &str, kind: ChangeKind, columns: Vec<String>,
+                           rows: Vec<HashMap<String, String>>) -> ChangeEvent {
+    let rows = rows.into_iter()
+        .map(|mut row| columns.iter()
+            .map(|c| row.remove(c).unwrap_or_else(|| NULL.to_string()))
+            .collect())
+        .collect();
+    ChangeEvent { table: table.to_string(), kind, rows: ResultSet { columns, rows, meta: Vec::new() } }
+}
+
+/// One row written to a table, as its WAL logged it. The log doesn't tell an
+/// insert from an update: either way `row` is the row as it now is.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RowChange {
+    pub table: String,
+    /// The key the row is stored under, the same for every write to it.
+    pub key: String,
+    /// The columns the row was written with; None if it was deleted.
+    pub row: Option<HashMap<String, String>>,
+    /// When the write was logged, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+/// The rows written to a table since the stream was opened, in the order its
+/// WAL logged them, which only ever holds committed writes. Iterating waits
+/// for the next one, and ends once the table is dropped or the database is.
+pub struct ChangeStream {
+    table: String,
+    batches: Receiver<Vec<StorageEntry>>,
+    pending: VecDeque<StorageEntry>,
+}
+
+impl ChangeStream {
+    pub(crate) fn new(table: &str, batches: Receiver<Vec<StorageEntry>>) -> Self {
+        Self { table: table.to_string(), batches, pending: VecDeque::new() }
+    }
+
+    /// The next change if one has been logged already, without waiting.
+    pub fn try_next(&mut self) -> Option<Result<RowChange, String>> {
+        while self.pending.is_empty() {
+            self.pending.extend(self.batches.try_recv().ok()?);
+        }
+        self.pending.pop_front().map(|entry| self.decode(entry))
+    }
+
+    fn decode(&self, entry: StorageEntry) -> Result<RowChange, String> {
+        let row = match entry.value {
+            StorageValue::Present(value) => Some(decode_row_json(&value)?),
+            StorageValue::Deleted => None,
+            StorageValue::Separated(_) => {
+                return Err(format!("The write of '{}' to '{}' isn't in the WAL", entry.key, self.table));
+            }
+        };
+        Ok(RowChange { table: self.table.clone(), key: entry.key, row, timestamp: entry.timestamp })
+    }
+}
+
+impl Iterator for ChangeStream {
+    type Item = Result<RowChange, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pending.is_empty() {
+            self.pending.extend(self.batches.recv().ok()?);
+        }
+        self.pending.pop_front().map(|entry| self.decode(entry))
+    }
+}
+
+impl fmt::Debug for ChangeStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChangeStream").field("table", &self.table).finish_non_exhaustive()
+    }
+}
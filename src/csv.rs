@@ -0,0 +1,82 @@
+//! Reading and writing RFC 4180 CSV, for importing tables and for the CSV
+//! output format.
+
+use std::io::{self, BufRead};
+
+/// Reads CSV records one at a time. Fields may be quoted, with `""` for a
+/// quote inside them, and quoted fields may span lines.
+pub struct CsvReader<R> {
+    reader: R,
+    line: usize,
+}
+
+impl<R: BufRead> CsvReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self { reader, line: 0 }
+    }
+
+    /// Line the last record returned ended on, from 1.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<Vec<String>>> {
+        let mut fields = Vec::new();
+        let mut field = String::new();
+        let mut in_quotes = false;
+        let mut read_any = false;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                if in_quotes {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                              format!("unterminated quoted field at line {}", self.line)));
+                }
+                if !read_any {
+                    return Ok(None);
+                }
+                fields.push(field);
+                return Ok(Some(fields));
+            }
+            self.line += 1;
+            read_any = true;
+
+            let mut chars = line.chars().peekable();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' if in_quotes && chars.peek() == Some(&'"') => {
+                        chars.next();
+                        field.push('"');
+                    }
+                    '"' if in_quotes => in_quotes = false,
+                    '"' if field.is_empty() => in_quotes = true,
+                    ',' if !in_quotes => fields.push(std::mem::take(&mut field)),
+                    '\r' | '\n' if !in_quotes => {}
+                    c => field.push(c),
+                }
+            }
+            if !in_quotes {
+                fields.push(field);
+                return Ok(Some(fields));
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for CsvReader<R> {
+    type Item = io::Result<Vec<String>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+/// `field` as a CSV field, quoted if it has to be.
+pub fn quote_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
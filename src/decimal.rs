@@ -0,0 +1,215 @@
+//! Exact fixed-point numbers for DECIMAL(p,s) and NUMERIC(p,s) columns, whose
+//! sums and comparisons mustn't drift the way floating-point ones do.
+
+use crate::ast::NULL;
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+/// The most digits a DECIMAL can have, all of which fit in an i128.
+pub const MAX_DECIMAL_PRECISION: u32 = 38;
+
+/// A number with `scale` digits after the point, held as a whole number of
+/// 10^-scale units. Equal values compare equal whatever their scale, so
+/// 1.5 = 1.50.
+#[derive(Debug, Clone, Copy)]
+pub struct Decimal {
+    units: i128,
+    scale: u32,
+}
+
+impl Decimal {
+    pub fn zero(scale: u32) -> Self {
+        Self { units: 0, scale }
+    }
+
+    pub fn scale(&self) -> u32 {
+        self.scale
+    }
+
+    /// Rounded half away from zero, or padded with zeros, to `scale` digits
+    /// after the point; None if that doesn't fit.
+    pub fn rescale(self, scale: u32) -> Option<Self> {
+        let units = if scale >= self.scale {
+            self.units.checked_mul(10i128.checked_pow(scale - self.scale)?)?
+        } else {
+            div_rounded(self.units, 10i128.checked_pow(self.scale - scale)?)
+        };
+        Some(Self { units, scale })
+    }
+
+    /// The exact sum, at the larger of the two scales.
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        let scale = self.scale.max(other.scale);
+        let units = self.rescale(scale)?.units.checked_add(other.rescale(scale)?.units)?;
+        Some(Self { units, scale })
+    }
+
+    /// This divided by `divisor` and rounded to `scale` digits, e.g. for AVG.
+    pub fn checked_div(self, divisor: i128, scale: u32) -> Option<Self> {
+        if divisor == 0 {
+            return None;
+        }
+        let units = self.rescale(scale)?.units;
+        Some(Self { units: div_rounded(units, divisor), scale })
+    }
+
+    pub fn abs(self) -> Self {
+        Self { units: self.units.abs(), scale: self.scale }
+    }
+
+    /// The largest whole number not above this one.
+    pub fn floor(self) -> Self {
+        Self { units: self.units.div_euclid(10i128.pow(self.scale)), scale: 0 }
+    }
+
+    /// The smallest whole number not below this one.
+    pub fn ceil(self) -> Self {
+        Self { units: -(-self.units).div_euclid(10i128.pow(self.scale)), scale: 0 }
+    }
+
+    /// Digits before the point, not counting a leading zero.
+    pub fn integer_digits(&self) -> u32 {
+        let integer = (self.units / 10i128.pow(self.scale)).unsigned_abs();
+        if integer == 0 { 0 } else { integer.ilog10() + 1 }
+    }
+}
+
+// Rounds half away from zero.
+fn div_rounded(units: i128, divisor: i128) -> i128 {
+    let (quotient, remainder) = (units / divisor, units % divisor);
+    if remainder.unsigned_abs() * 2 >= divisor.unsigned_abs() {
+        quotient + (units.signum() * divisor.signum())
+    } else {
+        quotient
+    }
+}
+
+impl FromStr for Decimal {
+    type Err = String;
+
+    /// Parses `[+-]digits[.digits]`.
+    fn from_str(s: &str) -> Result<Self, String> {
+        let invalid = || format!("'{}' is not a decimal number", s);
+        let trimmed = s.trim();
+        let (negative, digits) = match trimmed.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, trimmed.strip_prefix('+').unwrap_or(trimmed)),
+        };
+        let (integer, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        if integer.is_empty() && fraction.is_empty()
+            || !integer.bytes().chain(fraction.bytes()).all(|b| b.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let significant = integer.trim_start_matches('0').len() + fraction.len();
+        if significant > MAX_DECIMAL_PRECISION as usize {
+            return Err(format!("'{}' has more than {} digits", s, MAX_DECIMAL_PRECISION));
+        }
+        let units: i128 = format!("{}{}", integer, fraction).parse().unwrap_or(0);
+        Ok(Self { units: if negative { -units } else { units }, scale: fraction.len() as u32 })
+    }
+}
+
+impl fmt::Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let digits = format!("{:0width$}", self.units.unsigned_abs(), width = self.scale as usize + 1);
+        let (integer, fraction) = digits.split_at(digits.len() - self.scale as usize);
+        let sign = if self.units < 0 { "-" } else { "" };
+        if fraction.is_empty() {
+            write!(f, "{}{}", sign, integer)
+        } else {
+            write!(f, "{}{}.{}", sign, integer, fraction)
+        }
+    }
+}
+
+impl Ord for Decimal {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let scale = self.scale.max(other.scale);
+        match (self.rescale(scale), other.rescale(scale)) {
+            (Some(a), Some(b)) => a.units.cmp(&b.units),
+            // Only the one with more integer digits can overflow at the
+            // shared scale, so its sign decides
+            (None, _) => self.units.signum().cmp(&0),
+            (_, None) => 0.cmp(&other.units.signum()),
+        }
+    }
+}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Decimal {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Decimal {}
+
+/// The precision and scale a column declared DECIMAL(p,s) or NUMERIC(p,s)
+/// holds. A bare DECIMAL keeps each value's own scale.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecimalType {
+    pub precision: u32,
+    pub scale: Option<u32>,
+}
+
+impl DecimalType {
+    /// The type a column's data type names, e.g. "DECIMAL(10,2)"; None for
+    /// a type that isn't DECIMAL or NUMERIC.
+    pub fn of(data_type: &str) -> Result<Option<Self>, String> {
+        let upper = data_type.to_uppercase();
+        let Some(params) = upper.strip_prefix("DECIMAL").or_else(|| upper.strip_prefix("NUMERIC")) else {
+            return Ok(None);
+        };
+        if params.is_empty() {
+            return Ok(Some(Self { precision: MAX_DECIMAL_PRECISION, scale: None }));
+        }
+        let invalid = || format!("Invalid data type '{}'", data_type);
+        let params = params.strip_prefix('(').and_then(|p| p.strip_suffix(')')).ok_or_else(invalid)?;
+        let (precision, scale) = match params.split_once(',') {
+            Some((precision, scale)) => (precision, scale),
+            None => (params, "0"),
+        };
+        let precision: u32 = precision.trim().parse().map_err(|_| invalid())?;
+        let scale: u32 = scale.trim().parse().map_err(|_| invalid())?;
+        if !(1..=MAX_DECIMAL_PRECISION).contains(&precision) {
+            return Err(format!("DECIMAL precision must be between 1 and {}", MAX_DECIMAL_PRECISION));
+        }
+        if scale > precision {
+            return Err(format!("DECIMAL scale {} is larger than its precision {}", scale, precision));
+        }
+        Ok(Some(Self { precision, scale: Some(scale) }))
+    }
+
+    /// `value` rounded to the type's scale, or an error if it isn't a number
+    /// or has too many digits before the point. NULL is kept as it is.
+    pub fn fit(&self, value: &str) -> Result<String, String> {
+        if value == NULL {
+            return Ok(value.to_string());
+        }
+        let decimal: Decimal = value.parse()?;
+        let too_large = || format!("'{}' is too large for {}", value, self);
+        let decimal = match self.scale {
+            Some(scale) => decimal.rescale(scale).ok_or_else(too_large)?,
+            None => decimal,
+        };
+        if decimal.integer_digits() > self.precision - self.scale.unwrap_or(0) {
+            return Err(too_large());
+        }
+        Ok(decimal.to_string())
+    }
+}
+
+impl fmt::Display for DecimalType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.scale {
+            Some(scale) => write!(f, "DECIMAL({},{})", self.precision, scale),
+            None => write!(f, "DECIMAL"),
+        }
+    }
+}
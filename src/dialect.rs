@@ -0,0 +1,88 @@
+//! SQL dialects: which other engines' syntax the tokenizer and parser
+//! accept, so statements written for them parse without edits.
+
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Dialect {
+    /// Whatever any of the other dialects accepts, where that's unambiguous:
+    /// `"` and `` ` `` quote identifiers, and every LIMIT form and both ways
+    /// of changing a column's type parse.
+    #[default]
+    Generic,
+    /// ANSI SQL: `"identifiers"`, `ALTER COLUMN c [SET DATA] TYPE t` and
+    /// `OFFSET n ROWS FETCH FIRST n ROWS ONLY`.
+    Standard,
+    /// MySQL: `` `identifiers` ``, `"strings"`, `MODIFY [COLUMN] c t`, and
+    /// `LIMIT n OFFSET m` or `LIMIT m, n`.
+    MySql,
+    /// SQLite: `"identifiers"`, `` `identifiers` `` and `[identifiers]`, and
+    /// both LIMIT forms. SQLite can't change a column's type.
+    Sqlite,
+}
+
+impl Dialect {
+    /// The character that closes an identifier `open` starts, if `open`
+    /// quotes identifiers.
+    pub fn identifier_quote(self, open: char) -> Option<char> {
+        match (self, open) {
+            (Dialect::MySql, '"') => None,
+            (Dialect::Standard, '`') => None,
+            (Dialect::Sqlite, '[') => Some(']'),
+            (_, '"') | (_, '`') => Some(open),
+            _ => None,
+        }
+    }
+
+    /// Whether `"` quotes strings rather than identifiers.
+    pub fn double_quoted_strings(self) -> bool {
+        self == Dialect::MySql
+    }
+
+    /// Whether `ALTER TABLE t MODIFY c type` is accepted.
+    pub fn supports_modify(self) -> bool {
+        matches!(self, Dialect::Generic | Dialect::MySql)
+    }
+
+    /// Whether `ALTER TABLE t ALTER COLUMN c TYPE type` is accepted.
+    pub fn supports_alter_column(self) -> bool {
+        matches!(self, Dialect::Generic | Dialect::Standard)
+    }
+
+    /// Whether `LIMIT n [OFFSET m]` and `LIMIT m, n` are accepted.
+    pub fn supports_limit(self) -> bool {
+        self != Dialect::Standard
+    }
+
+    /// Whether `FETCH FIRST n ROWS ONLY` is accepted.
+    pub fn supports_fetch(self) -> bool {
+        matches!(self, Dialect::Generic | Dialect::Standard)
+    }
+}
+
+impl fmt::Display for Dialect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Dialect::Generic => "generic",
+            Dialect::Standard => "standard",
+            Dialect::MySql => "mysql",
+            Dialect::Sqlite => "sqlite",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Dialect {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "generic" => Ok(Dialect::Generic),
+            "standard" | "ansi" => Ok(Dialect::Standard),
+            "mysql" => Ok(Dialect::MySql),
+            "sqlite" => Ok(Dialect::Sqlite),
+            _ => Err(format!("Unknown dialect '{}'; expected generic, standard, mysql or sqlite", s)),
+        }
+    }
+}
@@ -1,175 +1,269 @@
 use crate::ast::{
     SQLStatement, SelectStatement, InsertStatement, UpdateStatement, DeleteStatement,
-    CreateTableStatement, AlterTableStatement, DropTableStatement, AlterAction,
-    OrderByClause, WhereClause, ColumnExpr,HavingClause, JoinClause, JoinType,
+    CreateTableStatement, AlterTableStatement, DropTableStatement, AlterAction, ColumnExpr,
+    TransactionStatement, WhereClause, InList, JoinClause, FunctionCall, SetValue, NULL,
 };
-use std::collections::HashMap;
+use crate::blob;
+use crate::decimal::DecimalType;
+use crate::join::{assigned_column, join_rows, joined_matches, joined_side, qualify_columns, unqualified_where};
+use crate::math;
+use crate::returning::{returned_rows, Image};
+use crate::output::{ColumnMeta, ResultSet, StatementResult, ValueType};
+use crate::rewrite::{RewriteRule, RewriteRules};
+use crate::row::{parse_select, Row};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+
+type Tables = HashMap<String, Vec<HashMap<String, String>>>;
+// Each table's columns in the order CREATE TABLE declared them, then any
+// added or first inserted since
+type Columns = HashMap<String, Vec<String>>;
 
 #[derive(Debug)]
 pub struct Database {
-    tables: HashMap<String, Vec<HashMap<String, String>>>,
+    tables: Tables,
+    columns: Columns,
+    transaction: Option<Transaction>,
+    rewrite_rules: RewriteRules,
+    rows_affected: usize,
+    last_insert_rowid: Option<String>,
+}
+
+// Undo state for an open transaction: the tables and their columns as they
+// were at BEGIN plus a copy of both for every savepoint taken since.
+#[derive(Debug)]
+struct Transaction {
+    snapshot: Tables,
+    columns: Columns,
+    savepoints: Vec<(String, Tables, Columns)>,
+}
+
+// The format `Database::save` writes. Rows and tables are sorted by key, so
+// saving the same tables twice writes the same file.
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    version: u32,
+    tables: BTreeMap<String, Vec<BTreeMap<String, String>>>,
+    #[serde(default)]
+    columns: BTreeMap<String, Vec<String>>,
+}
+
+const SNAPSHOT_VERSION: u32 = 1;
+
+impl Default for Database {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Database {
     pub fn new() -> Self {
-        Self { tables: HashMap::new() }
+        Self {
+            tables: HashMap::new(),
+            columns: HashMap::new(),
+            transaction: None,
+            rewrite_rules: RewriteRules::default(),
+            rows_affected: 0,
+            last_insert_rowid: None,
+        }
     }
 
-    pub fn execute(&mut self, stmt: SQLStatement) -> Result<String, String> {
-        match stmt {
-            SQLStatement::Select(s)      => self.execute_select(&s),
+    /// The number of rows the last INSERT, UPDATE or DELETE inserted,
+    /// updated or deleted.
+    pub fn rows_affected(&self) -> usize {
+        self.rows_affected
+    }
+
+    /// The position in its table, counting from 1, of the last row the last
+    /// INSERT added, since in-memory rows have no other key. None if no row
+    /// has been inserted.
+    pub fn last_insert_rowid(&self) -> Option<String> {
+        self.last_insert_rowid.clone()
+    }
+
+    /// Names of all tables, sorted.
+    pub fn table_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.tables.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    /// Writes every table and its rows to the snapshot file `path`, which
+    /// `load` reads back. The file is replaced whole, so a crash while saving
+    /// leaves the previous snapshot; during a transaction, the tables as they
+    /// were at BEGIN are saved.
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        let (tables, columns) = match &self.transaction {
+            Some(tx) => (&tx.snapshot, &tx.columns),
+            None => (&self.tables, &self.columns),
+        };
+        let snapshot = Snapshot {
+            version: SNAPSHOT_VERSION,
+            tables: tables.iter()
+                .map(|(name, rows)| (name.clone(), rows.iter().map(|row| row.clone().into_iter().collect()).collect()))
+                .collect(),
+            columns: columns.iter().map(|(name, columns)| (name.clone(), columns.clone())).collect(),
+        };
+        let write = || -> std::io::Result<()> {
+            let tmp_path = format!("{}.tmp", path);
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            serde_json::to_writer(&mut writer, &snapshot)?;
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+            fs::rename(&tmp_path, path)
+        };
+        write().map_err(|e| format!("Failed to save database to '{}': {}", path, e))
+    }
+
+    /// A database holding the tables of the snapshot file `path` that `save`
+    /// wrote.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| format!("Failed to open snapshot '{}': {}", path, e))?;
+        let snapshot: Snapshot = serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| format!("'{}' is not a database snapshot: {}", path, e))?;
+        if snapshot.version > SNAPSHOT_VERSION {
+            return Err(format!("Snapshot '{}' is at version {}, newer than the {} this build supports",
+                               path, snapshot.version, SNAPSHOT_VERSION));
+        }
+        let mut db = Self::new();
+        db.tables = snapshot.tables.into_iter()
+            .map(|(name, rows)| (name, rows.into_iter().map(|row| row.into_iter().collect()).collect()))
+            .collect();
+        db.columns = snapshot.columns.into_iter().collect();
+        Ok(db)
+    }
+
+    /// Runs `rule` on every statement from then on, before it is executed.
+    pub fn add_rewrite_rule(&mut self, rule: impl RewriteRule + 'static) {
+        self.rewrite_rules.add(rule);
+    }
+
+    pub fn execute(&mut self, stmt: SQLStatement) -> Result<StatementResult, String> {
+        match self.rewrite_rules.apply(stmt)? {
+            SQLStatement::Select(s)      => self.select(&s).map(StatementResult::Rows),
             SQLStatement::Insert(s)      => self.execute_insert(s),
             SQLStatement::Update(s)      => self.execute_update(s),
             SQLStatement::Delete(s)      => self.execute_delete(s),
-            SQLStatement::CreateTable(s) => self.execute_create_table(s),
-            SQLStatement::AlterTable(s)  => self.execute_alter_table(s),
-            SQLStatement::DropTable(s)   => self.execute_drop_table(s),
+            SQLStatement::CreateTable(s) => self.execute_create_table(s).map(StatementResult::Done),
+            SQLStatement::AlterTable(s)  => self.execute_alter_table(s).map(StatementResult::Done),
+            SQLStatement::DropTable(s)   => self.execute_drop_table(s).map(StatementResult::Done),
+            SQLStatement::Transaction(s) => self.execute_transaction(s).map(StatementResult::Done),
+            SQLStatement::Show(_)        => Err("SHOW is only supported by the persistent database".to_string()),
+            SQLStatement::Explain(_)     => Err("EXPLAIN is only supported by the persistent database".to_string()),
+            SQLStatement::Vacuum(_)      => Err("VACUUM is only supported by the persistent database".to_string()),
+            SQLStatement::Analyze(_)     => Err("ANALYZE is only supported by the persistent database".to_string()),
+            SQLStatement::SetVariable(..) => Err("SET is only supported in a session of the persistent database".to_string()),
         }
     }
 
-    fn execute_select(&self, stmt: &SelectStatement) -> Result<String, String> {
-        // 1. Evaluate JOIN if any
-        let mut rows = if let Some(join) = &stmt.join {
-            let left_table = self.tables.get(&stmt.table)
-                .ok_or_else(|| format!("Left table '{}' not found", stmt.table))?;
-            let right_table = self.tables.get(&join.table)
-                .ok_or_else(|| format!("Right table '{}' not found", join.table))?;
-    
-            let mut result = Vec::new();
-            let left_col = join.on_left.split('.').last().unwrap();
-let right_col = join.on_right.split('.').last().unwrap();
-
-match join.join_type {
-    JoinType::Inner => {
-        for lrow in left_table {
-            for rrow in right_table {
-                if lrow.get(left_col) == rrow.get(right_col) {
-                    let mut combined = lrow.clone();
-                    for (k, v) in rrow {
-                        combined.insert(format!("{}.{}", join.table, k), v.clone());
-                    }
-                    result.push(combined);
+    fn execute_transaction(&mut self, stmt: TransactionStatement) -> Result<String, String> {
+        match stmt {
+            // Nothing else writes to an in-memory database, so there is
+            // nothing for an optimistic transaction to validate
+            TransactionStatement::Begin | TransactionStatement::BeginOptimistic => {
+                if self.transaction.is_some() {
+                    return Err("Transaction already in progress".to_string());
                 }
+                self.transaction = Some(Transaction {
+                    snapshot: self.tables.clone(),
+                    columns: self.columns.clone(),
+                    savepoints: Vec::new(),
+                });
+                Ok(" Transaction started".to_string())
             }
-        }
-    }
-
-    JoinType::Left => {
-        for lrow in left_table {
-            let mut matched = false;
-            for rrow in right_table {
-                if lrow.get(left_col) == rrow.get(right_col) {
-                    let mut combined = lrow.clone();
-                    for (k, v) in rrow {
-                        combined.insert(format!("{}.{}", join.table, k), v.clone());
-                    }
-                    result.push(combined);
-                    matched = true;
-                }
+            TransactionStatement::Commit => {
+                self.transaction.take().ok_or("No transaction in progress")?;
+                Ok(" Transaction committed".to_string())
             }
-            if !matched {
-                let mut combined = lrow.clone();
-                for k in right_table[0].keys() {
-                    combined.insert(format!("{}.{}", join.table, k), "NULL".to_string());
-                }
-                result.push(combined);
+            TransactionStatement::Rollback => {
+                let tx = self.transaction.take().ok_or("No transaction in progress")?;
+                self.tables = tx.snapshot;
+                self.columns = tx.columns;
+                Ok(" Transaction rolled back".to_string())
             }
-        }
-    }
-
-    JoinType::Right => {
-        for rrow in right_table {
-            let mut matched = false;
-            for lrow in left_table {
-                if lrow.get(left_col) == rrow.get(right_col) {
-                    let mut combined = lrow.clone();
-                    for (k, v) in rrow {
-                        combined.insert(format!("{}.{}", join.table, k), v.clone());
-                    }
-                    result.push(combined);
-                    matched = true;
-                }
+            TransactionStatement::Savepoint(name) => {
+                let tx = self.transaction.as_mut()
+                    .ok_or("SAVEPOINT can only be used inside a transaction")?;
+                tx.savepoints.push((name.clone(), self.tables.clone(), self.columns.clone()));
+                Ok(format!(" Savepoint '{}' created", name))
             }
-            if !matched {
-                let mut combined = HashMap::new();
-                for k in left_table[0].keys() {
-                    combined.insert(k.clone(), "NULL".to_string());
-                }
-                for (k, v) in rrow {
-                    combined.insert(format!("{}.{}", join.table, k), v.clone());
-                }
-                result.push(combined);
+            TransactionStatement::RollbackToSavepoint(name) => {
+                let tx = self.transaction.as_mut().ok_or("No transaction in progress")?;
+                let pos = tx.savepoints.iter().rposition(|(n, ..)| *n == name)
+                    .ok_or_else(|| format!("Savepoint '{}' does not exist", name))?;
+                // The savepoint itself survives a rollback to it; later ones do not.
+                tx.savepoints.truncate(pos + 1);
+                self.tables = tx.savepoints[pos].1.clone();
+                self.columns = tx.savepoints[pos].2.clone();
+                Ok(format!(" Rolled back to savepoint '{}'", name))
+            }
+            TransactionStatement::ReleaseSavepoint(name) => {
+                let tx = self.transaction.as_mut().ok_or("No transaction in progress")?;
+                let pos = tx.savepoints.iter().rposition(|(n, ..)| *n == name)
+                    .ok_or_else(|| format!("Savepoint '{}' does not exist", name))?;
+                tx.savepoints.truncate(pos);
+                Ok(format!(" Released savepoint '{}'", name))
             }
         }
     }
 
-    JoinType::Full => {
-        let mut matched_right = vec![false; right_table.len()];
-        for lrow in left_table {
-            let mut matched = false;
-            for (i, rrow) in right_table.iter().enumerate() {
-                if lrow.get(left_col) == rrow.get(right_col) {
-                    let mut combined = lrow.clone();
-                    for (k, v) in rrow {
-                        combined.insert(format!("{}.{}", join.table, k), v.clone());
-                    }
-                    result.push(combined);
-                    matched = true;
-                    matched_right[i] = true;
-                }
-            }
-            if !matched {
-                let mut combined = lrow.clone();
-                for k in right_table[0].keys() {
-                    combined.insert(format!("{}.{}", join.table, k), "NULL".to_string());
-                }
-                result.push(combined);
-            }
-        }
+    /// Runs the SELECT in `sql` and calls `f` on each row, e.g.
+    /// `db.query_map("SELECT name, age FROM users", |row| row.get::<u32>("age"))`.
+    pub fn query_map<T>(&self, sql: &str, f: impl FnMut(Row<'_>) -> Result<T, String>) -> Result<Vec<T>, String> {
+        self.query(&parse_select(sql)?)?.map(f)
+    }
 
-        for (i, rrow) in right_table.iter().enumerate() {
-            if !matched_right[i] {
-                let mut combined = HashMap::new();
-                for k in left_table[0].keys() {
-                    combined.insert(k.clone(), "NULL".to_string());
-                }
-                for (k, v) in rrow {
-                    combined.insert(format!("{}.{}", join.table, k), v.clone());
-                }
-                result.push(combined);
-            }
-        }
+    /// Runs the SELECT in `sql`, deserializing each row into a `T`; see
+    /// `ResultSet::deserialize`.
+    pub fn query_as<T: DeserializeOwned>(&self, sql: &str) -> Result<Vec<T>, String> {
+        self.query(&parse_select(sql)?)?.deserialize()
     }
-    JoinType::Cross => {
-        for lrow in left_table {
-            for rrow in right_table {
-                let mut combined = lrow.clone();
-                for (k, v) in rrow {
-                    combined.insert(format!("{}.{}", join.table, k), v.clone());
-                }
-                result.push(combined);
-            }
-        }
+
+    /// Runs a SELECT, returning its rows rather than formatting them.
+    pub fn query(&self, stmt: &SelectStatement) -> Result<ResultSet, String> {
+        self.select(&*self.rewrite_rules.apply_select(stmt)?)
     }
-}
 
+    fn select(&self, stmt: &SelectStatement) -> Result<ResultSet, String> {
+        check_grouping(stmt)?;
+        if stmt.as_of.is_some() {
+            return Err("AS OF is only supported by the persistent database, which keeps a table's history".to_string());
+        }
+        if let Some(function) = &stmt.table_function {
+            return Err(format!("{}() is only supported by the persistent database, which keeps ANALYZE's statistics", function.name()));
+        }
+
+        // 1. Evaluate JOIN if any
+        let mut rows = if let Some(join) = &stmt.join {
+            let left_table = self.tables.get(&stmt.table)
+                .ok_or_else(|| format!("Left table '{}' not found", stmt.table))?;
+            let right_rows = self.joined_side(join, "Right table")?;
     
-            result} else {
-            self.tables.get(&stmt.table)
+            join_rows(left_table, &right_rows, join, &stmt.table, false)
+        } else {
+            let mut rows = self.tables.get(&stmt.table)
                 .ok_or_else(|| format!("Table '{}' not found", stmt.table))?
-                .clone()
+                .clone();
+            qualify_columns(&mut rows, &stmt.table);
+            rows
         };
     
         // 2. Apply WHERE filter
         if let Some(where_clause) = &stmt.where_clause {
-            rows = rows.into_iter()
-                .filter(|row| row.get(&where_clause.column)
-                    .map_or(false, |val| val == &where_clause.value))
-                .collect();
+            let where_clause = self.run_subquery(where_clause)?;
+            rows.retain(|row| where_matches(row, &where_clause));
         }
     
-        // 3. Apply GROUP BY
-        if let Some(group_cols) = &stmt.group_by {
+        // 3. Apply GROUP BY, keeping the rows each group aggregates over.
+        // Aggregates without GROUP BY make one group of every row.
+        let filtered = rows.clone();
+        let group_cols = stmt.group_by.as_deref().unwrap_or_default();
+        if stmt.group_by.is_some() || has_aggregates(&stmt.columns) {
             let mut seen = Vec::new();
             let mut grouped = Vec::new();
             for r in &rows {
@@ -183,248 +277,299 @@ match join.join_type {
             }
             rows = grouped;
         }
+        let group_of = |row: &HashMap<String, String>| -> Vec<&HashMap<String, String>> {
+            filtered.iter()
+                .filter(|r| group_cols.iter().all(|col| r.get(col) == row.get(col)))
+                .collect()
+        };
     
+        // A group's value of a select-list expression, or of the one an
+        // alias names
+        let value_of = |key: &ColumnExpr, row: &HashMap<String, String>| match key {
+            ColumnExpr::Column(c) => row.get(c).cloned().unwrap_or_else(|| NULL.to_string()),
+            ColumnExpr::Length(c) => blob::length(row.get(c).map_or(NULL, String::as_str)),
+            ColumnExpr::Math(call) => math::value_of(call, row),
+            aggregated => aggregate(aggregated, &group_of(row)).to_string(),
+        };
+
         // 4. Apply HAVING
         if let Some(having) = &stmt.having {
-            let val: f64 = having.value.parse().unwrap_or(0.0);
-            let all_rows = rows.clone();
-            rows = rows.into_iter().filter(|group_row| {
-                let group: Vec<_> = self.tables.get(&stmt.table).unwrap().iter().filter(|r| {
-                    stmt.group_by.as_ref().map_or(true, |cols| {
-                        cols.iter().all(|c| r.get(c) == group_row.get(c))
-                    })
-                }).collect();
-    
-                let agg_val = match &having.column_expr {
-                    ColumnExpr::CountAll => group.len() as f64,
-                    ColumnExpr::Count(col) => group.iter().filter(|r| r.contains_key(col)).count() as f64,
-                    ColumnExpr::Sum(col) => group.iter().filter_map(|r| r.get(col)?.parse::<f64>().ok()).sum(),
-                    ColumnExpr::Avg(col) => {
-                        let vals: Vec<f64> = group.iter().filter_map(|r| r.get(col)?.parse::<f64>().ok()).collect();
-                        if vals.is_empty() { 0.0 } else { vals.iter().sum::<f64>() / vals.len() as f64 }
-                    }
-                    ColumnExpr::Min(col) => group.iter().filter_map(|r| r.get(col)?.parse::<f64>().ok()).min_by(|a, b| a.partial_cmp(b).unwrap()).unwrap_or(0.0),
-                    ColumnExpr::Max(col) => group.iter().filter_map(|r| r.get(col)?.parse::<f64>().ok()).max_by(|a, b| a.partial_cmp(b).unwrap()).unwrap_or(0.0),
-                    _ => 0.0,
-                };
-    
+            let key = resolve_alias(&stmt.columns, &having.column_expr);
+            rows.retain(|group_row| {
+                let ordering = compare_values(&value_of(key, group_row), &having.value);
                 match having.operator.as_str() {
-                    "=" => agg_val == val,
-                    ">" => agg_val > val,
-                    "<" => agg_val < val,
+                    "=" => ordering == Ordering::Equal,
+                    ">" => ordering == Ordering::Greater,
+                    "<" => ordering == Ordering::Less,
                     _ => false,
                 }
-            }).collect();
+            });
         }
     
-        // 5. Apply ORDER BY
+        // 5. Apply ORDER BY, by a column's text or an aggregate's value
         if let Some(order) = &stmt.order_by {
-            let empty = String::new();
-            rows.sort_by(|a, b| {
-                let va = a.get(&order.column).unwrap_or(&empty);
-                let vb = b.get(&order.column).unwrap_or(&empty);
-                if order.descending { vb.cmp(va) } else { va.cmp(vb) }
+            let key = resolve_alias(&stmt.columns, &order.expr);
+            let mut keyed: Vec<_> = rows.into_iter()
+                .map(|row| (value_of(key, &row), row))
+                .collect();
+            keyed.sort_by(|(a, _), (b, _)| {
+                let ordering = match key {
+                    ColumnExpr::Column(_) => a.cmp(b),
+                    _ => compare_values(a, b),
+                };
+                if order.descending { ordering.reverse() } else { ordering }
             });
+            rows = keyed.into_iter().map(|(_, row)| row).collect();
         }
     
-        // 6. Output formatting
-        if rows.is_empty() {
-            return Err("No matching rows found".to_string());
-        }
-    
-        let mut output = String::new();
+        // 6. Apply OFFSET and LIMIT
+        let rows: Vec<_> = rows.into_iter()
+            .skip(stmt.offset.unwrap_or(0))
+            .take(stmt.limit.unwrap_or(usize::MAX))
+            .collect();
+
+        // 7. Collect the selected values; SELECT * shows each column once, in
+        // schema order, by its qualified name only over a JOIN
+        let all_keys = match &stmt.join {
+            Some(join) => std::iter::once(stmt.table.as_str()).chain(join.tables())
+                .flat_map(|table| self.all_columns(table).into_iter().map(move |column| format!("{}.{}", table, column)))
+                .collect(),
+            None => self.all_columns(&stmt.table),
+        };
         let headers: Vec<String> = if stmt.columns.len() == 1 && matches!(stmt.columns[0], ColumnExpr::All) {
-            let mut keys: Vec<_> = rows[0].keys().cloned().collect();
-            keys.sort();
-            keys
+            all_keys.clone()
         } else {
-            stmt.columns.iter().map(|col| match col {
-                ColumnExpr::Column(c) => c.clone(),
-                ColumnExpr::All => "*".to_string(),
-                ColumnExpr::Count(c) => format!("COUNT({})", c),
-                ColumnExpr::CountAll => "COUNT(*)".to_string(),
-                ColumnExpr::Sum(c) => format!("SUM({})", c),
-                ColumnExpr::Avg(c) => format!("AVG({})", c),
-                ColumnExpr::Min(c) => format!("MIN({})", c),
-                ColumnExpr::Max(c) => format!("MAX({})", c),
-            }).collect()
+            stmt.columns.iter().map(ColumnExpr::name).collect()
         };
-        output += &headers.join(" | ");
-        output += "\n";
-        output += &"-".repeat(headers.join(" | ").len());
-        output += "\n";
-    
+        let mut values = Vec::new();
         for row in rows {
-            let line = if stmt.columns.len() == 1 && matches!(stmt.columns[0], ColumnExpr::All) {
-                let mut keys: Vec<_> = row.keys().collect();
-                keys.sort();
-                keys.iter()
-                    .map(|k| row.get(*k).unwrap_or(&"".to_string()).clone())
+            let selected = if stmt.columns.len() == 1 && matches!(stmt.columns[0], ColumnExpr::All) {
+                all_keys.iter()
+                    .map(|k| row.get(k).cloned().unwrap_or_else(|| NULL.to_string()))
                     .collect::<Vec<_>>()
-                    .join(" | ")
             } else {
-                stmt.columns.iter().map(|col| {
-                    match col {
-                        ColumnExpr::Column(c) => {
-                            // Try fully qualified first
-                            row.get(c).cloned()
-                             // If not found, try unqualified match
-                             .or_else(|| {
-                                 let parts: Vec<&str> = c.split('.').collect();
-                                 if parts.len() == 2 {
-                                     row.get(parts[1]).cloned()
-                                 } else {
-                                     None
-                                 }
-                             })
-                             .unwrap_or_default()
-                        },                        
-
-        ColumnExpr::CountAll => {
-            let group_rows: Vec<_> = self.tables.get(&stmt.table).unwrap().iter()
-                .filter(|r| stmt.group_by.as_ref().map_or(true, |cols| {
-                    cols.iter().all(|col| r.get(col) == row.get(col))
-                }))
-                .collect();
-            group_rows.len().to_string()
-        }
-
-        ColumnExpr::Count(c) => {
-            let group_rows: Vec<_> = self.tables.get(&stmt.table).unwrap().iter()
-                .filter(|r| stmt.group_by.as_ref().map_or(true, |cols| {
-                    cols.iter().all(|col| r.get(col) == row.get(col))
-                }))
-                .collect();
-            group_rows.iter().filter(|r| r.contains_key(c)).count().to_string()
-        }
-
-        ColumnExpr::Sum(c) => {
-            let group_rows: Vec<_> = self.tables.get(&stmt.table).unwrap().iter()
-                .filter(|r| stmt.group_by.as_ref().map_or(true, |cols| {
-                    cols.iter().all(|col| r.get(col) == row.get(col))
-                }))
-                .collect();
-            let sum: f64 = group_rows.iter()
-                .filter_map(|r| r.get(c)?.parse::<f64>().ok())
-                .sum();
-            sum.to_string()
-        }
-
-        ColumnExpr::Avg(c) => {
-            let group_rows: Vec<_> = self.tables.get(&stmt.table).unwrap().iter()
-                .filter(|r| stmt.group_by.as_ref().map_or(true, |cols| {
-                    cols.iter().all(|col| r.get(col) == row.get(col))
-                }))
-                .collect();
-            let vals: Vec<f64> = group_rows.iter()
-                .filter_map(|r| r.get(c)?.parse::<f64>().ok())
-                .collect();
-            if vals.is_empty() { "0".to_string() }
-            else { (vals.iter().sum::<f64>() / vals.len() as f64).to_string() }
-        }
-
-        ColumnExpr::Min(c) => {
-            let group_rows: Vec<_> = self.tables.get(&stmt.table).unwrap().iter()
-                .filter(|r| stmt.group_by.as_ref().map_or(true, |cols| {
-                    cols.iter().all(|col| r.get(col) == row.get(col))
-                }))
-                .collect();
-            group_rows.iter()
-                .filter_map(|r| r.get(c)?.parse::<f64>().ok())
-                .min_by(|a, b| a.partial_cmp(b).unwrap())
-                .unwrap_or(0.0)
-                .to_string()
-        }
-
-        ColumnExpr::Max(c) => {
-            let group_rows: Vec<_> = self.tables.get(&stmt.table).unwrap().iter()
-                .filter(|r| stmt.group_by.as_ref().map_or(true, |cols| {
-                    cols.iter().all(|col| r.get(col) == row.get(col))
-                }))
-                .collect();
-            group_rows.iter()
-                .filter_map(|r| r.get(c)?.parse::<f64>().ok())
-                .max_by(|a, b| a.partial_cmp(b).unwrap())
-                .unwrap_or(0.0)
-                .to_string()
-        }
-
-        _ => "".to_string() 
-                    }
-                }).collect::<Vec<_>>().join(" | ")
+                stmt.columns.iter().map(|col| match col.unaliased() {
+                    ColumnExpr::Column(c) => row.get(c).cloned().unwrap_or_else(|| NULL.to_string()),
+                    ColumnExpr::Length(c) => blob::length(row.get(c).map_or(NULL, String::as_str)),
+                    ColumnExpr::Math(call) => math::value_of(call, &row),
+                    ColumnExpr::All | ColumnExpr::Aliased(..) => String::new(),
+                    aggregated => aggregate(aggregated, &group_of(&row)).to_string(),
+                }).collect::<Vec<_>>()
             };
-            output += &line;
-            output += "\n";
+            values.push(selected);
         }
     
-        Ok(output)
+        let mut meta = ColumnMeta::of_select(&stmt.columns, &headers, |name| {
+            let table = match (name.split_once('.'), &stmt.join) {
+                (Some((table, _)), _) => table,
+                // A bare name is of the first table that has it
+                (None, Some(join)) => std::iter::once(stmt.table.as_str()).chain(join.tables())
+                    .find(|table| self.tables[*table].first().is_some_and(|row| row.contains_key(name)))
+                    .unwrap_or(&join.table),
+                (None, None) => &stmt.table,
+            };
+            (Some(table.to_string()), ValueType::Unknown)
+        });
+        // The type of a column read from a table is that of its values
+        for (i, column) in meta.iter_mut().enumerate().filter(|(_, column)| column.table.is_some()) {
+            column.value_type = ValueType::infer(values.iter().map(|row| row[i].as_str()));
+        }
+        Ok(ResultSet { columns: headers, rows: values, meta })
     }
     
     
-    fn execute_insert(&mut self, stmt: InsertStatement) -> Result<String, String> {
-        let table = self.tables.entry(stmt.table.clone()).or_insert_with(Vec::new);
-    
-        for value_tuple in stmt.values {
-            if stmt.columns.len() != value_tuple.len() {
-                return Err("Column count does not match value count".to_string());
+    fn execute_insert(&mut self, stmt: InsertStatement) -> Result<StatementResult, String> {
+        // There's no primary key for a row to conflict on
+        if stmt.on_conflict.is_some() {
+            return Err("INSERT OR IGNORE and INSERT OR REPLACE need a primary key, which in-memory tables don't have".to_string());
+        }
+        // Without a column list, each tuple gives every column in order
+        let insert_columns = if stmt.columns.is_empty() {
+            self.columns.get(&stmt.table).cloned()
+                .ok_or_else(|| format!("Table '{}' not found", stmt.table))?
+        } else {
+            stmt.columns
+        };
+        // Every row is checked before any is added, so a bad one inserts
+        // none of them, nor creates the table
+        if stmt.values.iter().any(|value_tuple| value_tuple.len() != insert_columns.len()) {
+            return Err("Column count does not match value count".to_string());
+        }
+        let table = self.tables.entry(stmt.table.clone()).or_default();
+        let columns = self.columns.entry(stmt.table.clone()).or_default();
+        for column in &insert_columns {
+            if !columns.contains(column) {
+                columns.push(column.clone());
             }
+        }
+        self.rows_affected = 0;
     
-            let new_row: HashMap<String, String> = stmt.columns
+        for value_tuple in stmt.values {
+            let new_row: HashMap<String, String> = insert_columns
                 .iter()
                 .cloned()
-                .zip(value_tuple.into_iter())
+                .zip(value_tuple)
                 .collect();
     
             table.push(new_row);
+            self.rows_affected += 1;
+            self.last_insert_rowid = Some(table.len().to_string());
         }
     
-        Ok(" Insert successful".to_string())
+        Ok(StatementResult::Changed {
+            message: " Insert successful".to_string(),
+            rows_affected: self.rows_affected,
+            last_insert_rowid: self.last_insert_rowid.clone(),
+        })
     }
     
 
-    fn execute_update(&mut self, stmt: UpdateStatement) -> Result<String, String> {
+    fn execute_update(&mut self, stmt: UpdateStatement) -> Result<StatementResult, String> {
+        let matches = self.rows_to_change(&stmt.table, stmt.join.as_ref(), stmt.where_clause.as_ref())?;
+        let assignments = stmt.assignments.iter()
+            .map(|(col, val)| {
+                let val = match val {
+                    SetValue::Math(call) => {
+                        let column = assigned_column(&call.column, &stmt.table)?.to_string();
+                        SetValue::Math(FunctionCall { column, ..call.clone() })
+                    }
+                    SetValue::Value(_) => val.clone(),
+                };
+                Ok((assigned_column(col, &stmt.table)?.to_string(), val))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let columns = self.all_columns(&stmt.table);
+        if let Some(returning) = &stmt.returning {
+            returned_rows(returning, &stmt.table, &columns, Image::New, &[])?;
+        }
         let table = self.tables.get_mut(&stmt.table)
             .ok_or_else(|| format!("Table '{}' not found", stmt.table))?;
 
         let mut updated = 0;
-        for row in table.iter_mut() {
-            if stmt.where_clause.as_ref().map_or(true, |wc| row.get(&wc.column) == Some(&wc.value)) {
-                for (col, val) in &stmt.assignments {
-                    row.insert(col.clone(), val.clone());
-                }
+        let mut changed = Vec::new();
+        for (row, matched) in table.iter_mut().zip(matches) {
+            if matched {
+                let old = stmt.returning.is_some().then(|| row.clone());
+                // Every function reads the row as it was before the UPDATE
+                let values: Vec<_> = assignments.iter()
+                    .map(|(col, val)| match val {
+                        SetValue::Value(value) => (col.clone(), value.clone()),
+                        SetValue::Math(call) => (col.clone(), math::value_of(call, row)),
+                    })
+                    .collect();
+                row.extend(values);
+                changed.extend(old.map(|old| (old, Some(row.clone()))));
                 updated += 1;
             }
         }
+        self.rows_affected = updated;
 
-        if updated > 0 {
-            Ok(format!(" Updated {} row(s)", updated))
+        if let Some(returning) = &stmt.returning {
+            returned_rows(returning, &stmt.table, &columns, Image::New, &changed).map(StatementResult::Rows)
+        } else if updated > 0 {
+            Ok(StatementResult::Changed {
+                message: format!(" Updated {} row(s)", updated),
+                rows_affected: updated,
+                last_insert_rowid: None,
+            })
         } else {
             Err("No rows updated".into())
         }
     }
 
-    fn execute_delete(&mut self, stmt: DeleteStatement) -> Result<String, String> {
+    fn execute_delete(&mut self, stmt: DeleteStatement) -> Result<StatementResult, String> {
+        let mut matches = self.rows_to_change(&stmt.table, stmt.join.as_ref(), stmt.where_clause.as_ref())?.into_iter();
+        let columns = self.all_columns(&stmt.table);
+        if let Some(returning) = &stmt.returning {
+            returned_rows(returning, &stmt.table, &columns, Image::Old, &[])?;
+        }
         let table = self.tables.get_mut(&stmt.table)
             .ok_or_else(|| format!("Table '{}' not found", stmt.table))?;
 
         let before = table.len();
+        let mut changed = Vec::new();
         table.retain(|row| {
-            stmt.where_clause.as_ref().map_or(true, |wc| row.get(&wc.column).map_or(true, |v| v != &wc.value))
+            let matched = matches.next().unwrap_or(false);
+            if matched && stmt.returning.is_some() {
+                changed.push((row.clone(), None));
+            }
+            !matched
         });
         let deleted = before - table.len();
+        self.rows_affected = deleted;
 
-        if deleted > 0 {
-            Ok(format!("🗑️ Deleted {} row(s)", deleted))
+        if let Some(returning) = &stmt.returning {
+            returned_rows(returning, &stmt.table, &columns, Image::Old, &changed).map(StatementResult::Rows)
+        } else if deleted > 0 {
+            Ok(StatementResult::Changed {
+                message: format!("🗑️ Deleted {} row(s)", deleted),
+                rows_affected: deleted,
+                last_insert_rowid: None,
+            })
         } else {
             Err("No matching rows to delete".into())
         }
     }
 
+    // Which rows of `table` an UPDATE or DELETE changes: those meeting its
+    // WHERE, or over a JOIN, joined to a row with which they meet it
+    fn rows_to_change(&self, table: &str, join: Option<&JoinClause>, where_clause: Option<&WhereClause>)
+                      -> Result<Vec<bool>, String> {
+        let rows = self.tables.get(table)
+            .ok_or_else(|| format!("Table '{}' not found", table))?;
+        let where_clause = where_clause.map(|wc| self.run_subquery(wc)).transpose()?;
+        match join {
+            Some(join) => {
+                let right_rows = self.joined_side(join, "Table")?;
+                joined_matches(rows, &right_rows, join, table, |row| {
+                    Ok(where_clause.as_ref().is_none_or(|wc| where_matches(row, wc)))
+                })
+            }
+            None => {
+                let where_clause = where_clause.map(|wc| unqualified_where(&wc, table));
+                Ok(rows.iter().map(|row| where_clause.as_ref().is_none_or(|wc| where_matches(row, wc))).collect())
+            }
+        }
+    }
+
+    // The rows the joined side of `join` reads, each of whose tables must
+    // exist; `what` names a missing one in the error
+    fn joined_side(&self, join: &JoinClause, what: &str) -> Result<Cow<'_, [HashMap<String, String>]>, String> {
+        joined_side(join, &mut |table| match self.tables.get(table) {
+            Some(rows) => Ok(Cow::Borrowed(rows)),
+            None => Err(format!("{} '{}' not found", what, table)),
+        })
+    }
+
+    // The columns of `table` in schema order; for a table loaded from a
+    // snapshot that didn't keep the order, those of its first row, sorted
+    fn all_columns(&self, table: &str) -> Vec<String> {
+        if let Some(columns) = self.columns.get(table) {
+            return columns.clone();
+        }
+        let mut columns: Vec<String> = self.tables.get(table).and_then(|rows| rows.first())
+            .map_or_else(Vec::new, |row| row.keys().cloned().collect());
+        columns.sort();
+        columns
+    }
+
+    fn run_subquery(&self, where_clause: &WhereClause) -> Result<WhereClause, String> {
+        with_subquery_run(where_clause, |query| self.query(query))
+    }
+
     fn execute_create_table(&mut self, stmt: CreateTableStatement) -> Result<String, String> {
+        let mut names = HashSet::new();
+        if let Some((column, _)) = stmt.columns.iter().find(|(name, _)| !names.insert(name)) {
+            return Err(format!("Column '{}' is defined more than once in table '{}'", column, stmt.table));
+        }
+        for (_, data_type) in &stmt.columns {
+            check_type(data_type)?;
+        }
         if self.tables.contains_key(&stmt.table) {
             Err(format!("Table '{}' already exists", stmt.table))
         } else {
             self.tables.insert(stmt.table.clone(), Vec::new());
+            self.columns.insert(stmt.table.clone(), stmt.columns.into_iter().map(|(name, _)| name).collect());
             Ok(format!(" Created table '{}'", stmt.table))
         }
     }
@@ -432,11 +577,19 @@ match join.join_type {
     fn execute_alter_table(&mut self, stmt: AlterTableStatement) -> Result<String, String> {
         let td = self.tables.get_mut(&stmt.table)
             .ok_or_else(|| format!("Table '{}' not found", stmt.table))?;
+        match &stmt.action {
+            AlterAction::AddColumn(_, Some(data_type)) | AlterAction::ModifyColumn(_, data_type) => check_type(data_type)?,
+            _ => {}
+        }
 
         match &stmt.action {
-            AlterAction::AddColumn(col) => {
+            AlterAction::AddColumn(col, _) => {
                 for row in td.iter_mut() {
-                    row.insert(col.clone(), String::new());
+                    row.insert(col.clone(), NULL.to_string());
+                }
+                let columns = self.columns.entry(stmt.table.clone()).or_default();
+                if !columns.contains(col) {
+                    columns.push(col.clone());
                 }
                 Ok(format!(" Added column '{}' to '{}'", col, stmt.table))
             }
@@ -444,19 +597,152 @@ match join.join_type {
                 for row in td.iter_mut() {
                     row.remove(col);
                 }
+                if let Some(columns) = self.columns.get_mut(&stmt.table) {
+                    columns.retain(|column| column != col);
+                }
                 Ok(format!(" Dropped column '{}' from '{}'", col, stmt.table))
             }
             AlterAction::ModifyColumn(col, new_type) => {
                 Ok(format!(" Modified column '{}' to '{}' in '{}'", col, new_type, stmt.table))
             }
+            AlterAction::SetOptions(_) => {
+                Ok(format!(" Storage options have no effect on in-memory table '{}'", stmt.table))
+            }
         }
     }
 
     fn execute_drop_table(&mut self, stmt: DropTableStatement) -> Result<String, String> {
         if self.tables.remove(&stmt.table).is_some() {
+            self.columns.remove(&stmt.table);
             Ok(format!("🗑️ Dropped table '{}'", stmt.table))
         } else {
             Err(format!("Table '{}' does not exist", stmt.table))
         }
     }
 }
+
+// Tables here keep no column types, so a DECIMAL couldn't be kept exact; its
+// sums and averages would drift like any float's
+fn check_type(data_type: &str) -> Result<(), String> {
+    match DecimalType::of(data_type)? {
+        Some(_) => Err(format!("{} columns need the persistent database; in-memory tables keep no column types", data_type)),
+        None => Ok(()),
+    }
+}
+
+// Compares two cell values numerically when both parse as numbers and
+// lexicographically otherwise.
+pub(crate) fn compare_values(a: &str, b: &str) -> Ordering {
+    match (a.parse::<f64>(), b.parse::<f64>()) {
+        (Ok(x), Ok(y)) => x.partial_cmp(&y).unwrap_or(Ordering::Equal),
+        _ => a.cmp(b),
+    }
+}
+
+/// `where_clause` with an IN subquery replaced by the values `select`
+/// returns for it, less NULLs, which IN never matches.
+pub(crate) fn with_subquery_run(where_clause: &WhereClause,
+                                select: impl FnOnce(&SelectStatement) -> Result<ResultSet, String>)
+                                -> Result<WhereClause, String> {
+    let Some(InList::Subquery(query)) = &where_clause.in_list else {
+        return Ok(where_clause.clone());
+    };
+    let result = select(query)?;
+    if result.columns.len() != 1 {
+        return Err(format!("A subquery in IN must select one column, not {}", result.columns.len()));
+    }
+    let values = result.rows.into_iter()
+        .filter_map(|mut row| row.pop())
+        .filter(|value| value != NULL)
+        .collect();
+    Ok(WhereClause { in_list: Some(InList::Values(values)), ..where_clause.clone() })
+}
+
+// A comparison with NULL never holds; only IS NULL finds it
+fn where_matches(row: &HashMap<String, String>, wc: &WhereClause) -> bool {
+    let value = row.get(&wc.column).map_or(NULL, String::as_str);
+    let computed;
+    let value = match &wc.function {
+        Some(call) => {
+            computed = math::apply(call, value);
+            &computed
+        }
+        None => value,
+    };
+    if value == NULL || wc.value == NULL {
+        return match wc.operator.as_str() {
+            "IS" => value == NULL,
+            "IS NOT" => value != NULL,
+            _ => false,
+        };
+    }
+    match wc.operator.as_str() {
+        "=" => value == wc.value,
+        "<" => compare_values(value, &wc.value) == Ordering::Less,
+        ">" => compare_values(value, &wc.value) == Ordering::Greater,
+        "BETWEEN" => {
+            let upper = wc.upper_bound.as_deref().unwrap_or(&wc.value);
+            compare_values(value, &wc.value) != Ordering::Less
+                && compare_values(value, upper) != Ordering::Greater
+        }
+        "IN" => matches!(&wc.in_list, Some(InList::Values(values)) if values.iter().any(|v| v == value)),
+        _ => false,
+    }
+}
+
+// An aggregate over the rows of a group, as a number; 0 for anything that
+// isn't an aggregate.
+fn aggregate(expr: &ColumnExpr, group: &[&HashMap<String, String>]) -> f64 {
+    let numbers = |col: &str| -> Vec<f64> {
+        group.iter().filter_map(|r| r.get(col)?.parse::<f64>().ok()).collect()
+    };
+    match expr {
+        ColumnExpr::CountAll => group.len() as f64,
+        ColumnExpr::Count(col) => group.iter().filter(|r| r.get(col).is_some_and(|value| value != NULL)).count() as f64,
+        ColumnExpr::Sum(col) => numbers(col).iter().sum(),
+        ColumnExpr::Avg(col) => {
+            let vals = numbers(col);
+            if vals.is_empty() { 0.0 } else { vals.iter().sum::<f64>() / vals.len() as f64 }
+        }
+        ColumnExpr::Min(col) => numbers(col).into_iter().min_by(|a, b| a.partial_cmp(b).unwrap()).unwrap_or(0.0),
+        ColumnExpr::Max(col) => numbers(col).into_iter().max_by(|a, b| a.partial_cmp(b).unwrap()).unwrap_or(0.0),
+        _ => 0.0,
+    }
+}
+
+pub(crate) fn has_aggregates(columns: &[ColumnExpr]) -> bool {
+    columns.iter().any(ColumnExpr::is_aggregate)
+}
+
+/// The select-list expression `expr` names if it is one of the list's
+/// aliases, as ORDER BY can use, or else `expr` itself.
+pub(crate) fn resolve_alias<'a>(columns: &'a [ColumnExpr], expr: &'a ColumnExpr) -> &'a ColumnExpr {
+    let aliased = columns.iter().find_map(|column| match (column, expr) {
+        (ColumnExpr::Aliased(aliased, alias), ColumnExpr::Column(name)) if alias == name => Some(aliased.unaliased()),
+        _ => None,
+    });
+    aliased.unwrap_or(expr)
+}
+
+// Every column selected alongside GROUP BY or an aggregate must be grouped
+// on, since the rows of a group can each have a different value of it.
+pub(crate) fn check_grouping(stmt: &SelectStatement) -> Result<(), String> {
+    if stmt.group_by.is_none() && !has_aggregates(&stmt.columns) {
+        return Ok(());
+    }
+    let unqualified = |column: &str| column.rsplit('.').next().unwrap_or(column).to_string();
+    let grouped: Vec<String> = stmt.group_by.iter().flatten().map(|c| unqualified(c)).collect();
+    for col in &stmt.columns {
+        match col.unaliased() {
+            ColumnExpr::All => {
+                return Err("SELECT * can't be combined with GROUP BY or aggregates; select the grouped columns instead".to_string());
+            }
+            ColumnExpr::Column(c) | ColumnExpr::Length(c) | ColumnExpr::Math(FunctionCall { column: c, .. })
+                if !grouped.contains(&unqualified(c)) => {
+                return Err(format!("Column '{}' must appear in GROUP BY or be used in an aggregate function", c));
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,65 @@
+//! Failure injection for crash testing, built with the `fault-injection`
+//! feature.
+//!
+//! A fault armed with [`inject`] makes the storage of every table under the
+//! given directory fail with an I/O error when it reaches the fault point,
+//! leaving its files as a crash at that moment would. The failed storage is
+//! then in an undefined state: drop it (which, unlike `close`, flushes
+//! nothing) and reopen the directory to check what recovery makes of it.
+//!
+//! Faults are keyed by directory, so tests using their own temporary
+//! directories can inject them in parallel.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultPoint {
+    /// After a write batch is appended to the WAL, before the memtable sees it.
+    WalAppend,
+    /// After a flush has written its SSTable, before the manifest lists it.
+    Flush,
+    /// After a compaction has written its outputs, before the manifest
+    /// swaps them in for its inputs.
+    Compaction,
+    /// After a shard has written its part of a batch spanning a partitioned
+    /// table's shards, before the next shard writes its own.
+    ShardBatch,
+}
+
+#[derive(Debug)]
+struct Fault {
+    dir: PathBuf,
+    point: FaultPoint,
+    // Times the point is passed before the fault fires
+    skip: usize,
+}
+
+static FAULTS: Mutex<Vec<Fault>> = Mutex::new(Vec::new());
+
+/// Arms a fault that fires, once, the `skip + 1`th time storage under `dir`
+/// reaches `point`.
+pub fn inject(dir: &Path, point: FaultPoint, skip: usize) {
+    let mut faults = FAULTS.lock().unwrap_or_else(PoisonError::into_inner);
+    faults.push(Fault { dir: dir.to_path_buf(), point, skip });
+}
+
+/// Disarms every fault under `dir` that hasn't fired.
+pub fn clear(dir: &Path) {
+    FAULTS.lock().unwrap_or_else(PoisonError::into_inner).retain(|f| !f.dir.starts_with(dir));
+}
+
+// Called by the storage at each fault point with its table directory.
+pub(crate) fn check(table_dir: &Path, point: FaultPoint) -> io::Result<()> {
+    let mut faults = FAULTS.lock().unwrap_or_else(PoisonError::into_inner);
+    let Some(index) = faults.iter().position(|f| f.point == point && table_dir.starts_with(&f.dir)) else {
+        return Ok(());
+    };
+    if faults[index].skip > 0 {
+        faults[index].skip -= 1;
+        return Ok(());
+    }
+    faults.remove(index);
+    Err(io::Error::other(format!("injected fault at {:?} in {}", point, table_dir.display())))
+}
@@ -0,0 +1,410 @@
+//! Writing statements back out as SQL: on one line through `Display`, or
+//! laid out one clause per line by `format_sql`.
+
+use crate::ast::{
+    AlterAction, ColumnExpr, ConflictAction, CreateTableStatement, ExplainFormat, FunctionCall, HavingClause, Hint, InList, JoinClause, JoinCondition, JoinType, SQLStatement,
+    SelectStatement, SetValue, ShowStatement, TransactionStatement, WhereClause, NULL,
+};
+use crate::blob::Blob;
+use crate::dialect::Dialect;
+use crate::parser::{parse_statement_with_dialect, RESERVED_NAMES};
+use crate::tokenizer::{tokenize, SqlError, Token};
+use std::fmt;
+
+/// Parses `sql` and writes it back with upper-case keywords, each clause on
+/// its own line and lists of columns or rows indented beneath their
+/// statement, ending in `;`.
+pub fn format_sql(sql: &str) -> Result<String, SqlError> {
+    format_sql_with_dialect(sql, Dialect::default())
+}
+
+pub fn format_sql_with_dialect(sql: &str, dialect: Dialect) -> Result<String, SqlError> {
+    let statement = parse_statement_with_dialect(sql, dialect)?;
+    Ok(format_statement(&statement, dialect))
+}
+
+/// `statement` laid out as `format_sql` does, in syntax `dialect` parses.
+pub fn format_statement(statement: &SQLStatement, dialect: Dialect) -> String {
+    let mut sql = SqlWriter { dialect, pretty: true }.statement(statement).join("\n");
+    sql.push(';');
+    sql
+}
+
+/// The statement as one line of SQL, without a trailing `;`.
+impl fmt::Display for SQLStatement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let writer = SqlWriter { dialect: Dialect::default(), pretty: false };
+        f.write_str(&writer.statement(self).join(" "))
+    }
+}
+
+/// The condition, without `WHERE`.
+impl fmt::Display for WhereClause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let writer = SqlWriter { dialect: Dialect::default(), pretty: false };
+        f.write_str(&writer.condition(self))
+    }
+}
+
+/// The condition, without `HAVING`.
+impl fmt::Display for HavingClause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let writer = SqlWriter { dialect: Dialect::default(), pretty: false };
+        f.write_str(&writer.having(self))
+    }
+}
+
+/// One comparison of a JOIN's ON condition.
+impl fmt::Display for JoinCondition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let writer = SqlWriter { dialect: Dialect::default(), pretty: false };
+        f.write_str(&writer.join_condition(self))
+    }
+}
+
+struct SqlWriter {
+    dialect: Dialect,
+    // One clause per line, rather than all on one
+    pretty: bool,
+}
+
+impl SqlWriter {
+    // The statement's clauses, which are joined by newlines or spaces
+    fn statement(&self, statement: &SQLStatement) -> Vec<String> {
+        match statement {
+            SQLStatement::Select(select) => self.select(select),
+            SQLStatement::Insert(insert) => {
+                let or = match insert.on_conflict {
+                    Some(ConflictAction::Ignore) => " OR IGNORE",
+                    Some(ConflictAction::Replace) => " OR REPLACE",
+                    None => "",
+                };
+                let mut head = format!("INSERT{} INTO {}", or, self.ident(&insert.table));
+                if !insert.columns.is_empty() {
+                    head += &format!(" ({})", self.idents(&insert.columns));
+                }
+                let tuples: Vec<String> = insert.values.iter()
+                    .map(|row| format!("({})", row.iter().map(|v| string(v)).collect::<Vec<_>>().join(", ")))
+                    .collect();
+                let mut clauses = vec![head];
+                if tuples.len() == 1 {
+                    clauses.push(format!("VALUES {}", tuples[0]));
+                } else {
+                    clauses.extend(self.list("VALUES".to_string(), tuples, None));
+                }
+                clauses
+            }
+            SQLStatement::Update(update) => {
+                let assignments: Vec<String> = update.assignments.iter()
+                    .map(|(column, value)| match value {
+                        SetValue::Value(value) => format!("{} = {}", self.ident(column), string(value)),
+                        SetValue::Math(call) => format!("{} = {}", self.ident(column), self.call(call)),
+                    })
+                    .collect();
+                let mut clauses = vec![format!("UPDATE {}", self.ident(&update.table))];
+                clauses.extend(update.join.as_ref().map(|join| self.join(join)));
+                clauses.push(format!("SET {}", assignments.join(", ")));
+                clauses.extend(self.where_clause(&update.where_clause));
+                clauses.extend(self.returning(&update.returning));
+                clauses
+            }
+            SQLStatement::Delete(delete) => {
+                let table = self.ident(&delete.table);
+                let mut clauses = match &delete.join {
+                    Some(join) => vec![format!("DELETE {} FROM {}", table, table), self.join(join)],
+                    None => vec![format!("DELETE FROM {}", table)],
+                };
+                clauses.extend(self.where_clause(&delete.where_clause));
+                clauses.extend(self.returning(&delete.returning));
+                clauses
+            }
+            SQLStatement::CreateTable(create) => self.create_table(create),
+            SQLStatement::AlterTable(alter) => {
+                let action = match &alter.action {
+                    AlterAction::AddColumn(column, Some(data_type)) => {
+                        format!("ADD COLUMN {} {}", self.ident(column), data_type)
+                    }
+                    AlterAction::AddColumn(column, None) => format!("ADD COLUMN {}", self.ident(column)),
+                    AlterAction::DropColumn(column) => format!("DROP COLUMN {}", self.ident(column)),
+                    AlterAction::ModifyColumn(column, data_type) if !self.dialect.supports_modify()
+                        && self.dialect.supports_alter_column() => {
+                        format!("ALTER COLUMN {} TYPE {}", self.ident(column), data_type)
+                    }
+                    AlterAction::ModifyColumn(column, data_type) => {
+                        format!("MODIFY COLUMN {} {}", self.ident(column), data_type)
+                    }
+                    AlterAction::SetOptions(options) => format!("SET ({})", self.options(options)),
+                };
+                vec![format!("ALTER TABLE {} {}", self.ident(&alter.table), action)]
+            }
+            SQLStatement::DropTable(drop) => vec![format!("DROP TABLE {}", self.ident(&drop.table))],
+            SQLStatement::Transaction(transaction) => vec![match transaction {
+                TransactionStatement::Begin => "BEGIN".to_string(),
+                TransactionStatement::BeginOptimistic => "BEGIN OPTIMISTIC".to_string(),
+                TransactionStatement::Commit => "COMMIT".to_string(),
+                TransactionStatement::Rollback => "ROLLBACK".to_string(),
+                TransactionStatement::Savepoint(name) => format!("SAVEPOINT {}", self.ident(name)),
+                TransactionStatement::RollbackToSavepoint(name) => {
+                    format!("ROLLBACK TO SAVEPOINT {}", self.ident(name))
+                }
+                TransactionStatement::ReleaseSavepoint(name) => format!("RELEASE SAVEPOINT {}", self.ident(name)),
+            }],
+            SQLStatement::Show(ShowStatement::StorageStats) => vec!["SHOW STORAGE STATS".to_string()],
+            SQLStatement::Show(ShowStatement::TableStatus) => vec!["SHOW TABLE STATUS".to_string()],
+            SQLStatement::Show(ShowStatement::Variable(name)) => vec![format!("SHOW {}", self.ident(name))],
+            SQLStatement::SetVariable(name, value) => vec![format!("SET {} = {}", self.ident(name), string(value))],
+            SQLStatement::Vacuum(Some(table)) => vec![format!("VACUUM {}", self.ident(table))],
+            SQLStatement::Vacuum(None) => vec!["VACUUM".to_string()],
+            SQLStatement::Analyze(Some(table)) => vec![format!("ANALYZE {}", self.ident(table))],
+            SQLStatement::Analyze(None) => vec!["ANALYZE".to_string()],
+            SQLStatement::Explain(explain) => {
+                let mut clauses = self.select(&explain.select);
+                let format = match explain.format {
+                    ExplainFormat::Text => "",
+                    ExplainFormat::Json => "(FORMAT JSON) ",
+                };
+                clauses[0] = format!("EXPLAIN {}{}", format, clauses[0]);
+                clauses
+            }
+        }
+    }
+
+    fn select(&self, select: &SelectStatement) -> Vec<String> {
+        let columns: Vec<String> = select.columns.iter().map(|c| self.column_expr(c)).collect();
+        let hints = match select.hints.is_empty() {
+            true => String::new(),
+            false => {
+                let hints: Vec<String> = select.hints.iter().map(|hint| match hint {
+                    Hint::NoIndex(table) => format!("NO_INDEX({})", self.ident(table)),
+                    Hint::JoinOrder(tables) => format!("JOIN_ORDER({})", self.idents(tables)),
+                }).collect();
+                format!("/*+ {} */ ", hints.join(" "))
+            }
+        };
+        let from = match &select.table_function {
+            Some(function) => {
+                let args: Vec<String> = function.args().into_iter().map(string).collect();
+                format!("{}({})", function.name(), args.join(", "))
+            }
+            None => self.ident(&select.table),
+        };
+        let mut clauses = vec![
+            format!("SELECT {}{}", hints, columns.join(", ")),
+            format!("FROM {}{}", from,
+                    select.as_of.map(|timestamp| format!(" AS OF {}", timestamp)).unwrap_or_default()),
+        ];
+        clauses.extend(select.join.as_ref().map(|join| self.join(join)));
+        clauses.extend(self.where_clause(&select.where_clause));
+        if let Some(group_by) = &select.group_by {
+            clauses.push(format!("GROUP BY {}", self.idents(group_by)));
+        }
+        if let Some(having) = &select.having {
+            clauses.push(format!("HAVING {}", self.having(having)));
+        }
+        if let Some(order_by) = &select.order_by {
+            let direction = if order_by.descending { " DESC" } else { "" };
+            clauses.push(format!("ORDER BY {}{}", self.column_expr(&order_by.expr), direction));
+        }
+        match (select.limit, select.offset) {
+            (limit, Some(offset)) if !self.dialect.supports_limit() => {
+                clauses.push(format!("OFFSET {} ROWS", offset));
+                clauses.extend(limit.map(|limit| format!("FETCH FIRST {} ROWS ONLY", limit)));
+            }
+            (Some(limit), _) if !self.dialect.supports_limit() => {
+                clauses.push(format!("FETCH FIRST {} ROWS ONLY", limit));
+            }
+            (Some(limit), Some(offset)) => clauses.push(format!("LIMIT {} OFFSET {}", limit, offset)),
+            (Some(limit), None) => clauses.push(format!("LIMIT {}", limit)),
+            (None, Some(offset)) => clauses.push(format!("OFFSET {}", offset)),
+            (None, None) => {}
+        }
+        clauses
+    }
+
+    fn create_table(&self, create: &CreateTableStatement) -> Vec<String> {
+        let mut columns: Vec<String> = create.columns.iter()
+            .map(|(name, data_type)| match &create.primary_key {
+                Some(primary_key) if primary_key == name => {
+                    format!("{} {} PRIMARY KEY", self.ident(name), data_type)
+                }
+                _ => format!("{} {}", self.ident(name), data_type),
+            })
+            .collect();
+        if let Some(primary_key) = &create.primary_key {
+            if !create.columns.iter().any(|(name, _)| name == primary_key) {
+                columns.push(format!("PRIMARY KEY ({})", self.ident(primary_key)));
+            }
+        }
+        let head = format!("CREATE TABLE {} (", self.ident(&create.table));
+        let mut clauses = self.list(head, columns, Some(")"));
+        if let Some(partition_by) = &create.partition_by {
+            clauses.push(if partition_by.split_at.is_empty() {
+                format!("PARTITION BY HASH({}) PARTITIONS {}", self.ident(&partition_by.column), partition_by.partitions)
+            } else {
+                let values: Vec<String> = partition_by.split_at.iter().map(|value| string(value)).collect();
+                format!("PARTITION BY RANGE({}) SPLIT AT ({})", self.ident(&partition_by.column), values.join(", "))
+            });
+        }
+        if !create.options.is_empty() {
+            clauses.push(format!("WITH ({})", self.options(&create.options)));
+        }
+        clauses
+    }
+
+    // `head` followed by the comma-separated `items`, indented one per line
+    // when pretty, then `close`
+    fn list(&self, head: String, items: Vec<String>, close: Option<&str>) -> Vec<String> {
+        if !self.pretty {
+            let separator = if close.is_some() { "" } else { " " };
+            return vec![format!("{}{}{}{}", head, separator, items.join(", "), close.unwrap_or(""))];
+        }
+        let last = items.len().saturating_sub(1);
+        let mut lines = vec![head];
+        lines.extend(items.into_iter().enumerate()
+            .map(|(i, item)| format!("  {}{}", item, if i == last { "" } else { "," })));
+        lines.extend(close.map(str::to_string));
+        lines
+    }
+
+    fn join(&self, join: &JoinClause) -> String {
+        let indent = if self.pretty { "  " } else { "" };
+        let join_type = match join.join_type {
+            JoinType::Inner => "JOIN",
+            JoinType::Left => "LEFT JOIN",
+            JoinType::Right => "RIGHT JOIN",
+            JoinType::Full => "FULL JOIN",
+            JoinType::Cross => "CROSS JOIN",
+        };
+        let mut clause = match &join.nested {
+            // A group stays on one line, even when pretty
+            Some(nested) => {
+                let writer = SqlWriter { dialect: self.dialect, pretty: false };
+                format!("{}{} ({} {})", indent, join_type, self.ident(&join.table), writer.join(nested))
+            }
+            None => format!("{}{} {}", indent, join_type, self.ident(&join.table)),
+        };
+        if join.join_type != JoinType::Cross {
+            let on: Vec<_> = join.on.iter().map(|condition| self.join_condition(condition)).collect();
+            clause += &format!(" ON {}", on.join(" AND "));
+        }
+        clause
+    }
+
+    fn where_clause(&self, where_clause: &Option<WhereClause>) -> Option<String> {
+        Some(format!("WHERE {}", self.condition(where_clause.as_ref()?)))
+    }
+
+    fn returning(&self, returning: &Option<Vec<String>>) -> Option<String> {
+        let columns: Vec<String> = returning.as_ref()?.iter()
+            .map(|column| match column.strip_suffix('*') {
+                Some(qualifier) => format!("{}*", qualifier.strip_suffix('.').map_or_else(String::new, |q| self.ident(q) + ".")),
+                None => self.ident(column),
+            })
+            .collect();
+        Some(format!("RETURNING {}", columns.join(", ")))
+    }
+
+    fn condition(&self, clause: &WhereClause) -> String {
+        let column = match &clause.function {
+            Some(call) => self.call(call),
+            None => self.ident(&clause.column),
+        };
+        match (&clause.upper_bound, &clause.in_list) {
+            (Some(upper), _) => format!("{} BETWEEN {} AND {}", column, string(&clause.value), string(upper)),
+            // A subquery stays on one line, even when pretty
+            (None, Some(InList::Subquery(query))) => {
+                let writer = SqlWriter { dialect: self.dialect, pretty: false };
+                format!("{} IN ({})", column, writer.select(query).join(" "))
+            }
+            (None, Some(InList::Values(values))) => {
+                let values: Vec<_> = values.iter().map(|value| string(value)).collect();
+                format!("{} IN ({})", column, values.join(", "))
+            }
+            (None, None) => format!("{} {} {}", column, clause.operator, string(&clause.value)),
+        }
+    }
+
+    fn call(&self, call: &FunctionCall) -> String {
+        match &call.arg {
+            Some(arg) => format!("{}({}, {})", call.function.name(), self.ident(&call.column), arg),
+            None => format!("{}({})", call.function.name(), self.ident(&call.column)),
+        }
+    }
+
+    fn join_condition(&self, condition: &JoinCondition) -> String {
+        format!("{} {} {}", self.ident(&condition.left), condition.operator, self.ident(&condition.right))
+    }
+
+    fn having(&self, having: &HavingClause) -> String {
+        format!("{} {} {}", self.column_expr(&having.column_expr), having.operator, string(&having.value))
+    }
+
+    fn column_expr(&self, expr: &ColumnExpr) -> String {
+        match expr {
+            ColumnExpr::All => "*".to_string(),
+            ColumnExpr::CountAll => "COUNT(*)".to_string(),
+            ColumnExpr::Column(column) => self.ident(column),
+            ColumnExpr::Count(column) => format!("COUNT({})", self.ident(column)),
+            ColumnExpr::Sum(column) => format!("SUM({})", self.ident(column)),
+            ColumnExpr::Avg(column) => format!("AVG({})", self.ident(column)),
+            ColumnExpr::Min(column) => format!("MIN({})", self.ident(column)),
+            ColumnExpr::Max(column) => format!("MAX({})", self.ident(column)),
+            ColumnExpr::Length(column) => format!("LENGTH({})", self.ident(column)),
+            ColumnExpr::Math(call) => self.call(call),
+            ColumnExpr::Aliased(expr, alias) => format!("{} AS {}", self.column_expr(expr), self.ident(alias)),
+        }
+    }
+
+    fn options(&self, options: &[(String, String)]) -> String {
+        let options: Vec<String> = options.iter()
+            .map(|(name, value)| {
+                let value = if value.parse::<f64>().is_ok() || is_plain_word(value) { value.clone() } else { string(value) };
+                format!("{} = {}", name, value)
+            })
+            .collect();
+        options.join(", ")
+    }
+
+    fn idents(&self, names: &[String]) -> String {
+        names.iter().map(|name| self.ident(name)).collect::<Vec<_>>().join(", ")
+    }
+
+    fn ident(&self, name: &str) -> String {
+        let quote = if self.dialect.identifier_quote('"').is_some() { '"' } else { '`' };
+        quote_name(name, quote)
+    }
+}
+
+/// A name, in `quote`s if it wouldn't otherwise read back as one; `t.c` is
+/// two names.
+pub(crate) fn quote_name(name: &str, quote: char) -> String {
+    let parts: Vec<String> = name.split('.')
+        .map(|part| {
+            if is_plain_word(part) {
+                part.to_string()
+            } else {
+                let escaped = part.replace(quote, &format!("{}{}", quote, quote));
+                format!("{}{}{}", quote, escaped, quote)
+            }
+        })
+        .collect();
+    parts.join(".")
+}
+
+// Whether `word` tokenizes as itself, rather than as a keyword or several
+// tokens, and is never read as a keyword
+fn is_plain_word(word: &str) -> bool {
+    matches!(tokenize(word).as_deref(), Ok([Token::Identifier(parsed)]) if parsed == word)
+        && !RESERVED_NAMES.iter().any(|reserved| reserved.eq_ignore_ascii_case(word))
+}
+
+// A value as a literal: a blob as written, anything else as a string
+fn string(value: &str) -> String {
+    if value == NULL {
+        return "NULL".to_string();
+    }
+    if value.parse::<Blob>().is_ok() {
+        return value.to_string();
+    }
+    format!("'{}'", value.replace('\'', "''"))
+}
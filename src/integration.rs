@@ -17,5 +17,5 @@ pub fn process_query(db: &mut Database, query: &str) -> Result<String, String> {
     // Execution - Execute the AST in the database engine
     let result = db.execute(ast).map_err(|e| format!("Execution error: {e}"))?;
     
-    Ok(result)
+    Ok(result.to_string())
 }
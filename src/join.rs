@@ -0,0 +1,239 @@
+//! Joining the rows of two tables, or of a table and a parenthesized group
+//! of joined tables, for both executors, and naming the columns of the rows
+//! a query reads. A row keys each column both as `table.column` and by its
+//! bare name, so a query can use either.
+
+use crate::ast::{JoinClause, JoinCondition, JoinType, WhereClause, NULL};
+use crate::executor::compare_values;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+// The rows of a table, borrowed where they are held in memory
+type Rows<'a> = Cow<'a, [HashMap<String, String>]>;
+
+/// Whether a join finds the rows matching each row through a hash table
+/// rather than by comparing it with every row of the other table, which
+/// takes at least one equality between the two tables in its ON.
+#[cfg(feature = "storage")]
+pub(crate) fn is_hash_join(join: &JoinClause) -> bool {
+    !hash_keys(join).is_empty()
+}
+
+// The columns of each table the equalities of the ON condition pair them by
+fn hash_keys(join: &JoinClause) -> Vec<(&str, &str)> {
+    join.on.iter()
+        .filter(|condition| condition.operator == "=")
+        .filter_map(|condition| match sides(condition, join) {
+            ((false, left), (true, right)) | ((true, right), (false, left)) => Some((left, right)),
+            _ => None,
+        })
+        .collect()
+}
+
+// Which table each column of a condition is from, true for the joined one,
+// and the name to look it up by. A qualified column names its table; an
+// unqualified one on the left of the comparison is the FROM table's, and on
+// the right the joined table's. A column of a table is looked up by its bare
+// name, and one of a group, whose rows are already joined, as qualified.
+fn sides<'a>(condition: &'a JoinCondition, join: &JoinClause) -> ((bool, &'a str), (bool, &'a str)) {
+    let side = |column: &'a str, right: bool| match column.split_once('.') {
+        Some((table, _)) if join.nested.is_some() && is_joined(join, table) => (true, column),
+        Some((table, bare)) => (is_joined(join, table), bare),
+        None => (right, column),
+    };
+    (side(&condition.left, false), side(&condition.right, true))
+}
+
+// Whether `table` is on the joined side of `join`
+fn is_joined(join: &JoinClause, table: &str) -> bool {
+    join.table == table || join.nested.as_ref().is_some_and(|nested| is_joined(nested, table))
+}
+
+// A row's values of the columns a hash join pairs rows by; None if any is
+// NULL, which matches nothing
+fn key<'a>(row: &'a HashMap<String, String>, columns: &[&str]) -> Option<Vec<&'a String>> {
+    columns.iter().map(|column| row.get(*column).filter(|value| *value != NULL)).collect()
+}
+
+// Whether a pair of rows meets every comparison of the ON condition. A
+// comparison with NULL, or a missing column, never holds.
+fn meets(join: &JoinClause, lrow: &HashMap<String, String>, rrow: &HashMap<String, String>) -> bool {
+    join.on.iter().all(|condition| {
+        let ((left_right, left), (right_right, right)) = sides(condition, join);
+        let value = |right, column| if right { rrow.get(column) } else { lrow.get(column) };
+        let (a, b) = match (value(left_right, left), value(right_right, right)) {
+            (Some(a), Some(b)) if a != NULL && b != NULL => (a, b),
+            _ => return false,
+        };
+        match condition.operator.as_str() {
+            "=" => a == b,
+            "<" => compare_values(a, b) == Ordering::Less,
+            ">" => compare_values(a, b) == Ordering::Greater,
+            _ => false,
+        }
+    })
+}
+
+/// The rows of `table` joined to those of `join.table`. An outer join pads
+/// the side without a match with NULLs. With `right_first`, an INNER or
+/// CROSS join loops over the joined table's rows on the outside, which
+/// orders the result by them.
+pub(crate) fn join_rows(left_rows: &[HashMap<String, String>], right_rows: &[HashMap<String, String>],
+                        join: &JoinClause, table: &str, right_first: bool) -> Vec<HashMap<String, String>> {
+    join_pairs(left_rows, right_rows, join, right_first).into_iter()
+        .map(|(left, right)| combine(left_rows, right_rows, join, table, left, right))
+        .collect()
+}
+
+/// Which of the rows of `table` an UPDATE or DELETE over a JOIN changes:
+/// those that, joined to a row of `join.table` (or to NULLs, for a LEFT
+/// JOIN), make a row `meets` holds for.
+pub(crate) fn joined_matches(left_rows: &[HashMap<String, String>], right_rows: &[HashMap<String, String>],
+                             join: &JoinClause, table: &str,
+                             mut meets: impl FnMut(&HashMap<String, String>) -> Result<bool, String>)
+                             -> Result<Vec<bool>, String> {
+    let mut matched = vec![false; left_rows.len()];
+    for (left, right) in join_pairs(left_rows, right_rows, join, false) {
+        let Some(i) = left else { continue };
+        if !matched[i] && meets(&combine(left_rows, right_rows, join, table, left, right))? {
+            matched[i] = true;
+        }
+    }
+    Ok(matched)
+}
+
+// One row of each side, or None for the NULLs an outer join pads with
+fn combine(left_rows: &[HashMap<String, String>], right_rows: &[HashMap<String, String>], join: &JoinClause,
+           table: &str, left: Option<usize>, right: Option<usize>) -> HashMap<String, String> {
+    let mut combined = HashMap::new();
+    let sides = [(left, table, left_rows, false), (right, join.table.as_str(), right_rows, join.nested.is_some())];
+    for (row, name, rows, group) in sides {
+        // A group's rows are keyed `table.column` already, besides the bare
+        // names joining them added
+        let columns = row.map(|i| &rows[i]).or(rows.first()).into_iter().flatten()
+            .filter(|(k, _)| !group || k.contains('.'));
+        combined.extend(columns.map(|(k, v)| {
+            let key = if group { k.clone() } else { format!("{}.{}", name, k) };
+            (key, if row.is_some() { v.clone() } else { NULL.to_string() })
+        }));
+    }
+    let mut tables = vec![table];
+    tables.extend(join.tables());
+    alias_join_columns(&mut combined, &tables);
+    combined
+}
+
+// The positions of the rows of each table that the join pairs
+fn join_pairs(left_rows: &[HashMap<String, String>], right_rows: &[HashMap<String, String>],
+              join: &JoinClause, right_first: bool) -> Vec<(Option<usize>, Option<usize>)> {
+    // Each row of the outer table is matched against the inner one's
+    let right_outer = join.join_type == JoinType::Right
+        || right_first && matches!(join.join_type, JoinType::Inner | JoinType::Cross);
+    let (outer_rows, inner_rows) = if right_outer { (right_rows, left_rows) } else { (left_rows, right_rows) };
+    let (left_key, right_key): (Vec<_>, Vec<_>) = hash_keys(join).into_iter().unzip();
+    let (outer_key, inner_key) = if right_outer { (right_key, left_key) } else { (left_key, right_key) };
+    let mut index: HashMap<Vec<&String>, Vec<usize>> = HashMap::new();
+    if !inner_key.is_empty() {
+        for (i, row) in inner_rows.iter().enumerate() {
+            if let Some(key) = key(row, &inner_key) {
+                index.entry(key).or_default().push(i);
+            }
+        }
+    }
+
+    let mut result = Vec::new();
+    let mut matched_inner = vec![false; inner_rows.len()];
+    for (o, outer) in outer_rows.iter().enumerate() {
+        let candidates = if outer_key.is_empty() {
+            (0..inner_rows.len()).collect()
+        } else {
+            key(outer, &outer_key).and_then(|key| index.get(&key)).cloned().unwrap_or_default()
+        };
+        let mut matched = false;
+        for i in candidates {
+            let (lrow, rrow) = if right_outer { (&inner_rows[i], outer) } else { (outer, &inner_rows[i]) };
+            if meets(join, lrow, rrow) {
+                result.push(if right_outer { (Some(i), Some(o)) } else { (Some(o), Some(i)) });
+                matched = true;
+                matched_inner[i] = true;
+            }
+        }
+        if !matched {
+            match join.join_type {
+                JoinType::Left | JoinType::Full => result.push((Some(o), None)),
+                JoinType::Right => result.push((None, Some(o))),
+                JoinType::Inner | JoinType::Cross => {}
+            }
+        }
+    }
+    if join.join_type == JoinType::Full {
+        for (i, matched) in matched_inner.into_iter().enumerate() {
+            if !matched {
+                result.push((None, Some(i)));
+            }
+        }
+    }
+    result
+}
+
+// Keys each column of a joined row by its bare name too, so that WHERE,
+// GROUP BY, aggregates and ORDER BY find it either way. Where several
+// tables have a column of that name, the bare name is the first one's of
+// `tables`, the FROM table's before the joined ones.
+fn alias_join_columns(row: &mut HashMap<String, String>, tables: &[&str]) {
+    let mut aliases: HashMap<String, String> = HashMap::new();
+    for table in tables {
+        for (key, value) in row.iter() {
+            if let Some((_, column)) = key.split_once('.').filter(|(qualifier, _)| qualifier == table) {
+                aliases.entry(column.to_string()).or_insert_with(|| value.clone());
+            }
+        }
+    }
+    row.extend(aliases);
+}
+
+/// The rows of the joined side of `join`, reading each table's with
+/// `rows_of`: the table's own, or a group's joined together.
+pub(crate) fn joined_side<'a>(join: &JoinClause, rows_of: &mut dyn FnMut(&str) -> Result<Rows<'a>, String>)
+                              -> Result<Rows<'a>, String> {
+    let rows = rows_of(&join.table)?;
+    match &join.nested {
+        Some(nested) => Ok(Cow::Owned(join_rows(&rows, &joined_side(nested, rows_of)?, nested, &join.table, false))),
+        None => Ok(rows),
+    }
+}
+
+/// Keys each column of the rows of `table`, read without a JOIN, as
+/// `table.column` too.
+pub(crate) fn qualify_columns(rows: &mut [HashMap<String, String>], table: &str) {
+    for row in rows {
+        let qualified: Vec<_> = row.iter()
+            .map(|(column, value)| (format!("{}.{}", table, column), value.clone()))
+            .collect();
+        row.extend(qualified);
+    }
+}
+
+/// A WHERE condition on a table's own rows, e.g. of an UPDATE, with a
+/// `table.` qualifier dropped from its column.
+pub(crate) fn unqualified_where(where_clause: &WhereClause, table: &str) -> WhereClause {
+    let mut where_clause = where_clause.clone();
+    if let Some(column) = where_clause.column.strip_prefix(table).and_then(|rest| rest.strip_prefix('.')) {
+        where_clause.column = column.to_string();
+        if let Some(call) = &mut where_clause.function {
+            call.column = where_clause.column.clone();
+        }
+    }
+    where_clause
+}
+
+/// A column an UPDATE of `table` sets, with a `table.` qualifier dropped.
+/// Only the updated table's columns can be set.
+pub(crate) fn assigned_column<'a>(column: &'a str, table: &str) -> Result<&'a str, String> {
+    match column.split_once('.') {
+        Some((qualifier, column)) if qualifier == table => Ok(column),
+        Some((qualifier, _)) => Err(format!("UPDATE of '{}' can't set a column of '{}'", table, qualifier)),
+        None => Ok(column),
+    }
+}
@@ -1,14 +1,105 @@
+pub mod dialect;
 pub mod tokenizer;
 pub mod parser;
 pub mod ast;
+pub mod format;
+pub mod builder;
+pub mod rewrite;
+pub mod decimal;
+pub mod blob;
+#[cfg(feature = "executor")]
 pub mod executor;
+#[cfg(feature = "executor")]
+pub(crate) mod join;
+#[cfg(feature = "executor")]
+pub(crate) mod math;
+#[cfg(feature = "executor")]
+pub(crate) mod returning;
+#[cfg(feature = "executor")]
+pub mod output;
+#[cfg(feature = "executor")]
+pub mod result_formatter;
+#[cfg(feature = "executor")]
+pub mod row;
+#[cfg(feature = "executor")]
+pub mod table;
+#[cfg(feature = "executor")]
+pub mod csv;
+#[cfg(feature = "storage")]
 pub mod storage;
+#[cfg(feature = "fault-injection")]
+pub mod faults;
+#[cfg(feature = "storage")]
+pub mod partition;
+#[cfg(feature = "storage")]
+pub mod catalog;
+#[cfg(feature = "storage")]
+pub mod migrations;
+pub mod script;
+#[cfg(feature = "storage")]
 pub mod persistent_executor;
+#[cfg(feature = "storage")]
+pub mod plan;
+#[cfg(feature = "storage")]
+pub mod slow_query;
+#[cfg(feature = "storage")]
+pub mod changes;
+#[cfg(feature = "storage")]
+pub mod locks;
+#[cfg(feature = "storage")]
+pub mod session;
+#[cfg(feature = "storage")]
+pub mod pool;
+#[cfg(feature = "storage")]
+pub mod server;
+#[cfg(feature = "executor")]
 pub mod integration;  // If integration logic exists
+#[cfg(feature = "wasm")]
+pub mod wasm;
 
+pub use dialect::*;
 pub use tokenizer::*;
 pub use parser::*;
 pub use ast::*;
+pub use format::*;
+pub use builder::*;
+pub use rewrite::*;
+pub use decimal::*;
+pub use blob::*;
+#[cfg(feature = "executor")]
 pub use executor::*;
+#[cfg(feature = "executor")]
+pub use output::*;
+#[cfg(feature = "executor")]
+pub use result_formatter::*;
+#[cfg(feature = "executor")]
+pub use row::*;
+#[cfg(feature = "executor")]
+pub use table::*;
+#[cfg(feature = "executor")]
+pub use csv::*;
+#[cfg(feature = "storage")]
 pub use storage::*;
+#[cfg(feature = "storage")]
+pub use partition::*;
+#[cfg(feature = "storage")]
+pub use catalog::*;
+#[cfg(feature = "storage")]
+pub use migrations::*;
+pub use script::*;
+#[cfg(feature = "storage")]
 pub use persistent_executor::*;
+#[cfg(feature = "storage")]
+pub use plan::*;
+#[cfg(feature = "storage")]
+pub use slow_query::*;
+#[cfg(feature = "storage")]
+pub use changes::*;
+#[cfg(feature = "storage")]
+pub use locks::{DEADLOCK_DETECTED, SERIALIZATION_FAILURE};
+#[cfg(feature = "storage")]
+pub use session::*;
+#[cfg(feature = "storage")]
+pub use pool::*;
+#[cfg(feature = "storage")]
+pub use server::*;
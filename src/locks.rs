@@ -0,0 +1,221 @@
+//! Table locks of the sessions sharing a `PersistentDatabase`. A statement
+//! locks the tables it reads shared and those it writes exclusively, in
+//! table name order, and a transaction keeps its locks until it commits or
+//! rolls back. A session that would wait on a cycle of sessions each waiting
+//! for the next is refused with `DEADLOCK_DETECTED` instead.
+//!
+//! A transaction begun with `BEGIN OPTIMISTIC` takes no locks until it
+//! commits. Instead it records what it reads, and its COMMIT fails with
+//! `SERIALIZATION_FAILURE` if another session has written any of that
+//! since it began: a row it looked up by key, or any row of a table it
+//! scanned.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Condvar, Mutex, PoisonError};
+
+/// The SQLSTATE an error starts with when it ended a transaction to break a
+/// deadlock; running the transaction again may succeed.
+pub const DEADLOCK_DETECTED: &str = "40P01";
+
+/// The SQLSTATE an error starts with when an optimistic transaction read
+/// something written since it began, and was rolled back at COMMIT; running
+/// it again may succeed.
+pub const SERIALIZATION_FAILURE: &str = "40001";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+/// The locks a statement takes, by table; a table both read and written is
+/// locked exclusively.
+#[derive(Debug, Default)]
+pub(crate) struct LockSet(BTreeMap<String, LockMode>);
+
+impl LockSet {
+    pub(crate) fn add(&mut self, table: &str, mode: LockMode) {
+        let held = self.0.entry(table.to_string()).or_insert(mode);
+        *held = (*held).max(mode);
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct TableLocks {
+    state: Mutex<LockState>,
+    released: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct LockState {
+    tables: HashMap<String, TableLock>,
+    // The lock each blocked session waits for
+    waiting: HashMap<u64, (String, LockMode)>,
+}
+
+#[derive(Debug, Default)]
+struct TableLock {
+    shared: HashSet<u64>,
+    exclusive: Option<u64>,
+}
+
+impl TableLock {
+    // The other sessions `session` would have to wait for
+    fn blockers(&self, session: u64, mode: LockMode) -> Vec<u64> {
+        let holders: Box<dyn Iterator<Item = &u64>> = match mode {
+            LockMode::Shared => Box::new(self.exclusive.iter()),
+            LockMode::Exclusive => Box::new(self.exclusive.iter().chain(&self.shared)),
+        };
+        holders.copied().filter(|&holder| holder != session).collect()
+    }
+}
+
+impl LockState {
+    fn blockers(&self, session: u64, table: &str, mode: LockMode) -> Vec<u64> {
+        self.tables.get(table).map_or_else(Vec::new, |lock| lock.blockers(session, mode))
+    }
+
+    // Whether waiting on `blockers` would have `session` wait on itself
+    fn closes_cycle(&self, session: u64, blockers: Vec<u64>) -> bool {
+        let mut seen = HashSet::new();
+        let mut stack = blockers;
+        while let Some(blocker) = stack.pop() {
+            if blocker == session {
+                return true;
+            }
+            if seen.insert(blocker) {
+                if let Some((table, mode)) = self.waiting.get(&blocker) {
+                    stack.extend(self.blockers(blocker, table, *mode));
+                }
+            }
+        }
+        false
+    }
+}
+
+impl TableLocks {
+    /// Takes each lock of `locks` for `session`, waiting for other sessions
+    /// to release them, unless that would deadlock.
+    pub(crate) fn acquire(&self, session: u64, locks: &LockSet) -> Result<(), String> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        for (table, &mode) in &locks.0 {
+            loop {
+                let blockers = state.blockers(session, table, mode);
+                if blockers.is_empty() {
+                    break;
+                }
+                if state.closes_cycle(session, blockers) {
+                    state.waiting.remove(&session);
+                    return Err(format!("{}: Deadlock detected waiting for table '{}'; the transaction was rolled back",
+                                       DEADLOCK_DETECTED, table));
+                }
+                state.waiting.insert(session, (table.clone(), mode));
+                state = self.released.wait(state).unwrap_or_else(PoisonError::into_inner);
+            }
+            state.waiting.remove(&session);
+            let lock = state.tables.entry(table.clone()).or_default();
+            match mode {
+                LockMode::Shared if lock.exclusive != Some(session) => {
+                    lock.shared.insert(session);
+                }
+                LockMode::Shared => {}
+                LockMode::Exclusive => {
+                    lock.shared.remove(&session);
+                    lock.exclusive = Some(session);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Releases every lock `session` holds.
+    pub(crate) fn release_all(&self, session: u64) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.tables.retain(|_, lock| {
+            lock.shared.remove(&session);
+            if lock.exclusive == Some(session) {
+                lock.exclusive = None;
+            }
+            lock.exclusive.is_some() || !lock.shared.is_empty()
+        });
+        state.waiting.remove(&session);
+        drop(state);
+        self.released.notify_all();
+    }
+}
+
+/// What an optimistic transaction has read, to validate at COMMIT.
+#[derive(Debug, Default)]
+pub(crate) struct ReadSet {
+    // The write version when the transaction began
+    start: u64,
+    tables: HashSet<String>,
+    rows: HashSet<(String, String)>,
+}
+
+impl ReadSet {
+    pub(crate) fn read_table(&mut self, table: &str) {
+        self.tables.insert(table.to_string());
+    }
+
+    pub(crate) fn read_row(&mut self, table: &str, key: &str) {
+        self.rows.insert((table.to_string(), key.to_string()));
+    }
+}
+
+/// The version of each row and table's last write, kept while optimistic
+/// transactions are open so that they can be validated.
+#[derive(Debug, Default)]
+pub(crate) struct WriteVersions {
+    version: u64,
+    optimistic: usize,
+    tables: HashMap<String, TableVersions>,
+}
+
+#[derive(Debug, Default)]
+struct TableVersions {
+    last: u64,
+    rows: HashMap<String, u64>,
+}
+
+impl WriteVersions {
+    pub(crate) fn begin(&mut self) -> ReadSet {
+        self.optimistic += 1;
+        ReadSet { start: self.version, ..ReadSet::default() }
+    }
+
+    pub(crate) fn end(&mut self) {
+        self.optimistic -= 1;
+        if self.optimistic == 0 {
+            self.tables.clear();
+        }
+    }
+
+    /// Gives the rows of `table` a batch wrote a new version.
+    pub(crate) fn record<'a>(&mut self, table: &str, keys: impl Iterator<Item = &'a str>) {
+        if self.optimistic == 0 {
+            return;
+        }
+        self.version += 1;
+        let versions = self.tables.entry(table.to_string()).or_default();
+        versions.last = self.version;
+        versions.rows.extend(keys.map(|key| (key.to_string(), self.version)));
+    }
+
+    /// Fails if anything `reads` covers was written after it began.
+    pub(crate) fn validate(&self, reads: &ReadSet) -> Result<(), String> {
+        let newer = |version: u64| version > reads.start;
+        let table = reads.tables.iter()
+            .find(|table| self.tables.get(*table).is_some_and(|versions| newer(versions.last)))
+            .or_else(|| reads.rows.iter()
+                .find(|(table, key)| self.tables.get(table)
+                    .and_then(|versions| versions.rows.get(key))
+                    .is_some_and(|&version| newer(version)))
+                .map(|(table, _)| table));
+        match table {
+            Some(table) => Err(format!("{}: Table '{}' was written since the transaction began; it was rolled back",
+                                       SERIALIZATION_FAILURE, table)),
+            None => Ok(()),
+        }
+    }
+}
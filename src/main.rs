@@ -1,48 +1,858 @@
-mod tokenizer;
-mod parser;
-mod ast;
-mod executor;
-mod storage;
-mod persistent_executor;
-
-use tokenizer::Tokenizer;
-use parser::Parser;
-use persistent_executor::PersistentDatabase;
-use std::io::{self, Write};
+use rust_sql_parser::ast;
+use rust_sql_parser::catalog::TableDef;
+use rust_sql_parser::csv::CsvReader;
+use rust_sql_parser::executor::Database;
+use rust_sql_parser::output::{ColumnMeta, OutputFormat, OutputSettings, RowSink, RowWriter, StatementResult};
+use rust_sql_parser::tokenizer::SqlError;
+use rust_sql_parser::dialect::Dialect;
+use rust_sql_parser::format::format_sql_with_dialect;
+use rust_sql_parser::parser::{parse_statement, parse_to_json};
+use rust_sql_parser::persistent_executor::PersistentDatabase;
+use rust_sql_parser::script::{OnError, StatementReader};
+use rust_sql_parser::session::Session;
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, IsTerminal, Read, Write};
+use std::process::{self, Child, Command, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "\
+Usage: rust_sql_parser [--data-dir <path>] [--format <format>]
+       rust_sql_parser run <file.sql> [--data-dir <path>]
+       rust_sql_parser parse [--dialect <dialect>] [<sql>]
+       rust_sql_parser fmt [--dialect <dialect>] [<sql>]
+
+Commands:
+  run <file.sql>     Execute every statement in <file.sql>, stopping at the
+                     first that fails, instead of starting the REPL
+  parse [<sql>]      Print the syntax tree of a statement, or of the one on
+                     stdin, as JSON without executing it
+  fmt [<sql>]        Print a statement, or every statement on stdin, with
+                     upper-case keywords and one clause per line
+
+Options:
+  --data-dir <path>  Store tables in <path> using the persistent LSM engine
+                     instead of in memory
+  --format <format>  Print query results as list (the default), table, csv,
+                     json or vertical
+  --dialect <name>   Parse with the syntax of generic (the default), standard,
+                     mysql or sqlite SQL
+  -h, --help         Print this help
+
+When stdin is not a terminal, the statements piped to it are run without a
+prompt and the exit status is 1 if any of them failed.";
+
+#[derive(Debug, Default, PartialEq)]
+enum Subcommand {
+    #[default]
+    Repl,
+    Run(String),
+    // The statement, or None to read it from stdin
+    Parse(Option<String>),
+    // Likewise for the statements to format
+    Format(Option<String>),
+}
+
+#[derive(Debug, Default)]
+struct Args {
+    command: Subcommand,
+    data_dir: Option<String>,
+    format: OutputFormat,
+    dialect: Dialect,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut parsed = Args::default();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--data-dir" => {
+                parsed.data_dir = Some(args.next().ok_or("--data-dir needs a path")?);
+            }
+            "--format" => {
+                parsed.format = args.next().ok_or("--format needs a format")?.parse()?;
+            }
+            "--dialect" => {
+                parsed.dialect = args.next().ok_or("--dialect needs a dialect")?.parse()?;
+            }
+            "run" if parsed.command == Subcommand::Repl => {
+                parsed.command = Subcommand::Run(args.next().ok_or("run needs a script file")?);
+            }
+            "parse" if parsed.command == Subcommand::Repl => parsed.command = Subcommand::Parse(None),
+            "fmt" if parsed.command == Subcommand::Repl => parsed.command = Subcommand::Format(None),
+            _ => {
+                if let Some(path) = arg.strip_prefix("--data-dir=") {
+                    parsed.data_dir = Some(path.to_string());
+                } else if let Some(format) = arg.strip_prefix("--format=") {
+                    parsed.format = format.parse()?;
+                } else if let Some(dialect) = arg.strip_prefix("--dialect=") {
+                    parsed.dialect = dialect.parse()?;
+                } else if parsed.command == Subcommand::Parse(None) && !arg.starts_with('-') {
+                    parsed.command = Subcommand::Parse(Some(arg));
+                } else if parsed.command == Subcommand::Format(None) && !arg.starts_with('-') {
+                    parsed.command = Subcommand::Format(Some(arg));
+                } else {
+                    return Err(format!("Unknown argument '{}'", arg));
+                }
+            }
+        }
+    }
+    Ok(parsed)
+}
+
+// The database the REPL runs statements against: the persistent one in a
+// session of its own, which keeps the output settings. The in-memory engine
+// has no sessions, so they are kept beside it.
+enum Engine {
+    Memory(Box<Database>, OutputSettings),
+    Persistent(Box<Session>),
+}
+
+impl Engine {
+    fn open(args: &Args) -> Result<Self, String> {
+        let settings = OutputSettings { format: args.format, pager: io::stdout().is_terminal(), ..OutputSettings::default() };
+        match &args.data_dir {
+            Some(data_dir) => PersistentDatabase::new(data_dir).map(|db| {
+                let mut session = Session::new(Arc::new(db));
+                *session.settings_mut() = settings;
+                Engine::Persistent(Box::new(session))
+            }),
+            None => Ok(Engine::Memory(Box::new(Database::new()), settings)),
+        }
+    }
+
+    fn settings(&self) -> &OutputSettings {
+        match self {
+            Engine::Memory(_, settings) => settings,
+            Engine::Persistent(session) => session.settings(),
+        }
+    }
+
+    fn settings_mut(&mut self) -> &mut OutputSettings {
+        match self {
+            Engine::Memory(_, settings) => settings,
+            Engine::Persistent(session) => session.settings_mut(),
+        }
+    }
+
+    fn execute(&mut self, statement: ast::SQLStatement) -> Result<StatementResult, String> {
+        match self {
+            Engine::Memory(db, _) => db.execute(statement),
+            Engine::Persistent(session) => session.execute(statement),
+        }
+    }
+
+    // How long the last statement took to plan; the in-memory engine runs
+    // statements as they are written
+    fn plan_time(&self) -> Duration {
+        match self {
+            Engine::Memory(..) => Duration::ZERO,
+            Engine::Persistent(session) => session.last_plan_time(),
+        }
+    }
+
+    // Like `execute`, but a SELECT of the persistent database hands its rows
+    // to `sink` as they are read and returns None
+    fn execute_into(&mut self, statement: ast::SQLStatement, sink: &mut dyn RowSink) -> Result<Option<StatementResult>, String> {
+        match (self, statement) {
+            (Engine::Persistent(session), ast::SQLStatement::Select(select)) => session.query_each(&select, sink).map(|_| None),
+            (engine, statement) => engine.execute(statement).map(Some),
+        }
+    }
+
+    // Runs the statements of a script, stopping at the first failure.
+    fn execute_script(&mut self, path: &str) -> Result<usize, String> {
+        let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+        match self {
+            Engine::Memory(db, _) => {
+                let mut statements = 0;
+                for statement in StatementReader::new(BufReader::new(file)) {
+                    let statement = statement.map_err(|e| format!("Failed to read script: {}", e))?;
+                    execute_query(&statement.sql)
+                        .and_then(|stmt| db.execute(stmt))
+                        .map_err(|e| format!("Statement at line {} failed: {}", statement.line, e))?;
+                    statements += 1;
+                }
+                Ok(statements)
+            }
+            Engine::Persistent(session) => Ok(session.run(|db| db.execute_script(file, OnError::Stop, |_| {}))?.statements),
+        }
+    }
+
+    fn table_names(&self) -> Result<Vec<String>, String> {
+        match self {
+            Engine::Memory(db, _) => Ok(db.table_names()),
+            Engine::Persistent(session) => Ok(session.database().tables()?.into_iter().map(|t| t.name).collect()),
+        }
+    }
+
+    fn table_defs(&self) -> Result<Vec<TableDef>, String> {
+        match self {
+            Engine::Memory(..) => Err("The in-memory engine keeps no schema; use --data-dir".to_string()),
+            Engine::Persistent(session) => session.database().tables(),
+        }
+    }
+
+    // Loads a CSV file whose header names the columns, returning the rows
+    // loaded.
+    fn import_csv(&mut self, path: &str, table: &str) -> Result<usize, String> {
+        let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path, e))?;
+        match self {
+            Engine::Memory(db, _) => {
+                let mut records = CsvReader::new(BufReader::new(file))
+                    .filter(|r| !matches!(r, Ok(fields) if fields.len() == 1 && fields[0].is_empty()));
+                let columns = records.next()
+                    .ok_or("CSV file is empty")?
+                    .map_err(|e| format!("Failed to read CSV: {}", e))?;
+                let values = records.collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| format!("Failed to read CSV: {}", e))?;
+                let rows = values.len();
+                db.execute(ast::SQLStatement::Insert(ast::InsertStatement { table: table.to_string(), columns, values, on_conflict: None }))?;
+                Ok(rows)
+            }
+            Engine::Persistent(session) => session.run(|db| db.import_csv(table, file)),
+        }
+    }
+
+    fn dump(&self, table: Option<&str>, writer: impl Write) -> Result<(), String> {
+        match (self, table) {
+            (Engine::Memory(..), _) => Err("The in-memory engine keeps no schema; use --data-dir".to_string()),
+            (Engine::Persistent(session), Some(table)) => session.run(|db| db.dump_table(table, writer)),
+            (Engine::Persistent(session), None) => session.run(|db| db.dump(writer)),
+        }
+    }
+
+    // Table and column names, for completion.
+    fn names(&self) -> Vec<String> {
+        match self {
+            Engine::Memory(db, _) => db.table_names(),
+            Engine::Persistent(session) => session.database().tables().unwrap_or_default().into_iter()
+                .flat_map(|t| {
+                    let columns = t.column_names();
+                    std::iter::once(t.name).chain(columns)
+                })
+                .collect(),
+        }
+    }
+
+    // Rolls back a transaction left open before closing the database.
+    fn close(&mut self) -> Result<(), String> {
+        match self {
+            Engine::Memory(..) => Ok(()),
+            Engine::Persistent(session) => {
+                session.end();
+                session.database().close()
+            }
+        }
+    }
+}
 
 fn main() {
-    let mut db = PersistentDatabase::new("data").expect("Failed to initialize database");
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.iter().any(|a| a == "-h" || a == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+    let args = parse_args(args.into_iter()).unwrap_or_else(|e| {
+        eprintln!("{}\n\n{}", e, USAGE);
+        process::exit(2);
+    });
+    match args.command {
+        Subcommand::Parse(sql) => parse_only(sql, args.dialect),
+        Subcommand::Format(sql) => format_only(sql, args.dialect),
+        _ => {}
+    }
+    let mut db = Engine::open(&args).unwrap_or_else(|e| {
+        eprintln!("Failed to open database: {}", e);
+        process::exit(1);
+    });
+    if let Subcommand::Run(script) = &args.command {
+        let result = db.execute_script(script);
+        if let Err(e) = db.close() {
+            eprintln!("Failed to close database: {}", e);
+            process::exit(1);
+        }
+        match result {
+            Ok(statements) => println!("Executed {} statements from {}", statements, script),
+            Err(e) => {
+                eprintln!("{}: {}", script, e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+    if !io::stdin().is_terminal() {
+        run_batch(&mut db);
+    }
 
-    loop {
-        print!("sql> ");
-        io::stdout().flush().unwrap();
+    let mut editor: Editor<SqlHelper, DefaultHistory> = Editor::new().unwrap_or_else(|e| {
+        eprintln!("Failed to start the line editor: {}", e);
+        process::exit(1);
+    });
+    editor.set_helper(Some(SqlHelper { names: db.names() }));
 
-        let mut query = String::new();
-        io::stdin().read_line(&mut query).unwrap();
-        let query = query.trim();
+    loop {
+        let line = match editor.readline("sql> ") {
+            Ok(line) => line,
+            // Ctrl-C abandons the line being typed
+            Err(ReadlineError::Interrupted) => continue,
+            // End of input exits like `exit` does
+            Err(_) => String::from("exit"),
+        };
+        let query = line.trim();
+        if !query.is_empty() {
+            let _ = editor.add_history_entry(query);
+        }
 
         if query.eq_ignore_ascii_case("exit") {
             println!("👋 Exiting SQL Parser...");
-            db.close().expect("Failed to close database");
+            if let Err(e) = db.close() {
+                eprintln!("Failed to close database: {}", e);
+                process::exit(1);
+            }
             break;
         }
+        if query.is_empty() {
+            continue;
+        }
+        if query.starts_with(['.', '\\']) {
+            match run_meta_command(&mut db, query) {
+                Ok(output) if output.is_empty() => {}
+                Ok(output) => println!("{}", output),
+                Err(e) => eprintln!(" {}", e),
+            }
+            continue;
+        }
+
+        let result = match terminal_height().filter(|_| db.settings().pager) {
+            Some(height) => {
+                let mut output = Paged::new(height);
+                let result = run_statement(&mut db, query, &mut output);
+                result.and(output.finish())
+            }
+            None => run_statement(&mut db, query, &mut io::stdout().lock()),
+        };
+        if let Err(e) = result {
+            eprintln!(" {}", e);
+        }
+        if let Some(helper) = editor.helper_mut() {
+            helper.names = db.names();
+        }
+    }
+}
+
+const KEYWORDS: &[&str] = &[
+    "ADD", "ALTER", "ANALYZE", "AND", "AS", "ASC", "AVG", "BEGIN", "BETWEEN", "BY", "COLUMN", "COMMIT",
+    "COUNT", "CREATE", "CROSS", "DELETE", "DESC", "DROP", "EXPLAIN", "FALSE", "FETCH", "FIRST", "FROM", "FULL", "GROUP",
+    "HASH", "HAVING", "INNER", "INSERT", "INTO", "JOIN", "KEY", "LEFT", "LENGTH", "LIMIT", "MAX", "MIN",
+    "MODIFY", "OFFSET", "ON", "ONLY", "ORDER", "PARTITION", "PARTITIONS", "PRIMARY", "RELEASE", "RETURNING",
+    "RIGHT", "ROLLBACK", "ROWS", "SAVEPOINT", "SELECT", "SET", "SHOW", "SUM", "TABLE", "TO", "TRUE", "TYPE",
+    "UPDATE", "VACUUM", "VALUES", "WHERE", "WITH",
+];
+
+const META_COMMANDS: &[&str] = &[
+    ".bench", ".dump", ".help", ".import", ".indexes", ".maxwidth", ".mode", ".pager", ".schema", ".tables", ".timing",
+];
+
+// Completes SQL keywords, in the case the word was started in, and the
+// names in the schema as of the last statement.
+struct SqlHelper {
+    names: Vec<String>,
+}
+
+impl SqlHelper {
+    fn candidates(&self, line: &str, prefix: &str) -> Vec<String> {
+        if prefix.starts_with('.') && line.starts_with(prefix) {
+            return META_COMMANDS.iter().filter(|c| c.starts_with(prefix)).map(|c| c.to_string()).collect();
+        }
+        if prefix.is_empty() {
+            return Vec::new();
+        }
+        let lowercase = prefix.chars().all(|c| !c.is_ascii_uppercase());
+        let keywords = KEYWORDS.iter()
+            .filter(|k| k.starts_with(&prefix.to_ascii_uppercase()))
+            .map(|k| if lowercase { k.to_ascii_lowercase() } else { k.to_string() });
+        let names = self.names.iter().filter(|n| n.starts_with(prefix)).cloned();
+        let mut candidates: Vec<String> = keywords.chain(names).collect();
+        candidates.sort();
+        candidates.dedup();
+        candidates
+    }
+}
+
+impl Completer for SqlHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos].char_indices().rev()
+            .find(|&(_, c)| !(c.is_alphanumeric() || c == '_' || c == '.'))
+            .map_or(0, |(i, c)| i + c.len_utf8());
+        Ok((start, self.candidates(line, &line[start..pos])))
+    }
+}
+
+impl Hinter for SqlHelper {
+    type Hint = String;
+}
+
+impl Highlighter for SqlHelper {}
+
+impl Validator for SqlHelper {}
 
-        match execute_query(query) {
-            Ok(statement) => {
-                match db.execute(statement) {
-                    Ok(result) => println!("{}", result),
-                    Err(e) => eprintln!(" Execution error: {}", e),
+impl Helper for SqlHelper {}
+
+// Prints the syntax tree of `sql`, or of stdin, as JSON and exits.
+fn parse_only(sql: Option<String>, dialect: Dialect) -> ! {
+    let sql = sql.unwrap_or_else(read_stdin);
+    match parse_to_json(&sql, dialect) {
+        Ok(json) => {
+            println!("{}", json);
+            process::exit(0);
+        }
+        Err(e) => {
+            eprintln!("{}", diagnostic(&sql, &e));
+            process::exit(1);
+        }
+    }
+}
+
+// Prints `sql`, or each statement on stdin, formatted, and exits non-zero
+// if any of them doesn't parse.
+fn format_only(sql: Option<String>, dialect: Dialect) -> ! {
+    let sql = sql.unwrap_or_else(read_stdin);
+    let mut failed = false;
+    let mut first = true;
+    for statement in StatementReader::new(sql.as_bytes()) {
+        let statement = statement.expect("reading from memory can't fail");
+        match format_sql_with_dialect(&statement.sql, dialect) {
+            Ok(formatted) => {
+                // A blank line between statements
+                if !first {
+                    println!();
+                }
+                first = false;
+                println!("{}", formatted);
+            }
+            Err(e) => {
+                eprintln!("Line {}: {}", statement.line, diagnostic(&statement.sql, &e));
+                failed = true;
+            }
+        }
+    }
+    process::exit(if failed { 1 } else { 0 })
+}
+
+fn read_stdin() -> String {
+    let mut input = String::new();
+    if let Err(e) = io::stdin().read_to_string(&mut input) {
+        eprintln!("Failed to read input: {}", e);
+        process::exit(1);
+    }
+    input
+}
+
+// Runs piped statements without prompting, exiting non-zero if any failed.
+fn run_batch(db: &mut Engine) -> ! {
+    let mut failed = false;
+    for statement in StatementReader::new(io::stdin().lock()) {
+        let statement = statement.unwrap_or_else(|e| {
+            eprintln!("Failed to read input: {}", e);
+            process::exit(1);
+        });
+        match run_statement(db, &statement.sql, &mut io::stdout().lock()) {
+            Ok(()) => {}
+            Err(e) => {
+                eprintln!("Line {}: {}", statement.line, e);
+                failed = true;
+            }
+        }
+    }
+    if let Err(e) = db.close() {
+        eprintln!("Failed to close database: {}", e);
+        process::exit(1);
+    }
+    process::exit(if failed { 1 } else { 0 })
+}
+
+// Parses and runs a statement, writing the rows of a SELECT in the chosen
+// format as they are read, or the message of any other statement, followed
+// by how long each step took if timing is on.
+fn run_statement(db: &mut Engine, sql: &str, output: &mut impl Write) -> Result<(), String> {
+    let started = Instant::now();
+    let statement = parse_statement(sql).map_err(|e| diagnostic(sql, &e))?;
+    let parse_time = started.elapsed();
+
+    let started = Instant::now();
+    let mut rows = RowOutput::new(db.settings().row_writer(&mut *output));
+    let written = match db.execute_into(statement, &mut rows) {
+        // The reader, e.g. a pager, quit before reading it all
+        Err(_) if rows.broken => return Ok(()),
+        Err(e) => return Err(format!("Execution error: {}", e)),
+        Ok(None) => rows.finish(),
+        Ok(Some(StatementResult::Rows(result))) => rows.columns(&result.columns, &result.meta)
+            .and_then(|()| result.rows.iter().try_for_each(|row| rows.row(row)))
+            .and_then(|()| rows.finish()),
+        Ok(Some(outcome)) => {
+            drop(rows);
+            let started = Instant::now();
+            writeln!(output, "{}", outcome).map(|()| started.elapsed())
+        }
+    };
+    let total_time = started.elapsed();
+    let written = written.and_then(|format_time| {
+        if !db.settings().timing {
+            return Ok(());
+        }
+        // Rows are written as they're read, so the time writing them took is
+        // told apart from the rest
+        let plan_time = db.plan_time();
+        let execute_time = total_time.saturating_sub(plan_time + format_time);
+        writeln!(output, "Time: parse {}, plan {}, execute {}, format {}",
+                 millis(parse_time), millis(plan_time), millis(execute_time), millis(format_time))
+    });
+    match written {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        written => written.map_err(|e| format!("Failed to write output: {}", e)),
+    }
+}
+
+// Writes a SELECT's rows as they come, timing how long writing them takes.
+// In the list format a result without rows says so rather than printing a
+// bare header, so the header waits for the first row.
+struct RowOutput<W: Write> {
+    writer: RowWriter<W>,
+    header: Option<(Vec<String>, Vec<ColumnMeta>)>,
+    format_time: Duration,
+    // Writing failed because the reader went away
+    broken: bool,
+}
+
+impl<W: Write> RowOutput<W> {
+    fn new(writer: RowWriter<W>) -> Self {
+        Self { writer, header: None, format_time: Duration::ZERO, broken: false }
+    }
+
+    // Ends the output, returning how long writing it took
+    fn finish(mut self) -> io::Result<Duration> {
+        let started = Instant::now();
+        match self.header.take() {
+            Some(_) => writeln!(self.writer.into_inner(), "No matching rows found")?,
+            None => self.writer.finish()?,
+        }
+        Ok(self.format_time + started.elapsed())
+    }
+
+    fn timed(&mut self, write: impl FnOnce(&mut RowWriter<W>) -> io::Result<()>) -> io::Result<()> {
+        let started = Instant::now();
+        let written = write(&mut self.writer);
+        self.format_time += started.elapsed();
+        self.broken |= written.as_ref().is_err_and(|e| e.kind() == io::ErrorKind::BrokenPipe);
+        written
+    }
+}
+
+impl<W: Write> RowSink for RowOutput<W> {
+    fn columns(&mut self, columns: &[String], meta: &[ColumnMeta]) -> io::Result<()> {
+        if self.writer.settings().format == OutputFormat::List {
+            self.header = Some((columns.to_vec(), meta.to_vec()));
+            return Ok(());
+        }
+        self.timed(|writer| writer.columns(columns, meta))
+    }
+
+    fn row(&mut self, row: &[String]) -> io::Result<()> {
+        let header = self.header.take();
+        self.timed(|writer| {
+            if let Some((columns, meta)) = header {
+                writer.columns(&columns, &meta)?;
+            }
+            writer.row(row)
+        })
+    }
+}
+
+#[cfg(unix)]
+fn terminal_height() -> Option<usize> {
+    if !io::stdout().is_terminal() {
+        return None;
+    }
+    // SAFETY: TIOCGWINSZ only writes a winsize through the pointer
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    let ok = unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0;
+    (ok && size.ws_row > 0).then_some(size.ws_row as usize)
+}
+
+#[cfg(not(unix))]
+fn terminal_height() -> Option<usize> {
+    None
+}
+
+enum Sink {
+    // Holding output until it is known whether it fits on the screen
+    Buffer(Vec<u8>),
+    Pager(Child),
+    Stdout,
+}
+
+// Writes to the terminal, or through $PAGER (`less` if unset) once the output
+// is taller than the terminal. Setting PAGER to an empty string turns paging
+// off.
+struct Paged {
+    height: usize,
+    lines: usize,
+    sink: Sink,
+}
+
+impl Paged {
+    fn new(height: usize) -> Self {
+        Self { height, lines: 0, sink: Sink::Buffer(Vec::new()) }
+    }
+
+    fn spawn_pager() -> Option<Child> {
+        let pager = std::env::var("PAGER").unwrap_or_else(|_| "less -FRX".to_string());
+        if pager.trim().is_empty() {
+            return None;
+        }
+        let pager = Command::new("sh").arg("-c").arg(&pager).stdin(Stdio::piped()).spawn().ok()?;
+        // Ctrl-C in the pager is for the pager, not for the REPL
+        #[cfg(unix)]
+        unsafe {
+            libc::signal(libc::SIGINT, libc::SIG_IGN);
+        }
+        Some(pager)
+    }
+
+    fn finish(self) -> Result<(), String> {
+        let result = match self.sink {
+            Sink::Buffer(buffer) => io::stdout().write_all(&buffer),
+            Sink::Pager(mut pager) => {
+                drop(pager.stdin.take());
+                let waited = pager.wait().map(|_| ());
+                #[cfg(unix)]
+                unsafe {
+                    libc::signal(libc::SIGINT, libc::SIG_DFL);
                 }
-            },
-            Err(e) => eprintln!(" Parse error: {}", e),
+                waited
+            }
+            Sink::Stdout => Ok(()),
+        };
+        result.map_err(|e| format!("Failed to write output: {}", e))
+    }
+}
+
+impl Write for Paged {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let buffer = match &mut self.sink {
+            Sink::Buffer(buffer) => buffer,
+            Sink::Pager(pager) => return pager.stdin.as_mut().expect("pager stdin is piped").write(data),
+            Sink::Stdout => return io::stdout().write(data),
+        };
+        buffer.extend_from_slice(data);
+        self.lines += data.iter().filter(|&&b| b == b'\n').count();
+        // Leave a line for the next prompt
+        if self.lines >= self.height {
+            let buffer = std::mem::take(buffer);
+            self.sink = match Paged::spawn_pager() {
+                Some(pager) => Sink::Pager(pager),
+                None => Sink::Stdout,
+            };
+            self.write_all(&buffer)?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match &mut self.sink {
+            Sink::Buffer(_) => Ok(()),
+            Sink::Pager(pager) => pager.stdin.as_mut().expect("pager stdin is piped").flush(),
+            Sink::Stdout => io::stdout().flush(),
+        }
+    }
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.3} ms", duration.as_secs_f64() * 1000.0)
+}
+
+const META_HELP: &str = "\
+.tables             List the tables
+.bench COUNT STATEMENT
+                    Run a statement COUNT times and report its latency
+.dump [table] [> FILE]
+                    Write CREATE TABLE and INSERT statements that recreate
+                    one or every table, to FILE or the screen
+.import FILE TABLE  Load a CSV file into a table, creating it from the header
+                    if it doesn't exist
+.schema [table]     Show the CREATE TABLE statement of one or every table
+.indexes            List the primary key index of each table
+.mode [format]      Show or set how query results are printed: list, table,
+                    ascii, csv, json or vertical
+.maxwidth [N|off]   Cut values wider than N characters short in the table and
+                    ascii formats
+.pager [on|off]     Page results taller than the terminal through $PAGER
+.timing [on|off]    Print how long parsing, planning, running and
+                    formatting each statement took; \\timing toggles it too
+.help               Show this help";
+
+// Runs a REPL `.command`.
+fn run_meta_command(db: &mut Engine, line: &str) -> Result<String, String> {
+    let mut words = line.split_whitespace();
+    let command = words.next().unwrap_or_default();
+    let argument = words.next().map(|a| a.trim_end_matches(';'));
+    if command == ".dump" {
+        let rest: Vec<&str> = line.trim_end_matches(';').split_whitespace().skip(1).collect();
+        let (table, path) = match rest.as_slice() {
+            [] => (None, None),
+            [">", path] => (None, Some(*path)),
+            [table] => (Some(*table), None),
+            [table, ">", path] => (Some(*table), Some(*path)),
+            _ => return Err("Usage: .dump [table] [> FILE]".to_string()),
+        };
+        return match path {
+            Some(path) => {
+                let file = File::create(path).map_err(|e| format!("Failed to create {}: {}", path, e))?;
+                db.dump(table, BufWriter::new(file))?;
+                Ok(format!("Dumped to {}", path))
+            }
+            None => {
+                db.dump(table, io::stdout().lock())?;
+                Ok(String::new())
+            }
+        };
+    }
+    if command == ".bench" {
+        let usage = "Usage: .bench COUNT STATEMENT";
+        let count: usize = argument.and_then(|c| c.parse().ok()).filter(|&c| c > 0).ok_or(usage)?;
+        let sql = line.trim_start().splitn(3, char::is_whitespace).nth(2).ok_or(usage)?;
+        return bench(db, count, sql);
+    }
+    if command == ".import" {
+        let (Some(path), Some(table), None) = (argument, words.next(), words.next()) else {
+            return Err("Usage: .import FILE TABLE".to_string());
+        };
+        let loaded = db.import_csv(path, table.trim_end_matches(';'))?;
+        return Ok(format!("Loaded {} rows into '{}'", loaded, table.trim_end_matches(';')));
+    }
+    let settings = *db.settings();
+    match (command, argument) {
+        (".tables", None) => Ok(db.table_names()?.join("\n")),
+        (".schema", table) => {
+            let tables: Vec<TableDef> = db.table_defs()?.into_iter()
+                .filter(|t| table.is_none_or(|name| t.name == name))
+                .collect();
+            match table {
+                Some(name) if tables.is_empty() => Err(format!("Table '{}' not found", name)),
+                _ => Ok(tables.iter().map(TableDef::create_sql).collect::<Vec<_>>().join("\n")),
+            }
+        }
+        (".indexes", None) => {
+            let indexes: Vec<String> = db.table_defs()?.into_iter()
+                .filter_map(|t| {
+                    let primary_key = t.primary_key?;
+                    Some(match t.partitions {
+                        Some(partitions) if t.split_at.is_empty() => format!("{}: PRIMARY KEY ({}), {} hash partitions", t.name, primary_key, partitions),
+                        Some(partitions) => format!("{}: PRIMARY KEY ({}), {} range partitions", t.name, primary_key, partitions),
+                        None => format!("{}: PRIMARY KEY ({})", t.name, primary_key),
+                    })
+                })
+                .collect();
+            Ok(indexes.join("\n"))
+        }
+        (".mode", None) => Ok(settings.format.to_string()),
+        (".mode", Some(mode)) => {
+            let format = mode.parse()?;
+            db.settings_mut().format = format;
+            Ok(format!("Output format set to {}", format))
+        }
+        (".maxwidth", None) => Ok(settings.max_width.map_or("off".to_string(), |width| width.to_string())),
+        (".maxwidth", Some(width)) => {
+            let max_width = match width {
+                "off" => None,
+                width => Some(width.parse().ok().filter(|&width| width > 0)
+                    .ok_or_else(|| format!("Expected a number of characters or off, not '{}'", width))?),
+            };
+            db.settings_mut().max_width = max_width;
+            Ok(match max_width {
+                Some(width) => format!("Columns are cut to {} characters", width),
+                None => "Columns are as wide as their values".to_string(),
+            })
+        }
+        (".pager", toggle) => {
+            let pager = parse_toggle(toggle, settings.pager)?;
+            db.settings_mut().pager = pager;
+            Ok(format!("Pager is {}", if pager { "on" } else { "off" }))
+        }
+        (".timing" | "\\timing", toggle) => {
+            let timing = parse_toggle(toggle, settings.timing)?;
+            db.settings_mut().timing = timing;
+            Ok(format!("Timing is {}", if timing { "on" } else { "off" }))
         }
+        (".help", None) => Ok(META_HELP.to_string()),
+        _ => Err(format!("Unknown command '{}'; try .help", line)),
+    }
+}
+
+// Runs `sql` `count` times, parsing it once, and reports the latency of the
+// runs and how many rows per second they returned.
+fn bench(db: &mut Engine, count: usize, sql: &str) -> Result<String, String> {
+    let statement = parse_statement(sql).map_err(|e| diagnostic(sql, &e))?;
+    let mut latencies = Vec::with_capacity(count);
+    let mut rows = 0;
+    for _ in 0..count {
+        let statement = statement.clone();
+        let started = Instant::now();
+        if let StatementResult::Rows(result) = db.execute(statement)? {
+            rows += result.rows.len();
+        }
+        latencies.push(started.elapsed());
+    }
+
+    latencies.sort();
+    let total: Duration = latencies.iter().sum();
+    let p95 = latencies[(count * 95).div_ceil(100) - 1];
+    Ok(format!("{} runs: min {}, avg {}, p95 {}, max {}, {:.0} rows/sec",
+               count, millis(latencies[0]), millis(total / count as u32), millis(p95),
+               millis(latencies[count - 1]), rows as f64 / total.as_secs_f64()))
+}
+
+// `on` or `off`; no argument flips `current`.
+fn parse_toggle(toggle: Option<&str>, current: bool) -> Result<bool, String> {
+    match toggle {
+        None => Ok(!current),
+        Some(toggle) if toggle.eq_ignore_ascii_case("on") => Ok(true),
+        Some(toggle) if toggle.eq_ignore_ascii_case("off") => Ok(false),
+        Some(toggle) => Err(format!("Expected on or off, not '{}'", toggle)),
     }
 }
 
 fn execute_query(query: &str) -> Result<ast::SQLStatement, String> {
-    let mut tokenizer = Tokenizer::new(query);
-    let tokens = tokenizer.tokenize()?;
+    parse_statement(query).map_err(|e| e.message)
+}
+
+const RED: &str = "\x1b[1;31m";
+const RESET: &str = "\x1b[0m";
+
+// A parse error with the line of `sql` it is on and a caret under where it
+// is, colored when stderr is a terminal that wants color.
+fn diagnostic(sql: &str, error: &SqlError) -> String {
+    let color = io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none();
+    let (red, reset) = if color { (RED, RESET) } else { ("", "") };
+    let mut message = format!("{}Parse error:{} {}", red, reset, error.message);
+    let Some(span) = error.span else {
+        return message;
+    };
 
-    let mut parser = Parser::new(tokens);
-    parser.parse()
+    let line_start = sql[..span.start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = sql[span.start..].find('\n').map_or(sql.len(), |i| span.start + i);
+    let line = sql[line_start..line_end].trim_end();
+    let column = sql[line_start..span.start].chars().count();
+    let width = sql[span.start..span.end.min(line_end)].chars().count().max(1);
+    message += &format!("\n  {}\n  {}{}{}{}", line, " ".repeat(column), red, "^".repeat(width), reset);
+    message
 }
@@ -0,0 +1,46 @@
+//! The math functions ABS, ROUND, FLOOR, CEIL, MOD and POWER, for both
+//! executors. ABS, ROUND, FLOOR and CEIL are exact, as for DECIMALs; MOD of
+//! two whole numbers is too. NULL, a value that isn't a number, MOD by zero
+//! and a POWER too large to hold all give NULL.
+
+use crate::ast::{FunctionCall, MathFunction, NULL};
+use crate::decimal::Decimal;
+use std::collections::HashMap;
+
+/// The call's value for a row, which holds its column.
+pub(crate) fn value_of(call: &FunctionCall, row: &HashMap<String, String>) -> String {
+    apply(call, row.get(&call.column).map_or(NULL, String::as_str))
+}
+
+/// The call's value for its column's `value`.
+pub(crate) fn apply(call: &FunctionCall, value: &str) -> String {
+    evaluate(call, value).unwrap_or_else(|| NULL.to_string())
+}
+
+fn evaluate(call: &FunctionCall, value: &str) -> Option<String> {
+    let decimal = || value.parse::<Decimal>().ok();
+    let arg = call.arg.as_deref();
+    match call.function {
+        MathFunction::Abs => Some(decimal()?.abs().to_string()),
+        MathFunction::Floor => Some(decimal()?.floor().to_string()),
+        MathFunction::Ceil => Some(decimal()?.ceil().to_string()),
+        MathFunction::Round => {
+            let decimal = decimal()?;
+            let digits = arg.map_or(Some(0), |digits| digits.parse().ok())?;
+            // Rounding to more digits than it has leaves it as it is
+            Some(decimal.rescale(digits.min(decimal.scale()))?.to_string())
+        }
+        MathFunction::Mod => {
+            let divisor = arg?;
+            match (value.parse::<i128>(), divisor.parse::<i128>()) {
+                (Ok(value), Ok(divisor)) => value.checked_rem(divisor).map(|rem| rem.to_string()),
+                _ => finite(value.parse::<f64>().ok()? % divisor.parse::<f64>().ok()?),
+            }
+        }
+        MathFunction::Power => finite(value.parse::<f64>().ok()?.powf(arg?.parse().ok()?)),
+    }
+}
+
+fn finite(number: f64) -> Option<String> {
+    number.is_finite().then(|| number.to_string())
+}
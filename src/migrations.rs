@@ -0,0 +1,156 @@
+//! Versioning of the data directory layout.
+//!
+//! A data directory records the layout version it was written in, in a
+//! `VERSION` file. Opening it for writing runs every migration newer than
+//! that version, in order, bumping the file after each one, so an upgrade
+//! that is interrupted resumes where it stopped. A directory written by a
+//! newer version than this build is refused instead of being misread.
+
+use crate::catalog::Catalog;
+use crate::partition::PartitionedStorage;
+use crate::persistent_executor::partitioning;
+use crate::storage::WriteBatch;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::ops::Bound::Unbounded;
+use std::path::Path;
+
+pub const VERSION_FILE: &str = "VERSION";
+/// The layout version this build writes.
+pub const DATA_FORMAT_VERSION: u32 = 2;
+
+struct Migration {
+    // The version the migration upgrades to
+    version: u32,
+    description: &'static str,
+    run: fn(&Path) -> Result<(), String>,
+}
+
+// In version order. A migration must be safe to run again after a crash part
+// way through it, since the version is only bumped once it has finished.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "move per-table schema directories into the catalog",
+        run: migrate_legacy_schemas,
+    },
+    Migration {
+        version: 2,
+        description: "store NULLs in rows as JSON null rather than the text 'NULL'",
+        run: migrate_null_values,
+    },
+];
+
+/// The layout version of `data_dir`. A directory with data but no version
+/// file predates versioning and is version 0; None if there is no data yet.
+pub fn data_format_version(data_dir: &Path) -> Result<Option<u32>, String> {
+    match fs::read_to_string(data_dir.join(VERSION_FILE)) {
+        Ok(version) => version.trim().parse().map(Some)
+            .map_err(|_| format!("Invalid {} file in '{}'", VERSION_FILE, data_dir.display())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let has_data = fs::read_dir(data_dir).is_ok_and(|mut entries| entries.next().is_some());
+            Ok(has_data.then_some(0))
+        }
+        Err(e) => Err(format!("Failed to read {} file: {}", VERSION_FILE, e)),
+    }
+}
+
+/// Upgrades `data_dir` to `DATA_FORMAT_VERSION`, returning the version it
+/// was at. An empty directory is just marked as current.
+pub fn migrate(data_dir: &Path) -> Result<u32, String> {
+    let Some(version) = data_format_version(data_dir)? else {
+        write_version(data_dir, DATA_FORMAT_VERSION)?;
+        return Ok(DATA_FORMAT_VERSION);
+    };
+    check_not_newer(data_dir, version)?;
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > version) {
+        (migration.run)(data_dir).map_err(|e| {
+            format!("Migration to version {} ({}) failed: {}", migration.version, migration.description, e)
+        })?;
+        write_version(data_dir, migration.version)?;
+    }
+    Ok(version)
+}
+
+/// Fails unless `data_dir` is already at `DATA_FORMAT_VERSION`, for opens
+/// that must not change it.
+pub fn check_current(data_dir: &Path) -> Result<(), String> {
+    let version = data_format_version(data_dir)?.unwrap_or(DATA_FORMAT_VERSION);
+    check_not_newer(data_dir, version)?;
+    if version < DATA_FORMAT_VERSION {
+        return Err(format!(
+            "Data directory '{}' is at format version {} and must be opened for writing once to upgrade it to {}",
+            data_dir.display(), version, DATA_FORMAT_VERSION,
+        ));
+    }
+    Ok(())
+}
+
+fn check_not_newer(data_dir: &Path, version: u32) -> Result<(), String> {
+    if version > DATA_FORMAT_VERSION {
+        return Err(format!(
+            "Data directory '{}' is at format version {}, newer than the {} this build supports",
+            data_dir.display(), version, DATA_FORMAT_VERSION,
+        ));
+    }
+    Ok(())
+}
+
+// Replaced atomically, like the storage manifests.
+fn write_version(data_dir: &Path, version: u32) -> Result<(), String> {
+    let write = || -> io::Result<()> {
+        let tmp_path = data_dir.join(format!("{}.tmp", VERSION_FILE));
+        let mut file = File::create(&tmp_path)?;
+        writeln!(file, "{}", version)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, data_dir.join(VERSION_FILE))?;
+        #[cfg(unix)]
+        File::open(data_dir)?.sync_all()?;
+        Ok(())
+    };
+    write().map_err(|e| format!("Failed to write {} file: {}", VERSION_FILE, e))
+}
+
+fn migrate_legacy_schemas(data_dir: &Path) -> Result<(), String> {
+    let mut catalog = Catalog::open(data_dir)?;
+    catalog.migrate_legacy_schemas(data_dir)?;
+    catalog.close()
+}
+
+// Until rows could hold a NULL apart from text, the text 'NULL' was how one
+// was stored, so each becomes a JSON null. Only rows still holding one are
+// rewritten, which makes running this again harmless.
+fn migrate_null_values(data_dir: &Path) -> Result<(), String> {
+    let mut catalog = Catalog::open(data_dir)?;
+    let tables = catalog.tables()
+        .map(|table| Ok((table.name.clone(), partitioning(table)?)))
+        .collect::<Result<Vec<_>, String>>()?;
+    catalog.close()?;
+
+    for (name, partitioning) in tables {
+        let storage_err = |e: io::Error| format!("table '{}': {}", name, e);
+        let mut storage = PartitionedStorage::open(data_dir, &name, partitioning.as_ref()).map_err(storage_err)?;
+        let mut batch = WriteBatch::new();
+        for row in storage.scan((Unbounded, Unbounded), false).map_err(storage_err)? {
+            let (key, value) = row.map_err(storage_err)?;
+            let mut row: HashMap<String, Option<String>> = serde_json::from_str(&value)
+                .map_err(|e| format!("table '{}': invalid row: {}", name, e))?;
+            let nulls: Vec<_> = row.iter()
+                .filter(|(_, value)| value.as_deref() == Some("NULL"))
+                .map(|(column, _)| column.clone())
+                .collect();
+            if nulls.is_empty() {
+                continue;
+            }
+            for column in nulls {
+                row.insert(column, None);
+            }
+            batch.put(key, serde_json::to_string(&row).map_err(|e| e.to_string())?);
+        }
+        storage.write_deferred(batch).map_err(storage_err)?;
+        storage.close().map_err(storage_err)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,426 @@
+//! What a statement returns, the rows of a SELECT among it, and the
+//! formats the REPL can render rows in.
+
+use crate::ast::{ColumnExpr, MathFunction, NULL};
+use crate::blob::Blob;
+use crate::csv::quote_field;
+use crate::result_formatter::{ResultFormatter, TableStyle};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::fmt;
+use std::io::{self, Write};
+use std::str::FromStr;
+
+/// What `execute` returns for each kind of statement.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum StatementResult {
+    /// The rows of a SELECT.
+    Rows(ResultSet),
+    /// What an INSERT, UPDATE or DELETE changed, and its message.
+    Changed {
+        message: String,
+        rows_affected: usize,
+        /// The key of the last row an INSERT stored; None for UPDATE and
+        /// DELETE.
+        last_insert_rowid: Option<String>,
+    },
+    /// The message of any other statement: DDL, transaction control, SHOW,
+    /// EXPLAIN or VACUUM.
+    Done(String),
+}
+
+impl StatementResult {
+    /// The rows of a SELECT; None for any other statement.
+    pub fn into_rows(self) -> Option<ResultSet> {
+        match self {
+            StatementResult::Rows(rows) => Some(rows),
+            _ => None,
+        }
+    }
+
+    /// The number of rows an INSERT, UPDATE or DELETE changed; 0 for any
+    /// other statement.
+    pub fn rows_affected(&self) -> usize {
+        match self {
+            StatementResult::Changed { rows_affected, .. } => *rows_affected,
+            _ => 0,
+        }
+    }
+}
+
+/// Rows as the REPL lists them, "No matching rows found" for none, and the
+/// message of any other statement.
+impl fmt::Display for StatementResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StatementResult::Rows(rows) if rows.rows.is_empty() => f.write_str("No matching rows found"),
+            StatementResult::Rows(rows) => rows.fmt(f),
+            StatementResult::Changed { message, .. } | StatementResult::Done(message) => f.write_str(message),
+        }
+    }
+}
+
+/// The result of a SELECT: column names and one value per column per row.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ResultSet {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+    /// What each column holds, in the order of `columns`; empty where
+    /// that isn't known.
+    #[serde(default)]
+    pub meta: Vec<ColumnMeta>,
+}
+
+/// A column of a result: its name, the table it was read from, and the
+/// type of its values.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ColumnMeta {
+    pub name: String,
+    /// None for a computed column, e.g. `COUNT(*)`.
+    pub table: Option<String>,
+    pub value_type: ValueType,
+}
+
+/// The type of a column's values. The persistent database knows them from
+/// the table's schema, and the in-memory one infers them from the values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ValueType {
+    Integer,
+    Real,
+    Decimal,
+    Text,
+    Boolean,
+    Blob,
+    /// Nothing to tell it by, e.g. a column of only NULLs.
+    Unknown,
+}
+
+impl ValueType {
+    /// The type of a column declared as `data_type`, e.g. "DECIMAL(10,2)".
+    pub fn of_declared(data_type: &str) -> Self {
+        let upper = data_type.to_uppercase();
+        let name = upper.split('(').next().unwrap_or_default().trim();
+        match name {
+            "INT" | "INTEGER" | "BIGINT" | "SMALLINT" | "TINYINT" => ValueType::Integer,
+            "REAL" | "FLOAT" | "DOUBLE" => ValueType::Real,
+            "DECIMAL" | "NUMERIC" => ValueType::Decimal,
+            "BOOL" | "BOOLEAN" => ValueType::Boolean,
+            "BLOB" => ValueType::Blob,
+            _ => ValueType::Text,
+        }
+    }
+
+    /// The narrowest type all of `values` fit, leaving out NULLs.
+    pub fn infer<'a>(values: impl IntoIterator<Item = &'a str>) -> Self {
+        values.into_iter()
+            .filter(|value| *value != NULL)
+            .map(|value| {
+                if value.parse::<i64>().is_ok() {
+                    ValueType::Integer
+                } else if value.parse::<f64>().is_ok() {
+                    ValueType::Real
+                } else if value == "true" || value == "false" {
+                    ValueType::Boolean
+                } else if value.parse::<Blob>().is_ok() {
+                    ValueType::Blob
+                } else {
+                    ValueType::Text
+                }
+            })
+            .fold(ValueType::Unknown, |inferred, value_type| match (inferred, value_type) {
+                (ValueType::Unknown, value_type) => value_type,
+                (inferred, value_type) if inferred == value_type => inferred,
+                (ValueType::Integer | ValueType::Real, ValueType::Integer | ValueType::Real) => ValueType::Real,
+                _ => ValueType::Text,
+            })
+    }
+}
+
+impl ColumnMeta {
+    /// The columns of a result with `headers` from selecting `columns`,
+    /// given the table and type of each column the query reads by name.
+    pub(crate) fn of_select(columns: &[ColumnExpr], headers: &[String],
+                            source: impl Fn(&str) -> (Option<String>, ValueType)) -> Vec<Self> {
+        if let [ColumnExpr::All] = columns {
+            return headers.iter()
+                .map(|name| {
+                    let (table, value_type) = source(name);
+                    ColumnMeta { name: name.clone(), table, value_type }
+                })
+                .collect();
+        }
+        columns.iter().zip(headers)
+            .map(|(column, name)| {
+                let (table, value_type) = match column.unaliased() {
+                    ColumnExpr::Column(c) => source(c),
+                    ColumnExpr::Count(_) | ColumnExpr::CountAll | ColumnExpr::Length(_) => (None, ValueType::Integer),
+                    // Aggregates of a DECIMAL stay exact
+                    ColumnExpr::Sum(c) | ColumnExpr::Avg(c) | ColumnExpr::Min(c) | ColumnExpr::Max(c) => match source(c).1 {
+                        ValueType::Decimal => (None, ValueType::Decimal),
+                        _ => (None, ValueType::Real),
+                    },
+                    // Only POWER turns whole or exact numbers into floating point
+                    ColumnExpr::Math(call) => match source(&call.column).1 {
+                        value_type @ (ValueType::Integer | ValueType::Decimal) if call.function != MathFunction::Power => (None, value_type),
+                        _ => (None, ValueType::Real),
+                    },
+                    ColumnExpr::All | ColumnExpr::Aliased(..) => (None, ValueType::Unknown),
+                };
+                ColumnMeta { name: name.clone(), table, value_type }
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// `a | b` lines under a header, as `execute` returns them.
+    #[default]
+    List,
+    /// A box-drawn table with each column padded to its widest value.
+    Table,
+    /// The same table drawn with `+`, `-` and `|`.
+    Ascii,
+    /// RFC 4180 CSV with a header line.
+    Csv,
+    /// A JSON array with one object per row. Values of numeric and boolean
+    /// columns are JSON numbers and booleans, NULLs null, and the rest strings.
+    Json,
+    /// One `column: value` line per column, for rows too wide for a table.
+    Vertical,
+}
+
+impl OutputFormat {
+    pub const NAMES: &'static [&'static str] = &["list", "table", "ascii", "csv", "json", "vertical"];
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            OutputFormat::List => "list",
+            OutputFormat::Table => "table",
+            OutputFormat::Ascii => "ascii",
+            OutputFormat::Csv => "csv",
+            OutputFormat::Json => "json",
+            OutputFormat::Vertical => "vertical",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "list" => Ok(OutputFormat::List),
+            "table" | "box" => Ok(OutputFormat::Table),
+            "ascii" => Ok(OutputFormat::Ascii),
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            "vertical" | "line" => Ok(OutputFormat::Vertical),
+            _ => Err(format!("Unknown format '{}'; expected one of {}", s, OutputFormat::NAMES.join(", "))),
+        }
+    }
+}
+
+/// How a client such as the REPL prints what statements return; each
+/// `Session` has its own.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OutputSettings {
+    pub format: OutputFormat,
+    /// Whether to print how long each statement took.
+    pub timing: bool,
+    /// Whether output taller than the terminal goes through a pager.
+    pub pager: bool,
+    /// The most characters a column of the table and ascii formats shows.
+    pub max_width: Option<usize>,
+}
+
+impl OutputSettings {
+    /// Writes `result` in `format`, cutting the columns of the table and
+    /// ascii formats to `max_width`.
+    pub fn write(&self, result: &ResultSet, mut writer: impl Write) -> io::Result<()> {
+        if let Some(formatter) = self.table_formatter() {
+            formatter.write(result, &mut writer)?;
+            return writer.flush();
+        }
+        let mut rows = self.row_writer(writer);
+        rows.columns(&result.columns, &result.meta)?;
+        result.rows.iter().try_for_each(|row| rows.row(row))?;
+        rows.finish()
+    }
+
+    /// A `RowWriter` in these settings.
+    pub fn row_writer<W: Write>(&self, writer: W) -> RowWriter<W> {
+        RowWriter { settings: *self, writer, columns: Vec::new(), meta: Vec::new(), rows: 0, held: Vec::new() }
+    }
+
+    fn table_formatter(&self) -> Option<ResultFormatter> {
+        let style = match self.format {
+            OutputFormat::Table => TableStyle::Unicode,
+            OutputFormat::Ascii => TableStyle::Ascii,
+            _ => return None,
+        };
+        let formatter = ResultFormatter::new().style(style);
+        Some(self.max_width.map_or(formatter, |width| formatter.max_width(width)))
+    }
+}
+
+/// Where the rows of a query go as they are read; see
+/// `PersistentDatabase::query_each`.
+pub trait RowSink {
+    /// The result's columns, given once before its first row.
+    fn columns(&mut self, columns: &[String], meta: &[ColumnMeta]) -> io::Result<()>;
+    fn row(&mut self, row: &[String]) -> io::Result<()>;
+}
+
+/// Writes rows in a format as they come. The table and ascii formats pad
+/// each column to its widest value, so they hold the rows until `finish`.
+pub struct RowWriter<W: Write> {
+    settings: OutputSettings,
+    writer: W,
+    columns: Vec<String>,
+    meta: Vec<ColumnMeta>,
+    rows: usize,
+    held: Vec<Vec<String>>,
+}
+
+impl<W: Write> RowWriter<W> {
+    /// The number of rows written so far.
+    pub fn rows(&self) -> usize {
+        self.rows
+    }
+
+    pub fn settings(&self) -> &OutputSettings {
+        &self.settings
+    }
+
+    /// The writer, leaving the output unfinished.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Ends the output, writing a table's rows, and flushes it.
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(formatter) = self.settings.table_formatter() {
+            let result = ResultSet { columns: self.columns, rows: self.held, meta: self.meta };
+            formatter.write(&result, &mut self.writer)?;
+        } else if self.settings.format == OutputFormat::Json {
+            writeln!(self.writer, "{}", if self.rows == 0 { "[]" } else { "]" })?;
+        }
+        self.writer.flush()
+    }
+}
+
+impl<W: Write> RowSink for RowWriter<W> {
+    fn columns(&mut self, columns: &[String], meta: &[ColumnMeta]) -> io::Result<()> {
+        self.columns = columns.to_vec();
+        self.meta = meta.to_vec();
+        match self.settings.format {
+            OutputFormat::List => {
+                let header = columns.join(" | ");
+                writeln!(self.writer, "{}", header)?;
+                writeln!(self.writer, "{}", "-".repeat(header.chars().count()))
+            }
+            OutputFormat::Csv => {
+                let header: Vec<String> = columns.iter().map(|column| quote_field(column)).collect();
+                write!(self.writer, "{}\r\n", header.join(","))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn row(&mut self, row: &[String]) -> io::Result<()> {
+        self.rows += 1;
+        match self.settings.format {
+            OutputFormat::List => {
+                let values: Vec<Cow<str>> = row.iter().map(|value| shown(value)).collect();
+                writeln!(self.writer, "{}", values.join(" | "))
+            }
+            // A NULL is an empty field and empty text a quoted one, `""`
+            OutputFormat::Csv => {
+                let fields: Vec<String> = row.iter()
+                    .map(|field| match field.as_str() {
+                        NULL => String::new(),
+                        "" => "\"\"".to_string(),
+                        field => quote_field(field),
+                    })
+                    .collect();
+                write!(self.writer, "{}\r\n", fields.join(","))
+            }
+            OutputFormat::Json => {
+                let members: Vec<String> = self.columns.iter().zip(row).enumerate()
+                    .map(|(i, (column, value))| {
+                        let value_type = self.meta.get(i).map(|meta| meta.value_type);
+                        format!("{}: {}", json_string(column), json_value(value, value_type))
+                    })
+                    .collect();
+                let separator = if self.rows == 1 { "[" } else { ",\n " };
+                write!(self.writer, "{}{{{}}}", separator, members.join(", "))
+            }
+            OutputFormat::Vertical => {
+                let width = self.columns.iter().map(|c| c.chars().count()).max().unwrap_or(0);
+                writeln!(self.writer, "*** {}. row ***", self.rows)?;
+                for (column, value) in self.columns.iter().zip(row) {
+                    writeln!(self.writer, "{:>width$}: {}", column, shown(value), width = width)?;
+                }
+                Ok(())
+            }
+            OutputFormat::Table | OutputFormat::Ascii => {
+                self.held.push(row.to_vec());
+                Ok(())
+            }
+        }
+    }
+}
+
+impl ResultSet {
+    pub fn render(&self, format: OutputFormat) -> String {
+        let mut output = Vec::new();
+        self.write(format, &mut output).expect("writing to a Vec can't fail");
+        String::from_utf8(output).expect("results are UTF-8")
+    }
+
+    /// Writes the rows in `format` as they are formatted, rather than
+    /// building the whole output first.
+    pub fn write(&self, format: OutputFormat, writer: impl Write) -> io::Result<()> {
+        OutputSettings { format, ..OutputSettings::default() }.write(self, writer)
+    }
+}
+
+impl fmt::Display for ResultSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.render(OutputFormat::List))
+    }
+}
+
+/// A value as the text formats show it: NULL as the word, and text that
+/// reads as it quoted, so the two can be told apart.
+pub(crate) fn shown(value: &str) -> Cow<'_, str> {
+    if value == NULL {
+        Cow::Borrowed("NULL")
+    } else if value.eq_ignore_ascii_case("NULL") {
+        Cow::Owned(format!("'{}'", value))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+fn json_string(s: &str) -> String {
+    serde_json::Value::from(s).to_string()
+}
+
+// A value as JSON: null for NULL, numbers and booleans as such where the
+// column's type says they are, and everything else, DECIMALs included, as a
+// string
+fn json_value(value: &str, value_type: Option<ValueType>) -> String {
+    let json = match value_type {
+        _ if value == NULL => Some(serde_json::Value::Null),
+        Some(ValueType::Integer) => value.parse::<i64>().ok().map(serde_json::Value::from),
+        Some(ValueType::Real) => value.parse::<f64>().ok().filter(|v| v.is_finite()).map(serde_json::Value::from),
+        Some(ValueType::Boolean) => value.parse::<bool>().ok().map(serde_json::Value::from),
+        _ => None,
+    };
+    json.map_or_else(|| json_string(value), |json| json.to_string())
+}
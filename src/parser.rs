@@ -1,40 +1,219 @@
 use crate::ast::{
     SQLStatement,SelectStatement,InsertStatement,UpdateStatement,DeleteStatement,
-    WhereClause,CreateTableStatement,AlterTableStatement,DropTableStatement,
+    WhereClause,InList,CreateTableStatement,AlterTableStatement,DropTableStatement,
     AlterAction,OrderByClause,ColumnExpr,HavingClause,
-    JoinClause,JoinType,
+    JoinClause,JoinCondition,JoinType,TransactionStatement,ShowStatement,PartitionBy,Hint,
+    ExplainStatement,ExplainFormat,ConflictAction,FunctionCall,MathFunction,SetValue,TableFunction,NULL,
 };
-use crate::tokenizer::Token;
+use crate::blob::Blob;
+use crate::dialect::Dialect;
+use crate::tokenizer::{tokenize, tokenize_spanned, Span, SqlError, Token, Tokens};
+use std::cell::Cell;
+
+/// Words read as keywords in places a name can also go, e.g. AS in a SELECT
+/// list, so a name spelled like one has to be quoted there.
+pub(crate) const RESERVED_NAMES: &[&str] = &["AS", "PRIMARY"];
+// Words that can follow a table name in FROM, so aren't taken as its alias
+const NOT_ALIASES: &[&str] = &["AS", "LIMIT", "OFFSET", "FETCH", "HAVING", "RETURNING"];
+
 pub struct Parser {
     tokens: Vec<Token>,
     current: usize,
+    // Where each token is, if the parser was given spans
+    spans: Vec<Span>,
+    // The furthest token looked at, which is where a parse error is reported
+    furthest: Cell<usize>,
+    dialect: Dialect,
 }
 
 impl Parser {
     pub fn new(tokens: Vec<Token>) -> Self {
-        Self { tokens, current: 0 }
+        let tokens = without_misplaced_hints(tokens, |token| token);
+        Self { tokens, current: 0, spans: Vec::new(), furthest: Cell::new(0), dialect: Dialect::default() }
+    }
+
+    pub fn with_spans(tokens: Vec<(Token, Span)>) -> Self {
+        let (tokens, spans) = without_misplaced_hints(tokens, |(token, _)| token).into_iter().unzip();
+        Self { tokens, current: 0, spans, furthest: Cell::new(0), dialect: Dialect::default() }
+    }
+
+    /// Accepts `dialect`'s syntax; see `Dialect`.
+    pub fn with_dialect(mut self, dialect: Dialect) -> Self {
+        self.dialect = dialect;
+        self
+    }
+
+    /// Like `parse`, pointing the error at the token the parser gave up on,
+    /// or past the end of `sql` if it ran out of tokens.
+    pub fn parse_spanned(&mut self, sql: &str) -> Result<SQLStatement, SqlError> {
+        self.parse().map_err(|message| {
+            let span = self.spans.get(self.furthest.get()).copied()
+                .unwrap_or(Span { start: sql.len(), end: sql.len() });
+            SqlError { message, span: Some(span) }
+        })
     }
 
     pub fn parse(&mut self) -> Result<SQLStatement, String> {
         match self.peek() {
-            Some(Token::Select) => { self.advance(); self.parse_select() }
+            Some(Token::Select) => { self.advance(); self.parse_select().map(SQLStatement::Select) }
             Some(Token::Insert) => { self.advance(); self.parse_insert() }
             Some(Token::Update) => { self.advance(); self.parse_update() }
             Some(Token::Delete) => { self.advance(); self.parse_delete() }
             Some(Token::Create) => { self.advance(); self.parse_create_table() }
             Some(Token::Alter) => { self.advance(); self.parse_alter_table() }
             Some(Token::Drop)   => { self.advance(); self.parse_drop_table() } 
+            Some(Token::Begin) | Some(Token::Commit) | Some(Token::Rollback)
+            | Some(Token::Savepoint) | Some(Token::Release) => self.parse_transaction(),
+            Some(Token::Set) => { self.advance(); self.parse_set_variable() }
+            Some(Token::Identifier(word)) if word.eq_ignore_ascii_case("SHOW") => {
+                self.advance();
+                self.parse_show()
+            }
+            Some(Token::Identifier(word)) if word.eq_ignore_ascii_case("VACUUM") => {
+                self.advance();
+                let table = match self.peek() {
+                    Some(Token::Identifier(_) | Token::QuotedIdentifier(_)) => {
+                        Some(self.expect_identifier("Expected table name after VACUUM")?)
+                    }
+                    _ => None,
+                };
+                Ok(SQLStatement::Vacuum(table))
+            }
+            Some(Token::Identifier(word)) if word.eq_ignore_ascii_case("ANALYZE") => {
+                self.advance();
+                let table = match self.peek() {
+                    Some(Token::Identifier(_) | Token::QuotedIdentifier(_)) => {
+                        Some(self.expect_identifier("Expected table name after ANALYZE")?)
+                    }
+                    _ => None,
+                };
+                Ok(SQLStatement::Analyze(table))
+            }
+            Some(Token::Identifier(word)) if word.eq_ignore_ascii_case("EXPLAIN") => {
+                self.advance();
+                self.parse_explain()
+            }
             _ => Err("Unexpected token at start of statement".to_string()),
         }
     }
 
-    fn parse_select(&mut self) -> Result<SQLStatement, String> {
+    fn parse_select(&mut self) -> Result<SelectStatement, String> {
+        let hints = match self.peek() {
+            Some(Token::Hint(text)) => {
+                let text = text.clone();
+                self.advance();
+                parse_hints(&text)?
+            }
+            _ => Vec::new(),
+        };
         let columns = self.parse_column_expr_list(Token::From)?;
         self.expect(Token::From)?;
-        let table = self.expect_identifier("Expected table name after FROM")?;
+        let mut table = self.expect_identifier("Expected table name after FROM")?;
+        let table_function = self.parse_optional_table_function(&table)?;
+        if let Some(function) = &table_function {
+            table = function.name().to_string();
+        }
+        let mut aliases = Vec::new();
+        self.parse_optional_table_alias(&table, &mut aliases)?;
+        let as_of = self.parse_optional_as_of()?;
+
+        let join = self.parse_optional_join(&mut aliases)?;
+        if table_function.is_some() && (join.is_some() || as_of.is_some()) {
+            return Err(format!("{}() can't be joined or read AS OF", table));
+        }
 
-        let mut join = None;
+        let where_cl = self.parse_optional_where_clause()?;
+        let group_by = self.parse_optional_group_by()?;
+        let mut having = self.parse_optional_having()?;
+        let order_by = self.parse_optional_order_by()?;
+        // HAVING used to be accepted only after ORDER BY
+        if having.is_none() {
+            having = self.parse_optional_having()?;
+        }
+        let (limit, offset) = self.parse_optional_limit()?;
+        if let Some(token) = self.peek().filter(|token| !matches!(token, Token::Semicolon | Token::RightParen)) {
+            return Err(format!("Unexpected {:?} after the query", token));
+        }
 
+        let mut stmt = SelectStatement {
+            columns,
+            table,
+            join,
+            where_clause: where_cl,
+            group_by,
+            order_by,
+            having,
+            limit,
+            offset,
+            hints,
+            as_of,
+            table_function,
+        };
+        resolve_table_aliases(&mut stmt, &aliases)?;
+        Ok(stmt)
+    }
+
+    // `[AS] alias` after table `table` in FROM or JOIN, recorded in `aliases`
+    fn parse_optional_table_alias(&mut self, table: &str, aliases: &mut Vec<(String, String)>) -> Result<(), String> {
+        let alias = match self.peek() {
+            Some(Token::Identifier(word)) if word.eq_ignore_ascii_case("AS") => {
+                if matches!(self.tokens.get(self.current + 1), Some(Token::Identifier(word)) if word.eq_ignore_ascii_case("OF")) {
+                    return Ok(());
+                }
+                self.advance();
+                self.expect_identifier("Expected an alias after AS")?
+            }
+            Some(Token::Identifier(word)) if NOT_ALIASES.iter().any(|k| word.eq_ignore_ascii_case(k)) => return Ok(()),
+            Some(Token::Identifier(_) | Token::QuotedIdentifier(_)) => self.expect_identifier("Expected an alias")?,
+            _ => return Ok(()),
+        };
+        aliases.push((alias, table.to_string()));
+        Ok(())
+    }
+
+    // The arguments of a table function called `name` after FROM, e.g.
+    // `stats('users')`, if FROM names one rather than a table
+    fn parse_optional_table_function(&mut self, name: &str) -> Result<Option<TableFunction>, String> {
+        if self.peek() != Some(&Token::LeftParen) {
+            return Ok(None);
+        }
+        self.advance();
+        let mut args = Vec::new();
+        while self.peek() != Some(&Token::RightParen) {
+            if !args.is_empty() {
+                self.expect(Token::Comma)?;
+            }
+            match self.advance() {
+                Some(Token::StringLiteral(arg)) => args.push(arg),
+                _ => return Err(format!("Expected a string argument to {}()", name)),
+            }
+        }
+        self.expect(Token::RightParen)?;
+        TableFunction::from_call(name, &args).map(Some)
+    }
+
+    // `AS OF <timestamp>`, in milliseconds since the Unix epoch
+    fn parse_optional_as_of(&mut self) -> Result<Option<u64>, String> {
+        let keyword = |token: Option<&Token>, keyword: &str| {
+            matches!(token, Some(Token::Identifier(word)) if word.eq_ignore_ascii_case(keyword))
+        };
+        if !keyword(self.peek(), "AS") || !keyword(self.tokens.get(self.current + 1), "OF") {
+            return Ok(None);
+        }
+        self.advance();
+        self.advance();
+        let timestamp = match self.advance() {
+            Some(Token::NumberLiteral(n)) => n.to_string(),
+            Some(Token::StringLiteral(s)) => s,
+            _ => return Err("Expected a timestamp after AS OF".to_string()),
+        };
+        timestamp.parse().map(Some)
+            .map_err(|_| format!("AS OF takes milliseconds since the Unix epoch, not '{}'", timestamp))
+    }
+
+    // A JOIN of any type after a statement's table, recording the aliases
+    // its tables are given in `aliases`
+    fn parse_optional_join(&mut self, aliases: &mut Vec<(String, String)>) -> Result<Option<JoinClause>, String> {
         if let Some(token) = self.peek().cloned() {
             let join_type = match token {
                 Token::Join => { self.advance(); JoinType::Inner },
@@ -45,46 +224,50 @@ impl Parser {
                 _ => JoinType::Inner, // fallback, join won't be triggered
             };
 
-            if matches!(token, Token::Join | Token::Left | Token::Right | Token::Full) {
-                let join_table = self.expect_identifier("Expected table name after JOIN")?;
-                self.expect(Token::On)?;
-                let left = self.parse_qualified_identifier()?;
-                self.expect(Token::Equals)?;
-                let right = self.parse_qualified_identifier()?;
-                
-                join = Some(JoinClause {
+            if matches!(token, Token::Join | Token::Left | Token::Right | Token::Full | Token::Cross) {
+                // A parenthesized group is joined as a whole
+                let (join_table, nested) = if self.peek() == Some(&Token::LeftParen) {
+                    self.advance();
+                    let table = self.expect_identifier("Expected table name after '('")?;
+                    self.parse_optional_table_alias(&table, aliases)?;
+                    let nested = self.parse_optional_join(aliases)?
+                        .ok_or("Expected JOIN inside parentheses after the table name")?;
+                    self.expect(Token::RightParen)?;
+                    (table, Some(Box::new(nested)))
+                } else {
+                    let table = self.expect_identifier("Expected table name after JOIN")?;
+                    self.parse_optional_table_alias(&table, aliases)?;
+                    (table, None)
+                };
+                // A CROSS JOIN pairs every row with every row, so has no ON
+                let mut on = Vec::new();
+                if join_type != JoinType::Cross {
+                    self.expect(Token::On)?;
+                    loop {
+                        let left = self.parse_qualified_identifier()?;
+                        let operator = match self.advance() {
+                            Some(Token::Equals) => "=",
+                            Some(Token::LessThan) => "<",
+                            Some(Token::GreaterThan) => ">",
+                            _ => return Err("Expected =, < or > in JOIN condition".to_string()),
+                        };
+                        let right = self.parse_qualified_identifier()?;
+                        on.push(JoinCondition { left, operator: operator.to_string(), right });
+                        if !self.skip_keyword("AND") {
+                            break;
+                        }
+                    }
+                }
+
+                return Ok(Some(JoinClause {
                     join_type,
                     table: join_table,
-                    on_left: left,
-                    on_right: right,
-                });
-            }
-            else if token == Token::Cross {
-                let join_table = self.expect_identifier("Expected table name after CROSS JOIN")?;
-
-        join = Some(JoinClause {
-            join_type,
-            table: join_table,
-            on_left: String::new(),
-            on_right: String::new(),
-        });
+                    on,
+                    nested,
+                }));
             }
         }
-
-        let where_cl = self.parse_optional_where_clause()?;
-        let group_by = self.parse_optional_group_by()?;
-        let order_by = self.parse_optional_order_by()?;
-        let having = self.parse_optional_having()?;
-
-        Ok(SQLStatement::Select(SelectStatement {
-            columns,
-            table,
-            join,
-            where_clause: where_cl,
-            group_by,
-            order_by,
-            having,
-        }))
+        Ok(None)
     }
 
     fn parse_qualified_identifier(&mut self) -> Result<String, String> {
@@ -92,7 +275,7 @@ impl Parser {
     
         if self.peek() == Some(&Token::Dot) {
             self.advance(); // skip the dot
-            if let Some(Token::Identifier(second)) = self.advance() {
+            if let Some(Token::Identifier(second) | Token::QuotedIdentifier(second)) = self.advance() {
                 return Ok(format!("{}.{}", first, second));
             } else {
                 return Err("Expected identifier after '.'".to_string());
@@ -108,10 +291,24 @@ impl Parser {
         self.expect(Token::LeftParen)?;
 
         let mut columns = Vec::new();
+        let mut primary_key = None;
         loop {
-            let name = self.expect_identifier("Expected column name")?;
-            let datatype = self.expect_identifier("Expected data type")?;
-            columns.push((name, datatype));
+            if self.skip_keyword("PRIMARY") {
+                // Table constraint: PRIMARY KEY (column)
+                self.expect_keyword("KEY")?;
+                self.expect(Token::LeftParen)?;
+                let column = self.expect_identifier("Expected column name in PRIMARY KEY")?;
+                self.expect(Token::RightParen)?;
+                Self::set_primary_key(&mut primary_key, column)?;
+            } else {
+                let name = self.expect_identifier("Expected column name")?;
+                let datatype = self.parse_data_type("Expected data type")?;
+                if self.skip_keyword("PRIMARY") {
+                    self.expect_keyword("KEY")?;
+                    Self::set_primary_key(&mut primary_key, name.clone())?;
+                }
+                columns.push((name, datatype));
+            }
 
             match self.peek() {
                 Some(Token::Comma) => { self.advance(); }
@@ -120,7 +317,100 @@ impl Parser {
             }
         }
 
-        Ok(SQLStatement::CreateTable(CreateTableStatement { table, columns }))
+        let partition_by = if self.skip_keyword("PARTITION") { Some(self.parse_partition_by()?) } else { None };
+        let options = if self.skip_keyword("WITH") { self.parse_options()? } else { Vec::new() };
+
+        Ok(SQLStatement::CreateTable(CreateTableStatement { table, columns, primary_key, options, partition_by }))
+    }
+
+    // Parses a type name with optional size parameters, e.g. DECIMAL(10, 2),
+    // which are kept in the type as "DECIMAL(10,2)".
+    fn parse_data_type(&mut self, message: &str) -> Result<String, String> {
+        let name = self.expect_identifier(message)?;
+        if self.peek() != Some(&Token::LeftParen) {
+            return Ok(name);
+        }
+        self.advance();
+        let mut params = Vec::new();
+        loop {
+            match self.advance() {
+                Some(Token::NumberLiteral(n)) if n >= 0.0 && n.fract() == 0.0 => params.push((n as u64).to_string()),
+                _ => return Err(format!("Expected a whole number in the parameters of type {}", name)),
+            }
+            match self.advance() {
+                Some(Token::Comma) => {}
+                Some(Token::RightParen) => break,
+                _ => return Err(format!("Expected ',' or ')' in the parameters of type {}", name)),
+            }
+        }
+        Ok(format!("{}({})", name, params.join(",")))
+    }
+
+    // Parses `BY HASH(column) PARTITIONS n` or `BY RANGE(column) SPLIT AT
+    // (value, ...)` after PARTITION.
+    fn parse_partition_by(&mut self) -> Result<PartitionBy, String> {
+        self.expect(Token::By)?;
+        if self.skip_keyword("RANGE") {
+            self.expect(Token::LeftParen)?;
+            let column = self.expect_identifier("Expected column name in PARTITION BY RANGE")?;
+            self.expect(Token::RightParen)?;
+            self.expect_keyword("SPLIT")?;
+            self.expect_keyword("AT")?;
+            let split_at = self.parse_value_tuple()?;
+            if split_at.is_empty() {
+                return Err("SPLIT AT needs at least one value".to_string());
+            }
+            return Ok(PartitionBy { column, partitions: split_at.len() + 1, split_at });
+        }
+        self.expect_keyword("HASH")?;
+        self.expect(Token::LeftParen)?;
+        let column = self.expect_identifier("Expected column name in PARTITION BY HASH")?;
+        self.expect(Token::RightParen)?;
+        self.expect_keyword("PARTITIONS")?;
+        let partitions = match self.advance() {
+            Some(Token::NumberLiteral(n)) if n.fract() == 0.0 && n >= 1.0 => n as usize,
+            _ => return Err("Expected a positive partition count after PARTITIONS".to_string()),
+        };
+        Ok(PartitionBy { column, partitions, split_at: Vec::new() })
+    }
+
+    // Parses `(name = value, ...)`, or a single `name = value` without the
+    // parentheses; values are numbers, strings or words.
+    fn parse_options(&mut self) -> Result<Vec<(String, String)>, String> {
+        if !matches!(self.peek(), Some(Token::LeftParen)) {
+            return Ok(vec![self.parse_option()?]);
+        }
+        self.advance();
+        let mut options = Vec::new();
+        loop {
+            options.push(self.parse_option()?);
+            match self.advance() {
+                Some(Token::Comma) => {}
+                Some(Token::RightParen) => break,
+                _ => return Err("Expected ',' or ')' after option".to_string()),
+            }
+        }
+        Ok(options)
+    }
+
+    fn parse_option(&mut self) -> Result<(String, String), String> {
+        let name = self.expect_identifier("Expected option name")?;
+        self.expect(Token::Equals)?;
+        let value = match self.advance() {
+            Some(Token::NumberLiteral(n)) => n.to_string(),
+            Some(Token::StringLiteral(value)) | Some(Token::Identifier(value)) => value.clone(),
+            Some(t) => return Err(format!("Expected value for option '{}' but found {:?}", name, t)),
+            None => return Err(format!("Expected value for option '{}' but reached end of input", name)),
+        };
+        Ok((name.to_lowercase(), value))
+    }
+
+    fn set_primary_key(primary_key: &mut Option<String>, column: String) -> Result<(), String> {
+        if primary_key.is_some() {
+            return Err("Only one PRIMARY KEY column is supported".to_string());
+        }
+        *primary_key = Some(column);
+        Ok(())
     }
 
     fn parse_alter_table(&mut self) -> Result<SQLStatement, String> {
@@ -129,30 +419,59 @@ impl Parser {
 
         match self.advance() {
             Some(Token::Add) => {
+                self.skip_column_keyword();
                 let column = self.expect_identifier("Expected column name after ADD")?;
-                if let Some(Token::Identifier(_)) = self.peek() {
-                    self.advance(); // optionally consume data type
-                }
+                let data_type = match self.peek() {
+                    Some(Token::Identifier(_)) => Some(self.parse_data_type("Expected data type")?),
+                    _ => None,
+                };
                 Ok(SQLStatement::AlterTable(AlterTableStatement {
                     table,
-                    action: AlterAction::AddColumn(column),
+                    action: AlterAction::AddColumn(column, data_type),
                 }))
             }
             Some(Token::Drop) => {
+                self.skip_column_keyword();
                 let column = self.expect_identifier("Expected column name after DROP")?;
                 Ok(SQLStatement::AlterTable(AlterTableStatement {
                     table,
                     action: AlterAction::DropColumn(column),
                 }))
             }
-            Some(Token::Modify) => {
+            Some(Token::Modify) if self.dialect.supports_modify() => {
+                self.skip_column_keyword();
                 let column = self.expect_identifier("Expected column name after MODIFY")?;
-                let new_type = self.expect_identifier("Expected new data type after column")?;
+                let new_type = self.parse_data_type("Expected new data type after column")?;
                 Ok(SQLStatement::AlterTable(AlterTableStatement {
                     table,
                     action: AlterAction::ModifyColumn(column, new_type),
                 }))
             }
+            // ALTER [COLUMN] c [SET DATA] TYPE t
+            Some(Token::Alter) if self.dialect.supports_alter_column() => {
+                self.skip_column_keyword();
+                let column = self.expect_identifier("Expected column name after ALTER COLUMN")?;
+                if self.peek() == Some(&Token::Set) {
+                    self.advance();
+                    self.expect_keyword("DATA")?;
+                }
+                self.expect_keyword("TYPE")?;
+                let new_type = self.parse_data_type("Expected new data type after TYPE")?;
+                Ok(SQLStatement::AlterTable(AlterTableStatement {
+                    table,
+                    action: AlterAction::ModifyColumn(column, new_type),
+                }))
+            }
+            Some(Token::Modify) | Some(Token::Alter) => {
+                Err(format!("The {} dialect doesn't support changing a column's type this way", self.dialect))
+            }
+            Some(Token::Set) => {
+                let options = self.parse_options()?;
+                Ok(SQLStatement::AlterTable(AlterTableStatement {
+                    table,
+                    action: AlterAction::SetOptions(options),
+                }))
+            }
             Some(t) => Err(format!("Unexpected token in ALTER TABLE: {:?}", t)),
             None => Err("Unexpected end of input in ALTER TABLE".to_string()),
         }
@@ -164,11 +483,190 @@ impl Parser {
         Ok(SQLStatement::DropTable(DropTableStatement { table }))
     }
 
+    fn parse_transaction(&mut self) -> Result<SQLStatement, String> {
+        let stmt = match self.advance() {
+            Some(Token::Begin) => {
+                let optimistic = self.skip_keyword("OPTIMISTIC");
+                self.skip_keyword("TRANSACTION");
+                match optimistic {
+                    true => TransactionStatement::BeginOptimistic,
+                    false => TransactionStatement::Begin,
+                }
+            }
+            Some(Token::Commit) => TransactionStatement::Commit,
+            Some(Token::Rollback) => {
+                if self.skip_keyword("TO") {
+                    if self.peek() == Some(&Token::Savepoint) {
+                        self.advance();
+                    }
+                    let name = self.expect_identifier("Expected savepoint name after ROLLBACK TO")?;
+                    TransactionStatement::RollbackToSavepoint(name)
+                } else {
+                    TransactionStatement::Rollback
+                }
+            }
+            Some(Token::Savepoint) => {
+                let name = self.expect_identifier("Expected savepoint name after SAVEPOINT")?;
+                TransactionStatement::Savepoint(name)
+            }
+            Some(Token::Release) => {
+                if self.peek() == Some(&Token::Savepoint) {
+                    self.advance();
+                }
+                let name = self.expect_identifier("Expected savepoint name after RELEASE")?;
+                TransactionStatement::ReleaseSavepoint(name)
+            }
+            _ => return Err("Expected transaction control statement".to_string()),
+        };
+        Ok(SQLStatement::Transaction(stmt))
+    }
+
+    fn parse_show(&mut self) -> Result<SQLStatement, String> {
+        if self.skip_keyword("STORAGE") {
+            self.expect_keyword("STATS")?;
+            return Ok(SQLStatement::Show(ShowStatement::StorageStats));
+        }
+        if self.peek() == Some(&Token::Table) {
+            self.advance();
+            self.expect_keyword("STATUS")?;
+            return Ok(SQLStatement::Show(ShowStatement::TableStatus));
+        }
+        if let Some(Token::Identifier(_) | Token::QuotedIdentifier(_)) = self.peek() {
+            let name = self.expect_identifier("Expected a variable name after SHOW")?;
+            return Ok(SQLStatement::Show(ShowStatement::Variable(name)));
+        }
+        Err("Expected STORAGE STATS, TABLE STATUS or a variable name after SHOW".to_string())
+    }
+
+    // `SET name = value` or `SET name TO value`, where the value is a
+    // string, a number or a bare word such as `on`
+    fn parse_set_variable(&mut self) -> Result<SQLStatement, String> {
+        let name = self.expect_identifier("Expected a variable name after SET")?;
+        if self.peek() == Some(&Token::Equals) {
+            self.advance();
+        } else {
+            self.expect_keyword("TO")?;
+        }
+        let value = match self.advance() {
+            Some(Token::StringLiteral(value) | Token::Identifier(value)) => value,
+            Some(Token::NumberLiteral(n)) => n.to_string(),
+            _ => return Err(format!("Expected a value for '{}'", name)),
+        };
+        Ok(SQLStatement::SetVariable(name, value))
+    }
+
+    fn parse_explain(&mut self) -> Result<SQLStatement, String> {
+        let mut format = ExplainFormat::Text;
+        if self.peek() == Some(&Token::LeftParen) {
+            self.advance();
+            self.expect_keyword("FORMAT")?;
+            format = if self.skip_keyword("JSON") {
+                ExplainFormat::Json
+            } else if self.skip_keyword("TEXT") {
+                ExplainFormat::Text
+            } else {
+                return Err("Expected TEXT or JSON after FORMAT".to_string());
+            };
+            self.expect(Token::RightParen)?;
+        }
+        self.expect(Token::Select).map_err(|_| "EXPLAIN is only supported for SELECT".to_string())?;
+        let select = self.parse_select()?;
+        Ok(SQLStatement::Explain(ExplainStatement { format, select }))
+    }
+
+    // Consumes the COLUMN of ADD COLUMN c and the like, unless it is the
+    // name of the column itself.
+    fn skip_column_keyword(&mut self) {
+        if matches!(self.tokens.get(self.current + 1), Some(Token::Identifier(_) | Token::QuotedIdentifier(_))) {
+            self.skip_keyword("COLUMN");
+        }
+    }
+
+    // LIMIT n [OFFSET m], LIMIT m, n, and [OFFSET m {ROW | ROWS}]
+    // [FETCH {FIRST | NEXT} [n] {ROW | ROWS} ONLY], as the dialect allows.
+    fn parse_optional_limit(&mut self) -> Result<(Option<usize>, Option<usize>), String> {
+        let (mut limit, mut offset) = (None, None);
+        if self.skip_keyword("LIMIT") {
+            if !self.dialect.supports_limit() {
+                return Err(format!("The {} dialect doesn't support LIMIT; use FETCH FIRST n ROWS ONLY", self.dialect));
+            }
+            let count = self.expect_count("LIMIT")?;
+            if self.peek() == Some(&Token::Comma) {
+                self.advance();
+                offset = Some(count);
+                limit = Some(self.expect_count("LIMIT")?);
+            } else {
+                limit = Some(count);
+            }
+        }
+        if offset.is_none() && self.skip_keyword("OFFSET") {
+            offset = Some(self.expect_count("OFFSET")?);
+            if !self.skip_keyword("ROWS") {
+                self.skip_keyword("ROW");
+            }
+        }
+        if limit.is_none() && self.skip_keyword("FETCH") {
+            if !self.dialect.supports_fetch() {
+                return Err(format!("The {} dialect doesn't support FETCH; use LIMIT", self.dialect));
+            }
+            if !self.skip_keyword("FIRST") {
+                self.expect_keyword("NEXT")?;
+            }
+            let count = match self.peek() {
+                Some(Token::NumberLiteral(_)) => self.expect_count("FETCH FIRST")?,
+                _ => 1,
+            };
+            if !self.skip_keyword("ROWS") {
+                self.expect_keyword("ROW")?;
+            }
+            self.expect_keyword("ONLY")?;
+            limit = Some(count);
+        }
+        Ok((limit, offset))
+    }
+
+    fn expect_count(&mut self, clause: &str) -> Result<usize, String> {
+        match self.advance() {
+            Some(Token::NumberLiteral(n)) if n >= 0.0 && n.fract() == 0.0 => Ok(n as usize),
+            Some(t) => Err(format!("Expected a row count after {} but found {:?}", clause, t)),
+            None => Err(format!("Expected a row count after {} but reached end of input", clause)),
+        }
+    }
+
+    // Consumes an optional non-reserved keyword (e.g. TO, TRANSACTION) that is
+    // tokenized as a plain identifier.
+    fn skip_keyword(&mut self, keyword: &str) -> bool {
+        if let Some(Token::Identifier(word)) = self.peek() {
+            if word.eq_ignore_ascii_case(keyword) {
+                self.advance();
+                return true;
+            }
+        }
+        false
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), String> {
+        if self.skip_keyword(keyword) {
+            Ok(())
+        } else {
+            match self.peek() {
+                Some(t) => Err(format!("Expected {}, but found {:?}", keyword, t)),
+                None => Err(format!("Expected {}, but reached end of input", keyword)),
+            }
+        }
+    }
+
     fn parse_optional_order_by(&mut self) -> Result<Option<OrderByClause>, String> {
         if let Some(Token::Order) = self.peek() {
             self.advance();
             self.expect(Token::By)?;
-            let column = self.expect_identifier("Expected column name after ORDER BY")?;
+            let column = self.expect_column("Expected column name after ORDER BY")?;
+            let call = self.peek() == Some(&Token::LeftParen) && !column.contains('.');
+            let expr = match MathFunction::from_name(&column) {
+                Some(function) if call => ColumnExpr::Math(self.parse_math_call(function)?),
+                _ if call => self.parse_aggregate_call(&column)?,
+                _ => ColumnExpr::Column(column),
+            };
 
             let descending = match self.peek() {
                 Some(Token::Desc) => { self.advance(); true },
@@ -176,11 +674,64 @@ impl Parser {
                 _ => false,
             };
 
-            Ok(Some(OrderByClause { column, descending }))
+            Ok(Some(OrderByClause { expr, descending }))
         } else {
             Ok(None)
         }
     }
+    // The parenthesized argument of an aggregate such as COUNT(*) or
+    // SUM(amount), after the function's name
+    fn parse_aggregate_call(&mut self, func: &str) -> Result<ColumnExpr, String> {
+        self.expect(Token::LeftParen)?;
+        let func_upper = func.to_uppercase();
+        if func_upper == "COUNT" && self.peek() == Some(&Token::Asterisk) {
+            self.advance();
+            self.expect(Token::RightParen)?;
+            return Ok(ColumnExpr::CountAll);
+        }
+        let column = self.expect_column("Expected column or '*' inside function call")?;
+        self.expect(Token::RightParen)?;
+        match func_upper.as_str() {
+            "COUNT" => Ok(ColumnExpr::Count(column)),
+            "SUM" => Ok(ColumnExpr::Sum(column)),
+            "AVG" => Ok(ColumnExpr::Avg(column)),
+            "MIN" => Ok(ColumnExpr::Min(column)),
+            "MAX" => Ok(ColumnExpr::Max(column)),
+            _ => Err(format!("Unsupported aggregate: {}", func)),
+        }
+    }
+
+    // The parenthesized arguments of a math function such as ABS(balance) or
+    // ROUND(price, 2), after the function's name
+    fn parse_math_call(&mut self, function: MathFunction) -> Result<FunctionCall, String> {
+        self.expect(Token::LeftParen)?;
+        let column = self.expect_column(&format!("Expected column name inside {}()", function.name()))?;
+        let arg = if self.peek() == Some(&Token::Comma) {
+            self.advance();
+            let arg = match self.advance() {
+                Some(Token::NumberLiteral(n)) => n.to_string(),
+                Some(Token::StringLiteral(s)) => s,
+                Some(t) => return Err(format!("Expected a number as the second argument of {}() but found {:?}", function.name(), t)),
+                None => return Err(format!("Expected a number as the second argument of {}() but reached end of input", function.name())),
+            };
+            let valid = match function {
+                MathFunction::Round => arg.parse::<u32>().is_ok(),
+                MathFunction::Mod | MathFunction::Power => arg.parse::<f64>().is_ok(),
+                _ => return Err(format!("{}() takes one argument", function.name())),
+            };
+            if !valid {
+                return Err(format!("'{}' is not a valid second argument of {}()", arg, function.name()));
+            }
+            Some(arg)
+        } else if matches!(function, MathFunction::Mod | MathFunction::Power) {
+            return Err(format!("{}() takes two arguments", function.name()));
+        } else {
+            None
+        };
+        self.expect(Token::RightParen)?;
+        Ok(FunctionCall { function, column, arg })
+    }
+
     fn parse_optional_group_by(&mut self) -> Result<Option<Vec<String>>, String> {
         if let Some(Token::Group) = self.peek() {
             self.advance();
@@ -188,8 +739,7 @@ impl Parser {
             let mut cols = Vec::new();
             // read comma‑separated identifiers
             loop {
-                let c = self.expect_identifier("Expected column name after GROUP BY")?;
-                cols.push(c);
+                cols.push(self.expect_column("Expected column name after GROUP BY")?);
                 if let Some(Token::Comma) = self.peek() {
                     self.advance();
                 } else {
@@ -206,33 +756,13 @@ impl Parser {
             if word.eq_ignore_ascii_case("having") {
                 self.advance();
     
-                // Parse left-hand expression: could be COUNT(*), SUM(col), etc.
-                let expr = match self.advance() {
-                    Some(Token::Identifier(func)) => {
-                        let func_upper = func.to_uppercase();
-                        self.expect(Token::LeftParen)?;
-                        let inner = match self.advance() {
-                            Some(Token::Asterisk) if func_upper == "COUNT" => {
-                                self.expect(Token::RightParen)?;
-                                ColumnExpr::CountAll
-                            }
-                            Some(Token::Identifier(col)) => {
-                                self.expect(Token::RightParen)?;
-                                match func_upper.as_str() {
-                                    "COUNT" => ColumnExpr::Count(col),
-                                    "SUM" => ColumnExpr::Sum(col),
-                                    "AVG" => ColumnExpr::Avg(col),
-                                    "MIN" => ColumnExpr::Min(col),
-                                    "MAX" => ColumnExpr::Max(col),
-                                    _ => return Err(format!("Unsupported aggregate in HAVING: {}", func)),
-                                }
-                            }
-                            _ => return Err("Expected column or '*' inside function call".to_string()),
-                        };
-                        inner
-                    }
-                    Some(t) => return Err(format!("Unexpected token in HAVING: {:?}", t)),
-                    None => return Err("Unexpected end of input in HAVING clause".to_string()),
+                // An aggregate such as COUNT(*) or SUM(col), or the alias of
+                // one in the select list
+                let name = self.expect_column("Expected aggregate or alias in HAVING")?;
+                let expr = if self.peek() == Some(&Token::LeftParen) && !name.contains('.') {
+                    self.parse_aggregate_call(&name)?
+                } else {
+                    ColumnExpr::Column(name)
                 };
     
                 let operator = match self.advance() {
@@ -244,6 +774,7 @@ impl Parser {
     
                 let value = match self.advance() {
                     Some(Token::StringLiteral(s)) => s.clone(),
+                    Some(Token::HexLiteral(bytes)) => Blob(bytes).to_string(),
                     Some(Token::NumberLiteral(n)) => n.to_string(),
                     Some(t) => return Err(format!("Expected value in HAVING but found {:?}", t)),
                     None => return Err("Expected value in HAVING but found end of input".to_string()),
@@ -265,27 +796,44 @@ impl Parser {
                     self.advance();
                     columns.push(ColumnExpr::All);
                 }
-                Some(Token::Identifier(first)) => {
+                Some(Token::Identifier(word)) if word.eq_ignore_ascii_case("AS") => {
+                    // AS follows the expression it names, not a comma
+                    let follows_comma = self.current.checked_sub(1).and_then(|i| self.tokens.get(i)) == Some(&Token::Comma);
+                    self.advance();
+                    let alias = self.expect_identifier("Expected alias after AS")?;
+                    match columns.pop() {
+                        Some(expr @ (ColumnExpr::All | ColumnExpr::Aliased(..))) => {
+                            return Err(format!("Can't alias {}", expr.name()));
+                        }
+                        Some(expr) if !follows_comma => columns.push(ColumnExpr::Aliased(Box::new(expr), alias)),
+                        _ => return Err("Expected a column before AS".to_string()),
+                    }
+                }
+                Some(Token::Identifier(first) | Token::QuotedIdentifier(first)) => {
                     let mut ident = first.clone();
                     self.advance();
     
                     // Handle qualified names: users.name
                     if self.peek() == Some(&Token::Dot) {
                         self.advance(); // skip the dot
-                        if let Some(Token::Identifier(second)) = self.advance() {
+                        if let Some(Token::Identifier(second) | Token::QuotedIdentifier(second)) = self.advance() {
                             ident = format!("{}.{}", ident, second);
                         } else {
                             return Err("Expected identifier after '.'".to_string());
                         }
                     }
     
-                    // Check for aggregate functions like COUNT(), SUM()
-                    if self.peek() == Some(&Token::LeftParen) {
+                    let math = MathFunction::from_name(&ident).filter(|_| self.peek() == Some(&Token::LeftParen));
+                    if let Some(function) = math {
+                        columns.push(ColumnExpr::Math(self.parse_math_call(function)?));
+                    } else if self.peek() == Some(&Token::LeftParen) {
+                        // Check for aggregate functions like COUNT(), SUM()
                         self.advance(); // skip '('
     
-                        let inner_col = match self.advance() {
-                            Some(Token::Identifier(name)) => name.clone(),
+                        let inner_col = match self.peek() {
+                            Some(Token::Identifier(_) | Token::QuotedIdentifier(_)) => self.parse_qualified_identifier()?,
                             Some(Token::Asterisk) if ident.to_uppercase() == "COUNT" => {
+                                self.advance();
                                 self.expect(Token::RightParen)?;
                                 columns.push(ColumnExpr::CountAll);
                                 if self.peek() == Some(&Token::Comma) {
@@ -304,6 +852,7 @@ impl Parser {
                             "AVG"   => ColumnExpr::Avg(inner_col),
                             "MIN"   => ColumnExpr::Min(inner_col),
                             "MAX"   => ColumnExpr::Max(inner_col),
+                            "LENGTH" => ColumnExpr::Length(inner_col),
                             _ => return Err(format!("Unknown function '{}'", ident)),
                         };
     
@@ -324,15 +873,36 @@ impl Parser {
     }    
 
     fn parse_insert(&mut self) -> Result<SQLStatement, String> {
+        let (table, columns, on_conflict) = self.parse_insert_header()?;
+        let values = self.parse_values_list()?;
+        Ok(SQLStatement::Insert(InsertStatement { table, columns, values, on_conflict }))
+    }
+
+    // What follows INSERT up to and including VALUES
+    fn parse_insert_header(&mut self) -> Result<(String, Vec<String>, Option<ConflictAction>), String> {
+        let on_conflict = if self.skip_keyword("OR") {
+            if self.skip_keyword("IGNORE") {
+                Some(ConflictAction::Ignore)
+            } else if self.skip_keyword("REPLACE") {
+                Some(ConflictAction::Replace)
+            } else {
+                return Err("Expected IGNORE or REPLACE after INSERT OR".to_string());
+            }
+        } else {
+            None
+        };
         self.expect(Token::Into)?;
         let table = self.expect_identifier("Expected table name after INSERT INTO")?;
-        self.expect(Token::LeftParen)?;
-        let columns = self.parse_column_list_until(Token::RightParen)?;
-        self.expect(Token::RightParen)?;
+        let columns = if self.peek() == Some(&Token::LeftParen) {
+            self.advance();
+            let columns = self.parse_column_list_until(Token::RightParen)?;
+            self.expect(Token::RightParen)?;
+            columns
+        } else {
+            Vec::new()
+        };
         self.expect(Token::Values)?;
-
-        let values = self.parse_values_list()?;
-        Ok(SQLStatement::Insert(InsertStatement { table, columns, values }))
+        Ok((table, columns, on_conflict))
     }
 
     fn parse_column_list_until(&mut self, terminator: Token) -> Result<Vec<String>, String> {
@@ -340,7 +910,7 @@ impl Parser {
         loop {
             match self.peek() {
                 Some(t) if *t == terminator => break,
-                Some(Token::Identifier(name)) => {
+                Some(Token::Identifier(name) | Token::QuotedIdentifier(name)) => {
                     columns.push(name.clone());
                     self.advance();
                 }
@@ -374,10 +944,27 @@ impl Parser {
         self.expect(Token::LeftParen)?;
         loop {
             match self.peek() {
-                Some(Token::StringLiteral(val)) => {
-                    values.push(val.clone());
+                Some(Token::StringLiteral(_)) | Some(Token::HexLiteral(_)) => {
+                    values.push(self.expect_value("Expected value in VALUES tuple")?);
+                }
+                Some(Token::Identifier(word)) if bool_literal(word).is_some() => {
+                    values.push(self.expect_value("Expected value in VALUES tuple")?);
+                }
+                Some(Token::Identifier(word)) if word.eq_ignore_ascii_case("NULL") => {
+                    values.push(NULL.to_string());
                     self.advance();
                 }
+                Some(Token::NumberLiteral(n)) => {
+                    values.push(n.to_string());
+                    self.advance();
+                }
+                Some(Token::Unknown(sign)) if sign == "-" => {
+                    self.advance();
+                    match self.advance() {
+                        Some(Token::NumberLiteral(n)) => values.push((-n).to_string()),
+                        _ => return Err("Expected a number after '-' in VALUES tuple".to_string()),
+                    }
+                }
                 Some(Token::Comma) => { self.advance(); }
                 Some(Token::RightParen) => {
                     self.advance();
@@ -392,17 +979,73 @@ impl Parser {
 
     fn parse_update(&mut self) -> Result<SQLStatement, String> {
         let table = self.expect_identifier("Expected table name after UPDATE")?;
+        let join = self.parse_unaliased_join()?;
         self.expect(Token::Set)?;
         let assignments = self.parse_assignments()?;
         let where_clause = self.parse_optional_where_clause()?;
-        Ok(SQLStatement::Update(UpdateStatement { table, assignments, where_clause }))
+        let returning = self.parse_optional_returning()?;
+        Ok(SQLStatement::Update(UpdateStatement { table, join, assignments, where_clause, returning }))
     }
 
     fn parse_delete(&mut self) -> Result<SQLStatement, String> {
+        // MySQL names the table to delete from before FROM when joining
+        let target = match self.peek() {
+            Some(Token::Identifier(_) | Token::QuotedIdentifier(_)) => {
+                Some(self.expect_identifier("Expected table name after DELETE")?)
+            }
+            _ => None,
+        };
         self.expect(Token::From)?;
         let table = self.expect_identifier("Expected table name after DELETE FROM")?;
+        if target.as_ref().is_some_and(|target| *target != table) {
+            return Err(format!("DELETE can only delete from its FROM table '{}'", table));
+        }
+        let join = self.parse_unaliased_join()?;
         let where_clause = self.parse_optional_where_clause()?;
-        Ok(SQLStatement::Delete(DeleteStatement { table, where_clause }))
+        let returning = self.parse_optional_returning()?;
+        Ok(SQLStatement::Delete(DeleteStatement { table, join, where_clause, returning }))
+    }
+
+    // The JOIN of an UPDATE or DELETE, whose tables can't take aliases
+    fn parse_unaliased_join(&mut self) -> Result<Option<JoinClause>, String> {
+        let mut aliases = Vec::new();
+        let join = self.parse_optional_join(&mut aliases)?;
+        match aliases.first() {
+            Some((alias, _)) => Err(format!("Table aliases such as '{}' only work in SELECT", alias)),
+            None => Ok(join),
+        }
+    }
+
+    // `RETURNING *` or a list of columns, each of which may be qualified,
+    // e.g. `old.name`, or be `old.*`
+    fn parse_optional_returning(&mut self) -> Result<Option<Vec<String>>, String> {
+        if !self.skip_keyword("RETURNING") {
+            return Ok(None);
+        }
+        let mut columns = Vec::new();
+        loop {
+            if self.peek() == Some(&Token::Asterisk) {
+                self.advance();
+                columns.push("*".to_string());
+            } else {
+                let name = self.expect_identifier("Expected column name after RETURNING")?;
+                if self.peek() == Some(&Token::Dot) {
+                    self.advance();
+                    let column = match self.advance() {
+                        Some(Token::Asterisk) => "*".to_string(),
+                        Some(Token::Identifier(column) | Token::QuotedIdentifier(column)) => column,
+                        _ => return Err("Expected column name after '.'".to_string()),
+                    };
+                    columns.push(format!("{}.{}", name, column));
+                } else {
+                    columns.push(name);
+                }
+            }
+            if self.peek() != Some(&Token::Comma) {
+                return Ok(Some(columns));
+            }
+            self.advance();
+        }
     }
 
     fn parse_optional_where_clause(&mut self) -> Result<Option<WhereClause>, String> {
@@ -415,23 +1058,116 @@ impl Parser {
     }
 
     fn parse_where_clause(&mut self) -> Result<WhereClause, String> {
-        let column = self.expect_identifier("Expected column name in WHERE clause")?;
-        let operator = match self.advance() {
+        // `WHERE NOT active` tests a boolean column for false
+        if self.skip_keyword("NOT") {
+            let column = self.expect_column("Expected boolean column after NOT")?;
+            if !self.at_condition_end() {
+                return Err("NOT only negates a boolean column".to_string());
+            }
+            return Ok(WhereClause { column, operator: "=".to_string(), value: "false".to_string(), upper_bound: None, in_list: None, function: None });
+        }
+        let function = self.peek_math_call();
+        let (column, function) = match function {
+            Some(function) => {
+                self.advance();
+                let call = self.parse_math_call(function)?;
+                (call.column.clone(), Some(call))
+            }
+            None => (self.expect_column("Expected column name in WHERE clause")?, None),
+        };
+        if self.skip_keyword("BETWEEN") {
+            let value = self.expect_value("Expected lower bound after BETWEEN")?;
+            self.expect_keyword("AND")?;
+            let upper = self.expect_value("Expected upper bound after AND")?;
+            return Ok(WhereClause {
+                column,
+                operator: "BETWEEN".to_string(),
+                value,
+                upper_bound: Some(upper),
+                in_list: None,
+                function,
+            });
+        }
+        if self.skip_keyword("IS") {
+            let operator = if self.skip_keyword("NOT") { "IS NOT" } else { "IS" };
+            self.expect_keyword("NULL")?;
+            return Ok(WhereClause {
+                column,
+                operator: operator.to_string(),
+                value: NULL.to_string(),
+                upper_bound: None,
+                in_list: None,
+                function,
+            });
+        }
+        if self.skip_keyword("IN") {
+            self.expect(Token::LeftParen)?;
+            let in_list = if self.peek() == Some(&Token::Select) {
+                self.advance();
+                InList::Subquery(Box::new(self.parse_select()?))
+            } else {
+                let mut values = vec![self.expect_value("Expected value in IN list")?];
+                while self.peek() == Some(&Token::Comma) {
+                    self.advance();
+                    values.push(self.expect_value("Expected value in IN list")?);
+                }
+                InList::Values(values)
+            };
+            self.expect(Token::RightParen)?;
+            return Ok(WhereClause {
+                column,
+                operator: "IN".to_string(),
+                value: String::new(),
+                upper_bound: None,
+                in_list: Some(in_list),
+                function,
+            });
+        }
+        let operator = match self.peek() {
             Some(Token::Equals) => "=".to_string(),
             Some(Token::LessThan) => "<".to_string(),
             Some(Token::GreaterThan) => ">".to_string(),
+            // A column on its own, e.g. `WHERE active`, is a boolean test
+            _ if self.at_condition_end() => {
+                return Ok(WhereClause { column, operator: "=".to_string(), value: "true".to_string(), upper_bound: None, in_list: None, function });
+            }
             _ => return Err("Expected comparison operator in WHERE clause".to_string()),
         };
-        let value = self.expect_string_literal("Expected value in WHERE clause")?;
-        Ok(WhereClause { column, operator, value })
+        self.advance();
+        let value = self.expect_value("Expected value in WHERE clause")?;
+        Ok(WhereClause { column, operator, value, upper_bound: None, in_list: None, function })
+    }
+
+    // Whether the next token ends a WHERE condition, so a column before it
+    // stands alone
+    fn at_condition_end(&self) -> bool {
+        match self.peek() {
+            None | Some(Token::Semicolon | Token::RightParen | Token::Order | Token::Group) => true,
+            Some(Token::Identifier(word)) => ["LIMIT", "AND", "OR", "RETURNING"].iter().any(|k| word.eq_ignore_ascii_case(k)),
+            _ => false,
+        }
+    }
+
+    // The math function the next tokens call, if they are its name and '('
+    fn peek_math_call(&self) -> Option<MathFunction> {
+        match (self.peek(), self.tokens.get(self.current + 1)) {
+            (Some(Token::Identifier(name)), Some(Token::LeftParen)) => MathFunction::from_name(name),
+            _ => None,
+        }
     }
 
-    fn parse_assignments(&mut self) -> Result<Vec<(String, String)>, String> {
+    fn parse_assignments(&mut self) -> Result<Vec<(String, SetValue)>, String> {
         let mut assignments = Vec::new();
         loop {
-            let column = self.expect_identifier("Expected column name in SET clause")?;
+            let column = self.expect_column("Expected column name in SET clause")?;
             self.expect(Token::Equals)?;
-            let value = self.expect_string_literal("Expected value in SET clause")?;
+            let value = match self.peek_math_call() {
+                Some(function) => {
+                    self.advance();
+                    SetValue::Math(self.parse_math_call(function)?)
+                }
+                None => SetValue::Value(self.expect_value("Expected value in SET clause")?),
+            };
             assignments.push((column, value));
             if let Some(Token::Comma) = self.peek() {
                 self.advance();
@@ -443,6 +1179,7 @@ impl Parser {
     }
 
     fn advance(&mut self) -> Option<Token> {
+        self.furthest.set(self.furthest.get().max(self.current));
         if self.current < self.tokens.len() {
             let token = self.tokens[self.current].clone();
             self.current += 1;
@@ -453,6 +1190,7 @@ impl Parser {
     }
 
     fn peek(&self) -> Option<&Token> {
+        self.furthest.set(self.furthest.get().max(self.current));
         self.tokens.get(self.current)
     }
 
@@ -466,22 +1204,274 @@ impl Parser {
 
     fn expect_identifier(&mut self, error_message: &str) -> Result<String, String> {
         match self.advance() {
-            Some(Token::Identifier(name)) => Ok(name.clone()),
+            Some(Token::Identifier(name) | Token::QuotedIdentifier(name)) => Ok(name.clone()),
             Some(t) => Err(format!("{} but found {:?}", error_message, t)),
             None => Err(format!("{} but reached end of input", error_message)),
         }
     }
 
-    fn expect_string_literal(&mut self, error_message: &str) -> Result<String, String> {
+    // A column name, which may be qualified by its table as `table.column`
+    fn expect_column(&mut self, error_message: &str) -> Result<String, String> {
+        let name = self.expect_identifier(error_message)?;
+        if self.peek() != Some(&Token::Dot) {
+            return Ok(name);
+        }
+        self.advance();
+        Ok(format!("{}.{}", name, self.expect_identifier("Expected column name after '.'")?))
+    }
+
+    // A string, a blob literal kept in its `X'...'` form, or TRUE or FALSE
+    fn expect_value(&mut self, error_message: &str) -> Result<String, String> {
         match self.advance() {
             Some(Token::StringLiteral(value)) => Ok(value.clone()),
+            Some(Token::HexLiteral(bytes)) => Ok(Blob(bytes).to_string()),
+            Some(Token::Identifier(word)) if word.eq_ignore_ascii_case("NULL") => Ok(NULL.to_string()),
+            Some(Token::Identifier(word)) => match bool_literal(&word) {
+                Some(value) => Ok(value.to_string()),
+                None => Err(format!("{} but found {:?}", error_message, Token::Identifier(word))),
+            },
             Some(t) => Err(format!("{} but found {:?}", error_message, t)),
             None => Err(format!("{} but reached end of input", error_message)),
         }
     }
 }
 
+// TRUE and FALSE, in any case, as the values booleans are stored as
+fn bool_literal(word: &str) -> Option<&'static str> {
+    if word.eq_ignore_ascii_case("TRUE") {
+        Some("true")
+    } else if word.eq_ignore_ascii_case("FALSE") {
+        Some("false")
+    } else {
+        None
+    }
+}
+
+// A hint comment anywhere but straight after SELECT is only a comment
+fn without_misplaced_hints<T>(tokens: Vec<T>, token: impl Fn(&T) -> &Token) -> Vec<T> {
+    let mut kept: Vec<T> = Vec::with_capacity(tokens.len());
+    for t in tokens {
+        let after_select = kept.last().is_some_and(|previous| *token(previous) == Token::Select);
+        if matches!(token(&t), Token::Hint(_)) && !after_select {
+            continue;
+        }
+        kept.push(t);
+    }
+    kept
+}
+
+// The hints in a /*+ ... */ comment: names, each optionally followed by
+// arguments in parentheses. Hints the planner doesn't know are ignored.
+fn parse_hints(text: &str) -> Result<Vec<Hint>, String> {
+    let mut parser = Parser::new(tokenize(text)?);
+    let mut hints = Vec::new();
+    while parser.peek().is_some() {
+        let name = parser.expect_identifier("Expected a hint name")?;
+        let mut args = Vec::new();
+        if parser.peek() == Some(&Token::LeftParen) {
+            parser.advance();
+            loop {
+                match parser.advance() {
+                    Some(Token::RightParen) => break,
+                    Some(Token::Comma) => {}
+                    Some(arg) => args.push(arg),
+                    None => return Err(format!("Expected ')' after the arguments of hint {}", name)),
+                }
+            }
+        }
+        let tables = || args.iter()
+            .map(|arg| match arg {
+                Token::Identifier(table) | Token::QuotedIdentifier(table) => Ok(table.clone()),
+                _ => Err(format!("Hint {} takes table names, but found {:?}", name, arg)),
+            })
+            .collect::<Result<Vec<_>, _>>();
+        match name.to_uppercase().as_str() {
+            "NO_INDEX" => hints.extend(tables()?.into_iter().map(Hint::NoIndex)),
+            "JOIN_ORDER" => hints.push(Hint::JoinOrder(tables()?)),
+            _ => {}
+        }
+    }
+    Ok(hints)
+}
+
+// Rewrites the columns a SELECT qualifies by a table's alias, `u.name`, to
+// be qualified by the table itself, `users.name`, as the executors expect.
+fn resolve_table_aliases(stmt: &mut SelectStatement, aliases: &[(String, String)]) -> Result<(), String> {
+    if aliases.is_empty() {
+        return Ok(());
+    }
+    let mut tables = vec![stmt.table.as_str()];
+    if let Some(join) = &stmt.join {
+        tables.extend(join.tables());
+    }
+    for (i, table) in tables.iter().enumerate() {
+        if tables[..i].contains(table) {
+            return Err(format!("Table '{}' appears twice; a table can't be joined to itself, even under an alias", table));
+        }
+    }
+    for (i, (alias, _)) in aliases.iter().enumerate() {
+        if aliases[..i].iter().any(|(other, _)| other == alias) {
+            return Err(format!("Alias '{}' is given to two tables", alias));
+        }
+    }
+
+    let table_of = |name: &str| aliases.iter().find(|(alias, _)| alias == name).map(|(_, table)| table.clone());
+    let resolve = |name: &mut String| {
+        if let Some((prefix, column)) = name.split_once('.') {
+            if let Some(table) = table_of(prefix) {
+                *name = format!("{}.{}", table, column);
+            }
+        }
+    };
+    fn resolve_expr(expr: &mut ColumnExpr, resolve: &impl Fn(&mut String)) {
+        match expr {
+            ColumnExpr::Column(name) | ColumnExpr::Count(name) | ColumnExpr::Sum(name) | ColumnExpr::Avg(name)
+            | ColumnExpr::Min(name) | ColumnExpr::Max(name) | ColumnExpr::Length(name) => resolve(name),
+            ColumnExpr::Math(call) => resolve(&mut call.column),
+            ColumnExpr::Aliased(expr, _) => resolve_expr(expr, resolve),
+            ColumnExpr::CountAll | ColumnExpr::All => {}
+        }
+    }
+    fn resolve_join(join: &mut JoinClause, resolve: &impl Fn(&mut String)) {
+        for condition in &mut join.on {
+            resolve(&mut condition.left);
+            resolve(&mut condition.right);
+        }
+        if let Some(nested) = &mut join.nested {
+            resolve_join(nested, resolve);
+        }
+    }
+
+    for expr in &mut stmt.columns {
+        resolve_expr(expr, &resolve);
+    }
+    if let Some(where_clause) = &mut stmt.where_clause {
+        resolve(&mut where_clause.column);
+        if let Some(function) = &mut where_clause.function {
+            resolve(&mut function.column);
+        }
+    }
+    for column in stmt.group_by.iter_mut().flatten() {
+        resolve(column);
+    }
+    if let Some(having) = &mut stmt.having {
+        resolve_expr(&mut having.column_expr, &resolve);
+    }
+    if let Some(order_by) = &mut stmt.order_by {
+        resolve_expr(&mut order_by.expr, &resolve);
+    }
+    if let Some(join) = &mut stmt.join {
+        resolve_join(join, &resolve);
+    }
+    for hint in &mut stmt.hints {
+        match hint {
+            Hint::NoIndex(table) => *table = table_of(table).unwrap_or(table.clone()),
+            Hint::JoinOrder(tables) => {
+                for table in tables {
+                    *table = table_of(table).unwrap_or(table.clone());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
 pub fn parse_sql(tokens: Vec<Token>) -> Result<SQLStatement, String> {
     let mut parser = Parser::new(tokens);
     parser.parse()
 }
+
+/// Parses an `INSERT ... VALUES` up to its first tuple, leaving the tuples to
+/// be parsed one at a time as the returned stream is iterated.
+pub fn parse_insert_stream(sql: &str) -> Result<InsertStream<'_>, String> {
+    let mut tokens = Tokens::new(sql, Dialect::default());
+    let mut header = Vec::new();
+    while header.last() != Some(&Token::Values) {
+        match tokens.next() {
+            Some(token) => header.push(token.map_err(|e| e.message)?.0),
+            None => return Err("Expected VALUES".to_string()),
+        }
+    }
+    let mut parser = Parser::new(header);
+    parser.expect(Token::Insert)?;
+    let (table, columns, on_conflict) = parser.parse_insert_header()?;
+    Ok(InsertStream { table, columns, on_conflict, sql, tokens, done: false })
+}
+
+/// An INSERT whose VALUES tuples are parsed as they are iterated, from
+/// `parse_insert_stream`. Only the tuple being parsed is held as tokens, so
+/// a generated INSERT of thousands of rows takes no more memory than its
+/// values, and no more time per row however many there are.
+pub struct InsertStream<'a> {
+    pub table: String,
+    pub columns: Vec<String>,
+    pub on_conflict: Option<ConflictAction>,
+    sql: &'a str,
+    tokens: Tokens<'a>,
+    done: bool,
+}
+
+impl InsertStream<'_> {
+    /// The whole statement, as given to `parse_insert_stream`.
+    pub fn sql(&self) -> &str {
+        self.sql
+    }
+
+    /// Parses the rest of the tuples into the statement `parse_sql` would
+    /// have made.
+    pub fn into_statement(mut self) -> Result<InsertStatement, String> {
+        let values = self.by_ref().collect::<Result<_, _>>()?;
+        Ok(InsertStatement { table: self.table, columns: self.columns, values, on_conflict: self.on_conflict })
+    }
+
+    // Like `parse_values_list`, the tuples end at the first one not followed
+    // by a comma
+    fn next_tuple(&mut self) -> Result<Option<Vec<String>>, String> {
+        let mut tuple = Vec::new();
+        while tuple.last() != Some(&Token::RightParen) {
+            match self.tokens.next().transpose().map_err(|e| e.message)? {
+                Some((token, _)) if tuple.is_empty() && token != Token::LeftParen => return Ok(None),
+                Some((token, _)) => tuple.push(token),
+                None if tuple.is_empty() => return Ok(None),
+                None => return Err("Unexpected end of input in VALUES tuple".to_string()),
+            }
+        }
+        let values = Parser::new(tuple).parse_value_tuple()?;
+        if !matches!(self.tokens.next(), Some(Ok((Token::Comma, _)))) {
+            self.done = true;
+        }
+        Ok(Some(values))
+    }
+}
+
+impl Iterator for InsertStream<'_> {
+    type Item = Result<Vec<String>, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let tuple = self.next_tuple();
+        if !matches!(tuple, Ok(Some(_))) {
+            self.done = true;
+        }
+        tuple.transpose()
+    }
+}
+
+/// Tokenizes and parses one statement, with the location of any error.
+pub fn parse_statement(sql: &str) -> Result<SQLStatement, SqlError> {
+    parse_statement_with_dialect(sql, Dialect::default())
+}
+
+pub fn parse_statement_with_dialect(sql: &str, dialect: Dialect) -> Result<SQLStatement, SqlError> {
+    Parser::with_spans(tokenize_spanned(sql, dialect)?).with_dialect(dialect).parse_spanned(sql)
+}
+
+/// Parses one statement without running it, returning its AST as pretty
+/// JSON, e.g. `{"Select": {"columns": [...], "table": "users", ...}}`.
+#[cfg(feature = "serde")]
+pub fn parse_to_json(sql: &str, dialect: Dialect) -> Result<String, SqlError> {
+    let statement = parse_statement_with_dialect(sql, dialect)?;
+    Ok(serde_json::to_string_pretty(&statement).expect("the AST serializes to JSON"))
+}
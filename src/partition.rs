@@ -0,0 +1,509 @@
+//! Hash and range partitioning of a table across several `LSMStorage`
+//! shards.
+//!
+//! An unpartitioned table is a single shard kept directly in the table's
+//! directory. A table created with `PARTITION BY HASH(col) PARTITIONS n` has
+//! `n` shards in `shard_<i>` subdirectories, and each row lives in the shard
+//! its storage key hashes to. One created with `PARTITION BY RANGE(col)
+//! SPLIT AT (...)` has a shard per range of storage keys, so a scan of a
+//! range of keys reads only the shards it overlaps. Every shard has its own
+//! WAL, memtable and compactions, so each compaction only rewrites one
+//! shard's data. Scans read the shards in parallel, each a little ahead of
+//! the merge of their rows.
+
+#[cfg(feature = "fault-injection")]
+use crate::faults::{self, FaultPoint};
+use crate::storage::{
+    copy_table_files_since, fnv1a, BackedUpFiles, CompactionThrottle, Durability, Generation, LSMStorage, ScanIter, StorageConfig,
+    StorageEntry, StorageStats, SyncTicket, TableStatus, WriteBatch, FNV_OFFSET_BASIS,
+};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BinaryHeap, VecDeque};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// A batch spanning several shards, logged before any shard applies it so a
+// crash part way through is finished on the next open
+const BATCH_LOG: &str = "batch.log";
+
+type ShardOps = Vec<Vec<(String, Option<String>)>>;
+
+/// Rows of a `PartitionedStorage` in key order; see [`PartitionedStorage::scan`].
+pub type PartitionScan<'a> = Box<dyn Iterator<Item = io::Result<(String, String)>> + 'a>;
+
+// Rows a shard reads ahead of the merge at most. Each read-ahead doubles the
+// last, starting from one row, so a LIMIT still reads little more than the
+// rows it returns while a long scan reads every shard in parallel.
+const MAX_READ_AHEAD: usize = 256;
+
+// The shards' scans, each in key order, merged into one: the heap holds the
+// next row of every shard that has one left, and each shard's rows after it
+// are read ahead into `buffered`.
+struct MergedScan<'a> {
+    scans: Vec<ScanIter<'a>>,
+    buffered: Vec<VecDeque<(String, String)>>,
+    exhausted: Vec<bool>,
+    heads: BinaryHeap<ShardHead>,
+    reverse: bool,
+    read_ahead: usize,
+    // The shard whose row was returned last, advanced only when the next row
+    // is asked for
+    pending: Option<usize>,
+}
+
+// A shard's next row, ordered so the heap pops the row that comes first in
+// the scan's direction. A key lives in one shard only, so keys never tie.
+struct ShardHead {
+    row: (String, String),
+    shard: usize,
+    reverse: bool,
+}
+
+impl Ord for ShardHead {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let order = self.row.0.cmp(&other.row.0).then(self.shard.cmp(&other.shard));
+        if self.reverse { order } else { order.reverse() }
+    }
+}
+
+impl PartialOrd for ShardHead {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for ShardHead {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for ShardHead {}
+
+impl<'a> MergedScan<'a> {
+    fn new(scans: Vec<ScanIter<'a>>, reverse: bool) -> io::Result<Self> {
+        let shards = scans.len();
+        let mut merged = Self {
+            scans,
+            buffered: vec![VecDeque::new(); shards],
+            exhausted: vec![false; shards],
+            heads: BinaryHeap::new(),
+            reverse,
+            read_ahead: 1,
+            pending: None,
+        };
+        merged.read_ahead()?;
+        for shard in 0..shards {
+            merged.advance(shard)?;
+        }
+        Ok(merged)
+    }
+
+    fn advance(&mut self, shard: usize) -> io::Result<()> {
+        if self.buffered[shard].is_empty() && !self.exhausted[shard] {
+            self.read_ahead()?;
+        }
+        if let Some(row) = self.buffered[shard].pop_front() {
+            self.heads.push(ShardHead { row, shard, reverse: self.reverse });
+        }
+        Ok(())
+    }
+
+    // Tops up, in parallel, every shard that has read less than half of the
+    // read-ahead, then doubles it
+    fn read_ahead(&mut self) -> io::Result<()> {
+        let read_ahead = self.read_ahead;
+        let mut shards: Vec<_> = self.scans.iter_mut()
+            .zip(&mut self.buffered)
+            .zip(&mut self.exhausted)
+            .filter(|((_, buffered), exhausted)| !**exhausted && buffered.len() * 2 < read_ahead.max(2))
+            .map(|((scan, buffered), exhausted)| (scan, buffered, exhausted))
+            .collect();
+        let fill = |(scan, buffered, exhausted): &mut (&mut ScanIter<'a>, &mut VecDeque<(String, String)>, &mut bool)| {
+            while buffered.len() < read_ahead {
+                match scan.next().transpose()? {
+                    Some(row) => buffered.push_back(row),
+                    None => {
+                        **exhausted = true;
+                        break;
+                    }
+                }
+            }
+            Ok(())
+        };
+        let result = match shards.as_mut_slice() {
+            [] => Ok(()),
+            [shard] => fill(shard),
+            shards => thread::scope(|scope| {
+                let reads: Vec<_> = shards.iter_mut().map(|shard| scope.spawn(|| fill(shard))).collect();
+                reads.into_iter().try_for_each(|read| read.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+            }),
+        };
+        self.read_ahead = (read_ahead * 2).min(MAX_READ_AHEAD);
+        result
+    }
+}
+
+impl Iterator for MergedScan<'_> {
+    type Item = io::Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(shard) = self.pending.take() {
+            if let Err(e) = self.advance(shard) {
+                self.heads.clear();
+                return Some(Err(e));
+            }
+        }
+        let head = self.heads.pop()?;
+        self.pending = Some(head.shard);
+        Some(Ok(head.row))
+    }
+}
+
+/// How a partitioned table's rows are spread over its shards.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Partitioning {
+    /// Over this many shards, by a hash of the storage key.
+    Hash(usize),
+    /// By storage key: the first shard holds the keys below the first of
+    /// these, and each later shard those from its own up to the next.
+    Range(Vec<String>),
+}
+
+impl Partitioning {
+    pub fn partitions(&self) -> usize {
+        match self {
+            Partitioning::Hash(partitions) => *partitions,
+            Partitioning::Range(split_at) => split_at.len() + 1,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PartitionedStorage {
+    dir: PathBuf,
+    shards: Vec<LSMStorage>,
+    // Where the range shards after the first begin; empty if hashed
+    split_at: Vec<String>,
+    // Set when a batch spanning shards failed part way: some shards hold
+    // their part and some don't, so the table refuses reads and writes until
+    // it's reopened, which finishes the batch from its log
+    unfinished_batch: bool,
+}
+
+fn split_at(partitioning: Option<&Partitioning>) -> Vec<String> {
+    match partitioning {
+        Some(Partitioning::Range(split_at)) => split_at.clone(),
+        _ => Vec::new(),
+    }
+}
+
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+fn shard_name(shard: usize) -> String {
+    format!("shard_{}", shard)
+}
+
+impl PartitionedStorage {
+    /// Opens table `table_name` of `data_dir`, partitioned as
+    /// `partitioning` or, for `None`, unpartitioned.
+    pub fn open(data_dir: &Path, table_name: &str, partitioning: Option<&Partitioning>) -> io::Result<Self> {
+        let dir = data_dir.join(table_name);
+        let shards = match partitioning.map(Partitioning::partitions) {
+            None => vec![LSMStorage::new(data_dir, table_name)?],
+            Some(n) => (0..n).map(|i| LSMStorage::new(&dir, &shard_name(i))).collect::<io::Result<_>>()?,
+        };
+        let mut storage = Self { dir, shards, split_at: split_at(partitioning), unfinished_batch: false };
+        storage.recover_batch_log()?;
+        Ok(storage)
+    }
+
+    /// Opens the table without writing to it; see `LSMStorage::open_read_only`.
+    /// A batch a writer is part way through applying may be partly visible.
+    pub fn open_read_only(data_dir: &Path, table_name: &str, partitioning: Option<&Partitioning>) -> io::Result<Self> {
+        let dir = data_dir.join(table_name);
+        let shards = match partitioning.map(Partitioning::partitions) {
+            None => vec![LSMStorage::open_read_only(data_dir, table_name)?],
+            Some(n) => (0..n).map(|i| LSMStorage::open_read_only(&dir, &shard_name(i))).collect::<io::Result<_>>()?,
+        };
+        Ok(Self { dir, shards, split_at: split_at(partitioning), unfinished_batch: false })
+    }
+
+    /// The generation of every shard; see `LSMStorage::generation`.
+    pub fn generation(data_dir: &Path, table_name: &str, partitions: Option<usize>) -> Vec<Generation> {
+        let dir = data_dir.join(table_name);
+        match partitions {
+            None => vec![LSMStorage::generation(&dir)],
+            Some(n) => (0..n).map(|i| LSMStorage::generation(&dir.join(shard_name(i)))).collect(),
+        }
+    }
+
+    /// Copies the files of a closed table, shards included; see
+    /// `copy_table_files`.
+    pub fn copy_files(table_dir: &Path, dest: &Path, partitions: Option<usize>) -> io::Result<()> {
+        Self::copy_files_since(table_dir, dest, partitions, &BTreeMap::new()).map(|_| ())
+    }
+
+    /// Like `copy_files`, leaving out the SSTables `since` holds unchanged;
+    /// see `copy_table_files_since`. `since` and the result list the
+    /// SSTables of each directory, by its path within the table's ("" for
+    /// the table's own).
+    pub fn copy_files_since(table_dir: &Path, dest: &Path, partitions: Option<usize>,
+                            since: &BTreeMap<String, BackedUpFiles>) -> io::Result<BTreeMap<String, BackedUpFiles>> {
+        let dirs = std::iter::once(String::new()).chain((0..partitions.unwrap_or(0)).map(shard_name));
+        let mut copied = BTreeMap::new();
+        for dir in dirs {
+            let since = since.get(&dir).cloned().unwrap_or_default();
+            let sstables = copy_table_files_since(&table_dir.join(&dir), &dest.join(&dir), &since)?;
+            copied.insert(dir, sstables);
+        }
+        Ok(copied)
+    }
+
+    /// Restores every shard of a table; see `LSMStorage::restore_to`.
+    pub fn restore_to(data_dir: &Path, table_name: &str, partitions: Option<usize>,
+                      dest_dir: &Path, timestamp: u64) -> io::Result<()> {
+        match partitions {
+            None => LSMStorage::restore_to(data_dir, table_name, dest_dir, timestamp),
+            Some(n) => (0..n).try_for_each(|i| {
+                LSMStorage::restore_to(&data_dir.join(table_name), &shard_name(i), &dest_dir.join(table_name), timestamp)
+            }),
+        }
+    }
+
+    /// The live key/value pairs of every shard as they were at `timestamp`,
+    /// in key order; see `LSMStorage::scan_as_of` for the history kept.
+    pub fn scan_as_of(&self, timestamp: u64) -> io::Result<Vec<(String, String)>> {
+        let mut rows = Vec::new();
+        for shard in &self.shards {
+            rows.extend(shard.scan_as_of(timestamp)?);
+        }
+        rows.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(rows)
+    }
+
+    pub fn partitions(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn shard_for(&self, key: &str) -> usize {
+        if !self.split_at.is_empty() {
+            return self.split_at.partition_point(|split| split.as_str() <= key);
+        }
+        (fnv1a(key.as_bytes(), FNV_OFFSET_BASIS) % self.shards.len() as u64) as usize
+    }
+
+    // Whether any key within `range` can live in `shard`; only a range
+    // shard's keys are bounded
+    fn shard_overlaps(&self, shard: usize, range: (Bound<&str>, Bound<&str>)) -> bool {
+        if self.split_at.is_empty() {
+            return true;
+        }
+        let below_start = match (shard.checked_sub(1).map(|i| self.split_at[i].as_str()), range.1) {
+            (Some(start), Bound::Included(end)) => end < start,
+            (Some(start), Bound::Excluded(end)) => end <= start,
+            _ => false,
+        };
+        let from_end = match (self.split_at.get(shard).map(String::as_str), range.0) {
+            (Some(end), Bound::Included(start) | Bound::Excluded(start)) => start >= end,
+            _ => false,
+        };
+        !below_start && !from_end
+    }
+
+    fn check_finished(&self) -> io::Result<()> {
+        if self.unfinished_batch {
+            return Err(io::Error::other(format!(
+                "a write to {} failed part way across its shards; reopen the table to finish it", self.dir.display())));
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, key: &str) -> io::Result<Option<String>> {
+        self.check_finished()?;
+        self.shards[self.shard_for(key)].get(key)
+    }
+
+    /// Live rows within `range`, in descending key order if `reverse`,
+    /// streamed. Each shard scans only `range`, and the shards' rows are
+    /// merged as they are read, so a LIMIT reads little more than the rows it
+    /// returns.
+    pub fn scan<'a>(&'a self, range: (Bound<&str>, Bound<&str>), reverse: bool) -> io::Result<PartitionScan<'a>> {
+        self.check_finished()?;
+        let mut scans = self.shards.iter().enumerate()
+            .filter(|&(i, _)| self.shard_overlaps(i, range))
+            .map(|(_, shard)| if reverse { shard.scan_rev(range) } else { shard.scan(range) })
+            .collect::<io::Result<Vec<_>>>()?;
+        if scans.len() <= 1 {
+            return Ok(Box::new(scans.pop().into_iter().flatten()));
+        }
+        Ok(Box::new(MergedScan::new(scans, reverse)?))
+    }
+
+    /// Applies a batch atomically; see `LSMStorage::write_deferred`. A batch
+    /// touching several shards is logged first and made durable before this
+    /// returns, so it never yields a ticket. If a shard fails to take its
+    /// part, the table is unusable until reopened, and reopening it finishes
+    /// the batch.
+    pub fn write_deferred(&mut self, batch: WriteBatch) -> io::Result<Option<SyncTicket>> {
+        self.check_finished()?;
+        if let [shard] = self.shards.as_mut_slice() {
+            return shard.write_deferred(batch);
+        }
+
+        let mut by_shard: ShardOps = vec![Vec::new(); self.shards.len()];
+        for (key, value) in batch {
+            by_shard[self.shard_for(&key)].push((key, value));
+        }
+        let mut touched = by_shard.iter().enumerate().filter(|(_, ops)| !ops.is_empty()).map(|(i, _)| i);
+        match (touched.next(), touched.next()) {
+            (None, _) => return Ok(None),
+            (Some(shard), None) => {
+                let ops = std::mem::take(&mut by_shard[shard]);
+                return self.shards[shard].write_deferred(WriteBatch::from(ops));
+            }
+            _ => {}
+        }
+
+        let sync = self.durability() != Durability::OnClose;
+        self.log_batch(&by_shard, sync)?;
+        let result = self.apply_shard_ops(by_shard, sync);
+        self.unfinished_batch = result.is_err();
+        result
+    }
+
+    // Writes the batch log whole under its name, so recovery never reads
+    // one cut short, and makes it durable before any shard is written if
+    // `sync`
+    fn log_batch(&self, by_shard: &ShardOps, sync: bool) -> io::Result<()> {
+        let record = serde_json::to_vec(by_shard).map_err(io::Error::other)?;
+        let tmp_path = self.dir.join(format!("{}.tmp", BATCH_LOG));
+        let mut log = File::create(&tmp_path)?;
+        log.write_all(&record)?;
+        if sync {
+            log.sync_all()?;
+        }
+        fs::rename(&tmp_path, self.dir.join(BATCH_LOG))?;
+        if sync {
+            sync_dir(&self.dir)?;
+        }
+        Ok(())
+    }
+
+    // Writes each shard's part of a logged batch, syncs the shards that were
+    // written if asked to, and then drops the log.
+    fn apply_shard_ops(&mut self, by_shard: ShardOps, sync: bool) -> io::Result<Option<SyncTicket>> {
+        for (shard, ops) in self.shards.iter_mut().zip(by_shard) {
+            if ops.is_empty() {
+                continue;
+            }
+            let ticket = shard.write_deferred(WriteBatch::from(ops))?;
+            match ticket {
+                Some(ticket) => ticket.wait()?,
+                None if sync => shard.sync_wal()?,
+                None => {}
+            }
+            #[cfg(feature = "fault-injection")]
+            faults::check(&self.dir, FaultPoint::ShardBatch)?;
+        }
+        // Once a later write has changed the batch's keys, replaying it
+        // would undo that, so the log has to be durably gone first, whatever
+        // the durability
+        fs::remove_file(self.dir.join(BATCH_LOG))?;
+        sync_dir(&self.dir)?;
+        Ok(None)
+    }
+
+    fn recover_batch_log(&mut self) -> io::Result<()> {
+        let record = match fs::read(self.dir.join(BATCH_LOG)) {
+            Ok(record) => record,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        // A log that doesn't parse was never applied to any shard
+        match serde_json::from_slice::<ShardOps>(&record) {
+            Ok(by_shard) if by_shard.len() == self.shards.len() => {
+                self.apply_shard_ops(by_shard, true)?;
+            }
+            _ => fs::remove_file(self.dir.join(BATCH_LOG))?,
+        }
+        Ok(())
+    }
+
+    /// I/O counters summed over the shards.
+    pub fn stats(&self) -> StorageStats {
+        let mut stats = StorageStats::default();
+        self.shards.iter().for_each(|shard| stats.add(&shard.stats()));
+        stats
+    }
+
+    /// Every shard's status, totalled; see `LSMStorage::status`.
+    pub fn status(&self) -> io::Result<TableStatus> {
+        let mut status = TableStatus::default();
+        for shard in &self.shards {
+            status.add(&shard.status()?);
+        }
+        Ok(status)
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.shards[0].durability()
+    }
+
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.shards.iter_mut().for_each(|shard| shard.set_durability(durability));
+    }
+
+    pub fn storage_config(&self) -> StorageConfig {
+        self.shards[0].storage_config()
+    }
+
+    /// Applies to each shard on its own, so a table with `n` shards can hold
+    /// up to `n` full memtables.
+    pub fn set_storage_config(&mut self, config: StorageConfig) {
+        self.shards.iter_mut().for_each(|shard| shard.set_storage_config(config));
+    }
+
+    pub fn set_compaction_throttle(&mut self, throttle: Option<Arc<CompactionThrottle>>) {
+        self.shards.iter_mut().for_each(|shard| shard.set_compaction_throttle(throttle.clone()));
+    }
+
+    pub fn set_wal_archiving(&mut self, archive: bool) -> io::Result<()> {
+        self.shards.iter_mut().try_for_each(|shard| shard.set_wal_archiving(archive))
+    }
+
+    pub fn set_wal_retention(&mut self, retention: Option<Duration>) {
+        for shard in &mut self.shards {
+            shard.set_wal_retention(retention);
+        }
+    }
+
+    /// Sends `listener` the batches every shard logs; a batch that spans
+    /// shards arrives as one part per shard.
+    pub fn subscribe_wal(&mut self, listener: Sender<Vec<StorageEntry>>) {
+        for shard in &mut self.shards {
+            shard.subscribe_wal(listener.clone());
+        }
+    }
+
+    /// Compacts every shard; see `LSMStorage::compact`.
+    pub fn compact(&mut self) -> io::Result<u64> {
+        self.shards.iter_mut().map(LSMStorage::compact).sum()
+    }
+
+    pub fn close(&mut self) -> io::Result<()> {
+        self.shards.iter_mut().try_for_each(LSMStorage::close)
+    }
+}
@@ -1,444 +1,1927 @@
 use crate::ast::{
     SQLStatement, SelectStatement, InsertStatement, UpdateStatement, DeleteStatement,
     CreateTableStatement, AlterTableStatement, DropTableStatement, AlterAction,
-    OrderByClause, WhereClause, ColumnExpr, HavingClause, JoinClause, JoinType,
+    WhereClause, ColumnExpr, ConflictAction, HavingClause, InList, JoinClause, JoinType,
+    TransactionStatement, ShowStatement, Hint, ExplainStatement, ExplainFormat, FunctionCall, MathFunction, SetValue,
+    TableFunction, NULL,
 };
-use crate::storage::{LSMStorage, StorageValue};
-use std::collections::HashMap;
-use std::path::PathBuf;
-use std::fs;
+use crate::catalog::{Catalog, ColumnDef, ColumnStats, TableDef, TableStats, CATALOG_DIR};
+use crate::changes::{change_event, ChangeEvent, ChangeHooks, ChangeKind, ChangeStream};
+use crate::blob::{self, Blob};
+use crate::csv::CsvReader;
+use crate::decimal::{Decimal, DecimalType};
+use crate::executor::{check_grouping, compare_values, has_aggregates, resolve_alias, with_subquery_run};
+use crate::format::quote_name;
+use crate::join::{assigned_column, is_hash_join, join_rows, joined_matches, joined_side, qualify_columns, unqualified_where};
+use crate::migrations::{self, VERSION_FILE};
+use crate::parser::{parse_insert_stream, parse_sql, InsertStream};
+use crate::math;
+use crate::returning::{returned_rows, Image};
+use crate::locks::{LockMode, LockSet, ReadSet, TableLocks, WriteVersions};
+use crate::partition::{PartitionedStorage, Partitioning};
+use crate::plan::PlanNode;
+use crate::output::{ColumnMeta, ResultSet, RowSink, StatementResult, ValueType};
+use crate::rewrite::{RewriteRule, RewriteRules};
+use crate::row::{parse_select, Row};
+use crate::script::{OnError, ScriptError, ScriptProgress, ScriptSummary, StatementReader};
+use crate::slow_query::{self, SlowQuery, SlowQueryLog};
+use crate::storage::{
+    BackedUpFiles, CompactionThrottle, Durability, Generation, LSMStorage, StorageConfig, StorageStats, SyncTicket, TableStatus, WriteBatch,
+};
+use crate::tokenizer::tokenize;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex, PoisonError, RwLock};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use serde_json;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Database-level log holding the write set of a transaction while it is being
+// applied to the individual tables.
+const COMMIT_LOG: &str = "commit.log";
+/// The file in a backup listing the SSTables it holds, which a later
+/// `backup_incremental` leaves out.
+pub const BACKUP_MANIFEST: &str = "BACKUP_MANIFEST";
+
+// The SSTables of a backup: of each table, then of each of its directories
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct BackupManifest {
+    tables: BTreeMap<String, BTreeMap<String, BackedUpFiles>>,
+}
+const MAX_PARTITIONS: usize = 256;
+const DUMP_ROWS_PER_INSERT: usize = 100;
+const IMPORT_ROWS_PER_BATCH: usize = 1000;
+// AVG of a DECIMAL column keeps at least this many digits after the point
+const MIN_DECIMAL_AVG_SCALE: u32 = 6;
 
+type TableHandle = Arc<RwLock<PartitionedStorage>>;
+type RowIter<'a> = Box<dyn Iterator<Item = Result<(String, String), String>> + 'a>;
+type Rows = Vec<HashMap<String, String>>;
+
+/// LSM-backed database. All methods take `&self`, so a handle can be shared
+/// between threads (e.g. behind an `Arc`): reads of a table run concurrently,
+/// writes to the same table are serialized by its lock.
 #[derive(Debug)]
 pub struct PersistentDatabase {
     data_dir: PathBuf,
-    tables: HashMap<String, LSMStorage>,
-    table_schemas: HashMap<String, Vec<String>>, // table_name -> column_names
+    tables: RwLock<HashMap<String, TableHandle>>,
+    catalog: RwLock<Catalog>,
+    // The open transaction of each session, by its id; see `in_session`
+    transactions: Mutex<HashMap<u64, Transaction>>,
+    table_locks: TableLocks,
+    next_session: AtomicU64,
+    // Commits share the commit log, so they are applied one at a time
+    commit_lock: Mutex<()>,
+    // What optimistic transactions are validated against at COMMIT
+    versions: Mutex<WriteVersions>,
+    row_seq: AtomicU64,
+    options: DatabaseOptions,
+    compaction_throttle: Option<Arc<CompactionThrottle>>,
+    slow_query_log: RwLock<Option<SlowQueryLog>>,
+    change_hooks: RwLock<ChangeHooks>,
+    rewrite_rules: RwLock<RewriteRules>,
+    // What a read-only database last loaded; see `refresh`
+    loaded: Mutex<LoadedGenerations>,
+}
+
+#[derive(Debug, Default)]
+struct LoadedGenerations {
+    catalog: Option<Generation>,
+    tables: HashMap<String, Vec<Generation>>,
+}
+
+/// Storage settings applied to every table of a `PersistentDatabase`.
+#[derive(Debug, Clone, Default)]
+pub struct DatabaseOptions {
+    pub durability: Durability,
+    /// Keep cleared WAL segments for `PersistentDatabase::restore_to`.
+    pub archive_wal: bool,
+    /// How far back the WAL archive keeps history for AS OF and
+    /// `restore_to`; older segments are deleted. `None` keeps it all.
+    pub wal_retention: Option<Duration>,
+    /// Size limits of tables created without their own.
+    pub storage: StorageConfig,
+    /// See `PersistentDatabase::open_read_only`.
+    pub read_only: bool,
+    /// Combined read and write rate of all tables' compactions.
+    pub compaction_bytes_per_sec: Option<u64>,
+    /// How many tables may compact at the same time; 0 is no limit.
+    pub max_concurrent_compactions: Option<usize>,
+}
+
+fn lock_err<T>(_: PoisonError<T>) -> String {
+    "Database lock poisoned".to_string()
+}
+
+fn wait_durable(ticket: Option<SyncTicket>) -> Result<(), String> {
+    match ticket {
+        Some(ticket) => ticket.wait().map_err(|e| format!("Failed to sync write-ahead log: {}", e)),
+        None => Ok(()),
+    }
+}
+
+thread_local! {
+    // Rows changed by the last INSERT, UPDATE or DELETE run on this thread,
+    // and the key of the last row inserted
+    static LAST_WRITE: RefCell<(usize, Option<String>)> = const { RefCell::new((0, None)) };
+    // The session statements run on this thread belong to; 0, the
+    // database's own, unless run through `in_session`
+    static SESSION: Cell<u64> = const { Cell::new(0) };
+    // How long the last statement run on this thread spent planning
+    static PLAN_TIME: Cell<Duration> = const { Cell::new(Duration::ZERO) };
+}
+
+fn current_session() -> u64 {
+    SESSION.with(Cell::get)
+}
+
+// Runs a planning step of a statement, adding its time to `last_plan_time`
+fn planned<T>(plan: impl FnOnce() -> T) -> T {
+    let started = Instant::now();
+    let planned = plan();
+    PLAN_TIME.with(|time| time.set(time.get() + started.elapsed()));
+    planned
+}
+
+fn start_planning() {
+    PLAN_TIME.with(|time| time.set(Duration::ZERO));
+}
+
+// Records a statement's changes for `rows_affected` and `last_insert_rowid`;
+// a statement that inserted nothing keeps the last inserted key.
+fn record_write(rows_affected: usize, last_key: Option<String>) {
+    LAST_WRITE.with(|last| {
+        let mut last = last.borrow_mut();
+        last.0 = rows_affected;
+        if last_key.is_some() {
+            last.1 = last_key;
+        }
+    });
+}
+
+// Writes made inside an open transaction. They are buffered here and only
+// reach table storage on COMMIT, when the changes they made are passed to the
+// change hooks; a savepoint is a position in both logs.
+#[derive(Debug, Default)]
+struct Transaction {
+    writes: Vec<PendingWrite>,
+    changes: Vec<ChangeEvent>,
+    savepoints: Vec<Savepoint>,
+    // What an optimistic transaction has read; None for one that locks
+    reads: Option<ReadSet>,
+}
+
+#[derive(Debug)]
+struct Savepoint {
+    name: String,
+    writes: usize,
+    changes: usize,
+}
+
+// A write made outside a transaction, to be waited on once the table lock is
+// released; its change then goes to the change hooks.
+struct Written {
+    ticket: Option<SyncTicket>,
+    change: Option<ChangeEvent>,
+}
+
+// How a SELECT reads its table: only the keys a primary key predicate
+// selects, in key order for ORDER BY the primary key, both, or neither
+#[derive(Debug, Default)]
+struct Access {
+    lookup: Option<KeyLookup>,
+    // Descending if true
+    key_order: Option<bool>,
+}
+
+#[derive(Debug)]
+enum KeyLookup {
+    // None for a value that can't be a key (e.g. 'abc' for an INT key),
+    // which matches nothing
+    Point(Option<String>),
+    Range(String, String),
+}
+
+// What an UPDATE or DELETE tests its table's rows against: the WHERE, with
+// any IN subquery already run, and over a JOIN the joined table's rows. Both
+// are read before the table is locked for writing.
+struct WriteFilter {
+    join: Option<(JoinClause, Rows)>,
+    where_clause: Option<WhereClause>,
+}
+
+impl WriteFilter {
+    // Whether each of `rows` is to be changed
+    fn matches(&self, rows: &[HashMap<String, String>], table_name: &str, types: &ColumnTypes) -> Result<Vec<bool>, String> {
+        let meets = |row: &HashMap<String, String>| match &self.where_clause {
+            Some(where_clause) => PersistentDatabase::evaluate_where_condition(row, where_clause, types),
+            None => Ok(true),
+        };
+        match &self.join {
+            Some((join, right_rows)) => joined_matches(rows, right_rows, join, table_name, meets),
+            None => rows.iter().map(meets).collect(),
+        }
+    }
+}
+
+// A table's columns whose declared type changes how their values are stored
+// and compared.
+#[derive(Debug, Default)]
+struct ColumnTypes {
+    decimals: HashMap<String, DecimalType>,
+    blobs: HashSet<String>,
+    booleans: HashSet<String>,
+}
+
+impl ColumnTypes {
+    // A value as its column stores it: DECIMALs rounded to their scale, BLOBs
+    // as `X'...'` literals and BOOLEANs as true or false.
+    fn stored_value(&self, column: &str, value: &str) -> Result<String, String> {
+        let invalid = |e: String| format!("Invalid value for column '{}': {}", column, e);
+        if let Some(decimal) = self.decimals.get(column) {
+            decimal.fit(value).map_err(invalid)
+        } else if self.blobs.contains(column) {
+            Ok(blob::to_blob(value))
+        } else if self.booleans.contains(column) {
+            convert_value(value, "BOOLEAN").map_err(invalid)
+        } else {
+            Ok(value.to_string())
+        }
+    }
+}
+
+// A table's primary key column. Rows are stored under the key's value,
+// encoded so that storage key order matches the column's value order.
+#[derive(Debug, Clone)]
+struct PrimaryKey {
+    column: String,
+    data_type: String,
+}
+
+impl PrimaryKey {
+    fn is_integer(&self) -> bool {
+        matches!(self.data_type.to_uppercase().as_str(), "INT" | "INTEGER" | "BIGINT")
+    }
+
+    fn storage_key(&self, value: &str) -> Result<String, String> {
+        if value == NULL {
+            return Err(format!("Primary key '{}' can't be NULL", self.column));
+        }
+        if !self.is_integer() {
+            return Ok(value.to_string());
+        }
+        let n: i64 = value.trim().parse()
+            .map_err(|_| format!("Invalid integer '{}' for primary key '{}'", value, self.column))?;
+        // Flipping the sign bit makes the zero-padded unsigned form sort like i64
+        Ok(format!("{:020}", (n as u64) ^ (1 << 63)))
+    }
+}
+
+/// How a table's rows are spread over its shards, with the values a range
+/// partitioned table is split at encoded as storage keys.
+pub(crate) fn partitioning(table: &TableDef) -> Result<Option<Partitioning>, String> {
+    let Some(partitions) = table.partitions else {
+        return Ok(None);
+    };
+    if table.split_at.is_empty() {
+        return Ok(Some(Partitioning::Hash(partitions)));
+    }
+    let primary_key = table.primary_key.as_ref()
+        .and_then(|column| table.column(column))
+        .map(|c| PrimaryKey { column: c.name.clone(), data_type: c.data_type.clone() })
+        .ok_or_else(|| format!("Table '{}' is split by range without a primary key", table.name))?;
+    let bounds = table.split_at.iter()
+        .map(|value| primary_key.storage_key(value))
+        .collect::<Result<_, _>>()?;
+    Ok(Some(Partitioning::Range(bounds)))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PendingWrite {
+    table: String,
+    key: String,
+    value: Option<String>, // None = delete
 }
 
 impl PersistentDatabase {
     pub fn new(data_dir: &str) -> Result<Self, String> {
+        Self::with_options(data_dir, DatabaseOptions::default())
+    }
+
+    /// Opens the database with the given WAL durability mode for its tables.
+    pub fn with_durability(data_dir: &str, durability: Durability) -> Result<Self, String> {
+        Self::with_options(data_dir, DatabaseOptions { durability, ..DatabaseOptions::default() })
+    }
+
+    /// Opens an existing database for queries only. Statements that write
+    /// are rejected and nothing on disk is changed, so it can run next to a
+    /// process that has the database open for writing; each SELECT sees that
+    /// process's writes as of when it starts. A commit still being applied by
+    /// the writer may be seen partially.
+    pub fn open_read_only(data_dir: &str) -> Result<Self, String> {
+        Self::with_options(data_dir, DatabaseOptions { read_only: true, ..DatabaseOptions::default() })
+    }
+
+    pub fn with_options(data_dir: &str, options: DatabaseOptions) -> Result<Self, String> {
         let data_path = PathBuf::from(data_dir);
+        if options.read_only {
+            migrations::check_current(&data_path)?;
+            let catalog_generation = LSMStorage::generation(&data_path.join(CATALOG_DIR));
+            let catalog = Catalog::open_read_only(&data_path)?;
+            return Ok(Self {
+                data_dir: data_path,
+                tables: RwLock::new(HashMap::new()),
+                catalog: RwLock::new(catalog),
+                transactions: Mutex::new(HashMap::new()),
+                table_locks: TableLocks::default(),
+                next_session: AtomicU64::new(1),
+                commit_lock: Mutex::new(()),
+                versions: Mutex::new(WriteVersions::default()),
+                row_seq: AtomicU64::new(0),
+                options,
+                compaction_throttle: None,
+                slow_query_log: RwLock::new(None),
+                change_hooks: RwLock::new(ChangeHooks::default()),
+                rewrite_rules: RwLock::new(RewriteRules::default()),
+                loaded: Mutex::new(LoadedGenerations { catalog: Some(catalog_generation), tables: HashMap::new() }),
+            });
+        }
         fs::create_dir_all(&data_path).map_err(|e| format!("Failed to create data directory: {}", e))?;
+        migrations::migrate(&data_path)?;
         
-        let mut db = Self {
+        // Only the catalog is read here; table storage is opened on first use
+        let mut catalog = Catalog::open(&data_path)?;
+        catalog.set_wal_archiving(options.archive_wal)?;
+        catalog.set_wal_retention(options.wal_retention);
+        let compaction_throttle = match (options.compaction_bytes_per_sec, options.max_concurrent_compactions) {
+            (None, None) => None,
+            (rate, jobs) => Some(Arc::new(CompactionThrottle::new(rate, jobs))),
+        };
+        let db = Self {
             data_dir: data_path,
-            tables: HashMap::new(),
-            table_schemas: HashMap::new(),
+            tables: RwLock::new(HashMap::new()),
+            catalog: RwLock::new(catalog),
+            transactions: Mutex::new(HashMap::new()),
+            table_locks: TableLocks::default(),
+            next_session: AtomicU64::new(1),
+            commit_lock: Mutex::new(()),
+            versions: Mutex::new(WriteVersions::default()),
+            row_seq: AtomicU64::new(0),
+            options,
+            compaction_throttle,
+            slow_query_log: RwLock::new(None),
+            change_hooks: RwLock::new(ChangeHooks::default()),
+            rewrite_rules: RwLock::new(RewriteRules::default()),
+            loaded: Mutex::new(LoadedGenerations::default()),
         };
-        
-        // Load existing schemas
-        db.load_schemas()?;
+
+        db.recover_commit_log()?;
         
         Ok(db)
     }
 
-    pub fn execute(&mut self, stmt: SQLStatement) -> Result<String, String> {
-        match stmt {
-            SQLStatement::Select(s)      => self.execute_select(&s),
-            SQLStatement::Insert(s)      => self.execute_insert(s),
-            SQLStatement::Update(s)      => self.execute_update(s),
-            SQLStatement::Delete(s)      => self.execute_delete(s),
+    pub fn execute(&self, stmt: SQLStatement) -> Result<StatementResult, String> {
+        start_planning();
+        let stmt = planned(|| self.rewrite_rules.read().map_err(lock_err)?.apply(stmt))?;
+        let sql = self.slow_query_threshold()?.map(|_| stmt.to_string());
+        self.log_if_slow(sql, || self.locked(&stmt_locks(&stmt), || self.execute_unlogged(stmt)))
+    }
+
+    /// A new session id for `in_session`.
+    pub(crate) fn new_session(&self) -> u64 {
+        self.next_session.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Runs `run` on this thread as `session`, which has a transaction and
+    /// table locks of its own.
+    pub(crate) fn in_session<T>(&self, session: u64, run: impl FnOnce() -> T) -> T {
+        let outer = SESSION.with(|current| current.replace(session));
+        let result = run();
+        SESSION.with(|current| current.set(outer));
+        result
+    }
+
+    // Runs a statement holding `locks`. Outside a transaction they are only
+    // held for the statement; a transaction that would deadlock waiting for
+    // them is rolled back.
+    fn locked<T>(&self, locks: &LockSet, run: impl FnOnce() -> Result<T, String>) -> Result<T, String> {
+        let session = current_session();
+        let optimistic = self.transactions.lock().map_err(lock_err)?
+            .get(&session).is_some_and(|tx| tx.reads.is_some());
+        if optimistic {
+            return run();
+        }
+        if let Err(e) = self.table_locks.acquire(session, locks) {
+            self.transactions.lock().map_err(lock_err)?.remove(&session);
+            self.table_locks.release_all(session);
+            return Err(e);
+        }
+        let result = run();
+        if !self.in_transaction() {
+            self.table_locks.release_all(session);
+        }
+        result
+    }
+
+    fn execute_unlogged(&self, stmt: SQLStatement) -> Result<StatementResult, String> {
+        match &stmt {
+            SQLStatement::Select(select) if self.options.read_only => {
+                self.execute_read_only(|| self.select(select)).map(StatementResult::Rows)
+            }
+            SQLStatement::Select(_) | SQLStatement::Show(_) | SQLStatement::Explain(_) => self.execute_statement(stmt),
+            _ => {
+                self.check_writable()?;
+                self.atomically(|| self.execute_statement(stmt))
+            }
+        }
+    }
+
+    // Runs a statement so that it takes effect whole or not at all. Outside
+    // a transaction each statement writes a single batch; inside one, a
+    // statement that fails has the writes it added dropped again, as if
+    // rolled back to a savepoint taken before it, and the transaction goes
+    // on without them.
+    fn atomically<T>(&self, run: impl FnOnce() -> Result<T, String>) -> Result<T, String> {
+        let session = current_session();
+        let mark = self.transactions.lock().map_err(lock_err)?
+            .get(&session).map(|tx| (tx.writes.len(), tx.changes.len()));
+        let result = run();
+        if let (Err(_), Some((writes, changes))) = (&result, mark) {
+            if let Some(tx) = self.transactions.lock().map_err(lock_err)?.get_mut(&session) {
+                tx.writes.truncate(writes);
+                tx.changes.truncate(changes);
+            }
+        }
+        result
+    }
+
+    /// Runs the SELECT in `sql` and calls `f` on each row.
+    pub fn query_map<T>(&self, sql: &str, f: impl FnMut(Row<'_>) -> Result<T, String>) -> Result<Vec<T>, String> {
+        self.query(&parse_select(sql)?)?.map(f)
+    }
+
+    /// Runs the SELECT in `sql`, deserializing each row into a `T`; see
+    /// `ResultSet::deserialize`.
+    pub fn query_as<T: DeserializeOwned>(&self, sql: &str) -> Result<Vec<T>, String> {
+        self.query(&parse_select(sql)?)?.deserialize()
+    }
+
+    /// Runs a SELECT, returning its rows rather than formatting them.
+    pub fn query(&self, stmt: &SelectStatement) -> Result<ResultSet, String> {
+        start_planning();
+        let rewrite_rules = self.rewrite_rules.read().map_err(lock_err)?.clone();
+        let stmt = &*planned(|| rewrite_rules.apply_select(stmt))?;
+        let sql = self.slow_query_threshold()?.map(|_| SQLStatement::Select(stmt.clone()).to_string());
+        self.log_if_slow(sql, || self.locked(&select_locks(stmt), || {
+            if self.options.read_only {
+                return self.execute_read_only(|| self.select(stmt));
+            }
+            self.select(stmt)
+        }))
+    }
+
+    /// Like `query`, but hands `sink` each row as it is read rather than
+    /// collecting them first, for a SELECT of one table without a join,
+    /// grouping, aggregates, or an ORDER BY other than by the primary key.
+    /// Writes to the table wait until the last row is handed over. Other
+    /// queries, and those of a read-only database, run whole and then hand
+    /// over their rows. Returns the number of rows.
+    pub fn query_each(&self, stmt: &SelectStatement, sink: &mut dyn RowSink) -> Result<usize, String> {
+        start_planning();
+        let rewrite_rules = self.rewrite_rules.read().map_err(lock_err)?.clone();
+        let stmt = &*planned(|| rewrite_rules.apply_select(stmt))?;
+        let sql = self.slow_query_threshold()?.map(|_| SQLStatement::Select(stmt.clone()).to_string());
+        self.log_if_slow(sql, || self.locked(&select_locks(stmt), || {
+            if self.options.read_only {
+                return send_rows(self.execute_read_only(|| self.select(stmt))?, sink);
+            }
+            self.select_each(stmt, sink)
+        }))
+    }
+
+    /// Runs `rule` on every statement from then on, before it is executed.
+    pub fn add_rewrite_rule(&self, rule: impl RewriteRule + 'static) -> Result<(), String> {
+        self.rewrite_rules.write().map_err(lock_err)?.add(rule);
+        Ok(())
+    }
+
+    /// Calls `log` with each statement that takes at least `threshold` from
+    /// then on, replacing any earlier slow-query log.
+    pub fn log_slow_queries(&self, threshold: Duration, log: impl Fn(&SlowQuery) + Send + Sync + 'static) -> Result<(), String> {
+        *self.slow_query_log.write().map_err(lock_err)? = Some(SlowQueryLog { threshold, log: Box::new(log) });
+        Ok(())
+    }
+
+    /// Appends each statement that takes at least `threshold` to the file at
+    /// `path`, one line each: seconds since the epoch, milliseconds taken,
+    /// rows examined and returned, and the SQL.
+    pub fn log_slow_queries_to_file(&self, threshold: Duration, path: impl AsRef<Path>) -> Result<(), String> {
+        let log = slow_query::file_logger(path.as_ref())
+            .map_err(|e| format!("Failed to open slow query log: {}", e))?;
+        *self.slow_query_log.write().map_err(lock_err)? = Some(SlowQueryLog { threshold, log });
+        Ok(())
+    }
+
+    pub fn stop_logging_slow_queries(&self) -> Result<(), String> {
+        *self.slow_query_log.write().map_err(lock_err)? = None;
+        Ok(())
+    }
+
+    /// Calls `hook` with the rows each INSERT, UPDATE or DELETE on `table`
+    /// changed, once they are committed: straight after the statement, or
+    /// at COMMIT for one run inside a transaction.
+    pub fn on_change(&self, table: &str, hook: impl Fn(&ChangeEvent) + Send + Sync + 'static) -> Result<(), String> {
+        self.change_hooks.write().map_err(lock_err)?.add(table, Arc::new(hook));
+        Ok(())
+    }
+
+    /// A stream of the rows written to `table` from now on, decoded from the
+    /// batches its WAL logs, e.g. to replicate it or keep an outside index of
+    /// it up to date. A transaction's writes arrive once it commits.
+    pub fn subscribe_changes(&self, table: &str) -> Result<ChangeStream, String> {
+        let table_storage = self.table(table)?;
+        let (sender, receiver) = mpsc::channel();
+        table_storage.write().map_err(lock_err)?.subscribe_wal(sender);
+        Ok(ChangeStream::new(table, receiver))
+    }
+
+    /// Removes every `on_change` hook on `table`.
+    pub fn remove_change_hooks(&self, table: &str) -> Result<(), String> {
+        self.change_hooks.write().map_err(lock_err)?.remove(table);
+        Ok(())
+    }
+
+    fn slow_query_threshold(&self) -> Result<Option<Duration>, String> {
+        Ok(self.slow_query_log.read().map_err(lock_err)?.as_ref().map(|log| log.threshold))
+    }
+
+    // Runs a statement, passing it to the slow-query log if it has the
+    // statement's `sql` and the statement took long enough
+    fn log_if_slow<T>(&self, sql: Option<String>, run: impl FnOnce() -> Result<T, String>) -> Result<T, String> {
+        let Some(sql) = sql else {
+            return run();
+        };
+        slow_query::start_statement();
+        let started = Instant::now();
+        let result = run();
+        let duration = started.elapsed();
+
+        let log = self.slow_query_log.read().map_err(lock_err)?;
+        if let Some(log) = log.as_ref().filter(|log| duration >= log.threshold) {
+            let (rows_examined, rows_returned) = slow_query::statement_rows();
+            (log.log)(&SlowQuery { sql, duration, rows_examined, rows_returned });
+        }
+        result
+    }
+
+    /// The number of rows the last INSERT, UPDATE or DELETE run on this
+    /// thread inserted, updated or deleted. It is kept per thread, so that
+    /// each thread sharing the database sees its own statements'.
+    pub fn rows_affected(&self) -> usize {
+        LAST_WRITE.with(|last| last.borrow().0)
+    }
+
+    /// The key of the last row the last INSERT run on this thread stored:
+    /// its primary key value, or for a table without a primary key the key
+    /// generated for it. None if the thread hasn't inserted a row.
+    pub fn last_insert_rowid(&self) -> Option<String> {
+        LAST_WRITE.with(|last| last.borrow().1.clone())
+    }
+
+    /// How long the last statement run on this thread took to plan: to go
+    /// through the rewrite rules and, for a SELECT, to choose between
+    /// looking up keys, reading them in order and scanning its table. Kept
+    /// per thread, like `rows_affected`.
+    pub fn last_plan_time(&self) -> Duration {
+        PLAN_TIME.with(Cell::get)
+    }
+
+    /// Whether a BEGIN is waiting for its COMMIT or ROLLBACK. Outside a
+    /// `Pool` connection or server session, the open transaction belongs to
+    /// the whole database, not to a thread.
+    pub fn in_transaction(&self) -> bool {
+        self.transactions.lock().unwrap_or_else(PoisonError::into_inner).contains_key(&current_session())
+    }
+
+    fn execute_statement(&self, stmt: SQLStatement) -> Result<StatementResult, String> {
+        let message = match stmt {
+            SQLStatement::Select(s)      => return self.select(&s).map(StatementResult::Rows),
+            SQLStatement::Insert(s)      => return self.execute_insert(s),
+            SQLStatement::Update(s)      => return self.execute_update(s),
+            SQLStatement::Delete(s)      => return self.execute_delete(s),
             SQLStatement::CreateTable(s) => self.execute_create_table(s),
             SQLStatement::AlterTable(s)  => self.execute_alter_table(s),
             SQLStatement::DropTable(s)   => self.execute_drop_table(s),
+            SQLStatement::Transaction(s) => self.execute_transaction(s),
+            SQLStatement::Show(s)        => self.execute_show(s),
+            SQLStatement::Explain(s)     => self.execute_explain(s),
+            SQLStatement::Vacuum(table)  => {
+                let reclaimed = self.vacuum(table.as_deref())?;
+                Ok(format!("Vacuum reclaimed {} bytes", reclaimed))
+            }
+            SQLStatement::Analyze(table) => {
+                let analyzed = self.analyze(table.as_deref())?;
+                Ok(format!("Analyzed {} tables", analyzed))
+            }
+            SQLStatement::SetVariable(..) => Err("SET needs a session; run it through a Session".to_string()),
+        };
+        message.map(StatementResult::Done)
+    }
+
+    // A read-only SELECT first catches up with what the writer changed since
+    // the last one. The writer's compactions can still remove an SSTable
+    // between loading and reading it, so a SELECT that fails to read a table
+    // is tried once more with everything loaded afresh.
+    fn execute_read_only<T>(&self, run: impl Fn() -> Result<T, String>) -> Result<T, String> {
+        self.refresh()?;
+        match run() {
+            Err(e) if is_storage_error(&e) => {
+                self.loaded.lock().map_err(lock_err)?.catalog = None;
+                self.refresh()?;
+                run()
+            }
+            result => result,
         }
     }
 
-    fn execute_select(&mut self, stmt: &SelectStatement) -> Result<String, String> {
-        let table_name = &stmt.table;
-        
-        // Get table storage
-        let table_storage = self.tables.get_mut(table_name)
-            .ok_or_else(|| format!("Table '{}' not found", table_name))?;
+    // Reloads the catalog, closing every table, if the writer has changed it,
+    // and otherwise closes the tables it has written to since they were
+    // opened; they are opened again as they're read
+    fn refresh(&self) -> Result<(), String> {
+        let mut tables = self.tables.write().map_err(lock_err)?;
+        let mut loaded = self.loaded.lock().map_err(lock_err)?;
+        let catalog_generation = LSMStorage::generation(&self.data_dir.join(CATALOG_DIR));
+        if loaded.catalog.as_ref() != Some(&catalog_generation) {
+            *self.catalog.write().map_err(lock_err)? = Catalog::open_read_only(&self.data_dir)?;
+            tables.clear();
+            *loaded = LoadedGenerations { catalog: Some(catalog_generation), tables: HashMap::new() };
+            return Ok(());
+        }
+        let catalog = self.catalog.read().map_err(lock_err)?;
+        tables.retain(|name, _| {
+            let partitions = catalog.table(name).and_then(|table| table.partitions);
+            loaded.tables.get(name) == Some(&PartitionedStorage::generation(&self.data_dir, name, partitions))
+        });
+        loaded.tables.retain(|name, _| tables.contains_key(name));
+        Ok(())
+    }
+
+    fn check_writable(&self) -> Result<(), String> {
+        if self.options.read_only {
+            return Err("Database is open read-only".to_string());
+        }
+        Ok(())
+    }
+
+    fn execute_transaction(&self, stmt: TransactionStatement) -> Result<String, String> {
+        let session = current_session();
+        let mut transactions = self.transactions.lock().map_err(lock_err)?;
+        match stmt {
+            TransactionStatement::Begin | TransactionStatement::BeginOptimistic => {
+                if transactions.contains_key(&session) {
+                    return Err("Transaction already in progress".to_string());
+                }
+                let reads = match stmt {
+                    TransactionStatement::BeginOptimistic => Some(self.versions.lock().map_err(lock_err)?.begin()),
+                    _ => None,
+                };
+                transactions.insert(session, Transaction { reads, ..Transaction::default() });
+                Ok("Transaction started".to_string())
+            }
+            TransactionStatement::Commit => {
+                let tx = transactions.remove(&session).ok_or("No transaction in progress")?;
+                drop(transactions);
+                let committed = match &tx.reads {
+                    Some(reads) => self.commit_optimistic(session, tx.writes, reads),
+                    None => self.commit_writes(tx.writes, None),
+                };
+                self.table_locks.release_all(session);
+                committed?;
+                let catalog = self.catalog.read().map_err(lock_err)?;
+                let changes: Vec<ChangeEvent> = tx.changes.into_iter()
+                    .filter(|change| catalog.table(&change.table).is_some())
+                    .collect();
+                drop(catalog);
+                changes.iter().try_for_each(|change| self.notify_change(change))?;
+                Ok("Transaction committed".to_string())
+            }
+            TransactionStatement::Rollback => {
+                let tx = transactions.remove(&session).ok_or("No transaction in progress")?;
+                if tx.reads.is_some() {
+                    self.versions.lock().map_err(lock_err)?.end();
+                }
+                self.table_locks.release_all(session);
+                Ok("Transaction rolled back".to_string())
+            }
+            TransactionStatement::Savepoint(name) => {
+                let tx = transactions.get_mut(&session)
+                    .ok_or("SAVEPOINT can only be used inside a transaction")?;
+                tx.savepoints.push(Savepoint { name: name.clone(), writes: tx.writes.len(), changes: tx.changes.len() });
+                Ok(format!("Savepoint '{}' created", name))
+            }
+            TransactionStatement::RollbackToSavepoint(name) => {
+                let tx = transactions.get_mut(&session).ok_or("No transaction in progress")?;
+                let pos = tx.savepoints.iter().rposition(|s| s.name == name)
+                    .ok_or_else(|| format!("Savepoint '{}' does not exist", name))?;
+                // The savepoint itself survives a rollback to it; later ones do not.
+                tx.savepoints.truncate(pos + 1);
+                tx.writes.truncate(tx.savepoints[pos].writes);
+                tx.changes.truncate(tx.savepoints[pos].changes);
+                Ok(format!("Rolled back to savepoint '{}'", name))
+            }
+            TransactionStatement::ReleaseSavepoint(name) => {
+                let tx = transactions.get_mut(&session).ok_or("No transaction in progress")?;
+                let pos = tx.savepoints.iter().rposition(|s| s.name == name)
+                    .ok_or_else(|| format!("Savepoint '{}' does not exist", name))?;
+                tx.savepoints.truncate(pos);
+                Ok(format!("Released savepoint '{}'", name))
+            }
+        }
+    }
+
+    // Commits an optimistic transaction: with the tables it writes locked,
+    // so no locking transaction is still reading them, its writes are made
+    // unless something it read was written since it began.
+    fn commit_optimistic(&self, session: u64, writes: Vec<PendingWrite>, reads: &ReadSet) -> Result<(), String> {
+        let mut locks = LockSet::default();
+        writes.iter().for_each(|write| locks.add(&write.table, LockMode::Exclusive));
+        let committed = self.table_locks.acquire(session, &locks)
+            .and_then(|()| self.commit_writes(writes, Some(reads)));
+        self.versions.lock().map_err(lock_err)?.end();
+        committed
+    }
 
-        // Get all rows from storage
-        let all_rows = table_storage.get_all()
-            .map_err(|e| format!("Storage error: {}", e))?;
+    // Makes a transaction's writes durable as one unit: the whole write set is
+    // appended to the commit log as a single record, then applied to each
+    // table as one WAL batch. A crash in between is repaired on open by
+    // redoing the record; a torn record means the commit never happened.
+    // An optimistic transaction's `reads` are validated first.
+    fn commit_writes(&self, writes: Vec<PendingWrite>, reads: Option<&ReadSet>) -> Result<(), String> {
+        let _commit = self.commit_lock.lock().map_err(lock_err)?;
+        if let Some(reads) = reads {
+            self.versions.lock().map_err(lock_err)?.validate(reads)?;
+        }
+        // Tables dropped mid-transaction have nothing left to write to
+        let catalog = self.catalog.read().map_err(lock_err)?;
+        let writes: Vec<PendingWrite> = writes.into_iter()
+            .filter(|w| catalog.table(&w.table).is_some())
+            .collect();
+        drop(catalog);
+        if writes.is_empty() {
+            return Ok(());
+        }
+
+        let log_path = self.data_dir.join(COMMIT_LOG);
+        let record = serde_json::to_string(&writes)
+            .map_err(|e| format!("Failed to serialize commit record: {}", e))?;
+        let mut log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)
+            .map_err(|e| format!("Failed to open commit log: {}", e))?;
+        writeln!(log, "{}", record).map_err(|e| format!("Failed to write commit log: {}", e))?;
+        if self.options.durability != Durability::OnClose {
+            log.sync_data().map_err(|e| format!("Failed to sync commit log: {}", e))?;
+        }
+
+        self.apply_writes(writes)?;
+
+        fs::remove_file(&log_path).map_err(|e| format!("Failed to clear commit log: {}", e))
+    }
+
+    fn apply_writes(&self, writes: Vec<PendingWrite>) -> Result<(), String> {
+        let mut by_table: BTreeMap<String, WriteBatch> = BTreeMap::new();
+        for write in writes {
+            let batch = by_table.entry(write.table).or_default();
+            match write.value {
+                Some(value) => batch.put(write.key, value),
+                None => batch.delete(write.key),
+            };
+        }
+
+        // Every table's batch is written before waiting on any sync, so the
+        // syncs of different tables overlap
+        let mut tickets = Vec::new();
+        for (table_name, batch) in by_table {
+            if let Ok(table_storage) = self.table(&table_name) {
+                let mut table_storage = table_storage.write().map_err(lock_err)?;
+                self.versions.lock().map_err(lock_err)?.record(&table_name, batch.keys());
+                tickets.push(table_storage.write_deferred(batch).map_err(|e| format!("Storage error: {}", e))?);
+            }
+        }
+        tickets.into_iter().try_for_each(wait_durable)
+    }
+
+    fn recover_commit_log(&self) -> Result<(), String> {
+        let log_path = self.data_dir.join(COMMIT_LOG);
+        if !log_path.exists() {
+            return Ok(());
+        }
+
+        let content = fs::read_to_string(&log_path)
+            .map_err(|e| format!("Failed to read commit log: {}", e))?;
+        for line in content.lines().filter(|l| !l.trim().is_empty()) {
+            let Ok(writes) = serde_json::from_str::<Vec<PendingWrite>>(line) else {
+                break;
+            };
+            self.apply_writes(writes)?;
+        }
+
+        fs::remove_file(&log_path).map_err(|e| format!("Failed to clear commit log: {}", e))
+    }
+
+    // Returns a table's storage, opening it (and replaying its WAL) the first
+    // time the table is used.
+    fn table(&self, table_name: &str) -> Result<TableHandle, String> {
+        if let Some(table_storage) = self.tables.read().map_err(lock_err)?.get(table_name) {
+            return Ok(table_storage.clone());
+        }
+
+        let mut tables = self.tables.write().map_err(lock_err)?;
+        // Another thread may have opened it while we waited for the lock
+        if let Some(table_storage) = tables.get(table_name) {
+            return Ok(table_storage.clone());
+        }
+        let (config, partitions, partitioning) = match self.catalog.read().map_err(lock_err)?.table(table_name) {
+            Some(table) => (table.storage, table.partitions, partitioning(table)?),
+            None => return Err(format!("Table '{}' not found", table_name)),
+        };
+
+        let table_storage = if self.options.read_only {
+            // Taken first, so a write made while the table opens reopens it
+            let generation = PartitionedStorage::generation(&self.data_dir, table_name, partitions);
+            let table_storage = PartitionedStorage::open_read_only(&self.data_dir, table_name, partitioning.as_ref())
+                .map_err(|e| format!("Failed to open table storage: {}", e))?;
+            self.loaded.lock().map_err(lock_err)?.tables.insert(table_name.to_string(), generation);
+            table_storage
+        } else {
+            let mut table_storage = PartitionedStorage::open(&self.data_dir, table_name, partitioning.as_ref())
+                .map_err(|e| format!("Failed to open table storage: {}", e))?;
+            self.configure_storage(&mut table_storage, config)?;
+            table_storage
+        };
+        let table_storage = Arc::new(RwLock::new(table_storage));
+        tables.insert(table_name.to_string(), table_storage.clone());
+        Ok(table_storage)
+    }
+
+    fn configure_storage(&self, table_storage: &mut PartitionedStorage, config: Option<StorageConfig>) -> Result<(), String> {
+        table_storage.set_durability(self.options.durability);
+        table_storage.set_storage_config(config.unwrap_or(self.options.storage));
+        table_storage.set_compaction_throttle(self.compaction_throttle.clone());
+        table_storage.set_wal_retention(self.options.wal_retention);
+        table_storage.set_wal_archiving(self.options.archive_wal)
+            .map_err(|e| format!("Failed to create WAL archive: {}", e))
+    }
+
+    // Every row of a table, decoded
+    fn decoded_rows(&self, table_name: &str) -> Result<Rows, String> {
+        self.scan_table(table_name)?.iter()
+            .map(|(_, value)| self.decode_row(table_name, value))
+            .collect()
+    }
 
-        // Convert to HashMap format for compatibility with existing logic
+    // The keys and decoded rows of a table the caller has locked
+    fn decoded_rows_with_keys(&self, table_name: &str, table_storage: &PartitionedStorage)
+                              -> Result<(Vec<String>, Rows), String> {
+        let mut keys = Vec::new();
         let mut rows = Vec::new();
-        for (key, value) in all_rows {
-            let row_data: HashMap<String, String> = serde_json::from_str(&value)
-                .map_err(|e| format!("Failed to deserialize row data: {}", e))?;
-            rows.push(row_data);
+        for row in self.visible_rows(table_name, table_storage)? {
+            let (key, value) = row?;
+            rows.push(self.decode_row(table_name, &value)?);
+            keys.push(key);
         }
+        Ok((keys, rows))
+    }
 
-        // Handle JOIN if present
-        if let Some(join) = &stmt.join {
-            let right_table_storage = self.tables.get_mut(&join.table)
-                .ok_or_else(|| format!("Right table '{}' not found", join.table))?;
-            
-            let right_rows = right_table_storage.get_all()
-                .map_err(|e| format!("Storage error: {}", e))?;
-            
-            let mut right_rows_data = Vec::new();
-            for (_, value) in right_rows {
-                let row_data: HashMap<String, String> = serde_json::from_str(&value)
-                    .map_err(|e| format!("Failed to deserialize row data: {}", e))?;
-                right_rows_data.push(row_data);
+    fn write_filter(&self, table_name: &str, join: Option<&JoinClause>, where_clause: Option<&WhereClause>)
+                    -> Result<WriteFilter, String> {
+        let where_clause = where_clause.map(|wc| self.run_subquery(wc)).transpose()?;
+        match join {
+            Some(join) => {
+                let right_rows = joined_side(join, &mut |table| self.decoded_rows(table).map(Cow::Owned))?.into_owned();
+                Ok(WriteFilter { join: Some((join.clone(), right_rows)), where_clause })
             }
+            None => {
+                let where_clause = where_clause.map(|wc| unqualified_where(&wc, table_name));
+                Ok(WriteFilter { join: None, where_clause })
+            }
+        }
+    }
 
-            rows = self.perform_join(&rows, &right_rows_data, join, table_name)?;
+    // The WHERE condition with its IN subquery, if it has one, run
+    fn run_subquery(&self, where_clause: &WhereClause) -> Result<WhereClause, String> {
+        with_subquery_run(where_clause, |query| {
+            let rewrite_rules = self.rewrite_rules.read().map_err(lock_err)?.clone();
+            self.select(&*rewrite_rules.apply_select(query)?)
+        })
+    }
+
+    fn scan_table(&self, table_name: &str) -> Result<Vec<(String, String)>, String> {
+        let table_storage = self.table(table_name)?;
+        let table_storage = table_storage.read().map_err(lock_err)?;
+        let rows = self.visible_rows(table_name, &table_storage)?.collect();
+        rows
+    }
+
+    // The (key, row json) pairs of a table as they were at `timestamp`, for
+    // AS OF
+    fn read_as_of(&self, table_name: &str, timestamp: u64) -> Result<Vec<(String, String)>, String> {
+        let table_storage = self.table(table_name)?;
+        let table_storage = table_storage.read().map_err(lock_err)?;
+        table_storage.scan_as_of(timestamp).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => format!("Table '{}' keeps no history to read AS OF; it needs archive_wal", table_name),
+            _ => format!("Can't read '{}' as of {}: {}", table_name, timestamp, e),
+        })
+    }
+
+    // Streams the (key, row json) pairs of a table as seen by the current
+    // session, i.e. with any uncommitted transaction writes applied on top.
+    fn visible_rows<'a>(&self, table_name: &str, table_storage: &'a PartitionedStorage) -> Result<RowIter<'a>, String> {
+        self.visible_scan(table_name, table_storage, (Bound::Unbounded, Bound::Unbounded), false)
+    }
+
+    // Key-range counterpart of `visible_rows`: only the SSTables and blocks
+    // overlapping [start, end] are read from storage.
+    fn visible_range<'a>(&self, table_name: &str, table_storage: &'a PartitionedStorage,
+                         start: &str, end: &str) -> Result<RowIter<'a>, String> {
+        self.visible_scan(table_name, table_storage, (Bound::Included(start), Bound::Included(end)), false)
+    }
+
+    fn visible_scan<'a>(&self, table_name: &str, table_storage: &'a PartitionedStorage,
+                        range: (Bound<&str>, Bound<&str>), descending: bool) -> Result<RowIter<'a>, String> {
+        let rows = table_storage.scan(range, descending)
+            .map_err(|e| format!("Storage error: {}", e))?
+            .map(|row| row.map_err(|e| format!("Storage error: {}", e)));
+
+        let mut transactions = self.transactions.lock().map_err(lock_err)?;
+        let tx = transactions.get_mut(&current_session());
+        if let Some(reads) = tx.and_then(|tx| tx.reads.as_mut()) {
+            reads.read_table(table_name);
         }
+        let Some(tx) = transactions.get(&current_session()).filter(|tx| tx.writes.iter().any(|w| w.table == table_name)) else {
+            return Ok(Box::new(rows));
+        };
+
+        // Transaction write sets are small, so the overlay is applied eagerly
+        let mut merged = rows.collect::<Result<BTreeMap<String, String>, String>>()?;
+        for write in tx.writes.iter().filter(|w| w.table == table_name && range.contains(w.key.as_str())) {
+            match &write.value {
+                Some(value) => { merged.insert(write.key.clone(), value.clone()); }
+                None => { merged.remove(&write.key); }
+            }
+        }
+        if descending {
+            Ok(Box::new(merged.into_iter().rev().map(Ok)))
+        } else {
+            Ok(Box::new(merged.into_iter().map(Ok)))
+        }
+    }
+
+    // Single-key counterpart of `visible_rows`.
+    fn visible_get(&self, table_name: &str, table_storage: &PartitionedStorage, key: &str) -> Result<Option<String>, String> {
+        if let Some(tx) = self.transactions.lock().map_err(lock_err)?.get_mut(&current_session()) {
+            if let Some(reads) = &mut tx.reads {
+                reads.read_row(table_name, key);
+            }
+            if let Some(write) = tx.writes.iter().rev().find(|w| w.table == table_name && w.key == key) {
+                return Ok(write.value.clone());
+            }
+        }
+        table_storage.get(key).map_err(|e| format!("Storage error: {}", e))
+    }
+
+    fn primary_key(&self, table_name: &str) -> Result<Option<PrimaryKey>, String> {
+        let catalog = self.catalog.read().map_err(lock_err)?;
+        let Some(table) = catalog.table(table_name) else {
+            return Ok(None);
+        };
+        Ok(table.primary_key.as_ref().and_then(|column| {
+            table.column(column).map(|c| PrimaryKey { column: c.name.clone(), data_type: c.data_type.clone() })
+        }))
+    }
+
+    fn column_names(&self, table_name: &str) -> Result<Option<Vec<String>>, String> {
+        Ok(self.catalog.read().map_err(lock_err)?.table(table_name).map(TableDef::column_names))
+    }
+
+    // The columns SELECT * shows, in schema order: over a JOIN, those of every
+    // table by their qualified names, as the in-memory database does
+    fn select_all_columns(&self, table_name: &str, joined: &[&str]) -> Result<Option<Vec<String>>, String> {
+        let Some(columns) = self.column_names(table_name)? else {
+            return Ok(None);
+        };
+        if joined.is_empty() {
+            return Ok(Some(columns));
+        }
+        let qualified = |table: &str, columns: Vec<String>| {
+            columns.into_iter().map(|column| format!("{}.{}", table, column)).collect::<Vec<_>>()
+        };
+        let mut all = qualified(table_name, columns);
+        for table in joined {
+            all.extend(qualified(table, self.column_names(table)?.unwrap_or_default()));
+        }
+        Ok(Some(all))
+    }
+
+    fn column_types(&self, table_name: &str) -> Result<ColumnTypes, String> {
+        let mut types = ColumnTypes::default();
+        let catalog = self.catalog.read().map_err(lock_err)?;
+        for column in catalog.table(table_name).map_or(&[][..], |table| &table.columns) {
+            // Under the names a query can use for it
+            for name in [column.name.clone(), format!("{}.{}", table_name, column.name)] {
+                match column.data_type.to_uppercase().as_str() {
+                    "BLOB" => { types.blobs.insert(name); }
+                    "BOOL" | "BOOLEAN" => { types.booleans.insert(name); }
+                    data_type => if let Ok(Some(decimal)) = DecimalType::of(data_type) {
+                        types.decimals.insert(name, decimal);
+                    },
+                }
+            }
+        }
+        Ok(types)
+    }
+
+    // Fast path for `WHERE <primary key> = value` and `WHERE <primary key>
+    // BETWEEN low AND high`: reads only the matching keys from storage instead
+    // of scanning the table. Returns None when the WHERE clause can't be
+    // answered from the key alone.
+    fn key_lookup(&self, table_name: &str, where_clause: Option<&WhereClause>) -> Result<Option<KeyLookup>, String> {
+        let Some(where_clause) = where_clause else {
+            return Ok(None);
+        };
+        let Some(primary_key) = self.primary_key(table_name)? else {
+            return Ok(None);
+        };
+        if primary_key.column != where_clause.column || where_clause.function.is_some() {
+            return Ok(None);
+        }
+
+        match (where_clause.operator.as_str(), &where_clause.upper_bound) {
+            ("=", _) => Ok(Some(KeyLookup::Point(primary_key.storage_key(&where_clause.value).ok()))),
+            ("BETWEEN", Some(upper_bound)) => {
+                // Non-integer bounds on an INT key fall back to a full scan
+                match (primary_key.storage_key(&where_clause.value), primary_key.storage_key(upper_bound)) {
+                    (Ok(start), Ok(end)) => Ok(Some(KeyLookup::Range(start, end))),
+                    _ => Ok(None),
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn plan_access(&self, stmt: &SelectStatement) -> Result<Access, String> {
+        let table_name = &stmt.table;
+        if stmt.join.is_some() || stmt.as_of.is_some() || stmt.hints.contains(&Hint::NoIndex(table_name.clone())) {
+            return Ok(Access::default());
+        }
+        let lookup = self.key_lookup(table_name, stmt.where_clause.as_ref())?;
+        // ORDER BY the primary key can read rows in key order instead of sorting
+        let key_order = match (&stmt.order_by, self.primary_key(table_name)?) {
+            (Some(order_by), Some(primary_key)) if stmt.group_by.is_none()
+                && matches!(resolve_alias(&stmt.columns, &order_by.expr), ColumnExpr::Column(c) if *c == primary_key.column) => {
+                Some(order_by.descending)
+            }
+            _ => None,
+        };
+        Ok(Access { lookup, key_order })
+    }
+
+    fn read_access(&self, table_name: &str, access: &Access) -> Result<Vec<(String, String)>, String> {
+        if access.lookup.is_none() && access.key_order.is_none() {
+            return self.scan_table(table_name);
+        }
+        let table_storage = self.table(table_name)?;
+        let table_storage = table_storage.read().map_err(lock_err)?;
+        let mut rows: Vec<(String, String)> = match &access.lookup {
+            Some(KeyLookup::Point(None)) => Vec::new(),
+            Some(KeyLookup::Point(Some(key))) => {
+                let row = self.visible_get(table_name, &table_storage, key)?;
+                row.map(|value| (key.clone(), value)).into_iter().collect()
+            }
+            Some(KeyLookup::Range(start, end)) => {
+                self.visible_range(table_name, &table_storage, start, end)?.collect::<Result<_, _>>()?
+            }
+            None => {
+                let descending = access.key_order == Some(true);
+                return self.visible_scan(table_name, &table_storage, (Bound::Unbounded, Bound::Unbounded), descending)?
+                    .collect();
+            }
+        };
+        if access.key_order == Some(true) {
+            rows.reverse();
+        }
+        Ok(rows)
+    }
+
+    // Routes a batch of row writes, and the change they make, either to the
+    // open transaction or straight to the (write-locked) table storage as a
+    // single WAL record. The result should be passed to `finish_write` once
+    // the table lock is released, so concurrent writers can share a WAL sync.
+    fn write_batch(&self, table_name: &str, table_storage: &mut PartitionedStorage,
+                   batch: WriteBatch, change: Option<ChangeEvent>) -> Result<Written, String> {
+        if let Some(tx) = self.transactions.lock().map_err(lock_err)?.get_mut(&current_session()) {
+            tx.writes.extend(batch.into_iter().map(|(key, value)| {
+                PendingWrite { table: table_name.to_string(), key, value }
+            }));
+            tx.changes.extend(change);
+            return Ok(Written { ticket: None, change: None });
+        }
+
+        self.versions.lock().map_err(lock_err)?.record(table_name, batch.keys());
+        let ticket = table_storage.write_deferred(batch).map_err(|e| format!("Storage error: {}", e))?;
+        Ok(Written { ticket, change })
+    }
+
+    fn finish_write(&self, written: Written) -> Result<(), String> {
+        wait_durable(written.ticket)?;
+        match written.change {
+            Some(change) => self.notify_change(&change),
+            None => Ok(()),
+        }
+    }
+
+    fn watches_changes(&self, table_name: &str) -> Result<bool, String> {
+        Ok(self.change_hooks.read().map_err(lock_err)?.watches(table_name))
+    }
+
+    // The event for rows a statement changed, if anything watches the table
+    fn change(&self, table_name: &str, kind: ChangeKind, rows: Option<Vec<HashMap<String, String>>>) -> Result<Option<ChangeEvent>, String> {
+        let Some(rows) = rows.filter(|rows| !rows.is_empty()) else {
+            return Ok(None);
+        };
+        let columns = self.column_names(table_name)?.unwrap_or_default();
+        Ok(Some(change_event(table_name, kind, columns, rows)))
+    }
+
+    fn notify_change(&self, change: &ChangeEvent) -> Result<(), String> {
+        let hooks = self.change_hooks.read().map_err(lock_err)?.hooks(&change.table);
+        for hook in hooks {
+            hook(change);
+        }
+        Ok(())
+    }
+
+    fn select(&self, stmt: &SelectStatement) -> Result<ResultSet, String> {
+        let table_name = &stmt.table;
+        check_grouping(stmt)?;
+
+        let (access, mut rows) = match &stmt.table_function {
+            Some(function) => (Access::default(), self.table_function_rows(function)?),
+            None => {
+                // Get all rows from storage, or only the keys a primary key predicate selects
+                let access = planned(|| self.plan_access(stmt))?;
+                let all_rows = match stmt.as_of {
+                    Some(timestamp) => self.read_as_of(table_name, timestamp)?,
+                    None => self.read_access(table_name, &access)?,
+                };
+
+                // Convert to HashMap format for compatibility with existing logic
+                let mut rows = Vec::new();
+                for (_key, value) in all_rows {
+                    rows.push(self.decode_row(table_name, &value)?);
+                }
+                (access, rows)
+            }
+        };
+
+        // Handle JOIN if present
+        if let Some(join) = &stmt.join {
+            let right_rows = joined_side(join, &mut |table| match stmt.as_of {
+                Some(timestamp) => self.read_as_of(table, timestamp)?.iter()
+                    .map(|(_, value)| self.decode_row(table, value))
+                    .collect::<Result<_, _>>().map(Cow::Owned),
+                None => self.decoded_rows(table).map(Cow::Owned)
+                    .map_err(|_| format!("Right table '{}' not found", table)),
+            })?;
+            rows = join_rows(&rows, &right_rows, join, table_name, joins_right_first(stmt));
+        } else {
+            qualify_columns(&mut rows, table_name);
+        }
+
+        let types = match stmt.table_function {
+            Some(_) => ColumnTypes::default(),
+            None => self.column_types(table_name)?,
+        };
 
         // Apply WHERE clause
         if let Some(where_clause) = &stmt.where_clause {
-            rows = self.apply_where_clause(rows, where_clause)?;
+            rows = self.apply_where_clause(rows, &self.run_subquery(where_clause)?, &types)?;
         }
 
-        // Check if we have aggregate functions without GROUP BY
-        let has_aggregates = stmt.columns.iter().any(|col| {
-            matches!(col, ColumnExpr::Count(_) | ColumnExpr::Sum(_) | ColumnExpr::Avg(_) | 
-                           ColumnExpr::Min(_) | ColumnExpr::Max(_) | ColumnExpr::CountAll)
-        });
-        
-        // Apply GROUP BY or handle aggregates without GROUP BY
+        // Apply GROUP BY or handle aggregates without GROUP BY, computing
+        // HAVING's and ORDER BY's aggregates too even if they aren't selected
+        let aggregates: Vec<ColumnExpr> = stmt.columns.iter().cloned()
+            .chain(stmt.having.as_ref().map(|having| resolve_alias(&stmt.columns, &having.column_expr).clone()))
+            .chain(stmt.order_by.as_ref().map(|order_by| resolve_alias(&stmt.columns, &order_by.expr).clone()))
+            .collect();
         if let Some(group_by) = &stmt.group_by {
-            rows = self.apply_group_by(rows, group_by, &stmt.columns)?;
-        } else if has_aggregates {
+            rows = self.apply_group_by(rows, group_by, &aggregates, &types)?;
+        } else if has_aggregates(&stmt.columns) {
             // For aggregates without GROUP BY, treat all rows as one group
-            rows = self.apply_group_by(rows, &[], &stmt.columns)?;
+            rows = self.apply_group_by(rows, &[], &aggregates, &types)?;
         }
 
         // Apply HAVING
         if let Some(having) = &stmt.having {
-            rows = self.apply_having(rows, having)?;
+            let key = resolve_alias(&stmt.columns, &having.column_expr);
+            rows = self.apply_having(rows, key, having, &types)?;
         }
 
         // Apply ORDER BY
-        if let Some(order_by) = &stmt.order_by {
-            rows = self.apply_order_by(rows, order_by)?;
+        if let Some(order_by) = stmt.order_by.as_ref().filter(|_| access.key_order.is_none()) {
+            rows = self.apply_order_by(rows, resolve_alias(&stmt.columns, &order_by.expr), order_by.descending, &types)?;
         }
 
-        // Format result
-        self.format_select_result(&rows, &stmt.columns, table_name)
+        // Apply OFFSET and LIMIT
+        let rows: Vec<_> = rows.into_iter()
+            .skip(stmt.offset.unwrap_or(0))
+            .take(stmt.limit.unwrap_or(usize::MAX))
+            .collect();
+
+        let joined = stmt.join.as_ref().map_or_else(Vec::new, JoinClause::tables);
+        let schema = match &stmt.table_function {
+            Some(function) => Some(function.columns().iter().map(|c| c.to_string()).collect()),
+            None => self.select_all_columns(table_name, &joined)?,
+        };
+        let mut result = self.select_result(&rows, &stmt.columns, schema)?;
+        result.meta = self.column_meta(stmt, &result.columns)?;
+        Ok(result)
     }
 
-    fn execute_insert(&mut self, stmt: InsertStatement) -> Result<String, String> {
+    // `select` for `query_each`: a query that can be answered a row at a
+    // time straight off a scan of its table is
+    fn select_each(&self, stmt: &SelectStatement, sink: &mut dyn RowSink) -> Result<usize, String> {
         let table_name = &stmt.table;
-        
-        // Get or create table storage
-        let table_storage = self.tables.entry(table_name.clone())
-            .or_insert_with(|| {
-                LSMStorage::new(&self.data_dir, table_name)
-                    .expect("Failed to create table storage")
-            });
+        check_grouping(stmt)?;
+        let simple = stmt.table_function.is_none() && stmt.as_of.is_none() && stmt.join.is_none()
+            && stmt.group_by.is_none() && stmt.having.is_none() && !has_aggregates(&stmt.columns);
+        let access = if simple { planned(|| self.plan_access(stmt))? } else { Access::default() };
+        let schema = if simple { self.select_all_columns(table_name, &[])? } else { None };
+        let Some(schema) = schema.filter(|_| access.lookup.is_none() && (stmt.order_by.is_none() || access.key_order.is_some())) else {
+            return send_rows(self.select(stmt)?, sink);
+        };
 
-        let mut inserted_count = 0;
+        let types = self.column_types(table_name)?;
+        let where_clause = stmt.where_clause.as_ref().map(|wc| self.run_subquery(wc)).transpose()?;
+        let headers = result_headers(&stmt.columns, &schema);
+        sink.columns(&headers, &self.column_meta(stmt, &headers)?).map_err(write_rows_err)?;
+
+        let table_storage = self.table(table_name)?;
+        let table_storage = table_storage.read().map_err(lock_err)?;
+        let descending = access.key_order == Some(true);
+        let mut rows = self.visible_scan(table_name, &table_storage, (Bound::Unbounded, Bound::Unbounded), descending)?;
+        let mut skip = stmt.offset.unwrap_or(0);
+        let mut sent = 0;
+        while sent < stmt.limit.unwrap_or(usize::MAX) {
+            let Some(row) = rows.next() else {
+                break;
+            };
+            let mut row = [self.decode_row(table_name, &row?.1)?];
+            qualify_columns(&mut row, table_name);
+            if let Some(where_clause) = &where_clause {
+                if !Self::evaluate_where_condition(&row[0], where_clause, &types)? {
+                    continue;
+                }
+            }
+            if skip > 0 {
+                skip -= 1;
+                continue;
+            }
+            sink.row(&result_row(&row[0], &stmt.columns, &schema)).map_err(write_rows_err)?;
+            sent += 1;
+        }
+        slow_query::set_rows_returned(sent);
+        Ok(sent)
+    }
+
+    // The table and declared type of each column of a SELECT's result
+    fn column_meta(&self, stmt: &SelectStatement, headers: &[String]) -> Result<Vec<ColumnMeta>, String> {
+        if stmt.table_function.is_some() {
+            return Ok(ColumnMeta::of_select(&stmt.columns, headers, |name| {
+                let value_type = match name.rsplit('.').next() {
+                    Some("column_name") => ValueType::Text,
+                    Some("row_count" | "null_count" | "distinct_count") => ValueType::Integer,
+                    _ => ValueType::Unknown,
+                };
+                (None, value_type)
+            }));
+        }
+        let catalog = self.catalog.read().map_err(lock_err)?;
+        let tables: Vec<&TableDef> = std::iter::once(stmt.table.as_str())
+            .chain(stmt.join.iter().flat_map(JoinClause::tables))
+            .filter_map(|table| catalog.table(table))
+            .collect();
+        Ok(ColumnMeta::of_select(&stmt.columns, headers, |name| {
+            let (table, column) = match name.split_once('.') {
+                Some((table, column)) => (Some(table), column),
+                None => (None, name),
+            };
+            tables.iter()
+                .filter(|def| table.is_none_or(|table| table == def.name))
+                .find_map(|def| def.columns.iter().find(|c| c.name == column)
+                    .map(|c| (Some(def.name.clone()), ValueType::of_declared(&c.data_type))))
+                .unwrap_or((None, ValueType::Unknown))
+        }))
+    }
+
+    fn execute_insert(&self, stmt: InsertStatement) -> Result<StatementResult, String> {
+        self.insert_values(&stmt.table, &stmt.columns, stmt.values.into_iter().map(Ok), stmt.on_conflict)
+    }
+
+    /// Runs an INSERT from `parse_insert_stream`, parsing its tuples as their
+    /// rows are added to the batch rather than parsing the statement whole
+    /// first, which is how `execute_script` runs a script's INSERTs. The rows
+    /// still go to storage as one batch, so a tuple that doesn't parse
+    /// inserts none of them. With rewrite rules added, the statement is
+    /// parsed whole and run through `execute` so that they see it.
+    pub fn execute_insert_stream(&self, insert: InsertStream<'_>) -> Result<StatementResult, String> {
+        if !self.rewrite_rules.read().map_err(lock_err)?.is_empty() {
+            return self.execute(SQLStatement::Insert(insert.into_statement()?));
+        }
+        let sql = self.slow_query_threshold()?.map(|_| insert.sql().to_string());
+        let (table, columns, on_conflict) = (insert.table.clone(), insert.columns.clone(), insert.on_conflict);
+        let mut locks = LockSet::default();
+        locks.add(&table, LockMode::Exclusive);
+        self.log_if_slow(sql, || self.locked(&locks, || {
+            self.check_writable()?;
+            self.atomically(|| self.insert_values(&table, &columns, insert, on_conflict))
+        }))
+    }
+
+    fn insert_values(&self, table_name: &str, columns: &[String], rows: impl IntoIterator<Item = Result<Vec<String>, String>>,
+                     on_conflict: Option<ConflictAction>) -> Result<StatementResult, String> {
+        let (inserted_count, last_key) = self.insert_rows(table_name, columns, rows, on_conflict)?;
+        record_write(inserted_count, last_key.clone());
+        Ok(StatementResult::Changed {
+            message: format!("{} row(s) inserted successfully", inserted_count),
+            rows_affected: inserted_count,
+            last_insert_rowid: last_key,
+        })
+    }
+
+    /// Inserts many rows into `table_name` at once. All rows go to storage as
+    /// one WAL batch, and the memtable size check runs once at the end rather
+    /// than per row, which makes bulk loads far cheaper than row-at-a-time
+    /// INSERTs. Returns the number of rows inserted.
+    pub fn insert_many(&self, table_name: &str, columns: &[String], rows: Vec<Vec<String>>) -> Result<usize, String> {
+        Ok(self.insert_rows(table_name, columns, rows.into_iter().map(Ok), None)?.0)
+    }
+
+    // `insert_many`, also returning the key of the last row inserted: its
+    // primary key value, or the key generated for it. A row whose primary
+    // key is taken is an error unless `on_conflict` skips or overwrites it.
+    fn insert_rows(&self, table_name: &str, columns: &[String], rows: impl IntoIterator<Item = Result<Vec<String>, String>>,
+                   on_conflict: Option<ConflictAction>) -> Result<(usize, Option<String>), String> {
+        self.check_writable()?;
+        let table_storage = self.table(table_name)?;
+        let mut table_storage = table_storage.write().map_err(lock_err)?;
+
+        // Without a column list, each tuple gives every column in schema order
+        let schema_columns;
+        let whole_rows = columns.is_empty();
+        let columns = if whole_rows {
+            schema_columns = self.column_names(table_name)?.unwrap_or_default();
+            &schema_columns[..]
+        } else {
+            columns
+        };
+
+        // Rows of a table with a primary key are stored under the key's value
+        let primary_key = self.primary_key(table_name)?;
+        let primary_key_index = match &primary_key {
+            Some(primary_key) => Some(columns.iter().position(|c| *c == primary_key.column)
+                .ok_or_else(|| format!("Primary key column '{}' requires a value", primary_key.column))?),
+            None => None,
+        };
+
+        let types = self.column_types(table_name)?;
+        let mut batch = WriteBatch::new();
+        let mut batch_keys = HashSet::new();
+        let mut changed_rows = self.watches_changes(table_name)?.then(Vec::new);
+        let mut last_key = None;
 
         // Process each row in the values
-        for values_row in stmt.values {
-            // Generate a unique key for this row
-            let row_key = format!("row_{}_{}", SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_millis(), inserted_count);
+        for values_row in rows {
+            let values_row = values_row?;
+            if whole_rows && values_row.len() != columns.len() {
+                return Err("Column count does not match value count".to_string());
+            }
+            let row_key = if let (Some(primary_key), Some(index)) = (&primary_key, primary_key_index) {
+                let value = values_row.get(index)
+                    .ok_or_else(|| format!("Primary key column '{}' requires a value", columns[index]))?;
+                let key = primary_key.storage_key(value)?;
+                let taken = !batch_keys.insert(key.clone())
+                    || self.visible_get(table_name, &table_storage, &key)?.is_some();
+                match on_conflict {
+                    _ if !taken => {}
+                    Some(ConflictAction::Ignore) => continue,
+                    Some(ConflictAction::Replace) => {}
+                    None => return Err(format!("Duplicate primary key '{}' in table '{}'", value, table_name)),
+                }
+                key
+            } else {
+                // Generate a unique key for this row
+                format!("row_{}_{:06}", SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis(), self.row_seq.fetch_add(1, Ordering::Relaxed))
+            };
 
             // Create row data
             let mut row_data = HashMap::new();
-            for (i, column) in stmt.columns.iter().enumerate() {
+            for (i, column) in columns.iter().enumerate() {
                 if i < values_row.len() {
-                    row_data.insert(column.clone(), values_row[i].clone());
+                    let value = types.stored_value(column, &values_row[i])?;
+                    row_data.insert(column.clone(), value);
                 }
             }
 
             // Serialize row data
-            let row_json = serde_json::to_string(&row_data)
-                .map_err(|e| format!("Failed to serialize row data: {}", e))?;
+            let row_json = encode_row(&row_data)?;
+
+            last_key = Some(match &primary_key {
+                Some(primary_key) => row_data.get(&primary_key.column).cloned().unwrap_or_default(),
+                None => row_key.clone(),
+            });
+            batch.put(row_key, row_json);
+            if let Some(changed_rows) = &mut changed_rows {
+                changed_rows.push(row_data);
+            }
+        }
+
+        let inserted_count = batch.len();
+        let change = self.change(table_name, ChangeKind::Insert, changed_rows)?;
+        let written = self.write_batch(table_name, &mut table_storage, batch, change)?;
+        drop(table_storage);
+        self.finish_write(written)?;
+        Ok((inserted_count, last_key))
+    }
 
-            // Store in LSM storage
-            table_storage.insert(row_key, row_json)
-                .map_err(|e| format!("Storage error: {}", e))?;
+    /// Loads a CSV file into `table_name`, whose header names the columns.
+    /// A missing table is created with those columns as TEXT; an existing one
+    /// must have them all. Rows are inserted `IMPORT_ROWS_PER_BATCH` at a
+    /// time, so a failure part way leaves the earlier batches loaded. Returns
+    /// the number of rows loaded.
+    pub fn import_csv(&self, table_name: &str, reader: impl Read) -> Result<usize, String> {
+        self.check_writable()?;
+        let mut records = CsvReader::new(BufReader::new(reader));
+        let columns: Vec<String> = records.next()
+            .ok_or("CSV file is empty")?
+            .map_err(|e| format!("Failed to read CSV: {}", e))?
+            .into_iter()
+            .map(|c| c.trim().to_string())
+            .collect();
 
-            inserted_count += 1;
+        let table = self.catalog.read().map_err(lock_err)?.table(table_name).cloned();
+        match table {
+            Some(table) => {
+                if let Some(column) = columns.iter().find(|c| table.column(c).is_none()) {
+                    return Err(format!("Column '{}' not found in table '{}'", column, table_name));
+                }
+            }
+            None => {
+                self.execute_create_table(CreateTableStatement {
+                    table: table_name.to_string(),
+                    columns: columns.iter().map(|c| (c.clone(), "TEXT".to_string())).collect(),
+                    primary_key: None,
+                    options: Vec::new(),
+                    partition_by: None,
+                })?;
+            }
         }
 
-        Ok(format!("{} row(s) inserted successfully", inserted_count))
+        let mut loaded = 0;
+        let mut batch = Vec::new();
+        for record in records.by_ref() {
+            let record = record.map_err(|e| format!("Failed to read CSV: {}", e))?;
+            // Blank lines
+            if record.len() == 1 && record[0].is_empty() {
+                continue;
+            }
+            if record.len() != columns.len() {
+                return Err(format!("Line {} has {} fields but the header has {}",
+                                   records.line(), record.len(), columns.len()));
+            }
+            batch.push(record);
+            if batch.len() == IMPORT_ROWS_PER_BATCH {
+                loaded += self.insert_many(table_name, &columns, std::mem::take(&mut batch))?;
+            }
+        }
+        if !batch.is_empty() {
+            loaded += self.insert_many(table_name, &columns, batch)?;
+        }
+        Ok(loaded)
     }
 
-    fn execute_update(&mut self, stmt: UpdateStatement) -> Result<String, String> {
+    fn execute_update(&self, stmt: UpdateStatement) -> Result<StatementResult, String> {
         let table_name = &stmt.table;
+        let filter = self.write_filter(table_name, stmt.join.as_ref(), stmt.where_clause.as_ref())?;
         
-        let table_storage = self.tables.get_mut(table_name)
-            .ok_or_else(|| format!("Table '{}' not found", table_name))?;
+        // Hold the table's write lock across the read-modify-write
+        let table_storage = self.table(table_name)?;
+        let mut table_storage = table_storage.write().map_err(lock_err)?;
 
-        // Get all rows
-        let all_rows = table_storage.get_all()
-            .map_err(|e| format!("Storage error: {}", e))?;
+        let primary_key = self.primary_key(table_name)?;
+        let mut unmoved_keys = HashSet::new();
+
+        let types = self.column_types(table_name)?;
+        let assignments = stmt.assignments.iter()
+            .map(|(column, value)| {
+                let column = assigned_column(column, table_name)?;
+                let value = match value {
+                    SetValue::Value(value) => SetValue::Value(types.stored_value(column, value)?),
+                    SetValue::Math(call) => {
+                        let source = assigned_column(&call.column, table_name)?.to_string();
+                        SetValue::Math(FunctionCall { column: source, ..call.clone() })
+                    }
+                };
+                Ok((column.to_string(), value))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let columns = self.column_names(table_name)?.unwrap_or_default();
+        if let Some(returning) = &stmt.returning {
+            returned_rows(returning, table_name, &columns, Image::New, &[])?;
+        }
 
         let mut updated_count = 0;
         let mut updates = Vec::new();
+        let mut changed_rows = self.watches_changes(table_name)?.then(Vec::new);
+        let mut returned = Vec::new();
 
-        for (key, value) in all_rows {
-            let mut row_data: HashMap<String, String> = serde_json::from_str(&value)
-                .map_err(|e| format!("Failed to deserialize row data: {}", e))?;
+        let (keys, rows) = self.decoded_rows_with_keys(table_name, &table_storage)?;
+        let matches = filter.matches(&rows, table_name, &types)?;
+        for ((key, mut row_data), should_update) in keys.into_iter().zip(rows).zip(matches) {
+            let old = (should_update && stmt.returning.is_some()).then(|| row_data.clone());
+            if should_update {
+                // Apply updates; every function reads the row as it was
+                // before the UPDATE
+                let values = assignments.iter()
+                    .map(|(column, value)| match value {
+                        SetValue::Value(value) => Ok((column.clone(), value.clone())),
+                        SetValue::Math(call) => Ok((column.clone(), types.stored_value(column, &math::value_of(call, &row_data))?)),
+                    })
+                    .collect::<Result<Vec<_>, String>>()?;
+                row_data.extend(values);
+                updated_count += 1;
+            }
 
-            // Check WHERE condition
-            let should_update = if let Some(where_clause) = &stmt.where_clause {
-                Self::evaluate_where_condition(&row_data, where_clause)?
-            } else {
-                true
+            // Assigning a new primary key value moves the row to a new key
+            let new_key = match &primary_key {
+                Some(primary_key) if should_update => match row_data.get(&primary_key.column) {
+                    Some(value) => primary_key.storage_key(value)?,
+                    None => key.clone(),
+                },
+                _ => key.clone(),
             };
+            if new_key == key {
+                unmoved_keys.insert(key.clone());
+            }
 
+            // Re-serialize and store
             if should_update {
-                // Apply updates
-                for (column, new_value) in &stmt.assignments {
-                    row_data.insert(column.clone(), new_value.clone());
+                let new_row_json = encode_row(&row_data)?;
+                updates.push((key, new_key, new_row_json));
+                returned.extend(old.map(|old| (old, Some(row_data.clone()))));
+                if let Some(changed_rows) = &mut changed_rows {
+                    changed_rows.push(row_data);
                 }
-                updated_count += 1;
             }
+        }
 
-            // Re-serialize and store
-            let new_row_json = serde_json::to_string(&row_data)
-                .map_err(|e| format!("Failed to serialize row data: {}", e))?;
-            
-            updates.push((key, new_row_json));
+        let mut moved_keys = HashSet::new();
+        for (key, new_key, _) in &updates {
+            if new_key != key && (unmoved_keys.contains(new_key) || !moved_keys.insert(new_key)) {
+                let value = primary_key.as_ref()
+                    .and_then(|pk| stmt.assignments.iter()
+                        .find(|(column, _)| assigned_column(column, table_name).is_ok_and(|column| column == pk.column)))
+                    .and_then(|(_, value)| match value {
+                        SetValue::Value(value) => Some(value.as_str()),
+                        SetValue::Math(_) => None,
+                    })
+                    .unwrap_or(new_key.as_str());
+                return Err(format!("Duplicate primary key '{}' in table '{}'", value, table_name));
+            }
         }
 
-        // Apply all updates
-        for (key, new_row_json) in updates {
-            table_storage.delete(key.clone())
-                .map_err(|e| format!("Storage error: {}", e))?;
-            table_storage.insert(key, new_row_json)
-                .map_err(|e| format!("Storage error: {}", e))?;
+        // Apply all updates as one batch. Every moved row's old key is removed
+        // before any new one is written, so rows swapping primary keys don't
+        // clobber each other.
+        let mut batch = WriteBatch::new();
+        for (key, new_key, _) in &updates {
+            if new_key != key {
+                batch.delete(key.clone());
+            }
         }
+        for (_, new_key, new_row_json) in updates {
+            batch.put(new_key, new_row_json);
+        }
+        let change = self.change(table_name, ChangeKind::Update, changed_rows)?;
+        let written = self.write_batch(table_name, &mut table_storage, batch, change)?;
+        drop(table_storage);
+        self.finish_write(written)?;
 
-        Ok(format!("Updated {} rows", updated_count))
+        record_write(updated_count, None);
+        if let Some(returning) = &stmt.returning {
+            return returned_rows(returning, table_name, &columns, Image::New, &returned).map(StatementResult::Rows);
+        }
+        Ok(StatementResult::Changed {
+            message: format!("Updated {} rows", updated_count),
+            rows_affected: updated_count,
+            last_insert_rowid: None,
+        })
     }
 
-    fn execute_delete(&mut self, stmt: DeleteStatement) -> Result<String, String> {
+    fn execute_delete(&self, stmt: DeleteStatement) -> Result<StatementResult, String> {
         let table_name = &stmt.table;
+        let filter = self.write_filter(table_name, stmt.join.as_ref(), stmt.where_clause.as_ref())?;
         
-        let table_storage = self.tables.get_mut(table_name)
-            .ok_or_else(|| format!("Table '{}' not found", table_name))?;
-
-        // Get all rows
-        let all_rows = table_storage.get_all()
-            .map_err(|e| format!("Storage error: {}", e))?;
+        // Hold the table's write lock across the read-modify-write
+        let table_storage = self.table(table_name)?;
+        let mut table_storage = table_storage.write().map_err(lock_err)?;
 
+        let types = self.column_types(table_name)?;
+        let columns = self.column_names(table_name)?.unwrap_or_default();
+        if let Some(returning) = &stmt.returning {
+            returned_rows(returning, table_name, &columns, Image::Old, &[])?;
+        }
         let mut deleted_count = 0;
         let mut keys_to_delete = Vec::new();
+        let mut changed_rows = self.watches_changes(table_name)?.then(Vec::new);
+        let mut returned = Vec::new();
 
-        for (key, value) in all_rows {
-            let row_data: HashMap<String, String> = serde_json::from_str(&value)
-                .map_err(|e| format!("Failed to deserialize row data: {}", e))?;
-
-            // Check WHERE condition
-            let should_delete = if let Some(where_clause) = &stmt.where_clause {
-                Self::evaluate_where_condition(&row_data, where_clause)?
-            } else {
-                true
-            };
-
+        let (keys, rows) = self.decoded_rows_with_keys(table_name, &table_storage)?;
+        let matches = filter.matches(&rows, table_name, &types)?;
+        for ((key, row_data), should_delete) in keys.into_iter().zip(rows).zip(matches) {
             if should_delete {
                 keys_to_delete.push(key);
                 deleted_count += 1;
+                if stmt.returning.is_some() {
+                    returned.push((row_data.clone(), None));
+                }
+                if let Some(changed_rows) = &mut changed_rows {
+                    changed_rows.push(row_data);
+                }
             }
         }
 
         // Delete the keys
+        let mut batch = WriteBatch::new();
         for key in keys_to_delete {
-            table_storage.delete(key)
-                .map_err(|e| format!("Storage error: {}", e))?;
+            batch.delete(key);
         }
+        let change = self.change(table_name, ChangeKind::Delete, changed_rows)?;
+        let written = self.write_batch(table_name, &mut table_storage, batch, change)?;
+        drop(table_storage);
+        self.finish_write(written)?;
 
-        Ok(format!("Deleted {} rows", deleted_count))
+        record_write(deleted_count, None);
+        if let Some(returning) = &stmt.returning {
+            return returned_rows(returning, table_name, &columns, Image::Old, &returned).map(StatementResult::Rows);
+        }
+        Ok(StatementResult::Changed {
+            message: format!("Deleted {} rows", deleted_count),
+            rows_affected: deleted_count,
+            last_insert_rowid: None,
+        })
     }
 
-    fn execute_create_table(&mut self, stmt: CreateTableStatement) -> Result<String, String> {
+    fn execute_create_table(&self, stmt: CreateTableStatement) -> Result<String, String> {
         let table_name = &stmt.table;
-        
-        // Create table storage
-        let table_storage = LSMStorage::new(&self.data_dir, table_name)
-            .map_err(|e| format!("Failed to create table storage: {}", e))?;
-        
-        self.tables.insert(table_name.clone(), table_storage);
-
-        // Store schema
-        let columns: Vec<String> = stmt.columns.iter().map(|col| col.0.clone()).collect();
-        self.table_schemas.insert(table_name.clone(), columns.clone());
-
-        // Persist schema to disk
-        let mut schema_storage = LSMStorage::new(&self.data_dir, &format!("{}_schema", table_name))
-            .map_err(|e| format!("Failed to create schema storage: {}", e))?;
-        
-        let schema_json = serde_json::to_string(&columns)
-            .map_err(|e| format!("Failed to serialize schema: {}", e))?;
-        
-        schema_storage.insert("schema".to_string(), schema_json)
-            .map_err(|e| format!("Failed to store schema: {}", e))?;
-
-        Ok(format!("Created table '{}'", table_name))
-    }
 
-    fn execute_alter_table(&mut self, stmt: AlterTableStatement) -> Result<String, String> {
-        let table_name = &stmt.table;
-        
-        // For now, we'll just acknowledge the alter table command
-        // In a full implementation, you'd need to handle schema changes
-        match &stmt.action {
-            AlterAction::AddColumn(column_name) => {
-                Ok(format!("Added column '{}' to table '{}'", column_name, table_name))
+        if table_name == CATALOG_DIR {
+            return Err(format!("Table name '{}' is reserved", table_name));
+        }
+        for (_, data_type) in &stmt.columns {
+            DecimalType::of(data_type)?;
+        }
+        if let Some(primary_key) = &stmt.primary_key {
+            if !stmt.columns.iter().any(|(name, _)| name == primary_key) {
+                return Err(format!("Primary key column '{}' is not defined in table '{}'", primary_key, table_name));
             }
-            AlterAction::DropColumn(column_name) => {
-                Ok(format!("Dropped column '{}' from table '{}'", column_name, table_name))
+        }
+        let (directory, options): (Vec<_>, Vec<_>) = stmt.options.iter().cloned()
+            .partition(|(name, _)| name == "directory");
+        let storage = if options.is_empty() {
+            None
+        } else {
+            Some(apply_storage_options(self.options.storage, &options)?)
+        };
+        let (partitions, split_at) = match &stmt.partition_by {
+            Some(partition_by) if stmt.primary_key.as_ref() != Some(&partition_by.column) => {
+                return Err(format!("Table '{}' can only be partitioned by its primary key", table_name));
             }
-            AlterAction::ModifyColumn(column_name, _) => {
-                Ok(format!("Modified column '{}' in table '{}'", column_name, table_name))
+            Some(partition_by) if partition_by.partitions > MAX_PARTITIONS => {
+                return Err(format!("A table can have at most {} partitions", MAX_PARTITIONS));
+            }
+            Some(partition_by) => {
+                let data_type = stmt.columns.iter()
+                    .find(|(name, _)| *name == partition_by.column)
+                    .map(|(_, data_type)| data_type.as_str())
+                    .unwrap_or_default();
+                let split_at = partition_by.split_at.iter()
+                    .map(|value| convert_value(value, data_type))
+                    .collect::<Result<Vec<_>, _>>()?;
+                (Some(partition_by.partitions), split_at)
+            }
+            None => (None, Vec::new()),
+        };
+        
+        let table = TableDef {
+            name: table_name.clone(),
+            columns: stmt.columns.iter()
+                .map(|(name, data_type)| ColumnDef { name: name.clone(), data_type: data_type.clone() })
+                .collect(),
+            primary_key: stmt.primary_key.clone(),
+            storage,
+            partitions,
+            split_at,
+        };
+        table.validate()?;
+        let partitioning = partitioning(&table)?;
+        if let Some(Partitioning::Range(bounds)) = &partitioning {
+            if bounds.windows(2).any(|pair| pair[0] >= pair[1]) {
+                return Err("The values a table is split at must be in ascending order".to_string());
             }
         }
+
+        // Create table storage, holding the lock on open tables until the
+        // catalog records the table so two CREATEs of one name can't both
+        // succeed. A directory for a table the catalog doesn't know about
+        // was left behind by a CREATE or DROP TABLE that crashed half way,
+        // so its contents are stale.
+        let mut tables = self.tables.write().map_err(lock_err)?;
+        if self.catalog.read().map_err(lock_err)?.table(table_name).is_some() {
+            return Err(format!("Table '{}' already exists", table_name));
+        }
+        let table_dir = self.data_dir.join(table_name);
+        remove_table_dir(&table_dir)?;
+        if let Some((_, directory)) = directory.last() {
+            link_table_dir(directory, &table_dir)?;
+        }
+        let mut table_storage = PartitionedStorage::open(&self.data_dir, table_name, partitioning.as_ref())
+            .map_err(|e| format!("Failed to create table storage: {}", e))?;
+        self.configure_storage(&mut table_storage, storage)?;
+        self.catalog.write().map_err(lock_err)?.create_table(table)?;
+        tables.insert(table_name.clone(), Arc::new(RwLock::new(table_storage)));
+        drop(tables);
+
+        Ok(format!("Created table '{}'", table_name))
     }
 
-    fn execute_drop_table(&mut self, stmt: DropTableStatement) -> Result<String, String> {
+    fn execute_alter_table(&self, stmt: AlterTableStatement) -> Result<String, String> {
         let table_name = &stmt.table;
-        
-        // Remove from memory
-        self.tables.remove(table_name);
-        self.table_schemas.remove(table_name);
 
-        // Remove from disk
-        let table_dir = self.data_dir.join(table_name);
-        if table_dir.exists() {
-            fs::remove_dir_all(&table_dir)
-                .map_err(|e| format!("Failed to remove table directory: {}", e))?;
+        // Schema changes take effect immediately, so they can't be rolled back
+        if self.in_transaction() {
+            return Err("ALTER TABLE cannot be used inside a transaction".to_string());
         }
 
-        Ok(format!("Dropped table '{}'", table_name))
-    }
+        // Hold the table's write lock while rows are rewritten
+        let table_storage = self.table(table_name)?;
+        let mut table_storage = table_storage.write().map_err(lock_err)?;
+        let mut table = self.catalog.read().map_err(lock_err)?
+            .table(table_name)
+            .cloned()
+            .ok_or_else(|| format!("Table '{}' not found", table_name))?;
+        let primary_key = self.primary_key(table_name)?;
 
-    // Helper methods for JOIN operations
-    fn perform_join(&self, left_rows: &[HashMap<String, String>], 
-                   right_rows: &[HashMap<String, String>], 
-                   join: &JoinClause, table_name: &str) -> Result<Vec<HashMap<String, String>>, String> {
-        let mut result = Vec::new();
-        let left_col = join.on_left.split('.').last().unwrap();
-        let right_col = join.on_right.split('.').last().unwrap();
-
-        match join.join_type {
-            JoinType::Inner => {
-                for lrow in left_rows {
-                    for rrow in right_rows {
-                        if lrow.get(left_col) == rrow.get(right_col) {
-                            let mut combined = HashMap::new();
-                            // Add left table columns with table prefix (use the main table name)
-                            for (k, v) in lrow {
-                                combined.insert(format!("{}.{}", table_name, k), v.clone());
-                            }
-                            // Add right table columns with table prefix
-                            for (k, v) in rrow {
-                                combined.insert(format!("{}.{}", join.table, k), v.clone());
-                            }
-                            result.push(combined);
-                        }
-                    }
+        match &stmt.action {
+            AlterAction::AddColumn(column_name, data_type) => {
+                if table.column(column_name).is_some() {
+                    return Err(format!("Column '{}' already exists in table '{}'", column_name, table_name));
                 }
-            }
-            JoinType::Left => {
-                for lrow in left_rows {
-                    let mut matched = false;
-                    for rrow in right_rows {
-                        if lrow.get(left_col) == rrow.get(right_col) {
-                            let mut combined = HashMap::new();
-                            // Add left table columns with table prefix
-                            for (k, v) in lrow {
-                                combined.insert(format!("{}.{}", table_name, k), v.clone());
-                            }
-                            // Add right table columns with table prefix
-                            for (k, v) in rrow {
-                                combined.insert(format!("{}.{}", join.table, k), v.clone());
-                            }
-                            result.push(combined);
-                            matched = true;
-                        }
-                    }
-                    if !matched {
-                        let mut combined = HashMap::new();
-                        // Add left table columns with table prefix
-                        for (k, v) in lrow {
-                            combined.insert(format!("{}.{}", table_name, k), v.clone());
-                        }
-                        // Add NULL values for right table columns
-                        if !right_rows.is_empty() {
-                            for k in right_rows[0].keys() {
-                                combined.insert(format!("{}.{}", join.table, k), "NULL".to_string());
-                            }
-                        }
-                        result.push(combined);
-                    }
+                if let Some(data_type) = data_type {
+                    DecimalType::of(data_type)?;
                 }
+                // Existing rows aren't touched; reads fill the new column with NULL
+                table.columns.push(ColumnDef {
+                    name: column_name.clone(),
+                    data_type: data_type.clone().unwrap_or_else(|| "TEXT".to_string()),
+                });
+                self.catalog.write().map_err(lock_err)?.put_table(table)?;
+                Ok(format!("Added column '{}' to table '{}'", column_name, table_name))
             }
-            JoinType::Right => {
-                for rrow in right_rows {
-                    let mut matched = false;
-                    for lrow in left_rows {
-                        if lrow.get(left_col) == rrow.get(right_col) {
-                            let mut combined = HashMap::new();
-                            // Add left table columns with table prefix
-                            for (k, v) in lrow {
-                                combined.insert(format!("{}.{}", table_name, k), v.clone());
-                            }
-                            // Add right table columns with table prefix
-                            for (k, v) in rrow {
-                                combined.insert(format!("{}.{}", join.table, k), v.clone());
-                            }
-                            result.push(combined);
-                            matched = true;
-                        }
-                    }
-                    if !matched {
-                        let mut combined = HashMap::new();
-                        // Add NULL values for left table columns
-                        if !left_rows.is_empty() {
-                            for k in left_rows[0].keys() {
-                                combined.insert(format!("{}.{}", table_name, k), "NULL".to_string());
-                            }
-                        }
-                        // Add right table columns with table prefix
-                        for (k, v) in rrow {
-                            combined.insert(format!("{}.{}", join.table, k), v.clone());
-                        }
-                        result.push(combined);
+            AlterAction::DropColumn(column_name) => {
+                if table.column(column_name).is_none() {
+                    return Err(format!("Column '{}' not found in table '{}'", column_name, table_name));
+                }
+                if primary_key.is_some_and(|pk| pk.column == *column_name) {
+                    return Err(format!("Cannot drop primary key column '{}'", column_name));
+                }
+
+                // Rewrite every row without the column, so re-adding a column of
+                // the same name later doesn't resurrect old values
+                let mut batch = WriteBatch::new();
+                for row in self.visible_rows(table_name, &table_storage)? {
+                    let (key, value) = row?;
+                    let mut row_data = decode_row_json(&value)?;
+                    if row_data.remove(column_name).is_some() {
+                        let row_json = encode_row(&row_data)?;
+                        batch.put(key, row_json);
                     }
                 }
+                self.finish_write(self.write_batch(table_name, &mut table_storage, batch, None)?)?;
+
+                table.columns.retain(|c| c.name != *column_name);
+                self.catalog.write().map_err(lock_err)?.put_table(table)?;
+                Ok(format!("Dropped column '{}' from table '{}'", column_name, table_name))
             }
-            JoinType::Full => {
-                // Implementation similar to LEFT + RIGHT join
-                result.extend(self.perform_join(left_rows, right_rows, &JoinClause {
-                    join_type: JoinType::Left,
-                    table: join.table.clone(),
-                    on_left: join.on_left.clone(),
-                    on_right: join.on_right.clone(),
-                }, table_name)?);
-            }
-            JoinType::Cross => {
-                for lrow in left_rows {
-                    for rrow in right_rows {
-                        let mut combined = HashMap::new();
-                        // Add left table columns with table prefix
-                        for (k, v) in lrow {
-                            combined.insert(format!("{}.{}", table_name, k), v.clone());
-                        }
-                        // Add right table columns with table prefix
-                        for (k, v) in rrow {
-                            combined.insert(format!("{}.{}", join.table, k), v.clone());
-                        }
-                        result.push(combined);
+            AlterAction::ModifyColumn(column_name, new_type) => {
+                let Some(column) = table.columns.iter_mut().find(|c| c.name == *column_name) else {
+                    return Err(format!("Column '{}' not found in table '{}'", column_name, table_name));
+                };
+                // The key encoding depends on the key column's type
+                if primary_key.is_some_and(|pk| pk.column == *column_name) {
+                    return Err(format!("Cannot modify primary key column '{}'", column_name));
+                }
+
+                // Convert every value first so a bad one leaves the table untouched
+                let mut batch = WriteBatch::new();
+                for row in self.visible_rows(table_name, &table_storage)? {
+                    let (key, value) = row?;
+                    let mut row_data = decode_row_json(&value)?;
+                    let Some(old_value) = row_data.get(column_name) else {
+                        continue;
+                    };
+                    let new_value = convert_value(old_value, new_type).map_err(|e| {
+                        format!("Cannot convert column '{}' to {}: {}", column_name, new_type, e)
+                    })?;
+                    if new_value != *old_value {
+                        row_data.insert(column_name.clone(), new_value);
+                        let row_json = encode_row(&row_data)?;
+                        batch.put(key, row_json);
                     }
                 }
+                self.finish_write(self.write_batch(table_name, &mut table_storage, batch, None)?)?;
+
+                column.data_type = new_type.clone();
+                self.catalog.write().map_err(lock_err)?.put_table(table)?;
+                Ok(format!("Modified column '{}' in table '{}'", column_name, table_name))
+            }
+            AlterAction::SetOptions(options) => {
+                let config = apply_storage_options(table_storage.storage_config(), options)?;
+                table_storage.set_storage_config(config);
+                table.storage = Some(config);
+                self.catalog.write().map_err(lock_err)?.put_table(table)?;
+                Ok(format!("Updated storage options of table '{}'", table_name))
+            }
+        }
+    }
+
+    // Parses a stored row, filling columns added by ALTER TABLE after the row
+    // was written with NULL.
+    fn decode_row(&self, table_name: &str, value: &str) -> Result<HashMap<String, String>, String> {
+        slow_query::count_row_examined();
+        let mut row_data = decode_row_json(value)?;
+        if let Some(table) = self.catalog.read().map_err(lock_err)?.table(table_name) {
+            for column in table.column_names() {
+                row_data.entry(column).or_insert_with(|| NULL.to_string());
             }
         }
+        Ok(row_data)
+    }
 
-        Ok(result)
+    fn execute_drop_table(&self, stmt: DropTableStatement) -> Result<String, String> {
+        let table_name = &stmt.table;
+        
+        // Removing the catalog record is the atomic step: once it's written the
+        // table no longer exists on restart, and a directory left behind by a
+        // crash is cleaned up when a table of the same name is created.
+        let mut tables = self.tables.write().map_err(lock_err)?;
+        let table_storage = tables.remove(table_name);
+        self.catalog.write().map_err(lock_err)?.remove_table(table_name)?;
+        drop(tables);
+
+        // Wait for in-flight statements on the table before deleting its files
+        let _table_guard = match &table_storage {
+            Some(table_storage) => Some(table_storage.write().map_err(lock_err)?),
+            None => None,
+        };
+        if let Some(tx) = self.transactions.lock().map_err(lock_err)?.get_mut(&current_session()) {
+            tx.writes.retain(|w| w.table != *table_name);
+        }
+
+        remove_table_dir(&self.data_dir.join(table_name))?;
+
+        Ok(format!("Dropped table '{}'", table_name))
     }
 
-    fn apply_where_clause(&self, rows: Vec<HashMap<String, String>>, 
-                         where_clause: &WhereClause) -> Result<Vec<HashMap<String, String>>, String> {
+    // Helper methods for JOIN operations
+    fn apply_where_clause(&self, rows: Vec<HashMap<String, String>>, where_clause: &WhereClause,
+                          types: &ColumnTypes) -> Result<Vec<HashMap<String, String>>, String> {
         let mut filtered_rows = Vec::new();
         
         for row in rows {
-            if Self::evaluate_where_condition(&row, where_clause)? {
+            if Self::evaluate_where_condition(&row, where_clause, types)? {
                 filtered_rows.push(row);
             }
         }
@@ -446,13 +1929,72 @@ impl PersistentDatabase {
         Ok(filtered_rows)
     }
 
-    fn evaluate_where_condition(row: &HashMap<String, String>, 
-                               where_clause: &WhereClause) -> Result<bool, String> {
+    fn evaluate_where_condition(row: &HashMap<String, String>, where_clause: &WhereClause,
+                                types: &ColumnTypes) -> Result<bool, String> {
         let left_value = row.get(&where_clause.column)
             .ok_or_else(|| format!("Column '{}' not found", where_clause.column))?;
+
+        // A function's value is tested as a column of its own, which compares
+        // as a DECIMAL if the function keeps its column's values exact
+        if let Some(call) = &where_clause.function {
+            let name = call.name();
+            let mut computed = ColumnTypes::default();
+            if let Some(&decimal) = types.decimals.get(&where_clause.column).filter(|_| call.function != MathFunction::Power) {
+                computed.decimals.insert(name.clone(), decimal);
+            }
+            let clause = WhereClause { column: name.clone(), function: None, ..where_clause.clone() };
+            return Self::evaluate_where_condition(&HashMap::from([(name, math::apply(call, left_value))]), &clause, &computed);
+        }
         
+        // A comparison with NULL never holds; only IS NULL finds it
         let right_value = &where_clause.value;
+        if left_value == NULL || right_value == NULL {
+            return Ok(match where_clause.operator.as_str() {
+                "IS" => left_value == NULL,
+                "IS NOT" => left_value != NULL,
+                _ => false,
+            });
+        }
+
+        // IN holds if the column equals any of the values, as `=` compares them
+        if let Some(InList::Values(values)) = &where_clause.in_list {
+            if types.decimals.contains_key(&where_clause.column) {
+                let left: Decimal = left_value.parse()?;
+                return Ok(values.iter().any(|value| value.parse::<Decimal>().is_ok_and(|value| value == left)));
+            }
+            if types.booleans.contains(&where_clause.column) {
+                for value in values {
+                    if convert_value(value, "BOOLEAN")? == *left_value {
+                        return Ok(true);
+                    }
+                }
+                return Ok(false);
+            }
+            return Ok(values.contains(left_value));
+        }
         
+        if types.booleans.contains(&where_clause.column) && matches!(where_clause.operator.as_str(), "=" | "!=") {
+            let right = convert_value(right_value, "BOOLEAN")?;
+            return Ok((*left_value == right) == (where_clause.operator == "="));
+        }
+        if types.decimals.contains_key(&where_clause.column) {
+            let left: Decimal = left_value.parse()?;
+            let right: Decimal = right_value.parse()?;
+            return match where_clause.operator.as_str() {
+                "=" => Ok(left == right),
+                "!=" => Ok(left != right),
+                ">" => Ok(left > right),
+                "<" => Ok(left < right),
+                "BETWEEN" => {
+                    let upper: Decimal = where_clause.upper_bound.as_ref()
+                        .ok_or("BETWEEN requires an upper bound")?
+                        .parse()?;
+                    Ok(right <= left && left <= upper)
+                }
+                _ => Err(format!("Unsupported operator: {}", where_clause.operator)),
+            };
+        }
+
         match where_clause.operator.as_str() {
             "=" => Ok(left_value == right_value),
             "!=" => Ok(left_value != right_value),
@@ -466,23 +2008,32 @@ impl PersistentDatabase {
                 let right_num: f64 = right_value.parse().map_err(|_| "Invalid number")?;
                 Ok(left_num < right_num)
             }
+            "BETWEEN" => {
+                let upper_value = where_clause.upper_bound.as_ref()
+                    .ok_or("BETWEEN requires an upper bound")?;
+                let bounds = (left_value.parse::<f64>(), right_value.parse::<f64>(), upper_value.parse::<f64>());
+                if let (Ok(left_num), Ok(low), Ok(high)) = bounds {
+                    Ok(low <= left_num && left_num <= high)
+                } else {
+                    Ok(right_value <= left_value && left_value <= upper_value)
+                }
+            }
             _ => Err(format!("Unsupported operator: {}", where_clause.operator)),
         }
     }
 
-    fn apply_group_by(&self, rows: Vec<HashMap<String, String>>, 
-                     group_by: &[String], 
-                     columns: &[ColumnExpr]) -> Result<Vec<HashMap<String, String>>, String> {
+    fn apply_group_by(&self, rows: Vec<HashMap<String, String>>, group_by: &[String], columns: &[ColumnExpr],
+                      types: &ColumnTypes) -> Result<Vec<HashMap<String, String>>, String> {
         // Simple grouping implementation
         let mut groups: HashMap<String, Vec<HashMap<String, String>>> = HashMap::new();
         
         for row in rows {
             let group_key: String = group_by.iter()
-                .map(|col| row.get(col).unwrap_or(&"NULL".to_string()).clone())
+                .map(|col| row.get(col).unwrap_or(&NULL.to_string()).clone())
                 .collect::<Vec<_>>()
                 .join("|");
             
-            groups.entry(group_key).or_insert_with(Vec::new).push(row);
+            groups.entry(group_key).or_default().push(row);
         }
         
         let mut result = Vec::new();
@@ -492,14 +2043,34 @@ impl PersistentDatabase {
                 
                 // Apply aggregate functions
                 for col_expr in columns {
-                    match col_expr {
-                        ColumnExpr::Column(name) => {
+                    match col_expr.unaliased() {
+                        ColumnExpr::Column(_) | ColumnExpr::Length(_) | ColumnExpr::Math(_) => {
                             // Keep the first value for grouping columns
                         }
                         ColumnExpr::Count(col_name) => {
-                            let count = group_rows.len() as f64;
+                            let count = group_rows.iter().filter(|row| row.get(col_name).is_some_and(|v| v != NULL)).count() as f64;
                             aggregated_row.insert(format!("COUNT({})", col_name), count.to_string());
                         }
+                        ColumnExpr::Sum(col_name) if types.decimals.contains_key(col_name) => {
+                            let sum = decimal_sum(&group_rows, col_name, &types.decimals[col_name])?;
+                            aggregated_row.insert(format!("SUM({})", col_name), sum.to_string());
+                        }
+                        ColumnExpr::Avg(col_name) if types.decimals.contains_key(col_name) => {
+                            let sum = decimal_sum(&group_rows, col_name, &types.decimals[col_name])?;
+                            let count = decimal_values(&group_rows, col_name).count() as i128;
+                            let scale = sum.scale().max(MIN_DECIMAL_AVG_SCALE);
+                            let avg = if count == 0 { Some(Decimal::zero(scale)) } else { sum.checked_div(count, scale) }
+                                .ok_or_else(|| format!("AVG({}) is too large for a DECIMAL", col_name))?;
+                            aggregated_row.insert(format!("AVG({})", col_name), avg.to_string());
+                        }
+                        ColumnExpr::Min(col_name) if types.decimals.contains_key(col_name) => {
+                            let min = decimal_values(&group_rows, col_name).min();
+                            aggregated_row.insert(format!("MIN({})", col_name), min.map_or(NULL.to_string(), |m| m.to_string()));
+                        }
+                        ColumnExpr::Max(col_name) if types.decimals.contains_key(col_name) => {
+                            let max = decimal_values(&group_rows, col_name).max();
+                            aggregated_row.insert(format!("MAX({})", col_name), max.map_or(NULL.to_string(), |m| m.to_string()));
+                        }
                         ColumnExpr::Sum(col_name) => {
                             let values: Vec<f64> = group_rows.iter()
                                 .filter_map(|row| row.get(col_name).and_then(|v| v.parse().ok()))
@@ -532,7 +2103,7 @@ impl PersistentDatabase {
                             let count = group_rows.len() as f64;
                             aggregated_row.insert("COUNT(*)".to_string(), count.to_string());
                         }
-                        ColumnExpr::All => {
+                        ColumnExpr::All | ColumnExpr::Aliased(..) => {
                             // Keep all columns as is
                         }
                     }
@@ -545,34 +2116,47 @@ impl PersistentDatabase {
         Ok(result)
     }
 
-    fn apply_having(&self, rows: Vec<HashMap<String, String>>, 
-                   having: &HavingClause) -> Result<Vec<HashMap<String, String>>, String> {
+    // Keeps the groups whose value of `key`, HAVING's expression or the one
+    // its alias names, meets the condition
+    fn apply_having(&self, rows: Vec<HashMap<String, String>>, key: &ColumnExpr, having: &HavingClause,
+                    types: &ColumnTypes) -> Result<Vec<HashMap<String, String>>, String> {
         let mut filtered_rows = Vec::new();
         
         for row in rows {
-            // For simplicity, we'll extract the column name from the column_expr
-            let column_name = match &having.column_expr {
-                ColumnExpr::Column(name) => name.clone(),
-                ColumnExpr::Count(name) => format!("COUNT({})", name),
-                ColumnExpr::Sum(name) => format!("SUM({})", name),
-                ColumnExpr::Avg(name) => format!("AVG({})", name),
-                ColumnExpr::Min(name) => format!("MIN({})", name),
-                ColumnExpr::Max(name) => format!("MAX({})", name),
-                ColumnExpr::CountAll => "COUNT(*)".to_string(),
-                ColumnExpr::All => "ALL".to_string(),
-            };
+            // Aggregates were computed into the row under their names
+            let column_name = key.name();
             
             let value = row.get(&column_name)
                 .ok_or_else(|| format!("Column '{}' not found", column_name))?;
+
+            // Every aggregate but COUNT of a DECIMAL column is a DECIMAL
+            let decimal = match key {
+                ColumnExpr::Column(name) | ColumnExpr::Sum(name) | ColumnExpr::Avg(name)
+                | ColumnExpr::Min(name) | ColumnExpr::Max(name) => types.decimals.contains_key(name) && value != NULL,
+                _ => false,
+            };
+            if decimal {
+                let value: Decimal = value.parse()?;
+                let threshold: Decimal = having.value.parse()?;
+                let condition_met = match having.operator.as_str() {
+                    ">" => value > threshold,
+                    "<" => value < threshold,
+                    "=" => value == threshold,
+                    "!=" => value != threshold,
+                    _ => return Err(format!("Unsupported operator: {}", having.operator)),
+                };
+                if condition_met {
+                    filtered_rows.push(row);
+                }
+                continue;
+            }
             
-            let num_value: f64 = value.parse().map_err(|_| "Invalid number")?;
-            let threshold: f64 = having.value.parse().map_err(|_| "Invalid number")?;
-            
+            let ordering = compare_values(value, &having.value);
             let condition_met = match having.operator.as_str() {
-                ">" => num_value > threshold,
-                "<" => num_value < threshold,
-                "=" => num_value == threshold,
-                "!=" => num_value != threshold,
+                ">" => ordering == std::cmp::Ordering::Greater,
+                "<" => ordering == std::cmp::Ordering::Less,
+                "=" => ordering == std::cmp::Ordering::Equal,
+                "!=" => ordering != std::cmp::Ordering::Equal,
                 _ => return Err(format!("Unsupported operator: {}", having.operator)),
             };
             
@@ -584,219 +2168,810 @@ impl PersistentDatabase {
         Ok(filtered_rows)
     }
 
-    fn apply_order_by(&self, mut rows: Vec<HashMap<String, String>>, 
-                     order_by: &OrderByClause) -> Result<Vec<HashMap<String, String>>, String> {
-        let empty = "".to_string();
-        rows.sort_by(|a, b| {
-            let a_val = a.get(&order_by.column).unwrap_or(&empty);
-            let b_val = b.get(&order_by.column).unwrap_or(&empty);
-            
-            if order_by.descending {
-                b_val.cmp(a_val)
-            } else {
-                a_val.cmp(b_val)
-            }
+    // Sorts by a column's text, or by the value of an aggregate, which was
+    // computed into each row under its name
+    fn apply_order_by(&self, rows: Vec<HashMap<String, String>>, key: &ColumnExpr, descending: bool,
+                      types: &ColumnTypes) -> Result<Vec<HashMap<String, String>>, String> {
+        let value = |row: &HashMap<String, String>| match key {
+            ColumnExpr::Length(column) => blob::length(row.get(column).map_or(NULL, String::as_str)),
+            ColumnExpr::Math(call) => math::value_of(call, row),
+            ColumnExpr::Column(column) => row.get(column).cloned().unwrap_or_default(),
+            aggregated => row.get(&aggregated.name()).cloned().unwrap_or_default(),
+        };
+
+        // DECIMALs, and aggregates of them but COUNT, sort by value, with NULLs lowest
+        let decimal = match key {
+            ColumnExpr::Column(column) | ColumnExpr::Sum(column) | ColumnExpr::Avg(column)
+            | ColumnExpr::Min(column) | ColumnExpr::Max(column) => types.decimals.contains_key(column),
+            _ => false,
+        };
+        if decimal {
+            let mut keyed: Vec<_> = rows.into_iter()
+                .map(|row| (value(&row).parse::<Decimal>().ok(), row))
+                .collect();
+            keyed.sort_by(|a, b| if descending { b.0.cmp(&a.0) } else { a.0.cmp(&b.0) });
+            return Ok(keyed.into_iter().map(|(_, row)| row).collect());
+        }
+
+        let mut keyed: Vec<_> = rows.into_iter().map(|row| (value(&row), row)).collect();
+        keyed.sort_by(|(a, _), (b, _)| {
+            let ordering = match key {
+                ColumnExpr::Column(_) => a.cmp(b),
+                _ => compare_values(a, b),
+            };
+            if descending { ordering.reverse() } else { ordering }
         });
-        
-        Ok(rows)
+        Ok(keyed.into_iter().map(|(_, row)| row).collect())
     }
 
-    fn format_select_result(&self, rows: &[HashMap<String, String>], 
-                           columns: &[ColumnExpr], table_name: &str) -> Result<String, String> {
-        if rows.is_empty() {
-            return Ok("No matching rows found".to_string());
+    // Picks the selected columns out of each row; SELECT * picks those of
+    // `schema`.
+    fn select_result(&self, rows: &[HashMap<String, String>], columns: &[ColumnExpr],
+                     schema: Option<Vec<String>>) -> Result<ResultSet, String> {
+        // Without a schema, SELECT * shows the first row's columns, sorted
+        let schema = schema.unwrap_or_else(|| {
+            let mut columns: Vec<String> = rows.first()
+                .map_or_else(Vec::new, |row| row.keys().filter(|k| !k.contains('.')).cloned().collect());
+            columns.sort();
+            columns
+        });
+        let result: Vec<Vec<String>> = rows.iter().map(|row| result_row(row, columns, &schema)).collect();
+        slow_query::set_rows_returned(result.len());
+        Ok(ResultSet { columns: result_headers(columns, &schema), rows: result, meta: Vec::new() })
+    }
+
+    /// The definitions of all tables, by name.
+    pub fn tables(&self) -> Result<Vec<TableDef>, String> {
+        if self.options.read_only {
+            self.refresh()?;
         }
-        
-        let mut result = String::new();
-        
-        // Print headers
-        let headers: Vec<String> = if columns.len() == 1 && matches!(columns[0], ColumnExpr::All) {
-            // For SELECT *, show all column names
-            if let Some(schema) = self.table_schemas.get(table_name) {
-                schema.clone()
-            } else {
-                vec!["*".to_string()]
+        Ok(self.catalog.read().map_err(lock_err)?.tables().cloned().collect())
+    }
+
+    /// Compacts `table`, or every table, in full; see `LSMStorage::compact`.
+    /// Returns the bytes of storage reclaimed.
+    pub fn vacuum(&self, table: Option<&str>) -> Result<u64, String> {
+        self.check_writable()?;
+        if self.in_transaction() {
+            return Err("VACUUM cannot run inside a transaction".to_string());
+        }
+        let tables = match table {
+            Some(table) => vec![table.to_string()],
+            None => self.catalog.read().map_err(lock_err)?.tables().map(|t| t.name.clone()).collect(),
+        };
+        let mut reclaimed = 0;
+        for table_name in tables {
+            let table_storage = self.table(&table_name)?;
+            reclaimed += table_storage.write().map_err(lock_err)?.compact()
+                .map_err(|e| format!("Failed to compact table '{}': {}", table_name, e))?;
+        }
+        Ok(reclaimed)
+    }
+
+    /// Gathers the row count of `table`, or of every table, and the nulls,
+    /// distinct values and smallest and largest value of each of its
+    /// columns into the catalog, where `stats('table')` reads them. Returns
+    /// the number of tables analyzed.
+    pub fn analyze(&self, table: Option<&str>) -> Result<usize, String> {
+        self.check_writable()?;
+        if self.in_transaction() {
+            return Err("ANALYZE cannot run inside a transaction".to_string());
+        }
+        let tables: Vec<TableDef> = {
+            let catalog = self.catalog.read().map_err(lock_err)?;
+            match table {
+                Some(table) => vec![catalog.table(table).cloned().ok_or_else(|| format!("Table '{}' not found", table))?],
+                None => catalog.tables().cloned().collect(),
             }
-        } else {
-            columns.iter().map(|col| {
-                match col {
-                    ColumnExpr::Column(name) => name.clone(),
-                    ColumnExpr::Count(name) => format!("COUNT({})", name),
-                    ColumnExpr::Sum(name) => format!("SUM({})", name),
-                    ColumnExpr::Avg(name) => format!("AVG({})", name),
-                    ColumnExpr::Min(name) => format!("MIN({})", name),
-                    ColumnExpr::Max(name) => format!("MAX({})", name),
-                    ColumnExpr::CountAll => "COUNT(*)".to_string(),
-                    ColumnExpr::All => "*".to_string(),
-                }
-            }).collect()
         };
-        
-        result.push_str(&headers.join(" | "));
-        result.push('\n');
-        result.push_str(&"-".repeat(result.len()));
-        result.push('\n');
-        
-        // Print rows
-        for row in rows {
-            let values: Vec<String> = if columns.len() == 1 && matches!(columns[0], ColumnExpr::All) {
-                // For SELECT *, show all column values in schema order
-                if let Some(schema) = self.table_schemas.get(table_name) {
-                    schema.iter()
-                        .map(|col_name| row.get(col_name).unwrap_or(&"NULL".to_string()).clone())
-                        .collect()
-                } else {
-                    // Fallback: show all values in the row
-                    row.values().cloned().collect()
+        for table in &tables {
+            let rows = self.decoded_rows(&table.name)?;
+            let columns = table.columns.iter().map(|column| column_stats(&column.name, &rows)).collect();
+            let stats = TableStats { rows: rows.len() as u64, columns };
+            self.catalog.write().map_err(lock_err)?.put_stats(&table.name, stats)?;
+        }
+        Ok(tables.len())
+    }
+
+    // The rows a table function returns
+    fn table_function_rows(&self, function: &TableFunction) -> Result<Rows, String> {
+        match function {
+            TableFunction::Stats(table_name) => {
+                let catalog = self.catalog.read().map_err(lock_err)?;
+                let table = catalog.table(table_name).ok_or_else(|| format!("Table '{}' not found", table_name))?;
+                let stats = catalog.stats(table_name)
+                    .ok_or_else(|| format!("Table '{}' has no statistics; run ANALYZE {} first", table_name, table_name))?;
+                Ok(table.columns.iter().map(|column| {
+                    // A column added since ANALYZE ran was NULL in every row it saw
+                    let column_stats = stats.column(&column.name).cloned().unwrap_or_else(|| ColumnStats {
+                        name: column.name.clone(), nulls: stats.rows, distinct: 0, min: None, max: None,
+                    });
+                    let null = || NULL.to_string();
+                    let values = [
+                        column.name.clone(),
+                        stats.rows.to_string(),
+                        column_stats.nulls.to_string(),
+                        column_stats.distinct.to_string(),
+                        column_stats.min.unwrap_or_else(null),
+                        column_stats.max.unwrap_or_else(null),
+                    ];
+                    function.columns().iter().map(|c| c.to_string()).zip(values).collect()
+                }).collect())
+            }
+        }
+    }
+
+    /// I/O counters of every table opened since the database was, by table
+    /// name. Tables that haven't been used yet aren't listed.
+    pub fn storage_stats(&self) -> Result<BTreeMap<String, StorageStats>, String> {
+        let tables = self.tables.read().map_err(lock_err)?;
+        let mut stats = BTreeMap::new();
+        for (name, handle) in tables.iter() {
+            stats.insert(name.clone(), handle.read().map_err(lock_err)?.stats());
+        }
+        Ok(stats)
+    }
+
+    /// Row estimate, SSTable count, bytes on disk, WAL size and last
+    /// compaction of every table, by name.
+    pub fn table_status(&self) -> Result<BTreeMap<String, TableStatus>, String> {
+        let names: Vec<String> = self.catalog.read().map_err(lock_err)?.tables()
+            .map(|table| table.name.clone())
+            .collect();
+        let mut status = BTreeMap::new();
+        for name in names {
+            let table_storage = self.table(&name)?;
+            let table_status = table_storage.read().map_err(lock_err)?.status()
+                .map_err(|e| format!("Failed to read the status of '{}': {}", name, e))?;
+            status.insert(name, table_status);
+        }
+        Ok(status)
+    }
+
+    fn execute_show(&self, stmt: ShowStatement) -> Result<String, String> {
+        match stmt {
+            ShowStatement::Variable(name) => Err(format!("SHOW {} needs a session; run it through a Session", name)),
+            ShowStatement::TableStatus => {
+                let mut result = ["table", "rows", "sstables", "disk_bytes", "wal_bytes", "last_compaction"].join(" | ");
+                result.push('\n');
+                result.push_str(&"-".repeat(result.len()));
+                result.push('\n');
+                for (name, status) in self.table_status()? {
+                    let last_compaction = status.last_compaction
+                        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                        .map_or("NULL".to_string(), |since| since.as_millis().to_string());
+                    let values = [
+                        name,
+                        status.estimated_rows.to_string(),
+                        status.sstables.to_string(),
+                        status.disk_bytes.to_string(),
+                        status.wal_bytes.to_string(),
+                        last_compaction,
+                    ];
+                    result.push_str(&values.join(" | "));
+                    result.push('\n');
                 }
-            } else {
-                columns.iter().map(|col| {
-                    match col {
-                        ColumnExpr::Column(name) => {
-                            // For JOINs, handle both prefixed and unprefixed column names
-                            if let Some(value) = row.get(name) {
-                                // Direct match (e.g., "name" or "customers.name")
-                                value.clone()
-                            } else {
-                                // Try to find the column with table prefix
-                                let mut found = false;
-                                let mut result = "NULL".to_string();
-                                for (key, value) in row {
-                                    if key.ends_with(&format!(".{}", name)) {
-                                        result = value.clone();
-                                        found = true;
-                                        break;
-                                    }
-                                }
-                                if found { 
-                                    result 
-                                } else {
-                                    // Try to find the column without table prefix
-                                    let mut found = false;
-                                    let mut result = "NULL".to_string();
-                                    for (key, value) in row {
-                                        if key == name {
-                                            result = value.clone();
-                                            found = true;
-                                            break;
-                                        }
-                                    }
-                                    if found { result } else { "NULL".to_string() }
-                                }
-                            }
-                        },
-                        ColumnExpr::Count(name) => {
-                            row.get(&format!("COUNT({})", name))
-                                .unwrap_or(&"NULL".to_string())
-                                .clone()
-                        }
-                        ColumnExpr::Sum(name) => {
-                            row.get(&format!("SUM({})", name))
-                                .unwrap_or(&"NULL".to_string())
-                                .clone()
-                        }
-                        ColumnExpr::Avg(name) => {
-                            row.get(&format!("AVG({})", name))
-                                .unwrap_or(&"NULL".to_string())
-                                .clone()
-                        }
-                        ColumnExpr::Min(name) => {
-                            row.get(&format!("MIN({})", name))
-                                .unwrap_or(&"NULL".to_string())
-                                .clone()
-                        }
-                        ColumnExpr::Max(name) => {
-                            row.get(&format!("MAX({})", name))
-                                .unwrap_or(&"NULL".to_string())
-                                .clone()
-                        }
-                        ColumnExpr::CountAll => {
-                            row.get("COUNT(*)")
-                                .unwrap_or(&"NULL".to_string())
-                                .clone()
-                        }
-                        ColumnExpr::All => {
-                            // This shouldn't happen in the else branch, but just in case
-                            "*".to_string()
-                        }
-                    }
-                }).collect()
-            };
-            
-            result.push_str(&values.join(" | "));
-            result.push('\n');
+                Ok(result)
+            }
+            ShowStatement::StorageStats => {
+                let stats = self.storage_stats()?;
+                let mut total = StorageStats::default();
+                stats.values().for_each(|s| total.add(s));
+
+                let mut result = [
+                    "table", "wal_bytes", "flush_bytes", "compaction_read", "compaction_written",
+                    "query_read", "user_read", "flushes", "flush_ms", "compactions", "compaction_ms",
+                    "write_amp", "read_amp",
+                ].join(" | ");
+                result.push('\n');
+                result.push_str(&"-".repeat(result.len()));
+                result.push('\n');
+                for (name, s) in stats.iter().chain([(&"total".to_string(), &total)]) {
+                    let values = [
+                        name.clone(),
+                        s.wal_bytes_written.to_string(),
+                        s.flush_bytes_written.to_string(),
+                        s.compaction_bytes_read.to_string(),
+                        s.compaction_bytes_written.to_string(),
+                        s.query_bytes_read.to_string(),
+                        s.user_bytes_read.to_string(),
+                        s.flushes.to_string(),
+                        s.flush_time.as_millis().to_string(),
+                        s.compactions.to_string(),
+                        s.compaction_time.as_millis().to_string(),
+                        format!("{:.2}", s.write_amplification()),
+                        format!("{:.2}", s.read_amplification()),
+                    ];
+                    result.push_str(&values.join(" | "));
+                    result.push('\n');
+                }
+                Ok(result)
+            }
         }
-        
-        Ok(result)
     }
 
-    fn load_schemas(&mut self) -> Result<(), String> {
-        if !self.data_dir.exists() {
-            return Ok(());
+    fn execute_explain(&self, stmt: ExplainStatement) -> Result<String, String> {
+        let plan = self.explain(&stmt.select)?;
+        Ok(match stmt.format {
+            ExplainFormat::Text => plan.to_string(),
+            ExplainFormat::Json => plan.to_json(),
+        })
+    }
+
+    /// The steps a SELECT would run, as `EXPLAIN` reports them, without
+    /// running it.
+    pub fn explain(&self, stmt: &SelectStatement) -> Result<PlanNode, String> {
+        let table_name = &stmt.table;
+        if stmt.table_function.is_none() && self.catalog.read().map_err(lock_err)?.table(table_name).is_none() {
+            return Err(format!("Table '{}' not found", table_name));
         }
-        
-        for entry in fs::read_dir(&self.data_dir)
-            .map_err(|e| format!("Failed to read data directory: {}", e))? {
-            let entry = entry.map_err(|e| format!("Failed to read directory entry: {}", e))?;
-            let path = entry.path();
-            
-            if path.is_dir() {
-                let table_name = path.file_name()
-                    .and_then(|n| n.to_str())
-                    .ok_or_else(|| "Invalid table name".to_string())?;
-                
-                // Check if this is a schema directory
-                if table_name.ends_with("_schema") {
-                    let actual_table_name = table_name.trim_end_matches("_schema");
-                    let mut schema_storage = LSMStorage::new(&self.data_dir, table_name)
-                        .map_err(|e| format!("Failed to open schema storage: {}", e))?;
-                    
-                    if let Ok(Some(schema_json)) = schema_storage.get("schema") {
-                        let columns: Vec<String> = serde_json::from_str(&schema_json)
-                            .map_err(|e| format!("Failed to deserialize schema: {}", e))?;
-                        
-                        self.table_schemas.insert(actual_table_name.to_string(), columns);
-                        
-                        // Also initialize the table storage
-                        let table_storage = LSMStorage::new(&self.data_dir, actual_table_name)
-                            .map_err(|e| format!("Failed to open table storage: {}", e))?;
-                        self.tables.insert(actual_table_name.to_string(), table_storage);
-                    }
-                } else if !table_name.ends_with("_schema") && !self.tables.contains_key(table_name) {
-                    // Check if this is a regular table directory (not schema)
-                    // and we haven't already loaded it
-                    let schema_dir = format!("{}_schema", table_name);
-                    let schema_path = self.data_dir.join(&schema_dir);
-                    
-                    if schema_path.exists() {
-                        // This table has a schema, so it's a valid table
-                        let table_storage = LSMStorage::new(&self.data_dir, table_name)
-                            .map_err(|e| format!("Failed to open table storage: {}", e))?;
-                        self.tables.insert(table_name.to_string(), table_storage);
-                        
-                        // Load the schema if not already loaded
-                        if !self.table_schemas.contains_key(table_name) {
-                            let mut schema_storage = LSMStorage::new(&self.data_dir, &schema_dir)
-                                .map_err(|e| format!("Failed to open schema storage: {}", e))?;
-                            
-                            if let Ok(Some(schema_json)) = schema_storage.get("schema") {
-                                let columns: Vec<String> = serde_json::from_str(&schema_json)
-                                    .map_err(|e| format!("Failed to deserialize schema: {}", e))?;
-                                self.table_schemas.insert(table_name.to_string(), columns);
-                            }
-                        }
+        check_grouping(stmt)?;
+        let access = match stmt.table_function {
+            Some(_) => Access::default(),
+            None => self.plan_access(stmt)?,
+        };
+        let direction = |descending| if descending { "DESC" } else { "ASC" };
+        let scan = match (&access.lookup, &stmt.where_clause, &stmt.table_function) {
+            (_, _, Some(function)) => {
+                let args: Vec<String> = function.args().into_iter().map(sql_string).collect();
+                PlanNode::new("Function Scan").detail(format!("{}({})", function.name(), args.join(", ")))
+            }
+            (Some(KeyLookup::Point(_)), Some(where_clause), _) => {
+                PlanNode::new("Key Lookup").table(table_name).detail(where_clause.to_string())
+            }
+            (Some(KeyLookup::Range(..)), Some(where_clause), _) => {
+                PlanNode::new("Key Range Scan").table(table_name).detail(where_clause.to_string())
+            }
+            _ => match (access.key_order, &stmt.order_by) {
+                (Some(descending), Some(order_by)) => PlanNode::new("Key Order Scan").table(table_name)
+                    .detail(format!("{} {}", order_by.expr.name(), direction(descending))),
+                _ => PlanNode::new("Seq Scan").table(table_name),
+            },
+        };
+        let as_of = |scan: PlanNode| match stmt.as_of {
+            Some(timestamp) => scan.detail(format!("AS OF {}", timestamp)),
+            None => scan,
+        };
+        let scan = as_of(scan);
+        let mut plan = match &stmt.join {
+            Some(join) => join_plan(join, scan, &as_of, joins_right_first(stmt)),
+            None => scan,
+        };
+        if let Some(where_clause) = &stmt.where_clause {
+            plan = PlanNode::new("Filter").detail(where_clause.to_string()).over(plan);
+        }
+        if let Some(group_by) = &stmt.group_by {
+            plan = PlanNode::new("Aggregate").detail(format!("GROUP BY {}", group_by.join(", "))).over(plan);
+        } else if has_aggregates(&stmt.columns) {
+            plan = PlanNode::new("Aggregate").over(plan);
+        }
+        if let Some(having) = &stmt.having {
+            plan = PlanNode::new("Filter").detail(having.to_string()).over(plan);
+        }
+        if let Some(order_by) = stmt.order_by.as_ref().filter(|_| access.key_order.is_none()) {
+            let key = resolve_alias(&stmt.columns, &order_by.expr).name();
+            plan = PlanNode::new("Sort").detail(format!("{} {}", key, direction(order_by.descending))).over(plan);
+        }
+        if stmt.limit.is_some() || stmt.offset.is_some() {
+            let limit = stmt.limit.map_or("ALL".to_string(), |limit| limit.to_string());
+            plan = PlanNode::new("Limit").detail(format!("LIMIT {} OFFSET {}", limit, stmt.offset.unwrap_or(0))).over(plan);
+        }
+        Ok(plan)
+    }
+
+    /// Runs the statements of a SQL script, such as a `dump`, in order,
+    /// reading it as it goes. `progress` is called after every statement.
+    /// With `OnError::Stop` the first failure ends the run, rolling back any
+    /// transaction the script left open, and is returned as the error; with
+    /// `OnError::Continue` failures are collected in the summary.
+    pub fn execute_script(&self, reader: impl Read, on_error: OnError,
+                          mut progress: impl FnMut(&ScriptProgress)) -> Result<ScriptSummary, String> {
+        let had_transaction = self.in_transaction();
+        let mut statements = StatementReader::new(BufReader::new(reader));
+        let mut summary = ScriptSummary::default();
+        while let Some(statement) = statements.next() {
+            let statement = statement.map_err(|e| format!("Failed to read script: {}", e))?;
+            let result = match parse_insert_stream(&statement.sql) {
+                Ok(insert) => self.execute_insert_stream(insert),
+                Err(_) => tokenize(&statement.sql).and_then(parse_sql).and_then(|stmt| self.execute(stmt)),
+            };
+            summary.statements += 1;
+            if let Err(error) = result {
+                if on_error == OnError::Stop {
+                    if !had_transaction && self.in_transaction() {
+                        self.execute_transaction(TransactionStatement::Rollback)?;
                     }
+                    return Err(format!("Statement at line {} failed: {}", statement.line, error));
                 }
+                summary.errors.push(ScriptError { line: statement.line, statement: statement.sql, error });
             }
+            progress(&ScriptProgress {
+                statements: summary.statements,
+                failed: summary.errors.len(),
+                bytes_read: statements.bytes_read(),
+            });
         }
-        
-        Ok(())
+        Ok(summary)
+    }
+
+    /// Writes the database as SQL: a CREATE TABLE statement per table, then
+    /// INSERTs of its rows in key order. Running the output against an empty
+    /// database recreates this one. Each table is read at a single point in
+    /// time, but writes to other tables may land while the dump runs.
+    pub fn dump(&self, writer: impl Write) -> Result<(), String> {
+        self.dump_tables(self.tables()?, writer)
+    }
+
+    /// Like `dump`, for one table.
+    pub fn dump_table(&self, table_name: &str, writer: impl Write) -> Result<(), String> {
+        let table = self.tables()?.into_iter()
+            .find(|t| t.name == table_name)
+            .ok_or_else(|| format!("Table '{}' not found", table_name))?;
+        self.dump_tables(vec![table], writer)
+    }
+
+    fn dump_tables(&self, tables: Vec<TableDef>, mut writer: impl Write) -> Result<(), String> {
+        let write_err = |e: std::io::Error| format!("Failed to write dump: {}", e);
+        for table in tables {
+            writeln!(writer, "{}", table.create_sql()).map_err(write_err)?;
+
+            let columns = table.column_names();
+            let rows = self.scan_table(&table.name)?;
+            for chunk in rows.chunks(DUMP_ROWS_PER_INSERT) {
+                let mut tuples = Vec::new();
+                for (_, value) in chunk {
+                    let row = self.decode_row(&table.name, value)?;
+                    let values: Vec<String> = columns.iter().map(|c| sql_string(&row[c])).collect();
+                    tuples.push(format!("({})", values.join(", ")));
+                }
+                let names: Vec<String> = columns.iter().map(|c| quote_name(c, '"')).collect();
+                writeln!(writer, "INSERT INTO {} ({}) VALUES {};", quote_name(&table.name, '"'), names.join(", "), tuples.join(", "))
+                    .map_err(write_err)?;
+            }
+        }
+        writer.flush().map_err(write_err)
+    }
+
+    /// Writes a point-in-time copy of the database to `dest_dir`, which must
+    /// not exist or be empty; the copy opens like any other data directory.
+    /// Writes and DDL wait while it is taken.
+    pub fn backup(&self, dest_dir: &str) -> Result<(), String> {
+        self.backup_files(dest_dir, &BackupManifest::default())
+    }
+
+    /// Like `backup`, but leaves out the SSTables an earlier backup, full or
+    /// incremental, already holds: `since_manifest` is that backup's
+    /// directory or its `BACKUP_MANIFEST`. SSTables are never changed once
+    /// written, so for a large table that is mostly read this copies little
+    /// more than its newest SSTables and WAL. An incremental backup doesn't
+    /// open on its own; `restore_incremental` puts one back together.
+    pub fn backup_incremental(&self, dest_dir: &str, since_manifest: &str) -> Result<(), String> {
+        let mut path = PathBuf::from(since_manifest);
+        if path.is_dir() {
+            path.push(BACKUP_MANIFEST);
+        }
+        let json = fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read backup manifest '{}': {}", path.display(), e))?;
+        let since: BackupManifest = serde_json::from_str(&json)
+            .map_err(|e| format!("Invalid backup manifest '{}': {}", path.display(), e))?;
+        self.backup_files(dest_dir, &since)
     }
 
-    pub fn close(&mut self) -> Result<(), String> {
-        for (_, storage) in self.tables.iter_mut() {
-            storage.close().map_err(|e| format!("Failed to close storage: {}", e))?;
+    /// Rebuilds a database in the new directory `dest_dir` from a full
+    /// backup and the incremental backups taken after it, oldest first.
+    pub fn restore_incremental(backups: &[&str], dest_dir: &str) -> Result<(), String> {
+        let dest = PathBuf::from(dest_dir);
+        if fs::read_dir(&dest).is_ok_and(|mut entries| entries.next().is_some()) {
+            return Err(format!("Restore directory '{}' is not empty", dest_dir));
+        }
+        // Each backup's files replace the last one's; SSTables that were
+        // compacted away in between are removed when the tables are opened
+        for backup in backups {
+            copy_dir_over(Path::new(backup), &dest)
+                .map_err(|e| format!("Failed to restore backup '{}': {}", backup, e))?;
         }
         Ok(())
     }
-} 
\ No newline at end of file
+
+    fn backup_files(&self, dest_dir: &str, since: &BackupManifest) -> Result<(), String> {
+        // Tables are flushed into the copy
+        self.check_writable()?;
+        let dest = PathBuf::from(dest_dir);
+        if fs::read_dir(&dest).is_ok_and(|mut entries| entries.next().is_some()) {
+            return Err(format!("Backup directory '{}' is not empty", dest_dir));
+        }
+        fs::create_dir_all(&dest).map_err(|e| format!("Failed to create backup directory: {}", e))?;
+
+        // Holding the table map blocks DDL and opening tables, and holding
+        // every open table's lock blocks writes and the compactions they run
+        let tables = self.tables.write().map_err(lock_err)?;
+        let mut names: Vec<&String> = tables.keys().collect();
+        names.sort();
+        let mut table_guards = Vec::new();
+        for name in names {
+            table_guards.push(tables[name].write().map_err(lock_err)?);
+        }
+        let mut catalog = self.catalog.write().map_err(lock_err)?;
+
+        for table_storage in table_guards.iter_mut() {
+            table_storage.close().map_err(|e| format!("Failed to flush storage: {}", e))?;
+        }
+        catalog.close()?;
+
+        let copy_err = |e: std::io::Error| format!("Failed to copy database files: {}", e);
+        let mut manifest = BackupManifest::default();
+        let mut copy = |name: &str, partitions| {
+            let table_dir = self.data_dir.join(name);
+            if table_dir.exists() {
+                let since = since.tables.get(name).cloned().unwrap_or_default();
+                let sstables = PartitionedStorage::copy_files_since(&table_dir, &dest.join(name), partitions, &since)
+                    .map_err(copy_err)?;
+                manifest.tables.insert(name.to_string(), sstables);
+            }
+            Ok::<_, String>(())
+        };
+        for table in catalog.tables() {
+            copy(&table.name, table.partitions)?;
+        }
+        copy(CATALOG_DIR, None)?;
+        // A commit caught half way through applying is redone when the copy is opened
+        for file in [VERSION_FILE, COMMIT_LOG] {
+            match fs::copy(self.data_dir.join(file), dest.join(file)) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(copy_err(e)),
+                _ => {}
+            }
+        }
+        let json = serde_json::to_string(&manifest).map_err(|e| format!("Failed to write backup manifest: {}", e))?;
+        fs::write(dest.join(BACKUP_MANIFEST), json).map_err(copy_err)
+    }
+
+    /// Rebuilds the database in `data_dir` as it was at `timestamp`
+    /// (milliseconds since the Unix epoch) into the new directory `dest_dir`,
+    /// from the WAL archives kept with `DatabaseOptions::archive_wal`.
+    pub fn restore_to(data_dir: &str, dest_dir: &str, timestamp: u64) -> Result<(), String> {
+        let (data_dir, dest) = (PathBuf::from(data_dir), PathBuf::from(dest_dir));
+        let restore_err = |table: &str, e: std::io::Error| format!("Failed to restore '{}': {}", table, e);
+        LSMStorage::restore_to(&data_dir, CATALOG_DIR, &dest, timestamp)
+            .map_err(|e| restore_err(CATALOG_DIR, e))?;
+
+        let mut catalog = Catalog::open(&dest)?;
+        for table in catalog.tables() {
+            PartitionedStorage::restore_to(&data_dir, &table.name, table.partitions, &dest, timestamp)
+                .map_err(|e| restore_err(&table.name, e))?;
+        }
+        catalog.close()
+    }
+
+    pub fn close(&self) -> Result<(), String> {
+        for storage in self.tables.read().map_err(lock_err)?.values() {
+            storage.write().map_err(lock_err)?.close().map_err(|e| format!("Failed to close storage: {}", e))?;
+        }
+        self.catalog.write().map_err(lock_err)?.close()
+    }
+} 
+#[cfg(feature = "tokio")]
+impl PersistentDatabase {
+    /// Opens the database on tokio's blocking pool, since recovery replays
+    /// every table's WAL from disk.
+    pub async fn new_async(data_dir: &str) -> Result<Arc<Self>, String> {
+        let data_dir = data_dir.to_string();
+        tokio::task::spawn_blocking(move || Self::new(&data_dir).map(Arc::new))
+            .await
+            .map_err(|e| format!("Background task failed: {}", e))?
+    }
+
+    /// Async counterpart of [`PersistentDatabase::execute`]. The statement runs
+    /// on tokio's blocking pool, so WAL writes, memtable flushes and
+    /// compactions never stall the caller's runtime threads.
+    pub async fn execute_async(self: &Arc<Self>, stmt: SQLStatement) -> Result<StatementResult, String> {
+        let db = Arc::clone(self);
+        tokio::task::spawn_blocking(move || db.execute(stmt))
+            .await
+            .map_err(|e| format!("Background task failed: {}", e))?
+    }
+
+    pub async fn backup_async(self: &Arc<Self>, dest_dir: &str) -> Result<(), String> {
+        let db = Arc::clone(self);
+        let dest_dir = dest_dir.to_string();
+        tokio::task::spawn_blocking(move || db.backup(&dest_dir))
+            .await
+            .map_err(|e| format!("Background task failed: {}", e))?
+    }
+
+    pub async fn close_async(self: &Arc<Self>) -> Result<(), String> {
+        let db = Arc::clone(self);
+        tokio::task::spawn_blocking(move || db.close())
+            .await
+            .map_err(|e| format!("Background task failed: {}", e))?
+    }
+}
+
+// The names of a SELECT's result columns; `schema` is the table's columns
+fn result_headers(columns: &[ColumnExpr], schema: &[String]) -> Vec<String> {
+    if columns.len() == 1 && matches!(columns[0], ColumnExpr::All) {
+        // For SELECT *, show all column names
+        schema.to_vec()
+    } else {
+        columns.iter().map(ColumnExpr::name).collect()
+    }
+}
+
+// The selected values of a row, in the order of `result_headers`
+fn result_row(row: &HashMap<String, String>, columns: &[ColumnExpr], schema: &[String]) -> Vec<String> {
+    if columns.len() == 1 && matches!(columns[0], ColumnExpr::All) {
+        // For SELECT *, show all column values in schema order
+        return schema.iter()
+            .map(|col_name| row.get(col_name).cloned().unwrap_or_else(|| NULL.to_string()))
+            .collect();
+    }
+    columns.iter().map(|col| match col.unaliased() {
+        ColumnExpr::Column(name) => row.get(name).cloned().unwrap_or_else(|| NULL.to_string()),
+        ColumnExpr::Length(name) => blob::length(row.get(name).map_or(NULL, String::as_str)),
+        ColumnExpr::Math(call) => math::value_of(call, row),
+        // A lone * was handled above and aliases are unwrapped
+        ColumnExpr::All | ColumnExpr::Aliased(..) => "*".to_string(),
+        // Aggregates were computed into the row under their names
+        aggregated => row.get(&aggregated.name()).cloned().unwrap_or_else(|| NULL.to_string()),
+    }).collect()
+}
+
+// Hands the rows of a query that was run whole to a `query_each` sink
+fn send_rows(result: ResultSet, sink: &mut dyn RowSink) -> Result<usize, String> {
+    sink.columns(&result.columns, &result.meta).map_err(write_rows_err)?;
+    for row in &result.rows {
+        sink.row(row).map_err(write_rows_err)?;
+    }
+    Ok(result.rows.len())
+}
+
+fn write_rows_err(e: io::Error) -> String {
+    format!("Failed to write rows: {}", e)
+}
+
+// Whether an error came from reading a table's files rather than from the
+// statement itself
+fn is_storage_error(error: &str) -> bool {
+    error.starts_with("Storage error") || error.starts_with("Failed to open table storage")
+}
+
+// The tables a statement reads, locked shared, and writes, locked exclusively
+fn stmt_locks(stmt: &SQLStatement) -> LockSet {
+    let mut locks = LockSet::default();
+    match stmt {
+        SQLStatement::Select(select) => add_select_locks(&mut locks, select),
+        SQLStatement::Insert(insert) => locks.add(&insert.table, LockMode::Exclusive),
+        SQLStatement::Update(update) => {
+            locks.add(&update.table, LockMode::Exclusive);
+            add_read_locks(&mut locks, update.join.as_ref(), update.where_clause.as_ref());
+        }
+        SQLStatement::Delete(delete) => {
+            locks.add(&delete.table, LockMode::Exclusive);
+            add_read_locks(&mut locks, delete.join.as_ref(), delete.where_clause.as_ref());
+        }
+        SQLStatement::AlterTable(alter) => locks.add(&alter.table, LockMode::Exclusive),
+        SQLStatement::DropTable(drop) => locks.add(&drop.table, LockMode::Exclusive),
+        SQLStatement::Analyze(Some(table)) => locks.add(table, LockMode::Shared),
+        _ => {}
+    }
+    locks
+}
+
+// The tables a query reads, all locked shared
+fn select_locks(select: &SelectStatement) -> LockSet {
+    let mut locks = LockSet::default();
+    add_select_locks(&mut locks, select);
+    locks
+}
+
+fn add_select_locks(locks: &mut LockSet, select: &SelectStatement) {
+    locks.add(&select.table, LockMode::Shared);
+    add_read_locks(locks, select.join.as_ref(), select.where_clause.as_ref());
+}
+
+// The joined tables and the tables of an IN subquery
+fn add_read_locks(locks: &mut LockSet, join: Option<&JoinClause>, where_clause: Option<&WhereClause>) {
+    for table in join.map_or_else(Vec::new, JoinClause::tables) {
+        locks.add(table, LockMode::Shared);
+    }
+    if let Some(InList::Subquery(select)) = where_clause.and_then(|wc| wc.in_list.as_ref()) {
+        add_select_locks(locks, select);
+    }
+}
+
+// The step joining the rows `scan` reads to those of the joined side: a
+// scan of its table, or for a group, the group's own join. `right_first`
+// loops over the joined side's rows first, for a JOIN_ORDER hint; only inner
+// and cross joins can be reordered.
+fn join_plan(join: &JoinClause, scan: PlanNode, as_of: &dyn Fn(PlanNode) -> PlanNode, right_first: bool) -> PlanNode {
+    let table_scan = as_of(PlanNode::new("Seq Scan").table(&join.table));
+    let joined = match &join.nested {
+        Some(nested) => join_plan(nested, table_scan, as_of, false),
+        None => table_scan,
+    };
+    let join_type = match join.join_type {
+        JoinType::Inner => "INNER",
+        JoinType::Left => "LEFT",
+        JoinType::Right => "RIGHT",
+        JoinType::Full => "FULL",
+        JoinType::Cross => "CROSS",
+    };
+    let condition = match join.join_type {
+        JoinType::Cross => "CROSS JOIN".to_string(),
+        _ => {
+            let on: Vec<_> = join.on.iter().map(ToString::to_string).collect();
+            format!("{} JOIN ON {}", join_type, on.join(" AND "))
+        }
+    };
+    let right_first = right_first && matches!(join.join_type, JoinType::Inner | JoinType::Cross);
+    let (outer, inner) = if right_first { (joined, scan) } else { (scan, joined) };
+    let node = if is_hash_join(join) { "Hash Join" } else { "Nested Loop" };
+    PlanNode::new(node).detail(condition).over(outer).over(inner)
+}
+
+// Whether a JOIN_ORDER hint puts the joined table's rows in the outer loop
+fn joins_right_first(stmt: &SelectStatement) -> bool {
+    let Some(join) = &stmt.join else {
+        return false;
+    };
+    stmt.hints.iter().any(|hint| matches!(hint, Hint::JoinOrder(tables) if tables.first() == Some(&join.table)))
+}
+
+// Copies the files under `src` into `dest`, replacing any of the same name
+fn copy_dir_over(src: &Path, dest: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(dest)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dest.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir_over(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+// A row as it's stored: a JSON object of its columns, with NULL as null so
+// it's never read back as the text 'NULL'
+pub(crate) fn encode_row(row: &HashMap<String, String>) -> Result<String, String> {
+    let row: HashMap<&str, Option<&str>> = row.iter()
+        .map(|(column, value)| (column.as_str(), Some(value.as_str()).filter(|value| *value != NULL)))
+        .collect();
+    serde_json::to_string(&row).map_err(|e| format!("Failed to serialize row data: {}", e))
+}
+
+pub(crate) fn decode_row_json(json: &str) -> Result<HashMap<String, String>, String> {
+    let row: HashMap<String, Option<String>> = serde_json::from_str(json)
+        .map_err(|e| format!("Failed to deserialize row data: {}", e))?;
+    Ok(row.into_iter().map(|(column, value)| (column, value.unwrap_or_else(|| NULL.to_string()))).collect())
+}
+
+// A value as a literal; blobs keep their `X'...'` form
+fn sql_string(value: &str) -> String {
+    if value == NULL {
+        return "NULL".to_string();
+    }
+    if value.parse::<Blob>().is_ok() {
+        return value.to_string();
+    }
+    format!("'{}'", value.replace('\'', "''"))
+}
+
+// Applies CREATE TABLE ... WITH / ALTER TABLE ... SET options on top of `config`.
+// A table's directory is only chosen when it's created; see `link_table_dir`.
+fn apply_storage_options(mut config: StorageConfig, options: &[(String, String)]) -> Result<StorageConfig, String> {
+    for (name, value) in options {
+        let size = || value.parse::<usize>().ok().filter(|&n| n > 0)
+            .ok_or_else(|| format!("Option '{}' must be a positive number of bytes, got '{}'", name, value));
+        match name.as_str() {
+            "memtable_size" => config.memtable_size = size()?,
+            "sstable_size" => config.sstable_size = size()?,
+            // In seconds; 0 turns expiry off
+            "ttl" => {
+                let seconds = value.parse::<u64>()
+                    .map_err(|_| format!("Option 'ttl' must be a number of seconds, got '{}'", value))?;
+                config.ttl = (seconds > 0).then(|| Duration::from_secs(seconds));
+            }
+            "directory" => return Err("Option 'directory' can only be given when a table is created".to_string()),
+            _ => return Err(format!("Unknown table option '{}'", name)),
+        }
+    }
+    Ok(config)
+}
+
+// Puts a table's files in `directory`, which must not hold any files yet, by
+// linking the table's directory under the data directory to it
+fn link_table_dir(directory: &str, table_dir: &Path) -> Result<(), String> {
+    if fs::read_dir(directory).is_ok_and(|mut entries| entries.next().is_some()) {
+        return Err(format!("Table directory '{}' is not empty", directory));
+    }
+    let target = fs::create_dir_all(directory)
+        .and_then(|_| fs::canonicalize(directory))
+        .map_err(|e| format!("Failed to create table directory '{}': {}", directory, e))?;
+    #[cfg(unix)]
+    let linked = std::os::unix::fs::symlink(&target, table_dir);
+    #[cfg(windows)]
+    let linked = std::os::windows::fs::symlink_dir(&target, table_dir);
+    #[cfg(not(any(unix, windows)))]
+    let linked: std::io::Result<()> = Err(std::io::Error::new(std::io::ErrorKind::Unsupported,
+        "tables can't be kept outside the data directory on this platform"));
+    linked.map_err(|e| format!("Failed to link table directory '{}': {}", directory, e))
+}
+
+// Removes a table's directory and, if it's linked elsewhere, the files it
+// links to
+fn remove_table_dir(table_dir: &Path) -> Result<(), String> {
+    let remove_err = |e: std::io::Error| format!("Failed to remove table directory: {}", e);
+    if let Ok(target) = fs::read_link(table_dir) {
+        if target.exists() {
+            fs::remove_dir_all(&target).map_err(remove_err)?;
+        }
+        fs::remove_file(table_dir).map_err(remove_err)?;
+    } else if table_dir.exists() {
+        fs::remove_dir_all(table_dir).map_err(remove_err)?;
+    }
+    Ok(())
+}
+
+// What ANALYZE records of one column of a table's rows
+fn column_stats(column: &str, rows: &[HashMap<String, String>]) -> ColumnStats {
+    let values: Vec<&str> = rows.iter()
+        .filter_map(|row| row.get(column).map(String::as_str))
+        .filter(|value| *value != NULL)
+        .collect();
+    let distinct: HashSet<&str> = values.iter().copied().collect();
+    ColumnStats {
+        name: column.to_string(),
+        nulls: (rows.len() - values.len()) as u64,
+        distinct: distinct.len() as u64,
+        min: values.iter().min_by(|a, b| compare_values(a, b)).map(|value| value.to_string()),
+        max: values.iter().max_by(|a, b| compare_values(a, b)).map(|value| value.to_string()),
+    }
+}
+
+// The values of a DECIMAL column, skipping NULLs.
+fn decimal_values<'a>(rows: &'a [HashMap<String, String>], column: &'a str) -> impl Iterator<Item = Decimal> + 'a {
+    rows.iter().filter_map(move |row| row.get(column)?.parse().ok())
+}
+
+// The exact sum of a DECIMAL column, at least at the column's scale.
+fn decimal_sum(rows: &[HashMap<String, String>], column: &str, decimal: &DecimalType) -> Result<Decimal, String> {
+    decimal_values(rows, column)
+        .try_fold(Decimal::zero(decimal.scale.unwrap_or(0)), Decimal::checked_add)
+        .ok_or_else(|| format!("SUM({}) is too large for a DECIMAL", column))
+}
+
+// Converts a stored value to `data_type` for ALTER TABLE ... MODIFY, returning
+// its canonical form. NULL converts to every type.
+fn convert_value(value: &str, data_type: &str) -> Result<String, String> {
+    if let Some(decimal) = DecimalType::of(data_type)? {
+        return decimal.fit(value);
+    }
+    if value == NULL {
+        return Ok(value.to_string());
+    }
+    match data_type.to_uppercase().as_str() {
+        "INT" | "INTEGER" | "BIGINT" | "SMALLINT" => {
+            if let Ok(n) = value.trim().parse::<i64>() {
+                return Ok(n.to_string());
+            }
+            match value.trim().parse::<f64>() {
+                Ok(f) if f.fract() == 0.0 && f.abs() < i64::MAX as f64 => Ok((f as i64).to_string()),
+                _ => Err(format!("'{}' is not an integer", value)),
+            }
+        }
+        "FLOAT" | "REAL" | "DOUBLE" => {
+            value.trim().parse::<f64>()
+                .map(|_| value.trim().to_string())
+                .map_err(|_| format!("'{}' is not a number", value))
+        }
+        "BOOL" | "BOOLEAN" => match value.to_lowercase().as_str() {
+            "true" | "1" => Ok("true".to_string()),
+            "false" | "0" => Ok("false".to_string()),
+            _ => Err(format!("'{}' is not a boolean", value)),
+        },
+        "TEXT" | "VARCHAR" | "CHAR" | "STRING" => Ok(value.to_string()),
+        "BLOB" => Ok(blob::to_blob(value)),
+        other => Err(format!("unsupported data type '{}'", other)),
+    }
+}
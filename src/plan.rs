@@ -0,0 +1,68 @@
+//! The plan of a SELECT as `EXPLAIN` reports it: a tree of steps, each
+//! reading the rows of its children, with the table scans at the leaves.
+
+use serde::Serialize;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlanNode {
+    /// What the step does, e.g. "Seq Scan", "Filter" or "Sort".
+    pub node: String,
+    /// The table a scan reads.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub table: Option<String>,
+    /// The condition, columns or counts the step works with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<PlanNode>,
+}
+
+impl PlanNode {
+    pub(crate) fn new(node: &str) -> Self {
+        Self { node: node.to_string(), table: None, detail: None, children: Vec::new() }
+    }
+
+    pub(crate) fn table(mut self, table: &str) -> Self {
+        self.table = Some(table.to_string());
+        self
+    }
+
+    pub(crate) fn detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    /// This step reading the rows of `child`.
+    pub(crate) fn over(mut self, child: PlanNode) -> Self {
+        self.children.push(child);
+        self
+    }
+
+    /// The plan as JSON, for `EXPLAIN (FORMAT JSON)`.
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("a plan serializes to JSON")
+    }
+
+    fn write(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        write!(f, "{:indent$}{}", "", self.node, indent = depth * 2)?;
+        if let Some(table) = &self.table {
+            write!(f, " on {}", table)?;
+        }
+        if let Some(detail) = &self.detail {
+            write!(f, " ({})", detail)?;
+        }
+        for child in &self.children {
+            writeln!(f)?;
+            child.write(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+/// One step per line, each indented beneath the step that reads its rows.
+impl fmt::Display for PlanNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
@@ -0,0 +1,121 @@
+//! Sharing one `PersistentDatabase` between threads: a `Pool` of a fixed
+//! number of connections, each returned to the pool when dropped.
+//!
+//! Connections run statements concurrently, each with a transaction of its
+//! own. A statement waits for the tables it uses to be free of other
+//! connections' transactions (see `locks`), and one that would deadlock
+//! fails with `DEADLOCK_DETECTED`, rolling back its transaction. A
+//! connection dropped mid-transaction rolls it back. A thread holding a
+//! connection with an open transaction must not use a second connection on
+//! the same tables, which would wait for the first forever.
+
+use crate::ast::{SQLStatement, SelectStatement};
+use crate::output::{ResultSet, StatementResult};
+use crate::persistent_executor::PersistentDatabase;
+use crate::session::Session;
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+pub struct Pool {
+    db: Arc<PersistentDatabase>,
+    // Connections not handed out
+    free: Mutex<usize>,
+    returned: Condvar,
+}
+
+impl Pool {
+    /// Opens the database in `data_dir` with `size` connections, which
+    /// must be at least one.
+    pub fn new(data_dir: &str, size: usize) -> Result<Self, String> {
+        check_size(size)?;
+        Self::with_database(Arc::new(PersistentDatabase::new(data_dir)?), size)
+    }
+
+    /// A pool over an open database, which may also be used directly or by
+    /// a `Server`.
+    pub fn with_database(db: Arc<PersistentDatabase>, size: usize) -> Result<Self, String> {
+        check_size(size)?;
+        Ok(Self { db, free: Mutex::new(size), returned: Condvar::new() })
+    }
+
+    /// Waits for a free connection.
+    pub fn get(&self) -> Connection<'_> {
+        let mut free = self.free.lock().unwrap_or_else(PoisonError::into_inner);
+        while *free == 0 {
+            free = self.returned.wait(free).unwrap_or_else(PoisonError::into_inner);
+        }
+        *free -= 1;
+        self.connection()
+    }
+
+    /// A free connection, if there is one now.
+    pub fn try_get(&self) -> Option<Connection<'_>> {
+        self.get_timeout(Duration::ZERO)
+    }
+
+    /// Waits up to `timeout` for a free connection.
+    pub fn get_timeout(&self, timeout: Duration) -> Option<Connection<'_>> {
+        let deadline = Instant::now() + timeout;
+        let mut free = self.free.lock().unwrap_or_else(PoisonError::into_inner);
+        while *free == 0 {
+            let left = deadline.checked_duration_since(Instant::now()).filter(|left| !left.is_zero())?;
+            free = self.returned.wait_timeout(free, left).unwrap_or_else(PoisonError::into_inner).0;
+        }
+        *free -= 1;
+        Some(self.connection())
+    }
+
+    /// How many connections are free.
+    pub fn available(&self) -> usize {
+        *self.free.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn connection(&self) -> Connection<'_> {
+        Connection { pool: self, session: Session::new(self.db.clone()) }
+    }
+}
+
+// A pool without connections would make every `get` wait forever
+fn check_size(size: usize) -> Result<(), String> {
+    if size == 0 {
+        return Err("A pool needs at least one connection".to_string());
+    }
+    Ok(())
+}
+
+/// A connection from a `Pool`, returned to it when dropped.
+pub struct Connection<'a> {
+    pool: &'a Pool,
+    session: Session,
+}
+
+impl Connection<'_> {
+    pub fn execute(&mut self, stmt: SQLStatement) -> Result<StatementResult, String> {
+        self.session.execute(stmt)
+    }
+
+    /// Runs a SELECT, returning its rows rather than formatting them.
+    pub fn query(&self, stmt: &SelectStatement) -> Result<ResultSet, String> {
+        self.session.query(stmt)
+    }
+
+    /// Whether the connection has a transaction open, which a deadlock may
+    /// have rolled back.
+    pub fn in_transaction(&self) -> bool {
+        self.session.in_transaction()
+    }
+
+    /// The connection's session, with its variables and settings.
+    pub fn session(&mut self) -> &mut Session {
+        &mut self.session
+    }
+}
+
+impl Drop for Connection<'_> {
+    fn drop(&mut self) {
+        // Rolled back before another thread can take the connection's place
+        self.session.end();
+        *self.pool.free.lock().unwrap_or_else(PoisonError::into_inner) += 1;
+        self.pool.returned.notify_one();
+    }
+}
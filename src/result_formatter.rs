@@ -0,0 +1,145 @@
+//! Drawing a `ResultSet` as a table with its columns lined up, for the
+//! `table` and `ascii` output formats:
+//!
+//! ```text
+//! ┌────┬───────┐
+//! │ id │ name  │
+//! ├────┼───────┤
+//! │ 1  │ alice │
+//! └────┴───────┘
+//! ```
+//!
+//! Each column is as wide as its widest value or header, up to a maximum
+//! past which values are cut short and end in an ellipsis.
+
+use crate::output::{shown, ResultSet};
+use std::io::{self, Write};
+
+/// The characters a table's borders are drawn with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TableStyle {
+    /// Box-drawing characters, `┌─┬─┐`.
+    #[default]
+    Unicode,
+    /// `+`, `-` and `|`, for terminals and files without Unicode.
+    Ascii,
+}
+
+// The characters of one style: the corners and joints of the top, middle
+// and bottom rules, then the horizontal and vertical lines and the ellipsis
+struct Borders {
+    top: [&'static str; 3],
+    middle: [&'static str; 3],
+    bottom: [&'static str; 3],
+    horizontal: &'static str,
+    vertical: &'static str,
+    ellipsis: &'static str,
+}
+
+impl TableStyle {
+    fn borders(self) -> Borders {
+        match self {
+            TableStyle::Unicode => Borders {
+                top: ["┌", "┬", "┐"],
+                middle: ["├", "┼", "┤"],
+                bottom: ["└", "┴", "┘"],
+                horizontal: "─",
+                vertical: "│",
+                ellipsis: "…",
+            },
+            TableStyle::Ascii => Borders {
+                top: ["+", "+", "+"],
+                middle: ["+", "+", "+"],
+                bottom: ["+", "+", "+"],
+                horizontal: "-",
+                vertical: "|",
+                ellipsis: "...",
+            },
+        }
+    }
+}
+
+/// Lays out result sets as aligned tables, e.g.
+/// `ResultFormatter::new().style(TableStyle::Ascii).max_width(20).render(&result)`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResultFormatter {
+    style: TableStyle,
+    max_width: Option<usize>,
+}
+
+impl ResultFormatter {
+    /// Unicode borders, and no limit on the width of a column.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn style(mut self, style: TableStyle) -> Self {
+        self.style = style;
+        self
+    }
+
+    /// Cuts values and headers longer than `width` characters short.
+    pub fn max_width(mut self, width: usize) -> Self {
+        self.max_width = Some(width);
+        self
+    }
+
+    /// The width of each column in characters, not counting its padding.
+    pub fn widths(&self, result: &ResultSet) -> Vec<usize> {
+        let mut widths: Vec<usize> = result.columns.iter().map(|c| c.chars().count()).collect();
+        for row in &result.rows {
+            for (width, value) in widths.iter_mut().zip(row) {
+                *width = (*width).max(shown(value).chars().count());
+            }
+        }
+        match self.max_width {
+            Some(max) => widths.into_iter().map(|width| width.min(max)).collect(),
+            None => widths,
+        }
+    }
+
+    pub fn render(&self, result: &ResultSet) -> String {
+        let mut output = Vec::new();
+        self.write(result, &mut output).expect("writing to a Vec can't fail");
+        String::from_utf8(output).expect("results are UTF-8")
+    }
+
+    pub fn write(&self, result: &ResultSet, mut writer: impl Write) -> io::Result<()> {
+        let borders = self.style.borders();
+        let widths = self.widths(result);
+        let rule = |writer: &mut dyn Write, [left, middle, right]: [&str; 3]| {
+            let segments: Vec<String> = widths.iter().map(|w| borders.horizontal.repeat(w + 2)).collect();
+            writeln!(writer, "{}{}{}", left, segments.join(middle), right)
+        };
+        let line = |writer: &mut dyn Write, values: &[String]| {
+            let cells: Vec<String> = widths.iter().zip(values)
+                .map(|(&width, value)| {
+                    let value = truncate(&shown(value), width, borders.ellipsis);
+                    format!(" {}{} ", value, " ".repeat(width - value.chars().count()))
+                })
+                .collect();
+            writeln!(writer, "{}{}{}", borders.vertical, cells.join(borders.vertical), borders.vertical)
+        };
+
+        rule(&mut writer, borders.top)?;
+        line(&mut writer, &result.columns)?;
+        rule(&mut writer, borders.middle)?;
+        for row in &result.rows {
+            line(&mut writer, row)?;
+        }
+        rule(&mut writer, borders.bottom)?;
+        writer.flush()
+    }
+}
+
+// `value` cut to `width` characters, ending in `ellipsis` if it was longer
+fn truncate(value: &str, width: usize, ellipsis: &str) -> String {
+    if value.chars().count() <= width {
+        return value.to_string();
+    }
+    let ellipsis_width = ellipsis.chars().count();
+    if width < ellipsis_width {
+        return value.chars().take(width).collect();
+    }
+    value.chars().take(width - ellipsis_width).chain(ellipsis.chars()).collect()
+}
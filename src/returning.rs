@@ -0,0 +1,59 @@
+//! The rows an UPDATE or DELETE with RETURNING gives back, for both
+//! executors. Each changed row has two images: its values before the
+//! statement (`old.column`) and after it (`new.column`). An unqualified
+//! column reads a DELETE's old image and an UPDATE's new one, and a deleted
+//! row's new image is all NULLs.
+
+use crate::ast::NULL;
+use crate::output::ResultSet;
+use std::collections::HashMap;
+
+/// A changed row as it was and as the statement left it, None for a
+/// deleted row.
+pub(crate) type ChangedRow = (HashMap<String, String>, Option<HashMap<String, String>>);
+
+/// Which image of a changed row an unqualified column reads.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Image {
+    Old,
+    New,
+}
+
+/// The result of `returning` for the rows of `table` a statement changed.
+/// `columns` are the table's, in schema order, which `*` lists.
+pub(crate) fn returned_rows(returning: &[String], table: &str, columns: &[String], default: Image,
+                            changed: &[ChangedRow]) -> Result<ResultSet, String> {
+    let mut headers = Vec::new();
+    let mut sources = Vec::new();
+    for item in returning {
+        let (image, column) = match item.split_once('.') {
+            Some((qualifier, column)) if qualifier.eq_ignore_ascii_case("old") => (Image::Old, column),
+            Some((qualifier, column)) if qualifier.eq_ignore_ascii_case("new") => (Image::New, column),
+            Some((qualifier, column)) if qualifier == table => (default, column),
+            Some((qualifier, _)) => return Err(format!("RETURNING can't read a column of '{}'", qualifier)),
+            None => (default, item.as_str()),
+        };
+        if column == "*" {
+            headers.extend(columns.iter().cloned());
+            sources.extend(columns.iter().map(|column| (image, column.as_str())));
+        } else if columns.iter().any(|c| c == column) {
+            headers.push(if item.contains('.') { item.clone() } else { column.to_string() });
+            sources.push((image, column));
+        } else {
+            return Err(format!("Column '{}' not found in table '{}'", column, table));
+        }
+    }
+
+    let rows = changed.iter()
+        .map(|(old, new)| sources.iter()
+            .map(|&(image, column)| {
+                let row = match image {
+                    Image::Old => Some(old),
+                    Image::New => new.as_ref(),
+                };
+                row.and_then(|row| row.get(column)).cloned().unwrap_or_else(|| NULL.to_string())
+            })
+            .collect())
+        .collect();
+    Ok(ResultSet { columns: headers, rows, meta: Vec::new() })
+}
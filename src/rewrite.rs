@@ -0,0 +1,69 @@
+//! Passes over parsed statements that both executors run before executing
+//! one, e.g. to add a tenant's filter to every query:
+//!
+//! ```ignore
+//! db.add_rewrite_rule(|stmt: &mut SQLStatement| {
+//!     if let SQLStatement::Select(select) = stmt {
+//!         select.where_clause.get_or_insert(Condition::eq("tenant", "acme"));
+//!     }
+//!     Ok(())
+//! });
+//! ```
+
+use crate::ast::{SQLStatement, SelectStatement};
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::Arc;
+
+/// Rewrites a statement in place, or rejects it with an error that the
+/// executor returns instead of running it.
+pub trait RewriteRule: Send + Sync {
+    fn rewrite(&self, stmt: &mut SQLStatement) -> Result<(), String>;
+}
+
+impl<F: Fn(&mut SQLStatement) -> Result<(), String> + Send + Sync> RewriteRule for F {
+    fn rewrite(&self, stmt: &mut SQLStatement) -> Result<(), String> {
+        self(stmt)
+    }
+}
+
+/// Rewrite rules, run in the order they were added.
+#[derive(Clone, Default)]
+pub struct RewriteRules {
+    rules: Vec<Arc<dyn RewriteRule>>,
+}
+
+impl RewriteRules {
+    pub fn add(&mut self, rule: impl RewriteRule + 'static) {
+        self.rules.push(Arc::new(rule));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn apply(&self, mut stmt: SQLStatement) -> Result<SQLStatement, String> {
+        for rule in &self.rules {
+            rule.rewrite(&mut stmt)?;
+        }
+        Ok(stmt)
+    }
+
+    /// Like `apply`, for a SELECT that must stay one. It is only copied if
+    /// there are rules to run.
+    pub fn apply_select<'a>(&self, stmt: &'a SelectStatement) -> Result<Cow<'a, SelectStatement>, String> {
+        if self.is_empty() {
+            return Ok(Cow::Borrowed(stmt));
+        }
+        match self.apply(SQLStatement::Select(stmt.clone()))? {
+            SQLStatement::Select(select) => Ok(Cow::Owned(select)),
+            _ => Err("A rewrite rule turned a query into a statement that returns no rows".to_string()),
+        }
+    }
+}
+
+impl fmt::Debug for RewriteRules {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RewriteRules({})", self.rules.len())
+    }
+}
@@ -0,0 +1,187 @@
+//! Typed access to the rows of a SELECT, rather than parsing formatted
+//! output: `Row::get` for one value, and `ResultSet::deserialize` to fill a
+//! serde struct per row.
+
+use crate::ast::{SQLStatement, SelectStatement, NULL};
+use crate::output::ResultSet;
+use crate::parser::parse_statement;
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, MapAccess, Visitor};
+use serde::forward_to_deserialize_any;
+use std::fmt;
+use std::str::FromStr;
+
+/// One row of a `ResultSet`.
+#[derive(Debug, Clone, Copy)]
+pub struct Row<'a> {
+    columns: &'a [String],
+    values: &'a [String],
+}
+
+impl<'a> Row<'a> {
+    /// The value of column `name`, parsed as a `T`.
+    pub fn get<T: FromStr>(&self, name: &str) -> Result<T, String> {
+        let value = self.value(name)?;
+        value.parse()
+            .map_err(|_| format!("Column '{}' holds '{}', which isn't a {}", name, value, std::any::type_name::<T>()))
+    }
+
+    /// Like `get`, with `None` for NULL.
+    pub fn get_opt<T: FromStr>(&self, name: &str) -> Result<Option<T>, String> {
+        match self.value(name)? {
+            NULL => Ok(None),
+            _ => self.get(name).map(Some),
+        }
+    }
+
+    /// The value of column `name` as the executor returned it.
+    pub fn value(&self, name: &str) -> Result<&'a str, String> {
+        self.columns.iter().position(|c| c == name)
+            .map(|i| self.values[i].as_str())
+            .ok_or_else(|| format!("No column '{}' in the result", name))
+    }
+}
+
+impl ResultSet {
+    pub fn iter(&self) -> impl Iterator<Item = Row<'_>> {
+        self.rows.iter().map(|values| Row { columns: &self.columns, values })
+    }
+
+    /// Calls `f` on each row, stopping at the first error.
+    pub fn map<T>(&self, f: impl FnMut(Row<'_>) -> Result<T, String>) -> Result<Vec<T>, String> {
+        self.iter().map(f).collect()
+    }
+
+    /// Deserializes each row into a `T`, matching its fields to columns by
+    /// name. Numbers and booleans are parsed from the stored text, and NULL
+    /// is `None` for `Option` fields.
+    pub fn deserialize<T: DeserializeOwned>(&self) -> Result<Vec<T>, String> {
+        self.map(|row| T::deserialize(RowDeserializer { row, next: 0 }).map_err(|e| e.0))
+    }
+}
+
+/// Parses `sql`, which must be a SELECT, for `query_map` and `query_as`.
+pub(crate) fn parse_select(sql: &str) -> Result<SelectStatement, String> {
+    match parse_statement(sql).map_err(|e| e.message)? {
+        SQLStatement::Select(select) => Ok(select),
+        _ => Err("Only a SELECT returns rows".to_string()),
+    }
+}
+
+#[derive(Debug)]
+struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(message: T) -> Self {
+        Error(message.to_string())
+    }
+}
+
+// A row as a map from column name to value
+struct RowDeserializer<'a> {
+    row: Row<'a>,
+    next: usize,
+}
+
+impl<'de> de::Deserializer<'de> for RowDeserializer<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_map(self)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+impl<'de> MapAccess<'de> for RowDeserializer<'_> {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(&mut self, seed: K) -> Result<Option<K::Value>, Error> {
+        let Some(column) = self.row.columns.get(self.next) else {
+            return Ok(None);
+        };
+        seed.deserialize(column.as_str().into_deserializer()).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value, Error> {
+        let value = &self.row.values[self.next];
+        let column = &self.row.columns[self.next];
+        self.next += 1;
+        seed.deserialize(ValueDeserializer(value))
+            .map_err(|e| Error(format!("Column '{}': {}", column, e)))
+    }
+}
+
+// One stored value, parsed as whatever type is asked for
+struct ValueDeserializer<'a>(&'a str);
+
+impl ValueDeserializer<'_> {
+    fn parse<T: FromStr>(&self) -> Result<T, Error> {
+        self.0.parse()
+            .map_err(|_| Error(format!("'{}' isn't a {}", self.0, std::any::type_name::<T>())))
+    }
+}
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident),* $(,)?) => {
+        $(fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+            visitor.$visit(self.parse()?)
+        })*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for ValueDeserializer<'_> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_str(self.0)
+    }
+
+    deserialize_parsed! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+        deserialize_char => visit_char,
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        if self.0 == NULL {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str],
+                                         visitor: V) -> Result<V::Value, Error> {
+        visitor.visit_enum(self.0.into_deserializer())
+    }
+
+    forward_to_deserialize_any! {
+        i128 u128 str string bytes byte_buf unit unit_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
@@ -0,0 +1,163 @@
+//! Reading SQL scripts, e.g. the output of `PersistentDatabase::dump`, one
+//! statement at a time.
+//!
+//! Statements end with `;`. `-- ...` and `/* ... */` comments are dropped,
+//! except inside string literals.
+
+use std::collections::VecDeque;
+use std::io::{self, BufRead};
+
+/// What `PersistentDatabase::execute_script` does when a statement fails.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnError {
+    /// Stop at the first failure, rolling back a transaction the script
+    /// opened, and return the error.
+    #[default]
+    Stop,
+    /// Record the failure and carry on with the next statement.
+    Continue,
+}
+
+/// Passed to the progress callback of `execute_script` after each statement.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScriptProgress {
+    pub statements: usize,
+    pub failed: usize,
+    pub bytes_read: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptError {
+    /// Line of the script the statement starts on, from 1.
+    pub line: usize,
+    pub statement: String,
+    pub error: String,
+}
+
+/// What an `execute_script` run did; `errors` is only ever non-empty with
+/// `OnError::Continue`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScriptSummary {
+    pub statements: usize,
+    pub errors: Vec<ScriptError>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptStatement {
+    pub line: usize,
+    pub sql: String,
+}
+
+/// Splits a script into statements as it is read, so a large one is never
+/// held in memory whole.
+pub struct StatementReader<R> {
+    reader: R,
+    line: usize,
+    bytes_read: u64,
+    current: String,
+    // Line the statement being read starts on, once it has any text
+    start_line: Option<usize>,
+    in_string: bool,
+    in_block_comment: bool,
+    ready: VecDeque<ScriptStatement>,
+    done: bool,
+}
+
+impl<R: BufRead> StatementReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            line: 0,
+            bytes_read: 0,
+            current: String::new(),
+            start_line: None,
+            in_string: false,
+            in_block_comment: false,
+            ready: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    fn split_line(&mut self, text: &str) {
+        let mut chars = text.trim_end_matches(['\r', '\n']).chars().peekable();
+        while let Some(c) = chars.next() {
+            if self.in_block_comment {
+                if c == '*' && chars.peek() == Some(&'/') {
+                    chars.next();
+                    self.in_block_comment = false;
+                }
+                continue;
+            }
+            if self.in_string {
+                // A doubled quote closes and reopens the literal
+                self.in_string = c != '\'';
+                self.current.push(c);
+                continue;
+            }
+            match (c, chars.peek()) {
+                ('-', Some('-')) => break,
+                ('/', Some('*')) => {
+                    chars.next();
+                    self.in_block_comment = true;
+                    self.current.push(' ');
+                }
+                (';', _) => {
+                    self.current.push(c);
+                    self.finish_statement();
+                }
+                _ => {
+                    if self.start_line.is_none() && !c.is_whitespace() {
+                        self.start_line = Some(self.line);
+                    }
+                    self.in_string = c == '\'';
+                    self.current.push(c);
+                }
+            }
+        }
+        self.current.push('\n');
+    }
+
+    fn finish_statement(&mut self) {
+        let sql = std::mem::take(&mut self.current);
+        // A stray `;` is not a statement
+        if let Some(line) = self.start_line.take() {
+            self.ready.push_back(ScriptStatement { line, sql: sql.trim().to_string() });
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for StatementReader<R> {
+    type Item = io::Result<ScriptStatement>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(statement) = self.ready.pop_front() {
+                return Some(Ok(statement));
+            }
+            if self.done {
+                return None;
+            }
+            let mut text = String::new();
+            match self.reader.read_line(&mut text) {
+                Ok(0) => {
+                    // The last statement may leave out its `;`
+                    self.done = true;
+                    self.finish_statement();
+                }
+                Ok(n) => {
+                    self.line += 1;
+                    self.bytes_read += n as u64;
+                    self.split_line(&text);
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,215 @@
+//! Client/server access to a `PersistentDatabase` over TCP, so several
+//! processes can share one data directory.
+//!
+//! Both directions use frames of a 4-byte big-endian length followed by that
+//! many bytes. A request is the UTF-8 text of one SQL statement; the reply is
+//! a [`QueryResult`] as JSON. A connection's requests run in order, and
+//! different connections run concurrently, each with its own transaction,
+//! which locks the tables it uses until it commits, rolls back or
+//! disconnects (which rolls back); see `pool`.
+
+use crate::output::{ColumnMeta, ResultSet, StatementResult};
+use crate::parser::parse_sql;
+use crate::persistent_executor::PersistentDatabase;
+use crate::session::Session;
+use crate::tokenizer::tokenize;
+use serde::{Deserialize, Serialize};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+// Frames this large are refused rather than allocated
+const MAX_FRAME_SIZE: usize = 64 << 20;
+
+/// The reply to one request.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum QueryResult {
+    /// The rows of a SELECT.
+    Rows(ResultSet),
+    /// The message of an INSERT, UPDATE or DELETE, and what it changed;
+    /// see `PersistentDatabase::rows_affected` and `last_insert_rowid`.
+    Changed {
+        message: String,
+        rows_affected: usize,
+        last_insert_rowid: Option<String>,
+    },
+    /// The message of any other statement.
+    Ok(String),
+    Err(String),
+}
+
+impl From<Result<String, String>> for QueryResult {
+    fn from(result: Result<String, String>) -> Self {
+        match result {
+            Ok(output) => QueryResult::Ok(output),
+            Err(e) => QueryResult::Err(e),
+        }
+    }
+}
+
+impl From<Result<StatementResult, String>> for QueryResult {
+    fn from(result: Result<StatementResult, String>) -> Self {
+        match result {
+            Ok(StatementResult::Rows(rows)) => QueryResult::Rows(rows),
+            Ok(StatementResult::Changed { message, rows_affected, last_insert_rowid }) => {
+                QueryResult::Changed { message, rows_affected, last_insert_rowid }
+            }
+            Ok(StatementResult::Done(message)) => QueryResult::Ok(message),
+            Err(e) => QueryResult::Err(e),
+        }
+    }
+}
+
+impl QueryResult {
+    /// The names, tables and types of the columns of a SELECT's rows; empty
+    /// for any other statement.
+    pub fn columns(&self) -> &[ColumnMeta] {
+        match self {
+            QueryResult::Rows(rows) => &rows.meta,
+            _ => &[],
+        }
+    }
+
+    /// The number of rows an INSERT, UPDATE or DELETE changed; 0 for any
+    /// other statement.
+    pub fn rows_affected(&self) -> usize {
+        match self {
+            QueryResult::Changed { rows_affected, .. } => *rows_affected,
+            _ => 0,
+        }
+    }
+
+    /// The key of the last row an INSERT stored.
+    pub fn last_insert_rowid(&self) -> Option<&str> {
+        match self {
+            QueryResult::Changed { last_insert_rowid, .. } => last_insert_rowid.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// The output as `PersistentDatabase::execute` returns it.
+    pub fn into_result(self) -> Result<String, String> {
+        match self {
+            QueryResult::Rows(rows) if rows.rows.is_empty() => Ok("No matching rows found".to_string()),
+            QueryResult::Rows(rows) => Ok(rows.to_string()),
+            QueryResult::Changed { message, .. } | QueryResult::Ok(message) => Ok(message),
+            QueryResult::Err(e) => Err(e),
+        }
+    }
+}
+
+pub fn write_frame(writer: &mut impl Write, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_FRAME_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame too large"));
+    }
+    writer.write_all(&(payload.len() as u32).to_be_bytes())?;
+    writer.write_all(payload)?;
+    writer.flush()
+}
+
+/// Reads one frame; None if the peer closed the connection between frames.
+pub fn read_frame(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {} bytes is too large", len)));
+    }
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+/// Listens for clients of one database; see the module docs.
+pub struct Server {
+    listener: TcpListener,
+    db: Arc<PersistentDatabase>,
+}
+
+impl Server {
+    pub fn bind(db: Arc<PersistentDatabase>, addr: impl ToSocketAddrs) -> io::Result<Self> {
+        Ok(Self { listener: TcpListener::bind(addr)?, db })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Accepts connections, serving each on its own thread. A connection
+    /// that fails to be accepted, e.g. while the process is out of file
+    /// descriptors, is logged to stderr and the server goes on.
+    pub fn run(self) -> io::Result<()> {
+        for stream in self.listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("Failed to accept a connection: {}", e);
+                    // Give whatever ran out a moment to free up
+                    thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+            };
+            let db = self.db.clone();
+            thread::spawn(move || {
+                // A client that goes away mid-frame just ends its connection
+                let _ = serve_connection(db, stream);
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Serves `db` on `addr`; see `Server::run`.
+pub fn serve(db: Arc<PersistentDatabase>, addr: impl ToSocketAddrs) -> io::Result<()> {
+    Server::bind(db, addr)?.run()
+}
+
+// Dropping the session when the client goes away rolls back the transaction
+// it left open
+fn serve_connection(db: Arc<PersistentDatabase>, stream: TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    let mut session = Session::new(db);
+
+    while let Some(request) = read_frame(&mut reader)? {
+        let result = match String::from_utf8(request) {
+            Ok(sql) => tokenize(&sql).and_then(parse_sql).and_then(|stmt| session.execute(stmt)),
+            Err(_) => Err("Request is not valid UTF-8".to_string()),
+        };
+        let mut reply = serde_json::to_vec(&QueryResult::from(result)).map_err(io::Error::other)?;
+        if reply.len() > MAX_FRAME_SIZE {
+            let error = format!("The result is {} bytes, more than the {} a reply can hold", reply.len(), MAX_FRAME_SIZE);
+            reply = serde_json::to_vec(&QueryResult::Err(error)).map_err(io::Error::other)?;
+        }
+        write_frame(&mut writer, &reply)?;
+    }
+    Ok(())
+}
+
+/// A connection to a `Server`.
+pub struct Client {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl Client {
+    pub fn connect(addr: impl ToSocketAddrs) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(Self { reader: BufReader::new(stream.try_clone()?), writer: BufWriter::new(stream) })
+    }
+
+    /// Runs one statement on the server. I/O errors are about the
+    /// connection; SQL errors come back as `QueryResult::Err`.
+    pub fn execute(&mut self, sql: &str) -> io::Result<QueryResult> {
+        write_frame(&mut self.writer, sql.as_bytes())?;
+        let reply = read_frame(&mut self.reader)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "server closed the connection"))?;
+        serde_json::from_slice(&reply).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
@@ -0,0 +1,140 @@
+//! One client's connection to a shared `PersistentDatabase`, and the state
+//! that belongs to the connection rather than to the database: its
+//! transaction and table locks, the variables SET in it, how its results
+//! are printed and who it is authenticated as. The REPL runs its statements
+//! in a session, as does each `Pool` connection and `Server` client.
+//!
+//! `SET name = value` and `SHOW name` read and write the session's
+//! variables; every other statement runs against the database. A session
+//! dropped mid-transaction rolls it back.
+
+use crate::ast::{SQLStatement, SelectStatement, ShowStatement, TransactionStatement, NULL};
+use crate::output::{OutputSettings, ResultSet, RowSink, StatementResult};
+use crate::persistent_executor::PersistentDatabase;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+// The variable SHOW reads the authenticated user from, which can't be SET
+const SESSION_USER: &str = "session_user";
+
+pub struct Session {
+    db: Arc<PersistentDatabase>,
+    // The id the database keys the session's transaction and locks by
+    id: u64,
+    user: Option<String>,
+    variables: BTreeMap<String, String>,
+    settings: OutputSettings,
+}
+
+impl Session {
+    pub fn new(db: Arc<PersistentDatabase>) -> Self {
+        let id = db.new_session();
+        Self { db, id, user: None, variables: BTreeMap::new(), settings: OutputSettings::default() }
+    }
+
+    /// The same session, authenticated as `user`.
+    pub fn with_user(mut self, user: impl Into<String>) -> Self {
+        self.user = Some(user.into());
+        self
+    }
+
+    /// Who the session is authenticated as, which `SHOW session_user` shows
+    /// too; None for an anonymous one.
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    pub fn database(&self) -> &PersistentDatabase {
+        &self.db
+    }
+
+    pub fn settings(&self) -> &OutputSettings {
+        &self.settings
+    }
+
+    pub fn settings_mut(&mut self) -> &mut OutputSettings {
+        &mut self.settings
+    }
+
+    /// A variable's value, by its name in any case.
+    pub fn variable(&self, name: &str) -> Option<&str> {
+        let name = name.to_lowercase();
+        match name.as_str() {
+            SESSION_USER => Some(self.user().unwrap_or(NULL)),
+            _ => self.variables.get(&name).map(String::as_str),
+        }
+    }
+
+    /// The variables SET in the session, by lower-case name.
+    pub fn variables(&self) -> &BTreeMap<String, String> {
+        &self.variables
+    }
+
+    pub fn set_variable(&mut self, name: &str, value: impl Into<String>) -> Result<(), String> {
+        let name = name.to_lowercase();
+        if name == SESSION_USER {
+            return Err(format!("'{}' can't be SET", SESSION_USER));
+        }
+        self.variables.insert(name, value.into());
+        Ok(())
+    }
+
+    pub fn execute(&mut self, stmt: SQLStatement) -> Result<StatementResult, String> {
+        match stmt {
+            SQLStatement::SetVariable(name, value) => {
+                self.set_variable(&name, value)?;
+                Ok(StatementResult::Done(format!("Set {}", name)))
+            }
+            SQLStatement::Show(ShowStatement::Variable(name)) => {
+                let value = self.variable(&name).ok_or_else(|| format!("Unknown session variable '{}'", name))?;
+                let rows = vec![vec![value.to_string()]];
+                Ok(StatementResult::Rows(ResultSet { columns: vec![name], rows, meta: Vec::new() }))
+            }
+            stmt => self.run(|db| db.execute(stmt)),
+        }
+    }
+
+    /// Runs a SELECT, returning its rows rather than formatting them.
+    pub fn query(&self, stmt: &SelectStatement) -> Result<ResultSet, String> {
+        self.run(|db| db.query(stmt))
+    }
+
+    /// Like `query`, handing `sink` the rows as they are read; see
+    /// `PersistentDatabase::query_each`.
+    pub fn query_each(&self, stmt: &SelectStatement, sink: &mut dyn RowSink) -> Result<usize, String> {
+        self.run(|db| db.query_each(stmt, sink))
+    }
+
+    /// See `PersistentDatabase::last_plan_time`.
+    pub fn last_plan_time(&self) -> Duration {
+        self.db.last_plan_time()
+    }
+
+    /// Whether the session has a transaction open, which a deadlock may
+    /// have rolled back.
+    pub fn in_transaction(&self) -> bool {
+        self.run(PersistentDatabase::in_transaction)
+    }
+
+    /// Runs `run` on the database as this session, e.g. to run a script
+    /// inside its transaction.
+    pub fn run<T>(&self, run: impl FnOnce(&PersistentDatabase) -> T) -> T {
+        self.db.in_session(self.id, || run(&self.db))
+    }
+
+    /// Rolls back a transaction the session left open, as dropping it does.
+    pub fn end(&mut self) {
+        self.run(|db| {
+            if db.in_transaction() {
+                let _ = db.execute(SQLStatement::Transaction(TransactionStatement::Rollback));
+            }
+        });
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.end();
+    }
+}
@@ -0,0 +1,81 @@
+//! Recording statements that take too long, for
+//! `PersistentDatabase::log_slow_queries`.
+
+use std::cell::Cell;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A statement that ran for at least the slow-query threshold.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlowQuery {
+    /// The statement, as `Display` on `SQLStatement` writes it.
+    pub sql: String,
+    pub duration: Duration,
+    /// Rows read from storage to run the statement.
+    pub rows_examined: usize,
+    /// Rows a SELECT returned; None for other statements.
+    pub rows_returned: Option<usize>,
+}
+
+impl fmt::Display for SlowQuery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.3}ms examined={}", self.duration.as_secs_f64() * 1000.0, self.rows_examined)?;
+        if let Some(rows) = self.rows_returned {
+            write!(f, " returned={}", rows)?;
+        }
+        write!(f, " {}", self.sql)
+    }
+}
+
+pub(crate) type SlowQueryCallback = Box<dyn Fn(&SlowQuery) + Send + Sync>;
+
+pub(crate) struct SlowQueryLog {
+    pub(crate) threshold: Duration,
+    pub(crate) log: SlowQueryCallback,
+}
+
+impl fmt::Debug for SlowQueryLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SlowQueryLog").field("threshold", &self.threshold).finish_non_exhaustive()
+    }
+}
+
+/// A callback appending each slow query to `path` as a line of seconds since
+/// the epoch followed by the query's `Display`.
+pub(crate) fn file_logger(path: &Path) -> std::io::Result<SlowQueryCallback> {
+    let file = Mutex::new(OpenOptions::new().create(true).append(true).open(path)?);
+    Ok(Box::new(move |query: &SlowQuery| {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut file = file.lock().unwrap_or_else(|e| e.into_inner());
+        // A slow-query log that can't be written mustn't fail the statement
+        let _ = writeln!(file, "{} {}", now, query);
+    }))
+}
+
+thread_local! {
+    // What the statement running on this thread has read and returned
+    static ROWS_EXAMINED: Cell<usize> = const { Cell::new(0) };
+    static ROWS_RETURNED: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+pub(crate) fn start_statement() {
+    ROWS_EXAMINED.with(|rows| rows.set(0));
+    ROWS_RETURNED.with(|rows| rows.set(None));
+}
+
+pub(crate) fn count_row_examined() {
+    ROWS_EXAMINED.with(|rows| rows.set(rows.get() + 1));
+}
+
+pub(crate) fn set_rows_returned(count: usize) {
+    ROWS_RETURNED.with(|rows| rows.set(Some(count)));
+}
+
+/// Rows examined and returned since `start_statement`.
+pub(crate) fn statement_rows() -> (usize, Option<usize>) {
+    (ROWS_EXAMINED.with(Cell::get), ROWS_RETURNED.with(Cell::get))
+}
@@ -1,18 +1,203 @@
 use std::collections::BTreeMap;
+use std::ops::{Bound, RangeBounds};
 use std::fs::{self, File, OpenOptions};
-use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
-use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Condvar, Mutex, OnceLock, PoisonError};
+use std::thread::{self, JoinHandle};
 use serde::{Deserialize, Serialize};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+#[cfg(feature = "fault-injection")]
+use crate::faults::{self, FaultPoint};
 
 const MEMTABLE_SIZE_LIMIT: usize = 1024 * 1024; // 1MB
 const SSTABLE_SIZE_LIMIT: usize = 10 * 1024 * 1024; // 10MB
 
+/// When WAL writes are forced to stable storage with `sync_data`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Durability {
+    /// Every write is synced before it is acknowledged. Writers waiting on
+    /// the same WAL share one sync (group commit).
+    #[default]
+    Always,
+    /// Writes are synced by a background thread once per interval, so up to
+    /// an interval's worth of writes can be lost on power failure.
+    Interval(Duration),
+    /// Writes are only synced when the storage is closed.
+    OnClose,
+}
+
+/// Size limits of an `LSMStorage`; a `PersistentDatabase` can set them per
+/// table.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct StorageConfig {
+    /// The memtable is flushed once it holds this many bytes.
+    pub memtable_size: usize,
+    /// Compaction output is split into SSTables of about this many bytes;
+    /// the same setting as `CompactionConfig::target_file_size`.
+    pub sstable_size: usize,
+    /// Rows last written longer ago than this are left out of reads and
+    /// dropped by compaction.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<Duration>,
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            memtable_size: MEMTABLE_SIZE_LIMIT,
+            sstable_size: SSTABLE_SIZE_LIMIT,
+            ttl: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompactionStrategy {
+    /// Merge every SSTable into a single sorted run.
+    Full,
+    /// Keep SSTables in levels of growing size. Flushed files land in level
+    /// 0; when a level outgrows its budget it is merged into the next one.
+    Leveled,
+}
+
+/// When and how `LSMStorage` compacts its SSTables.
+#[derive(Debug, Clone)]
+pub struct CompactionConfig {
+    pub strategy: CompactionStrategy,
+    /// Number of level-0 (freshly flushed) SSTables that triggers a compaction.
+    pub trigger_file_count: usize,
+    /// Compaction output is split into files of roughly this many bytes.
+    pub target_file_size: usize,
+    /// Size budget of level 1 under `Leveled`; each deeper level gets 10x more.
+    pub max_level_size: usize,
+    /// How long a delete's tombstone is kept. Once older than this, it is
+    /// dropped by the first compaction after which no older SSTable could
+    /// still hold a value for the key.
+    pub tombstone_grace_period: Duration,
+}
+
+impl Default for CompactionConfig {
+    fn default() -> Self {
+        Self {
+            strategy: CompactionStrategy::Full,
+            trigger_file_count: 4,
+            target_file_size: SSTABLE_SIZE_LIMIT,
+            max_level_size: 10 * SSTABLE_SIZE_LIMIT,
+            tombstone_grace_period: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+impl CompactionConfig {
+    fn level_budget(&self, level: usize) -> usize {
+        self.max_level_size.saturating_mul(10usize.saturating_pow(level.saturating_sub(1) as u32))
+    }
+}
+
+/// Caps the I/O of compactions, shared by every storage it's set on: at
+/// most `max_jobs` compact at once, and together they read and write at most
+/// `bytes_per_sec`. A limit of 0, like `None`, is no limit. Compactions run on a background thread, so a throttled
+/// one takes longer without holding up the table's reads or writes.
+#[derive(Debug)]
+pub struct CompactionThrottle {
+    bytes_per_sec: Option<u64>,
+    max_jobs: Option<usize>,
+    state: Mutex<ThrottleState>,
+    job_done: Condvar,
+}
+
+#[derive(Debug)]
+struct ThrottleState {
+    running: usize,
+    // When the I/O charged so far is paid off
+    next_free: Instant,
+}
+
+impl CompactionThrottle {
+    pub fn new(bytes_per_sec: Option<u64>, max_jobs: Option<usize>) -> Self {
+        Self {
+            bytes_per_sec,
+            max_jobs: max_jobs.filter(|&max| max > 0),
+            state: Mutex::new(ThrottleState { running: 0, next_free: Instant::now() }),
+            job_done: Condvar::new(),
+        }
+    }
+
+    // Waits for a job slot, held until the permit is dropped.
+    fn start_job(&self) -> CompactionPermit<'_> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        while self.max_jobs.is_some_and(|max| state.running >= max) {
+            state = self.job_done.wait(state).unwrap_or_else(PoisonError::into_inner);
+        }
+        state.running += 1;
+        CompactionPermit { throttle: self }
+    }
+
+    // Charges `bytes` of I/O, sleeping until the rate allows it. Idle time
+    // isn't banked, so there are no bursts after it.
+    fn charge(&self, bytes: u64) {
+        let Some(bytes_per_sec) = self.bytes_per_sec.filter(|&rate| rate > 0) else {
+            return;
+        };
+        let wait = {
+            let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            let now = Instant::now();
+            state.next_free = state.next_free.max(now) + Duration::from_secs_f64(bytes as f64 / bytes_per_sec as f64);
+            state.next_free - now
+        };
+        thread::sleep(wait);
+    }
+}
+
+// Compaction I/O is charged to the throttle in pieces of about this many
+// bytes, so a compaction sleeps often and briefly rather than once per file.
+const THROTTLE_CHUNK: u64 = 64 * 1024;
+
+// Counts a compaction's I/O and charges it to its throttle, if it has one,
+// a chunk at a time.
+struct ThrottledIo<'a> {
+    throttle: Option<&'a CompactionThrottle>,
+    uncharged: u64,
+}
+
+impl ThrottledIo<'_> {
+    fn add(&mut self, bytes: usize) {
+        let Some(throttle) = self.throttle else {
+            return;
+        };
+        self.uncharged += bytes as u64;
+        if self.uncharged >= THROTTLE_CHUNK {
+            throttle.charge(std::mem::take(&mut self.uncharged));
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some(throttle) = self.throttle {
+            throttle.charge(std::mem::take(&mut self.uncharged));
+        }
+    }
+}
+
+struct CompactionPermit<'a> {
+    throttle: &'a CompactionThrottle,
+}
+
+impl Drop for CompactionPermit<'_> {
+    fn drop(&mut self) {
+        self.throttle.state.lock().unwrap_or_else(PoisonError::into_inner).running -= 1;
+        self.throttle.job_done.notify_one();
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum StorageValue {
     Present(String),
     Deleted,
+    /// A large value moved to the table's `ValueLog`, at this offset.
+    Separated(u64),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -22,17 +207,194 @@ pub struct StorageEntry {
     pub timestamp: u64,
 }
 
+// On-disk format shared by the WAL and SSTables: a header (magic + format
+// version) followed by length-prefixed, checksummed records. Files without
+// the header are from before the binary format and hold one JSON value per line.
+// Version 2 added separated values.
+const FORMAT_MAGIC: &[u8; 4] = b"LSMB";
+pub const FORMAT_VERSION: u8 = 2;
+const RECORD_HEADER_LEN: usize = 12; // u32 payload length + u64 checksum
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FileFormat {
+    Json,
+    Binary,
+}
+
+fn write_file_header(writer: &mut impl Write) -> io::Result<()> {
+    writer.write_all(FORMAT_MAGIC)?;
+    writer.write_all(&[FORMAT_VERSION])
+}
+
+// Opens `path` for reading, positioned just past the header if it has one.
+fn open_for_read(path: &Path) -> io::Result<(FileFormat, BufReader<File>)> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut header = [0u8; 5];
+    let read = read_full(&mut reader, &mut header)?;
+    if read == header.len() && header[..4] == FORMAT_MAGIC[..] {
+        if header[4] > FORMAT_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("{} has unsupported format version {}", path.display(), header[4])));
+        }
+        return Ok((FileFormat::Binary, reader));
+    }
+    // An empty file is a binary file that has no records yet
+    let format = if read == 0 { FileFormat::Binary } else { FileFormat::Json };
+    reader.seek(SeekFrom::Start(0))?;
+    Ok((format, reader))
+}
+
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
+
+fn write_record(writer: &mut impl Write, payload: &[u8]) -> io::Result<usize> {
+    writer.write_all(&(payload.len() as u32).to_le_bytes())?;
+    writer.write_all(&fnv1a(payload, FNV_OFFSET_BASIS).to_le_bytes())?;
+    writer.write_all(payload)?;
+    Ok(RECORD_HEADER_LEN + payload.len())
+}
+
+// Next record's payload, or None at the end of the file. A record that is
+// cut short or fails its checksum is an `InvalidData` error: in an SSTable
+// it means the file is corrupt. Only the WAL, whose last record can be torn
+// by a crash mid-write, reads it as the end; see `WAL::read_batches`.
+fn read_record(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0u8; RECORD_HEADER_LEN];
+    match read_full(reader, &mut header)? {
+        0 => return Ok(None),
+        RECORD_HEADER_LEN => {}
+        _ => return Err(corrupt_record()),
+    }
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as u64;
+    let checksum = u64::from_le_bytes(header[4..].try_into().unwrap());
+
+    let mut payload = Vec::new();
+    reader.take(len).read_to_end(&mut payload)?;
+    if payload.len() as u64 != len || fnv1a(&payload, FNV_OFFSET_BASIS) != checksum {
+        return Err(corrupt_record());
+    }
+    Ok(Some(payload))
+}
+
+// Fills `buf` from `offset` of `file` without moving a shared cursor.
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+    }
+    #[cfg(windows)]
+    {
+        let mut read = 0;
+        while read < buf.len() {
+            match std::os::windows::fs::FileExt::seek_read(file, &mut buf[read..], offset + read as u64)? {
+                0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+                n => read += n,
+            }
+        }
+        Ok(())
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = (file, buf, offset);
+        Err(io::Error::new(io::ErrorKind::Unsupported, "positional reads aren't supported on this platform"))
+    }
+}
+
+fn corrupt_record() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "record is cut short or fails its checksum")
+}
+
+// Slice counterpart of `read_record`: the next record's payload and the
+// number of bytes it takes up, or None at the end.
+#[cfg(feature = "mmap")]
+fn parse_record(buf: &[u8]) -> io::Result<Option<(&[u8], usize)>> {
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    let header = buf.get(..RECORD_HEADER_LEN).ok_or_else(corrupt_record)?;
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let checksum = u64::from_le_bytes(header[4..].try_into().unwrap());
+    let payload = buf.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + len).ok_or_else(corrupt_record)?;
+    if fnv1a(payload, FNV_OFFSET_BASIS) != checksum {
+        return Err(corrupt_record());
+    }
+    Ok(Some((payload, RECORD_HEADER_LEN + len)))
+}
+
+fn encode_entry(buf: &mut Vec<u8>, entry: &StorageEntry) {
+    buf.extend_from_slice(&(entry.key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(entry.key.as_bytes());
+    match &entry.value {
+        StorageValue::Present(value) => {
+            buf.push(0);
+            buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            buf.extend_from_slice(value.as_bytes());
+        }
+        StorageValue::Deleted => buf.push(1),
+        StorageValue::Separated(offset) => {
+            buf.push(2);
+            buf.extend_from_slice(&offset.to_le_bytes());
+        }
+    }
+    buf.extend_from_slice(&entry.timestamp.to_le_bytes());
+}
+
+fn decode_entry(buf: &[u8], pos: &mut usize) -> io::Result<StorageEntry> {
+    fn take<'a>(buf: &'a [u8], pos: &mut usize, len: usize) -> io::Result<&'a [u8]> {
+        let bytes = buf.get(*pos..*pos + len)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "truncated entry"))?;
+        *pos += len;
+        Ok(bytes)
+    }
+    fn take_string(buf: &[u8], pos: &mut usize) -> io::Result<String> {
+        let len = u32::from_le_bytes(take(buf, pos, 4)?.try_into().unwrap()) as usize;
+        String::from_utf8(take(buf, pos, len)?.to_vec())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    let key = take_string(buf, pos)?;
+    let value = match take(buf, pos, 1)?[0] {
+        0 => StorageValue::Present(take_string(buf, pos)?),
+        1 => StorageValue::Deleted,
+        2 => StorageValue::Separated(u64::from_le_bytes(take(buf, pos, 8)?.try_into().unwrap())),
+        tag => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown value tag {}", tag))),
+    };
+    let timestamp = u64::from_le_bytes(take(buf, pos, 8)?.try_into().unwrap());
+    Ok(StorageEntry { key, value, timestamp })
+}
+
 #[derive(Debug)]
 pub struct MemTable {
     data: BTreeMap<String, StorageEntry>,
     size: usize,
+    limit: usize,
+}
+
+impl Default for MemTable {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl MemTable {
     pub fn new() -> Self {
+        Self::with_limit(MEMTABLE_SIZE_LIMIT)
+    }
+
+    /// A memtable that reports itself full at `limit` bytes.
+    pub fn with_limit(limit: usize) -> Self {
         Self {
             data: BTreeMap::new(),
             size: 0,
+            limit,
         }
     }
 
@@ -62,12 +424,20 @@ impl MemTable {
         self.data.insert(key, entry);
     }
 
+    // Applies already timestamped entries, e.g. a WAL batch
+    pub fn apply(&mut self, entries: Vec<StorageEntry>) {
+        for entry in entries {
+            self.size += entry.key.len() + entry.value.serialized_size();
+            self.data.insert(entry.key.clone(), entry);
+        }
+    }
+
     pub fn get(&self, key: &str) -> Option<&StorageEntry> {
         self.data.get(key)
     }
 
     pub fn is_full(&self) -> bool {
-        self.size >= MEMTABLE_SIZE_LIMIT
+        self.size >= self.limit
     }
 
     pub fn clear(&mut self) {
@@ -78,14 +448,81 @@ impl MemTable {
     pub fn iter(&self) -> impl Iterator<Item = (&String, &StorageEntry)> {
         self.data.iter()
     }
+
+    pub fn range(&self, start: &str, end: &str) -> impl Iterator<Item = (&String, &StorageEntry)> {
+        self.data.range::<str, _>((Bound::Included(start), Bound::Included(end)))
+    }
 }
 
-#[derive(Debug)]
+const BLOOM_BITS_PER_KEY: usize = 10;
+const BLOOM_HASHES: u32 = 7; // ~1% false positives at 10 bits per key
+
+/// Bloom filter over an SSTable's keys, so lookups can skip files that
+/// definitely don't contain a key.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_hashes: u32,
+}
+
+impl BloomFilter {
+    pub fn new(expected_keys: usize) -> Self {
+        let num_bits = (expected_keys * BLOOM_BITS_PER_KEY).max(64);
+        Self {
+            bits: vec![0; num_bits.div_ceil(64)],
+            num_hashes: BLOOM_HASHES,
+        }
+    }
+
+    pub fn insert(&mut self, key: &str) {
+        for bit in self.bit_positions(key) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    /// How many keys have been inserted, estimated from the share of bits
+    /// that are set.
+    pub fn estimated_keys(&self) -> u64 {
+        let bits = (self.bits.len() * 64) as f64;
+        let set = self.bits.iter().map(|word| word.count_ones()).sum::<u32>() as f64;
+        if set >= bits {
+            return (bits / BLOOM_BITS_PER_KEY as f64) as u64;
+        }
+        (-bits / self.num_hashes as f64 * (1.0 - set / bits).ln()).round() as u64
+    }
+
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.bit_positions(key).all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    // Double hashing over two FNV-1a hashes. The filter is persisted, so the
+    // hash must be stable across runs, which rules out std's DefaultHasher.
+    fn bit_positions(&self, key: &str) -> impl Iterator<Item = usize> {
+        let h1 = fnv1a(key.as_bytes(), FNV_OFFSET_BASIS);
+        let h2 = fnv1a(key.as_bytes(), 0x84222325cbf29ce4) | 1;
+        let num_bits = (self.bits.len() * 64) as u64;
+        (0..self.num_hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize)
+    }
+}
+
+pub(crate) const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+
+pub(crate) fn fnv1a(bytes: &[u8], basis: u64) -> u64 {
+    bytes.iter().fold(basis, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+#[derive(Debug, Clone)]
 pub struct SSTable {
     pub path: PathBuf,
     pub min_key: String,
     pub max_key: String,
     pub size: usize,
+    pub bloom: Option<BloomFilter>,
+    pub level: usize,
+    pub id: u64,
+    // Shared with the owning `LSMStorage`; every byte read through `iter` is
+    // added to it
+    read_counter: Option<Arc<AtomicU64>>,
 }
 
 impl SSTable {
@@ -95,150 +532,1433 @@ impl SSTable {
             min_key: String::new(),
             max_key: String::new(),
             size: 0,
+            bloom: None,
+            level: 0,
+            id: 0,
+            read_counter: None,
+        }
+    }
+
+    /// Where the table's bloom filter is stored: next to it, as `.bloom`.
+    pub fn bloom_path(&self) -> PathBuf {
+        self.path.with_extension("bloom")
+    }
+
+    /// Opens an SSTable written by an earlier run, recovering its key range.
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        let mut sstable = Self::new(path);
+        let entries = sstable.read_entries()?;
+        if let (Some(first), Some(last)) = (entries.first(), entries.last()) {
+            sstable.min_key = first.key.clone();
+            sstable.max_key = last.key.clone();
+        }
+        sstable.size = fs::metadata(&sstable.path)?.len() as usize;
+        sstable.load_bloom(Some(&entries))?;
+        Ok(sstable)
+    }
+
+    // Loads the table's bloom filter, rebuilding it if it's missing or
+    // unreadable rather than failing.
+    fn load_bloom(&mut self, entries: Option<&[StorageEntry]>) -> io::Result<()> {
+        if let Some(bloom) = fs::read_to_string(self.bloom_path()).ok()
+            .and_then(|json| serde_json::from_str(&json).ok()) {
+            self.bloom = Some(bloom);
+            return Ok(());
+        }
+
+        let read;
+        let entries = match entries {
+            Some(entries) => entries,
+            None => {
+                read = self.read_entries()?;
+                &read
+            }
+        };
+        let mut bloom = BloomFilter::new(entries.len());
+        for entry in entries {
+            bloom.insert(&entry.key);
         }
+        self.bloom = Some(bloom);
+        Ok(())
     }
 
     pub fn write_from_memtable(&mut self, memtable: &MemTable) -> io::Result<()> {
+        let mut entries: Vec<_> = memtable.iter().map(|(_, entry)| entry).collect();
+        entries.sort_by(|a, b| a.key.cmp(&b.key));
+        self.write_entries(&entries)
+    }
+
+    /// Writes `entries`, which must be sorted by key, as this table's contents.
+    pub fn write_entries(&mut self, entries: &[&StorageEntry]) -> io::Result<()> {
         let file = OpenOptions::new()
             .create(true)
             .write(true)
             .truncate(true)
             .open(&self.path)?;
         let mut writer = BufWriter::new(file);
+        write_file_header(&mut writer)?;
+        self.size = FORMAT_MAGIC.len() + 1;
 
-        let mut entries: Vec<_> = memtable.iter().collect();
-        entries.sort_by_key(|(key, _)| key.clone());
-
-        if let Some((first_key, _)) = entries.first() {
-            self.min_key = first_key.to_string();
+        if let Some(first) = entries.first() {
+            self.min_key = first.key.clone();
         }
-        if let Some((last_key, _)) = entries.last() {
-            self.max_key = last_key.to_string();
+        if let Some(last) = entries.last() {
+            self.max_key = last.key.clone();
         }
 
-        for (_, entry) in entries {
-            let line = serde_json::to_string(&entry)?;
-            writeln!(writer, "{}", line)?;
-            self.size += line.len() + 1; // +1 for newline
+        let mut bloom = BloomFilter::new(entries.len());
+        let mut payload = Vec::new();
+        for entry in entries {
+            payload.clear();
+            encode_entry(&mut payload, entry);
+            self.size += write_record(&mut writer, &payload)?;
+            bloom.insert(&entry.key);
         }
 
         writer.flush()?;
+        // The WAL is cleared once a flush finishes, so the table must be on disk first
+        writer.get_ref().sync_all()?;
+        fs::write(self.bloom_path(), serde_json::to_string(&bloom)?)?;
+        self.bloom = Some(bloom);
         Ok(())
     }
 
-    pub fn read_entries(&self) -> io::Result<Vec<StorageEntry>> {
+    /// Streams the table's entries in key order.
+    pub fn iter(&self) -> io::Result<SSTableIter> {
+        #[cfg(feature = "mmap")]
+        if let Some(map) = self.map()? {
+            return Ok(self.iter_from(SSTableSource::Mapped(map, FORMAT_MAGIC.len() + 1)));
+        }
+        let (format, reader) = open_for_read(&self.path)?;
+        Ok(self.iter_from(SSTableSource::Buffered(format, reader)))
+    }
+
+    fn iter_from(&self, source: SSTableSource) -> SSTableIter {
+        SSTableIter { source, done: false, bytes_read: 0, read_counter: self.read_counter.clone() }
+    }
+
+    // Maps a binary table into memory. Empty and JSON tables are left to the
+    // buffered reader.
+    #[cfg(feature = "mmap")]
+    fn map(&self) -> io::Result<Option<memmap2::Mmap>> {
         let file = File::open(&self.path)?;
-        let reader = BufReader::new(file);
-        let mut entries = Vec::new();
+        if file.metadata()?.len() <= (FORMAT_MAGIC.len() + 1) as u64 {
+            return Ok(None);
+        }
+        // SAFETY: SSTables are never modified after being written, only
+        // deleted, which leaves an existing mapping intact
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        if map[..FORMAT_MAGIC.len()] != FORMAT_MAGIC[..] {
+            return Ok(None);
+        }
+        if map[FORMAT_MAGIC.len()] > FORMAT_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("{} has unsupported format version {}", self.path.display(), map[FORMAT_MAGIC.len()])));
+        }
+        Ok(Some(map))
+    }
 
-        for line in reader.lines() {
-            let line = line?;
-            if !line.trim().is_empty() {
-                let entry: StorageEntry = serde_json::from_str(&line)?;
-                entries.push(entry);
+    // Calls `f` with each entry in key order until it returns false.
+    fn scan(&self, mut f: impl FnMut(StorageEntry) -> bool) -> io::Result<()> {
+        for entry in self.iter()? {
+            if !f(entry?) {
+                break;
             }
         }
+        Ok(())
+    }
+
+    pub fn read_entries(&self) -> io::Result<Vec<StorageEntry>> {
+        let mut entries = Vec::new();
+        self.scan(|entry| {
+            entries.push(entry);
+            true
+        })?;
+        Ok(entries)
+    }
+
+    /// Entries with keys in `[start, end]`. Files whose key range doesn't
+    /// overlap are skipped without being opened, and since entries are sorted
+    /// by key, reading stops as soon as the range has been passed.
+    pub fn read_range(&self, start: &str, end: &str) -> io::Result<Vec<StorageEntry>> {
+        if self.max_key.as_str() < start || self.min_key.as_str() > end {
+            return Ok(Vec::new());
+        }
 
+        let mut entries = Vec::new();
+        self.scan(|entry| {
+            if entry.key.as_str() > end {
+                return false;
+            }
+            if entry.key.as_str() >= start {
+                entries.push(entry);
+            }
+            true
+        })?;
         Ok(entries)
     }
 
     pub fn get(&self, key: &str) -> io::Result<Option<StorageEntry>> {
-        // For simplicity, we'll read all entries
-        // In a real implementation, you'd use bloom filters and sparse indexes
-        let entries = self.read_entries()?;
-        Ok(entries.into_iter().find(|entry| entry.key == key))
+        if self.bloom.as_ref().is_some_and(|bloom| !bloom.may_contain(key)) {
+            return Ok(None);
+        }
+
+        // Entries are sorted, so the scan can stop once it's past `key`.
+        // In a real implementation, you'd use sparse indexes
+        let mut found = None;
+        self.scan(|entry| {
+            if entry.key.as_str() < key {
+                return true;
+            }
+            if entry.key == key {
+                found = Some(entry);
+            }
+            false
+        })?;
+        Ok(found)
+    }
+}
+
+// Smallest string greater than every string starting with `prefix`, if any.
+fn prefix_successor(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        // Skips the surrogate gap, which has no chars
+        let next = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32);
+        if let Some(next) = next {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
     }
+    None
 }
 
+/// Reads an SSTable one entry at a time; see [`SSTable::iter`].
 #[derive(Debug)]
-pub struct WAL {
-    path: PathBuf,
-    writer: BufWriter<File>,
+pub struct SSTableIter {
+    source: SSTableSource,
+    done: bool,
+    bytes_read: u64,
+    read_counter: Option<Arc<AtomicU64>>,
 }
 
-impl WAL {
-    pub fn new(data_dir: &Path) -> io::Result<Self> {
-        let wal_path = data_dir.join("wal.log");
-        let file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&wal_path)?;
-        let writer = BufWriter::new(file);
+#[derive(Debug)]
+enum SSTableSource {
+    Buffered(FileFormat, BufReader<File>),
+    // Binary tables only; records are decoded straight out of the mapping
+    #[cfg(feature = "mmap")]
+    Mapped(memmap2::Mmap, usize),
+}
 
-        Ok(Self {
-            path: wal_path,
-            writer,
-        })
+impl SSTableIter {
+    fn read_next(&mut self) -> io::Result<Option<StorageEntry>> {
+        match &mut self.source {
+            SSTableSource::Buffered(FileFormat::Binary, reader) => match read_record(reader)? {
+                Some(payload) => {
+                    self.bytes_read += (RECORD_HEADER_LEN + payload.len()) as u64;
+                    decode_entry(&payload, &mut 0).map(Some)
+                }
+                None => Ok(None),
+            },
+            SSTableSource::Buffered(FileFormat::Json, reader) => {
+                let mut line = String::new();
+                loop {
+                    line.clear();
+                    if reader.read_line(&mut line)? == 0 {
+                        return Ok(None);
+                    }
+                    self.bytes_read += line.len() as u64;
+                    if !line.trim().is_empty() {
+                        return Ok(Some(serde_json::from_str(&line)?));
+                    }
+                }
+            }
+            #[cfg(feature = "mmap")]
+            SSTableSource::Mapped(map, pos) => match parse_record(&map[*pos..])? {
+                Some((payload, len)) => {
+                    *pos += len;
+                    self.bytes_read += len as u64;
+                    decode_entry(payload, &mut 0).map(Some)
+                }
+                None => Ok(None),
+            },
+        }
     }
+}
 
-    pub fn log_insert(&mut self, key: &str, value: &str) -> io::Result<()> {
-        let entry = StorageEntry {
-            key: key.to_string(),
-            value: StorageValue::Present(value.to_string()),
-            timestamp: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_millis() as u64,
-        };
-        let line = serde_json::to_string(&entry)?;
-        writeln!(self.writer, "{}", line)?;
-        self.writer.flush()?;
-        Ok(())
+impl Iterator for SSTableIter {
+    type Item = io::Result<StorageEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let next = self.read_next().transpose();
+        self.done = !matches!(next, Some(Ok(_)));
+        next
     }
+}
 
-    pub fn log_delete(&mut self, key: &str) -> io::Result<()> {
-        let entry = StorageEntry {
-            key: key.to_string(),
-            value: StorageValue::Deleted,
-            timestamp: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_millis() as u64,
-        };
-        let line = serde_json::to_string(&entry)?;
-        writeln!(self.writer, "{}", line)?;
-        self.writer.flush()?;
-        Ok(())
+impl Drop for SSTableIter {
+    fn drop(&mut self) {
+        if let Some(counter) = &self.read_counter {
+            counter.fetch_add(self.bytes_read, Ordering::Relaxed);
+        }
     }
+}
 
-    pub fn clear(&mut self) -> io::Result<()> {
-        let _ = self.writer.flush();
-        let file = OpenOptions::new()
-            .write(true)
-            .truncate(true)
-            .open(&self.path)?;
-        self.writer = BufWriter::new(file);
+/// Live key/value pairs of an `LSMStorage` in key order; see [`LSMStorage::scan`].
+pub struct ScanIter<'a> {
+    // Oldest source first; the memtable is last
+    sources: Vec<Box<dyn Iterator<Item = io::Result<StorageEntry>> + Send + 'a>>,
+    heads: Vec<Option<StorageEntry>>,
+    reverse: bool,
+    values: &'a ValueLog,
+    bytes_returned: &'a AtomicU64,
+    // Entries older than this have expired
+    horizon: u64,
+}
+
+impl ScanIter<'_> {
+    fn advance(&mut self, source: usize) -> io::Result<()> {
+        self.heads[source] = self.sources[source].next().transpose()?;
         Ok(())
     }
 
-    pub fn replay(&self) -> io::Result<Vec<StorageEntry>> {
-        let file = File::open(&self.path)?;
-        let reader = BufReader::new(file);
-        let mut entries = Vec::new();
+    fn next_entry(&mut self) -> io::Result<Option<(String, String)>> {
+        loop {
+            // The smallest key (largest in reverse) wins, and for equal keys
+            // the newest source
+            let Some(newest) = (0..self.heads.len()).rev()
+                .filter(|&i| self.heads[i].is_some())
+                .min_by(|&a, &b| {
+                    let order = self.heads[a].as_ref().unwrap().key.cmp(&self.heads[b].as_ref().unwrap().key);
+                    if self.reverse { order.reverse() } else { order }
+                })
+            else {
+                return Ok(None);
+            };
+            let entry = self.heads[newest].take().unwrap();
+            self.advance(newest)?;
+            // Older versions of the key are shadowed
+            for source in 0..self.heads.len() {
+                if self.heads[source].as_ref().is_some_and(|head| head.key == entry.key) {
+                    self.advance(source)?;
+                }
+            }
 
-        for line in reader.lines() {
-            let line = line?;
-            if !line.trim().is_empty() {
-                let entry: StorageEntry = serde_json::from_str(&line)?;
-                entries.push(entry);
+            if entry.timestamp < self.horizon {
+                continue;
+            }
+            if let Some(value) = self.values.resolve(entry.value)? {
+                self.bytes_returned.fetch_add((entry.key.len() + value.len()) as u64, Ordering::Relaxed);
+                return Ok(Some((entry.key, value)));
             }
         }
-
-        Ok(entries)
     }
 }
 
-#[derive(Debug)]
-pub struct LSMStorage {
-    data_dir: PathBuf,
+impl Iterator for ScanIter<'_> {
+    type Item = io::Result<(String, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_entry() {
+            Ok(next) => next.map(Ok),
+            Err(e) => {
+                self.heads.clear();
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// A group of puts and deletes that `LSMStorage::write` applies atomically:
+/// one WAL record and one memtable update. Later operations on the same key
+/// win over earlier ones.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WriteBatch {
+    ops: Vec<(String, Option<String>)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, key: String, value: String) -> &mut Self {
+        self.ops.push((key, Some(value)));
+        self
+    }
+
+    pub fn delete(&mut self, key: String) -> &mut Self {
+        self.ops.push((key, None));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// The keys the batch writes, in order.
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.ops.iter().map(|(key, _)| key.as_str())
+    }
+}
+
+impl From<Vec<(String, Option<String>)>> for WriteBatch {
+    fn from(ops: Vec<(String, Option<String>)>) -> Self {
+        Self { ops }
+    }
+}
+
+impl IntoIterator for WriteBatch {
+    type Item = (String, Option<String>);
+    type IntoIter = std::vec::IntoIter<(String, Option<String>)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.ops.into_iter()
+    }
+}
+
+/// Makes WAL records durable. Threads that need the same WAL synced share a
+/// single `sync_data` call: the first becomes the leader and syncs every
+/// record written so far, the rest wait for it instead of syncing again.
+#[derive(Debug)]
+pub struct WalSyncer {
+    file: File,
+    state: Mutex<SyncState>,
+    synced: Condvar,
+    // How often the timer thread syncs; None once it should stop
+    sync_interval: Mutex<Option<Duration>>,
+}
+
+#[derive(Debug, Default)]
+struct SyncState {
+    written: u64, // sequence number of the last record written
+    synced: u64,  // sequence number of the last record known to be durable
+    syncing: bool,
+}
+
+impl WalSyncer {
+    fn new(file: &File) -> io::Result<Arc<Self>> {
+        Ok(Arc::new(Self {
+            file: file.try_clone()?,
+            state: Mutex::new(SyncState::default()),
+            synced: Condvar::new(),
+            sync_interval: Mutex::new(None),
+        }))
+    }
+
+    // Syncs every `interval` from a background thread, for
+    // `Durability::Interval`, until it's set to None or the syncer dropped.
+    // A sync that fails is tried again on the next tick.
+    fn set_sync_interval(self: &Arc<Self>, interval: Option<Duration>) {
+        let mut current = self.sync_interval.lock().unwrap_or_else(PoisonError::into_inner);
+        let running = current.is_some();
+        *current = interval;
+        if running || interval.is_none() {
+            return;
+        }
+        let syncer = Arc::downgrade(self);
+        thread::spawn(move || loop {
+            let interval = syncer.upgrade()
+                .and_then(|syncer| *syncer.sync_interval.lock().unwrap_or_else(PoisonError::into_inner));
+            let Some(interval) = interval else {
+                return;
+            };
+            thread::sleep(interval.max(MIN_SYNC_INTERVAL));
+            match syncer.upgrade() {
+                Some(syncer) => { let _ = syncer.sync_all_written(); }
+                None => return,
+            }
+        });
+    }
+
+    fn record_written(&self) -> u64 {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.written += 1;
+        state.written
+    }
+
+    /// Blocks until every record up to `seq` is durable.
+    pub fn sync_through(&self, seq: u64) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            if state.synced >= seq {
+                return Ok(());
+            }
+            if state.syncing {
+                state = self.synced.wait(state).unwrap_or_else(PoisonError::into_inner);
+                continue;
+            }
+
+            state.syncing = true;
+            let target = state.written;
+            drop(state);
+            let result = self.file.sync_data();
+            state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+            state.syncing = false;
+            if result.is_ok() {
+                state.synced = state.synced.max(target);
+            }
+            self.synced.notify_all();
+            result?;
+        }
+    }
+
+    pub fn sync_all_written(&self) -> io::Result<()> {
+        let written = self.state.lock().unwrap_or_else(PoisonError::into_inner).written;
+        self.sync_through(written)
+    }
+}
+
+// The timer thread of `Durability::Interval` waits at least this long
+const MIN_SYNC_INTERVAL: Duration = Duration::from_millis(1);
+
+/// A WAL write that still has to be made durable; see [`LSMStorage::write_deferred`].
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct SyncTicket {
+    syncer: Arc<WalSyncer>,
+    seq: u64,
+}
+
+impl SyncTicket {
+    pub fn wait(&self) -> io::Result<()> {
+        self.syncer.sync_through(self.seq)
+    }
+}
+
+#[derive(Debug)]
+pub struct WAL {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    syncer: Arc<WalSyncer>,
+    archive: bool,
+    retention: Option<Duration>,
+    bytes_written: u64,
+}
+
+/// Directory inside a table's directory holding its archived WAL segments.
+pub const WAL_ARCHIVE_DIR: &str = "wal_archive";
+// The log of a memtable that is being flushed in the background; see `WAL::rotate`.
+const IMMUTABLE_WAL: &str = "wal.imm.log";
+// Kept in `WAL_ARCHIVE_DIR` while the archive holds an unbroken history.
+const HISTORY: &str = "HISTORY";
+// A history is checkpointed each time this many segments were archived
+// since its last checkpoint, so a read replays at most this many.
+const CHECKPOINT_INTERVAL: u64 = 16;
+// Rows per record of a checkpoint file
+const CHECKPOINT_BATCH: usize = 1000;
+
+/// Where the unbroken history in a WAL archive begins: when archiving was
+/// turned on (or, once trimmed, the oldest time it can still be read at),
+/// and the first segment archived since.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct History {
+    pub since: u64,
+    pub first_segment: u64,
+    #[serde(default)]
+    pub checkpoints: Vec<Checkpoint>,
+}
+
+/// Every live row of a history as of the end of archived segment
+/// `segment`, kept in the archive as `checkpoint_<segment>.log`; all of
+/// those segments' batches were written by `through`.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub segment: u64,
+    pub through: u64,
+}
+
+impl WAL {
+    pub fn new(data_dir: &Path) -> io::Result<Self> {
+        let wal_path = data_dir.join("wal.log");
+        if wal_path.exists() {
+            Self::upgrade_json_log(&wal_path)?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&wal_path)?;
+        let syncer = WalSyncer::new(&file)?;
+        let mut writer = BufWriter::new(file);
+        if writer.get_ref().metadata()?.len() == 0 {
+            write_file_header(&mut writer)?;
+            writer.flush()?;
+        }
+
+        Ok(Self {
+            path: wal_path,
+            writer,
+            syncer,
+            archive: false,
+            retention: None,
+            bytes_written: 0,
+        })
+    }
+
+    /// When enabled, the log is copied to a numbered segment in
+    /// `WAL_ARCHIVE_DIR` each time it's cleared or retired instead of being
+    /// discarded.
+    pub fn set_archiving(&mut self, archive: bool) -> io::Result<()> {
+        if archive {
+            fs::create_dir_all(self.path.with_file_name(WAL_ARCHIVE_DIR))?;
+        }
+        self.archive = archive;
+        Ok(())
+    }
+
+    pub fn is_archiving(&self) -> bool {
+        self.archive
+    }
+
+    /// How long back the archive's history is kept. Each time a segment is
+    /// archived, the segments and checkpoints older than the newest
+    /// checkpoint from before then are deleted; `None` keeps everything.
+    pub fn set_retention(&mut self, retention: Option<Duration>) {
+        self.retention = retention;
+    }
+
+    /// The history kept in `archive_dir`, None if there is none or a log
+    /// was discarded without being archived since it began.
+    pub fn history(archive_dir: &Path) -> io::Result<Option<History>> {
+        match fs::read_to_string(archive_dir.join(HISTORY)) {
+            Ok(json) => Ok(Some(serde_json::from_str(&json)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Begins a new history in the archive from now. `base` holds the rows
+    /// written before that which no archived segment has, e.g. ones already
+    /// flushed to SSTables; they're archived as the history's first segment.
+    pub fn start_history(&mut self, base: &[StorageEntry]) -> io::Result<History> {
+        let archive_dir = self.path.with_file_name(WAL_ARCHIVE_DIR);
+        fs::create_dir_all(&archive_dir)?;
+        let history = History {
+            since: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64,
+            first_segment: Self::next_segment_number(&archive_dir)?,
+            checkpoints: Vec::new(),
+        };
+        // Checkpoints of an ended history
+        for entry in fs::read_dir(&archive_dir)? {
+            let path = entry?.path();
+            if Self::checkpoint_number(&path).is_some() {
+                fs::remove_file(path)?;
+            }
+        }
+        if !base.is_empty() {
+            let segment = archive_dir.join(format!("wal_{:06}.log", history.first_segment));
+            let tmp_path = segment.with_extension("tmp");
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            write_file_header(&mut writer)?;
+            write_record(&mut writer, &Self::encode_batch(base))?;
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+            fs::rename(&tmp_path, &segment)?;
+        }
+
+        Self::write_history(&archive_dir, &history)?;
+        Ok(history)
+    }
+
+    fn write_history(archive_dir: &Path, history: &History) -> io::Result<()> {
+        let tmp_path = archive_dir.join(format!("{}.tmp", HISTORY));
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(serde_json::to_string(history)?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, archive_dir.join(HISTORY))
+    }
+
+    // Called before a log with records in it is discarded unarchived: the
+    // archive no longer holds every write, so its history ends.
+    fn end_history(&self) -> io::Result<()> {
+        match fs::remove_file(self.path.with_file_name(WAL_ARCHIVE_DIR).join(HISTORY)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// The archived segments in `archive_dir`, oldest first.
+    pub fn archived_segments(archive_dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(archive_dir)? {
+            let path = entry?.path();
+            if let Some(number) = Self::segment_number(&path) {
+                segments.push((number, path));
+            }
+        }
+        segments.sort();
+        Ok(segments.into_iter().map(|(_, path)| path).collect())
+    }
+
+    fn segment_number(path: &Path) -> Option<u64> {
+        path.file_name()?.to_str()?.strip_prefix("wal_")?.strip_suffix(".log")?.parse().ok()
+    }
+
+    fn checkpoint_number(path: &Path) -> Option<u64> {
+        path.file_name()?.to_str()?.strip_prefix("checkpoint_")?.strip_suffix(".log")?.parse().ok()
+    }
+
+    fn checkpoint_path(archive_dir: &Path, segment: u64) -> PathBuf {
+        archive_dir.join(format!("checkpoint_{:06}.log", segment))
+    }
+
+    // Past every segment and checkpoint, as trimming may leave a checkpoint
+    // newer than every segment
+    fn next_segment_number(archive_dir: &Path) -> io::Result<u64> {
+        let mut next = 1;
+        for entry in fs::read_dir(archive_dir)? {
+            let path = entry?.path();
+            if let Some(number) = Self::segment_number(&path).or_else(|| Self::checkpoint_number(&path)) {
+                next = next.max(number + 1);
+            }
+        }
+        Ok(next)
+    }
+
+    /// The logs to replay, oldest first, to read the table in `table_dir`
+    /// as of `timestamp`: the newest checkpoint of `history` taken by then
+    /// and the segments archived after it, or else the segments from
+    /// `first_segment` on, and then the live logs.
+    pub fn logs_as_of(table_dir: &Path, history: Option<&History>, first_segment: u64, timestamp: u64) -> io::Result<Vec<PathBuf>> {
+        let archive_dir = table_dir.join(WAL_ARCHIVE_DIR);
+        let checkpoint = history.and_then(|history| {
+            history.checkpoints.iter().rev().find(|checkpoint| checkpoint.through <= timestamp)
+        });
+        let mut logs = Vec::new();
+        let first_segment = match checkpoint {
+            Some(checkpoint) => {
+                logs.push(Self::checkpoint_path(&archive_dir, checkpoint.segment));
+                checkpoint.segment + 1
+            }
+            None => first_segment,
+        };
+        logs.extend(Self::archived_segments(&archive_dir)?.into_iter()
+            .filter(|segment| Self::segment_number(segment) >= Some(first_segment)));
+        logs.push(table_dir.join(IMMUTABLE_WAL));
+        logs.push(table_dir.join("wal.log"));
+        Ok(logs)
+    }
+
+    // After segment `segment` is archived, checkpoints the history if it's
+    // due one and trims what the retention period no longer needs.
+    fn maintain_history(&self, archive_dir: &Path, segment: u64) -> io::Result<()> {
+        let Some(mut history) = Self::history(archive_dir)? else {
+            return Ok(());
+        };
+        let last = history.checkpoints.last().copied();
+        let unchecked = last.map_or(history.first_segment, |checkpoint| checkpoint.segment + 1);
+        if segment + 1 >= unchecked + CHECKPOINT_INTERVAL {
+            history.checkpoints.push(Self::write_checkpoint(archive_dir, &history, last, unchecked, segment)?);
+        }
+
+        let Some(retention) = self.retention else {
+            return Self::write_history(archive_dir, &history);
+        };
+        let horizon = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().saturating_sub(retention).as_millis() as u64;
+        let Some(base) = history.checkpoints.iter().rposition(|checkpoint| checkpoint.through < horizon) else {
+            return Self::write_history(archive_dir, &history);
+        };
+        let trimmed: Vec<_> = history.checkpoints.drain(..base).collect();
+        let base = history.checkpoints[0];
+        history.since = history.since.max(base.through);
+        history.first_segment = base.segment + 1;
+        // Recorded first, so a crash only leaves files the history ignores
+        Self::write_history(archive_dir, &history)?;
+        for checkpoint in trimmed {
+            fs::remove_file(Self::checkpoint_path(archive_dir, checkpoint.segment))?;
+        }
+        for path in Self::archived_segments(archive_dir)? {
+            if Self::segment_number(&path).is_some_and(|number| number <= base.segment) {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Writes the rows of `last` (or the history's start) updated by
+    // segments `from..=segment` as a checkpoint.
+    fn write_checkpoint(archive_dir: &Path, history: &History, last: Option<Checkpoint>, from: u64, segment: u64) -> io::Result<Checkpoint> {
+        let mut rows = BTreeMap::new();
+        let mut through = last.map_or(history.since, |checkpoint| checkpoint.through);
+        let mut logs: Vec<_> = last.map(|checkpoint| Self::checkpoint_path(archive_dir, checkpoint.segment)).into_iter().collect();
+        logs.extend(Self::archived_segments(archive_dir)?.into_iter()
+            .filter(|path| Self::segment_number(path).is_some_and(|number| (from..=segment).contains(&number))));
+        for entry in logs.iter().map(|log| Self::read_batches(log)).collect::<io::Result<Vec<_>>>()?.into_iter().flatten().flatten() {
+            through = through.max(entry.timestamp);
+            match entry.value {
+                StorageValue::Deleted => rows.remove(&entry.key),
+                _ => rows.insert(entry.key.clone(), entry),
+            };
+        }
+
+        let path = Self::checkpoint_path(archive_dir, segment);
+        let tmp_path = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        write_file_header(&mut writer)?;
+        let rows: Vec<_> = rows.into_values().collect();
+        for chunk in rows.chunks(CHECKPOINT_BATCH) {
+            write_record(&mut writer, &Self::encode_batch(chunk))?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(Checkpoint { segment, through })
+    }
+
+    // Archives the log at `path`, or ends the history if archiving is off.
+    // A log with no records is left out either way.
+    fn archive_segment(&mut self, path: &Path) -> io::Result<()> {
+        self.writer.flush()?;
+        if fs::metadata(path)?.len() <= (FORMAT_MAGIC.len() + 1) as u64 {
+            return Ok(());
+        }
+        if !self.archive {
+            return self.end_history();
+        }
+        let archive_dir = self.path.with_file_name(WAL_ARCHIVE_DIR);
+        fs::create_dir_all(&archive_dir)?;
+        let number = Self::next_segment_number(&archive_dir)?;
+        let segment = archive_dir.join(format!("wal_{:06}.log", number));
+
+        // Copied rather than renamed so the open file handles stay valid
+        let tmp_path = segment.with_extension("tmp");
+        fs::copy(path, &tmp_path)?;
+        File::open(&tmp_path)?.sync_all()?;
+        fs::rename(&tmp_path, &segment)?;
+        self.maintain_history(&archive_dir, number)
+    }
+
+    /// Sets the log aside as `wal.imm.log` and starts an empty one, so
+    /// writes can continue while the memtable it covers is flushed. Pending
+    /// `SyncTicket`s keep working on the old file.
+    pub fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        let immutable_path = self.path.with_file_name(IMMUTABLE_WAL);
+        fs::rename(&self.path, &immutable_path)?;
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let retired = std::mem::replace(&mut self.syncer, WalSyncer::new(&file)?);
+        self.writer = BufWriter::new(file);
+        write_file_header(&mut self.writer)?;
+        self.writer.flush()?;
+        // Makes the rename and the new log's directory entry durable
+        #[cfg(unix)]
+        File::open(self.path.parent().unwrap_or(Path::new(".")))?.sync_all()?;
+        // The old log's timer stops with it, so it's synced one last time
+        let interval = *retired.sync_interval.lock().unwrap_or_else(PoisonError::into_inner);
+        if interval.is_some() {
+            retired.set_sync_interval(None);
+            retired.sync_all_written()?;
+            self.syncer.set_sync_interval(interval);
+        }
+        Ok(())
+    }
+
+    /// Removes (or archives) the log set aside by `rotate`, once everything
+    /// in it is in an SSTable.
+    pub fn retire_immutable(&mut self) -> io::Result<()> {
+        let immutable_path = self.path.with_file_name(IMMUTABLE_WAL);
+        self.archive_segment(&immutable_path)?;
+        fs::remove_file(immutable_path)
+    }
+
+    /// Entries of a log set aside by `rotate` whose memtable was never
+    /// flushed, e.g. because of a crash; None if there is no such log.
+    pub fn replay_immutable(&self) -> io::Result<Option<Vec<StorageEntry>>> {
+        let immutable_path = self.path.with_file_name(IMMUTABLE_WAL);
+        if !immutable_path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(Self::read_batches(&immutable_path)?.into_iter().flatten().collect()))
+    }
+
+    pub fn syncer(&self) -> Arc<WalSyncer> {
+        self.syncer.clone()
+    }
+
+    /// Syncs the log every `interval` in the background; None stops it.
+    pub fn set_sync_interval(&mut self, interval: Option<Duration>) {
+        self.syncer.set_sync_interval(interval);
+    }
+
+    /// Bytes appended since the log was opened, headers included.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    // Rewrites a WAL from before the binary format in the current one. The new
+    // log is written beside the old one and renamed over it, so a crash
+    // leaves one or the other intact.
+    fn upgrade_json_log(wal_path: &Path) -> io::Result<()> {
+        let (format, reader) = open_for_read(wal_path)?;
+        if format == FileFormat::Binary {
+            return Ok(());
+        }
+
+        let mut batches = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let parsed = if line.starts_with('[') {
+                serde_json::from_str::<Vec<StorageEntry>>(line)
+            } else {
+                serde_json::from_str::<StorageEntry>(line).map(|entry| vec![entry])
+            };
+            match parsed {
+                Ok(batch) => batches.push(batch),
+                // Torn last line, see `read_batches`
+                Err(_) => break,
+            }
+        }
+
+        let tmp_path = wal_path.with_extension("log.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        write_file_header(&mut writer)?;
+        for batch in &batches {
+            write_record(&mut writer, &Self::encode_batch(batch))?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        drop(writer);
+        fs::rename(&tmp_path, wal_path)
+    }
+
+    fn encode_batch(entries: &[StorageEntry]) -> Vec<u8> {
+        let mut payload = (entries.len() as u32).to_le_bytes().to_vec();
+        for entry in entries {
+            encode_entry(&mut payload, entry);
+        }
+        payload
+    }
+
+    fn decode_batch(payload: &[u8]) -> io::Result<Vec<StorageEntry>> {
+        let count = payload.get(..4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "truncated batch"))?;
+        let mut pos = 4;
+        (0..count).map(|_| decode_entry(payload, &mut pos)).collect()
+    }
+
+    pub fn log_insert(&mut self, key: &str, value: &str) -> io::Result<u64> {
+        let entry = StorageEntry {
+            key: key.to_string(),
+            value: StorageValue::Present(value.to_string()),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64,
+        };
+        self.log_batch(&[entry])
+    }
+
+    pub fn log_delete(&mut self, key: &str) -> io::Result<u64> {
+        let entry = StorageEntry {
+            key: key.to_string(),
+            value: StorageValue::Deleted,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64,
+        };
+        self.log_batch(&[entry])
+    }
+
+    // Writes several entries as one checksummed record, so replay sees either
+    // all of them or, if the record was torn, none. The record reaches the OS
+    // but isn't synced; the returned sequence number is for `WalSyncer`.
+    pub fn log_batch(&mut self, entries: &[StorageEntry]) -> io::Result<u64> {
+        self.bytes_written += write_record(&mut self.writer, &Self::encode_batch(entries))? as u64;
+        self.writer.flush()?;
+        Ok(self.syncer.record_written())
+    }
+
+    pub fn clear(&mut self) -> io::Result<()> {
+        let path = self.path.clone();
+        self.archive_segment(&path)?;
+        let _ = self.writer.flush();
+        let file = OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.writer = BufWriter::new(file);
+        write_file_header(&mut self.writer)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// The logged batches in write order, one per `log_*` call.
+    pub fn replay_batches(&self) -> io::Result<Vec<Vec<StorageEntry>>> {
+        Self::read_batches(&self.path)
+    }
+
+    /// Reads the batches of a WAL file, e.g. an archived segment. A record
+    /// torn by a crash mid-write ends the log: it was never acknowledged, and
+    /// nothing was written after it.
+    pub fn read_batches(path: &Path) -> io::Result<Vec<Vec<StorageEntry>>> {
+        let (_, mut reader) = open_for_read(path)?;
+        let mut batches = Vec::new();
+        loop {
+            match read_record(&mut reader) {
+                Ok(Some(payload)) => batches.push(Self::decode_batch(&payload)?),
+                Ok(None) => break,
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(batches)
+    }
+
+    pub fn replay(&self) -> io::Result<Vec<StorageEntry>> {
+        Ok(self.replay_batches()?.into_iter().flatten().collect())
+    }
+}
+
+const VALUE_LOG: &str = "vlog.log";
+/// A threshold for `LSMStorage::set_value_threshold` that suits values of a
+/// few KB or more. Tables keep every value inline unless one is set.
+pub const DEFAULT_VALUE_THRESHOLD: usize = 4096;
+
+/// Holds values too large to keep inline in SSTables (WiscKey-style value
+/// separation). SSTables store a value's offset here instead, so compactions
+/// only rewrite the small pointer. Space taken by values that were later
+/// overwritten or deleted is not reclaimed, which is why separation is off
+/// unless a table turns it on.
+#[derive(Debug)]
+pub struct ValueLog {
+    path: PathBuf,
+    writer: Option<BufWriter<File>>,
+    len: u64,
+    // Read at an offset rather than from a cursor, so readers share it
+    reader: OnceLock<File>,
+}
+
+impl ValueLog {
+    pub fn open(table_dir: &Path) -> io::Result<Self> {
+        let path = table_dir.join(VALUE_LOG);
+        let len = match fs::metadata(&path) {
+            Ok(metadata) => metadata.len(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e),
+        };
+        Ok(Self { path, writer: None, len, reader: OnceLock::new() })
+    }
+
+    /// Appends `value`, returning its offset. The file is only created once
+    /// a value needs it.
+    pub fn append(&mut self, value: &str) -> io::Result<u64> {
+        if self.writer.is_none() {
+            let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+            let mut writer = BufWriter::new(file);
+            if self.len == 0 {
+                write_file_header(&mut writer)?;
+                self.len = (FORMAT_MAGIC.len() + 1) as u64;
+            }
+            self.writer = Some(writer);
+        }
+        let offset = self.len;
+        self.len += write_record(self.writer.as_mut().unwrap(), value.as_bytes())? as u64;
+        Ok(offset)
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        if let Some(file) = self.flushed_file()? {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    // Pushes buffered appends to the OS and returns a handle another thread
+    // can sync them with.
+    fn flushed_file(&mut self) -> io::Result<Option<File>> {
+        match &mut self.writer {
+            Some(writer) => {
+                writer.flush()?;
+                Ok(Some(writer.get_ref().try_clone()?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // The value a stored value stands for, reading it from the log if it was separated.
+    fn resolve(&self, value: StorageValue) -> io::Result<Option<String>> {
+        match value {
+            StorageValue::Present(value) => Ok(Some(value)),
+            StorageValue::Deleted => Ok(None),
+            StorageValue::Separated(offset) => self.read(offset).map(Some),
+        }
+    }
+
+    pub fn read(&self, offset: u64) -> io::Result<String> {
+        let file = match self.reader.get() {
+            Some(file) => file,
+            None => {
+                // Another reader may open it too; either handle will do
+                let _ = self.reader.set(File::open(&self.path)?);
+                self.reader.get().unwrap()
+            }
+        };
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData,
+            format!("{} has no valid record at offset {}", self.path.display(), offset));
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        read_exact_at(file, &mut header, offset).map_err(|_| invalid())?;
+        let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+        let checksum = u64::from_le_bytes(header[4..].try_into().unwrap());
+        let mut payload = vec![0u8; len];
+        read_exact_at(file, &mut payload, offset + RECORD_HEADER_LEN as u64).map_err(|_| invalid())?;
+        if fnv1a(&payload, FNV_OFFSET_BASIS) != checksum {
+            return Err(invalid());
+        }
+        String::from_utf8(payload).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+const MANIFEST: &str = "MANIFEST";
+
+/// Copies a table directory that nothing is writing to into `dest`.
+/// SSTables are never modified once written, so they are hard-linked where
+/// the file system allows it.
+pub fn copy_table_files(table_dir: &Path, dest: &Path) -> io::Result<()> {
+    copy_table_files_since(table_dir, dest, &BackedUpFiles::new()).map(|_| ())
+}
+
+/// An SSTable file as a backup saw it. Its size and modification time tell
+/// it apart from a later file of the same name, e.g. once the table has been
+/// dropped and created again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BackedUpFile {
+    pub len: u64,
+    pub modified: u64,
+}
+
+/// The SSTables of a table directory, by file name.
+pub type BackedUpFiles = BTreeMap<String, BackedUpFile>;
+
+/// Like `copy_table_files`, but leaves out the SSTables, and their bloom
+/// filters, that `since` holds unchanged. Returns every SSTable in the
+/// directory, for the next incremental copy.
+pub fn copy_table_files_since(table_dir: &Path, dest: &Path, since: &BackedUpFiles) -> io::Result<BackedUpFiles> {
+    fs::create_dir_all(dest)?;
+    let backed_up = |path: &Path| -> io::Result<BackedUpFile> {
+        let metadata = fs::metadata(path)?;
+        let modified = metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64;
+        Ok(BackedUpFile { len: metadata.len(), modified })
+    };
+    let mut sstables = BackedUpFiles::new();
+    for entry in fs::read_dir(table_dir)? {
+        let path = entry?.path();
+        let Some(file_name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        if !path.is_file() || file_name.ends_with(".tmp") {
+            continue;
+        }
+        let immutable = LSMStorage::parse_sstable_name(file_name).is_some();
+        let sstable_name = match file_name.strip_suffix(".bloom") {
+            Some(stem) => format!("{}.log", stem),
+            None => file_name.to_string(),
+        };
+        if let Some(previous) = since.get(&sstable_name) {
+            if backed_up(&table_dir.join(&sstable_name)).is_ok_and(|file| file == *previous) {
+                if immutable {
+                    sstables.insert(sstable_name, previous.clone());
+                }
+                continue;
+            }
+        }
+        if immutable {
+            sstables.insert(sstable_name, backed_up(&path)?);
+        }
+        let target = dest.join(file_name);
+        if !immutable || fs::hard_link(&path, &target).is_err() {
+            fs::copy(&path, &target)?;
+        }
+    }
+    Ok(sstables)
+}
+
+// The live SSTable set of a table. Flushes and compactions write their files
+// first and then replace the manifest, so any SSTable file it doesn't list
+// was left behind by a crash and is removed on open.
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    next_sstable_id: u64,
+    sstables: Vec<ManifestEntry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ManifestEntry {
+    file: String,
+    level: usize,
+    id: u64,
+    min_key: String,
+    max_key: String,
+    size: usize,
+}
+
+/// I/O counters of an `LSMStorage` since it was opened; see
+/// See [`LSMStorage::generation`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Generation(Vec<Option<(u64, Option<SystemTime>)>>);
+
+/// [`LSMStorage::stats`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StorageStats {
+    pub wal_bytes_written: u64,
+    /// SSTable and value log bytes written by flushes.
+    pub flush_bytes_written: u64,
+    pub compaction_bytes_read: u64,
+    pub compaction_bytes_written: u64,
+    /// SSTable bytes read by `get` and scans.
+    pub query_bytes_read: u64,
+    /// Key and value bytes returned by `get` and scans.
+    pub user_bytes_read: u64,
+    pub flushes: u64,
+    pub flush_time: Duration,
+    pub compactions: u64,
+    pub compaction_time: Duration,
+    pub last_compaction: Option<SystemTime>,
+}
+
+impl StorageStats {
+    /// Bytes written to disk per byte logged to the WAL.
+    pub fn write_amplification(&self) -> f64 {
+        if self.wal_bytes_written == 0 {
+            return 0.0;
+        }
+        let total = self.wal_bytes_written + self.flush_bytes_written + self.compaction_bytes_written;
+        total as f64 / self.wal_bytes_written as f64
+    }
+
+    /// SSTable bytes read per byte returned to callers.
+    pub fn read_amplification(&self) -> f64 {
+        if self.user_bytes_read == 0 {
+            return 0.0;
+        }
+        self.query_bytes_read as f64 / self.user_bytes_read as f64
+    }
+
+    /// Adds `other`'s counters to these, e.g. to total several tables.
+    pub fn add(&mut self, other: &StorageStats) {
+        self.wal_bytes_written += other.wal_bytes_written;
+        self.flush_bytes_written += other.flush_bytes_written;
+        self.compaction_bytes_read += other.compaction_bytes_read;
+        self.compaction_bytes_written += other.compaction_bytes_written;
+        self.query_bytes_read += other.query_bytes_read;
+        self.user_bytes_read += other.user_bytes_read;
+        self.flushes += other.flushes;
+        self.flush_time += other.flush_time;
+        self.compactions += other.compactions;
+        self.compaction_time += other.compaction_time;
+        self.last_compaction = self.last_compaction.max(other.last_compaction);
+    }
+}
+
+/// What a table holds and takes up on disk; see [`LSMStorage::status`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TableStatus {
+    /// Rows in the memtable and SSTables, the latter estimated from their
+    /// bloom filters. A row rewritten or deleted since its SSTable was
+    /// written is counted again.
+    pub estimated_rows: u64,
+    pub sstables: usize,
+    /// Every file of the table, its WAL archive included.
+    pub disk_bytes: u64,
+    /// The live WAL and the log of a memtable being flushed.
+    pub wal_bytes: u64,
+    /// When a compaction last wrote an SSTable that is still live, or last
+    /// finished since the table was opened.
+    pub last_compaction: Option<SystemTime>,
+}
+
+impl TableStatus {
+    /// Adds `other`'s counts to these, e.g. to total a table's shards.
+    pub fn add(&mut self, other: &TableStatus) {
+        self.estimated_rows += other.estimated_rows;
+        self.sstables += other.sstables;
+        self.disk_bytes += other.disk_bytes;
+        self.wal_bytes += other.wal_bytes;
+        self.last_compaction = self.last_compaction.max(other.last_compaction);
+    }
+}
+
+// The bytes of every file under `dir`
+fn dir_size(dir: &Path) -> io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        size += match entry.file_type()?.is_dir() {
+            true => dir_size(&entry.path())?,
+            false => entry.metadata()?.len(),
+        };
+    }
+    Ok(size)
+}
+
+// A full memtable whose SSTable is being written by a background thread. It
+// keeps serving reads until the SSTable is installed.
+#[derive(Debug)]
+struct FlushingMemTable {
+    memtable: MemTable,
+    // What the SSTable holds: the memtable's entries, large values already
+    // moved to the value log
+    entries: Arc<Vec<StorageEntry>>,
+    started: Instant,
+    value_log_bytes: u64,
+    task: JoinHandle<io::Result<SSTable>>,
+}
+
+// A compaction running on a background thread. Its inputs keep serving
+// reads until its outputs are swapped in for them.
+#[derive(Debug)]
+struct RunningCompaction {
+    inputs: Vec<PathBuf>,
+    started: Instant,
+    task: JoinHandle<io::Result<CompactionOutput>>,
+}
+
+#[derive(Debug)]
+struct CompactionOutput {
+    sstables: Vec<SSTable>,
+    bytes_read: u64,
+}
+
+// What a background compaction reads, and how it writes its outputs.
+struct CompactionJob {
+    // Copies of the input SSTables, oldest first, that don't count as query reads
+    inputs: Vec<SSTable>,
+    // SSTables in deeper levels than the inputs, which hold older data
+    older: Vec<SSTable>,
+    output_level: usize,
+    target_file_size: usize,
+    // Tombstones older than this can go if no older SSTable has the key
+    tombstone_horizon: u64,
+    expiry_horizon: u64,
+    data_dir: PathBuf,
+    next_sstable_id: Arc<AtomicU64>,
+    throttle: Option<Arc<CompactionThrottle>>,
+}
+
+impl CompactionJob {
+    // Writes the merge of the inputs as new SSTables. Outputs are written and
+    // swapped in by the manifest before the inputs are removed; a crash
+    // before the swap leaves orphaned outputs, after it orphaned inputs, both
+    // of which are cleaned up on open.
+    fn run(self) -> io::Result<CompactionOutput> {
+        let _permit = self.throttle.as_deref().map(CompactionThrottle::start_job);
+        let mut io = ThrottledIo { throttle: self.throttle.as_deref(), uncharged: 0 };
+
+        // Inputs are oldest first, so newer versions of a key overwrite older ones
+        let mut merged: BTreeMap<String, StorageEntry> = BTreeMap::new();
+        for sstable in &self.inputs {
+            for entry in sstable.iter()? {
+                let entry = entry?;
+                io.add(entry.key.len() + entry.value.serialized_size());
+                merged.insert(entry.key.clone(), entry);
+            }
+        }
+
+        // Any older version of an expired row has expired too, and deeper
+        // levels than the merged ones hold older data, which a tombstone may
+        // still need to shadow
+        merged.retain(|key, entry| {
+            if entry.timestamp < self.expiry_horizon && !matches!(entry.value, StorageValue::Deleted) {
+                return false;
+            }
+            !matches!(entry.value, StorageValue::Deleted)
+                || entry.timestamp > self.tombstone_horizon
+                || self.older.iter().any(|t| {
+                    t.min_key <= *key && *key <= t.max_key
+                        && t.bloom.as_ref().is_none_or(|bloom| bloom.may_contain(key))
+                })
+        });
+
+        let mut outputs = Vec::new();
+        let mut chunk: Vec<&StorageEntry> = Vec::new();
+        let mut chunk_size = 0;
+        for entry in merged.values() {
+            let size = entry.key.len() + entry.value.serialized_size();
+            io.add(size);
+            chunk_size += size;
+            chunk.push(entry);
+            if chunk_size >= self.target_file_size {
+                outputs.push(self.write_output(&chunk)?);
+                chunk.clear();
+                chunk_size = 0;
+            }
+        }
+        if !chunk.is_empty() {
+            outputs.push(self.write_output(&chunk)?);
+        }
+        io.finish();
+
+        #[cfg(feature = "fault-injection")]
+        faults::check(&self.data_dir, FaultPoint::Compaction)?;
+        let bytes_read = self.inputs.iter().map(|t| t.size as u64).sum();
+        Ok(CompactionOutput { sstables: outputs, bytes_read })
+    }
+
+    fn write_output(&self, entries: &[&StorageEntry]) -> io::Result<SSTable> {
+        let id = self.next_sstable_id.fetch_add(1, Ordering::Relaxed);
+        let mut sstable = sstable_file(&self.data_dir, self.output_level, id);
+        sstable.write_entries(entries)?;
+        Ok(sstable)
+    }
+}
+
+fn sstable_file(table_dir: &Path, level: usize, id: u64) -> SSTable {
+    let mut sstable = SSTable::new(table_dir.join(format!("sstable_{}_{}.log", level, id)));
+    sstable.level = level;
+    sstable.id = id;
+    sstable
+}
+
+#[derive(Debug)]
+pub struct LSMStorage {
+    data_dir: PathBuf,
     memtable: MemTable,
+    immutable: Option<FlushingMemTable>,
+    compacting: Option<RunningCompaction>,
     sstables: Vec<SSTable>,
-    wal: WAL,
+    wal: Option<WAL>, // None when read-only
+    #[allow(dead_code)]
     table_prefix: String,
+    compaction: CompactionConfig,
+    throttle: Option<Arc<CompactionThrottle>>,
+    // Shared with a running compaction, which numbers its outputs
+    next_sstable_id: Arc<AtomicU64>,
+    durability: Durability,
+    values: ValueLog,
+    value_threshold: Option<usize>,
+    ttl: Option<Duration>,
+    // Write-side counters; the read side is counted through the atomics below
+    // because reads only borrow the storage
+    stats: StorageStats,
+    query_bytes_read: Arc<AtomicU64>,
+    user_bytes_read: AtomicU64,
+    // Sent each batch once it is in the WAL; see `subscribe_wal`
+    wal_listeners: Vec<Sender<Vec<StorageEntry>>>,
 }
 
 impl LSMStorage {
     pub fn new(data_dir: &Path, table_name: &str) -> io::Result<Self> {
+        Self::with_config(data_dir, table_name, CompactionConfig::default())
+    }
+
+    pub fn with_config(data_dir: &Path, table_name: &str, compaction: CompactionConfig) -> io::Result<Self> {
         fs::create_dir_all(data_dir)?;
         
         let table_dir = data_dir.join(table_name);
@@ -246,181 +1966,930 @@ impl LSMStorage {
 
         let wal = WAL::new(&table_dir)?;
         let mut memtable = MemTable::new();
-        let sstables = Vec::new();
-
         // Replay WAL to recover any data that was in MemTable
         if let Ok(entries) = wal.replay() {
-            for entry in entries {
-                match entry.value {
-                    StorageValue::Present(value) => memtable.insert(entry.key, value),
-                    StorageValue::Deleted => memtable.delete(entry.key),
+            memtable.apply(entries);
+        }
+        // A memtable that was being flushed when the table was last closed
+        let immutable = wal.replay_immutable()?;
+
+        let mut storage = Self::load(table_dir, table_name, compaction, Some(wal), memtable)?;
+        if let Some(entries) = immutable {
+            let mut memtable = MemTable::new();
+            memtable.apply(entries);
+            storage.spawn_flush(memtable)?;
+        }
+        Ok(storage)
+    }
+
+    /// Opens a table without changing anything on disk: nothing is flushed
+    /// or cleaned up, and writes fail. Another process may keep writing the
+    /// table; this sees it as of the call.
+    pub fn open_read_only(data_dir: &Path, table_name: &str) -> io::Result<Self> {
+        let table_dir = data_dir.join(table_name);
+        if !table_dir.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                format!("{} does not exist", table_dir.display())));
+        }
+
+        // Logs before the manifest: anything a writer flushes out of them in
+        // the meantime is in an SSTable the manifest already lists
+        let live = match table_dir.join("wal.log") {
+            path if path.exists() => WAL::read_batches(&path)?,
+            _ => Vec::new(),
+        };
+        let mut memtable = MemTable::new();
+        let immutable = table_dir.join(IMMUTABLE_WAL);
+        if immutable.exists() {
+            match WAL::read_batches(&immutable) {
+                Ok(batches) => batches.into_iter().for_each(|batch| memtable.apply(batch)),
+                // Retired by the writer since the live log was read
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        live.into_iter().for_each(|batch| memtable.apply(batch));
+
+        Self::load(table_dir, table_name, CompactionConfig::default(), None, memtable)
+    }
+
+    /// The size and modification time of the files `open_read_only` reads
+    /// besides the SSTables: the manifest and the WALs. Every write, flush
+    /// and compaction changes it, so a reader can tell whether a table it
+    /// opened is out of date.
+    pub fn generation(table_dir: &Path) -> Generation {
+        Generation([MANIFEST, "wal.log", IMMUTABLE_WAL].iter()
+            .map(|name| fs::metadata(table_dir.join(name)).ok().map(|m| (m.len(), m.modified().ok())))
+            .collect())
+    }
+
+    // Loads the SSTables around an already replayed memtable. Without a WAL
+    // the storage is read-only and leaves the directory as it is.
+    fn load(table_dir: PathBuf, table_name: &str, compaction: CompactionConfig, wal: Option<WAL>, memtable: MemTable) -> io::Result<Self> {
+        let values = ValueLog::open(&table_dir)?;
+        let (mut sstables, next_sstable_id) = match Self::load_manifest(&table_dir, wal.is_some())? {
+            Some(loaded) => loaded,
+            None => {
+                // Tables written before the manifest existed: every SSTable
+                // file in the directory is live
+                let sstables = Self::load_sstables(&table_dir)?;
+                let next_sstable_id = sstables.iter().map(|t| t.id + 1).max().unwrap_or(0);
+                if wal.is_some() {
+                    Self::write_manifest_file(&table_dir, &sstables, next_sstable_id)?;
                 }
+                (sstables, next_sstable_id)
             }
+        };
+
+        let query_bytes_read = Arc::new(AtomicU64::new(0));
+        for sstable in &mut sstables {
+            sstable.read_counter = Some(query_bytes_read.clone());
         }
 
         Ok(Self {
             data_dir: table_dir,
             memtable,
+            immutable: None,
+            compacting: None,
             sstables,
             wal,
             table_prefix: table_name.to_string(),
+            compaction,
+            throttle: None,
+            ttl: None,
+            next_sstable_id: Arc::new(AtomicU64::new(next_sstable_id)),
+            durability: Durability::default(),
+            values,
+            value_threshold: None,
+            stats: StorageStats::default(),
+            query_bytes_read,
+            user_bytes_read: AtomicU64::new(0),
+            wal_listeners: Vec::new(),
         })
     }
 
-    pub fn insert(&mut self, key: String, value: String) -> io::Result<()> {
-        self.wal.log_insert(&key, &value)?;
-        self.memtable.insert(key, value);
+    /// Sends `listener` every batch written from now on, as it is logged to
+    /// the WAL, until its receiver is dropped.
+    pub fn subscribe_wal(&mut self, listener: Sender<Vec<StorageEntry>>) {
+        self.wal_listeners.push(listener);
+    }
 
-        if self.memtable.is_full() {
-            self.flush_memtable()?;
+    pub fn is_read_only(&self) -> bool {
+        self.wal.is_none()
+    }
+
+    fn wal(&mut self) -> io::Result<&mut WAL> {
+        self.wal.as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::PermissionDenied, "table is open read-only"))
+    }
+
+    /// I/O counters since the storage was opened.
+    pub fn stats(&self) -> StorageStats {
+        StorageStats {
+            wal_bytes_written: self.wal.as_ref().map_or(0, WAL::bytes_written),
+            query_bytes_read: self.query_bytes_read.load(Ordering::Relaxed),
+            user_bytes_read: self.user_bytes_read.load(Ordering::Relaxed),
+            ..self.stats.clone()
+        }
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    /// Row estimate, file counts and sizes of the table.
+    pub fn status(&self) -> io::Result<TableStatus> {
+        let memtable_rows = self.memtable.iter()
+            .chain(self.immutable.iter().flat_map(|flushing| flushing.memtable.iter()))
+            .filter(|(_, entry)| !matches!(entry.value, StorageValue::Deleted))
+            .count() as u64;
+        let sstable_rows: u64 = self.sstables.iter()
+            .map(|t| t.bloom.as_ref().map_or(0, BloomFilter::estimated_keys))
+            .sum();
+        let mut wal_bytes = 0;
+        for log in ["wal.log", IMMUTABLE_WAL] {
+            wal_bytes += fs::metadata(self.data_dir.join(log)).map_or(0, |m| m.len());
+        }
+        // Compactions write their outputs below level 0, which only flushes write to
+        let compacted = self.sstables.iter()
+            .filter(|t| t.level > 0)
+            .filter_map(|t| fs::metadata(&t.path).and_then(|m| m.modified()).ok())
+            .max();
+        Ok(TableStatus {
+            estimated_rows: memtable_rows + sstable_rows,
+            sstables: self.sstables.len(),
+            disk_bytes: dir_size(&self.data_dir)?,
+            wal_bytes,
+            last_compaction: compacted.max(self.stats.last_compaction),
+        })
+    }
+
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.durability = durability;
+        if let Some(wal) = &mut self.wal {
+            wal.set_sync_interval(match durability {
+                Durability::Interval(interval) => Some(interval),
+                _ => None,
+            });
+        }
+    }
+
+    /// Values longer than this many bytes are moved to the value log when
+    /// flushed; `None` keeps every value inline.
+    pub fn value_threshold(&self) -> Option<usize> {
+        self.value_threshold
+    }
+
+    pub fn set_value_threshold(&mut self, threshold: Option<usize>) {
+        self.value_threshold = threshold;
+    }
+
+    /// Turning archiving on begins the history `scan_as_of` reads, unless
+    /// one is already unbroken.
+    pub fn set_wal_archiving(&mut self, archive: bool) -> io::Result<()> {
+        let Some(wal) = &mut self.wal else {
+            return Ok(());
+        };
+        wal.set_archiving(archive)?;
+        if archive && WAL::history(&self.data_dir.join(WAL_ARCHIVE_DIR))?.is_none() {
+            // What's in the SSTables was never archived, or not since the
+            // last history ended
+            let mut rows = BTreeMap::new();
+            for sstable in &self.sstables {
+                for entry in sstable.iter()? {
+                    let entry = entry?;
+                    rows.insert(entry.key.clone(), entry);
+                }
+            }
+            let base: Vec<_> = rows.into_values()
+                .filter(|entry| !matches!(entry.value, StorageValue::Deleted))
+                .collect();
+            wal.start_history(&base)?;
+        }
+        Ok(())
+    }
+
+    /// See `WAL::set_retention`.
+    pub fn set_wal_retention(&mut self, retention: Option<Duration>) {
+        if let Some(wal) = &mut self.wal {
+            wal.set_retention(retention);
+        }
+    }
+
+    /// Rebuilds table `table_name` of `data_dir` as it was at `timestamp`
+    /// (milliseconds since the Unix epoch) in `dest_dir`, by replaying its
+    /// archived WAL segments, from the newest checkpoint taken by then, and
+    /// then its live WAL up to that point. Only complete if WAL archiving
+    /// was enabled since the table was created, and refused for a time
+    /// the WAL retention has trimmed away.
+    pub fn restore_to(data_dir: &Path, table_name: &str, dest_dir: &Path, timestamp: u64) -> io::Result<()> {
+        let table_dir = data_dir.join(table_name);
+        let archive_dir = table_dir.join(WAL_ARCHIVE_DIR);
+        if !archive_dir.is_dir() {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                format!("{} has no WAL archive", table_dir.display())));
+        }
+        if dest_dir.join(table_name).exists() {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                format!("{} already exists", dest_dir.join(table_name).display())));
+        }
+
+        let history = WAL::history(&archive_dir)?;
+        // A trimmed history keeps a checkpoint from before its first
+        // segment, and nothing older
+        if let Some(history) = &history {
+            let trimmed = history.checkpoints.first().is_some_and(|checkpoint| checkpoint.segment < history.first_segment);
+            if trimmed && timestamp < history.since {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                    format!("its WAL archive only goes back to {}", history.since)));
+            }
+        }
+        let segments = WAL::logs_as_of(&table_dir, history.as_ref(), 0, timestamp)?;
+        let mut restored = LSMStorage::new(dest_dir, table_name)?;
+        restored.set_durability(Durability::OnClose);
+        'replay: for segment in segments {
+            if !segment.exists() {
+                continue;
+            }
+            for batch in WAL::read_batches(&segment)? {
+                if batch.iter().any(|entry| entry.timestamp > timestamp) {
+                    break 'replay;
+                }
+                let _ = restored.write_entries(batch)?;
+            }
+        }
+        restored.close()
+    }
+
+    /// The live key/value pairs as they were at `timestamp`, in key order,
+    /// replayed from the WAL archive up to the last batch written by then.
+    /// History begins when archiving is turned on, and ends if a log is
+    /// ever discarded unarchived, e.g. while the table was open without
+    /// archiving; reading before it began, or from a table that isn't
+    /// archiving now, is an error, as is reading from before what the WAL
+    /// retention has kept. Each read replays the newest checkpoint taken by
+    /// then and the at most `CHECKPOINT_INTERVAL` segments archived after
+    /// it, so its cost is the table's size plus that many segments.
+    pub fn scan_as_of(&self, timestamp: u64) -> io::Result<Vec<(String, String)>> {
+        if self.wal.as_ref().is_some_and(|wal| !wal.is_archiving()) {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                format!("{} doesn't archive its WAL", self.data_dir.display())));
+        }
+        let archive_dir = self.data_dir.join(WAL_ARCHIVE_DIR);
+        let history = WAL::history(&archive_dir)?.ok_or_else(|| io::Error::new(io::ErrorKind::NotFound,
+            format!("{} has no unbroken WAL archive", self.data_dir.display())))?;
+        if timestamp < history.since {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                format!("its history begins at {}", history.since)));
+        }
+
+        let segments = WAL::logs_as_of(&self.data_dir, Some(&history), history.first_segment, timestamp)?;
+        let mut rows = BTreeMap::new();
+        'replay: for segment in segments {
+            if !segment.exists() {
+                continue;
+            }
+            let batches = WAL::read_batches(&segment)?;
+            self.query_bytes_read.fetch_add(fs::metadata(&segment)?.len(), Ordering::Relaxed);
+            for batch in batches {
+                if batch.iter().any(|entry| entry.timestamp > timestamp) {
+                    break 'replay;
+                }
+                for entry in batch {
+                    match self.values.resolve(entry.value)? {
+                        Some(value) => rows.insert(entry.key, value),
+                        None => rows.remove(&entry.key),
+                    };
+                }
+            }
+        }
+        Ok(rows.into_iter().collect())
+    }
+
+    pub fn storage_config(&self) -> StorageConfig {
+        StorageConfig {
+            memtable_size: self.memtable.limit,
+            sstable_size: self.compaction.target_file_size,
+            ttl: self.ttl,
+        }
+    }
+
+    /// Takes effect from the next write; a memtable already over the new
+    /// limit is flushed then.
+    pub fn set_storage_config(&mut self, config: StorageConfig) {
+        self.memtable.limit = config.memtable_size;
+        self.compaction.target_file_size = config.sstable_size;
+        self.ttl = config.ttl;
+    }
+
+    // Entries written before this timestamp have outlived the TTL; 0 if
+    // there is none.
+    fn expiry_horizon(&self) -> u64 {
+        self.ttl.map_or(0, |ttl| {
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap().saturating_sub(ttl).as_millis() as u64
+        })
+    }
+
+    /// Limits this storage's compactions together with those of every other
+    /// storage sharing `throttle`.
+    pub fn set_compaction_throttle(&mut self, throttle: Option<Arc<CompactionThrottle>>) {
+        self.throttle = throttle;
+    }
+
+    pub fn compaction_config(&self) -> &CompactionConfig {
+        &self.compaction
+    }
+
+    pub fn set_compaction_config(&mut self, compaction: CompactionConfig) {
+        self.compaction = compaction;
+    }
+
+    /// The live SSTables, oldest first.
+    pub fn sstables(&self) -> &[SSTable] {
+        &self.sstables
+    }
+
+    // SSTables are named `sstable_<level>_<id>.log`. Older versions wrote
+    // `sstable_<id>.log` for flushes and `sstable_compacted.log` for the merge
+    // of everything before them, which map to levels 0 and 1.
+    fn parse_sstable_name(file_name: &str) -> Option<(usize, u64)> {
+        if file_name == "sstable_compacted.log" {
+            return Some((1, 0));
+        }
+        let name = file_name.strip_prefix("sstable_")?.strip_suffix(".log")?;
+        match name.split_once('_') {
+            Some((level, id)) => Some((level.parse().ok()?, id.parse().ok()?)),
+            None => Some((0, name.parse().ok()?)),
+        }
+    }
+
+    // Deeper levels hold older data; within a level, higher ids are newer.
+    fn sort_oldest_first(sstables: &mut [SSTable]) {
+        sstables.sort_by_key(|t| (std::cmp::Reverse(t.level), t.id));
+    }
+
+    // Finds the SSTables written by earlier runs, oldest first.
+    fn load_sstables(table_dir: &Path) -> io::Result<Vec<SSTable>> {
+        let mut sstables = Vec::new();
+        for entry in fs::read_dir(table_dir)? {
+            let path = entry?.path();
+            let Some((level, id)) = path.file_name()
+                .and_then(|n| n.to_str())
+                .and_then(Self::parse_sstable_name) else {
+                continue;
+            };
+            let mut sstable = SSTable::open(path)?;
+            sstable.level = level;
+            sstable.id = id;
+            sstables.push(sstable);
+        }
+
+        Self::sort_oldest_first(&mut sstables);
+        Ok(sstables)
+    }
+
+    // Loads the SSTables listed in the manifest, removing any other SSTable
+    // files if `remove_orphans`. None if the table has no manifest yet.
+    fn load_manifest(table_dir: &Path, remove_orphans: bool) -> io::Result<Option<(Vec<SSTable>, u64)>> {
+        let json = match fs::read_to_string(table_dir.join(MANIFEST)) {
+            Ok(json) => json,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let manifest: Manifest = serde_json::from_str(&json)?;
+
+        let mut sstables = Vec::new();
+        for entry in &manifest.sstables {
+            let mut sstable = SSTable::new(table_dir.join(&entry.file));
+            sstable.level = entry.level;
+            sstable.id = entry.id;
+            sstable.min_key = entry.min_key.clone();
+            sstable.max_key = entry.max_key.clone();
+            sstable.size = entry.size;
+            sstable.load_bloom(None)?;
+            sstables.push(sstable);
         }
 
+        for dir_entry in fs::read_dir(table_dir)? {
+            let path = dir_entry?.path();
+            let Some(file_name) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            let sstable_name = file_name.strip_suffix(".bloom")
+                .map(|stem| format!("{}.log", stem))
+                .unwrap_or_else(|| file_name.to_string());
+            if remove_orphans
+                && Self::parse_sstable_name(&sstable_name).is_some()
+                && !manifest.sstables.iter().any(|e| e.file == sstable_name) {
+                fs::remove_file(&path)?;
+            }
+        }
+
+        Self::sort_oldest_first(&mut sstables);
+        Ok(Some((sstables, manifest.next_sstable_id)))
+    }
+
+    fn write_manifest(&self) -> io::Result<()> {
+        Self::write_manifest_file(&self.data_dir, &self.sstables, self.next_sstable_id.load(Ordering::Relaxed))
+    }
+
+    // Replaces the manifest atomically: a crash leaves either the old or the
+    // new one in place.
+    fn write_manifest_file(table_dir: &Path, sstables: &[SSTable], next_sstable_id: u64) -> io::Result<()> {
+        let manifest = Manifest {
+            next_sstable_id,
+            sstables: sstables.iter()
+                .map(|t| ManifestEntry {
+                    file: t.path.file_name().and_then(|n| n.to_str()).unwrap_or_default().to_string(),
+                    level: t.level,
+                    id: t.id,
+                    min_key: t.min_key.clone(),
+                    max_key: t.max_key.clone(),
+                    size: t.size,
+                })
+                .collect(),
+        };
+
+        let tmp_path = table_dir.join(format!("{}.tmp", MANIFEST));
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(serde_json::to_string(&manifest)?.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, table_dir.join(MANIFEST))?;
+        // Makes the rename itself durable
+        #[cfg(unix)]
+        File::open(table_dir)?.sync_all()?;
         Ok(())
     }
 
+    fn new_sstable(&mut self, level: usize) -> SSTable {
+        let id = self.next_sstable_id.fetch_add(1, Ordering::Relaxed);
+        let mut sstable = sstable_file(&self.data_dir, level, id);
+        sstable.read_counter = Some(self.query_bytes_read.clone());
+        sstable
+    }
+
+    pub fn insert(&mut self, key: String, value: String) -> io::Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.put(key, value);
+        self.write(batch)
+    }
+
     pub fn delete(&mut self, key: String) -> io::Result<()> {
-        self.wal.log_delete(&key)?;
-        self.memtable.delete(key);
+        let mut batch = WriteBatch::new();
+        batch.delete(key);
+        self.write(batch)
+    }
+
+    /// Applies a group of puts (`Some(value)`) and deletes (`None`) as a single
+    /// WAL record, so recovery never observes only part of the group.
+    pub fn apply_batch(&mut self, ops: Vec<(String, Option<String>)>) -> io::Result<()> {
+        self.write(WriteBatch::from(ops))
+    }
+
+    /// Applies a batch atomically: recovery sees all of it or none of it.
+    pub fn write(&mut self, batch: WriteBatch) -> io::Result<()> {
+        match self.write_deferred(batch)? {
+            Some(ticket) => ticket.wait(),
+            None => Ok(()),
+        }
+    }
+
+    /// Like `write`, but under `Durability::Always` returns before the WAL is
+    /// synced, with a ticket to wait on. Waiting after releasing any lock
+    /// around the storage lets concurrent writers share one sync.
+    pub fn write_deferred(&mut self, batch: WriteBatch) -> io::Result<Option<SyncTicket>> {
+        if batch.is_empty() {
+            return Ok(None);
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let entries: Vec<StorageEntry> = batch.into_iter()
+            .map(|(key, value)| StorageEntry {
+                key,
+                value: match value {
+                    Some(value) => StorageValue::Present(value),
+                    None => StorageValue::Deleted,
+                },
+                timestamp,
+            })
+            .collect();
+        self.write_entries(entries)
+    }
+
+    // Logs and applies entries that already carry their timestamps.
+    fn write_entries(&mut self, entries: Vec<StorageEntry>) -> io::Result<Option<SyncTicket>> {
+        self.finish_flush(false)?;
+        self.finish_compaction(false)?;
+        let seq = self.wal()?.log_batch(&entries)?;
+        #[cfg(feature = "fault-injection")]
+        faults::check(&self.data_dir, FaultPoint::WalAppend)?;
+        if !self.wal_listeners.is_empty() {
+            self.wal_listeners.retain(|listener| listener.send(entries.clone()).is_ok());
+        }
+        self.memtable.apply(entries);
+
+        let ticket = match self.durability {
+            Durability::Always => Some(SyncTicket { syncer: self.wal()?.syncer(), seq }),
+            // Synced by the WAL's timer
+            Durability::Interval(_) | Durability::OnClose => None,
+        };
 
         if self.memtable.is_full() {
-            self.flush_memtable()?;
+            self.rotate_memtable()?;
         }
 
-        Ok(())
+        Ok(ticket)
     }
 
-    pub fn get(&mut self, key: &str) -> io::Result<Option<String>> {
-        // First check memtable
-        if let Some(entry) = self.memtable.get(key) {
-            match &entry.value {
-                StorageValue::Present(value) => return Ok(Some(value.clone())),
-                StorageValue::Deleted => return Ok(None),
-            }
+    /// Makes every write so far durable, whatever the durability mode.
+    pub fn sync_wal(&mut self) -> io::Result<()> {
+        self.wal()?.syncer.sync_all_written()
+    }
+
+    pub fn get(&self, key: &str) -> io::Result<Option<String>> {
+        let value = self.lookup(key)?;
+        if let Some(value) = &value {
+            self.user_bytes_read.fetch_add((key.len() + value.len()) as u64, Ordering::Relaxed);
         }
+        Ok(value)
+    }
+
+    fn lookup(&self, key: &str) -> io::Result<Option<String>> {
+        // First check the memtables, newest first
+        let memtables = [Some(&self.memtable), self.immutable.as_ref().map(|f| &f.memtable)];
+        let mut found = memtables.into_iter().flatten().find_map(|m| m.get(key)).cloned();
 
         // Then check SSTables (newest first)
-        for sstable in self.sstables.iter().rev() {
-            if let Some(entry) = sstable.get(key)? {
-                match entry.value {
-                    StorageValue::Present(value) => return Ok(Some(value)),
-                    StorageValue::Deleted => return Ok(None),
+        if found.is_none() {
+            for sstable in self.sstables.iter().rev() {
+                found = sstable.get(key)?;
+                if found.is_some() {
+                    break;
                 }
             }
         }
 
-        Ok(None)
+        match found {
+            Some(entry) if entry.timestamp >= self.expiry_horizon() => self.values.resolve(entry.value),
+            _ => Ok(None),
+        }
+    }
+
+    pub fn get_all(&self) -> io::Result<Vec<(String, String)>> {
+        self.scan(..)?.collect()
+    }
+
+    /// Live key/value pairs with keys in the inclusive range `[start, end]`,
+    /// in key order. Only SSTables overlapping the range are read.
+    pub fn scan_range(&self, start: &str, end: &str) -> io::Result<Vec<(String, String)>> {
+        self.scan(start..=end)?.collect()
     }
 
-    pub fn get_all(&mut self) -> io::Result<Vec<(String, String)>> {
-        let mut result = Vec::new();
-        let mut seen_keys = std::collections::HashSet::new();
+    /// Streams the live key/value pairs in `range` in key order, merging the
+    /// memtable and SSTables one entry at a time; deleted keys are skipped.
+    /// SSTables that don't overlap the range are never opened.
+    pub fn scan<'k>(&self, range: impl RangeBounds<&'k str>) -> io::Result<ScanIter<'_>> {
+        self.merged_scan(range, false)
+    }
+
+    /// Like `scan`, but in descending key order. SSTables can only be read
+    /// forwards, so each one's part of the range is buffered.
+    pub fn scan_rev<'k>(&self, range: impl RangeBounds<&'k str>) -> io::Result<ScanIter<'_>> {
+        self.merged_scan(range, true)
+    }
+
+    /// Streams the live keys starting with `prefix`, e.g. `"user:"`.
+    pub fn scan_prefix(&self, prefix: &str) -> io::Result<ScanIter<'_>> {
+        match prefix_successor(prefix) {
+            Some(end) => self.scan((Bound::Included(prefix), Bound::Excluded(end.as_str()))),
+            None => self.scan(prefix..),
+        }
+    }
 
-        // Get from memtable first
-        for (key, entry) in self.memtable.iter() {
-            seen_keys.insert(key.clone());
-            if let StorageValue::Present(value) = &entry.value {
-                result.push((key.clone(), value.clone()));
+    fn merged_scan<'k>(&self, range: impl RangeBounds<&'k str>, reverse: bool) -> io::Result<ScanIter<'_>> {
+        let start = range.start_bound().map(|key| key.to_string());
+        let end = range.end_bound().map(|key| key.to_string());
+        // BTreeMap::range panics on an inverted range rather than returning nothing
+        let empty = match (&start, &end) {
+            (Bound::Included(s) | Bound::Excluded(s), Bound::Included(e) | Bound::Excluded(e)) => {
+                s > e || (s == e && matches!((&start, &end), (Bound::Excluded(_), Bound::Excluded(_))))
             }
+            _ => false,
+        };
+        if empty {
+            return Ok(ScanIter {
+                sources: Vec::new(),
+                heads: Vec::new(),
+                reverse,
+                values: &self.values,
+                bytes_returned: &self.user_bytes_read,
+                horizon: 0,
+            });
         }
+        let after_start = {
+            let start = start.clone();
+            move |key: &str| match &start {
+                Bound::Included(start) => key >= start.as_str(),
+                Bound::Excluded(start) => key > start.as_str(),
+                Bound::Unbounded => true,
+            }
+        };
+        let before_end = {
+            let end = end.clone();
+            move |key: &str| match &end {
+                Bound::Included(end) => key <= end.as_str(),
+                Bound::Excluded(end) => key < end.as_str(),
+                Bound::Unbounded => true,
+            }
+        };
 
-        // Get from SSTables
+        let mut sources: Vec<Box<dyn Iterator<Item = io::Result<StorageEntry>> + Send>> = Vec::new();
         for sstable in &self.sstables {
-            let entries = sstable.read_entries()?;
-            for entry in entries {
-                if !seen_keys.contains(&entry.key) {
-                    seen_keys.insert(entry.key.clone());
-                    if let StorageValue::Present(value) = entry.value {
-                        result.push((entry.key, value));
-                    }
-                }
+            if !before_end(&sstable.min_key) || !after_start(&sstable.max_key) {
+                continue;
+            }
+            let (after_start, before_end) = (after_start.clone(), before_end.clone());
+            // Errors pass both filters so they reach the caller
+            let entries = sstable.iter()?
+                .skip_while(move |entry| entry.as_ref().is_ok_and(|e| !after_start(&e.key)))
+                .take_while(move |entry| entry.as_ref().map_or(true, |e| before_end(&e.key)));
+            if reverse {
+                let mut entries = entries.collect::<io::Result<Vec<_>>>()?;
+                entries.reverse();
+                sources.push(Box::new(entries.into_iter().map(Ok)));
+            } else {
+                sources.push(Box::new(entries));
+            }
+        }
+        for memtable in self.immutable.iter().map(|f| &f.memtable).chain([&self.memtable]) {
+            let entries = memtable.data
+                .range::<str, _>((start.as_ref().map(String::as_str), end.as_ref().map(String::as_str)))
+                .map(|(_, entry)| Ok(entry.clone()));
+            if reverse {
+                sources.push(Box::new(entries.rev()));
+            } else {
+                sources.push(Box::new(entries));
             }
         }
 
-        result.sort_by_key(|(key, _)| key.clone());
-        Ok(result)
+        let mut scan = ScanIter {
+            heads: vec![None; sources.len()],
+            sources,
+            reverse,
+            values: &self.values,
+            bytes_returned: &self.user_bytes_read,
+            horizon: self.expiry_horizon(),
+        };
+        for source in 0..scan.sources.len() {
+            scan.advance(source)?;
+        }
+        Ok(scan)
     }
 
+    // Flushes the memtable and waits for it.
     fn flush_memtable(&mut self) -> io::Result<()> {
+        self.rotate_memtable()?;
+        self.finish_flush(true)
+    }
+
+    // Hands the memtable to a background flush and starts an empty one. Only
+    // one flush runs at a time, so a previous one is waited for first.
+    fn rotate_memtable(&mut self) -> io::Result<()> {
+        self.finish_flush(true)?;
         if self.memtable.data.is_empty() {
             return Ok(());
         }
+        self.wal()?.rotate()?;
+        let empty = MemTable::with_limit(self.memtable.limit);
+        let memtable = std::mem::replace(&mut self.memtable, empty);
+        self.spawn_flush(memtable)
+    }
 
-        let sstable_id = self.sstables.len();
-        let sstable_path = self.data_dir.join(format!("sstable_{}.log", sstable_id));
-        let mut sstable = SSTable::new(sstable_path);
-        
-        sstable.write_from_memtable(&self.memtable)?;
-        self.sstables.push(sstable);
-        
-        self.memtable.clear();
-        // Don't clear WAL - keep it for recovery
+    fn spawn_flush(&mut self, memtable: MemTable) -> io::Result<()> {
+        let started = Instant::now();
+        let value_log_len = self.values.len;
+        let mut entries = Vec::new();
+        for (_, entry) in memtable.iter() {
+            match &entry.value {
+                StorageValue::Present(value) if self.value_threshold.is_some_and(|t| value.len() > t) => {
+                    let offset = self.values.append(value)?;
+                    entries.push(StorageEntry { value: StorageValue::Separated(offset), ..entry.clone() });
+                }
+                _ => entries.push(entry.clone()),
+            }
+        }
+        let entries = Arc::new(entries);
+        self.immutable = Some(FlushingMemTable {
+            memtable,
+            task: self.spawn_sstable_write(entries.clone())?,
+            entries,
+            started,
+            value_log_bytes: self.values.len - value_log_len,
+        });
+        Ok(())
+    }
+
+    fn spawn_sstable_write(&mut self, entries: Arc<Vec<StorageEntry>>) -> io::Result<JoinHandle<io::Result<SSTable>>> {
+        let value_log = self.values.flushed_file()?;
+        let mut sstable = self.new_sstable(0);
+        Ok(thread::spawn(move || {
+            // The value log must be durable before the SSTable pointing into it is
+            if let Some(value_log) = value_log {
+                value_log.sync_data()?;
+            }
+            sstable.write_entries(&entries.iter().collect::<Vec<_>>())?;
+            Ok(sstable)
+        }))
+    }
 
-        // Simple compaction: if we have too many SSTables, merge them
-        if self.sstables.len() > 3 {
-            self.compact()?;
+    // Installs the SSTable of the background flush if it's done, or once it
+    // is if `wait`. A failed flush is started again.
+    fn finish_flush(&mut self, wait: bool) -> io::Result<()> {
+        if !self.immutable.as_ref().is_some_and(|f| wait || f.task.is_finished()) {
+            return Ok(());
         }
+        let flushing = self.immutable.take().unwrap();
+        let result = flushing.task.join()
+            .unwrap_or_else(|_| Err(io::Error::other("memtable flush panicked")));
+        let sstable = match result {
+            Ok(sstable) => sstable,
+            Err(e) => {
+                // Its values are in the value log already, so only the
+                // SSTable is written again
+                let task = self.spawn_sstable_write(flushing.entries.clone())?;
+                self.immutable = Some(FlushingMemTable { task, ..flushing });
+                return Err(e);
+            }
+        };
+
+        #[cfg(feature = "fault-injection")]
+        faults::check(&self.data_dir, FaultPoint::Flush)?;
+        self.stats.flush_bytes_written += sstable.size as u64 + flushing.value_log_bytes;
+        self.sstables.push(sstable);
+        self.write_manifest()?;
+        // Everything in the set-aside WAL is now in an SSTable, which is reloaded on open
+        self.wal()?.retire_immutable()?;
+        self.stats.flushes += 1;
+        self.stats.flush_time += flushing.started.elapsed();
 
+        self.maybe_compact();
         Ok(())
     }
 
-    fn compact(&mut self) -> io::Result<()> {
-        if self.sstables.len() < 2 {
-            return Ok(());
+    fn level_files(&self, level: usize) -> impl Iterator<Item = &SSTable> {
+        self.sstables.iter().filter(move |t| t.level == level)
+    }
+
+    /// Whether a compaction is running in the background.
+    pub fn is_compacting(&self) -> bool {
+        self.compacting.is_some()
+    }
+
+    // Starts the first compaction the levels need, unless one is running;
+    // the next one is started once it's swapped in.
+    fn maybe_compact(&mut self) {
+        if self.compacting.is_some() {
+            return;
+        }
+        let max_level = self.sstables.iter().map(|t| t.level).max().unwrap_or(0);
+        if self.level_files(0).count() >= self.compaction.trigger_file_count {
+            match self.compaction.strategy {
+                CompactionStrategy::Full => self.spawn_compaction(0, max_level, 1),
+                CompactionStrategy::Leveled => self.spawn_compaction(0, 1, 1),
+            }
+            return;
+        }
+        if self.compaction.strategy != CompactionStrategy::Leveled {
+            return;
         }
 
-        // Merge all SSTables into one
-        let mut all_entries = Vec::new();
-        
-        for sstable in &self.sstables {
-            let entries = sstable.read_entries()?;
-            all_entries.extend(entries);
+        // Push the first level that is over budget down into the next one
+        for level in 1..=max_level {
+            let level_size: usize = self.level_files(level).map(|t| t.size).sum();
+            if level_size > self.compaction.level_budget(level) {
+                self.spawn_compaction(level, level + 1, level + 1);
+                return;
+            }
         }
+    }
 
-        // Sort by key and timestamp (newest wins)
-        all_entries.sort_by(|a, b| {
-            a.key.cmp(&b.key).then(b.timestamp.cmp(&a.timestamp))
+    // Starts merging every SSTable in levels `from..=to` into a sorted run of
+    // files of about `target_file_size` bytes at `output_level`, on a
+    // background thread. Reads and writes carry on meanwhile;
+    // `finish_compaction` swaps the outputs in.
+    fn spawn_compaction(&mut self, from: usize, to: usize, output_level: usize) {
+        // Compaction reads are counted on their own, not as query reads
+        let copy = |t: &SSTable| SSTable { read_counter: None, ..t.clone() };
+        let inputs: Vec<SSTable> = self.sstables.iter().filter(|t| (from..=to).contains(&t.level)).map(copy).collect();
+        let tombstone_horizon = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .saturating_sub(self.compaction.tombstone_grace_period)
+            .as_millis() as u64;
+        let job = CompactionJob {
+            older: self.sstables.iter().filter(|t| t.level > to).map(copy).collect(),
+            inputs,
+            output_level,
+            target_file_size: self.compaction.target_file_size,
+            tombstone_horizon,
+            expiry_horizon: self.expiry_horizon(),
+            data_dir: self.data_dir.clone(),
+            next_sstable_id: self.next_sstable_id.clone(),
+            throttle: self.throttle.clone(),
+        };
+        self.compacting = Some(RunningCompaction {
+            inputs: job.inputs.iter().map(|t| t.path.clone()).collect(),
+            started: Instant::now(),
+            task: thread::spawn(move || job.run()),
         });
+    }
 
-        // Remove duplicates, keeping the newest
-        let mut unique_entries = Vec::new();
-        let mut last_key = None;
-        
-        for entry in all_entries {
-            if last_key.as_ref() != Some(&entry.key) {
-                unique_entries.push(entry.clone());
-                last_key = Some(entry.key);
-            }
+    // Swaps the outputs of the background compaction in for its inputs if
+    // it's done, or once it is if `wait`, and starts the next one the levels
+    // need. If it failed, its inputs stay live and the table is as it was.
+    fn finish_compaction(&mut self, wait: bool) -> io::Result<()> {
+        if !self.compacting.as_ref().is_some_and(|c| wait || c.task.is_finished()) {
+            return Ok(());
         }
+        let compaction = self.compacting.take().unwrap();
+        let output = compaction.task.join()
+            .unwrap_or_else(|_| Err(io::Error::other("compaction panicked")))?;
 
-        // Write to new SSTable
-        let new_sstable_path = self.data_dir.join("sstable_compacted.log");
-        let mut new_sstable = SSTable::new(new_sstable_path);
-        
-        // Create a temporary memtable to write the compacted data
-        let mut temp_memtable = MemTable::new();
-        for entry in unique_entries {
-            match entry.value {
-                StorageValue::Present(value) => temp_memtable.insert(entry.key, value),
-                StorageValue::Deleted => temp_memtable.delete(entry.key),
-            }
+        let (inputs, rest): (Vec<SSTable>, Vec<SSTable>) = std::mem::take(&mut self.sstables)
+            .into_iter()
+            .partition(|t| compaction.inputs.contains(&t.path));
+        self.sstables = rest;
+        self.stats.compaction_bytes_read += output.bytes_read;
+        self.stats.compaction_bytes_written += output.sstables.iter().map(|t| t.size as u64).sum::<u64>();
+        for mut sstable in output.sstables {
+            sstable.read_counter = Some(self.query_bytes_read.clone());
+            self.sstables.push(sstable);
         }
-        
-        new_sstable.write_from_memtable(&temp_memtable)?;
+        Self::sort_oldest_first(&mut self.sstables);
+        self.write_manifest()?;
 
-        // Remove old SSTables and replace with compacted one
-        for sstable in &self.sstables {
+        for sstable in &inputs {
             let _ = fs::remove_file(&sstable.path);
+            let _ = fs::remove_file(sstable.bloom_path());
         }
-        
-        self.sstables.clear();
-        self.sstables.push(new_sstable);
+        self.stats.compactions += 1;
+        self.stats.compaction_time += compaction.started.elapsed();
+        self.stats.last_compaction = Some(SystemTime::now());
 
+        self.maybe_compact();
         Ok(())
     }
 
+    // Waits for the running compaction, and any that follow it, to be
+    // swapped in.
+    fn finish_compactions(&mut self) -> io::Result<()> {
+        while self.compacting.is_some() {
+            self.finish_compaction(true)?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the memtable and merges every SSTable into one sorted run at
+    /// the deepest level, dropping overwritten values, expired rows and
+    /// tombstones past their grace period. Returns how many bytes of
+    /// SSTables that freed.
+    pub fn compact(&mut self) -> io::Result<u64> {
+        self.wal()?;
+        self.flush_memtable()?;
+        self.finish_compactions()?;
+        let size = |sstables: &[SSTable]| sstables.iter().map(|t| t.size as u64).sum::<u64>();
+        let before = size(&self.sstables);
+        if let Some(max_level) = self.sstables.iter().map(|t| t.level).max() {
+            self.spawn_compaction(0, max_level, max_level.max(1));
+            self.finish_compactions()?;
+        }
+        Ok(before.saturating_sub(size(&self.sstables)))
+    }
+
     pub fn close(&mut self) -> io::Result<()> {
+        if self.is_read_only() {
+            return Ok(());
+        }
         self.flush_memtable()?;
-        Ok(())
+        self.finish_compactions()?;
+        self.wal()?.syncer.sync_all_written()
+    }
+}
+
+impl Drop for LSMStorage {
+    // A flush or compaction left running would write its SSTables after the
+    // table may have been reopened. A flush's WAL is replayed on open either
+    // way, and a compaction's inputs stay listed in the manifest.
+    fn drop(&mut self) {
+        if let Some(flushing) = self.immutable.take() {
+            let _ = flushing.task.join();
+        }
+        if let Some(compaction) = self.compacting.take() {
+            let _ = compaction.task.join();
+        }
     }
 }
 
@@ -434,6 +2903,7 @@ impl SerializedSize for StorageValue {
         match self {
             StorageValue::Present(value) => value.len(),
             StorageValue::Deleted => 8, // "deleted" string length
+            StorageValue::Separated(_) => 8,
         }
     }
 } 
\ No newline at end of file
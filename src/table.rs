@@ -0,0 +1,106 @@
+//! Mapping plain structs to tables. `#[derive(SqlTable)]`, with the `derive`
+//! feature, implements `SqlTable` for a struct whose fields are `SqlValue`s:
+//!
+//! ```ignore
+//! #[derive(SqlTable)]
+//! #[sql(table = "users")]
+//! struct User {
+//!     #[sql(primary_key)]
+//!     id: u32,
+//!     name: String,
+//!     age: Option<u8>,
+//! }
+//!
+//! db.execute(User::create_table().into())?;
+//! db.execute(user.insert().into())?;
+//! let users = db.query(&User::select().build())?.map(User::from_row)?;
+//! ```
+//!
+//! Without `#[sql(table = ...)]` the table is named after the struct, in
+//! lower case.
+
+use crate::ast::{CreateTableStatement, InsertStatement, NULL};
+use crate::builder::Select;
+use crate::row::Row;
+
+#[cfg(feature = "derive")]
+pub use rust_sql_parser_derive::SqlTable;
+
+/// A Rust type stored in one column.
+pub trait SqlValue: Sized {
+    /// The column type `CREATE TABLE` declares.
+    const SQL_TYPE: &'static str;
+
+    fn to_sql(&self) -> String;
+
+    fn from_sql(value: &str) -> Result<Self, String>;
+}
+
+macro_rules! sql_value {
+    ($sql_type:literal: $($t:ty),*) => {
+        $(impl SqlValue for $t {
+            const SQL_TYPE: &'static str = $sql_type;
+
+            fn to_sql(&self) -> String {
+                self.to_string()
+            }
+
+            fn from_sql(value: &str) -> Result<Self, String> {
+                value.parse().map_err(|_| format!("'{}' isn't a {}", value, stringify!($t)))
+            }
+        })*
+    };
+}
+
+sql_value!("INT": i8, i16, i32, i64, u8, u16, u32, u64);
+sql_value!("REAL": f32, f64);
+sql_value!("BOOLEAN": bool);
+sql_value!("TEXT": String);
+
+/// NULL is `None`.
+impl<T: SqlValue> SqlValue for Option<T> {
+    const SQL_TYPE: &'static str = T::SQL_TYPE;
+
+    fn to_sql(&self) -> String {
+        match self {
+            Some(value) => value.to_sql(),
+            None => NULL.to_string(),
+        }
+    }
+
+    fn from_sql(value: &str) -> Result<Self, String> {
+        match value {
+            NULL => Ok(None),
+            _ => T::from_sql(value).map(Some),
+        }
+    }
+}
+
+/// A struct stored as one row of a table, one field per column.
+pub trait SqlTable: Sized {
+    const TABLE: &'static str;
+
+    /// The column of each field, in order.
+    const COLUMNS: &'static [&'static str];
+
+    /// `CREATE TABLE` with a column of each field's `SqlValue::SQL_TYPE`.
+    fn create_table() -> CreateTableStatement;
+
+    /// Inserts `self` as a row.
+    fn insert(&self) -> InsertStatement;
+
+    fn from_row(row: Row<'_>) -> Result<Self, String>;
+
+    /// Selects every field's column, for `from_row`.
+    fn select() -> Select {
+        Select::from(Self::TABLE).columns(Self::COLUMNS.iter().copied())
+    }
+}
+
+impl Row<'_> {
+    /// The value of column `name` as a `T`, which `#[derive(SqlTable)]`
+    /// reads each field with.
+    pub fn get_sql<T: SqlValue>(&self, name: &str) -> Result<T, String> {
+        T::from_sql(self.value(name)?).map_err(|e| format!("Column '{}': {}", name, e))
+    }
+}
@@ -1,22 +1,34 @@
+use crate::blob::decode_hex;
+use crate::dialect::Dialect;
+use std::fmt;
 use std::iter::Peekable;
-use std::str::Chars;
+use std::str::CharIndices;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Select, Insert, Update, Delete, From,
     Into, Values, Set, Where,
     Identifier(String), StringLiteral(String), NumberLiteral(f64),
+    // A name written in quotes, e.g. `"first name"`, which is never read as
+    // a keyword
+    QuotedIdentifier(String),
+    // The bytes of an `X'...'` literal
+    HexLiteral(Vec<u8>),
     Equals, Comma, Asterisk, Semicolon, LeftParen, RightParen,
     LessThan, GreaterThan,
     Unknown(String),
     Create, Table, Alter, Add, Drop,
     Modify, Order, By, Desc, Asc, Group,
     Join, Left, Right, Full, On, Dot, Cross,
+    Begin, Commit, Rollback, Savepoint, Release,
+    // The text of a `/*+ ... */` comment; other comments are skipped
+    Hint(String),
 }
 
 pub struct Tokenizer {
     input: String,
     position: usize,
+    dialect: Dialect,
 }
 
 impl Tokenizer {
@@ -24,93 +36,169 @@ impl Tokenizer {
         Self {
             input: input.to_string(),
             position: 0,
+            dialect: Dialect::default(),
         }
     }
 
+    pub fn with_dialect(mut self, dialect: Dialect) -> Self {
+        self.dialect = dialect;
+        self
+    }
+
     pub fn next_token(&mut self) -> Option<Token> {
         if self.position >= self.input.len() {
             return None;
         }
         let remaining_input = &self.input[self.position..];
-        match tokenize(remaining_input) {
-            Ok(tokens) => {
-                if let Some(token) = tokens.get(0).cloned() {
-                    self.position += token_length(&token);
-                    Some(token)
-                } else {
-                    None
-                }
-            }
-            Err(_) => None,
-        }
+        let (token, span) = Tokens::new(remaining_input, self.dialect).next()?.ok()?;
+        self.position += span.end;
+        Some(token)
     }
 
     pub fn tokenize(&mut self) -> Result<Vec<Token>, String> {
-        tokenize(&self.input)
+        tokenize_with_dialect(&self.input, self.dialect)
+    }
+}
+
+/// Byte offsets of a token, or of the text an error is about, in the SQL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// A tokenizer or parser error, with where in the SQL it happened if known.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SqlError {
+    pub message: String,
+    pub span: Option<Span>,
+}
+
+impl fmt::Display for SqlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
     }
 }
 
+impl std::error::Error for SqlError {}
+
 pub fn tokenize(input: &str) -> Result<Vec<Token>, String> {
-    let mut tokens = Vec::new();
-    let mut chars = input.chars().peekable();
+    tokenize_with_dialect(input, Dialect::default())
+}
+
+pub fn tokenize_with_dialect(input: &str, dialect: Dialect) -> Result<Vec<Token>, String> {
+    tokenize_spanned(input, dialect)
+        .map(|tokens| tokens.into_iter().map(|(token, _)| token).collect())
+        .map_err(|e| e.message)
+}
+
+/// Like `tokenize`, in `dialect` and also returning where each token is.
+pub fn tokenize_spanned(input: &str, dialect: Dialect) -> Result<Vec<(Token, Span)>, SqlError> {
+    Tokens::new(input, dialect).collect()
+}
+
+/// The tokens of `input`, read as they are asked for rather than all at
+/// once, so that a long statement, e.g. an INSERT of many rows, needn't be
+/// held as tokens whole. Reading stops at the first error.
+pub struct Tokens<'a> {
+    input: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+    dialect: Dialect,
+    failed: bool,
+}
 
-    while let Some(&ch) = chars.peek() {
+impl<'a> Tokens<'a> {
+    pub fn new(input: &'a str, dialect: Dialect) -> Self {
+        Self { input, chars: input.char_indices().peekable(), dialect, failed: false }
+    }
+
+    // The byte offset of the first character not yet read
+    fn position(&mut self) -> usize {
+        self.chars.peek().map_or(self.input.len(), |&(i, _)| i)
+    }
+
+    // The token starting with `ch` at `start`, if it isn't whitespace or a
+    // comment
+    fn read_token(&mut self, start: usize, ch: char) -> Result<Option<Token>, SqlError> {
+        let mut token = None;
         match ch {
-            ' ' | '\t' | '\n' => { chars.next(); }
-            '*' => { tokens.push(Token::Asterisk); chars.next(); }
-            ',' => { tokens.push(Token::Comma); chars.next(); }
-            '=' => { tokens.push(Token::Equals); chars.next(); }
-            ';' => { tokens.push(Token::Semicolon); chars.next(); }
-            '(' => { tokens.push(Token::LeftParen); chars.next(); }
-            ')' => { tokens.push(Token::RightParen); chars.next(); }
-            '>' => { tokens.push(Token::GreaterThan); chars.next(); }
-            '<' => { tokens.push(Token::LessThan); chars.next(); }
-            '.' => { tokens.push(Token::Dot); chars.next(); }
+            ' ' | '\t' | '\n' => { self.chars.next(); }
+            '*' => { token = Some(Token::Asterisk); self.chars.next(); }
+            ',' => { token = Some(Token::Comma); self.chars.next(); }
+            '=' => { token = Some(Token::Equals); self.chars.next(); }
+            ';' => { token = Some(Token::Semicolon); self.chars.next(); }
+            '(' => { token = Some(Token::LeftParen); self.chars.next(); }
+            ')' => { token = Some(Token::RightParen); self.chars.next(); }
+            '>' => { token = Some(Token::GreaterThan); self.chars.next(); }
+            '<' => { token = Some(Token::LessThan); self.chars.next(); }
+            '.' => { token = Some(Token::Dot); self.chars.next(); }
+            '/' if self.input[start..].starts_with("/*") => {
+                let end = self.input[start + 2..].find("*/")
+                    .ok_or_else(|| unterminated("comment", start, self.input))? + start + 2;
+                if let Some(hints) = self.input[start + 2..end].strip_prefix('+') {
+                    token = Some(Token::Hint(hints.trim().to_string()));
+                }
+                while self.chars.next_if(|&(i, _)| i < end + 2).is_some() {}
+            }
 
             '\'' => {
-                chars.next();
-                let mut literal = String::new();
-                while let Some(&c) = chars.peek() {
-                    if c == '\'' {
-                        chars.next();
-                        break;
-                    }
-                    literal.push(c);
-                    chars.next();
-                }
-                if literal.is_empty() {
-                    return Err("Unterminated string literal".to_string());
-                }
-                tokens.push(Token::StringLiteral(literal));
+                let literal = read_quoted(&mut self.chars, '\'')
+                    .ok_or_else(|| unterminated("string literal", start, self.input))?;
+                token = Some(Token::StringLiteral(checked_literal(literal, start, &mut self.chars)?));
+            }
+            '"' if self.dialect.double_quoted_strings() => {
+                let literal = read_quoted(&mut self.chars, '"')
+                    .ok_or_else(|| unterminated("string literal", start, self.input))?;
+                token = Some(Token::StringLiteral(checked_literal(literal, start, &mut self.chars)?));
+            }
+            _ if self.dialect.identifier_quote(ch).is_some() => {
+                let close = self.dialect.identifier_quote(ch).unwrap_or(ch);
+                let name = read_quoted(&mut self.chars, close)
+                    .ok_or_else(|| unterminated("quoted identifier", start, self.input))?;
+                token = Some(Token::QuotedIdentifier(name));
             }
 
             '0'..='9' => {
                 let mut number = String::new();
-                while let Some(&c) = chars.peek() {
+                while let Some(&(_, c)) = self.chars.peek() {
                     if c.is_numeric() || c == '.' {
                         number.push(c);
-                        chars.next();
+                        self.chars.next();
                     } else {
                         break;
                     }
                 }
                 match number.parse::<f64>() {
-                    Ok(num) => tokens.push(Token::NumberLiteral(num)),
-                    Err(_) => return Err(format!("Invalid number format: {}", number)),
+                    Ok(num) => token = Some(Token::NumberLiteral(num)),
+                    Err(_) => return Err(SqlError {
+                        message: format!("Invalid number format: {}", number),
+                        span: Some(Span { start, end: start + number.len() }),
+                    }),
                 }
             }
 
+            'X' | 'x' if self.input[start + 1..].starts_with('\'') => {
+                self.chars.next();
+                let digits = read_quoted(&mut self.chars, '\'')
+                    .ok_or_else(|| unterminated("hex literal", start, self.input))?;
+                let bytes = decode_hex(&digits).ok_or_else(|| SqlError {
+                    message: format!("Invalid hex literal: X'{}'", digits),
+                    span: Some(Span { start, end: start + digits.len() + 3 }),
+                })?;
+                token = Some(Token::HexLiteral(bytes));
+            }
+
             'A'..='Z' | 'a'..='z' => {
                 let mut word = String::new();
-                while let Some(&c) = chars.peek() {
+                while let Some(&(_, c)) = self.chars.peek() {
                     if c.is_alphanumeric() || c == '_' {
                         word.push(c);
-                        chars.next();
+                        self.chars.next();
                     } else {
                         break;
                     }
                 }
-                let token = match word.to_uppercase().as_str() {
+                token = Some(match word.to_uppercase().as_str() {
                     "SELECT" => Token::Select,
                     "INSERT" => Token::Insert,
                     "UPDATE" => Token::Update,
@@ -137,58 +225,75 @@ pub fn tokenize(input: &str) -> Result<Vec<Token>, String> {
                     "FULL" => Token::Full,
                     "CROSS" => Token::Cross,
                     "ON" => Token::On,
+                    "BEGIN" => Token::Begin,
+                    "COMMIT" => Token::Commit,
+                    "ROLLBACK" => Token::Rollback,
+                    "SAVEPOINT" => Token::Savepoint,
+                    "RELEASE" => Token::Release,
                     _ => Token::Identifier(word),
-                };
-                tokens.push(token);
+                });
             }
 
             _ => {
-                tokens.push(Token::Unknown(ch.to_string()));
-                chars.next();
+                token = Some(Token::Unknown(ch.to_string()));
+                self.chars.next();
+            }
+        }
+        Ok(token)
+    }
+}
+
+impl Iterator for Tokens<'_> {
+    type Item = Result<(Token, Span), SqlError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.failed {
+            let &(start, ch) = self.chars.peek()?;
+            match self.read_token(start, ch) {
+                Ok(Some(token)) => return Some(Ok((token, Span { start, end: self.position() }))),
+                Ok(None) => {}
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        None
+    }
+}
+
+// Reads a quoted string or identifier, whose opening quote is next, up to
+// `close`; a doubled `close` stands for one. None if it never closes.
+fn read_quoted(chars: &mut Peekable<CharIndices<'_>>, close: char) -> Option<String> {
+    chars.next();
+    let mut quoted = String::new();
+    while let Some((_, c)) = chars.next() {
+        if c == close {
+            if chars.peek().map(|&(_, c)| c) != Some(close) {
+                return Some(quoted);
             }
+            chars.next();
         }
+        quoted.push(c);
     }
-    Ok(tokens)
+    None
 }
 
-fn keyword_str(token: &Token) -> &'static str {
-    match token {
-        Token::Select => "SELECT",
-        Token::Insert => "INSERT",
-        Token::Update => "UPDATE",
-        Token::Delete => "DELETE",
-        Token::From => "FROM",
-        Token::Into => "INTO",
-        Token::Values => "VALUES",
-        Token::Set => "SET",
-        Token::Where => "WHERE",
-        Token::Create => "CREATE",
-        Token::Table => "TABLE",
-        Token::Alter => "ALTER",
-        Token::Add => "ADD",
-        Token::Drop => "DROP",
-        Token::Modify => "MODIFY",
-        Token::Order => "ORDER",
-        Token::By => "BY",
-        Token::Group => "GROUP",
-        Token::Join => "JOIN",
-        Token::Left => "LEFT",
-        Token::Right => "RIGHT",
-        Token::Full => "FULL",
-        Token::On => "ON",
-        Token::Desc => "DESC",
-        Token::Asc => "ASC",
-        Token::Cross => "CROSS",
-        _ => "",
+// A string literal can't hold a NUL character, which only `NULL` is held with
+fn checked_literal(literal: String, start: usize, rest: &mut Peekable<CharIndices<'_>>) -> Result<String, SqlError> {
+    if literal.contains('\0') {
+        let end = rest.peek().map_or(start + literal.len() + 2, |&(i, _)| i);
+        return Err(SqlError {
+            message: "A string literal can't contain a NUL character".to_string(),
+            span: Some(Span { start, end }),
+        });
     }
+    Ok(literal)
 }
 
-fn token_length(token: &Token) -> usize {
-    match token {
-        Token::Identifier(s) => s.len(),
-        Token::StringLiteral(s) => s.len() + 2,
-        Token::NumberLiteral(n) => n.to_string().len(),
-        Token::Unknown(s) => s.len(),
-        _ => keyword_str(token).len().max(1),
+fn unterminated(what: &str, start: usize, input: &str) -> SqlError {
+    SqlError {
+        message: format!("Unterminated {}", what),
+        span: Some(Span { start, end: input.len() }),
     }
 }
@@ -0,0 +1,71 @@
+//! The JavaScript API of the wasm build, over an in-memory `Database`:
+//!
+//! ```js
+//! import init, { SqlEngine } from "./pkg/rust_sql_parser.js";
+//!
+//! await init();
+//! const engine = new SqlEngine();
+//! engine.execute("CREATE TABLE users (id INT, name TEXT)");
+//! engine.execute("INSERT INTO users (id, name) VALUES ('1', 'alice')");
+//! const rows = JSON.parse(engine.query("SELECT name FROM users"));
+//! ```
+//!
+//! Errors are thrown as strings.
+
+use crate::ast::SQLStatement;
+use crate::executor::Database;
+use crate::format::format_sql;
+use crate::output::OutputFormat;
+use crate::parser::{parse_statement, parse_to_json};
+use crate::dialect::Dialect;
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen]
+pub struct SqlEngine {
+    db: Database,
+}
+
+#[wasm_bindgen]
+impl SqlEngine {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> SqlEngine {
+        SqlEngine { db: Database::new() }
+    }
+
+    /// Runs a statement, returning what it did, or for a SELECT its rows
+    /// as the REPL lists them.
+    pub fn execute(&mut self, sql: &str) -> Result<String, JsValue> {
+        let statement = parse_statement(sql).map_err(|e| JsValue::from_str(&e.message))?;
+        self.db.execute(statement)
+            .map(|result| result.to_string())
+            .map_err(|e| JsValue::from_str(&e))
+    }
+
+    /// Runs a SELECT, returning its rows as a JSON array of objects.
+    pub fn query(&self, sql: &str) -> Result<String, JsValue> {
+        match parse_statement(sql).map_err(|e| JsValue::from_str(&e.message))? {
+            SQLStatement::Select(select) => self.db.query(&select)
+                .map(|rows| rows.render(OutputFormat::Json))
+                .map_err(|e| JsValue::from_str(&e)),
+            _ => Err(JsValue::from_str("query only runs SELECT statements; use execute")),
+        }
+    }
+}
+
+impl Default for SqlEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The syntax tree of a statement as JSON, without running it.
+#[wasm_bindgen]
+pub fn parse(sql: &str) -> Result<String, JsValue> {
+    parse_to_json(sql, Dialect::default()).map_err(|e| JsValue::from_str(&e.message))
+}
+
+/// A statement with one clause per line, as `format_sql` writes it.
+#[wasm_bindgen]
+pub fn format(sql: &str) -> Result<String, JsValue> {
+    format_sql(sql).map_err(|e| JsValue::from_str(&e.message))
+}
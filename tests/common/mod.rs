@@ -0,0 +1,28 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("rust_sql_parser_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&path);
+        Self(path)
+    }
+
+    #[allow(dead_code)]
+    pub fn path(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+
+    #[allow(dead_code)]
+    pub fn as_path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
@@ -1,104 +1,656 @@
-use rust_sql_parser::ast::{SQLStatement, SelectStatement, InsertStatement, UpdateStatement, DeleteStatement};
-use std::collections::HashMap;
+#![cfg(feature = "executor")]
 
-#[derive(Debug)]
-pub struct Database {
-    tables: HashMap<String, Vec<HashMap<String, String>>>,
+use rust_sql_parser::executor::Database;
+use rust_sql_parser::integration::process_query;
+use rust_sql_parser::output::{OutputFormat, ResultSet, StatementResult, ValueType};
+use rust_sql_parser::result_formatter::{ResultFormatter, TableStyle};
+use rust_sql_parser::ast::{ColumnExpr, JoinType, SQLStatement, NULL};
+use rust_sql_parser::blob::Blob;
+use rust_sql_parser::builder::{Condition, CreateTable, Delete, Insert, Select, Update};
+use rust_sql_parser::parser::parse_sql;
+use rust_sql_parser::tokenizer::tokenize;
+
+mod common;
+use common::TempDir;
+
+fn setup_users(db: &mut Database) {
+    process_query(db, "CREATE TABLE users (id INT, name TEXT, age INT);").unwrap();
+    process_query(db, "INSERT INTO users (id, name, age) VALUES ('1', 'alice', '30');").unwrap();
+    process_query(db, "INSERT INTO users (id, name, age) VALUES ('2', 'bob', '25');").unwrap();
 }
 
-impl Database {
-    pub fn new() -> Self {
-        Self {
-            tables: HashMap::new(),
-        }
-    }
+#[test]
+fn test_insert_and_select() {
+    let mut db = Database::new();
+    setup_users(&mut db);
 
-    pub fn execute(&mut self, statement: SQLStatement) -> Result<String, String> {
-        match statement {
-            SQLStatement::Select(stmt) => self.execute_select(&stmt),
-            SQLStatement::Insert(stmt) => self.execute_insert(stmt),
-            SQLStatement::Update(stmt) => self.execute_update(stmt),
-            SQLStatement::Delete(stmt) => self.execute_delete(stmt),
-        }
-    }
+    let result = process_query(&mut db, "SELECT name FROM users WHERE id = '2';").unwrap();
+    assert!(result.contains("bob"));
+    assert!(!result.contains("alice"));
+}
 
-    fn execute_select(&self, stmt: &SelectStatement) -> Result<String, String> {
-        let table = self.tables.get(&stmt.table)
-            .ok_or_else(|| format!("Table '{}' not found", stmt.table))?;
-        
-        let filtered_rows: Vec<String> = table.iter()
-            .filter(|row| {
-                stmt.where_clause.as_ref().map_or(true, |where_clause| {
-                    row.get(&where_clause.column).map_or(false, |value| value == &where_clause.value)
-                })
-            })
-            .map(|row| format!("{:?}", row))
-            .collect();
-        
-        if filtered_rows.is_empty() {
-            return Err("No matching rows found".to_string());
-        }
-        
-        Ok(filtered_rows.join("\n"))
-    }
+#[test]
+fn test_update() {
+    let mut db = Database::new();
+    setup_users(&mut db);
+
+    process_query(&mut db, "UPDATE users SET age = '31' WHERE name = 'alice';").unwrap();
+    let result = process_query(&mut db, "SELECT age FROM users WHERE name = 'alice';").unwrap();
+    assert!(result.contains("31"));
+}
 
-    fn execute_insert(&mut self, stmt: InsertStatement) -> Result<String, String> {
-        let table = self.tables.entry(stmt.table.clone()).or_insert_with(Vec::new);
+#[test]
+fn test_delete() {
+    let mut db = Database::new();
+    setup_users(&mut db);
 
-        if stmt.columns.len() != stmt.values.len() {
-            return Err("Column count does not match value count".to_string());
-        }
+    process_query(&mut db, "DELETE FROM users WHERE name = 'bob';").unwrap();
+    assert_eq!(process_query(&mut db, "SELECT * FROM users WHERE name = 'bob';").unwrap(), "No matching rows found");
+}
+
+#[test]
+fn test_select_missing_table() {
+    let mut db = Database::new();
+    assert!(process_query(&mut db, "SELECT * FROM missing;").is_err());
+}
+
+#[test]
+fn test_rollback_to_savepoint() {
+    let mut db = Database::new();
+    setup_users(&mut db);
 
-        let new_row: HashMap<String, String> = stmt.columns.into_iter()
-            .zip(stmt.values.into_iter())
-            .collect();
+    process_query(&mut db, "BEGIN;").unwrap();
+    process_query(&mut db, "INSERT INTO users (id, name, age) VALUES ('3', 'carol', '41');").unwrap();
+    process_query(&mut db, "SAVEPOINT sp1;").unwrap();
+    process_query(&mut db, "DELETE FROM users WHERE name = 'alice';").unwrap();
+    process_query(&mut db, "ROLLBACK TO SAVEPOINT sp1;").unwrap();
+    process_query(&mut db, "COMMIT;").unwrap();
 
-        table.push(new_row);
-        Ok("Insert successful".to_string())
+    let result = process_query(&mut db, "SELECT name FROM users;").unwrap();
+    assert!(result.contains("alice"));
+    assert!(result.contains("carol"));
+}
+
+#[test]
+fn test_savepoint_errors() {
+    let mut db = Database::new();
+    setup_users(&mut db);
+
+    assert!(process_query(&mut db, "SAVEPOINT sp1;").is_err());
+    process_query(&mut db, "BEGIN;").unwrap();
+    process_query(&mut db, "SAVEPOINT sp1;").unwrap();
+    process_query(&mut db, "RELEASE SAVEPOINT sp1;").unwrap();
+    assert!(process_query(&mut db, "ROLLBACK TO SAVEPOINT sp1;").is_err());
+    process_query(&mut db, "ROLLBACK;").unwrap();
+}
+
+#[test]
+fn test_save_and_load() {
+    let dir = TempDir::new("memory_snapshot");
+    std::fs::create_dir_all(dir.path()).unwrap();
+    let path = format!("{}/db.json", dir.path());
+    let mut db = Database::new();
+    setup_users(&mut db);
+    process_query(&mut db, "CREATE TABLE empty (id INT);").unwrap();
+
+    // An open transaction's writes aren't saved
+    process_query(&mut db, "BEGIN;").unwrap();
+    process_query(&mut db, "DELETE FROM users WHERE name = 'alice';").unwrap();
+    db.save(&path).unwrap();
+
+    let mut loaded = Database::load(&path).unwrap();
+    assert_eq!(loaded.table_names(), vec!["empty", "users"]);
+    let result = process_query(&mut loaded, "SELECT name FROM users WHERE age = '30';").unwrap();
+    assert!(result.contains("alice"));
+    assert!(Database::load(&format!("{}/missing.json", dir.path())).is_err());
+}
+
+fn query(db: &Database, sql: &str) -> ResultSet {
+    match parse_sql(tokenize(sql).unwrap()).unwrap() {
+        SQLStatement::Select(stmt) => db.query(&stmt).unwrap(),
+        other => panic!("not a SELECT: {:?}", other),
     }
+}
+
+#[test]
+fn test_query_output_formats() {
+    let mut db = Database::new();
+    setup_users(&mut db);
+    process_query(&mut db, "INSERT INTO users (id, name, age) VALUES ('3', 'o''hara, jo', '41');").unwrap();
+
+    let result = query(&db, "SELECT id, name FROM users ORDER BY id;");
+    assert_eq!(result.columns, vec!["id", "name"]);
+    assert_eq!(result.rows.len(), 3);
+
+    assert_eq!(result.render(OutputFormat::Table), "\
+┌────┬────────────┐
+│ id │ name       │
+├────┼────────────┤
+│ 1  │ alice      │
+│ 2  │ bob        │
+│ 3  │ o'hara, jo │
+└────┴────────────┘
+");
+    assert_eq!(result.render(OutputFormat::Csv), "id,name\r\n1,alice\r\n2,bob\r\n3,\"o'hara, jo\"\r\n");
+    let json: serde_json::Value = serde_json::from_str(&result.render(OutputFormat::Json)).unwrap();
+    assert_eq!(json[2]["name"], "o'hara, jo");
+    assert!(result.render(OutputFormat::Vertical).starts_with("*** 1. row ***\n  id: 1\nname: alice\n"));
+    assert_eq!("box".parse::<OutputFormat>(), Ok(OutputFormat::Table));
+    assert!("xml".parse::<OutputFormat>().is_err());
+
+    assert!(query(&db, "SELECT name FROM users WHERE id = '9';").rows.is_empty());
+}
 
-    fn execute_update(&mut self, stmt: UpdateStatement) -> Result<String, String> {
-        let table = self.tables.get_mut(&stmt.table)
-            .ok_or_else(|| format!("Table '{}' not found", stmt.table))?;
-        
-        let mut updated = 0;
-        
-        for row in table.iter_mut() {
-            if stmt.where_clause.as_ref().map_or(true, |where_clause| {
-                row.get(&where_clause.column).map_or(false, |value| value == &where_clause.value)
-            }) {
-                for (column, value) in &stmt.assignments {
-                    row.insert(column.clone(), value.clone());
-                }
-                updated += 1;
+#[test]
+fn test_result_formatter() {
+    let mut db = Database::new();
+    process_query(&mut db, "CREATE TABLE notes (id INT, body TEXT);").unwrap();
+    process_query(&mut db, "INSERT INTO notes (id, body) VALUES ('1', 'café au lait'), ('2', 'a much longer note than that');").unwrap();
+    let result = query(&db, "SELECT id, body FROM notes ORDER BY id;");
+
+    let formatter = ResultFormatter::new().style(TableStyle::Ascii).max_width(12);
+    assert_eq!(formatter.widths(&result), [2, 12]);
+    assert_eq!(formatter.render(&result), "\
++----+--------------+
+| id | body         |
++----+--------------+
+| 1  | café au lait |
+| 2  | a much lo... |
++----+--------------+
+");
+    assert_eq!(ResultFormatter::new().max_width(6).render(&result).lines().nth(4), Some("│ 2  │ a muc… │"));
+    assert_eq!(result.render(OutputFormat::Ascii), ResultFormatter::new().style(TableStyle::Ascii).render(&result));
+    // The rule under a list's header is as long as the header in characters
+    let result = query(&db, "SELECT body AS café FROM notes WHERE id = '1';");
+    assert_eq!(result.render(OutputFormat::List), "café\n----\ncafé au lait\n");
+}
+
+#[test]
+fn test_limit_and_offset() {
+    let mut db = Database::new();
+    setup_users(&mut db);
+    process_query(&mut db, "INSERT INTO users (id, name, age) VALUES ('3', 'carol', '41');").unwrap();
+
+    let result = query(&db, "SELECT name FROM users ORDER BY id LIMIT 1 OFFSET 1;");
+    assert_eq!(result.rows, vec![vec!["bob"]]);
+    assert_eq!(query(&db, "SELECT name FROM users ORDER BY id LIMIT 1, 5;").rows.len(), 2);
+}
+
+#[test]
+fn test_built_statements() {
+    let mut db = Database::new();
+    db.execute(CreateTable::new("users").column("id", "INT").column("name", "TEXT").primary_key("id").into()).unwrap();
+    db.execute(Insert::into("users").columns(["id", "name"]).row(["1", "alice"]).row(["2", "o'hara"]).into()).unwrap();
+    db.execute(Update::table("users").set("name", "bob").filter(Condition::eq("id", "1")).into()).unwrap();
+
+    let select = Select::from("users").columns(["name"]).filter(Condition::between("id", "1", "2")).order_by("id");
+    assert_eq!(db.query(&select.clone().build()).unwrap().rows, vec![vec!["bob"], vec!["o'hara"]]);
+    // The same statement as parsing the SQL it prints
+    let sql = SQLStatement::from(select).to_string();
+    assert_eq!(sql, "SELECT name FROM users WHERE id BETWEEN '1' AND '2' ORDER BY id");
+    assert_eq!(query(&db, &sql).rows.len(), 2);
+
+    db.execute(Delete::from("users").filter(Condition::gt("id", "1")).into()).unwrap();
+    let count = Select::from("users").expr(ColumnExpr::CountAll).build();
+    assert_eq!(db.query(&count).unwrap().rows, vec![vec!["1"]]);
+}
+
+#[test]
+fn test_rewrite_rules() {
+    let mut db = Database::new();
+    setup_users(&mut db);
+
+    // Queries only see one tenant's rows, and it can't drop tables
+    db.add_rewrite_rule(|stmt: &mut SQLStatement| {
+        match stmt {
+            SQLStatement::Select(select) => {
+                select.where_clause.get_or_insert(Condition::eq("name", "bob"));
             }
+            SQLStatement::DropTable(_) => return Err("DROP TABLE is not allowed".to_string()),
+            _ => {}
         }
+        Ok(())
+    });
+    let names = db.query_map("SELECT name FROM users", |row| row.get::<String>("name")).unwrap();
+    assert_eq!(names, ["bob"]);
+    assert!(process_query(&mut db, "SELECT * FROM users;").unwrap().contains("bob"));
+    assert!(!process_query(&mut db, "SELECT * FROM users;").unwrap().contains("alice"));
+    assert_eq!(process_query(&mut db, "DROP TABLE users;").unwrap_err(), "Execution error: DROP TABLE is not allowed");
 
-        if updated > 0 {
-            Ok(format!("Updated {} row(s)", updated))
-        } else {
-            Err("No rows updated".to_string())
-        }
+    db.add_rewrite_rule(|stmt: &mut SQLStatement| {
+        *stmt = SQLStatement::from(Delete::from("users"));
+        Ok(())
+    });
+    assert!(db.query_map("SELECT name FROM users", |row| row.get::<String>("name")).is_err());
+}
+
+#[test]
+fn test_blob_values() {
+    let mut db = Database::new();
+    process_query(&mut db, "CREATE TABLE files (name TEXT, data BLOB);").unwrap();
+    process_query(&mut db, "INSERT INTO files (name, data) VALUES ('a', X'DEADBEEF'), ('b', 'héllo');").unwrap();
+    let lengths = db.query_map("SELECT LENGTH(data) FROM files ORDER BY name", |row| row.get::<usize>("LENGTH(data)")).unwrap();
+    assert_eq!(lengths, [4, 5]);
+    let blobs = db.query_map("SELECT data FROM files WHERE data = X'deadbeef'", |row| row.get::<Blob>("data")).unwrap();
+    assert_eq!(blobs, [Blob(vec![0xde, 0xad, 0xbe, 0xef])]);
+}
+
+#[test]
+fn test_create_table_rejects_duplicates() {
+    let mut db = Database::new();
+    setup_users(&mut db);
+    assert!(process_query(&mut db, "CREATE TABLE users (id INT);").unwrap_err().contains("already exists"));
+    assert!(process_query(&mut db, "CREATE TABLE pets (id INT, id TEXT);").unwrap_err()
+        .contains("Column 'id' is defined more than once in table 'pets'"));
+    assert!(process_query(&mut db, "SELECT * FROM pets;").is_err());
+}
+
+#[test]
+fn test_group_by_requires_grouped_columns() {
+    let mut db = Database::new();
+    setup_users(&mut db);
+    let error = process_query(&mut db, "SELECT name, COUNT(*) FROM users GROUP BY age;").unwrap_err();
+    assert!(error.contains("Column 'name' must appear in GROUP BY"), "{}", error);
+    assert!(db.query_map("SELECT name, COUNT(*) FROM users", |row| row.get::<String>("name")).is_err());
+    assert!(db.query_map("SELECT * FROM users GROUP BY age", |row| row.get::<String>("age")).is_err());
+    let ages = db.query_map("SELECT users.age, COUNT(*) FROM users GROUP BY age ORDER BY age", |row| row.get::<u32>("users.age"));
+    assert!(ages.is_ok(), "{:?}", ages);
+}
+
+#[test]
+fn test_aggregates_over_join() {
+    let mut db = Database::new();
+    setup_users(&mut db);
+    process_query(&mut db, "CREATE TABLE orders (id INT, user_id INT, amount INT);").unwrap();
+    process_query(&mut db, "INSERT INTO orders (id, user_id, amount) VALUES ('1', '1', '10'), ('2', '1', '15'), ('3', '2', '7');").unwrap();
+
+    let join = "FROM users JOIN orders ON users.id = orders.user_id";
+    for sum in ["SUM(amount)", "SUM(orders.amount)"] {
+        let sql = format!("SELECT users.name, {} {} GROUP BY name ORDER BY name", sum, join);
+        let totals = db.query_map(&sql, |row| Ok((row.get::<String>("users.name")?, row.get::<f64>(sum)?))).unwrap();
+        assert_eq!(totals, [("alice".to_string(), 25.0), ("bob".to_string(), 7.0)]);
     }
+    let sql = format!("SELECT name {} GROUP BY name HAVING SUM(amount) > '10'", join);
+    assert_eq!(db.query_map(&sql, |row| row.get::<String>("name")).unwrap(), ["alice"]);
+    let sql = format!("SELECT COUNT(*), MAX(amount) {} WHERE amount > '8'", join);
+    let counts = db.query_map(&sql, |row| Ok((row.get::<u32>("COUNT(*)")?, row.get::<f64>("MAX(amount)")?))).unwrap();
+    assert_eq!(counts, [(2, 15.0)]);
+}
 
-    fn execute_delete(&mut self, stmt: DeleteStatement) -> Result<String, String> {
-        let table = self.tables.get_mut(&stmt.table)
-            .ok_or_else(|| format!("Table '{}' not found", stmt.table))?;
-        
-        let original_len = table.len();
-
-        table.retain(|row| {
-            stmt.where_clause.as_ref().map_or(true, |where_clause| {
-                row.get(&where_clause.column).map_or(true, |value| value != &where_clause.value)
-            })
-        });
-
-        let deleted = original_len - table.len();
-        
-        if deleted > 0 {
-            Ok(format!("Deleted {} row(s)", deleted))
-        } else {
-            Err("No matching rows found to delete".to_string())
-        }
+#[test]
+fn test_cross_join() {
+    let mut db = Database::new();
+    setup_users(&mut db);
+    process_query(&mut db, "CREATE TABLE colors (color TEXT);").unwrap();
+    process_query(&mut db, "INSERT INTO colors (color) VALUES ('red'), ('blue');").unwrap();
+
+    let sql = "SELECT name, color FROM users CROSS JOIN colors ORDER BY name";
+    let pairs = db.query_map(sql, |row| Ok((row.get::<String>("name")?, row.get::<String>("color")?))).unwrap();
+    assert_eq!(pairs.len(), 4);
+    assert!(pairs[..2].iter().all(|(name, _)| name == "alice"));
+    let counts = db.query_map("SELECT COUNT(*) FROM users CROSS JOIN colors WHERE color = 'red'", |row| row.get::<u32>("COUNT(*)"));
+    assert_eq!(counts.unwrap(), [2]);
+    assert!(process_query(&mut db, "SELECT * FROM users CROSS JOIN missing;").is_err());
+}
+
+#[test]
+fn test_join_on_several_columns() {
+    let mut db = Database::new();
+    process_query(&mut db, "CREATE TABLE stock (shop TEXT, item TEXT, count INT);").unwrap();
+    process_query(&mut db, "CREATE TABLE prices (shop TEXT, item TEXT, price INT);").unwrap();
+    process_query(&mut db, "INSERT INTO stock (shop, item, count) VALUES ('a', 'pen', '3'), ('a', 'ink', '1'), ('b', 'pen', '5');").unwrap();
+    process_query(&mut db, "INSERT INTO prices (shop, item, price) VALUES ('a', 'pen', '2'), ('b', 'pen', '4'), ('b', 'ink', '9');").unwrap();
+
+    let select = Select::from("stock").columns(["stock.shop", "stock.item", "price"])
+        .join(JoinType::Left, "prices", "stock.shop", "prices.shop")
+        .and_on("stock.item", "prices.item")
+        .order_by("count");
+    let sql = SQLStatement::from(select.clone()).to_string();
+    assert_eq!(sql, "SELECT stock.shop, stock.item, price FROM stock LEFT JOIN prices \
+                     ON stock.shop = prices.shop AND stock.item = prices.item ORDER BY count");
+    let rows = db.query(&select.build()).unwrap().rows;
+    assert_eq!(rows, [["a", "ink", NULL], ["a", "pen", "2"], ["b", "pen", "4"]]);
+}
+
+#[test]
+fn test_parenthesized_join() {
+    let mut db = Database::new();
+    process_query(&mut db, "CREATE TABLE orders (id INT, user_id INT);").unwrap();
+    process_query(&mut db, "CREATE TABLE users (id INT, team_id INT, name TEXT);").unwrap();
+    process_query(&mut db, "CREATE TABLE teams (id INT, label TEXT);").unwrap();
+    process_query(&mut db, "INSERT INTO orders (id, user_id) VALUES ('1', '10'), ('2', '11'), ('3', '12');").unwrap();
+    process_query(&mut db, "INSERT INTO users (id, team_id, name) VALUES ('10', '100', 'ann'), ('11', '101', 'bob');").unwrap();
+    process_query(&mut db, "INSERT INTO teams (id, label) VALUES ('100', 'red');").unwrap();
+
+    let sql = "SELECT orders.id, name, label FROM orders JOIN (users JOIN teams ON users.team_id = teams.id) \
+               ON orders.user_id = users.id ORDER BY orders.id";
+    assert_eq!(query(&db, sql).rows, [["1", "ann", "red"]]);
+    // The group is joined first, so bob, who has no team, isn't in it at all
+    let sql = "SELECT orders.id, name, label FROM orders LEFT JOIN (users JOIN teams ON users.team_id = teams.id) \
+               ON orders.user_id = users.id ORDER BY orders.id";
+    assert_eq!(query(&db, sql).rows, [["1", "ann", "red"], ["2", NULL, NULL], ["3", NULL, NULL]]);
+    let rows = query(&db, "SELECT * FROM orders JOIN (users LEFT JOIN teams ON users.team_id = teams.id) ON orders.user_id = users.id \
+                             WHERE orders.id = '2'");
+    assert_eq!(rows.columns, ["orders.id", "orders.user_id", "users.id", "users.team_id", "users.name", "teams.id", "teams.label"]);
+    assert_eq!(rows.rows, [["2", "11", "11", "101", "bob", NULL, NULL]]);
+
+    process_query(&mut db, "DELETE orders FROM orders JOIN (users JOIN teams ON users.team_id = teams.id) \
+                            ON orders.user_id = users.id WHERE label = 'red';").unwrap();
+    assert_eq!(query(&db, "SELECT id FROM orders ORDER BY id").rows, [["2"], ["3"]]);
+    assert!(process_query(&mut db, "SELECT * FROM orders JOIN (users JOIN missing ON users.id = missing.id) ON orders.user_id = users.id;").is_err());
+}
+
+#[test]
+fn test_qualified_column_names() {
+    let mut db = Database::new();
+    setup_users(&mut db);
+
+    let names = |db: &Database, sql: &str| db.query_map(sql, |row| row.get::<String>("name")).unwrap();
+    assert_eq!(names(&db, "SELECT name FROM users WHERE users.age > '26' ORDER BY users.id"), ["alice"]);
+    assert_eq!(names(&db, "SELECT name FROM users ORDER BY users.age"), ["bob", "alice"]);
+    let sql = "SELECT users.age, SUM(users.id) FROM users GROUP BY users.age ORDER BY age";
+    let sums = db.query_map(sql, |row| row.get::<f64>("SUM(users.id)")).unwrap();
+    assert_eq!(sums, [2.0, 1.0]);
+
+    process_query(&mut db, "UPDATE users SET age = '26' WHERE users.name = 'bob';").unwrap();
+    process_query(&mut db, "DELETE FROM users WHERE users.id = '1';").unwrap();
+    let rows = db.query(&Select::from("users").build()).unwrap();
+    assert_eq!(rows.columns, ["id", "name", "age"]);
+    assert_eq!(rows.rows, [["2", "bob", "26"]]);
+}
+
+#[test]
+fn test_insert_conflict_actions_are_rejected() {
+    let mut db = Database::new();
+    setup_users(&mut db);
+    for sql in [
+        "INSERT OR IGNORE INTO users (id, name, age) VALUES ('1', 'carol', '40');",
+        "INSERT OR REPLACE INTO users (id, name, age) VALUES ('1', 'carol', '40');",
+    ] {
+        assert!(process_query(&mut db, sql).unwrap_err().contains("primary key"), "{}", sql);
+    }
+    assert_eq!(db.query(&Select::from("users").build()).unwrap().rows.len(), 2);
+}
+
+#[test]
+fn test_decimal_columns_are_refused() {
+    let mut db = Database::new();
+    setup_users(&mut db);
+    for sql in [
+        "CREATE TABLE prices (id INT, amount DECIMAL(10, 2));",
+        "ALTER TABLE users ADD COLUMN balance NUMERIC(8, 2);",
+        "ALTER TABLE users MODIFY COLUMN age DECIMAL(5, 1);",
+    ] {
+        assert!(process_query(&mut db, sql).unwrap_err().contains("persistent database"), "{}", sql);
     }
-}
\ No newline at end of file
+    assert!(process_query(&mut db, "CREATE TABLE prices (id INT, amount FLOAT);").is_ok());
+}
+
+#[test]
+fn test_table_aliases() {
+    let mut db = Database::new();
+    setup_users(&mut db);
+    process_query(&mut db, "CREATE TABLE orders (id INT, user_id INT, amount INT);").unwrap();
+    process_query(&mut db, "INSERT INTO orders (id, user_id, amount) VALUES ('1', '1', '10'), ('2', '2', '7');").unwrap();
+
+    let sql = "SELECT u.name, o.amount FROM users u JOIN orders AS o ON u.id = o.user_id WHERE o.amount > '8'";
+    let rows = db.query_map(sql, |row| Ok((row.get::<String>("users.name")?, row.get::<u32>("orders.amount")?))).unwrap();
+    assert_eq!(rows, [("alice".to_string(), 10)]);
+    let names = db.query_map("SELECT name FROM users u ORDER BY u.age", |row| row.get::<String>("name")).unwrap();
+    assert_eq!(names, ["bob", "alice"]);
+}
+
+#[test]
+fn test_result_column_types() {
+    let mut db = Database::new();
+    setup_users(&mut db);
+
+    let select = |sql: &str| match parse_sql(tokenize(sql).unwrap()).unwrap() {
+        SQLStatement::Select(select) => db.query(&select).unwrap(),
+        _ => unreachable!(),
+    };
+    let result = select("SELECT name, users.age, COUNT(*) FROM users GROUP BY name, users.age ORDER BY name");
+    let meta: Vec<_> = result.meta.iter().map(|m| (m.name.as_str(), m.table.as_deref(), m.value_type)).collect();
+    assert_eq!(meta, [
+        ("name", Some("users"), ValueType::Text),
+        ("users.age", Some("users"), ValueType::Integer),
+        ("COUNT(*)", None, ValueType::Integer),
+    ]);
+    let json: serde_json::Value = serde_json::from_str(&result.render(OutputFormat::Json)).unwrap();
+    assert_eq!(json[0], serde_json::json!({"name": "alice", "users.age": 30, "COUNT(*)": 1}));
+}
+
+#[test]
+fn test_rows_affected() {
+    let mut db = Database::new();
+    assert_eq!(db.last_insert_rowid(), None);
+    setup_users(&mut db);
+    assert_eq!((db.rows_affected(), db.last_insert_rowid().as_deref()), (1, Some("2")));
+
+    process_query(&mut db, "INSERT INTO users (id, name, age) VALUES ('3', 'carol', '41'), ('4', 'dan', '19');").unwrap();
+    assert_eq!((db.rows_affected(), db.last_insert_rowid().as_deref()), (2, Some("4")));
+    process_query(&mut db, "UPDATE users SET age = '20' WHERE age < '30';").unwrap();
+    assert_eq!(db.rows_affected(), 2);
+    assert!(process_query(&mut db, "DELETE FROM users WHERE name = 'erin';").is_err());
+    assert_eq!((db.rows_affected(), db.last_insert_rowid().as_deref()), (0, Some("4")));
+}
+
+#[test]
+fn test_execute_result_kinds() {
+    let mut db = Database::new();
+    let mut execute = |sql: &str| db.execute(parse_sql(tokenize(sql).unwrap()).unwrap()).unwrap();
+
+    assert!(matches!(execute("CREATE TABLE users (id INT, name TEXT);"), StatementResult::Done(_)));
+    let inserted = execute("INSERT INTO users (id, name) VALUES ('1', 'alice'), ('2', 'bob');");
+    assert!(matches!(inserted, StatementResult::Changed { rows_affected: 2, last_insert_rowid: Some(ref id), .. } if id == "2"));
+    assert_eq!(execute("DELETE FROM users WHERE id = '2';").rows_affected(), 1);
+    let rows = execute("SELECT name FROM users WHERE id = '1';").into_rows().unwrap();
+    assert_eq!(rows.rows, [["alice"]]);
+    let empty = execute("SELECT name FROM users WHERE id = '2';");
+    assert_eq!(empty.to_string(), "No matching rows found");
+    assert!(execute("BEGIN;").into_rows().is_none());
+}
+
+#[test]
+fn test_order_by_aggregates_and_aliases() {
+    let mut db = Database::new();
+    process_query(&mut db, "CREATE TABLE staff (name TEXT, dept TEXT, salary INT);").unwrap();
+    process_query(&mut db, "INSERT INTO staff (name, dept, salary) VALUES ('a', 'ops', '9'), ('b', 'dev', '5'), \
+                            ('c', 'dev', '5'), ('d', 'hr', '4'), ('e', 'dev', '1'), ('f', 'ops', '1');").unwrap();
+
+    let depts = |db: &Database, sql: &str| db.query_map(sql, |row| row.get::<String>("dept")).unwrap();
+    assert_eq!(depts(&db, "SELECT dept, COUNT(*) FROM staff GROUP BY dept ORDER BY COUNT(*) DESC"), ["dev", "ops", "hr"]);
+    // By value, not text: 11 sorts after 10
+    assert_eq!(depts(&db, "SELECT dept, SUM(salary) AS total FROM staff GROUP BY dept ORDER BY total"), ["hr", "ops", "dev"]);
+    assert_eq!(depts(&db, "SELECT dept FROM staff GROUP BY dept ORDER BY MAX(salary)"), ["hr", "dev", "ops"]);
+    let names = db.query_map("SELECT name AS who FROM staff ORDER BY who DESC LIMIT 2", |row| row.get::<String>("who")).unwrap();
+    assert_eq!(names, ["f", "e"]);
+}
+
+#[test]
+fn test_having_on_aliases() {
+    let mut db = Database::new();
+    process_query(&mut db, "CREATE TABLE staff (name TEXT, dept TEXT, salary INT);").unwrap();
+    process_query(&mut db, "INSERT INTO staff (name, dept, salary) VALUES ('a', 'ops', '9'), ('b', 'dev', '5'), \
+                            ('c', 'dev', '5'), ('d', 'hr', '4'), ('e', 'dev', '1'), ('f', 'ops', '1');").unwrap();
+
+    let depts = |db: &Database, sql: &str| db.query_map(sql, |row| row.get::<String>("dept")).unwrap();
+    assert_eq!(depts(&db, "SELECT dept, COUNT(*) AS c FROM staff GROUP BY dept HAVING c > '1' ORDER BY c DESC"), ["dev", "ops"]);
+    assert_eq!(depts(&db, "SELECT dept, SUM(salary) AS total FROM staff GROUP BY dept HAVING total = '10'"), ["ops"]);
+    assert_eq!(depts(&db, "SELECT dept AS d, dept FROM staff GROUP BY dept HAVING d = 'hr'"), ["hr"]);
+}
+
+#[test]
+fn test_multi_table_writes() {
+    let mut db = Database::new();
+    process_query(&mut db, "CREATE TABLE users (id INT, banned TEXT);").unwrap();
+    process_query(&mut db, "CREATE TABLE orders (id INT, user_id INT, status TEXT);").unwrap();
+    process_query(&mut db, "INSERT INTO users (id, banned) VALUES ('1', 'no'), ('2', 'yes'), ('3', 'yes');").unwrap();
+    process_query(&mut db, "INSERT INTO orders (id, user_id, status) VALUES ('10', '1', 'open'), ('11', '2', 'open'), \
+                            ('12', '3', 'open'), ('13', '1', 'open');").unwrap();
+    let orders = |db: &Database, sql: &str| db.query_map(sql, |row| row.get::<String>("id")).unwrap();
+
+    process_query(&mut db, "UPDATE orders JOIN users ON orders.user_id = users.id SET orders.status = 'void' \
+                            WHERE users.banned = 'yes';").unwrap();
+    assert_eq!(db.rows_affected(), 2);
+    assert_eq!(orders(&db, "SELECT id FROM orders WHERE status = 'void'"), ["11", "12"]);
+    assert!(process_query(&mut db, "UPDATE orders JOIN users ON orders.user_id = users.id SET users.banned = 'no';").is_err());
+
+    process_query(&mut db, "DELETE FROM orders WHERE user_id IN (SELECT id FROM users WHERE banned = 'yes');").unwrap();
+    assert_eq!(db.rows_affected(), 2);
+    assert_eq!(orders(&db, "SELECT id FROM orders"), ["10", "13"]);
+    assert_eq!(orders(&db, "SELECT id FROM users WHERE id IN (SELECT user_id FROM orders)"), ["1"]);
+    process_query(&mut db, "DELETE orders FROM orders JOIN users ON orders.user_id = users.id WHERE orders.id = '13';").unwrap();
+    assert_eq!(orders(&db, "SELECT id FROM orders"), ["10"]);
+    assert!(process_query(&mut db, "DELETE FROM orders WHERE user_id IN (SELECT id, banned FROM users);").is_err());
+}
+
+#[test]
+fn test_math_functions() {
+    let mut db = Database::new();
+    process_query(&mut db, "CREATE TABLE items (id INT, price TEXT, stock INT);").unwrap();
+    process_query(&mut db, "INSERT INTO items (id, price, stock) VALUES ('1', '-2.75', '7'), ('2', '3.14159', '-3'), ('3', 'n/a', '4');").unwrap();
+    let values = |db: &Database, sql: &str| db.query_map(sql, |row| row.get::<String>("v")).unwrap();
+
+    assert_eq!(values(&db, "SELECT ABS(price) AS v FROM items"), ["2.75", "3.14159", NULL]);
+    assert_eq!(values(&db, "SELECT ROUND(price, 2) AS v FROM items"), ["-2.75", "3.14", NULL]);
+    assert_eq!(values(&db, "SELECT ROUND(price) AS v FROM items"), ["-3", "3", NULL]);
+    assert_eq!(values(&db, "SELECT FLOOR(price) AS v FROM items"), ["-3", "3", NULL]);
+    assert_eq!(values(&db, "SELECT CEIL(price) AS v FROM items"), ["-2", "4", NULL]);
+    assert_eq!(values(&db, "SELECT MOD(stock, 2) AS v FROM items"), ["1", "-1", "0"]);
+    assert_eq!(values(&db, "SELECT POWER(stock, 2) AS v FROM items"), ["49", "9", "16"]);
+    assert_eq!(values(&db, "SELECT MOD(stock, 0) AS v FROM items"), [NULL, NULL, NULL]);
+
+    assert_eq!(values(&db, "SELECT id AS v FROM items WHERE ABS(stock) > '3'"), ["1", "3"]);
+    // NULL sorts below every value
+    assert_eq!(values(&db, "SELECT id AS v FROM items ORDER BY ABS(price) DESC"), ["2", "1", "3"]);
+
+    process_query(&mut db, "UPDATE items SET price = ROUND(price, 1), stock = ABS(stock) WHERE MOD(stock, 2) = '-1';").unwrap();
+    assert_eq!(db.rows_affected(), 1);
+    assert_eq!(values(&db, "SELECT price AS v FROM items WHERE id = '2'"), ["3.1"]);
+    assert_eq!(values(&db, "SELECT stock AS v FROM items WHERE id = '2'"), ["3"]);
+}
+
+#[test]
+fn test_select_star_column_order() {
+    let dir = TempDir::new("memory_column_order");
+    std::fs::create_dir_all(dir.path()).unwrap();
+    let path = format!("{}/db.json", dir.path());
+    let mut db = Database::new();
+    process_query(&mut db, "CREATE TABLE people (name TEXT, id INT, city TEXT);").unwrap();
+    // Headers come from the schema, so an empty table has them too
+    assert_eq!(query(&db, "SELECT * FROM people").columns, ["name", "id", "city"]);
+
+    process_query(&mut db, "INSERT INTO people (id, city, name) VALUES ('1', 'oslo', 'ann');").unwrap();
+    process_query(&mut db, "ALTER TABLE people ADD COLUMN age INT;").unwrap();
+    process_query(&mut db, "ALTER TABLE people DROP COLUMN city;").unwrap();
+    let rows = query(&db, "SELECT * FROM people");
+    assert_eq!(rows.columns, ["name", "id", "age"]);
+    assert_eq!(rows.rows, [["ann", "1", NULL]]);
+
+    // Tables INSERT creates keep the order their columns were first inserted in
+    process_query(&mut db, "INSERT INTO pets (zeta, alpha) VALUES ('z', 'a');").unwrap();
+    process_query(&mut db, "INSERT INTO pets (beta, zeta) VALUES ('b', 'z');").unwrap();
+    assert_eq!(query(&db, "SELECT * FROM pets").columns, ["zeta", "alpha", "beta"]);
+
+    process_query(&mut db, "BEGIN;").unwrap();
+    process_query(&mut db, "ALTER TABLE people ADD COLUMN email TEXT;").unwrap();
+    process_query(&mut db, "ROLLBACK;").unwrap();
+    assert_eq!(query(&db, "SELECT * FROM people").columns, ["name", "id", "age"]);
+
+    db.save(&path).unwrap();
+    let loaded = Database::load(&path).unwrap();
+    assert_eq!(query(&loaded, "SELECT * FROM people").columns, ["name", "id", "age"]);
+    assert_eq!(query(&loaded, "SELECT * FROM pets JOIN people ON pets.zeta = people.name").columns,
+               ["pets.zeta", "pets.alpha", "pets.beta", "people.name", "people.id", "people.age"]);
+}
+
+#[test]
+fn test_update_and_delete_returning() {
+    let mut db = Database::new();
+    setup_users(&mut db);
+    let mut returning = |sql: &str| match db.execute(parse_sql(tokenize(sql).unwrap()).unwrap()).unwrap() {
+        StatementResult::Rows(result) => result,
+        other => panic!("expected rows: {:?}", other),
+    };
+
+    let result = returning("UPDATE users SET age = '31' WHERE name = 'alice' RETURNING old.age, age, name;");
+    assert_eq!(result.columns, ["old.age", "age", "name"]);
+    assert_eq!(result.rows, [["30", "31", "alice"]]);
+
+    let result = returning("DELETE FROM users WHERE id = '2' RETURNING *;");
+    assert_eq!(result.columns, ["id", "name", "age"]);
+    assert_eq!(result.rows, [["2", "bob", "25"]]);
+    // A DELETE's rows have no new image
+    let result = returning("DELETE FROM users WHERE name = 'alice' RETURNING name, new.name;");
+    assert_eq!(result.rows, [["alice", NULL]]);
+
+    // Matching no rows returns none rather than failing
+    assert!(returning("DELETE FROM users RETURNING id;").rows.is_empty());
+
+    // An unknown column fails before anything changes
+    process_query(&mut db, "INSERT INTO users (id, name, age) VALUES ('1', 'alice', '30');").unwrap();
+    let stmt = Update::table("users").set("age", "40").returning(["email"]).build();
+    assert!(db.execute(SQLStatement::Update(stmt)).is_err());
+    assert_eq!(query(&db, "SELECT age FROM users WHERE name = 'alice'").rows, [["30"]]);
+}
+
+#[test]
+fn test_failed_insert_adds_no_rows() {
+    let mut db = Database::new();
+    setup_users(&mut db);
+    let err = process_query(&mut db, "INSERT INTO users (id, name, age) VALUES ('3', 'carol', '41'), ('4', 'dave');").unwrap_err();
+    assert!(err.contains("Column count does not match value count"), "{}", err);
+    assert_eq!(query(&db, "SELECT COUNT(*) FROM users").rows, [["2"]]);
+
+    // Nor does it create the table it would have
+    assert!(process_query(&mut db, "INSERT INTO pets (name) VALUES ('rex'), ('tom', 'cat');").is_err());
+    assert!(!db.table_names().contains(&"pets".to_string()));
+
+    process_query(&mut db, "BEGIN;").unwrap();
+    process_query(&mut db, "INSERT INTO users (id, name, age) VALUES ('3', 'carol', '41');").unwrap();
+    assert!(process_query(&mut db, "INSERT INTO users (id, name) VALUES ('4', 'dave'), ('5');").is_err());
+    process_query(&mut db, "COMMIT;").unwrap();
+    assert_eq!(query(&db, "SELECT COUNT(*) FROM users").rows, [["3"]]);
+}
+
+#[test]
+fn test_null_is_not_the_text_null() {
+    let dir = TempDir::new("memory_nulls");
+    std::fs::create_dir_all(dir.path()).unwrap();
+    let path = format!("{}/db.json", dir.path());
+    let mut db = Database::new();
+    process_query(&mut db, "CREATE TABLE notes (id INT, body TEXT);").unwrap();
+    process_query(&mut db, "INSERT INTO notes (id, body) VALUES ('1', NULL), ('2', 'NULL');").unwrap();
+    process_query(&mut db, "CREATE TABLE tags (id INT, body TEXT);").unwrap();
+    process_query(&mut db, "INSERT INTO tags (id, body) VALUES ('8', NULL), ('9', 'NULL');").unwrap();
+    db.save(&path).unwrap();
+    let db = Database::load(&path).unwrap();
+
+    assert_eq!(query(&db, "SELECT id FROM notes WHERE body = 'NULL'").rows, [["2"]]);
+    assert_eq!(query(&db, "SELECT id FROM notes WHERE body IS NULL").rows, [["1"]]);
+    assert_eq!(query(&db, "SELECT id FROM notes WHERE body IS NOT NULL").rows, [["2"]]);
+    // NULL equals nothing, not even another NULL
+    assert_eq!(query(&db, "SELECT id FROM notes WHERE body > 'A'").rows, [["2"]]);
+    assert_eq!(query(&db, "SELECT notes.id, tags.id FROM notes JOIN tags ON notes.body = tags.body").rows, [["2", "9"]]);
+    assert_eq!(query(&db, "SELECT COUNT(body) FROM notes").rows, [["1"]]);
+
+    let result = query(&db, "SELECT id, body FROM notes ORDER BY id");
+    let json: serde_json::Value = serde_json::from_str(&result.render(OutputFormat::Json)).unwrap();
+    assert_eq!(json, serde_json::json!([{"id": 1, "body": null}, {"id": 2, "body": "NULL"}]));
+    assert_eq!(result.render(OutputFormat::Csv), "id,body\r\n1,\r\n2,NULL\r\n");
+    assert_eq!(result.render(OutputFormat::List), "id | body\n---------\n1 | NULL\n2 | 'NULL'\n");
+    assert!(result.render(OutputFormat::Table).contains("│ 2  │ 'NULL' │"), "{}", result.render(OutputFormat::Table));
+}
+
+#[test]
+fn test_insert_without_column_list() {
+    let mut db = Database::new();
+    setup_users(&mut db);
+    process_query(&mut db, "INSERT INTO users VALUES ('3', 'carol', '41');").unwrap();
+    assert_eq!(query(&db, "SELECT id, name, age FROM users WHERE id = '3'").rows, [["3", "carol", "41"]]);
+
+    let err = process_query(&mut db, "INSERT INTO users VALUES ('4', 'dave');").unwrap_err();
+    assert!(err.contains("Column count does not match value count"), "{}", err);
+    assert!(process_query(&mut db, "INSERT INTO pets VALUES ('rex');").unwrap_err().contains("not found"));
+}
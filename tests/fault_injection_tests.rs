@@ -0,0 +1,109 @@
+#![cfg(feature = "fault-injection")]
+
+use rust_sql_parser::faults::{self, FaultPoint};
+use rust_sql_parser::parser::parse_sql;
+use rust_sql_parser::persistent_executor::PersistentDatabase;
+use rust_sql_parser::storage::{CompactionConfig, LSMStorage};
+use rust_sql_parser::tokenizer::tokenize;
+
+mod common;
+use common::TempDir;
+
+fn run(db: &PersistentDatabase, sql: &str) -> Result<String, String> {
+    db.execute(parse_sql(tokenize(sql)?)?).map(|result| result.to_string())
+}
+
+fn entry(key: &str, value: &str) -> (String, String) {
+    (key.to_string(), value.to_string())
+}
+
+#[test]
+fn test_write_failing_after_wal_append_is_recovered() {
+    let dir = TempDir::new("fault_wal");
+    let mut storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+    storage.insert("a".to_string(), "1".to_string()).unwrap();
+    faults::inject(dir.as_path(), FaultPoint::WalAppend, 0);
+    assert!(storage.insert("b".to_string(), "2".to_string()).is_err());
+    drop(storage);
+
+    // The batch reached the WAL, so recovery applies it
+    let storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+    assert_eq!(storage.get_all().unwrap(), vec![entry("a", "1"), entry("b", "2")]);
+}
+
+#[test]
+fn test_crash_mid_flush_keeps_data() {
+    let dir = TempDir::new("fault_flush");
+    let mut storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+    storage.insert("a".to_string(), "1".to_string()).unwrap();
+    faults::inject(dir.as_path(), FaultPoint::Flush, 0);
+    assert!(storage.close().is_err());
+    drop(storage);
+
+    // The unlisted SSTable is discarded and the set-aside WAL replayed
+    let mut storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+    assert!(storage.sstables().is_empty());
+    assert_eq!(storage.get_all().unwrap(), vec![entry("a", "1")]);
+    storage.close().unwrap();
+    assert_eq!(storage.sstables().len(), 1);
+}
+
+#[test]
+fn test_crash_mid_compaction_keeps_inputs() {
+    let dir = TempDir::new("fault_compaction");
+    let config = CompactionConfig { trigger_file_count: 2, ..CompactionConfig::default() };
+    let mut storage = LSMStorage::with_config(dir.as_path(), "t", config.clone()).unwrap();
+    storage.insert("a".to_string(), "1".to_string()).unwrap();
+    storage.close().unwrap();
+    storage.insert("b".to_string(), "2".to_string()).unwrap();
+    faults::inject(dir.as_path(), FaultPoint::Compaction, 0);
+    assert!(storage.close().is_err());
+    drop(storage);
+
+    // The compaction's outputs are orphans; its inputs are still live
+    let mut storage = LSMStorage::with_config(dir.as_path(), "t", config).unwrap();
+    assert_eq!(storage.sstables().len(), 2);
+    assert_eq!(storage.get_all().unwrap(), vec![entry("a", "1"), entry("b", "2")]);
+    storage.insert("c".to_string(), "3".to_string()).unwrap();
+    storage.close().unwrap();
+    assert_eq!(storage.sstables().len(), 1);
+    assert_eq!(storage.get_all().unwrap().len(), 3);
+}
+
+#[test]
+fn test_commit_interrupted_between_tables_is_finished_on_open() {
+    let dir = TempDir::new("fault_commit");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE a (id INT PRIMARY KEY);").unwrap();
+    run(&db, "CREATE TABLE b (id INT PRIMARY KEY);").unwrap();
+    run(&db, "BEGIN;").unwrap();
+    run(&db, "INSERT INTO a (id) VALUES ('1');").unwrap();
+    run(&db, "INSERT INTO b (id) VALUES ('1');").unwrap();
+    faults::inject(&dir.as_path().join("b"), FaultPoint::WalAppend, 0);
+    assert!(run(&db, "COMMIT;").is_err());
+    drop(db);
+
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    for table in ["a", "b"] {
+        assert!(run(&db, &format!("SELECT id FROM {};", table)).unwrap().contains('1'), "{}", table);
+    }
+}
+
+#[test]
+fn test_batch_interrupted_between_shards_is_finished_on_open() {
+    let dir = TempDir::new("fault_shards");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE events (id INT PRIMARY KEY, v TEXT) PARTITION BY HASH(id) PARTITIONS 4;").unwrap();
+    let values: Vec<String> = (1..=20).map(|i| format!("('{}', 'x')", i)).collect();
+    faults::inject(&dir.as_path().join("events"), FaultPoint::ShardBatch, 0);
+    assert!(run(&db, &format!("INSERT INTO events (id, v) VALUES {};", values.join(", "))).is_err());
+
+    // Only some shards hold their rows, so the table refuses to be read
+    let err = run(&db, "SELECT COUNT(*) FROM events;").unwrap_err();
+    assert!(err.contains("reopen the table"), "{}", err);
+    drop(db);
+
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    assert_eq!(db.query_map("SELECT COUNT(*) FROM events", |row| row.get::<u32>("COUNT(*)")).unwrap(), [20]);
+    assert!(!dir.as_path().join("events").join("batch.log").exists());
+}
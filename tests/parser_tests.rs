@@ -1,20 +1,36 @@
 #[cfg(test)]
 mod tests {
-    use rust_sql_parser::tokenizer::{tokenize, Token};
-    use rust_sql_parser::parser::parse_sql;
-    use rust_sql_parser::ast::{SQLStatement, SelectStatement, InsertStatement, UpdateStatement, DeleteStatement, WhereClause};
+    use rust_sql_parser::tokenizer::{tokenize, tokenize_spanned, Span, Token};
+    use rust_sql_parser::parser::{parse_insert_stream, parse_sql, parse_statement, parse_statement_with_dialect};
+    #[cfg(feature = "serde")]
+    use rust_sql_parser::parser::parse_to_json;
+    use rust_sql_parser::dialect::Dialect;
+    use rust_sql_parser::format::{format_sql, format_sql_with_dialect};
+    use rust_sql_parser::ast::{SQLStatement, SelectStatement, InsertStatement, UpdateStatement, DeleteStatement, WhereClause, ColumnExpr, TransactionStatement, CreateTableStatement, ShowStatement, AlterTableStatement, AlterAction, PartitionBy, Hint, ExplainStatement, ExplainFormat, JoinCondition, InList, ConflictAction, SetValue, FunctionCall, MathFunction, TableFunction, NULL};
 
     #[test]
     fn test_parse_select() {
         let tokens = tokenize("SELECT name, age FROM users WHERE age > '30';").unwrap();
         let expected = SQLStatement::Select(SelectStatement {
-            columns: vec!["name".to_string(), "age".to_string()],
+            columns: vec![ColumnExpr::Column("name".to_string()), ColumnExpr::Column("age".to_string())],
             table: "users".to_string(),
             where_clause: Some(WhereClause {
                 column: "age".to_string(),
                 operator: ">".to_string(),
                 value: "30".to_string(),
+                upper_bound: None,
+                in_list: None,
+                function: None,
             }),
+            order_by: None,
+            group_by: None,
+            having: None,
+            join: None,
+            limit: None,
+            offset: None,
+            hints: Vec::new(),
+            as_of: None,
+            table_function: None,
         });
         let result = parse_sql(tokens).unwrap();
         assert_eq!(result, expected);
@@ -26,23 +42,39 @@ mod tests {
         let expected = SQLStatement::Insert(InsertStatement {
             table: "users".to_string(),
             columns: vec!["name".to_string(), "age".to_string()],
-            values: vec!["Alice".to_string(), "25".to_string()],
+            values: vec![vec!["Alice".to_string(), "25".to_string()]],
+            on_conflict: None,
         });
         let result = parse_sql(tokens).unwrap();
         assert_eq!(result, expected);
     }
 
+    #[test]
+    fn test_parse_escaped_and_empty_strings() {
+        let tokens = tokenize("INSERT INTO notes (body, author) VALUES ('it''s', '');").unwrap();
+        let SQLStatement::Insert(stmt) = parse_sql(tokens).unwrap() else {
+            panic!("expected INSERT");
+        };
+        assert_eq!(stmt.values, vec![vec!["it's".to_string(), String::new()]]);
+        assert!(tokenize("INSERT INTO notes VALUES ('open);").is_err());
+    }
+
     #[test]
     fn test_parse_update() {
         let tokens = tokenize("UPDATE users SET age = '26' WHERE name = 'Alice';").unwrap();
         let expected = SQLStatement::Update(UpdateStatement {
             table: "users".to_string(),
-            assignments: vec![("age".to_string(), "26".to_string())],
+            join: None,
+            assignments: vec![("age".to_string(), SetValue::Value("26".to_string()))],
             where_clause: Some(WhereClause {
                 column: "name".to_string(),
                 operator: "=".to_string(),
                 value: "Alice".to_string(),
+                upper_bound: None,
+                in_list: None,
+                function: None,
             }),
+            returning: None,
         });
         let result = parse_sql(tokens).unwrap();
         assert_eq!(result, expected);
@@ -53,11 +85,16 @@ mod tests {
         let tokens = tokenize("DELETE FROM users WHERE name = 'Bob';").unwrap();
         let expected = SQLStatement::Delete(DeleteStatement {
             table: "users".to_string(),
+            join: None,
             where_clause: Some(WhereClause {
                 column: "name".to_string(),
                 operator: "=".to_string(),
                 value: "Bob".to_string(),
+                upper_bound: None,
+                in_list: None,
+                function: None,
             }),
+            returning: None,
         });
         let result = parse_sql(tokens).unwrap();
         assert_eq!(result, expected);
@@ -67,9 +104,18 @@ mod tests {
     fn test_parse_select_without_where() {
         let tokens = tokenize("SELECT id FROM products;").unwrap();
         let expected = SQLStatement::Select(SelectStatement {
-            columns: vec!["id".to_string()],
+            columns: vec![ColumnExpr::Column("id".to_string())],
             table: "products".to_string(),
             where_clause: None,
+            order_by: None,
+            group_by: None,
+            having: None,
+            join: None,
+            limit: None,
+            offset: None,
+            hints: Vec::new(),
+            as_of: None,
+            table_function: None,
         });
         let result = parse_sql(tokens).unwrap();
         assert_eq!(result, expected);
@@ -80,15 +126,20 @@ mod tests {
         let tokens = tokenize("UPDATE users SET name = 'Charlie', age = '28' WHERE id = '3';").unwrap();
         let expected = SQLStatement::Update(UpdateStatement {
             table: "users".to_string(),
+            join: None,
             assignments: vec![
-                ("name".to_string(), "Charlie".to_string()),
-                ("age".to_string(), "28".to_string()),
+                ("name".to_string(), SetValue::Value("Charlie".to_string())),
+                ("age".to_string(), SetValue::Value("28".to_string())),
             ],
             where_clause: Some(WhereClause {
                 column: "id".to_string(),
                 operator: "=".to_string(),
                 value: "3".to_string(),
+                upper_bound: None,
+                in_list: None,
+                function: None,
             }),
+            returning: None,
         });
         let result = parse_sql(tokens).unwrap();
         assert_eq!(result, expected);
@@ -99,7 +150,9 @@ mod tests {
         let tokens = tokenize("DELETE FROM logs;").unwrap();
         let expected = SQLStatement::Delete(DeleteStatement {
             table: "logs".to_string(),
+            join: None,
             where_clause: None,
+            returning: None,
         });
         let result = parse_sql(tokens).unwrap();
         assert_eq!(result, expected);
@@ -111,7 +164,8 @@ mod tests {
         let expected = SQLStatement::Insert(InsertStatement {
             table: "users".to_string(),
             columns: vec![],
-            values: vec!["John".to_string(), "Doe".to_string(), "30".to_string()],
+            values: vec![vec!["John".to_string(), "Doe".to_string(), "30".to_string()]],
+            on_conflict: None,
         });
         let result = parse_sql(tokens).unwrap();
         assert_eq!(result, expected);
@@ -123,4 +177,643 @@ mod tests {
         let result = parse_sql(tokens);
         assert!(result.is_err());
     }
+
+    #[test]
+    fn test_parse_savepoint_statements() {
+        let cases = vec![
+            ("BEGIN;", TransactionStatement::Begin),
+            ("BEGIN TRANSACTION;", TransactionStatement::Begin),
+            ("BEGIN OPTIMISTIC;", TransactionStatement::BeginOptimistic),
+            ("COMMIT;", TransactionStatement::Commit),
+            ("ROLLBACK;", TransactionStatement::Rollback),
+            ("SAVEPOINT sp1;", TransactionStatement::Savepoint("sp1".to_string())),
+            ("ROLLBACK TO SAVEPOINT sp1;", TransactionStatement::RollbackToSavepoint("sp1".to_string())),
+            ("ROLLBACK TO sp1;", TransactionStatement::RollbackToSavepoint("sp1".to_string())),
+            ("RELEASE SAVEPOINT sp1;", TransactionStatement::ReleaseSavepoint("sp1".to_string())),
+        ];
+        for (sql, expected) in cases {
+            let result = parse_sql(tokenize(sql).unwrap()).unwrap();
+            assert_eq!(result, SQLStatement::Transaction(expected), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn test_parse_create_table_primary_key() {
+        let expected = SQLStatement::CreateTable(CreateTableStatement {
+            table: "users".to_string(),
+            columns: vec![("id".to_string(), "INT".to_string()), ("name".to_string(), "TEXT".to_string())],
+            primary_key: Some("id".to_string()),
+            options: vec![],
+            partition_by: None,
+        });
+        for sql in [
+            "CREATE TABLE users (id INT PRIMARY KEY, name TEXT);",
+            "CREATE TABLE users (id INT, name TEXT, PRIMARY KEY (id));",
+        ] {
+            assert_eq!(parse_sql(tokenize(sql).unwrap()).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn test_parse_where_between() {
+        let tokens = tokenize("DELETE FROM users WHERE id BETWEEN '10' AND '20';").unwrap();
+        let expected = SQLStatement::Delete(DeleteStatement {
+            table: "users".to_string(),
+            join: None,
+            where_clause: Some(WhereClause {
+                column: "id".to_string(),
+                operator: "BETWEEN".to_string(),
+                value: "10".to_string(),
+                upper_bound: Some("20".to_string()),
+                in_list: None,
+                function: None,
+            }),
+            returning: None,
+        });
+        assert_eq!(parse_sql(tokens).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_parse_show_storage_stats() {
+        let result = parse_sql(tokenize("show storage stats;").unwrap()).unwrap();
+        assert_eq!(result, SQLStatement::Show(ShowStatement::StorageStats));
+        assert!(parse_sql(tokenize("SHOW STORAGE;").unwrap()).is_err());
+        let result = parse_sql(tokenize("SHOW TABLE STATUS;").unwrap()).unwrap();
+        assert_eq!(result, SQLStatement::Show(ShowStatement::TableStatus));
+        assert_eq!(result.to_string(), "SHOW TABLE STATUS");
+        assert!(parse_sql(tokenize("SHOW TABLE;").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_parse_table_storage_options() {
+        let tokens = tokenize("CREATE TABLE logs (msg TEXT) WITH (memtable_size = 65536, SSTABLE_SIZE = '1048576');").unwrap();
+        let SQLStatement::CreateTable(stmt) = parse_sql(tokens).unwrap() else {
+            panic!("expected CREATE TABLE");
+        };
+        assert_eq!(stmt.options, vec![
+            ("memtable_size".to_string(), "65536".to_string()),
+            ("sstable_size".to_string(), "1048576".to_string()),
+        ]);
+
+        let tokens = tokenize("ALTER TABLE logs SET (memtable_size = 4096);").unwrap();
+        assert_eq!(parse_sql(tokens).unwrap(), SQLStatement::AlterTable(AlterTableStatement {
+            table: "logs".to_string(),
+            action: AlterAction::SetOptions(vec![("memtable_size".to_string(), "4096".to_string())]),
+        }));
+    }
+
+    #[test]
+    fn test_parse_table_ttl() {
+        let tokens = tokenize("CREATE TABLE sessions (id INT, user TEXT) WITH TTL = 3600;").unwrap();
+        let SQLStatement::CreateTable(stmt) = parse_sql(tokens).unwrap() else {
+            panic!("expected CREATE TABLE");
+        };
+        assert_eq!(stmt.options, vec![("ttl".to_string(), "3600".to_string())]);
+        assert!(parse_sql(tokenize("CREATE TABLE sessions (id INT) WITH TTL;").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_parse_partition_by_hash() {
+        let tokens = tokenize("CREATE TABLE events (id INT PRIMARY KEY, v TEXT) PARTITION BY HASH(id) PARTITIONS 8 WITH (memtable_size = 4096);").unwrap();
+        let SQLStatement::CreateTable(stmt) = parse_sql(tokens).unwrap() else {
+            panic!("expected CREATE TABLE");
+        };
+        assert_eq!(stmt.partition_by, Some(PartitionBy { column: "id".to_string(), partitions: 8, split_at: Vec::new() }));
+        assert_eq!(stmt.options.len(), 1);
+
+        let tokens = tokenize("CREATE TABLE events (id INT PRIMARY KEY) PARTITION BY RANGE(id) SPLIT AT (100, 200);").unwrap();
+        let SQLStatement::CreateTable(stmt) = parse_sql(tokens).unwrap() else {
+            panic!("expected CREATE TABLE");
+        };
+        let split_at = vec!["100".to_string(), "200".to_string()];
+        assert_eq!(stmt.partition_by, Some(PartitionBy { column: "id".to_string(), partitions: 3, split_at }));
+
+        for sql in [
+            "CREATE TABLE events (id INT) PARTITION BY HASH(id) PARTITIONS 0;",
+            "CREATE TABLE events (id INT) PARTITION BY HASH(id) PARTITIONS 2.5;",
+            "CREATE TABLE events (id INT) PARTITION BY RANGE(id) PARTITIONS 2;",
+            "CREATE TABLE events (id INT) PARTITION BY RANGE(id) SPLIT AT ();",
+        ] {
+            assert!(parse_sql(tokenize(sql).unwrap()).is_err(), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn test_parse_errors_have_spans() {
+        let sql = "SELECT name FROM;";
+        let error = parse_statement(sql).unwrap_err();
+        assert_eq!(error.span, Some(Span { start: 16, end: 17 }));
+        assert!(error.message.contains("Expected table name"));
+
+        // Running out of tokens points past the end
+        let sql = "SELECT name FROM users WHERE";
+        assert_eq!(parse_statement(sql).unwrap_err().span, Some(Span { start: sql.len(), end: sql.len() }));
+
+        let error = parse_statement("INSERT INTO t (a) VALUES ('x);").unwrap_err();
+        assert_eq!(error.span, Some(Span { start: 26, end: 30 }));
+
+        let tokens = tokenize_spanned("SELECT 'it''s'", Dialect::Generic).unwrap();
+        assert_eq!(tokens[1], (Token::StringLiteral("it's".to_string()), Span { start: 7, end: 14 }));
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_parse_to_json() {
+        let sql = "DELETE FROM users WHERE name = 'Bob';";
+        let json = parse_to_json(sql, Dialect::Generic).unwrap();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["Delete"]["table"], "users");
+        assert_eq!(value["Delete"]["where_clause"]["value"], "Bob");
+
+        // The JSON is the whole tree: it reads back into the same statement
+        let statement: SQLStatement = serde_json::from_str(&json).unwrap();
+        assert_eq!(statement, parse_statement(sql).unwrap());
+
+        assert!(parse_to_json("DELETE users", Dialect::Generic).unwrap_err().span.is_some());
+    }
+
+    #[test]
+    fn test_format_sql() {
+        let formatted = format_sql("select name, count(*) from users left join orders on users.id = orders.user_id \
+                                    where age > '30' group by name having count(*) > '1' order by name desc limit 5;").unwrap();
+        assert_eq!(formatted, "SELECT name, COUNT(*)\n\
+                               FROM users\n  \
+                               LEFT JOIN orders ON users.id = orders.user_id\n\
+                               WHERE age > '30'\n\
+                               GROUP BY name\n\
+                               HAVING COUNT(*) > '1'\n\
+                               ORDER BY name DESC\n\
+                               LIMIT 5;");
+
+        let formatted = format_sql("create table \"order\" (id int primary key, note text) with (ttl = 60)").unwrap();
+        assert_eq!(formatted, "CREATE TABLE \"order\" (\n  id int PRIMARY KEY,\n  note text\n)\nWITH (ttl = 60);");
+
+        // Formatting and Display both write SQL that parses back to the same statement
+        for sql in [
+            "INSERT INTO t (a, b) VALUES ('x', 'it''s'), ('y', 'z')",
+            "UPDATE t SET a = '1' WHERE b BETWEEN '1' AND '3'",
+            "ALTER TABLE t ADD COLUMN \"select\" TEXT",
+            "SELECT * FROM t CROSS JOIN u OFFSET 2",
+            "ROLLBACK TO SAVEPOINT s",
+        ] {
+            let statement = parse_statement(sql).unwrap();
+            assert_eq!(statement.to_string(), sql);
+            assert_eq!(parse_statement(&format_sql(sql).unwrap()).unwrap(), statement);
+        }
+
+        // In the syntax of the dialect
+        let formatted = format_sql_with_dialect("select * from t limit 2 offset 1", Dialect::Standard);
+        assert!(formatted.is_err());
+        let formatted = format_sql_with_dialect("SELECT * FROM t OFFSET 1 ROWS FETCH FIRST 2 ROWS ONLY", Dialect::Standard);
+        assert_eq!(formatted.unwrap(), "SELECT *\nFROM t\nOFFSET 1 ROWS\nFETCH FIRST 2 ROWS ONLY;");
+        let formatted = format_sql_with_dialect("alter table t modify `my col` int", Dialect::MySql);
+        assert_eq!(formatted.unwrap(), "ALTER TABLE t MODIFY COLUMN `my col` int;");
+    }
+
+    fn limits(sql: &str, dialect: Dialect) -> Result<(Option<usize>, Option<usize>), String> {
+        match parse_statement_with_dialect(sql, dialect).map_err(|e| e.message)? {
+            SQLStatement::Select(select) => Ok((select.limit, select.offset)),
+            other => panic!("not a SELECT: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_limit_in_each_dialect() {
+        assert_eq!(limits("SELECT id FROM t LIMIT 10 OFFSET 5;", Dialect::MySql), Ok((Some(10), Some(5))));
+        assert_eq!(limits("SELECT id FROM t LIMIT 5, 10;", Dialect::Sqlite), Ok((Some(10), Some(5))));
+        assert_eq!(limits("SELECT id FROM t OFFSET 5 ROWS FETCH FIRST 10 ROWS ONLY;", Dialect::Standard), Ok((Some(10), Some(5))));
+        assert_eq!(limits("SELECT id FROM t FETCH NEXT ROW ONLY;", Dialect::Generic), Ok((Some(1), None)));
+        assert!(limits("SELECT id FROM t LIMIT 10;", Dialect::Standard).is_err());
+        assert!(limits("SELECT id FROM t FETCH FIRST 10 ROWS ONLY;", Dialect::MySql).is_err());
+        assert!(limits("SELECT id FROM t LIMIT 2.5;", Dialect::Generic).is_err());
+    }
+
+    #[test]
+    fn test_parse_dialect_quoting_and_alter() {
+        let select = |sql, dialect| match parse_statement_with_dialect(sql, dialect) {
+            Ok(SQLStatement::Select(select)) => Ok((select.columns, select.where_clause.map(|w| w.value))),
+            Ok(other) => panic!("not a SELECT: {:?}", other),
+            Err(e) => Err(e.message),
+        };
+        let order = vec![ColumnExpr::Column("order".to_string())];
+        assert_eq!(select("SELECT `order` FROM t WHERE id = \"x\";", Dialect::MySql), Ok((order.clone(), Some("x".to_string()))));
+        assert_eq!(select("SELECT \"order\" FROM t;", Dialect::Standard), Ok((order.clone(), None)));
+        assert_eq!(select("SELECT [order] FROM t;", Dialect::Sqlite), Ok((order, None)));
+        assert!(parse_statement_with_dialect("SELECT `order` FROM t;", Dialect::Standard).is_err());
+
+        let modify = SQLStatement::AlterTable(AlterTableStatement {
+            table: "t".to_string(),
+            action: AlterAction::ModifyColumn("age".to_string(), "INT".to_string()),
+        });
+        assert_eq!(parse_statement_with_dialect("ALTER TABLE t ALTER COLUMN age SET DATA TYPE INT;", Dialect::Standard), Ok(modify.clone()));
+        assert_eq!(parse_statement_with_dialect("ALTER TABLE t MODIFY COLUMN age INT;", Dialect::MySql), Ok(modify));
+        assert!(parse_statement_with_dialect("ALTER TABLE t MODIFY age INT;", Dialect::Standard).is_err());
+        assert!(parse_statement_with_dialect("ALTER TABLE t ALTER COLUMN age TYPE INT;", Dialect::Sqlite).is_err());
+
+        let add = parse_sql(tokenize("ALTER TABLE t ADD COLUMN tag TEXT;").unwrap()).unwrap();
+        assert_eq!(add, SQLStatement::AlterTable(AlterTableStatement {
+            table: "t".to_string(),
+            action: AlterAction::AddColumn("tag".to_string(), Some("TEXT".to_string())),
+        }));
+    }
+
+    #[test]
+    fn test_parse_hints() {
+        let hints = |sql| match parse_statement(sql) {
+            Ok(SQLStatement::Select(select)) => Ok(select.hints),
+            Ok(other) => panic!("not a SELECT: {:?}", other),
+            Err(e) => Err(e.message),
+        };
+        let sql = "SELECT /*+ NO_INDEX(users) JOIN_ORDER(orders, users) PARALLEL(4) */ * FROM users /* all of them */;";
+        assert_eq!(hints(sql), Ok(vec![
+            Hint::NoIndex("users".to_string()),
+            Hint::JoinOrder(vec!["orders".to_string(), "users".to_string()]),
+        ]));
+        assert_eq!(format_sql(sql).unwrap(), "SELECT /*+ NO_INDEX(users) JOIN_ORDER(orders, users) */ *\nFROM users;");
+        // Anywhere else a hint is only a comment
+        assert_eq!(hints("SELECT * FROM users /*+ NO_INDEX(users) */;"), Ok(Vec::new()));
+        assert!(hints("SELECT /*+ NO_INDEX('users') */ * FROM users;").is_err());
+        assert!(hints("SELECT /* never closed FROM users;").is_err());
+    }
+
+    #[test]
+    fn test_parse_explain() {
+        let explain = |sql| match parse_statement(sql) {
+            Ok(SQLStatement::Explain(ExplainStatement { format, select })) => Ok((format, select.table)),
+            Ok(other) => panic!("not an EXPLAIN: {:?}", other),
+            Err(e) => Err(e.message),
+        };
+        assert_eq!(explain("EXPLAIN SELECT * FROM users;"), Ok((ExplainFormat::Text, "users".to_string())));
+        assert_eq!(explain("explain (format json) SELECT * FROM users;"), Ok((ExplainFormat::Json, "users".to_string())));
+        assert_eq!(explain("EXPLAIN DELETE FROM users;"), Err("EXPLAIN is only supported for SELECT".to_string()));
+        assert!(explain("EXPLAIN (FORMAT XML) SELECT * FROM users;").is_err());
+        assert_eq!(format_sql("explain (format json) select id from users where id = '1'").unwrap(),
+                   "EXPLAIN (FORMAT JSON) SELECT id\nFROM users\nWHERE id = '1';");
+    }
+
+    #[test]
+    fn test_parse_type_parameters() {
+        let columns = match parse_sql(tokenize("CREATE TABLE t (price DECIMAL(10, 2), code VARCHAR(8), n INT);").unwrap()) {
+            Ok(SQLStatement::CreateTable(create)) => create.columns,
+            other => panic!("unexpected {:?}", other),
+        };
+        let types: Vec<&str> = columns.iter().map(|(_, data_type)| data_type.as_str()).collect();
+        assert_eq!(types, ["DECIMAL(10,2)", "VARCHAR(8)", "INT"]);
+        assert!(parse_sql(tokenize("CREATE TABLE t (price DECIMAL(10, 'x'));").unwrap()).is_err());
+        assert!(parse_sql(tokenize("CREATE TABLE t (price DECIMAL(10;").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_parse_hex_literals() {
+        assert_eq!(tokenize("x'DEadbe01'").unwrap(), vec![Token::HexLiteral(vec![0xde, 0xad, 0xbe, 0x01])]);
+        assert!(tokenize("X'ABC'").is_err());
+        assert!(tokenize("X'zz'").is_err());
+        let insert = parse_sql(tokenize("INSERT INTO files (name, data) VALUES ('a', X'00ff');").unwrap()).unwrap();
+        match &insert {
+            SQLStatement::Insert(insert) => assert_eq!(insert.values, vec![vec!["a".to_string(), "X'00FF'".to_string()]]),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(insert.to_string(), "INSERT INTO files (name, data) VALUES ('a', X'00FF')");
+        assert_eq!(format_sql("select length(data) from files where data = x''").unwrap(),
+                   "SELECT LENGTH(data)\nFROM files\nWHERE data = X'';");
+    }
+
+    #[test]
+    fn test_parse_booleans() {
+        let where_clause = |sql: &str| match parse_sql(tokenize(sql).unwrap()) {
+            Ok(SQLStatement::Select(select)) => select.where_clause,
+            Ok(SQLStatement::Delete(delete)) => delete.where_clause,
+            other => panic!("unexpected {:?}", other),
+        };
+        let active = Some(WhereClause {
+            column: "active".to_string(),
+            operator: "=".to_string(),
+            value: "true".to_string(),
+            upper_bound: None,
+            in_list: None,
+            function: None,
+        });
+        assert_eq!(where_clause("SELECT * FROM users WHERE active ORDER BY id;"), active);
+        assert_eq!(where_clause("DELETE FROM users WHERE active"), active);
+        assert_eq!(where_clause("SELECT * FROM users WHERE active = True;"), active);
+        match parse_sql(tokenize("INSERT INTO users (id, active) VALUES ('1', FALSE);").unwrap()) {
+            Ok(SQLStatement::Insert(insert)) => assert_eq!(insert.values, vec![vec!["1".to_string(), "false".to_string()]]),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(parse_sql(tokenize("SELECT * FROM users WHERE active = yes;").unwrap()).is_err());
+
+        let inactive = active.clone().map(|clause| WhereClause { value: "false".to_string(), ..clause });
+        assert_eq!(where_clause("SELECT * FROM users WHERE NOT active;"), inactive);
+        assert!(parse_sql(tokenize("SELECT * FROM users WHERE NOT active = 'x';").unwrap()).is_err());
+        // Only the end of the condition makes a column a boolean test
+        for sql in ["DELETE FROM users WHERE name != 'a';", "DELETE FROM users WHERE name <> 'a';",
+                    "SELECT * FROM users WHERE name LIKE 'a%';"] {
+            assert!(parse_sql(tokenize(sql).unwrap()).is_err(), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn test_parse_qualified_aggregate_arguments() {
+        let sql = "SELECT users.name, SUM(orders.amount) FROM users JOIN orders ON users.id = orders.user_id \
+                   GROUP BY users.name HAVING COUNT(orders.id) > '1';";
+        match parse_sql(tokenize(sql).unwrap()) {
+            Ok(SQLStatement::Select(select)) => {
+                assert_eq!(select.columns[1], ColumnExpr::Sum("orders.amount".to_string()));
+                assert_eq!(select.having.unwrap().column_expr, ColumnExpr::Count("orders.id".to_string()));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_join_conditions() {
+        let sql = "SELECT * FROM events JOIN shifts ON events.ts > shifts.starts AND events.ts < shifts.ends";
+        let condition = |left: &str, operator: &str, right: &str| JoinCondition {
+            left: left.to_string(),
+            operator: operator.to_string(),
+            right: right.to_string(),
+        };
+        match parse_statement(sql).unwrap() {
+            SQLStatement::Select(select) => assert_eq!(select.join.unwrap().on, [
+                condition("events.ts", ">", "shifts.starts"),
+                condition("events.ts", "<", "shifts.ends"),
+            ]),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(parse_statement(sql).unwrap().to_string(), sql);
+        assert!(parse_statement("SELECT * FROM events JOIN shifts ON events.ts").is_err());
+    }
+
+    #[test]
+    fn test_parse_qualified_columns() {
+        let sql = "SELECT users.name FROM users WHERE users.age > '30' GROUP BY users.name ORDER BY users.name";
+        match parse_statement(sql).unwrap() {
+            SQLStatement::Select(select) => {
+                assert_eq!(select.where_clause.unwrap().column, "users.age");
+                assert_eq!(select.group_by.unwrap(), ["users.name"]);
+                assert_eq!(select.order_by.unwrap().expr, ColumnExpr::Column("users.name".to_string()));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(parse_statement(sql).unwrap().to_string(), sql);
+        assert!(parse_statement("SELECT * FROM users WHERE users. = '1'").is_err());
+    }
+
+    #[test]
+    fn test_parse_table_aliases() {
+        let sql = "SELECT u.name, COUNT(o.id) FROM users AS u JOIN orders o ON u.id = o.user_id WHERE o.total > '5' GROUP BY u.name ORDER BY u.name";
+        let SQLStatement::Select(select) = parse_statement(sql).unwrap() else {
+            panic!("expected SELECT");
+        };
+        assert_eq!(select.columns, [ColumnExpr::Column("users.name".to_string()), ColumnExpr::Count("orders.id".to_string())]);
+        let join = select.join.unwrap();
+        assert_eq!((join.table.as_str(), join.on[0].left.as_str(), join.on[0].right.as_str()), ("orders", "users.id", "orders.user_id"));
+        assert_eq!(select.where_clause.unwrap().column, "orders.total");
+        assert_eq!(select.group_by.unwrap(), ["users.name"]);
+        assert_eq!(select.order_by.unwrap().expr, ColumnExpr::Column("users.name".to_string()));
+
+        // AS OF still reads as a time, after an alias or without one
+        for sql in ["SELECT u.name FROM users u AS OF 1000", "SELECT name FROM users AS OF 1000"] {
+            let SQLStatement::Select(select) = parse_statement(sql).unwrap() else {
+                panic!("expected SELECT");
+            };
+            assert_eq!(select.as_of, Some(1000));
+        }
+
+        for sql in [
+            "SELECT * FROM users u JOIN users v ON u.id = v.manager_id",
+            "SELECT * FROM users u JOIN orders u ON u.id = u.user_id",
+            "UPDATE orders JOIN users u ON orders.user_id = u.id SET orders.status = 'void'",
+            "SELECT * FROM users u v",
+            "SELECT name FROM users WHERE id = '1' extra",
+        ] {
+            assert!(parse_statement(sql).is_err(), "{}", sql);
+        }
+    }
+
+    #[test]
+    fn test_parse_aliases_and_aggregate_order_by() {
+        let sql = "SELECT dept, COUNT(*) AS staff FROM employees GROUP BY dept ORDER BY COUNT(*) DESC";
+        match parse_statement(sql).unwrap() {
+            SQLStatement::Select(select) => {
+                assert_eq!(select.columns[1], ColumnExpr::Aliased(Box::new(ColumnExpr::CountAll), "staff".to_string()));
+                let order_by = select.order_by.unwrap();
+                assert_eq!((order_by.expr, order_by.descending), (ColumnExpr::CountAll, true));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(parse_statement(sql).unwrap().to_string(), sql);
+        let sql = "SELECT name AS n, SUM(salary) AS total FROM employees GROUP BY name ORDER BY total";
+        assert_eq!(parse_statement(sql).unwrap().to_string(), sql);
+
+        assert!(parse_statement("SELECT name, AS n FROM employees").is_err());
+        assert!(parse_statement("SELECT * AS everything FROM employees").is_err());
+        assert!(parse_statement("SELECT name FROM employees ORDER BY UPPER(name)").is_err());
+    }
+
+    #[test]
+    fn test_parse_having_on_alias() {
+        let sql = "SELECT dept, COUNT(*) AS c FROM employees GROUP BY dept HAVING c > '5'";
+        match parse_statement(sql).unwrap() {
+            SQLStatement::Select(select) => {
+                assert_eq!(select.having.unwrap().column_expr, ColumnExpr::Column("c".to_string()));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(parse_statement(sql).unwrap().to_string(), sql);
+        assert!(parse_statement("SELECT dept FROM employees GROUP BY dept HAVING UPPER(dept) = 'A'").is_err());
+    }
+
+    #[test]
+    fn test_parse_multi_table_writes() {
+        let sql = "DELETE FROM orders WHERE user_id IN (SELECT id FROM users WHERE banned = 'yes')";
+        match parse_statement(sql).unwrap() {
+            SQLStatement::Delete(delete) => {
+                let where_clause = delete.where_clause.unwrap();
+                assert_eq!(where_clause.operator, "IN");
+                match where_clause.in_list {
+                    Some(InList::Subquery(query)) => assert_eq!(query.table, "users"),
+                    other => panic!("unexpected {:?}", other),
+                }
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(parse_statement(sql).unwrap().to_string(), sql);
+
+        for sql in [
+            "UPDATE orders JOIN users ON orders.user_id = users.id SET orders.status = 'void' WHERE users.banned = 'yes'",
+            "DELETE orders FROM orders JOIN users ON orders.user_id = users.id WHERE users.banned = 'yes'",
+            "SELECT * FROM users WHERE name IN ('a', 'b')",
+        ] {
+            assert_eq!(parse_statement(sql).unwrap().to_string(), sql);
+        }
+        assert!(parse_statement("DELETE users FROM orders JOIN users ON orders.user_id = users.id").is_err());
+        assert!(parse_statement("DELETE FROM orders WHERE user_id IN (SELECT id FROM users").is_err());
+    }
+
+    #[test]
+    fn test_parse_insert_or_ignore_and_replace() {
+        match parse_statement("INSERT OR IGNORE INTO users (id) VALUES ('1')").unwrap() {
+            SQLStatement::Insert(insert) => assert_eq!(insert.on_conflict, Some(ConflictAction::Ignore)),
+            other => panic!("unexpected {:?}", other),
+        }
+        for sql in ["INSERT OR IGNORE INTO users (id) VALUES ('1')", "INSERT OR REPLACE INTO users VALUES ('1', 'a')"] {
+            assert_eq!(parse_statement(sql).unwrap().to_string(), sql);
+        }
+        assert!(parse_statement("INSERT OR UPDATE INTO users VALUES ('1')").is_err());
+    }
+
+    #[test]
+    fn test_parse_select_as_of() {
+        let sql = "SELECT name FROM users AS OF 1700000000000 WHERE id = '1';";
+        let stmt = parse_sql(tokenize(sql).unwrap()).unwrap();
+        let SQLStatement::Select(select) = &stmt else { panic!("expected a SELECT") };
+        assert_eq!(select.as_of, Some(1_700_000_000_000));
+        assert_eq!(select.where_clause.as_ref().unwrap().column, "id");
+        assert_eq!(stmt.to_string(), "SELECT name FROM users AS OF 1700000000000 WHERE id = '1'");
+        assert_eq!(parse_sql(tokenize(&stmt.to_string()).unwrap()).unwrap(), stmt);
+
+        assert!(parse_sql(tokenize("SELECT * FROM users AS OF 'yesterday';").unwrap()).is_err());
+        assert!(parse_sql(tokenize("SELECT * FROM users AS OF;").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_parse_quoted_identifiers() {
+        // Quoted, a keyword is a name
+        let sql = r#"SELECT "as", "first name" FROM "order" WHERE "primary" = 'x';"#;
+        let stmt = parse_sql(tokenize(sql).unwrap()).unwrap();
+        let SQLStatement::Select(select) = &stmt else { panic!("expected a SELECT") };
+        assert_eq!(select.columns, vec![ColumnExpr::Column("as".to_string()), ColumnExpr::Column("first name".to_string())]);
+        assert_eq!(select.table, "order");
+        assert_eq!(stmt.to_string(), r#"SELECT "as", "first name" FROM "order" WHERE "primary" = 'x'"#);
+        assert_eq!(parse_sql(tokenize(&stmt.to_string()).unwrap()).unwrap(), stmt);
+
+        let sql = r#"CREATE TABLE t ("primary" INT PRIMARY KEY, "key" TEXT);"#;
+        let stmt = parse_sql(tokenize(sql).unwrap()).unwrap();
+        assert_eq!(parse_sql(tokenize(&stmt.to_string()).unwrap()).unwrap(), stmt);
+        assert!(parse_sql(tokenize("SELECT as, b FROM t;").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_parse_math_functions() {
+        let sql = "SELECT ABS(balance), round(price, 2) AS price FROM items WHERE MOD(id, 2) = '0' ORDER BY FLOOR(price);";
+        let stmt = parse_sql(tokenize(sql).unwrap()).unwrap();
+        let SQLStatement::Select(select) = &stmt else { panic!("expected a SELECT") };
+        let round = FunctionCall { function: MathFunction::Round, column: "price".to_string(), arg: Some("2".to_string()) };
+        assert_eq!(select.columns[1], ColumnExpr::Aliased(Box::new(ColumnExpr::Math(round)), "price".to_string()));
+        let where_clause = select.where_clause.as_ref().unwrap();
+        assert_eq!(where_clause.column, "id");
+        assert_eq!(where_clause.function.as_ref().map(FunctionCall::name), Some("MOD(id, 2)".to_string()));
+        assert_eq!(stmt.to_string(), "SELECT ABS(balance), ROUND(price, 2) AS price FROM items WHERE MOD(id, 2) = '0' ORDER BY FLOOR(price)");
+        assert_eq!(parse_sql(tokenize(&stmt.to_string()).unwrap()).unwrap(), stmt);
+
+        let stmt = parse_sql(tokenize("UPDATE items SET price = CEILING(price), stock = '1';").unwrap()).unwrap();
+        let SQLStatement::Update(update) = &stmt else { panic!("expected an UPDATE") };
+        let ceil = FunctionCall { function: MathFunction::Ceil, column: "price".to_string(), arg: None };
+        assert_eq!(update.assignments[0].1, SetValue::Math(ceil));
+        assert_eq!(stmt.to_string(), "UPDATE items SET price = CEIL(price), stock = '1'");
+
+        assert!(parse_sql(tokenize("SELECT MOD(id) FROM items;").unwrap()).is_err());
+        assert!(parse_sql(tokenize("SELECT ABS(id, 2) FROM items;").unwrap()).is_err());
+        assert!(parse_sql(tokenize("SELECT ROUND(price, 1.5) FROM items;").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_parse_parenthesized_join() {
+        let sql = "SELECT * FROM a LEFT JOIN (b JOIN (c JOIN d ON c.id = d.id) ON b.c_id = c.id) ON a.b_id = b.id;";
+        let stmt = parse_sql(tokenize(sql).unwrap()).unwrap();
+        let SQLStatement::Select(select) = &stmt else { panic!("expected a SELECT") };
+        let join = select.join.as_ref().unwrap();
+        assert_eq!(join.tables(), ["b", "c", "d"]);
+        assert_eq!(join.on, [JoinCondition { left: "a.b_id".to_string(), operator: "=".to_string(), right: "b.id".to_string() }]);
+        assert_eq!(stmt.to_string(), sql.trim_end_matches(';'));
+        assert_eq!(parse_sql(tokenize(&stmt.to_string()).unwrap()).unwrap(), stmt);
+        assert_eq!(format_sql(sql).unwrap(), "\
+SELECT *
+FROM a
+  LEFT JOIN (b JOIN (c JOIN d ON c.id = d.id) ON b.c_id = c.id) ON a.b_id = b.id;");
+
+        assert!(parse_sql(tokenize("SELECT * FROM a JOIN (b) ON a.id = b.id;").unwrap()).is_err());
+        assert!(parse_sql(tokenize("SELECT * FROM a JOIN (b JOIN c ON b.id = c.id ON a.id = b.id;").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_parse_table_function_and_analyze() {
+        let sql = "SELECT column_name, null_count FROM STATS('users') WHERE null_count > '0';";
+        let stmt = parse_sql(tokenize(sql).unwrap()).unwrap();
+        let SQLStatement::Select(select) = &stmt else { panic!("expected a SELECT") };
+        assert_eq!(select.table, "stats");
+        assert_eq!(select.table_function, Some(TableFunction::Stats("users".to_string())));
+        assert_eq!(stmt.to_string(), "SELECT column_name, null_count FROM stats('users') WHERE null_count > '0'");
+        assert_eq!(parse_sql(tokenize(&stmt.to_string()).unwrap()).unwrap(), stmt);
+
+        assert_eq!(parse_sql(tokenize("ANALYZE users;").unwrap()).unwrap(), SQLStatement::Analyze(Some("users".to_string())));
+        assert_eq!(parse_sql(tokenize("ANALYZE;").unwrap()).unwrap().to_string(), "ANALYZE");
+
+        assert!(parse_sql(tokenize("SELECT * FROM stats();").unwrap()).is_err());
+        assert!(parse_sql(tokenize("SELECT * FROM stats(users);").unwrap()).is_err());
+        assert!(parse_sql(tokenize("SELECT * FROM histogram('users');").unwrap()).is_err());
+        assert!(parse_sql(tokenize("SELECT * FROM stats('users') JOIN users ON stats.column_name = users.name;").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_parse_session_variables() {
+        let stmt = parse_sql(tokenize("SET search_path TO public;").unwrap()).unwrap();
+        assert_eq!(stmt, SQLStatement::SetVariable("search_path".to_string(), "public".to_string()));
+        assert_eq!(stmt.to_string(), "SET search_path = 'public'");
+        assert_eq!(parse_sql(tokenize(&stmt.to_string()).unwrap()).unwrap(), stmt);
+        assert_eq!(parse_sql(tokenize("SET page_size = 50;").unwrap()).unwrap(),
+                   SQLStatement::SetVariable("page_size".to_string(), "50".to_string()));
+
+        let stmt = parse_sql(tokenize("SHOW session_user;").unwrap()).unwrap();
+        assert_eq!(stmt, SQLStatement::Show(ShowStatement::Variable("session_user".to_string())));
+        assert_eq!(stmt.to_string(), "SHOW session_user");
+
+        assert!(parse_sql(tokenize("SET page_size;").unwrap()).is_err());
+        assert!(parse_sql(tokenize("SET = 1;").unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_parse_typed_values_and_insert_streams() {
+        let sql = "INSERT INTO t (a, b, c, d) VALUES (1, -2.5, NULL, 'x'), (3, 4, null, TRUE);";
+        let stmt = parse_sql(tokenize(sql).unwrap()).unwrap();
+        let SQLStatement::Insert(insert) = &stmt else { panic!("expected an INSERT") };
+        assert_eq!(insert.values, vec![vec!["1", "-2.5", NULL, "x"], vec!["3", "4", NULL, "true"]]);
+        assert_eq!(parse_insert_stream(sql).unwrap().into_statement().unwrap(), *insert);
+        assert!(parse_sql(tokenize("INSERT INTO t (a) VALUES (-'1');").unwrap()).is_err());
+
+        let tuples: Vec<String> = (0..10_000).map(|i| format!("({}, 'v{}')", i, i)).collect();
+        let sql = format!("INSERT OR IGNORE INTO t (a, b) VALUES {}", tuples.join(", "));
+        let mut stream = parse_insert_stream(&sql).unwrap();
+        assert_eq!((stream.table.as_str(), stream.on_conflict), ("t", Some(ConflictAction::Ignore)));
+        assert_eq!(stream.next(), Some(Ok(vec!["0".to_string(), "v0".to_string()])));
+        assert_eq!(stream.by_ref().count(), 9_999);
+        assert_eq!(stream.next(), None);
+
+        let mut stream = parse_insert_stream("INSERT INTO t (a) VALUES ('1'), (2 3), ('4');").unwrap();
+        assert!(stream.next().unwrap().is_ok());
+        assert!(stream.next().unwrap().is_ok());
+        let mut stream = parse_insert_stream("INSERT INTO t (a) VALUES ('1'), (;").unwrap();
+        assert!(stream.nth(1).unwrap().is_err());
+        assert!(stream.next().is_none());
+        assert!(parse_insert_stream("INSERT INTO t (a) ('1');").is_err());
+    }
+
+    #[test]
+    fn test_parse_returning() {
+        let sql = "UPDATE jobs SET status = 'running' WHERE status = 'queued' RETURNING id, old.status, new.*;";
+        let stmt = parse_sql(tokenize(sql).unwrap()).unwrap();
+        let SQLStatement::Update(update) = &stmt else { panic!("expected an UPDATE") };
+        assert_eq!(update.returning, Some(vec!["id".to_string(), "old.status".to_string(), "new.*".to_string()]));
+        assert_eq!(stmt.to_string(), sql.trim_end_matches(';'));
+        assert_eq!(parse_sql(tokenize(&stmt.to_string()).unwrap()).unwrap(), stmt);
+
+        let stmt = parse_sql(tokenize("DELETE FROM jobs RETURNING *;").unwrap()).unwrap();
+        let SQLStatement::Delete(delete) = &stmt else { panic!("expected a DELETE") };
+        assert_eq!(delete.returning, Some(vec!["*".to_string()]));
+        assert_eq!(stmt.to_string(), "DELETE FROM jobs RETURNING *");
+
+        assert!(parse_sql(tokenize("DELETE FROM jobs RETURNING;").unwrap()).is_err());
+        assert!(parse_sql(tokenize("DELETE FROM jobs RETURNING old.;").unwrap()).is_err());
+    }
 }
@@ -0,0 +1,1815 @@
+#![cfg(feature = "storage")]
+
+use rust_sql_parser::ast::{SQLStatement, SetValue, UpdateStatement, NULL};
+use rust_sql_parser::blob::Blob;
+use rust_sql_parser::changes::{ChangeEvent, ChangeKind, RowChange};
+use rust_sql_parser::migrations::{data_format_version, DATA_FORMAT_VERSION, VERSION_FILE};
+use rust_sql_parser::output::{ColumnMeta, OutputFormat, OutputSettings, RowSink, StatementResult, ValueType};
+use rust_sql_parser::parser::{parse_insert_stream, parse_sql};
+use rust_sql_parser::persistent_executor::{DatabaseOptions, PersistentDatabase};
+use rust_sql_parser::script::OnError;
+use rust_sql_parser::storage::{LSMStorage, StorageConfig, WAL};
+use rust_sql_parser::tokenizer::tokenize;
+use std::fs;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+mod common;
+use common::TempDir;
+
+fn run(db: &PersistentDatabase, sql: &str) -> Result<String, String> {
+    db.execute(parse_sql(tokenize(sql)?)?).map(|result| result.to_string())
+}
+
+fn setup_users(db: &PersistentDatabase) {
+    run(db, "CREATE TABLE users (id INT, name TEXT, age INT);").unwrap();
+    run(db, "INSERT INTO users (id, name, age) VALUES ('1', 'alice', '30');").unwrap();
+    run(db, "INSERT INTO users (id, name, age) VALUES ('2', 'bob', '25');").unwrap();
+}
+
+#[test]
+fn test_rollback_to_savepoint() {
+    let dir = TempDir::new("savepoint");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    setup_users(&db);
+
+    run(&db, "BEGIN;").unwrap();
+    run(&db, "INSERT INTO users (id, name, age) VALUES ('3', 'carol', '41');").unwrap();
+    run(&db, "SAVEPOINT sp1;").unwrap();
+    run(&db, "DELETE FROM users WHERE name = 'alice';").unwrap();
+    assert!(!run(&db, "SELECT name FROM users;").unwrap().contains("alice"));
+    run(&db, "ROLLBACK TO SAVEPOINT sp1;").unwrap();
+    run(&db, "COMMIT;").unwrap();
+
+    let result = run(&db, "SELECT name FROM users;").unwrap();
+    assert!(result.contains("alice"));
+    assert!(result.contains("carol"));
+}
+
+#[test]
+fn test_rollback_discards_uncommitted_writes() {
+    let dir = TempDir::new("rollback");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    setup_users(&db);
+
+    run(&db, "BEGIN;").unwrap();
+    run(&db, "UPDATE users SET age = '99' WHERE name = 'bob';").unwrap();
+    assert!(run(&db, "SELECT age FROM users WHERE name = 'bob';").unwrap().contains("99"));
+    run(&db, "ROLLBACK;").unwrap();
+
+    let result = run(&db, "SELECT age FROM users WHERE name = 'bob';").unwrap();
+    assert!(result.contains("25"));
+    assert!(run(&db, "ROLLBACK TO SAVEPOINT sp1;").is_err());
+}
+
+#[test]
+fn test_commit_spans_tables_and_survives_reopen() {
+    let dir = TempDir::new("commit_reopen");
+    {
+        let db = PersistentDatabase::new(dir.path()).unwrap();
+        setup_users(&db);
+        run(&db, "CREATE TABLE orders (id INT, user_id INT);").unwrap();
+
+        run(&db, "BEGIN;").unwrap();
+        run(&db, "INSERT INTO users (id, name, age) VALUES ('3', 'carol', '41');").unwrap();
+        run(&db, "INSERT INTO orders (id, user_id) VALUES ('100', '3');").unwrap();
+        run(&db, "COMMIT;").unwrap();
+    }
+
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    assert!(run(&db, "SELECT name FROM users;").unwrap().contains("carol"));
+    assert!(run(&db, "SELECT id FROM orders;").unwrap().contains("100"));
+    assert!(!dir.as_path().join("commit.log").exists());
+}
+
+#[test]
+fn test_interrupted_commit_is_redone_and_torn_commit_dropped() {
+    let dir = TempDir::new("commit_recovery");
+    {
+        let db = PersistentDatabase::new(dir.path()).unwrap();
+        setup_users(&db);
+    }
+
+    // A complete commit record followed by one torn mid-write
+    let complete = r#"[{"table":"users","key":"row_9","value":"{\"id\":\"9\",\"name\":\"zed\",\"age\":\"50\"}"}]"#;
+    let torn = r#"[{"table":"users","key":"row_10","value":"{\"id\":\"10\",\"na"#;
+    fs::write(dir.as_path().join("commit.log"), format!("{}\n{}", complete, torn)).unwrap();
+
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    let result = run(&db, "SELECT name FROM users;").unwrap();
+    assert!(result.contains("zed"));
+    assert!(!result.contains("10"));
+    assert!(!dir.as_path().join("commit.log").exists());
+}
+
+#[test]
+fn test_database_is_send_and_sync() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<PersistentDatabase>();
+}
+
+#[test]
+fn test_concurrent_reads_and_writes() {
+    let dir = TempDir::new("concurrent");
+    let db = Arc::new(PersistentDatabase::new(dir.path()).unwrap());
+    setup_users(&db);
+
+    let handles: Vec<_> = (0..4).map(|t| {
+        let db = Arc::clone(&db);
+        thread::spawn(move || {
+            for i in 0..10 {
+                let sql = format!("INSERT INTO users (id, name, age) VALUES ('{}', 'user{}', '20');", t * 100 + i, t);
+                run(&db, &sql).unwrap();
+                assert!(run(&db, "SELECT name FROM users;").unwrap().contains("alice"));
+            }
+        })
+    }).collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    let result = run(&db, "SELECT COUNT(*) FROM users;").unwrap();
+    assert!(result.contains("42"));
+}
+
+#[cfg(feature = "tokio")]
+#[tokio::test]
+async fn test_execute_async() {
+    let dir = TempDir::new("async");
+    let db = PersistentDatabase::new_async(dir.path()).await.unwrap();
+
+    for sql in [
+        "CREATE TABLE users (id INT, name TEXT);",
+        "INSERT INTO users (id, name) VALUES ('1', 'alice');",
+    ] {
+        db.execute_async(parse_sql(tokenize(sql).unwrap()).unwrap()).await.unwrap();
+    }
+    let select = parse_sql(tokenize("SELECT name FROM users;").unwrap()).unwrap();
+    assert!(db.execute_async(select).await.unwrap().to_string().contains("alice"));
+    db.close_async().await.unwrap();
+}
+
+#[test]
+fn test_insert_many_writes_one_wal_record() {
+    let dir = TempDir::new("insert_many");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE items (id INT, label TEXT);").unwrap();
+
+    let columns = vec!["id".to_string(), "label".to_string()];
+    let rows: Vec<Vec<String>> = (0..500)
+        .map(|i| vec![i.to_string(), format!("item{}", i)])
+        .collect();
+    assert_eq!(db.insert_many("items", &columns, rows).unwrap(), 500);
+
+    assert!(run(&db, "SELECT COUNT(*) FROM items;").unwrap().contains("500"));
+    let wal = WAL::new(&dir.as_path().join("items")).unwrap();
+    assert_eq!(wal.replay_batches().unwrap().len(), 1);
+}
+
+#[test]
+fn test_streamed_insert_of_many_tuples_writes_one_wal_record() {
+    let dir = TempDir::new("insert_stream");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE items (id INT PRIMARY KEY, label TEXT, price FLOAT);").unwrap();
+
+    let tuples: Vec<String> = (0..10_000).map(|i| format!("({}, 'item{}', {}.25)", i, i, i % 10)).collect();
+    let sql = format!("INSERT INTO items (id, label, price) VALUES {};", tuples.join(", "));
+    let insert = parse_insert_stream(&sql).unwrap();
+    assert_eq!(insert.table, "items");
+    assert_eq!(db.execute_insert_stream(insert).unwrap().to_string(), "10000 row(s) inserted successfully");
+    assert!(run(&db, "SELECT COUNT(*) FROM items;").unwrap().contains("10000"));
+    assert!(run(&db, "SELECT label FROM items WHERE id = '9999';").unwrap().contains("item9999"));
+    let wal = WAL::new(&dir.as_path().join("items")).unwrap();
+    assert_eq!(wal.replay_batches().unwrap().len(), 1);
+
+    // A tuple that doesn't parse, however late, inserts none of the rows
+    let sql = format!("INSERT INTO items (id, label, price) VALUES {}, (20000, oops, 1);",
+                      (10_000..20_000).map(|i| format!("({}, 'item{}', 1)", i, i)).collect::<Vec<_>>().join(", "));
+    let err = db.execute_insert_stream(parse_insert_stream(&sql).unwrap()).unwrap_err();
+    assert!(err.contains("Unexpected token in VALUES tuple"), "{}", err);
+    assert!(run(&db, "SELECT COUNT(*) FROM items;").unwrap().contains("10000"));
+
+    // Scripts run their INSERTs as streams
+    let script = "INSERT INTO items (id, label, price) VALUES (-1, 'minus', -0.5), (-2, NULL, 0);\nSELECT COUNT(*) FROM items;\n";
+    assert_eq!(db.execute_script(script.as_bytes(), OnError::Stop, |_| {}).unwrap().statements, 2);
+    assert!(run(&db, "SELECT price FROM items WHERE id = '-1';").unwrap().contains("-0.5"));
+    assert!(parse_insert_stream("SELECT * FROM items;").is_err());
+}
+
+#[test]
+fn test_update_and_delete_write_one_wal_record_each() {
+    let dir = TempDir::new("update_batch");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE users (id INT PRIMARY KEY, name TEXT);").unwrap();
+    run(&db, "INSERT INTO users (id, name) VALUES ('1', 'alice'), ('2', 'bob'), ('3', 'carol');").unwrap();
+
+    // Moving primary keys deletes the old keys and writes the new ones together
+    run(&db, "UPDATE users SET id = '4' WHERE name = 'alice';").unwrap();
+    run(&db, "DELETE FROM users WHERE id BETWEEN '2' AND '3';").unwrap();
+
+    let wal = WAL::new(&dir.as_path().join("users")).unwrap();
+    assert_eq!(wal.replay_batches().unwrap().iter().map(Vec::len).collect::<Vec<_>>(), vec![3, 2, 2]);
+    let result = run(&db, "SELECT id, name FROM users;").unwrap();
+    assert!(result.contains("alice") && !result.contains("bob") && !result.contains("carol"), "{}", result);
+}
+
+#[test]
+fn test_backup_is_a_point_in_time_copy() {
+    let dir = TempDir::new("backup_source");
+    let backup_dir = TempDir::new("backup_dest");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE users (id INT PRIMARY KEY, name TEXT);").unwrap();
+    run(&db, "CREATE TABLE unused (id INT);").unwrap();
+    run(&db, "INSERT INTO users (id, name) VALUES ('1', 'alice');").unwrap();
+
+    db.backup(backup_dir.path()).unwrap();
+    run(&db, "INSERT INTO users (id, name) VALUES ('2', 'bob');").unwrap();
+    assert!(db.backup(backup_dir.path()).is_err());
+
+    let restored = PersistentDatabase::new(backup_dir.path()).unwrap();
+    let result = run(&restored, "SELECT name FROM users;").unwrap();
+    assert!(result.contains("alice") && !result.contains("bob"), "{}", result);
+    assert!(run(&restored, "SELECT id FROM unused;").is_ok());
+}
+
+#[test]
+fn test_incremental_backup_copies_only_new_sstables() {
+    let dir = TempDir::new("incremental_source");
+    let (full, first, second) = (TempDir::new("incremental_full"), TempDir::new("incremental_1"), TempDir::new("incremental_2"));
+    let restore_dir = TempDir::new("incremental_restore");
+    let sstables = |dir: &TempDir| fs::read_dir(format!("{}/users", dir.path())).unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .filter(|name| name.starts_with("sstable_") && name.ends_with(".log"))
+        .collect::<Vec<_>>();
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE users (id INT PRIMARY KEY, name TEXT);").unwrap();
+    run(&db, "INSERT INTO users (id, name) VALUES ('1', 'alice');").unwrap();
+    db.backup(full.path()).unwrap();
+
+    run(&db, "INSERT INTO users (id, name) VALUES ('2', 'bob');").unwrap();
+    db.backup_incremental(first.path(), full.path()).unwrap();
+    let (full_sstables, new_sstables) = (sstables(&full), sstables(&first));
+    assert!(!full_sstables.is_empty() && !new_sstables.is_empty());
+    assert!(new_sstables.iter().all(|name| !full_sstables.contains(name)), "{:?}", new_sstables);
+
+    // Chained from the last incremental backup, nothing new means no SSTables
+    db.backup_incremental(second.path(), &format!("{}/BACKUP_MANIFEST", first.path())).unwrap();
+    assert!(sstables(&second).is_empty());
+    assert!(db.backup_incremental(restore_dir.path(), dir.path()).is_err());
+
+    PersistentDatabase::restore_incremental(&[full.path(), first.path(), second.path()], restore_dir.path()).unwrap();
+    let restored = PersistentDatabase::new(restore_dir.path()).unwrap();
+    let names = restored.query_map("SELECT name FROM users ORDER BY id;", |row| row.get::<String>("name")).unwrap();
+    assert_eq!(names, ["alice", "bob"]);
+}
+
+#[test]
+fn test_restore_to_replays_archived_wal_up_to_timestamp() {
+    let dir = TempDir::new("pitr_source");
+    let restore_dir = TempDir::new("pitr_dest");
+    let now = || SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+    let db = PersistentDatabase::with_options(dir.path(), DatabaseOptions {
+        archive_wal: true,
+        ..DatabaseOptions::default()
+    }).unwrap();
+    run(&db, "CREATE TABLE users (id INT PRIMARY KEY, name TEXT);").unwrap();
+    run(&db, "INSERT INTO users (id, name) VALUES ('1', 'alice');").unwrap();
+    db.close().unwrap();
+    run(&db, "UPDATE users SET name = 'alicia' WHERE id = '1';").unwrap();
+    thread::sleep(Duration::from_millis(5));
+    let before_bob = now();
+    thread::sleep(Duration::from_millis(5));
+    run(&db, "INSERT INTO users (id, name) VALUES ('2', 'bob');").unwrap();
+
+    // The flushed insert comes from the archive, the update from the live WAL
+    PersistentDatabase::restore_to(dir.path(), restore_dir.path(), before_bob).unwrap();
+    let restored = PersistentDatabase::new(restore_dir.path()).unwrap();
+    let result = run(&restored, "SELECT name FROM users;").unwrap();
+    assert!(result.contains("alicia") && !result.contains("bob"), "{}", result);
+}
+
+#[test]
+fn test_select_as_of_reads_past_rows() {
+    let dir = TempDir::new("as_of");
+    let now = || SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+    let db = PersistentDatabase::with_options(dir.path(), DatabaseOptions {
+        archive_wal: true,
+        ..DatabaseOptions::default()
+    }).unwrap();
+    run(&db, "CREATE TABLE users (id INT PRIMARY KEY, name TEXT);").unwrap();
+    run(&db, "CREATE TABLE orders (id INT PRIMARY KEY, user_id INT);").unwrap();
+    run(&db, "INSERT INTO users (id, name) VALUES ('1', 'alice'), ('2', 'bob');").unwrap();
+    run(&db, "INSERT INTO orders (id, user_id) VALUES ('10', '1');").unwrap();
+    db.close().unwrap();
+    thread::sleep(Duration::from_millis(5));
+    let before = now();
+    thread::sleep(Duration::from_millis(5));
+    run(&db, "UPDATE users SET name = 'alicia' WHERE id = '1';").unwrap();
+    run(&db, "DELETE FROM users WHERE id = '2';").unwrap();
+    run(&db, "INSERT INTO orders (id, user_id) VALUES ('11', '1');").unwrap();
+
+    let names = |sql: &str| db.query_map(sql, |row| row.get::<String>("name")).unwrap();
+    assert_eq!(names(&format!("SELECT name FROM users AS OF {} WHERE id = '1';", before)), ["alice"]);
+    assert_eq!(names(&format!("SELECT name FROM users AS OF {} ORDER BY id;", before)), ["alice", "bob"]);
+    assert_eq!(names("SELECT name FROM users ORDER BY id;"), ["alicia"]);
+    // A joined table is read as of the same time
+    let joined = format!("SELECT name FROM users AS OF {} JOIN orders ON users.id = orders.user_id;", before);
+    assert_eq!(names(&joined), ["alice"]);
+    // History begins when the table was created with archiving on
+    let err = run(&db, &format!("SELECT name FROM users AS OF {};", before - 1_000_000)).unwrap_err();
+    assert!(err.contains("history begins at"), "{}", err);
+
+    // A session without archiving discards logs, which ends the history;
+    // the next one with it starts a new history from the rows then
+    db.close().unwrap();
+    drop(db);
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    let err = run(&db, &format!("SELECT name FROM users AS OF {};", now())).unwrap_err();
+    assert!(err.contains("no history"), "{}", err);
+    run(&db, "INSERT INTO users (id, name) VALUES ('3', 'carol');").unwrap();
+    db.close().unwrap();
+    drop(db);
+    let db = PersistentDatabase::with_options(dir.path(), DatabaseOptions {
+        archive_wal: true,
+        ..DatabaseOptions::default()
+    }).unwrap();
+    let err = run(&db, &format!("SELECT name FROM users AS OF {};", before)).unwrap_err();
+    assert!(err.contains("history begins at"), "{}", err);
+    let names = |sql: &str| db.query_map(sql, |row| row.get::<String>("name")).unwrap();
+    assert_eq!(names(&format!("SELECT name FROM users AS OF {} ORDER BY id;", now())), ["alicia", "carol"]);
+
+    let plain = PersistentDatabase::new(TempDir::new("as_of_plain").path()).unwrap();
+    run(&plain, "CREATE TABLE t (id INT);").unwrap();
+    let err = run(&plain, &format!("SELECT * FROM t AS OF {};", now())).unwrap_err();
+    assert!(err.contains("no history"), "{}", err);
+}
+
+#[test]
+fn test_primary_key_point_lookup() {
+    let dir = TempDir::new("pk_lookup");
+    {
+        let db = PersistentDatabase::new(dir.path()).unwrap();
+        run(&db, "CREATE TABLE accounts (id INT PRIMARY KEY, owner TEXT);").unwrap();
+        run(&db, "INSERT INTO accounts (id, owner) VALUES ('1', 'alice'), ('2', 'bob');").unwrap();
+        assert!(run(&db, "INSERT INTO accounts (id, owner) VALUES ('1', 'mallory');").is_err());
+    }
+
+    // The primary key survives a restart and rows are stored under its value
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    let result = run(&db, "SELECT owner FROM accounts WHERE id = '2';").unwrap();
+    assert!(result.contains("bob"));
+    assert!(!result.contains("alice"));
+    assert_eq!(run(&db, "SELECT owner FROM accounts WHERE id = '3';").unwrap(), "No matching rows found");
+
+    run(&db, "UPDATE accounts SET id = '3' WHERE owner = 'bob';").unwrap();
+    assert!(run(&db, "SELECT owner FROM accounts WHERE id = '3';").unwrap().contains("bob"));
+    assert!(run(&db, "UPDATE accounts SET id = '1' WHERE owner = 'bob';").is_err());
+}
+
+#[test]
+fn test_order_by_primary_key_follows_key_order() {
+    let dir = TempDir::new("pk_order_by");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE items (id INT PRIMARY KEY, label TEXT);").unwrap();
+    run(&db, "INSERT INTO items (id, label) VALUES ('9', 'nine'), ('10', 'ten'), ('-1', 'minus');").unwrap();
+
+    // INT keys come back in numeric order, not string order
+    let result = run(&db, "SELECT label FROM items ORDER BY id DESC;").unwrap();
+    let positions: Vec<usize> = ["ten", "nine", "minus"].iter().map(|l| result.find(l).unwrap()).collect();
+    assert!(positions.windows(2).all(|w| w[0] < w[1]), "{}", result);
+
+    run(&db, "BEGIN;").unwrap();
+    run(&db, "INSERT INTO items (id, label) VALUES ('100', 'hundred');").unwrap();
+    let result = run(&db, "SELECT label FROM items WHERE id BETWEEN '9' AND '100' ORDER BY id DESC;").unwrap();
+    assert!(result.find("hundred").unwrap() < result.find("ten").unwrap(), "{}", result);
+    assert!(!result.contains("minus"), "{}", result);
+}
+
+#[test]
+fn test_primary_key_between_uses_key_order() {
+    let dir = TempDir::new("pk_range");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE events (id INT PRIMARY KEY, name TEXT);").unwrap();
+    let columns = vec!["id".to_string(), "name".to_string()];
+    let rows: Vec<Vec<String>> = [-5, 2, 9, 10, 11, 100]
+        .iter()
+        .map(|i| vec![i.to_string(), format!("event{}", i)])
+        .collect();
+    db.insert_many("events", &columns, rows).unwrap();
+
+    // Integer keys compare numerically, so 9 and 10 fall inside the range
+    let result = run(&db, "SELECT name FROM events WHERE id BETWEEN '2' AND '10';").unwrap();
+    for name in ["event2", "event9", "event10"] {
+        assert!(result.contains(name), "missing {} in {}", name, result);
+    }
+    assert!(!result.contains("event11") && !result.contains("event100") && !result.contains("event-5"));
+
+    // Uncommitted writes inside the range are visible to the scan
+    run(&db, "BEGIN;").unwrap();
+    run(&db, "DELETE FROM events WHERE id = '9';").unwrap();
+    run(&db, "INSERT INTO events (id, name) VALUES ('3', 'event3');").unwrap();
+    let result = run(&db, "SELECT name FROM events WHERE id BETWEEN '-10' AND '3';").unwrap();
+    assert!(result.contains("event-5") && result.contains("event2") && result.contains("event3"));
+    assert!(!result.contains("event9"));
+    run(&db, "ROLLBACK;").unwrap();
+}
+
+#[test]
+fn test_alter_table_changes_stored_data() {
+    let dir = TempDir::new("alter_table");
+    {
+        let db = PersistentDatabase::new(dir.path()).unwrap();
+        setup_users(&db);
+        run(&db, "ALTER TABLE users ADD email TEXT;").unwrap();
+        assert!(run(&db, "ALTER TABLE users ADD email TEXT;").is_err());
+        run(&db, "INSERT INTO users (id, name, age, email) VALUES ('3', 'carol', '41', 'carol@example.com');").unwrap();
+    }
+
+    // The new column survives a restart and is NULL in older rows
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    let result = run(&db, "SELECT * FROM users WHERE email IS NULL;").unwrap();
+    assert!(result.starts_with("id | name | age | email"));
+    assert!(result.contains("alice") && !result.contains("carol"));
+
+    // MODIFY rejects values it can't convert and leaves the table as it was
+    assert!(run(&db, "ALTER TABLE users MODIFY email INT;").is_err());
+    run(&db, "ALTER TABLE users MODIFY age INT;").unwrap();
+
+    run(&db, "ALTER TABLE users DROP age;").unwrap();
+    assert!(run(&db, "SELECT age FROM users;").unwrap().lines().skip(2).all(|l| l == "NULL"));
+    run(&db, "ALTER TABLE users ADD age INT;").unwrap();
+    assert!(!run(&db, "SELECT age FROM users;").unwrap().contains("41"));
+
+    run(&db, "BEGIN;").unwrap();
+    assert!(run(&db, "ALTER TABLE users DROP email;").is_err());
+}
+
+#[test]
+fn test_drop_table_removes_all_directories() {
+    let dir = TempDir::new("drop_table");
+    {
+        let db = PersistentDatabase::new(dir.path()).unwrap();
+        setup_users(&db);
+        run(&db, "DROP TABLE users;").unwrap();
+        db.close().unwrap();
+    }
+    assert!(!dir.as_path().join("users").exists());
+
+    // A table directory left behind by a drop that crashed after updating the
+    // catalog doesn't leak into a new table of the same name
+    {
+        let mut orders = LSMStorage::new(dir.as_path(), "orders").unwrap();
+        orders.insert("row_1".to_string(), r#"{"id":"1"}"#.to_string()).unwrap();
+    }
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    assert!(run(&db, "SELECT * FROM users;").is_err());
+    assert!(run(&db, "SELECT * FROM orders;").is_err());
+    run(&db, "CREATE TABLE orders (id INT);").unwrap();
+    assert_eq!(run(&db, "SELECT * FROM orders;").unwrap(), "No matching rows found");
+
+    // Re-creating a dropped table starts out empty
+    setup_users(&db);
+    assert_eq!(run(&db, "SELECT COUNT(*) FROM users;").unwrap().lines().nth(2), Some("2"));
+}
+
+#[test]
+fn test_tables_are_opened_on_first_use() {
+    let dir = TempDir::new("lazy_open");
+    {
+        let db = PersistentDatabase::new(dir.path()).unwrap();
+        setup_users(&db);
+        run(&db, "CREATE TABLE orders (id INT);").unwrap();
+    }
+
+    // A table whose storage can't be opened only fails once it's used
+    fs::remove_dir_all(dir.as_path().join("orders")).unwrap();
+    fs::write(dir.as_path().join("orders"), "").unwrap();
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    assert!(run(&db, "SELECT name FROM users;").unwrap().contains("alice"));
+    assert!(run(&db, "SELECT * FROM orders;").unwrap_err().contains("Failed to open table storage"));
+}
+
+#[test]
+fn test_legacy_schema_directories_are_migrated() {
+    let dir = TempDir::new("catalog_migration");
+    {
+        let mut schema = LSMStorage::new(dir.as_path(), "users_schema").unwrap();
+        schema.insert("schema".to_string(), r#"["id","name"]"#.to_string()).unwrap();
+        let mut users = LSMStorage::new(dir.as_path(), "users").unwrap();
+        users.insert("row_1".to_string(), r#"{"id":"1","name":"alice"}"#.to_string()).unwrap();
+    }
+
+    // An unversioned directory is read-only only after it's been upgraded
+    assert!(PersistentDatabase::open_read_only(dir.path()).is_err());
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    assert!(!dir.as_path().join("users_schema").exists());
+    assert_eq!(data_format_version(dir.as_path()), Ok(Some(DATA_FORMAT_VERSION)));
+    assert!(run(&db, "SELECT * FROM users;").unwrap().contains("1 | alice"));
+    drop(db);
+
+    // The catalog alone is enough to find the table again
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    assert!(run(&db, "SELECT name FROM users;").unwrap().contains("alice"));
+}
+
+#[test]
+fn test_show_storage_stats_lists_open_tables() {
+    let dir = TempDir::new("storage_stats");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    setup_users(&db);
+    run(&db, "SELECT name FROM users;").unwrap();
+
+    let stats = db.storage_stats().unwrap();
+    assert!(stats["users"].wal_bytes_written > 0);
+
+    let result = run(&db, "SHOW STORAGE STATS;").unwrap();
+    let lines: Vec<&str> = result.lines().collect();
+    assert!(lines[0].starts_with("table | wal_bytes"));
+    assert!(lines[2].starts_with(&format!("users | {} |", stats["users"].wal_bytes_written)));
+    assert!(lines[3].starts_with("total |"));
+    assert!(run(&db, "SHOW TABLES;").is_err());
+}
+
+#[test]
+fn test_show_table_status() {
+    let dir = TempDir::new("table_status");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    // Keyed, so the bloom filters and their estimate are the same every run
+    run(&db, "CREATE TABLE users (id INT PRIMARY KEY, name TEXT, age INT);").unwrap();
+    run(&db, "CREATE TABLE empty (id INT);").unwrap();
+    for i in 1..40 {
+        run(&db, &format!("INSERT INTO users (id, name, age) VALUES ('{}', 'user{}', '40');", i, i)).unwrap();
+    }
+
+    let status = db.table_status().unwrap();
+    assert_eq!((status["users"].estimated_rows, status["users"].sstables), (39, 0));
+    assert!(status["users"].wal_bytes > 0 && status["users"].disk_bytes >= status["users"].wal_bytes);
+    assert_eq!(status["empty"].estimated_rows, 0);
+    assert!(status["users"].last_compaction.is_none());
+
+    // Flushed and compacted, the rows are estimated from the SSTables' bloom filters
+    db.close().unwrap();
+    db.vacuum(Some("users")).unwrap();
+    let users = &db.table_status().unwrap()["users"];
+    assert!(users.sstables > 0 && users.last_compaction.is_some());
+    assert!((35..=43).contains(&users.estimated_rows), "{}", users.estimated_rows);
+
+    let result = run(&db, "SHOW TABLE STATUS;").unwrap();
+    let lines: Vec<&str> = result.lines().collect();
+    assert_eq!(lines[0], "table | rows | sstables | disk_bytes | wal_bytes | last_compaction");
+    assert!(lines[2].starts_with("empty | 0 | 0 |") && lines[2].ends_with("| NULL"), "{}", lines[2]);
+    assert!(lines[3].starts_with(&format!("users | {} | {} |", users.estimated_rows, users.sstables)));
+}
+
+#[test]
+fn test_table_storage_options_persist_and_apply() {
+    let dir = TempDir::new("storage_options");
+    let options = DatabaseOptions {
+        storage: StorageConfig { memtable_size: 1 << 16, ..StorageConfig::default() },
+        ..DatabaseOptions::default()
+    };
+    {
+        let db = PersistentDatabase::with_options(dir.path(), options.clone()).unwrap();
+        run(&db, "CREATE TABLE small (id INT PRIMARY KEY, v TEXT) WITH (memtable_size = 64);").unwrap();
+        run(&db, "CREATE TABLE plain (id INT PRIMARY KEY, v TEXT);").unwrap();
+        assert!(run(&db, "CREATE TABLE bad (id INT) WITH (block_size = 10);").is_err());
+        assert!(run(&db, "CREATE TABLE bad (id INT) WITH (memtable_size = 0);").is_err());
+        run(&db, "ALTER TABLE plain SET (sstable_size = 4096);").unwrap();
+
+        // The 64-byte memtable is flushed after a couple of rows
+        for id in 0..4 {
+            run(&db, &format!("INSERT INTO small (id, v) VALUES ('{}', 'value');", id)).unwrap();
+        }
+        assert!(db.storage_stats().unwrap()["small"].flushes > 0);
+        db.close().unwrap();
+    }
+
+    // The table's own limit is kept in the catalog; other tables follow the database default
+    let db = PersistentDatabase::with_options(dir.path(), options).unwrap();
+    for id in 4..8 {
+        run(&db, &format!("INSERT INTO small (id, v) VALUES ('{}', 'value');", id)).unwrap();
+        run(&db, &format!("INSERT INTO plain (id, v) VALUES ('{}', 'value');", id)).unwrap();
+    }
+    let stats = db.storage_stats().unwrap();
+    assert!(stats["small"].flushes > 0);
+    assert_eq!(stats["plain"].flushes, 0);
+    assert_eq!(run(&db, "SELECT v FROM small;").unwrap().matches("value").count(), 8);
+}
+
+#[test]
+fn test_table_directory_option_keeps_files_elsewhere() {
+    let dir = TempDir::new("table_directory");
+    let elsewhere = TempDir::new("table_directory_target");
+    let target = elsewhere.as_path().join("events");
+    let create = format!("CREATE TABLE events (id INT PRIMARY KEY, v TEXT) WITH (directory = '{}', memtable_size = 64);", target.display());
+    {
+        let db = PersistentDatabase::new(dir.path()).unwrap();
+        run(&db, &create).unwrap();
+        for id in 0..4 {
+            run(&db, &format!("INSERT INTO events (id, v) VALUES ('{}', 'value');", id)).unwrap();
+        }
+        assert!(run(&db, "ALTER TABLE events SET (directory = '/tmp');").is_err());
+        db.close().unwrap();
+    }
+    assert!(fs::read_dir(&target).unwrap().next().is_some());
+
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    assert_eq!(run(&db, "SELECT v FROM events;").unwrap().matches("value").count(), 4);
+
+    // Another table can't share the directory while it holds files
+    assert!(run(&db, &create.replace("events (", "others (")).is_err());
+
+    run(&db, "DROP TABLE events;").unwrap();
+    assert!(!target.exists());
+    assert!(fs::symlink_metadata(dir.as_path().join("events")).is_err());
+}
+
+#[test]
+fn test_query_each_hands_over_rows_as_they_are_read() {
+    let dir = TempDir::new("query_each");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE items (id INT PRIMARY KEY, name TEXT, qty INT);").unwrap();
+    for id in 0..20 {
+        run(&db, &format!("INSERT INTO items (id, name, qty) VALUES ('{}', 'item{}', '{}');", id, id, id % 3)).unwrap();
+    }
+
+    let parse_select = |sql: &str| match parse_sql(tokenize(sql).unwrap()).unwrap() {
+        SQLStatement::Select(select) => select,
+        stmt => panic!("not a SELECT: {:?}", stmt),
+    };
+    // Streamed or not, the rows are those `query` returns
+    for sql in [
+        "SELECT * FROM items",
+        "SELECT name, qty FROM items WHERE qty = '1' LIMIT 3 OFFSET 2",
+        "SELECT id FROM items ORDER BY id DESC LIMIT 4",
+        "SELECT qty, COUNT(*) FROM items GROUP BY qty ORDER BY qty",
+        "SELECT name FROM items WHERE id = '7'",
+    ] {
+        let stmt = parse_select(sql);
+        let settings = OutputSettings { format: OutputFormat::Csv, ..OutputSettings::default() };
+        let mut output = Vec::new();
+        let mut writer = settings.row_writer(&mut output);
+        let count = db.query_each(&stmt, &mut writer).unwrap();
+        writer.finish().unwrap();
+        let result = db.query(&stmt).unwrap();
+        assert_eq!(count, result.rows.len(), "{}", sql);
+        assert_eq!(String::from_utf8(output).unwrap(), result.render(OutputFormat::Csv), "{}", sql);
+    }
+
+    // A sink that fails stops the scan
+    struct FailAfter(usize);
+    impl RowSink for FailAfter {
+        fn columns(&mut self, _: &[String], _: &[ColumnMeta]) -> std::io::Result<()> {
+            Ok(())
+        }
+        fn row(&mut self, _: &[String]) -> std::io::Result<()> {
+            self.0 = self.0.checked_sub(1).ok_or_else(|| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+            Ok(())
+        }
+    }
+    let examined = Arc::new(std::sync::Mutex::new(0));
+    let sink = examined.clone();
+    db.log_slow_queries(Duration::ZERO, move |query| *sink.lock().unwrap() = query.rows_examined).unwrap();
+    assert!(db.query_each(&parse_select("SELECT * FROM items"), &mut FailAfter(2)).is_err());
+    assert_eq!(*examined.lock().unwrap(), 3);
+}
+
+#[test]
+fn test_last_plan_time() {
+    let dir = TempDir::new("plan_time");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    setup_users(&db);
+    run(&db, "SELECT name FROM users WHERE id = '1';").unwrap();
+    let planned = db.last_plan_time();
+    assert!(planned > Duration::ZERO);
+    // Kept per thread
+    thread::scope(|scope| {
+        scope.spawn(|| assert_eq!(db.last_plan_time(), Duration::ZERO));
+    });
+    assert_eq!(db.last_plan_time(), planned);
+}
+
+#[test]
+fn test_read_only_open_follows_a_writer() {
+    let dir = TempDir::new("read_only");
+    assert!(PersistentDatabase::open_read_only(dir.path()).is_err());
+    assert!(!dir.as_path().join("_catalog").exists());
+
+    let writer = PersistentDatabase::new(dir.path()).unwrap();
+    setup_users(&writer);
+
+    let reader = PersistentDatabase::open_read_only(dir.path()).unwrap();
+    let result = run(&reader, "SELECT name FROM users;").unwrap();
+    assert!(result.contains("alice") && result.contains("bob"));
+    // Tables are only opened again once the writer has changed them
+    let read = |db: &PersistentDatabase| db.storage_stats().unwrap()["users"].user_bytes_read;
+    let first = read(&reader);
+    run(&reader, "SELECT name FROM users;").unwrap();
+    assert!(read(&reader) > first);
+    for sql in [
+        "INSERT INTO users (id, name, age) VALUES ('3', 'carol', '41');",
+        "DELETE FROM users;",
+        "CREATE TABLE other (id INT);",
+        "BEGIN;",
+    ] {
+        assert_eq!(run(&reader, sql), Err("Database is open read-only".to_string()), "{}", sql);
+    }
+
+    // Later writes, flushed or not, and new tables show up on the next query
+    run(&writer, "INSERT INTO users (id, name, age) VALUES ('3', 'carol', '41');").unwrap();
+    assert!(run(&reader, "SELECT name FROM users;").unwrap().contains("carol"));
+    writer.close().unwrap();
+    run(&writer, "CREATE TABLE other (id INT);").unwrap();
+    assert!(run(&reader, "SELECT id FROM other;").is_ok());
+    assert_eq!(run(&reader, "SELECT name FROM users;").unwrap().matches('\n').count(), 5);
+    reader.close().unwrap();
+}
+
+#[test]
+fn test_data_directory_format_version() {
+    let dir = TempDir::new("format_version");
+    drop(PersistentDatabase::new(dir.path()).unwrap());
+    let marker = fs::read_to_string(dir.as_path().join(VERSION_FILE)).unwrap();
+    assert_eq!(marker.trim(), DATA_FORMAT_VERSION.to_string());
+
+    // Written by a newer build: refused rather than misread
+    fs::write(dir.as_path().join(VERSION_FILE), format!("{}\n", DATA_FORMAT_VERSION + 1)).unwrap();
+    assert!(PersistentDatabase::new(dir.path()).unwrap_err().contains("newer"));
+    assert!(PersistentDatabase::open_read_only(dir.path()).is_err());
+}
+
+#[test]
+fn test_hash_partitioned_table() {
+    let dir = TempDir::new("partitioned");
+    {
+        let db = PersistentDatabase::new(dir.path()).unwrap();
+        assert!(run(&db, "CREATE TABLE bad (id INT PRIMARY KEY, v TEXT) PARTITION BY HASH(v) PARTITIONS 4;").is_err());
+        run(&db, "CREATE TABLE events (id INT PRIMARY KEY, v TEXT) PARTITION BY HASH(id) PARTITIONS 4;").unwrap();
+        let rows: Vec<String> = (0..40).map(|id| format!("('{}', 'row{}')", id, id)).collect();
+        run(&db, &format!("INSERT INTO events (id, v) VALUES {};", rows.join(", "))).unwrap();
+        run(&db, "DELETE FROM events WHERE id BETWEEN '10' AND '29';").unwrap();
+        db.close().unwrap();
+    }
+    let table_dir = dir.as_path().join("events");
+    for shard in 0..4 {
+        assert!(table_dir.join(format!("shard_{}", shard)).is_dir());
+    }
+    assert!(!table_dir.join("batch.log").exists());
+
+    // Rows from every shard come back in key order
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    let result = run(&db, "SELECT v FROM events ORDER BY id DESC;").unwrap();
+    assert_eq!(result.matches("row").count(), 20, "{}", result);
+    let positions: Vec<usize> = ["row39", "row30", "row9", "row0"].iter().map(|v| result.find(v).unwrap()).collect();
+    assert!(positions.windows(2).all(|w| w[0] < w[1]), "{}", result);
+    assert!(run(&db, "SELECT v FROM events WHERE id = '7';").unwrap().contains("row7"));
+    assert!(!run(&db, "SELECT v FROM events WHERE id = '17';").unwrap().contains("row17"));
+
+    // A transaction spanning shards commits as a whole
+    run(&db, "BEGIN;").unwrap();
+    run(&db, "UPDATE events SET v = 'changed';").unwrap();
+    run(&db, "COMMIT;").unwrap();
+    assert_eq!(run(&db, "SELECT v FROM events;").unwrap().matches("changed").count(), 20);
+}
+
+#[test]
+fn test_range_partitioned_table() {
+    let dir = TempDir::new("range_partitioned");
+    {
+        let db = PersistentDatabase::new(dir.path()).unwrap();
+        for sql in [
+            "CREATE TABLE bad (id INT PRIMARY KEY) PARTITION BY RANGE(id) SPLIT AT (20, 10);",
+            "CREATE TABLE bad (id INT PRIMARY KEY) PARTITION BY RANGE(id) SPLIT AT (10, 10);",
+            "CREATE TABLE bad (id INT PRIMARY KEY) PARTITION BY RANGE(id) SPLIT AT ('ten');",
+            "CREATE TABLE bad (id INT PRIMARY KEY, v TEXT) PARTITION BY RANGE(v) SPLIT AT ('m');",
+        ] {
+            assert!(run(&db, sql).is_err(), "{}", sql);
+        }
+        run(&db, "CREATE TABLE events (id INT PRIMARY KEY, v TEXT) PARTITION BY RANGE(id) SPLIT AT (0, 10, 20);").unwrap();
+        let rows: Vec<String> = (-5..30).map(|id| format!("('{}', 'row{}')", id, id)).collect();
+        run(&db, &format!("INSERT INTO events (id, v) VALUES {};", rows.join(", "))).unwrap();
+        db.close().unwrap();
+    }
+    for shard in 0..4 {
+        assert!(dir.as_path().join("events").join(format!("shard_{}", shard)).is_dir());
+    }
+
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    let result = run(&db, "SELECT v FROM events ORDER BY id;").unwrap();
+    assert_eq!(result.matches("row").count(), 35, "{}", result);
+    let positions: Vec<usize> = ["row-5", "row-1", "row0", "row9", "row10", "row29"].iter().map(|v| result.find(v).unwrap()).collect();
+    assert!(positions.windows(2).all(|w| w[0] < w[1]), "{}", result);
+    let result = run(&db, "SELECT v FROM events WHERE id BETWEEN '8' AND '11';").unwrap();
+    assert_eq!(result.matches("row").count(), 4, "{}", result);
+    assert!(run(&db, "SELECT v FROM events WHERE id = '-3';").unwrap().contains("row-3"));
+
+    let mut dump = Vec::new();
+    db.dump(&mut dump).unwrap();
+    let dump = String::from_utf8(dump).unwrap();
+    assert!(dump.contains("PARTITION BY RANGE(id) SPLIT AT ('0', '10', '20');"), "{}", dump);
+}
+
+#[test]
+fn test_table_ttl_expires_rows() {
+    let dir = TempDir::new("table_ttl");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE sessions (id INT PRIMARY KEY, user TEXT) WITH TTL = 1;").unwrap();
+    run(&db, "CREATE TABLE kept (id INT PRIMARY KEY, user TEXT);").unwrap();
+    assert!(run(&db, "CREATE TABLE bad (id INT) WITH TTL = 'soon';").is_err());
+    for table in ["sessions", "kept"] {
+        run(&db, &format!("INSERT INTO {} (id, user) VALUES ('1', 'alice');", table)).unwrap();
+    }
+    assert!(run(&db, "SELECT user FROM sessions WHERE id = '1';").unwrap().contains("alice"));
+
+    thread::sleep(Duration::from_millis(1100));
+    assert!(!run(&db, "SELECT user FROM sessions;").unwrap().contains("alice"));
+    assert!(!run(&db, "SELECT user FROM sessions WHERE id = '1';").unwrap().contains("alice"));
+    assert!(run(&db, "SELECT user FROM kept;").unwrap().contains("alice"));
+    // An expired key can be inserted again
+    run(&db, "INSERT INTO sessions (id, user) VALUES ('1', 'bob');").unwrap();
+    assert!(run(&db, "SELECT user FROM sessions;").unwrap().contains("bob"));
+
+    // TTL = 0 turns expiry off
+    run(&db, "ALTER TABLE sessions SET TTL = 0;").unwrap();
+    thread::sleep(Duration::from_millis(1100));
+    assert!(run(&db, "SELECT user FROM sessions;").unwrap().contains("bob"));
+}
+
+#[test]
+fn test_quoted_identifiers() {
+    let dir = TempDir::new("quoted_identifiers");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, r#"CREATE TABLE "order" (id INT PRIMARY KEY, "first name" TEXT, "as" TEXT);"#).unwrap();
+    run(&db, "CREATE TABLE items (id INT PRIMARY KEY, qty INT);").unwrap();
+    run(&db, r#"INSERT INTO "order" (id, "first name", "as") VALUES ('1', 'ann', 'x');"#).unwrap();
+    run(&db, "INSERT INTO items (id, qty) VALUES ('1', '3');").unwrap();
+
+    let names = db.query_map(r#"SELECT "first name" FROM "order" WHERE "as" = 'x';"#,
+                             |row| row.get::<String>("first name")).unwrap();
+    assert_eq!(names, vec!["ann"]);
+    // SELECT * over a JOIN shows both tables' columns, by their qualified names
+    let joined = run(&db, r#"SELECT * FROM "order" JOIN items ON "order".id = items.id;"#).unwrap();
+    assert!(joined.starts_with("order.id | order.first name | order.as | items.id | items.qty\n"), "{}", joined);
+    assert!(joined.contains("1 | ann | x | 1 | 3"), "{}", joined);
+
+    let mut dump = Vec::new();
+    db.dump_table("order", &mut dump).unwrap();
+    let dump = String::from_utf8(dump).unwrap();
+    assert!(dump.starts_with(r#"CREATE TABLE "order" (id INT PRIMARY KEY, "first name" TEXT, "as" TEXT);"#), "{}", dump);
+    let copy_dir = TempDir::new("quoted_identifiers_copy");
+    let copy = PersistentDatabase::new(copy_dir.path()).unwrap();
+    for statement in dump.lines() {
+        run(&copy, statement).unwrap();
+    }
+    let sql = r#"SELECT "first name", "as" FROM "order";"#;
+    assert_eq!(run(&copy, sql).unwrap(), run(&db, sql).unwrap());
+}
+
+#[test]
+fn test_math_functions() {
+    let dir = TempDir::new("math_functions");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE accounts (id INT PRIMARY KEY, balance DECIMAL(10,2), rate REAL);").unwrap();
+    run(&db, "INSERT INTO accounts (id, balance, rate) VALUES ('1', '-12.345', '1.5'), ('2', '7.5', '2'), ('3', '100', '0.5');").unwrap();
+    let values = |sql: &str| db.query_map(sql, |row| row.get::<String>("v")).unwrap();
+
+    assert_eq!(values("SELECT ABS(balance) AS v FROM accounts ORDER BY id;"), ["12.35", "7.50", "100.00"]);
+    assert_eq!(values("SELECT ROUND(balance, 1) AS v FROM accounts ORDER BY id;"), ["-12.4", "7.5", "100.0"]);
+    assert_eq!(values("SELECT MOD(id, 2) AS v FROM accounts ORDER BY id;"), ["1", "0", "1"]);
+    assert_eq!(values("SELECT POWER(rate, 2) AS v FROM accounts ORDER BY id;"), ["2.25", "4", "0.25"]);
+
+    // A DECIMAL's function compares by value, so 12.35 = 12.350
+    assert_eq!(values("SELECT id AS v FROM accounts WHERE ABS(balance) = '12.350';"), ["1"]);
+    assert_eq!(values("SELECT id AS v FROM accounts WHERE FLOOR(balance) < '0';"), ["1"]);
+    assert_eq!(values("SELECT id AS v FROM accounts WHERE MOD(id, 2) = '0';"), ["2"]);
+
+    run(&db, "UPDATE accounts SET balance = ABS(balance), rate = POWER(rate, 2) WHERE balance < '0';").unwrap();
+    assert_eq!(values("SELECT balance AS v FROM accounts WHERE id = '1';"), ["12.35"]);
+    assert_eq!(values("SELECT rate AS v FROM accounts WHERE id = '1';"), ["2.25"]);
+}
+
+#[test]
+fn test_dump_recreates_database() {
+    let dir = TempDir::new("dump_source");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE events (id INT PRIMARY KEY, v TEXT) PARTITION BY HASH(id) PARTITIONS 2 WITH TTL = 3600;").unwrap();
+    let rows: Vec<String> = (0..150).map(|id| format!("('{}', 'row{}')", id, id)).collect();
+    run(&db, &format!("INSERT INTO events (id, v) VALUES {};", rows.join(", "))).unwrap();
+    run(&db, "CREATE TABLE notes (body TEXT, author TEXT);").unwrap();
+    run(&db, "INSERT INTO notes (body, author) VALUES ('it''s done', ''), ('second', 'bob');").unwrap();
+    run(&db, "ALTER TABLE notes ADD COLUMN tag TEXT;").unwrap();
+
+    let mut dump = Vec::new();
+    db.dump(&mut dump).unwrap();
+    let dump = String::from_utf8(dump).unwrap();
+    assert!(dump.contains("CREATE TABLE events (id INT PRIMARY KEY, v TEXT) PARTITION BY HASH(id) PARTITIONS 2 WITH ("), "{}", dump);
+    assert!(dump.contains("ttl = 3600"), "{}", dump);
+    assert!(dump.contains("('it''s done', '', NULL)"), "{}", dump);
+    // 150 rows take two INSERTs
+    assert_eq!(dump.matches("INSERT INTO events").count(), 2);
+
+    let copy_dir = TempDir::new("dump_copy");
+    let copy = PersistentDatabase::new(copy_dir.path()).unwrap();
+    for statement in dump.lines() {
+        run(&copy, statement).unwrap();
+    }
+    for sql in ["SELECT v FROM events ORDER BY id;", "SELECT body, author, tag FROM notes;"] {
+        assert_eq!(run(&copy, sql).unwrap(), run(&db, sql).unwrap(), "{}", sql);
+    }
+
+    let mut notes = Vec::new();
+    db.dump_table("notes", &mut notes).unwrap();
+    let notes = String::from_utf8(notes).unwrap();
+    assert!(notes.starts_with("CREATE TABLE notes") && !notes.contains("events"), "{}", notes);
+    assert!(db.dump_table("missing", Vec::new()).is_err());
+}
+
+#[test]
+fn test_execute_script() {
+    let script = "\
+-- Accounts
+CREATE TABLE accounts (id INT PRIMARY KEY, owner TEXT);
+/* two rows,
+   one statement */
+INSERT INTO accounts (id, owner)
+    VALUES ('1', 'alice; -- not a comment'), ('2', 'bob');
+BEGIN;
+UPDATE accounts SET owner = 'carol' WHERE id = '2';
+INSERT INTO accounts (id, owner) VALUES ('1', 'duplicate');
+COMMIT;
+SELECT owner FROM accounts
+";
+
+    // Fail fast: the statements before the failure stay, the open transaction is rolled back
+    let dir = TempDir::new("script_stop");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    let error = db.execute_script(script.as_bytes(), OnError::Stop, |_| {}).unwrap_err();
+    assert!(error.starts_with("Statement at line 9 failed"), "{}", error);
+    let result = run(&db, "SELECT owner FROM accounts;").unwrap();
+    assert!(result.contains("alice; -- not a comment") && result.contains("bob"), "{}", result);
+    run(&db, "BEGIN;").unwrap();
+
+    // Continue: the failure is reported and the rest of the script runs
+    let dir = TempDir::new("script_continue");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    let mut calls = Vec::new();
+    let summary = db.execute_script(script.as_bytes(), OnError::Continue, |p| calls.push(p.clone())).unwrap();
+    assert_eq!(summary.statements, 7);
+    assert_eq!(summary.errors.len(), 1);
+    assert_eq!(summary.errors[0].line, 9);
+    assert_eq!(calls.len(), 7);
+    assert_eq!(calls.last().unwrap().bytes_read, script.len() as u64);
+    assert!(run(&db, "SELECT owner FROM accounts;").unwrap().contains("carol"));
+}
+
+#[test]
+fn test_query_returns_rows_in_schema_order() {
+    let dir = TempDir::new("query_rows");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE users (id INT PRIMARY KEY, name TEXT, age INT);").unwrap();
+    run(&db, "INSERT INTO users (id, name) VALUES ('1', 'alice'), ('2', 'bob');").unwrap();
+
+    let SQLStatement::Select(stmt) = parse_sql(tokenize("SELECT * FROM users ORDER BY id;").unwrap()).unwrap() else {
+        panic!("not a SELECT");
+    };
+    let result = db.query(&stmt).unwrap();
+    assert_eq!(result.columns, vec!["id", "name", "age"]);
+    assert_eq!(result.rows, vec![vec!["1", "alice", NULL], vec!["2", "bob", NULL]]);
+}
+
+#[test]
+fn test_query_map_and_query_as() {
+    #[derive(Debug, PartialEq, serde::Deserialize)]
+    struct User {
+        id: u32,
+        name: String,
+        age: Option<u8>,
+    }
+
+    let dir = TempDir::new("query_map");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE users (id INT PRIMARY KEY, name TEXT, age INT);").unwrap();
+    run(&db, "INSERT INTO users (id, name, age) VALUES ('1', 'alice', '30');").unwrap();
+    run(&db, "INSERT INTO users (id, name) VALUES ('2', 'bob');").unwrap();
+
+    let ages = db.query_map("SELECT * FROM users ORDER BY id", |row| row.get_opt::<u8>("age")).unwrap();
+    assert_eq!(ages, vec![Some(30), None]);
+    let error = db.query_map("SELECT name FROM users", |row| row.get::<u32>("name")).unwrap_err();
+    assert!(error.contains("Column 'name' holds 'alice'"), "{}", error);
+    assert!(db.query_map("SELECT name FROM users", |row| row.value("age").map(str::to_string)).is_err());
+
+    let users: Vec<User> = db.query_as("SELECT * FROM users ORDER BY id").unwrap();
+    assert_eq!(users, vec![
+        User { id: 1, name: "alice".to_string(), age: Some(30) },
+        User { id: 2, name: "bob".to_string(), age: None },
+    ]);
+    assert!(db.query_as::<User>("SELECT id, age FROM users").unwrap_err().contains("name"));
+    assert!(db.query_as::<User>("DELETE FROM users").is_err());
+}
+
+#[test]
+fn test_slow_query_log() {
+    let dir = TempDir::new("slow_queries");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    setup_users(&db);
+
+    let logged = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let sink = logged.clone();
+    db.log_slow_queries(Duration::ZERO, move |query| sink.lock().unwrap().push(query.clone())).unwrap();
+    run(&db, "SELECT name FROM users WHERE age > '26';").unwrap();
+    run(&db, "DELETE FROM users WHERE name = 'bob';").unwrap();
+    let logged = logged.lock().unwrap().clone();
+    assert_eq!(logged.len(), 2);
+    assert_eq!(logged[0].sql, "SELECT name FROM users WHERE age > '26'");
+    assert_eq!((logged[0].rows_examined, logged[0].rows_returned), (2, Some(1)));
+    assert_eq!(logged[1].rows_returned, None);
+
+    // Only statements over the threshold are written to the file
+    let log_path = format!("{}/slow.log", dir.path());
+    db.log_slow_queries_to_file(Duration::from_secs(3600), &log_path).unwrap();
+    run(&db, "SELECT * FROM users;").unwrap();
+    assert_eq!(fs::read_to_string(&log_path).unwrap(), "");
+    db.log_slow_queries_to_file(Duration::ZERO, &log_path).unwrap();
+    run(&db, "SELECT * FROM users;").unwrap();
+    let line = fs::read_to_string(&log_path).unwrap();
+    assert!(line.ends_with("ms examined=1 returned=1 SELECT * FROM users\n"), "{}", line);
+
+    db.stop_logging_slow_queries().unwrap();
+    run(&db, "SELECT * FROM users;").unwrap();
+    assert_eq!(fs::read_to_string(&log_path).unwrap(), line);
+}
+
+#[test]
+fn test_change_hooks() {
+    let dir = TempDir::new("change_hooks");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    setup_users(&db);
+
+    let events = Arc::new(std::sync::Mutex::new(Vec::<ChangeEvent>::new()));
+    let sink = events.clone();
+    db.on_change("users", move |event| sink.lock().unwrap().push(event.clone())).unwrap();
+    let take = || std::mem::take(&mut *events.lock().unwrap());
+
+    run(&db, "INSERT INTO users (id, name) VALUES ('3', 'carol');").unwrap();
+    run(&db, "UPDATE users SET age = '31' WHERE name = 'alice';").unwrap();
+    run(&db, "DELETE FROM users WHERE name = 'nobody';").unwrap();
+    let changes = take();
+    assert_eq!(changes.len(), 2);
+    assert_eq!((changes[0].kind, changes[0].table.as_str()), (ChangeKind::Insert, "users"));
+    assert_eq!(changes[0].rows.columns, ["id", "name", "age"]);
+    assert_eq!(changes[0].rows.rows, [["3", "carol", NULL]]);
+    assert_eq!(changes[1].kind, ChangeKind::Update);
+    assert_eq!(changes[1].rows.rows, [["1", "alice", "31"]]);
+
+    // Inside a transaction, only what is committed is passed on, at COMMIT
+    run(&db, "BEGIN;").unwrap();
+    run(&db, "DELETE FROM users WHERE name = 'bob';").unwrap();
+    run(&db, "SAVEPOINT s;").unwrap();
+    run(&db, "DELETE FROM users WHERE name = 'carol';").unwrap();
+    run(&db, "ROLLBACK TO SAVEPOINT s;").unwrap();
+    assert!(take().is_empty());
+    run(&db, "COMMIT;").unwrap();
+    let changes = take();
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].kind, ChangeKind::Delete);
+    assert_eq!(changes[0].rows.rows, [["2", "bob", "25"]]);
+
+    run(&db, "BEGIN;").unwrap();
+    run(&db, "DELETE FROM users;").unwrap();
+    run(&db, "ROLLBACK;").unwrap();
+    db.remove_change_hooks("users").unwrap();
+    run(&db, "DELETE FROM users;").unwrap();
+    assert!(take().is_empty());
+}
+
+#[test]
+fn test_subscribe_changes() {
+    let dir = TempDir::new("subscribe_changes");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE users (id INT PRIMARY KEY, name TEXT);").unwrap();
+    run(&db, "INSERT INTO users (id, name) VALUES ('1', 'alice');").unwrap();
+    let mut changes = db.subscribe_changes("users").unwrap();
+    assert!(changes.try_next().is_none());
+
+    run(&db, "INSERT INTO users (id, name) VALUES ('2', 'bob');").unwrap();
+    run(&db, "UPDATE users SET name = 'alicia' WHERE id = '1';").unwrap();
+    run(&db, "BEGIN;").unwrap();
+    run(&db, "DELETE FROM users WHERE id = '2';").unwrap();
+    run(&db, "COMMIT;").unwrap();
+    run(&db, "BEGIN;").unwrap();
+    run(&db, "DELETE FROM users;").unwrap();
+    run(&db, "ROLLBACK;").unwrap();
+
+    let name = |change: &RowChange| change.row.as_ref().map(|row| row["name"].clone());
+    let bob = changes.next().unwrap().unwrap();
+    assert_eq!((bob.table.as_str(), bob.row.as_ref().unwrap()["id"].as_str()), ("users", "2"));
+    assert_eq!(name(&bob).as_deref(), Some("bob"));
+    assert_eq!(name(&changes.next().unwrap().unwrap()).as_deref(), Some("alicia"));
+    let deleted = changes.next().unwrap().unwrap();
+    assert_eq!((&deleted.key, deleted.row.is_none()), (&bob.key, true));
+    assert!(deleted.timestamp >= bob.timestamp);
+    assert!(changes.try_next().is_none());
+
+    // The stream ends with the table
+    run(&db, "DROP TABLE users;").unwrap();
+    assert!(changes.next().is_none());
+    assert!(db.subscribe_changes("users").is_err());
+}
+
+#[test]
+fn test_rewrite_rules() {
+    let dir = TempDir::new("rewrite_rules");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    setup_users(&db);
+
+    // Every DELETE becomes a soft delete
+    db.add_rewrite_rule(|stmt: &mut SQLStatement| {
+        if let SQLStatement::Delete(delete) = stmt {
+            *stmt = SQLStatement::Update(UpdateStatement {
+                table: delete.table.clone(),
+                join: delete.join.take(),
+                assignments: vec![("age".to_string(), SetValue::Value(NULL.to_string()))],
+                where_clause: delete.where_clause.take(),
+                returning: delete.returning.take(),
+            });
+        }
+        Ok(())
+    }).unwrap();
+    assert_eq!(run(&db, "DELETE FROM users WHERE name = 'bob';").unwrap(), "Updated 1 rows");
+    let ages = db.query_map("SELECT name, age FROM users", |row| row.get_opt::<u32>("age")).unwrap();
+    assert_eq!(ages.len(), 2);
+    assert!(ages.contains(&None));
+}
+
+#[test]
+fn test_optimizer_hints() {
+    let dir = TempDir::new("optimizer_hints");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE users (id INT PRIMARY KEY, name TEXT);").unwrap();
+    run(&db, "INSERT INTO users (id, name) VALUES ('1', 'alice'), ('2', 'bob'), ('3', 'carol');").unwrap();
+    run(&db, "CREATE TABLE orders (id INT PRIMARY KEY, user_id INT, item TEXT);").unwrap();
+    run(&db, "INSERT INTO orders (id, user_id, item) VALUES ('1', '2', 'pen'), ('2', '1', 'ink');").unwrap();
+
+    let examined = Arc::new(std::sync::Mutex::new(0));
+    let sink = examined.clone();
+    db.log_slow_queries(Duration::ZERO, move |query| *sink.lock().unwrap() = query.rows_examined).unwrap();
+    let bob = "SELECT name FROM users WHERE id = '2';";
+    assert!(run(&db, bob).unwrap().contains("bob"));
+    assert_eq!(*examined.lock().unwrap(), 1);
+    let bob = "SELECT /*+ NO_INDEX(users) */ name FROM users WHERE id = '2';";
+    assert!(run(&db, bob).unwrap().contains("bob"));
+    assert_eq!(*examined.lock().unwrap(), 3);
+
+    let items = |sql| db.query_map(sql, |row| row.get::<String>("orders.item")).unwrap();
+    assert_eq!(items("SELECT orders.item FROM users JOIN orders ON users.id = orders.user_id"), ["ink", "pen"]);
+    assert_eq!(items("SELECT /*+ JOIN_ORDER(orders, users) */ orders.item FROM users JOIN orders ON users.id = orders.user_id"),
+               ["pen", "ink"]);
+
+    // A LEFT JOIN keeps its order, in the plan as when it runs
+    assert_eq!(run(&db, "EXPLAIN SELECT /*+ JOIN_ORDER(orders, users) */ * FROM users LEFT JOIN orders ON users.id = orders.user_id;").unwrap(), "\
+Hash Join (LEFT JOIN ON users.id = orders.user_id)
+  Seq Scan on users
+  Seq Scan on orders");
+}
+
+#[test]
+fn test_parenthesized_join() {
+    let dir = TempDir::new("parenthesized_join");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE orders (id INT PRIMARY KEY, user_id INT, status TEXT);").unwrap();
+    run(&db, "CREATE TABLE users (id INT PRIMARY KEY, team_id INT, name TEXT);").unwrap();
+    run(&db, "CREATE TABLE teams (id INT PRIMARY KEY, label TEXT);").unwrap();
+    run(&db, "INSERT INTO orders (id, user_id, status) VALUES ('1', '10', 'open'), ('2', '11', 'open');").unwrap();
+    run(&db, "INSERT INTO users (id, team_id, name) VALUES ('10', '100', 'ann'), ('11', '101', 'bob');").unwrap();
+    run(&db, "INSERT INTO teams (id, label) VALUES ('100', 'red');").unwrap();
+
+    let group = "orders LEFT JOIN (users JOIN teams ON users.team_id = teams.id) ON orders.user_id = users.id";
+    let rows = db.query_map(&format!("SELECT orders.id, name, label FROM {} ORDER BY orders.id;", group),
+                            |row| Ok(format!("{} {:?} {:?}", row.get::<String>("orders.id")?, row.get_opt::<String>("name")?,
+                                             row.get_opt::<String>("label")?))).unwrap();
+    assert_eq!(rows, ["1 Some(\"ann\") Some(\"red\")", "2 None None"]);
+    let all = run(&db, &format!("SELECT * FROM {} WHERE orders.id = '1';", group)).unwrap();
+    assert!(all.starts_with("orders.id | orders.user_id | orders.status | users.id | users.team_id | users.name | teams.id | teams.label\n"),
+            "{}", all);
+    assert_eq!(run(&db, &format!("EXPLAIN SELECT label FROM {};", group)).unwrap(), "\
+Hash Join (LEFT JOIN ON orders.user_id = users.id)
+  Seq Scan on orders
+  Hash Join (INNER JOIN ON users.team_id = teams.id)
+    Seq Scan on users
+    Seq Scan on teams");
+
+    run(&db, "UPDATE orders JOIN (users JOIN teams ON users.team_id = teams.id) ON orders.user_id = users.id \
+              SET status = 'red' WHERE teams.label = 'red';").unwrap();
+    assert_eq!(db.rows_affected(), 1);
+    let statuses = db.query_map("SELECT status FROM orders ORDER BY id;", |row| row.get::<String>("status")).unwrap();
+    assert_eq!(statuses, ["red", "open"]);
+}
+
+#[test]
+fn test_explain() {
+    let dir = TempDir::new("explain");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE users (id INT PRIMARY KEY, name TEXT, age INT);").unwrap();
+    run(&db, "CREATE TABLE orders (id INT, user_id INT);").unwrap();
+
+    assert_eq!(run(&db, "EXPLAIN SELECT name FROM users WHERE id = '2';").unwrap(),
+               "Filter (id = '2')\n  Key Lookup on users (id = '2')");
+    assert_eq!(run(&db, "EXPLAIN SELECT /*+ NO_INDEX(users) */ * FROM users ORDER BY id DESC LIMIT 5;").unwrap(),
+               "Limit (LIMIT 5 OFFSET 0)\n  Sort (id DESC)\n    Seq Scan on users");
+    let sql = "SELECT name, COUNT(*) FROM users JOIN orders ON users.id = orders.user_id GROUP BY name";
+    assert_eq!(run(&db, &format!("EXPLAIN {};", sql)).unwrap(), "\
+Aggregate (GROUP BY name)
+  Hash Join (INNER JOIN ON users.id = orders.user_id)
+    Seq Scan on users
+    Seq Scan on orders");
+
+    let plan: serde_json::Value = serde_json::from_str(&run(&db, "EXPLAIN (FORMAT JSON) SELECT * FROM users ORDER BY id;").unwrap()).unwrap();
+    assert_eq!(plan, serde_json::json!({"node": "Key Order Scan", "table": "users", "detail": "id ASC"}));
+    assert!(run(&db, "EXPLAIN SELECT * FROM missing;").is_err());
+}
+
+#[test]
+fn test_vacuum() {
+    let dir = TempDir::new("vacuum");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    setup_users(&db);
+    let long = "x".repeat(1000);
+    for i in 3..20 {
+        run(&db, &format!("INSERT INTO users (id, name, age) VALUES ('{}', '{}', '40');", i, long)).unwrap();
+    }
+    run(&db, "VACUUM users;").unwrap();
+
+    run(&db, "UPDATE users SET name = 'short' WHERE age = '40';").unwrap();
+    run(&db, "DELETE FROM users WHERE name = 'bob';").unwrap();
+    let reclaimed = db.vacuum(None).unwrap();
+    assert!(reclaimed > 17 * 900, "reclaimed {} bytes", reclaimed);
+    assert_eq!(run(&db, "VACUUM;").unwrap(), "Vacuum reclaimed 0 bytes");
+    let names = db.query_map("SELECT name FROM users", |row| row.get::<String>("name")).unwrap();
+    assert_eq!(names.len(), 18);
+    assert!(names.contains(&"alice".to_string()) && !names.contains(&"bob".to_string()));
+
+    assert!(run(&db, "VACUUM missing;").is_err());
+    run(&db, "BEGIN;").unwrap();
+    assert!(run(&db, "VACUUM;").is_err());
+    run(&db, "ROLLBACK;").unwrap();
+}
+
+#[test]
+fn test_decimal_columns() {
+    let dir = TempDir::new("decimal_columns");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE payments (id INT, amount DECIMAL(10, 2));").unwrap();
+    for i in 0..10 {
+        run(&db, &format!("INSERT INTO payments (id, amount) VALUES ('{}', '0.1');", i)).unwrap();
+    }
+    run(&db, "INSERT INTO payments (id, amount) VALUES ('10', '2.005'), ('11', '10');").unwrap();
+    let amount = |sql: &str, column: &str| db.query_map(sql, |row| row.get::<String>(column)).unwrap();
+
+    // Values are stored at the column's scale and summed without drift
+    assert_eq!(amount("SELECT amount FROM payments WHERE id = '10'", "amount"), ["2.01"]);
+    assert_eq!(amount("SELECT SUM(amount) FROM payments", "SUM(amount)"), ["13.01"]);
+    assert_eq!(amount("SELECT AVG(amount) FROM payments WHERE id < '10'", "AVG(amount)"), ["0.100000"]);
+    assert_eq!(amount("SELECT MAX(amount) FROM payments", "MAX(amount)"), ["10.00"]);
+    assert_eq!(amount("SELECT id FROM payments WHERE amount = '2.010'", "id"), ["10"]);
+    assert_eq!(amount("SELECT amount FROM payments ORDER BY amount DESC LIMIT 2", "amount"), ["10.00", "2.01"]);
+
+    assert!(run(&db, "INSERT INTO payments (id, amount) VALUES ('12', '123456789');").unwrap_err().contains("too large"));
+    assert!(run(&db, "UPDATE payments SET amount = 'ten' WHERE id = '11';").is_err());
+    assert!(run(&db, "CREATE TABLE bad (amount DECIMAL(2, 3));").is_err());
+}
+
+#[test]
+fn test_blob_columns() {
+    let dir = TempDir::new("blob_columns");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE files (id INT PRIMARY KEY, data BLOB);").unwrap();
+    run(&db, "INSERT INTO files (id, data) VALUES ('1', X'00FF0A22'), ('2', 'hi'), ('3', 'NULL');").unwrap();
+    run(&db, "UPDATE files SET data = x'c0ffee' WHERE id = '3';").unwrap();
+    drop(db);
+
+    // Bytes JSON couldn't hold as a string come back intact after reopening
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    let blobs = db.query_map("SELECT data FROM files", |row| row.get::<Blob>("data")).unwrap();
+    assert_eq!(blobs, [Blob(vec![0x00, 0xff, 0x0a, 0x22]), Blob(b"hi".to_vec()), Blob(vec![0xc0, 0xff, 0xee])]);
+    let lengths = db.query_map("SELECT LENGTH(data) FROM files", |row| row.get::<usize>("LENGTH(data)")).unwrap();
+    assert_eq!(lengths, [4, 2, 3]);
+    assert_eq!(db.query_map("SELECT id FROM files WHERE data = X'C0FFEE'", |row| row.get::<i64>("id")).unwrap(), [3]);
+
+    let mut dump = Vec::new();
+    db.dump_table("files", &mut dump).unwrap();
+    assert!(String::from_utf8(dump).unwrap().contains("X'00FF0A22'"));
+}
+
+#[test]
+fn test_boolean_columns() {
+    let dir = TempDir::new("boolean_columns");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE flags (id INT PRIMARY KEY, active BOOLEAN);").unwrap();
+    run(&db, "INSERT INTO flags (id, active) VALUES ('1', TRUE), ('2', 'false'), ('3', '1');").unwrap();
+    let ids = |sql: &str| db.query_map(sql, |row| row.get::<i64>("id")).unwrap();
+    assert_eq!(ids("SELECT id FROM flags WHERE active"), [1, 3]);
+    assert_eq!(ids("SELECT id FROM flags WHERE active = FALSE"), [2]);
+    assert_eq!(ids("SELECT id FROM flags WHERE NOT active"), [2]);
+    assert_eq!(ids("SELECT id FROM flags WHERE active = '0'"), [2]);
+    assert_eq!(db.query_map("SELECT active FROM flags WHERE id = '3'", |row| row.get::<bool>("active")).unwrap(), [true]);
+
+    run(&db, "UPDATE flags SET active = false WHERE active;").unwrap();
+    assert!(ids("SELECT id FROM flags WHERE active").is_empty());
+    assert!(run(&db, "INSERT INTO flags (id, active) VALUES ('4', 'maybe');").is_err());
+}
+
+#[test]
+fn test_create_table_rejects_duplicates() {
+    let dir = TempDir::new("create_table_duplicates");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    setup_users(&db);
+    assert_eq!(run(&db, "CREATE TABLE users (id INT);").unwrap_err(), "Table 'users' already exists");
+    assert_eq!(run(&db, "CREATE TABLE pets (id INT, name TEXT, id TEXT);").unwrap_err(),
+               "Column 'id' is defined more than once in table 'pets'");
+
+    // Neither touched the existing table nor left the rejected one behind
+    assert_eq!(db.query_map("SELECT name FROM users", |row| row.get::<String>("name")).unwrap().len(), 2);
+    assert!(run(&db, "SELECT * FROM pets;").is_err());
+}
+
+#[test]
+fn test_group_by_requires_grouped_columns() {
+    let dir = TempDir::new("group_by_grouped_columns");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    setup_users(&db);
+    let error = run(&db, "SELECT name, age FROM users GROUP BY age;").unwrap_err();
+    assert_eq!(error, "Column 'name' must appear in GROUP BY or be used in an aggregate function");
+    assert!(run(&db, "SELECT name, SUM(age) FROM users;").is_err());
+    assert!(run(&db, "EXPLAIN SELECT name FROM users GROUP BY age;").is_err());
+    let counts = db.query_map("SELECT age, COUNT(*) FROM users GROUP BY age", |row| row.get::<u32>("COUNT(*)")).unwrap();
+    assert_eq!(counts, [1, 1]);
+}
+
+#[test]
+fn test_aggregates_over_join() {
+    let dir = TempDir::new("join_aggregates");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    setup_users(&db);
+    run(&db, "CREATE TABLE orders (id INT, user_id INT, amount INT);").unwrap();
+    run(&db, "INSERT INTO orders (id, user_id, amount) VALUES ('1', '1', '10'), ('2', '1', '15'), ('3', '2', '7');").unwrap();
+
+    let join = "FROM users JOIN orders ON users.id = orders.user_id";
+    for sum in ["SUM(amount)", "SUM(orders.amount)"] {
+        let sql = format!("SELECT users.name, {} {} GROUP BY name ORDER BY name", sum, join);
+        let totals = db.query_map(&sql, |row| Ok((row.get::<String>("users.name")?, row.get::<f64>(sum)?))).unwrap();
+        assert_eq!(totals, [("alice".to_string(), 25.0), ("bob".to_string(), 7.0)]);
+    }
+    let sql = format!("SELECT name {} GROUP BY name HAVING SUM(amount) > '10'", join);
+    assert_eq!(db.query_map(&sql, |row| row.get::<String>("name")).unwrap(), ["alice"]);
+    let sql = format!("SELECT COUNT(*), MAX(amount) {} WHERE amount > '8'", join);
+    let counts = db.query_map(&sql, |row| Ok((row.get::<u32>("COUNT(*)")?, row.get::<f64>("MAX(amount)")?))).unwrap();
+    assert_eq!(counts, [(2, 15.0)]);
+    let ids = db.query_map(&format!("SELECT id {} ORDER BY id", join), |row| row.get::<u32>("id")).unwrap();
+    assert_eq!(ids, [1, 1, 2]);
+}
+
+#[test]
+fn test_full_outer_join() {
+    let dir = TempDir::new("full_join");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    setup_users(&db);
+    run(&db, "CREATE TABLE orders (id INT, user_id INT, item TEXT);").unwrap();
+    run(&db, "INSERT INTO orders (id, user_id, item) VALUES ('1', '1', 'pen'), ('2', '3', 'ink');").unwrap();
+
+    let sql = "SELECT users.name, orders.item FROM users FULL JOIN orders ON users.id = orders.user_id";
+    let mut pairs = db.query_map(sql, |row| Ok((row.get::<String>("users.name")?, row.get::<String>("orders.item")?))).unwrap();
+    pairs.sort();
+    let pair = |name: &str, item: &str| (name.to_string(), item.to_string());
+    assert_eq!(pairs, [pair(NULL, "ink"), pair("alice", "pen"), pair("bob", NULL)]);
+}
+
+#[test]
+fn test_cross_join() {
+    let dir = TempDir::new("cross_join");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    setup_users(&db);
+    run(&db, "CREATE TABLE colors (color TEXT);").unwrap();
+    run(&db, "INSERT INTO colors (color) VALUES ('red'), ('blue');").unwrap();
+
+    let sql = "SELECT name, color FROM users CROSS JOIN colors ORDER BY name";
+    let pairs = db.query_map(sql, |row| Ok((row.get::<String>("name")?, row.get::<String>("color")?))).unwrap();
+    assert_eq!(pairs.len(), 4);
+    assert!(pairs[..2].iter().all(|(name, _)| name == "alice"));
+    let counts = db.query_map("SELECT COUNT(*) FROM users CROSS JOIN colors WHERE color = 'red'", |row| row.get::<u32>("COUNT(*)"));
+    assert_eq!(counts.unwrap(), [2]);
+    assert_eq!(run(&db, "EXPLAIN SELECT * FROM users CROSS JOIN colors;").unwrap(), "\
+Nested Loop (CROSS JOIN)
+  Seq Scan on users
+  Seq Scan on colors");
+}
+
+#[test]
+fn test_join_on_ranges() {
+    let dir = TempDir::new("range_join");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE events (id INT, ts INT);").unwrap();
+    run(&db, "CREATE TABLE shifts (name TEXT, starts INT, ends INT);").unwrap();
+    run(&db, "INSERT INTO events (id, ts) VALUES ('1', '5'), ('2', '15'), ('3', '30');").unwrap();
+    run(&db, "INSERT INTO shifts (name, starts, ends) VALUES ('early', '0', '10'), ('late', '10', '20');").unwrap();
+
+    let sql = "SELECT id, name FROM events JOIN shifts ON events.ts > shifts.starts AND shifts.ends > events.ts";
+    let shifts = db.query_map(sql, |row| Ok((row.get::<u32>("id")?, row.get::<String>("name")?))).unwrap();
+    assert_eq!(shifts, [(1, "early".to_string()), (2, "late".to_string())]);
+    let sql = "SELECT id, name FROM events LEFT JOIN shifts ON ts > starts AND ts < ends ORDER BY id";
+    let names = db.query_map(sql, |row| row.get::<String>("name")).unwrap();
+    assert_eq!(names, ["early", "late", NULL]);
+    assert_eq!(run(&db, &format!("EXPLAIN {};", sql)).unwrap(), "\
+Sort (id ASC)
+  Nested Loop (LEFT JOIN ON ts > starts AND ts < ends)
+    Seq Scan on events
+    Seq Scan on shifts");
+}
+
+#[test]
+fn test_join_on_several_columns() {
+    let dir = TempDir::new("composite_join");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE stock (shop TEXT, item TEXT, count INT);").unwrap();
+    run(&db, "CREATE TABLE prices (shop TEXT, item TEXT, price INT, since INT);").unwrap();
+    run(&db, "INSERT INTO stock (shop, item, count) VALUES ('a', 'pen', '3'), ('a', 'ink', '1'), ('b', 'pen', '5');").unwrap();
+    run(&db, "INSERT INTO prices (shop, item, price, since) VALUES ('a', 'pen', '2', '1'), ('b', 'pen', '4', '7'), ('b', 'ink', '9', '1');").unwrap();
+
+    let sql = "SELECT stock.item, price FROM stock JOIN prices ON prices.item = stock.item AND stock.shop = prices.shop ORDER BY price";
+    let prices = db.query_map(sql, |row| Ok((row.get::<String>("stock.item")?, row.get::<u32>("price")?))).unwrap();
+    assert_eq!(prices, [("pen".to_string(), 2), ("pen".to_string(), 4)]);
+    // Equalities pick the candidate rows, and the other comparisons filter them
+    let sql = "SELECT stock.shop FROM stock JOIN prices ON stock.shop = prices.shop AND stock.item = prices.item AND count > since";
+    assert_eq!(db.query_map(sql, |row| row.get::<String>("stock.shop")).unwrap(), ["a"]);
+    assert_eq!(run(&db, &format!("EXPLAIN {};", sql)).unwrap(), "\
+Hash Join (INNER JOIN ON stock.shop = prices.shop AND stock.item = prices.item AND count > since)
+  Seq Scan on stock
+  Seq Scan on prices");
+}
+
+#[test]
+fn test_qualified_column_names() {
+    let dir = TempDir::new("qualified_names");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE users (id INT PRIMARY KEY, name TEXT, age INT, balance DECIMAL(6,2));").unwrap();
+    run(&db, "INSERT INTO users (id, name, age, balance) VALUES ('1', 'alice', '30', '10.5'), ('2', 'bob', '25', '9.75');").unwrap();
+
+    let names = |sql: &str| db.query_map(sql, |row| row.get::<String>("name")).unwrap();
+    assert_eq!(names("SELECT name FROM users WHERE users.age > '26' ORDER BY users.id"), ["alice"]);
+    assert_eq!(names("SELECT name FROM users WHERE users.id = '2'"), ["bob"]);
+    // Compared as DECIMALs, not as text
+    assert_eq!(names("SELECT name FROM users ORDER BY users.balance"), ["bob", "alice"]);
+    let sql = "SELECT users.age, SUM(users.balance) FROM users GROUP BY users.age ORDER BY age";
+    let sums = db.query_map(sql, |row| row.get::<String>("SUM(users.balance)")).unwrap();
+    assert_eq!(sums, ["9.75", "10.50"]);
+
+    run(&db, "UPDATE users SET age = '26' WHERE users.name = 'bob';").unwrap();
+    run(&db, "DELETE FROM users WHERE users.id = '1';").unwrap();
+    let rows = db.query_map("SELECT * FROM users", |row| Ok((row.get::<String>("name")?, row.get::<u32>("age")?))).unwrap();
+    assert_eq!(rows, [("bob".to_string(), 26)]);
+}
+
+#[test]
+fn test_table_aliases() {
+    let dir = TempDir::new("table_aliases");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE users (id INT PRIMARY KEY, name TEXT);").unwrap();
+    run(&db, "CREATE TABLE orders (id INT PRIMARY KEY, user_id INT, amount INT);").unwrap();
+    run(&db, "INSERT INTO users (id, name) VALUES ('1', 'alice'), ('2', 'bob');").unwrap();
+    run(&db, "INSERT INTO orders (id, user_id, amount) VALUES ('1', '1', '10'), ('2', '2', '7'), ('3', '1', '3');").unwrap();
+
+    let sql = "SELECT u.name, SUM(o.amount) FROM users u JOIN orders o ON u.id = o.user_id GROUP BY u.name ORDER BY u.name";
+    let rows = db.query_map(sql, |row| Ok((row.get::<String>("users.name")?, row.get::<u32>("SUM(orders.amount)")?))).unwrap();
+    assert_eq!(rows, [("alice".to_string(), 13), ("bob".to_string(), 7)]);
+    let names = db.query_map("SELECT u.name FROM users AS u WHERE u.id = '2'", |row| row.get::<String>("users.name")).unwrap();
+    assert_eq!(names, ["bob"]);
+}
+
+#[test]
+fn test_result_column_types() {
+    let dir = TempDir::new("result_column_types");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE payments (id INT, payer TEXT, amount DECIMAL(10, 2), paid BOOLEAN);").unwrap();
+    run(&db, "INSERT INTO payments (id, payer, amount, paid) VALUES ('1', 'alice', '2.50', 'true');").unwrap();
+
+    let select = |sql: &str| match parse_sql(tokenize(sql).unwrap()).unwrap() {
+        SQLStatement::Select(select) => db.query(&select).unwrap(),
+        _ => unreachable!(),
+    };
+    let types = |sql: &str| select(sql).meta.into_iter().map(|m| m.value_type).collect::<Vec<_>>();
+    assert_eq!(types("SELECT id, payer, amount, paid FROM payments"),
+               [ValueType::Integer, ValueType::Text, ValueType::Decimal, ValueType::Boolean]);
+    assert_eq!(types("SELECT ABS(amount), MOD(id, 2), POWER(id, 2) FROM payments"),
+               [ValueType::Decimal, ValueType::Integer, ValueType::Real]);
+    assert_eq!(types("SELECT SUM(amount), AVG(id), COUNT(*) FROM payments"),
+               [ValueType::Decimal, ValueType::Real, ValueType::Integer]);
+    let meta = select("SELECT payer FROM payments").meta;
+    assert_eq!((meta[0].name.as_str(), meta[0].table.as_deref()), ("payer", Some("payments")));
+}
+
+#[test]
+fn test_rows_affected() {
+    let dir = TempDir::new("rows_affected");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE users (id INT PRIMARY KEY, name TEXT);").unwrap();
+    run(&db, "CREATE TABLE events (name TEXT);").unwrap();
+
+    run(&db, "INSERT INTO users (id, name) VALUES ('1', 'alice'), ('2', 'bob');").unwrap();
+    assert_eq!((db.rows_affected(), db.last_insert_rowid().as_deref()), (2, Some("2")));
+    run(&db, "UPDATE users SET name = 'al' WHERE id = '1';").unwrap();
+    assert_eq!((db.rows_affected(), db.last_insert_rowid().as_deref()), (1, Some("2")));
+    run(&db, "DELETE FROM users;").unwrap();
+    assert_eq!(db.rows_affected(), 2);
+
+    // A table without a primary key reports the key generated for the row
+    run(&db, "INSERT INTO events (name) VALUES ('login');").unwrap();
+    let key = db.last_insert_rowid().unwrap();
+    assert!(key.starts_with("row_"));
+
+    // Each thread sees its own statements'
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            assert_eq!(db.last_insert_rowid(), None);
+            run(&db, "INSERT INTO users (id, name) VALUES ('3', 'carol');").unwrap();
+            assert_eq!(db.last_insert_rowid().as_deref(), Some("3"));
+        });
+    });
+    assert_eq!(db.last_insert_rowid(), Some(key));
+}
+
+#[test]
+fn test_order_by_aggregates_and_aliases() {
+    let dir = TempDir::new("order_by_aggregates");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE staff (name TEXT, dept TEXT, salary INT, bonus DECIMAL(6,2));").unwrap();
+    run(&db, "INSERT INTO staff (name, dept, salary, bonus) VALUES ('a', 'ops', '9', '0.50'), ('b', 'dev', '5', '2.00'), \
+              ('c', 'dev', '5', '0.25'), ('d', 'hr', '4', '10.00'), ('e', 'dev', '1', '0.25'), ('f', 'ops', '1', '0.25');").unwrap();
+
+    let depts = |sql: &str| db.query_map(sql, |row| row.get::<String>("dept")).unwrap();
+    assert_eq!(depts("SELECT dept, COUNT(*) FROM staff GROUP BY dept ORDER BY COUNT(*) DESC"), ["dev", "ops", "hr"]);
+    assert_eq!(depts("SELECT dept, SUM(salary) AS total FROM staff GROUP BY dept ORDER BY total"), ["hr", "ops", "dev"]);
+    // Computed for the sort though not selected, and DECIMAL sums by value
+    assert_eq!(depts("SELECT dept FROM staff GROUP BY dept ORDER BY SUM(bonus) DESC"), ["hr", "dev", "ops"]);
+    let names = db.query_map("SELECT name AS who FROM staff ORDER BY who DESC LIMIT 2", |row| row.get::<String>("who")).unwrap();
+    assert_eq!(names, ["f", "e"]);
+    let plan = run(&db, "EXPLAIN SELECT dept, COUNT(*) AS n FROM staff GROUP BY dept ORDER BY n DESC;").unwrap();
+    assert!(plan.contains("Sort (COUNT(*) DESC)"), "{}", plan);
+}
+
+#[test]
+fn test_having_on_aliases() {
+    let dir = TempDir::new("having_on_aliases");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE staff (name TEXT, dept TEXT, salary INT, bonus DECIMAL(6,2));").unwrap();
+    run(&db, "INSERT INTO staff (name, dept, salary, bonus) VALUES ('a', 'ops', '9', '0.50'), ('b', 'dev', '5', '2.00'), \
+              ('c', 'dev', '5', '0.25'), ('d', 'hr', '4', '10.00'), ('e', 'dev', '1', '0.25'), ('f', 'ops', '1', '0.25');").unwrap();
+
+    let depts = |sql: &str| db.query_map(sql, |row| row.get::<String>("dept")).unwrap();
+    assert_eq!(depts("SELECT dept, COUNT(*) AS c FROM staff GROUP BY dept HAVING c > '1' ORDER BY c DESC"), ["dev", "ops"]);
+    assert_eq!(depts("SELECT dept, SUM(bonus) AS total FROM staff GROUP BY dept HAVING total = '0.75'"), ["ops"]);
+    assert_eq!(depts("SELECT dept AS d, dept FROM staff GROUP BY dept HAVING d = 'hr'"), ["hr"]);
+}
+
+#[test]
+fn test_multi_table_writes() {
+    let dir = TempDir::new("multi_table_writes");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE users (id INT PRIMARY KEY, banned BOOLEAN);").unwrap();
+    run(&db, "CREATE TABLE orders (id INT PRIMARY KEY, user_id INT, status TEXT);").unwrap();
+    run(&db, "INSERT INTO users (id, banned) VALUES ('1', FALSE), ('2', TRUE), ('3', TRUE);").unwrap();
+    run(&db, "INSERT INTO orders (id, user_id, status) VALUES ('10', '1', 'open'), ('11', '2', 'open'), \
+              ('12', '3', 'open'), ('13', '1', 'open');").unwrap();
+    let orders = |sql: &str| db.query_map(sql, |row| row.get::<String>("id")).unwrap();
+
+    run(&db, "UPDATE orders JOIN users ON orders.user_id = users.id SET orders.status = 'void' WHERE users.banned = TRUE;").unwrap();
+    assert_eq!(db.rows_affected(), 2);
+    assert_eq!(orders("SELECT id FROM orders WHERE status = 'void'"), ["11", "12"]);
+    assert!(run(&db, "UPDATE orders JOIN users ON orders.user_id = users.id SET users.banned = FALSE;").is_err());
+
+    run(&db, "DELETE FROM orders WHERE user_id IN (SELECT id FROM users WHERE banned);").unwrap();
+    assert_eq!(db.rows_affected(), 2);
+    assert_eq!(orders("SELECT id FROM orders"), ["10", "13"]);
+    assert_eq!(orders("SELECT id FROM users WHERE id IN (SELECT user_id FROM orders)"), ["1"]);
+    run(&db, "DELETE orders FROM orders JOIN users ON orders.user_id = users.id WHERE orders.id = '13';").unwrap();
+    assert_eq!(orders("SELECT id FROM orders"), ["10"]);
+    // The subquery reads the table being written without deadlocking
+    run(&db, "DELETE FROM orders WHERE id IN (SELECT id FROM orders WHERE status = 'open');").unwrap();
+    assert!(orders("SELECT id FROM orders").is_empty());
+}
+
+#[test]
+fn test_insert_or_ignore_and_replace() {
+    let dir = TempDir::new("insert_or_ignore");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE users (id INT PRIMARY KEY, name TEXT);").unwrap();
+    run(&db, "INSERT INTO users (id, name) VALUES ('1', 'alice'), ('2', 'bob');").unwrap();
+    let names = |db: &PersistentDatabase| db.query_map("SELECT name FROM users ORDER BY id", |row| row.get::<String>("name")).unwrap();
+
+    assert!(run(&db, "INSERT INTO users (id, name) VALUES ('2', 'bert'), ('3', 'carol');").is_err());
+    run(&db, "INSERT OR IGNORE INTO users (id, name) VALUES ('2', 'bert'), ('3', 'carol'), ('3', 'cora');").unwrap();
+    assert_eq!(db.rows_affected(), 1);
+    assert_eq!(names(&db), ["alice", "bob", "carol"]);
+
+    run(&db, "INSERT OR REPLACE INTO users (id, name) VALUES ('1', 'alicia'), ('4', 'dan'), ('4', 'dave');").unwrap();
+    assert_eq!(db.rows_affected(), 3);
+    assert_eq!(names(&db), ["alicia", "bob", "carol", "dave"]);
+}
+
+#[test]
+fn test_import_csv() {
+    let dir = TempDir::new("import_csv");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    let csv = "id,name,note\r\n1,alice,\"likes \"\"tea\"\", coffee\"\r\n\r\n2,bob,\"two\nlines\"\r\n";
+    assert_eq!(db.import_csv("people", csv.as_bytes()).unwrap(), 2);
+    let result = run(&db, "SELECT note FROM people WHERE name = 'alice';").unwrap();
+    assert!(result.contains("likes \"tea\", coffee"), "{}", result);
+
+    // Appending needs the table to have every column
+    assert_eq!(db.import_csv("people", "name,id\ncarol,3\n".as_bytes()).unwrap(), 1);
+    assert!(db.import_csv("people", "id,age\n4,30\n".as_bytes()).is_err());
+    assert!(db.import_csv("people", "id,name\n5\n".as_bytes()).unwrap_err().contains("Line 2"));
+    assert!(run(&db, "SELECT id FROM people;").unwrap().lines().count() == 5);
+}
+
+#[test]
+fn test_limit_and_offset() {
+    let dir = TempDir::new("limit_offset");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE users (id INT PRIMARY KEY, name TEXT);").unwrap();
+    run(&db, "INSERT INTO users (id, name) VALUES ('1', 'alice'), ('2', 'bob'), ('3', 'carol');").unwrap();
+
+    let result = run(&db, "SELECT name FROM users ORDER BY id DESC LIMIT 2;").unwrap();
+    assert_eq!(result.lines().skip(2).collect::<Vec<_>>(), vec!["carol", "bob"]);
+    let result = run(&db, "SELECT name FROM users ORDER BY id OFFSET 2 ROWS;").unwrap();
+    assert_eq!(result.lines().skip(2).collect::<Vec<_>>(), vec!["carol"]);
+}
+
+#[test]
+fn test_analyze_and_stats() {
+    let dir = TempDir::new("analyze_stats");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    setup_users(&db);
+    run(&db, "INSERT INTO users (id, name, age) VALUES ('3', 'carol', '30'), ('4', NULL, '100');").unwrap();
+    let select = |db: &PersistentDatabase, sql: &str| match parse_sql(tokenize(sql).unwrap()).unwrap() {
+        SQLStatement::Select(select) => db.query(&select),
+        _ => unreachable!(),
+    };
+
+    assert!(select(&db, "SELECT * FROM stats('users')").unwrap_err().contains("run ANALYZE users"));
+    assert_eq!(run(&db, "ANALYZE users;").unwrap(), "Analyzed 1 tables");
+    let stats = select(&db, "SELECT * FROM stats('users')").unwrap();
+    assert_eq!(stats.columns, ["column_name", "row_count", "null_count", "distinct_count", "min_value", "max_value"]);
+    // Numbers are compared as numbers, so 100 is the largest age
+    assert_eq!(stats.rows, [
+        ["id", "4", "0", "4", "1", "4"],
+        ["name", "4", "1", "3", "alice", "carol"],
+        ["age", "4", "0", "3", "25", "100"],
+    ]);
+    assert_eq!(stats.meta.iter().map(|m| m.value_type).collect::<Vec<_>>()[..3],
+               [ValueType::Text, ValueType::Integer, ValueType::Integer]);
+
+    // The statistics are those of the last ANALYZE, until it runs again
+    run(&db, "DELETE FROM users WHERE id = '4';").unwrap();
+    run(&db, "ALTER TABLE users ADD COLUMN email TEXT;").unwrap();
+    let names = |db: &PersistentDatabase, sql: &str| db.query_map(sql, |row| row.get::<String>("column_name")).unwrap();
+    assert_eq!(names(&db, "SELECT column_name FROM stats('users') WHERE null_count > '0'"), ["name", "email"]);
+    assert_eq!(names(&db, "SELECT column_name FROM stats('users') WHERE distinct_count = '0'"), ["email"]);
+    assert_eq!(run(&db, "EXPLAIN SELECT * FROM stats('users') ORDER BY column_name;").unwrap(),
+               "Sort (column_name ASC)\n  Function Scan (stats('users'))");
+
+    run(&db, "BEGIN;").unwrap();
+    assert!(run(&db, "ANALYZE;").is_err());
+    run(&db, "ROLLBACK;").unwrap();
+    run(&db, "CREATE TABLE empty (id INT);").unwrap();
+    assert_eq!(run(&db, "ANALYZE;").unwrap(), "Analyzed 2 tables");
+    assert_eq!(select(&db, "SELECT * FROM stats('empty')").unwrap().rows, [["id", "0", "0", "0", NULL, NULL]]);
+    assert!(run(&db, "ANALYZE missing;").is_err());
+    db.close().unwrap();
+    drop(db);
+
+    // They are kept in the catalog, so survive a restart, until the table is dropped
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    assert_eq!(names(&db, "SELECT column_name FROM stats('users') ORDER BY distinct_count DESC LIMIT 1"), ["id"]);
+    assert_eq!(select(&db, "SELECT max_value FROM stats('users') WHERE column_name = 'id'").unwrap().rows, [["3"]]);
+    run(&db, "DROP TABLE users;").unwrap();
+    run(&db, "CREATE TABLE users (id INT);").unwrap();
+    assert!(select(&db, "SELECT * FROM stats('users')").is_err());
+}
+
+#[test]
+fn test_update_and_delete_returning() {
+    let dir = TempDir::new("returning");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE jobs (id INT PRIMARY KEY, status TEXT, tries INT);").unwrap();
+    run(&db, "INSERT INTO jobs (id, status, tries) VALUES (1, 'queued', 0), (2, 'queued', 0), (3, 'done', 1);").unwrap();
+    let returning = |sql: &str| match db.execute(parse_sql(tokenize(sql).unwrap()).unwrap()).unwrap() {
+        StatementResult::Rows(result) => result,
+        other => panic!("expected rows: {:?}", other),
+    };
+
+    // Claiming jobs shows each as it was and as the UPDATE left it
+    let result = returning("UPDATE jobs SET status = 'running', tries = '1' WHERE status = 'queued' RETURNING id, old.status, status;");
+    assert_eq!(result.columns, ["id", "old.status", "status"]);
+    assert_eq!(result.rows, [["1", "queued", "running"], ["2", "queued", "running"]]);
+    assert_eq!(db.rows_affected(), 2);
+
+    // A moved primary key is returned at its new value
+    let result = returning("UPDATE jobs SET id = '10' WHERE id = '3' RETURNING old.id, new.id;");
+    assert_eq!(result.rows, [["3", "10"]]);
+
+    // A rolled-back DELETE still returned what it deleted
+    run(&db, "BEGIN;").unwrap();
+    let result = returning("DELETE FROM jobs WHERE status = 'running' RETURNING *;");
+    assert_eq!(result.columns, ["id", "status", "tries"]);
+    assert_eq!(result.rows.len(), 2);
+    run(&db, "ROLLBACK;").unwrap();
+    assert!(run(&db, "SELECT COUNT(*) FROM jobs;").unwrap().contains('3'));
+
+    assert!(returning("DELETE FROM jobs WHERE status = 'failed' RETURNING id;").rows.is_empty());
+    assert!(run(&db, "DELETE FROM jobs RETURNING users.id;").is_err());
+    assert!(run(&db, "SELECT COUNT(*) FROM jobs;").unwrap().contains('3'));
+}
+
+#[test]
+fn test_failed_statements_change_nothing() {
+    let dir = TempDir::new("statement_atomicity");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE prices (id INT PRIMARY KEY, price DECIMAL(4,1));").unwrap();
+    run(&db, "INSERT INTO prices (id, price) VALUES (1, 1.5), (2, 99.5);").unwrap();
+    let prices = |db: &PersistentDatabase| db.query_map("SELECT price FROM prices", |row| row.get::<String>("price")).unwrap();
+
+    // A bad row late in a statement leaves the earlier ones out too
+    let err = run(&db, "INSERT INTO prices (id, price) VALUES (3, 1), (4, 2), (2, 3);").unwrap_err();
+    assert!(err.contains("Duplicate primary key '2'"), "{}", err);
+    // Squaring 1.5 fits the column, but not squaring 99.5
+    let err = run(&db, "UPDATE prices SET price = POWER(price, 2);").unwrap_err();
+    assert!(err.contains("too large"), "{}", err);
+    assert_eq!(prices(&db), ["1.5", "99.5"]);
+
+    // Inside a transaction, a failed statement is undone on its own and the
+    // transaction carries on
+    run(&db, "BEGIN;").unwrap();
+    run(&db, "INSERT INTO prices (id, price) VALUES (3, 3);").unwrap();
+    assert!(run(&db, "INSERT INTO prices (id, price) VALUES (4, 4), (5, 1000);").is_err());
+    assert!(run(&db, "INSERT INTO prices (id, price) VALUES (6, 6), (3, 3);").is_err());
+    assert!(db.in_transaction());
+    run(&db, "SAVEPOINT before_update;").unwrap();
+    run(&db, "UPDATE prices SET price = '0' WHERE id = '1';").unwrap();
+    run(&db, "ROLLBACK TO SAVEPOINT before_update;").unwrap();
+    run(&db, "COMMIT;").unwrap();
+    assert_eq!(prices(&db), ["1.5", "99.5", "3.0"]);
+}
+
+#[test]
+fn test_null_is_not_the_text_null() {
+    let dir = TempDir::new("nulls");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE notes (id INT PRIMARY KEY, body TEXT);").unwrap();
+    run(&db, "INSERT INTO notes (id, body) VALUES ('1', NULL), ('2', 'NULL');").unwrap();
+    run(&db, "CREATE TABLE tags (id INT, body TEXT);").unwrap();
+    run(&db, "INSERT INTO tags (id, body) VALUES ('8', NULL), ('9', 'NULL');").unwrap();
+    run(&db, "CREATE TABLE legacy (id INT, body TEXT);").unwrap();
+    assert!(run(&db, "INSERT INTO notes (id, body) VALUES (NULL, 'x');").unwrap_err().contains("can't be NULL"));
+    db.close().unwrap();
+    drop(db);
+
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    let ids = |sql: &str| db.query_map(sql, |row| row.get::<String>("id")).unwrap();
+    assert_eq!(ids("SELECT id FROM notes WHERE body = 'NULL'"), ["2"]);
+    assert_eq!(ids("SELECT id FROM notes WHERE body IS NULL"), ["1"]);
+    assert_eq!(ids("SELECT id FROM notes WHERE body IS NOT NULL"), ["2"]);
+    assert_eq!(ids("SELECT id FROM notes WHERE body IN ('NULL', 'x')"), ["2"]);
+    let joined = db.query_map("SELECT notes.id, tags.id FROM notes JOIN tags ON notes.body = tags.body",
+                              |row| Ok((row.get::<String>("notes.id")?, row.get::<String>("tags.id")?))).unwrap();
+    assert_eq!(joined, [("2".to_string(), "9".to_string())]);
+    assert_eq!(db.query_map("SELECT COUNT(body) FROM notes", |row| row.get::<u32>("COUNT(body)")).unwrap(), [1]);
+    db.close().unwrap();
+    drop(db);
+
+    // Rows written before NULL had its own encoding held it as the text, and are upgraded
+    {
+        let mut legacy = LSMStorage::new(dir.as_path(), "legacy").unwrap();
+        legacy.insert("row_1".to_string(), r#"{"id":"7","body":"NULL"}"#.to_string()).unwrap();
+    }
+    fs::write(dir.as_path().join(VERSION_FILE), "1\n").unwrap();
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    assert_eq!(data_format_version(dir.as_path()), Ok(Some(DATA_FORMAT_VERSION)));
+    let ids = |sql: &str| db.query_map(sql, |row| row.get::<String>("id")).unwrap();
+    assert_eq!(ids("SELECT id FROM legacy WHERE body IS NULL"), ["7"]);
+    assert!(ids("SELECT id FROM legacy WHERE body = 'NULL'").is_empty());
+}
+
+#[test]
+fn test_insert_without_column_list() {
+    let dir = TempDir::new("insert_without_columns");
+    let db = PersistentDatabase::new(dir.path()).unwrap();
+    run(&db, "CREATE TABLE users (id INT PRIMARY KEY, name TEXT, age INT);").unwrap();
+    run(&db, "INSERT INTO users VALUES (1, 'alice', 30), (2, 'bob', 25);").unwrap();
+    let rows = db.query_map("SELECT id, name, age FROM users ORDER BY id",
+                            |row| Ok((row.get::<i64>("id")?, row.get::<String>("name")?, row.get::<i64>("age")?))).unwrap();
+    assert_eq!(rows, [(1, "alice".to_string(), 30), (2, "bob".to_string(), 25)]);
+
+    let err = run(&db, "INSERT INTO users VALUES (3, 'carol');").unwrap_err();
+    assert!(err.contains("Column count does not match value count"), "{}", err);
+    assert_eq!(db.query_map("SELECT COUNT(*) FROM users", |row| row.get::<u32>("COUNT(*)")).unwrap(), [2]);
+}
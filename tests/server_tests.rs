@@ -0,0 +1,219 @@
+#![cfg(feature = "storage")]
+
+use rust_sql_parser::ast::NULL;
+use rust_sql_parser::output::ValueType;
+use rust_sql_parser::parser::parse_sql;
+use rust_sql_parser::persistent_executor::PersistentDatabase;
+use rust_sql_parser::locks::{DEADLOCK_DETECTED, SERIALIZATION_FAILURE};
+use rust_sql_parser::pool::{Connection, Pool};
+use rust_sql_parser::tokenizer::tokenize;
+use rust_sql_parser::server::{Client, QueryResult, Server};
+use rust_sql_parser::session::Session;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+mod common;
+use common::TempDir;
+
+fn start_server(dir: &TempDir) -> std::net::SocketAddr {
+    let db = Arc::new(PersistentDatabase::new(dir.path()).unwrap());
+    let server = Server::bind(db, "127.0.0.1:0").unwrap();
+    let addr = server.local_addr().unwrap();
+    thread::spawn(move || server.run());
+    addr
+}
+
+fn query(client: &mut Client, sql: &str) -> String {
+    client.execute(sql).unwrap().into_result().unwrap()
+}
+
+#[test]
+fn test_clients_share_a_database() {
+    let dir = TempDir::new("server_shared");
+    let addr = start_server(&dir);
+    let mut first = Client::connect(addr).unwrap();
+    let mut second = Client::connect(addr).unwrap();
+
+    query(&mut first, "CREATE TABLE users (id INT PRIMARY KEY, name TEXT);");
+    let inserted = second.execute("INSERT INTO users (id, name) VALUES ('1', 'alice');").unwrap();
+    assert_eq!((inserted.rows_affected(), inserted.last_insert_rowid()), (1, Some("1")));
+    assert!(query(&mut first, "SELECT name FROM users;").contains("alice"));
+    assert!(matches!(first.execute("SELEC name FROM users;").unwrap(), QueryResult::Err(_)));
+
+    let result = first.execute("SELECT id, name FROM users;").unwrap();
+    assert!(matches!(&result, QueryResult::Rows(rows) if rows.rows == [["1", "alice"]]));
+    let types: Vec<_> = result.columns().iter().map(|c| (c.name.as_str(), c.value_type)).collect();
+    assert_eq!(types, [("id", ValueType::Integer), ("name", ValueType::Text)]);
+}
+
+#[test]
+fn test_open_transaction_locks_its_tables() {
+    let dir = TempDir::new("server_transaction");
+    let addr = start_server(&dir);
+    let mut writer = Client::connect(addr).unwrap();
+    query(&mut writer, "CREATE TABLE users (id INT PRIMARY KEY, name TEXT);");
+    query(&mut writer, "CREATE TABLE orders (id INT PRIMARY KEY);");
+    query(&mut writer, "BEGIN;");
+    query(&mut writer, "INSERT INTO users (id, name) VALUES ('1', 'alice');");
+
+    // Other tables stay free
+    let mut other = Client::connect(addr).unwrap();
+    query(&mut other, "INSERT INTO orders (id) VALUES ('1');");
+
+    // Another client waits for the transaction rather than seeing its writes
+    let reader = thread::spawn(move || {
+        let mut reader = Client::connect(addr).unwrap();
+        query(&mut reader, "SELECT name FROM users;")
+    });
+    thread::sleep(Duration::from_millis(100));
+    assert!(!reader.is_finished());
+    query(&mut writer, "COMMIT;");
+    assert!(reader.join().unwrap().contains("alice"));
+
+    // Disconnecting rolls back
+    query(&mut writer, "BEGIN;");
+    query(&mut writer, "INSERT INTO users (id, name) VALUES ('2', 'bob');");
+    drop(writer);
+    let mut other = Client::connect(addr).unwrap();
+    assert!(!query(&mut other, "SELECT name FROM users;").contains("bob"));
+}
+
+fn run(connection: &mut Connection<'_>, sql: &str) -> Result<String, String> {
+    connection.execute(parse_sql(tokenize(sql)?)?).map(|result| result.to_string())
+}
+
+#[test]
+fn test_pool_hands_out_connections() {
+    let dir = TempDir::new("pool");
+    assert!(Pool::new(dir.path(), 0).is_err());
+    assert!(!dir.as_path().exists());
+    let pool = Pool::new(dir.path(), 2).unwrap();
+    run(&mut pool.get(), "CREATE TABLE users (id INT PRIMARY KEY, name TEXT);").unwrap();
+
+    let first = pool.get();
+    let second = pool.get();
+    assert_eq!(pool.available(), 0);
+    assert!(pool.try_get().is_none());
+    drop(first);
+    assert!(pool.get_timeout(Duration::from_millis(10)).is_some());
+    drop(second);
+    assert_eq!(pool.available(), 2);
+
+    thread::scope(|scope| {
+        for id in 0..8 {
+            let pool = &pool;
+            scope.spawn(move || {
+                let sql = format!("INSERT INTO users (id, name) VALUES ('{}', 'user{}');", id, id);
+                run(&mut pool.get(), &sql).unwrap();
+            });
+        }
+    });
+    assert!(run(&mut pool.get(), "SELECT * FROM users WHERE id = '7';").unwrap().contains("user7"));
+
+    // A connection dropped mid-transaction rolls it back
+    let mut connection = pool.get();
+    run(&mut connection, "BEGIN;").unwrap();
+    run(&mut connection, "DELETE FROM users;").unwrap();
+    drop(connection);
+    assert!(run(&mut pool.get(), "SELECT * FROM users;").unwrap().contains("user0"));
+}
+
+#[test]
+fn test_pool_transactions_detect_deadlocks() {
+    let dir = TempDir::new("pool_deadlock");
+    let pool = Pool::new(dir.path(), 2).unwrap();
+    run(&mut pool.get(), "CREATE TABLE a (id INT PRIMARY KEY);").unwrap();
+    run(&mut pool.get(), "CREATE TABLE b (id INT PRIMARY KEY);").unwrap();
+
+    let (mut first, mut second) = (pool.get(), pool.get());
+    run(&mut first, "BEGIN;").unwrap();
+    run(&mut first, "INSERT INTO a (id) VALUES ('1');").unwrap();
+    run(&mut second, "BEGIN;").unwrap();
+    run(&mut second, "INSERT INTO b (id) VALUES ('1');").unwrap();
+    thread::scope(|scope| {
+        // The first transaction waits for the second's lock on b...
+        let waiting = scope.spawn(move || {
+            run(&mut first, "INSERT INTO b (id) VALUES ('2');").unwrap();
+            run(&mut first, "COMMIT;").unwrap();
+        });
+        thread::sleep(Duration::from_millis(100));
+        assert!(!waiting.is_finished());
+
+        // ...so the second, waiting for the first's lock on a, is rolled back
+        let err = run(&mut second, "SELECT * FROM a;").unwrap_err();
+        assert!(err.starts_with(DEADLOCK_DETECTED), "{}", err);
+        assert!(!second.in_transaction());
+        waiting.join().unwrap();
+    });
+    let rows = run(&mut pool.get(), "SELECT id FROM b;").unwrap();
+    assert!(rows.contains('2') && !rows.contains('1'), "{}", rows);
+}
+
+#[test]
+fn test_optimistic_transactions_fail_on_conflicting_commits() {
+    let dir = TempDir::new("pool_optimistic");
+    let pool = Pool::new(dir.path(), 3).unwrap();
+    run(&mut pool.get(), "CREATE TABLE a (id INT PRIMARY KEY, n INT);").unwrap();
+    run(&mut pool.get(), "CREATE TABLE b (id INT PRIMARY KEY, n INT);").unwrap();
+    run(&mut pool.get(), "INSERT INTO a (id, n) VALUES ('1', '0');").unwrap();
+    run(&mut pool.get(), "INSERT INTO b (id, n) VALUES ('1', '0');").unwrap();
+
+    // Neither transaction waits for the other, but the one whose read was
+    // overwritten first can't commit
+    let (mut first, mut second) = (pool.get(), pool.get());
+    run(&mut first, "BEGIN OPTIMISTIC;").unwrap();
+    run(&mut first, "SELECT n FROM a WHERE id = '1';").unwrap();
+    run(&mut first, "UPDATE a SET n = '1' WHERE id = '1';").unwrap();
+    run(&mut second, "BEGIN OPTIMISTIC;").unwrap();
+    run(&mut second, "UPDATE a SET n = '2' WHERE id = '1';").unwrap();
+    run(&mut second, "COMMIT;").unwrap();
+    let err = run(&mut first, "COMMIT;").unwrap_err();
+    assert!(err.starts_with(SERIALIZATION_FAILURE), "{}", err);
+    assert!(!first.in_transaction());
+    assert!(run(&mut pool.get(), "SELECT n FROM a;").unwrap().contains('2'));
+
+    // Run again, it commits
+    run(&mut first, "BEGIN OPTIMISTIC;").unwrap();
+    run(&mut first, "UPDATE a SET n = '1' WHERE id = '1';").unwrap();
+    run(&mut first, "COMMIT;").unwrap();
+    assert!(run(&mut pool.get(), "SELECT n FROM a;").unwrap().contains('1'));
+
+    // Writes to tables it didn't read don't fail it
+    run(&mut first, "BEGIN OPTIMISTIC;").unwrap();
+    run(&mut first, "UPDATE b SET n = '1' WHERE id = '1';").unwrap();
+    run(&mut second, "UPDATE a SET n = '3' WHERE id = '1';").unwrap();
+    run(&mut first, "COMMIT;").unwrap();
+    assert!(run(&mut pool.get(), "SELECT n FROM b;").unwrap().contains('1'));
+}
+
+#[test]
+fn test_sessions_keep_their_own_variables_and_transactions() {
+    let dir = TempDir::new("sessions");
+    let db = Arc::new(PersistentDatabase::new(dir.path()).unwrap());
+    let execute = |session: &mut Session, sql: &str| {
+        session.execute(parse_sql(tokenize(sql)?)?).map(|result| result.to_string())
+    };
+    let mut alice = Session::new(db.clone()).with_user("alice");
+    let mut anonymous = Session::new(db.clone());
+
+    execute(&mut alice, "SET search_path TO 'public';").unwrap();
+    assert_eq!(alice.variable("SEARCH_PATH"), Some("public"));
+    assert!(execute(&mut alice, "SHOW Search_Path;").unwrap().contains("public"));
+    assert!(execute(&mut anonymous, "SHOW search_path;").unwrap_err().contains("Unknown session variable"));
+    assert!(execute(&mut alice, "SHOW session_user;").unwrap().contains("alice"));
+    assert_eq!(anonymous.variable("session_user"), Some(NULL));
+    assert!(execute(&mut alice, "SET session_user = 'bob';").is_err());
+    assert_eq!(alice.user(), Some("alice"));
+
+    // Each session has its own transaction, rolled back when it's dropped
+    execute(&mut alice, "CREATE TABLE users (id INT PRIMARY KEY, name TEXT);").unwrap();
+    execute(&mut alice, "BEGIN;").unwrap();
+    execute(&mut alice, "INSERT INTO users (id, name) VALUES ('1', 'alice');").unwrap();
+    assert!(alice.in_transaction() && !anonymous.in_transaction());
+    drop(alice);
+    assert!(!execute(&mut anonymous, "SELECT * FROM users;").unwrap().contains("alice"));
+
+    // SET and SHOW of a variable need a session
+    assert!(db.execute(parse_sql(tokenize("SET a = 1;").unwrap()).unwrap()).is_err());
+}
@@ -0,0 +1,846 @@
+#![cfg(feature = "storage")]
+
+use rust_sql_parser::partition::{PartitionScan, PartitionedStorage, Partitioning};
+use rust_sql_parser::storage::{
+    BloomFilter, CompactionConfig, CompactionStrategy, CompactionThrottle, Durability, LSMStorage, SSTable, ScanIter,
+    StorageConfig, StorageStats, StorageValue, WriteBatch, WAL,
+};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::ops::Bound::{self, Excluded, Included, Unbounded};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod common;
+use common::TempDir;
+
+#[test]
+fn test_apply_batch_puts_and_deletes() {
+    let dir = TempDir::new("storage_batch");
+    let mut storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+    storage.insert("a".to_string(), "1".to_string()).unwrap();
+
+    storage.apply_batch(vec![
+        ("a".to_string(), None),
+        ("b".to_string(), Some("2".to_string())),
+        ("c".to_string(), Some("3".to_string())),
+    ]).unwrap();
+
+    assert_eq!(storage.get("a").unwrap(), None);
+    assert_eq!(storage.get_all().unwrap(), vec![
+        ("b".to_string(), "2".to_string()),
+        ("c".to_string(), "3".to_string()),
+    ]);
+}
+
+#[test]
+fn test_write_batch_is_one_wal_record() {
+    let dir = TempDir::new("storage_write_batch");
+    {
+        let mut storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+        storage.insert("old".to_string(), "1".to_string()).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.delete("old".to_string())
+            .put("new".to_string(), "1".to_string())
+            .put("new".to_string(), "2".to_string());
+        assert_eq!(batch.len(), 3);
+        storage.write(batch).unwrap();
+    }
+
+    assert_eq!(WAL::new(&dir.as_path().join("t")).unwrap().replay_batches().unwrap().len(), 2);
+    let storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+    assert_eq!(storage.get_all().unwrap(), vec![("new".to_string(), "2".to_string())]);
+}
+
+#[test]
+fn test_scan_range_merges_sstables_and_memtable() {
+    let dir = TempDir::new("storage_scan_range");
+    let mut storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+    for key in ["a", "b", "c", "d", "e"] {
+        storage.insert(key.to_string(), "old".to_string()).unwrap();
+    }
+    storage.close().unwrap();
+
+    // Newer memtable entries shadow the flushed ones, including deletes
+    storage.insert("c".to_string(), "new".to_string()).unwrap();
+    storage.delete("d".to_string()).unwrap();
+
+    assert_eq!(storage.scan_range("b", "d").unwrap(), vec![
+        ("b".to_string(), "old".to_string()),
+        ("c".to_string(), "new".to_string()),
+    ]);
+    assert!(storage.scan_range("x", "z").unwrap().is_empty());
+    assert!(storage.scan_range("d", "b").unwrap().is_empty());
+}
+
+#[test]
+fn test_scan_streams_merged_entries_in_key_order() {
+    let dir = TempDir::new("storage_scan");
+    let mut storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+    for key in ["b", "d", "f"] {
+        storage.insert(key.to_string(), "1".to_string()).unwrap();
+    }
+    storage.close().unwrap();
+    for key in ["a", "d", "e"] {
+        storage.insert(key.to_string(), "2".to_string()).unwrap();
+    }
+    storage.close().unwrap();
+    storage.insert("c".to_string(), "3".to_string()).unwrap();
+    storage.delete("e".to_string()).unwrap();
+    storage.delete("f".to_string()).unwrap();
+
+    let keys = |range: (Bound<&str>, Bound<&str>)| -> Vec<(String, String)> {
+        storage.scan(range).unwrap().map(Result::unwrap).collect()
+    };
+    assert_eq!(keys((Unbounded, Unbounded)), vec![
+        ("a".to_string(), "2".to_string()),
+        ("b".to_string(), "1".to_string()),
+        ("c".to_string(), "3".to_string()),
+        ("d".to_string(), "2".to_string()),
+    ]);
+    assert_eq!(keys((Excluded("a"), Excluded("d"))).len(), 2);
+    assert!(keys((Included("e"), Unbounded)).is_empty());
+    assert!(keys((Excluded("c"), Excluded("c"))).is_empty());
+
+    // The iterator is lazy: taking the first row doesn't need the rest
+    assert_eq!(storage.scan("b"..).unwrap().next().unwrap().unwrap().0, "b");
+}
+
+#[test]
+fn test_reverse_and_prefix_scans() {
+    let dir = TempDir::new("storage_scan_rev");
+    let mut storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+    for key in ["order:1", "user:a", "user:c"] {
+        storage.insert(key.to_string(), "1".to_string()).unwrap();
+    }
+    storage.close().unwrap();
+    storage.insert("user:b".to_string(), "2".to_string()).unwrap();
+    storage.insert("user:c".to_string(), "2".to_string()).unwrap();
+    storage.insert("users".to_string(), "2".to_string()).unwrap();
+
+    let keys = |scan: ScanIter| -> Vec<String> {
+        scan.map(|row| row.unwrap().0).collect()
+    };
+    assert_eq!(keys(storage.scan_prefix("user:").unwrap()), vec!["user:a", "user:b", "user:c"]);
+    assert_eq!(keys(storage.scan_rev(..).unwrap()), vec!["users", "user:c", "user:b", "user:a", "order:1"]);
+    assert_eq!(keys(storage.scan_rev("user:b"..="user:c").unwrap()), vec!["user:c", "user:b"]);
+    assert_eq!(storage.scan_rev(..).unwrap().nth(1).unwrap().unwrap().1, "2");
+}
+
+#[test]
+fn test_sstables_are_reloaded_on_open() {
+    let dir = TempDir::new("storage_reload");
+    {
+        let mut storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+        storage.insert("a".to_string(), "1".to_string()).unwrap();
+        storage.insert("b".to_string(), "1".to_string()).unwrap();
+        storage.close().unwrap();
+        storage.insert("b".to_string(), "2".to_string()).unwrap();
+        storage.delete("a".to_string()).unwrap();
+        storage.close().unwrap();
+    }
+    // Flushed data now lives only in the SSTables
+    assert!(WAL::new(&dir.as_path().join("t")).unwrap().replay().unwrap().is_empty());
+
+    let storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+    assert_eq!(storage.get("a").unwrap(), None);
+    assert_eq!(storage.get("b").unwrap(), Some("2".to_string()));
+    assert_eq!(storage.get_all().unwrap(), vec![("b".to_string(), "2".to_string())]);
+    assert_eq!(storage.scan_range("a", "z").unwrap(), vec![("b".to_string(), "2".to_string())]);
+}
+
+#[test]
+fn test_sstables_missing_from_manifest_are_removed() {
+    let dir = TempDir::new("storage_manifest");
+    {
+        let mut storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+        storage.insert("a".to_string(), "1".to_string()).unwrap();
+        storage.close().unwrap();
+    }
+    let table_dir = dir.as_path().join("t");
+    assert!(table_dir.join("MANIFEST").exists());
+
+    // Left behind by a flush that crashed before updating the manifest
+    fs::copy(table_dir.join("sstable_0_0.log"), table_dir.join("sstable_0_7.log")).unwrap();
+    fs::write(table_dir.join("sstable_0_8.log"), b"LSMB\x01\x10\x00").unwrap();
+
+    let storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+    assert_eq!(storage.sstables().len(), 1);
+    assert!(!table_dir.join("sstable_0_7.log").exists());
+    assert!(!table_dir.join("sstable_0_8.log").exists());
+    assert_eq!(storage.get_all().unwrap(), vec![("a".to_string(), "1".to_string())]);
+}
+
+#[test]
+fn test_bloom_filter_has_no_false_negatives() {
+    let mut bloom = BloomFilter::new(1000);
+    for i in 0..1000 {
+        bloom.insert(&format!("key{}", i));
+    }
+    assert!((0..1000).all(|i| bloom.may_contain(&format!("key{}", i))));
+    let false_positives = (0..1000).filter(|i| bloom.may_contain(&format!("other{}", i))).count();
+    assert!(false_positives < 50, "{} false positives", false_positives);
+}
+
+#[test]
+fn test_sstable_bloom_filter_is_persisted() {
+    let dir = TempDir::new("storage_bloom");
+    {
+        let mut storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+        storage.insert("a".to_string(), "1".to_string()).unwrap();
+        storage.close().unwrap();
+    }
+    let table_dir = dir.as_path().join("t");
+    assert!(table_dir.join("sstable_0_0.bloom").exists());
+
+    let sstable = SSTable::open(table_dir.join("sstable_0_0.log")).unwrap();
+    assert!(sstable.bloom.as_ref().unwrap().may_contain("a"));
+    assert!(sstable.get("a").unwrap().is_some());
+    assert!(sstable.get("b").unwrap().is_none());
+}
+
+#[test]
+fn test_wal_replay_ignores_torn_tail() {
+    let dir = TempDir::new("storage_torn_wal");
+    {
+        let mut storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+        storage.insert("a".to_string(), "1".to_string()).unwrap();
+        storage.apply_batch(vec![
+            ("b".to_string(), Some("2".to_string())),
+            ("c".to_string(), Some("3".to_string())),
+        ]).unwrap();
+    }
+
+    // A record header promising more bytes than were written
+    let mut wal = OpenOptions::new().append(true).open(dir.as_path().join("t").join("wal.log")).unwrap();
+    wal.write_all(&64u32.to_le_bytes()).unwrap();
+    wal.write_all(&[0; 8]).unwrap();
+    wal.write_all(b"\x01\x00\x00\x00d").unwrap();
+    drop(wal);
+
+    let storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+    assert_eq!(storage.get("a").unwrap(), Some("1".to_string()));
+    assert_eq!(storage.get("c").unwrap(), Some("3".to_string()));
+    assert_eq!(storage.get("d").unwrap(), None);
+}
+
+#[test]
+fn test_corrupt_sstable_record_is_an_error() {
+    let dir = TempDir::new("storage_corrupt_sstable");
+    let mut storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+    flush_keys(&mut storage, 0..100, "value");
+    drop(storage);
+
+    let path = dir.as_path().join("t").join("sstable_0_0.log");
+    let mut bytes = fs::read(&path).unwrap();
+    let middle = bytes.len() / 2;
+    bytes[middle] ^= 0xff;
+    fs::write(&path, bytes).unwrap();
+
+    // Keys past the corrupt record aren't silently missing from reads
+    let mut storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+    assert_eq!(storage.get_all().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    assert_eq!(storage.get("key0099").unwrap_err().kind(), io::ErrorKind::InvalidData);
+    assert_eq!(storage.get("key0000").unwrap(), Some("value".to_string()));
+
+    // and compaction fails rather than rewriting the table without them
+    assert!(storage.compact().is_err());
+    assert_eq!(storage.sstables().len(), 1);
+    assert_eq!(storage.sstables()[0].path, path);
+    drop(storage);
+    assert!(path.exists());
+}
+
+#[test]
+fn test_json_files_from_older_versions_are_readable() {
+    let dir = TempDir::new("storage_json_compat");
+    let table_dir = dir.as_path().join("t");
+    fs::create_dir_all(&table_dir).unwrap();
+    fs::write(table_dir.join("sstable_0.log"), concat!(
+        r#"{"key":"a","value":{"Present":"1"},"timestamp":1}"#, "\n",
+        r#"{"key":"b","value":{"Present":"1"},"timestamp":1}"#, "\n",
+    )).unwrap();
+    fs::write(table_dir.join("wal.log"), concat!(
+        r#"{"key":"b","value":{"Present":"2"},"timestamp":2}"#, "\n",
+        r#"[{"key":"c","value":{"Present":"3"},"timestamp":2},{"key":"a","value":"Deleted","timestamp":2}]"#, "\n",
+    )).unwrap();
+
+    let mut storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+    assert_eq!(storage.get_all().unwrap(), vec![
+        ("b".to_string(), "2".to_string()),
+        ("c".to_string(), "3".to_string()),
+    ]);
+
+    // The WAL was upgraded to the binary format and keeps its batches
+    assert!(fs::read(table_dir.join("wal.log")).unwrap().starts_with(b"LSMB"));
+    assert_eq!(WAL::new(&table_dir).unwrap().replay_batches().unwrap().len(), 2);
+    storage.insert("d".to_string(), "4".to_string()).unwrap();
+    drop(storage);
+    let storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+    assert_eq!(storage.get("d").unwrap(), Some("4".to_string()));
+    assert_eq!(storage.get("a").unwrap(), None);
+}
+
+fn flush_keys(storage: &mut LSMStorage, keys: std::ops::Range<usize>, value: &str) {
+    for i in keys {
+        storage.insert(format!("key{:04}", i), value.to_string()).unwrap();
+    }
+    storage.close().unwrap();
+}
+
+#[test]
+fn test_full_compaction_splits_output_by_target_size() {
+    let dir = TempDir::new("storage_full_compaction");
+    let config = CompactionConfig {
+        strategy: CompactionStrategy::Full,
+        trigger_file_count: 2,
+        target_file_size: 200,
+        ..CompactionConfig::default()
+    };
+    let mut storage = LSMStorage::with_config(dir.as_path(), "t", config.clone()).unwrap();
+    flush_keys(&mut storage, 0..20, "old");
+    assert_eq!(storage.sstables().len(), 1);
+    flush_keys(&mut storage, 10..30, "new");
+
+    // Both flushes were merged into a run of several non-overlapping files
+    let sstables = storage.sstables();
+    assert!(sstables.len() > 1);
+    assert!(sstables.iter().all(|t| t.level == 1));
+    assert!(sstables.windows(2).all(|w| w[0].max_key < w[1].min_key));
+    drop(storage);
+
+    let storage = LSMStorage::with_config(dir.as_path(), "t", config).unwrap();
+    let all = storage.get_all().unwrap();
+    assert_eq!(all.len(), 30);
+    assert_eq!(storage.get("key0005").unwrap(), Some("old".to_string()));
+    assert_eq!(storage.get("key0015").unwrap(), Some("new".to_string()));
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn test_mapped_sstables_serve_reads_and_compaction() {
+    let dir = TempDir::new("storage_mmap");
+    let mut storage = LSMStorage::with_config(dir.as_path(), "t", CompactionConfig {
+        trigger_file_count: 2,
+        target_file_size: 200,
+        ..CompactionConfig::default()
+    }).unwrap();
+    flush_keys(&mut storage, 0..20, "old");
+    assert_eq!(storage.get("key0005").unwrap(), Some("old".to_string()));
+    assert_eq!(storage.get("key0020").unwrap(), None);
+    assert_eq!(storage.scan_range("key0003", "key0004").unwrap(), vec![
+        ("key0003".to_string(), "old".to_string()),
+        ("key0004".to_string(), "old".to_string()),
+    ]);
+
+    flush_keys(&mut storage, 10..30, "new");
+    assert_eq!(storage.stats().compactions, 1);
+    assert!(storage.sstables().len() > 1);
+    assert_eq!(storage.get_all().unwrap().len(), 30);
+    assert_eq!(storage.get("key0005").unwrap(), Some("old".to_string()));
+    assert_eq!(storage.get("key0015").unwrap(), Some("new".to_string()));
+    let keys: Vec<String> = storage.scan_rev("key0018"..="key0021").unwrap().map(|e| e.unwrap().0).collect();
+    assert_eq!(keys, vec!["key0021", "key0020", "key0019", "key0018"]);
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn test_mapped_sstable_truncated_or_corrupt_is_an_error() {
+    let dir = TempDir::new("storage_mmap_corrupt");
+    let mut storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+    flush_keys(&mut storage, 0..100, "value");
+    drop(storage);
+    let path = dir.as_path().join("t").join("sstable_0_0.log");
+    let intact = fs::read(&path).unwrap();
+
+    // Cut off in the middle of the records, inside the first record's
+    // header, and with one byte flipped
+    let mut flipped = intact.clone();
+    flipped[intact.len() / 2] ^= 0xff;
+    for damaged in [&intact[..intact.len() / 2], &intact[..11], &flipped[..]] {
+        fs::write(&path, damaged).unwrap();
+        let storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+        assert_eq!(storage.get_all().unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(storage.get("key0099").unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
+
+#[test]
+fn test_leveled_compaction_pushes_full_levels_down() {
+    let dir = TempDir::new("storage_leveled_compaction");
+    let mut storage = LSMStorage::with_config(dir.as_path(), "t", CompactionConfig {
+        strategy: CompactionStrategy::Leveled,
+        trigger_file_count: 2,
+        target_file_size: 1000,
+        max_level_size: 500,
+        ..CompactionConfig::default()
+    }).unwrap();
+
+    for round in 0..6 {
+        flush_keys(&mut storage, (round * 10)..(round * 10 + 15), &format!("v{}", round));
+        assert!(storage.sstables().iter().filter(|t| t.level == 0).count() < 2);
+    }
+    assert!(storage.sstables().iter().any(|t| t.level >= 2));
+
+    assert_eq!(storage.get_all().unwrap().len(), 65);
+    assert_eq!(storage.get("key0012").unwrap(), Some("v1".to_string()));
+    assert_eq!(storage.get("key0064").unwrap(), Some("v5".to_string()));
+}
+
+#[test]
+fn test_writes_survive_reopen_in_every_durability_mode() {
+    for (name, durability) in [
+        ("always", Durability::Always),
+        ("interval", Durability::Interval(Duration::from_millis(5))),
+        ("on_close", Durability::OnClose),
+    ] {
+        let dir = TempDir::new(&format!("storage_durability_{}", name));
+        {
+            let mut storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+            storage.set_durability(durability);
+            storage.insert("a".to_string(), "1".to_string()).unwrap();
+            storage.apply_batch(vec![("b".to_string(), Some("2".to_string()))]).unwrap();
+            storage.delete("a".to_string()).unwrap();
+        }
+        let storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+        assert_eq!(storage.get_all().unwrap(), vec![("b".to_string(), "2".to_string())], "{}", name);
+    }
+}
+
+#[test]
+fn test_deferred_syncs_from_concurrent_writers() {
+    let dir = TempDir::new("storage_group_commit");
+    let storage = Arc::new(Mutex::new(LSMStorage::new(dir.as_path(), "t").unwrap()));
+
+    let handles: Vec<_> = (0..8).map(|t| {
+        let storage = Arc::clone(&storage);
+        thread::spawn(move || {
+            for i in 0..20 {
+                let mut batch = WriteBatch::new();
+                batch.put(format!("key{}_{:02}", t, i), i.to_string());
+                let ticket = storage.lock().unwrap().write_deferred(batch).unwrap();
+                // Always mode hands back a ticket, waited on outside the lock
+                ticket.unwrap().wait().unwrap();
+            }
+        })
+    }).collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    drop(storage);
+
+    let storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+    assert_eq!(storage.get_all().unwrap().len(), 160);
+}
+
+#[test]
+fn test_compaction_drops_tombstones_past_grace_period() {
+    for (grace_period, kept) in [(Duration::ZERO, false), (Duration::from_secs(3600), true)] {
+        let dir = TempDir::new("storage_tombstone_gc");
+        let mut storage = LSMStorage::with_config(dir.as_path(), "t", CompactionConfig {
+            trigger_file_count: 2,
+            tombstone_grace_period: grace_period,
+            ..CompactionConfig::default()
+        }).unwrap();
+        storage.insert("a".to_string(), "1".to_string()).unwrap();
+        storage.insert("b".to_string(), "1".to_string()).unwrap();
+        storage.close().unwrap();
+        storage.delete("a".to_string()).unwrap();
+        storage.close().unwrap();
+
+        // The full compaction merged everything, so nothing is left to shadow
+        let entries: Vec<_> = storage.sstables().iter()
+            .flat_map(|t| t.read_entries().unwrap())
+            .map(|e| e.key)
+            .collect();
+        assert_eq!(entries.contains(&"a".to_string()), kept);
+        assert_eq!(storage.get("a").unwrap(), None);
+        assert_eq!(storage.get("b").unwrap(), Some("1".to_string()));
+    }
+}
+
+#[test]
+fn test_large_values_are_separated_into_value_log() {
+    let dir = TempDir::new("storage_value_log");
+    let big = "x".repeat(100);
+    {
+        let mut storage = LSMStorage::with_config(dir.as_path(), "t", CompactionConfig {
+            trigger_file_count: 2,
+            ..CompactionConfig::default()
+        }).unwrap();
+        storage.set_value_threshold(Some(50));
+        storage.insert("big".to_string(), big.clone()).unwrap();
+        storage.insert("small".to_string(), "1".to_string()).unwrap();
+        storage.close().unwrap();
+        storage.insert("other".to_string(), big.clone()).unwrap();
+        storage.close().unwrap();
+
+        // Compaction carried the pointers over without rewriting the values
+        let entries: Vec<_> = storage.sstables().iter().flat_map(|t| t.read_entries().unwrap()).collect();
+        assert_eq!(entries.iter().filter(|e| matches!(e.value, StorageValue::Separated(_))).count(), 2);
+        assert!(entries.iter().any(|e| matches!(&e.value, StorageValue::Present(v) if v == "1")));
+    }
+
+    // Separation is off unless set, but values already separated still read
+    let storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+    assert_eq!(storage.value_threshold(), None);
+    assert_eq!(storage.get("big").unwrap(), Some(big.clone()));
+    assert_eq!(storage.get_all().unwrap(), vec![
+        ("big".to_string(), big.clone()),
+        ("other".to_string(), big.clone()),
+        ("small".to_string(), "1".to_string()),
+    ]);
+
+    // Readers of the value log don't share a cursor
+    thread::scope(|scope| {
+        for key in ["big", "other"].repeat(4) {
+            let (storage, big) = (&storage, &big);
+            scope.spawn(move || {
+                for _ in 0..50 {
+                    assert_eq!(storage.get(key).unwrap().as_ref(), Some(big));
+                }
+            });
+        }
+    });
+}
+
+#[test]
+fn test_stats_count_flushes_compactions_and_reads() {
+    let dir = TempDir::new("storage_stats");
+    let mut storage = LSMStorage::with_config(dir.as_path(), "t", CompactionConfig {
+        trigger_file_count: 2,
+        ..CompactionConfig::default()
+    }).unwrap();
+    assert_eq!(storage.stats(), StorageStats::default());
+
+    storage.insert("a".to_string(), "1".to_string()).unwrap();
+    storage.close().unwrap();
+    let stats = storage.stats();
+    assert!(stats.wal_bytes_written > 0);
+    assert_eq!(stats.flushes, 1);
+    assert_eq!(stats.flush_bytes_written, storage.sstables()[0].size as u64);
+    assert_eq!(stats.compactions, 0);
+
+    storage.insert("b".to_string(), "2".to_string()).unwrap();
+    storage.close().unwrap();
+    let stats = storage.stats();
+    assert_eq!((stats.flushes, stats.compactions), (2, 1));
+    assert!(stats.compaction_bytes_read > 0);
+    assert_eq!(stats.compaction_bytes_written, storage.sstables()[0].size as u64);
+    assert!(stats.write_amplification() > 1.0);
+    // Compaction reads aren't query reads
+    assert_eq!(stats.query_bytes_read, 0);
+
+    assert_eq!(storage.get("a").unwrap(), Some("1".to_string()));
+    assert_eq!(storage.scan(..).unwrap().count(), 2);
+    let stats = storage.stats();
+    assert_eq!(stats.user_bytes_read, 6);
+    assert!(stats.query_bytes_read > 0);
+    assert!(stats.read_amplification() > 1.0);
+}
+
+#[test]
+fn test_full_memtable_is_flushed_in_the_background() {
+    let dir = TempDir::new("storage_background_flush");
+    {
+        let mut storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+        storage.set_storage_config(StorageConfig { memtable_size: 32, ..StorageConfig::default() });
+        // Every write sees the earlier ones, whether they are in the active
+        // memtable, the one being flushed, or an SSTable
+        for i in 0..20 {
+            storage.insert(format!("key{:02}", i), format!("v{}", i)).unwrap();
+            assert_eq!(storage.get(&format!("key{:02}", i)).unwrap(), Some(format!("v{}", i)));
+            assert_eq!(storage.scan(..).unwrap().count(), i + 1);
+        }
+        assert!(storage.stats().flushes > 0);
+    }
+
+    let storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+    assert_eq!(storage.get_all().unwrap().len(), 20);
+}
+
+#[test]
+fn test_unflushed_immutable_wal_is_recovered() {
+    let dir = TempDir::new("storage_immutable_wal");
+    let table_dir = dir.as_path().join("t");
+    {
+        let mut storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+        storage.insert("a".to_string(), "1".to_string()).unwrap();
+        storage.insert("b".to_string(), "1".to_string()).unwrap();
+    }
+    // As if the table was closed while that log's memtable was being flushed
+    fs::rename(table_dir.join("wal.log"), table_dir.join("wal.imm.log")).unwrap();
+
+    {
+        let mut storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+        storage.insert("a".to_string(), "2".to_string()).unwrap();
+        assert_eq!(storage.get("a").unwrap(), Some("2".to_string()));
+        assert_eq!(storage.get("b").unwrap(), Some("1".to_string()));
+        storage.close().unwrap();
+    }
+    assert!(!table_dir.join("wal.imm.log").exists());
+
+    let storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+    assert_eq!(storage.get_all().unwrap(), vec![
+        ("a".to_string(), "2".to_string()),
+        ("b".to_string(), "1".to_string()),
+    ]);
+}
+
+#[test]
+fn test_compaction_throttle_limits_io_rate() {
+    let dir = TempDir::new("storage_throttle");
+    let mut storage = LSMStorage::with_config(dir.as_path(), "t", CompactionConfig {
+        trigger_file_count: 2,
+        ..CompactionConfig::default()
+    }).unwrap();
+    // Reading and writing ~2KB at 8KB/s takes at least a quarter second
+    storage.set_compaction_throttle(Some(Arc::new(CompactionThrottle::new(Some(8 * 1024), Some(1)))));
+    storage.insert("a".to_string(), "x".repeat(1000)).unwrap();
+    storage.close().unwrap();
+    storage.insert("b".to_string(), "x".repeat(1000)).unwrap();
+
+    let started = Instant::now();
+    storage.close().unwrap();
+    assert_eq!(storage.stats().compactions, 1);
+    assert!(started.elapsed() >= Duration::from_millis(200), "{:?}", started.elapsed());
+    assert_eq!(storage.get_all().unwrap().len(), 2);
+}
+
+#[test]
+fn test_throttled_compaction_runs_off_the_table_lock() {
+    let dir = TempDir::new("storage_throttle_background");
+    let mut storage = LSMStorage::with_config(dir.as_path(), "t", CompactionConfig {
+        trigger_file_count: 2,
+        ..CompactionConfig::default()
+    }).unwrap();
+    // Reading and writing ~80KB at 80KB/s takes about a second
+    storage.set_compaction_throttle(Some(Arc::new(CompactionThrottle::new(Some(80 * 1024), None))));
+    let rows = |prefix: &str| (0..5).map(|i| (format!("{}{}", prefix, i), Some("x".repeat(4000)))).collect();
+    storage.apply_batch(rows("a")).unwrap();
+    storage.close().unwrap();
+    storage.set_storage_config(StorageConfig { memtable_size: 1, ..StorageConfig::default() });
+    let storage = Arc::new(RwLock::new(storage));
+
+    // The write that installs the second flush starts the compaction, and
+    // doesn't wait for it
+    {
+        let mut storage = storage.write().unwrap();
+        storage.apply_batch(rows("b")).unwrap();
+        storage.insert("c".to_string(), "1".to_string()).unwrap();
+        assert!(storage.is_compacting());
+    }
+    // Reads are answered while the compaction is still running
+    let reader = {
+        let storage = storage.clone();
+        thread::spawn(move || {
+            let storage = storage.read().unwrap();
+            assert_eq!(storage.get("a0").unwrap(), Some("x".repeat(4000)));
+            assert_eq!(storage.get("b4").unwrap(), Some("x".repeat(4000)));
+            assert!(storage.is_compacting());
+            assert_eq!(storage.stats().compactions, 0);
+        })
+    };
+    reader.join().unwrap();
+
+    let mut storage = storage.write().unwrap();
+    storage.close().unwrap();
+    assert!(!storage.is_compacting());
+    assert!(storage.stats().compactions >= 1);
+    assert_eq!(storage.get_all().unwrap().len(), 11);
+}
+
+#[test]
+fn test_compaction_throttle_of_zero_jobs_is_unlimited() {
+    let dir = TempDir::new("storage_throttle_zero_jobs");
+    let mut storage = LSMStorage::with_config(dir.as_path(), "t", CompactionConfig {
+        trigger_file_count: 2,
+        ..CompactionConfig::default()
+    }).unwrap();
+    storage.set_compaction_throttle(Some(Arc::new(CompactionThrottle::new(None, Some(0)))));
+    for i in 0..3 {
+        storage.insert(format!("k{}", i), "v".to_string()).unwrap();
+        storage.close().unwrap();
+    }
+    assert!(!storage.is_compacting());
+    assert!(storage.stats().compactions >= 1);
+    assert_eq!(storage.get_all().unwrap().len(), 3);
+}
+
+#[test]
+fn test_partitioned_scan_merges_shards_as_it_reads() {
+    let dir = TempDir::new("storage_partitioned_scan");
+    let mut storage = PartitionedStorage::open(dir.as_path(), "t", Some(&Partitioning::Hash(4))).unwrap();
+    let mut batch = WriteBatch::new();
+    for i in 0..100 {
+        batch.put(format!("key{:04}", i), "x".repeat(100));
+    }
+    assert!(storage.write_deferred(batch).unwrap().is_none());
+    storage.close().unwrap();
+    storage.write_deferred(WriteBatch::from(vec![("key0100".to_string(), Some("x".repeat(100)))])).unwrap();
+
+    let keys = |scan: PartitionScan| scan.map(|row| row.unwrap().0).collect::<Vec<_>>();
+    let all: Vec<String> = (0..=100).map(|i| format!("key{:04}", i)).collect();
+    assert_eq!(keys(storage.scan((Unbounded, Unbounded), false).unwrap()), all);
+    assert_eq!(keys(storage.scan((Unbounded, Unbounded), true).unwrap()), all.iter().rev().cloned().collect::<Vec<_>>());
+    assert_eq!(keys(storage.scan((Included("key0010"), Excluded("key0013")), false).unwrap()),
+               vec!["key0010", "key0011", "key0012"]);
+    assert_eq!(keys(storage.scan((Excluded("key0098"), Unbounded), true).unwrap()), vec!["key0100", "key0099"]);
+
+    // The first row takes the head row of each shard and one more, not the table
+    let before = storage.stats().user_bytes_read;
+    let first = storage.scan((Unbounded, Unbounded), false).unwrap().next().unwrap().unwrap();
+    assert_eq!(first.0, "key0000");
+    let read = storage.stats().user_bytes_read - before;
+    assert!(read <= 5 * 107, "{} bytes read", read);
+}
+
+#[test]
+fn test_range_partitioned_scan_reads_only_overlapping_shards() {
+    let dir = TempDir::new("storage_range_partitioned");
+    let partitioning = Partitioning::Range(vec!["key0040".to_string(), "key0080".to_string()]);
+    let mut storage = PartitionedStorage::open(dir.as_path(), "t", Some(&partitioning)).unwrap();
+    let mut batch = WriteBatch::new();
+    for i in 0..120 {
+        batch.put(format!("key{:04}", i), "x".repeat(100));
+    }
+    storage.write_deferred(batch).unwrap();
+    storage.close().unwrap();
+    drop(storage);
+
+    // Each shard holds just its range of keys
+    for (shard, expected) in [(0, 0..40), (1, 40..80), (2, 80..120)] {
+        let shard = LSMStorage::new(&dir.as_path().join("t"), &format!("shard_{}", shard)).unwrap();
+        let keys: Vec<String> = shard.get_all().unwrap().into_iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, expected.map(|i| format!("key{:04}", i)).collect::<Vec<_>>());
+    }
+
+    let storage = PartitionedStorage::open(dir.as_path(), "t", Some(&partitioning)).unwrap();
+    let keys = |scan: PartitionScan| scan.map(|row| row.unwrap().0).collect::<Vec<_>>();
+    assert_eq!(keys(storage.scan((Included("key0078"), Excluded("key0082")), false).unwrap()),
+               vec!["key0078", "key0079", "key0080", "key0081"]);
+    assert_eq!(keys(storage.scan((Included("key0090"), Included("key0040")), false).unwrap()), Vec::<String>::new());
+    let before = storage.stats().user_bytes_read;
+    assert_eq!(keys(storage.scan((Included("key0041"), Included("key0043")), true).unwrap()),
+               vec!["key0043", "key0042", "key0041"]);
+    let read = storage.stats().user_bytes_read - before;
+    assert!(read <= 3 * 107, "{} bytes read", read);
+}
+
+#[test]
+fn test_ttl_hides_and_purges_expired_rows() {
+    let dir = TempDir::new("storage_ttl");
+    let mut storage = LSMStorage::with_config(dir.as_path(), "t", CompactionConfig {
+        trigger_file_count: 2,
+        ..CompactionConfig::default()
+    }).unwrap();
+    storage.set_storage_config(StorageConfig { ttl: Some(Duration::from_millis(300)), ..StorageConfig::default() });
+    storage.insert("old".to_string(), "1".to_string()).unwrap();
+    storage.close().unwrap();
+    thread::sleep(Duration::from_millis(400));
+    storage.insert("new".to_string(), "2".to_string()).unwrap();
+
+    assert_eq!(storage.get("old").unwrap(), None);
+    assert_eq!(storage.get_all().unwrap(), vec![("new".to_string(), "2".to_string())]);
+
+    // Compaction drops the expired row for good
+    storage.close().unwrap();
+    assert_eq!(storage.stats().compactions, 1);
+    storage.set_storage_config(StorageConfig::default());
+    assert_eq!(storage.get("old").unwrap(), None);
+    assert_eq!(storage.get("new").unwrap(), Some("2".to_string()));
+}
+
+#[test]
+fn test_scan_as_of_replays_history_since_archiving_began() {
+    let dir = TempDir::new("storage_as_of");
+    let now = || std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+    let mut storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+    storage.insert("a".to_string(), "1".to_string()).unwrap();
+    storage.close().unwrap();
+    assert_eq!(storage.scan_as_of(now()).unwrap_err().kind(), io::ErrorKind::NotFound);
+
+    // The flushed row predates archiving, so it opens the history
+    let before_archiving = now();
+    thread::sleep(Duration::from_millis(5));
+    storage.set_wal_archiving(true).unwrap();
+    assert_eq!(storage.scan_as_of(before_archiving).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(storage.scan_as_of(now()).unwrap(), [("a".to_string(), "1".to_string())]);
+
+    // A read replays the newest checkpoint and the segments after it, not
+    // every write since the history began
+    let value = "x".repeat(1000);
+    let mut times = Vec::new();
+    for i in 0..50 {
+        storage.insert("b".to_string(), format!("{}{}", i, value)).unwrap();
+        storage.close().unwrap();
+        times.push(now());
+        thread::sleep(Duration::from_millis(2));
+    }
+    let before = storage.stats().query_bytes_read;
+    let rows = storage.scan_as_of(now()).unwrap();
+    assert_eq!(rows.len(), 2);
+    assert!(rows[1].1.starts_with("49x"));
+    assert!(storage.stats().query_bytes_read - before < 20 * 1000);
+    let rows = storage.scan_as_of(times[20]).unwrap();
+    assert!(rows[1].1.starts_with("20x"));
+    let checkpoints = fs::read_dir(dir.as_path().join("t").join("wal_archive")).unwrap()
+        .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().starts_with("checkpoint_"))
+        .count();
+    assert_eq!(checkpoints, 3);
+
+    // A log discarded unarchived ends the history
+    storage.set_wal_archiving(false).unwrap();
+    storage.delete("a".to_string()).unwrap();
+    storage.close().unwrap();
+    let before_restart = now();
+    thread::sleep(Duration::from_millis(5));
+    storage.set_wal_archiving(true).unwrap();
+    storage.insert("c".to_string(), "3".to_string()).unwrap();
+    assert_eq!(storage.scan_as_of(before_restart).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    let keys: Vec<_> = storage.scan_as_of(now()).unwrap().into_iter().map(|(key, _)| key).collect();
+    assert_eq!(keys, ["b", "c"]);
+}
+
+#[test]
+fn test_wal_retention_trims_archive_to_a_checkpoint() {
+    let dir = TempDir::new("storage_wal_retention");
+    let now = || std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis() as u64;
+    let mut storage = LSMStorage::new(dir.as_path(), "t").unwrap();
+    storage.set_wal_archiving(true).unwrap();
+    storage.set_wal_retention(Some(Duration::from_millis(200)));
+    let write = |storage: &mut LSMStorage, i: usize| {
+        storage.insert(format!("k{:02}", i), i.to_string()).unwrap();
+        storage.close().unwrap();
+    };
+    let mut early = 0;
+    for i in 0..20 {
+        write(&mut storage, i);
+        if i == 5 {
+            early = now();
+            thread::sleep(Duration::from_millis(2));
+        }
+    }
+    // Kept back to the newest checkpoint before the retention period, the
+    // one after 16 segments
+    thread::sleep(Duration::from_millis(300));
+    for i in 20..40 {
+        write(&mut storage, i);
+    }
+
+    let archive = dir.as_path().join("t").join("wal_archive");
+    let segments = fs::read_dir(&archive).unwrap()
+        .filter(|entry| entry.as_ref().unwrap().file_name().to_string_lossy().starts_with("wal_"))
+        .count();
+    assert!(segments < 40, "{} segments", segments);
+    assert_eq!(storage.scan_as_of(early).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    assert_eq!(storage.scan_as_of(now()).unwrap().len(), 40);
+
+    let restore_dir = TempDir::new("storage_wal_retention_restore");
+    assert!(LSMStorage::restore_to(dir.as_path(), "t", restore_dir.as_path(), early).is_err());
+    LSMStorage::restore_to(dir.as_path(), "t", restore_dir.as_path(), now()).unwrap();
+    let restored = LSMStorage::new(restore_dir.as_path(), "t").unwrap();
+    assert_eq!(restored.get_all().unwrap().len(), 40);
+}
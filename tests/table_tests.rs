@@ -0,0 +1,48 @@
+#![cfg(feature = "derive")]
+
+use rust_sql_parser::executor::Database;
+use rust_sql_parser::table::SqlTable;
+
+#[derive(Debug, PartialEq, SqlTable)]
+#[sql(table = "users")]
+struct User {
+    #[sql(primary_key)]
+    id: u32,
+    name: String,
+    score: f64,
+    age: Option<u8>,
+}
+
+#[derive(Debug, PartialEq, SqlTable)]
+struct Tag {
+    label: String,
+}
+
+#[test]
+fn test_derived_table_mapping() {
+    let create = User::create_table();
+    assert_eq!(create.table, "users");
+    assert_eq!(create.primary_key.as_deref(), Some("id"));
+    assert_eq!(create.columns, vec![
+        ("id".to_string(), "INT".to_string()),
+        ("name".to_string(), "TEXT".to_string()),
+        ("score".to_string(), "REAL".to_string()),
+        ("age".to_string(), "INT".to_string()),
+    ]);
+    assert_eq!(Tag::TABLE, "tag");
+
+    let mut db = Database::new();
+    db.execute(User::create_table().into()).unwrap();
+    let users = vec![
+        User { id: 1, name: "o'hara".to_string(), score: 2.5, age: Some(30) },
+        User { id: 2, name: "bob".to_string(), score: -1.0, age: None },
+    ];
+    for user in &users {
+        db.execute(user.insert().into()).unwrap();
+    }
+
+    let rows = db.query(&User::select().order_by("id").build()).unwrap();
+    assert_eq!(rows.map(User::from_row).unwrap(), users);
+    let error = db.query(&User::select().build()).unwrap().map(Tag::from_row).unwrap_err();
+    assert!(error.contains("label"), "{}", error);
+}